@@ -0,0 +1,14 @@
+//! Feeds arbitrary bytes (as lossy UTF-8) through `parse_patch` and
+//! `render_patch`: a panic, or a rendering that doesn't parse and render
+//! back to itself, is a crash.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let text = String::from_utf8_lossy(data);
+    if let Err(msg) = patch_22::check_roundtrip(&text) {
+        panic!("{msg}");
+    }
+});
@@ -0,0 +1,32 @@
+/*
+ * C ABI of the patch-22 library (built as a cdylib: libpatch_22.so,
+ * libpatch_22.dylib or patch_22.dll). See "Embedding" in README.md.
+ */
+#ifndef APPLY_PATCH_H
+#define APPLY_PATCH_H
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+/*
+ * Applies `patch` as `apply_patch` would and returns its exit code.
+ *
+ * `options` is NULL or a JSON object with any of "cwd", "config", "tenant"
+ * (strings) and "dry_run" (boolean). When `out_json` isn't NULL it receives
+ * {"exit_code": N, "stdout": "...", "stderr": "..."}, to be released with
+ * apply_patch_free.
+ *
+ * Runs take turns, and while one lasts descriptors 1 and 2 point at pipes
+ * that collect its output.
+ */
+int apply_patch_run(const char *patch, const char *options, char **out_json);
+
+/* Releases an answer from apply_patch_run. NULL is ignored. */
+void apply_patch_free(char *json);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* APPLY_PATCH_H */
@@ -0,0 +1,252 @@
+//! `*** Add Files From Archive: DIR` sections: a base64 tar (optionally
+//! gzip-compressed) whose regular files are added under DIR.
+//!
+//! Archive sections are expanded into ordinary `*** Add File:` sections
+//! before anything else looks at the patch, so policies, guards and the
+//! applier treat every extracted entry exactly like a hand-written add.
+//! Entries must be UTF-8 text with relative paths that stay inside DIR, and
+//! the `archives` limits cap how much one patch can unpack.
+
+use crate::patch::ADD_FILE_MARKER;
+use serde::Deserialize;
+use serde::Serialize;
+use std::borrow::Cow;
+use std::path::Component;
+use std::path::Path;
+
+pub(crate) const ADD_ARCHIVE_MARKER: &str = "*** Add Files From Archive: ";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct ArchiveLimits {
+    /// Most regular files one archive may contain.
+    #[serde(default = "default_max_files")]
+    pub(crate) max_files: usize,
+    /// Largest archive, in bytes once decoded and decompressed.
+    #[serde(default = "default_max_bytes")]
+    pub(crate) max_bytes: usize,
+}
+
+fn default_max_files() -> usize {
+    200
+}
+
+fn default_max_bytes() -> usize {
+    1024 * 1024
+}
+
+impl Default for ArchiveLimits {
+    fn default() -> Self {
+        Self {
+            max_files: default_max_files(),
+            max_bytes: default_max_bytes(),
+        }
+    }
+}
+
+/// Replaces every archive section in `patch_text` with one add section per
+/// file. Patches without archive sections are returned unchanged.
+pub(crate) fn expand<'a>(
+    patch_text: &'a str,
+    limits: &ArchiveLimits,
+) -> Result<Cow<'a, str>, String> {
+    if !patch_text
+        .lines()
+        .any(|line| line.trim_start().starts_with(ADD_ARCHIVE_MARKER))
+    {
+        return Ok(Cow::Borrowed(patch_text));
+    }
+    let mut out = String::new();
+    let mut lines = patch_text.lines().peekable();
+    while let Some(line) = lines.next() {
+        let Some(dir) = line.trim().strip_prefix(ADD_ARCHIVE_MARKER) else {
+            out.push_str(line);
+            out.push('\n');
+            continue;
+        };
+        let mut encoded = String::new();
+        while let Some(next) = lines.next_if(|l| !l.trim_start().starts_with("***")) {
+            encoded.push_str(next.trim());
+        }
+        let fail = |msg: String| format!("Invalid archive for {dir}: {msg}");
+        let data = decode_base64(&encoded).map_err(fail)?;
+        let data = if data.starts_with(&[0x1f, 0x8b]) {
+            crate::inflate::gunzip(&data, limits.max_bytes).map_err(fail)?
+        } else if data.len() > limits.max_bytes {
+            return Err(fail(format!(
+                "archive is larger than {} bytes",
+                limits.max_bytes
+            )));
+        } else {
+            data
+        };
+        for (name, contents) in read_tar(&data, limits.max_files).map_err(fail)? {
+            let path = match dir.trim_end_matches('/') {
+                "" | "." => name,
+                dir => format!("{dir}/{name}"),
+            };
+            out.push_str(ADD_FILE_MARKER);
+            out.push_str(&path);
+            out.push('\n');
+            for content_line in contents.lines() {
+                out.push('+');
+                out.push_str(content_line);
+                out.push('\n');
+            }
+        }
+    }
+    Ok(Cow::Owned(out))
+}
+
+/// One entry of a tar, with the GNU long-name or pax headers in front of it.
+pub(crate) struct TarEntry<'a> {
+    pub(crate) name: String,
+    /// The header's type flag (`b'0'` or `0` for a regular file).
+    pub(crate) kind: u8,
+    /// Long-name and pax headers that belong to this entry, as stored.
+    pub(crate) extended: &'a [u8],
+    pub(crate) header: &'a [u8],
+    pub(crate) body: &'a [u8],
+}
+
+/// Every entry of a ustar/pax/GNU tar, in order.
+pub(crate) fn tar_entries(data: &[u8]) -> Result<Vec<TarEntry<'_>>, String> {
+    let mut entries: Vec<TarEntry<'_>> = Vec::new();
+    let mut long_name: Option<String> = None;
+    let mut extended_start: Option<usize> = None;
+    let mut pos = 0;
+    while pos + 512 <= data.len() {
+        let start = pos;
+        let header = &data[pos..pos + 512];
+        if header.iter().all(|b| *b == 0) {
+            break;
+        }
+        let size = parse_octal(&header[124..136]).ok_or("corrupt tar header")?;
+        let body_start = pos + 512;
+        let body = data
+            .get(body_start..body_start + size)
+            .ok_or("truncated tar entry")?;
+        pos = body_start + size.div_ceil(512) * 512;
+
+        match header[156] {
+            // GNU long name for the next entry.
+            b'L' => {
+                long_name = Some(field(body));
+                extended_start.get_or_insert(start);
+                continue;
+            }
+            // pax header: only `path` matters here.
+            b'x' => {
+                long_name = pax_path(body);
+                extended_start.get_or_insert(start);
+                continue;
+            }
+            _ => {}
+        }
+        let name = match long_name.take() {
+            Some(name) => name,
+            None => {
+                let name = field(&header[0..100]);
+                let prefix = field(&header[345..500]);
+                if header[257..262] == *b"ustar" && !prefix.is_empty() {
+                    format!("{prefix}/{name}")
+                } else {
+                    name
+                }
+            }
+        };
+        entries.push(TarEntry {
+            name,
+            kind: header[156],
+            extended: &data[extended_start.take().unwrap_or(start)..start],
+            header,
+            body,
+        });
+    }
+    Ok(entries)
+}
+
+/// Regular files in a ustar/pax/GNU tar, as `(path, contents)`.
+fn read_tar(data: &[u8], max_files: usize) -> Result<Vec<(String, String)>, String> {
+    let mut files: Vec<(String, String)> = Vec::new();
+    for entry in tar_entries(data)? {
+        let name = entry.name;
+        match entry.kind {
+            b'0' | 0 => {}
+            b'5' | b'g' => continue,
+            _ => return Err(format!("{name} is not a regular file")),
+        }
+
+        let name =
+            contained_name(&name).ok_or_else(|| format!("{name} escapes the target directory"))?;
+        if files.len() == max_files {
+            return Err(format!("more than {max_files} files"));
+        }
+        let contents = String::from_utf8(entry.body.to_vec())
+            .map_err(|_| format!("{name} is not UTF-8 text"))?;
+        files.push((name, contents));
+    }
+    Ok(files)
+}
+
+/// A NUL-terminated header field.
+fn field(bytes: &[u8]) -> String {
+    let end = bytes.iter().position(|b| *b == 0).unwrap_or(bytes.len());
+    String::from_utf8_lossy(&bytes[..end]).into_owned()
+}
+
+fn parse_octal(bytes: &[u8]) -> Option<usize> {
+    let text = field(bytes);
+    let text = text.trim_matches(|c: char| c == ' ' || c == '\0');
+    if text.is_empty() {
+        return Some(0);
+    }
+    usize::from_str_radix(text, 8).ok()
+}
+
+/// The `path` record of a pax extended header (`<len> path=<value>\n`).
+fn pax_path(body: &[u8]) -> Option<String> {
+    let text = String::from_utf8_lossy(body);
+    text.lines().find_map(|record| {
+        let (_, kv) = record.split_once(' ')?;
+        kv.strip_prefix("path=").map(str::to_string)
+    })
+}
+
+/// `name` without `./` components, or `None` if it is absolute, climbs out
+/// with `..` or is empty.
+pub(crate) fn contained_name(name: &str) -> Option<String> {
+    let mut parts: Vec<String> = Vec::new();
+    for component in Path::new(name).components() {
+        match component {
+            Component::Normal(part) => parts.push(part.to_string_lossy().into_owned()),
+            Component::CurDir => {}
+            Component::ParentDir | Component::RootDir | Component::Prefix(_) => return None,
+        }
+    }
+    (!parts.is_empty()).then(|| parts.join("/"))
+}
+
+fn decode_base64(text: &str) -> Result<Vec<u8>, String> {
+    let mut out: Vec<u8> = Vec::with_capacity(text.len() * 3 / 4);
+    let mut buf = 0u32;
+    let mut bits = 0;
+    for c in text.bytes().filter(|c| !c.is_ascii_whitespace()) {
+        let value = match c {
+            b'A'..=b'Z' => c - b'A',
+            b'a'..=b'z' => c - b'a' + 26,
+            b'0'..=b'9' => c - b'0' + 52,
+            b'+' => 62,
+            b'/' => 63,
+            b'=' => break,
+            _ => return Err("body is not valid base64".to_string()),
+        };
+        buf = (buf << 6) | u32::from(value);
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((buf >> bits) as u8);
+            buf &= (1 << bits) - 1;
+        }
+    }
+    Ok(out)
+}
@@ -0,0 +1,177 @@
+//! Append-only audit log (`audit_log` in the config): one JSON line per
+//! patch invocation describing what was decided and why.
+//!
+//! With `audit.forward_socket` each entry is also sent, as one datagram, to
+//! a Unix socket where a collector (such as `apply_patch audit-collector`)
+//! gathers the events of every agent on the machine. The send never blocks:
+//! when no collector is listening or its queue is full the event is
+//! dropped, and the run goes on as if the socket weren't configured.
+
+use crate::Decision;
+use crate::Mode;
+use crate::branchrules::BranchGuard;
+use crate::clean::CleanGuard;
+use crate::codeowners::CodeownersGuard;
+use crate::diagnostics;
+use crate::dirops::DirDeleteGuard;
+use crate::editor::EditorGuard;
+use crate::expected::ExpectedFilesGuard;
+use crate::fingerprint::Fingerprint;
+use crate::freshness::StaleReadGuard;
+use crate::gitscope::GitBoundaryGuard;
+use crate::jsonl;
+use crate::limits::SizeGuard;
+use crate::lint::LintGuard;
+use crate::newdirs::NewDirGuard;
+use crate::policy::Escalation;
+use crate::policy::PatchFacts;
+use crate::policy::ShadowDecision;
+use crate::reason::RefusalReason;
+use crate::resources::Usage;
+use crate::symlinks::SymlinkGuard;
+use serde::Deserialize;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::path::PathBuf;
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub(crate) struct AuditConfig {
+    /// Unix datagram socket every entry is also sent to.
+    #[serde(default)]
+    pub(crate) forward_socket: Option<PathBuf>,
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct AuditEntry<'a> {
+    pub(crate) timestamp: u64,
+    pub(crate) cwd: Option<String>,
+    /// Mode from the config file, before policies and escalation.
+    pub(crate) base_mode: Mode,
+    /// Mode that was actually enforced.
+    pub(crate) mode: Mode,
+    pub(crate) decision: Decision,
+    pub(crate) files: &'a [String],
+    pub(crate) lines_added: usize,
+    pub(crate) lines_removed: usize,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub(crate) metadata: &'a BTreeMap<String, serde_json::Value>,
+    /// Sections removed by `drop_paths`.
+    #[serde(skip_serializing_if = "<[String]>::is_empty")]
+    pub(crate) dropped: &'a [String],
+    /// Index of the policy rule that selected the mode, if any.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) policy: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) escalation: Option<&'a Escalation>,
+    /// What shadow policy rules and escalation would have enforced.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) shadow: Option<&'a ShadowDecision>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) editor_guard: Option<&'a EditorGuard>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) git_boundaries: Option<&'a GitBoundaryGuard>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) require_clean: Option<&'a CleanGuard>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) stale_reads: Option<&'a StaleReadGuard>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) branch_rule: Option<&'a BranchGuard>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) codeowners: Option<&'a CodeownersGuard>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) symlinks: Option<&'a SymlinkGuard>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) new_dirs: Option<&'a NewDirGuard>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) dir_delete: Option<&'a DirDeleteGuard>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) lint: Option<&'a LintGuard>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) expected_files: Option<&'a ExpectedFilesGuard>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) target_size: Option<&'a SizeGuard>,
+    /// Why the patch was refused, if it was.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) reason: Option<&'a RefusalReason>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) tenant: Option<&'a str>,
+    /// Host, user, repository and branch (see `fingerprint`).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) fingerprint: Option<&'a Fingerprint>,
+    /// What the invocation cost (see `resources`).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) resources: Option<&'a Usage>,
+}
+
+impl<'a> AuditEntry<'a> {
+    pub(crate) fn new(
+        base_mode: Mode,
+        mode: Mode,
+        decision: Decision,
+        facts: &'a PatchFacts,
+    ) -> Self {
+        Self {
+            timestamp: jsonl::timestamp(),
+            cwd: std::env::current_dir()
+                .ok()
+                .map(|p| p.display().to_string()),
+            base_mode,
+            mode,
+            decision,
+            files: &facts.files,
+            lines_added: facts.lines_added,
+            lines_removed: facts.lines_removed,
+            metadata: &facts.metadata,
+            dropped: &[],
+            policy: None,
+            escalation: None,
+            shadow: None,
+            editor_guard: None,
+            git_boundaries: None,
+            require_clean: None,
+            stale_reads: None,
+            branch_rule: None,
+            codeowners: None,
+            symlinks: None,
+            new_dirs: None,
+            dir_delete: None,
+            lint: None,
+            expected_files: None,
+            target_size: None,
+            reason: None,
+            tenant: None,
+            fingerprint: None,
+            resources: None,
+        }
+    }
+}
+
+/// Appends `entry` to the audit log. Failures are reported on stderr but
+/// never change the outcome of the run.
+pub(crate) fn record(path: &Path, entry: &AuditEntry<'_>) {
+    if let Err(err) = jsonl::append(path, entry) {
+        diagnostics::warning(format!(
+            "failed to write audit log {}: {err}",
+            path.display()
+        ));
+    }
+}
+
+/// Sends `entry` to the collector listening on `socket`, if there is one
+/// ready to take it; otherwise the event is dropped.
+#[cfg(unix)]
+pub(crate) fn forward(socket: &Path, entry: &AuditEntry<'_>) {
+    use std::os::unix::net::UnixDatagram;
+    let Ok(line) = serde_json::to_vec(entry) else {
+        return;
+    };
+    if let Ok(sender) = UnixDatagram::unbound()
+        && sender.set_nonblocking(true).is_ok()
+    {
+        let _ = sender.send_to(&line, socket);
+    }
+}
+
+#[cfg(not(unix))]
+pub(crate) fn forward(_socket: &Path, _entry: &AuditEntry<'_>) {}
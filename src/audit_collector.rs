@@ -0,0 +1,136 @@
+//! `apply_patch audit-collector [--socket PATH] [--out FILE]`: the
+//! reference sink for `audit.forward_socket`.
+//!
+//! It binds a Unix datagram socket at PATH (by default the configured
+//! `audit.forward_socket`) and writes every audit event it receives as one
+//! JSON line to FILE, appending, or to stdout. Datagrams that aren't JSON
+//! are reported on stderr and skipped. A socket file left behind by a
+//! collector that is gone is replaced; one with a collector still behind it
+//! is an error. The collector runs until it is killed.
+
+use crate::diagnostics;
+use std::io::Write;
+use std::path::PathBuf;
+
+/// Largest event accepted, in bytes.
+#[cfg(unix)]
+const MAX_EVENT: usize = 256 * 1024;
+
+fn usage() -> i32 {
+    eprintln!("Usage: apply_patch audit-collector [--socket PATH] [--out FILE]");
+    2
+}
+
+pub(crate) fn run_audit_collector(args: &[String]) -> i32 {
+    let mut socket: Option<PathBuf> = None;
+    let mut out: Option<PathBuf> = None;
+    let mut i = 0;
+    while i < args.len() {
+        let target = match args[i].as_str() {
+            "--socket" => &mut socket,
+            "--out" => &mut out,
+            _ => return usage(),
+        };
+        let Some(value) = args.get(i + 1) else {
+            diagnostics::error(format!("{} requires a value.", args[i]));
+            return 2;
+        };
+        *target = Some(PathBuf::from(value));
+        i += 2;
+    }
+    let socket = match socket {
+        Some(socket) => socket,
+        None => match crate::effective_config(&crate::RunOptions::default()) {
+            Ok(cfg) => match cfg.audit.forward_socket {
+                Some(socket) => socket,
+                None => {
+                    diagnostics::error(
+                        "no socket to listen on: pass --socket or set audit.forward_socket in the config.",
+                    );
+                    return 2;
+                }
+            },
+            Err(err) => {
+                diagnostics::error(err);
+                return 1;
+            }
+        },
+    };
+    let mut sink: Box<dyn Write> = match &out {
+        Some(path) => {
+            let file = std::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path);
+            match file {
+                Ok(file) => Box::new(file),
+                Err(err) => {
+                    diagnostics::error(format!("failed to open {}: {err}", path.display()));
+                    return 1;
+                }
+            }
+        }
+        None => Box::new(std::io::stdout()),
+    };
+    match collect(&socket, &mut *sink) {
+        Ok(()) => 0,
+        Err(err) => {
+            diagnostics::error(err);
+            1
+        }
+    }
+}
+
+#[cfg(unix)]
+fn bind(socket: &std::path::Path) -> Result<std::os::unix::net::UnixDatagram, String> {
+    use std::os::unix::fs::FileTypeExt;
+    use std::os::unix::net::UnixDatagram;
+    if let Ok(meta) = std::fs::symlink_metadata(socket) {
+        if !meta.file_type().is_socket() {
+            return Err(format!("{} exists and is not a socket", socket.display()));
+        }
+        let live = UnixDatagram::unbound().is_ok_and(|probe| probe.connect(socket).is_ok());
+        if live {
+            return Err(format!(
+                "another collector is already listening on {}",
+                socket.display()
+            ));
+        }
+        std::fs::remove_file(socket)
+            .map_err(|err| format!("failed to remove the stale {}: {err}", socket.display()))?;
+    }
+    if let Some(parent) = socket.parent().filter(|p| !p.as_os_str().is_empty()) {
+        std::fs::create_dir_all(parent)
+            .map_err(|err| format!("failed to create {}: {err}", parent.display()))?;
+    }
+    UnixDatagram::bind(socket)
+        .map_err(|err| format!("cannot listen on {}: {err}", socket.display()))
+}
+
+#[cfg(unix)]
+fn collect(socket: &std::path::Path, sink: &mut dyn Write) -> Result<(), String> {
+    let listener = bind(socket)?;
+    eprintln!("Collecting audit events on {}", socket.display());
+    let mut buf = vec![0; MAX_EVENT];
+    loop {
+        let len = match listener.recv(&mut buf) {
+            Ok(len) => len,
+            Err(err) if err.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(err) => return Err(format!("failed to receive an event: {err}")),
+        };
+        let event = &buf[..len];
+        if serde_json::from_slice::<serde_json::Value>(event).is_err() {
+            eprintln!("Skipped a {len}-byte datagram that is not a JSON audit event.");
+            continue;
+        }
+        sink.write_all(event)
+            .and_then(|()| sink.write_all(b"\n"))
+            .and_then(|()| sink.flush())
+            .map_err(|err| format!("failed to write an event: {err}"))?;
+    }
+}
+
+#[cfg(not(unix))]
+fn collect(_socket: &std::path::Path, _sink: &mut dyn Write) -> Result<(), String> {
+    Err("audit-collector needs Unix domain sockets".to_string())
+}
@@ -0,0 +1,299 @@
+//! `apply_patch batch MANIFEST`: applies several patches in order, each in
+//! its own directory with its own options, for orchestrators replaying an
+//! agent session.
+//!
+//! The manifest is JSON, or YAML (the subset in `yaml.rs`) when it doesn't
+//! start with `{`:
+//!
+//! ```json
+//! {
+//!   "on_error": "stop",
+//!   "patches": [
+//!     { "patch": "01.patch", "cwd": "service-a" },
+//!     { "name": "docs", "patch_text": "*** Begin Patch\n...", "args": ["--dry-run"] }
+//!   ]
+//! }
+//! ```
+//!
+//! Each entry is run as the CLI would run it, with the patch on stdin, so
+//! modes, policies and guards apply per patch. Children run with
+//! `APPLY_PATCH_EXIT_CODES=detailed` so a refusal, which exits 0 by
+//! default, is reported as `refused` rather than `applied`; an entry whose
+//! `args` include `--no-config` ignores that variable, so its refusals
+//! still count as applied. Relative paths are resolved
+//! against the manifest's directory, which is also the default `cwd`. With
+//! `on_error: stop` (the default, or `--fail-fast`) the first failure or
+//! refusal skips the rest; `continue` (or `--continue-on-error`) runs every
+//! entry.
+
+use crate::diagnostics;
+use crate::exit;
+use crate::exit::Exit;
+use serde::Deserialize;
+use serde::Serialize;
+use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
+use std::process::Command;
+use std::process::Stdio;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+enum OnError {
+    #[default]
+    Stop,
+    Continue,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Manifest {
+    #[serde(default)]
+    on_error: OnError,
+    patches: Vec<Entry>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Entry {
+    #[serde(default)]
+    name: Option<String>,
+    /// A patch file.
+    #[serde(default)]
+    patch: Option<PathBuf>,
+    /// The patch itself, instead of `patch`.
+    #[serde(default)]
+    patch_text: Option<String>,
+    #[serde(default)]
+    cwd: Option<PathBuf>,
+    /// Extra CLI options, e.g. `["--dry-run"]`.
+    #[serde(default)]
+    args: Vec<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+enum Status {
+    Applied,
+    Refused,
+    Failed,
+    Skipped,
+}
+
+#[derive(Debug, Serialize)]
+struct Outcome {
+    name: String,
+    cwd: String,
+    status: Status,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    exit_code: Option<i32>,
+    stdout: String,
+    stderr: String,
+}
+
+#[derive(Debug, Serialize)]
+struct Report<'a> {
+    ok: bool,
+    patches: &'a [Outcome],
+}
+
+pub(crate) fn run_batch(args: &[String]) -> i32 {
+    let mut manifest_path: Option<&str> = None;
+    let mut on_error: Option<OnError> = None;
+    let mut json = false;
+    for arg in args {
+        match arg.as_str() {
+            "--fail-fast" => on_error = Some(OnError::Stop),
+            "--continue-on-error" => on_error = Some(OnError::Continue),
+            "--json" => json = true,
+            other if other.starts_with('-') => {
+                diagnostics::error(format!("unknown option: {other}"));
+                return 2;
+            }
+            path if manifest_path.is_none() => manifest_path = Some(path),
+            _ => {
+                eprintln!(
+                    "Usage: apply_patch batch [--fail-fast|--continue-on-error] [--json] MANIFEST"
+                );
+                return 2;
+            }
+        }
+    }
+    let Some(manifest_path) = manifest_path else {
+        eprintln!("Usage: apply_patch batch [--fail-fast|--continue-on-error] [--json] MANIFEST");
+        return 2;
+    };
+    let manifest_path = Path::new(manifest_path);
+    let manifest: Manifest = match std::fs::read_to_string(manifest_path)
+        .map_err(|err| err.to_string())
+        .and_then(|text| {
+            if text.trim_start().starts_with('{') {
+                serde_json::from_str(&text).map_err(|err| err.to_string())
+            } else {
+                crate::yaml::parse(&text)
+                    .and_then(|value| serde_json::from_value(value).map_err(|err| err.to_string()))
+            }
+        }) {
+        Ok(manifest) => manifest,
+        Err(err) => {
+            diagnostics::error(format!(
+                "invalid batch manifest {}: {err}",
+                manifest_path.display()
+            ));
+            return 2;
+        }
+    };
+    let base = manifest_path
+        .parent()
+        .filter(|p| !p.as_os_str().is_empty())
+        .unwrap_or(Path::new("."));
+    let on_error = on_error.unwrap_or(manifest.on_error);
+    let exe = match std::env::current_exe() {
+        Ok(exe) => exe,
+        Err(err) => {
+            diagnostics::error(format!("cannot locate apply_patch: {err}"));
+            return 1;
+        }
+    };
+
+    let total = manifest.patches.len();
+    let mut outcomes: Vec<Outcome> = Vec::new();
+    let mut first_failure: Option<i32> = None;
+    for (i, entry) in manifest.patches.iter().enumerate() {
+        let name = entry.name.clone().unwrap_or_else(|| match &entry.patch {
+            Some(path) => path.display().to_string(),
+            None => format!("#{}", i + 1),
+        });
+        let cwd = entry
+            .cwd
+            .as_ref()
+            .map_or(base.to_path_buf(), |dir| base.join(dir));
+        let mut outcome = Outcome {
+            name,
+            cwd: cwd.display().to_string(),
+            status: Status::Skipped,
+            exit_code: None,
+            stdout: String::new(),
+            stderr: String::new(),
+        };
+        if first_failure.is_none() || on_error == OnError::Continue {
+            let patch = if cwd.is_dir() {
+                patch_text(entry, base)
+            } else {
+                Err(format!("{} is not a directory.", cwd.display()))
+            };
+            let (code, stdout, stderr) = match patch {
+                Ok(text) => run_one(&exe, &entry.args, &cwd, &text),
+                Err(err) => (2, String::new(), format!("Error: {err}\n")),
+            };
+            let (status, code) = match exit::from_detailed(code) {
+                Some(Exit::Applied | Exit::AppliedWithWarning) => (Status::Applied, 0),
+                Some(refusal @ (Exit::RefusedByMode | Exit::RefusedByPolicy)) => {
+                    (Status::Refused, refusal.code())
+                }
+                Some(failure) => (Status::Failed, failure.code()),
+                None => (Status::Failed, code),
+            };
+            outcome.status = status;
+            outcome.exit_code = Some(code);
+            outcome.stdout = stdout;
+            outcome.stderr = stderr;
+            if status != Status::Applied && first_failure.is_none() {
+                first_failure = Some(code);
+            }
+        }
+        if !json {
+            print_outcome(&outcome, i + 1, total);
+        }
+        outcomes.push(outcome);
+    }
+
+    let count = |status: Status| outcomes.iter().filter(|o| o.status == status).count();
+    if json {
+        match serde_json::to_string(&Report {
+            ok: first_failure.is_none(),
+            patches: &outcomes,
+        }) {
+            Ok(line) => println!("{line}"),
+            Err(err) => {
+                diagnostics::error(format!("failed to serialize batch report: {err}"));
+                return 1;
+            }
+        }
+    } else {
+        println!(
+            "Batch: {} applied, {} refused, {} failed, {} skipped.",
+            count(Status::Applied),
+            count(Status::Refused),
+            count(Status::Failed),
+            count(Status::Skipped)
+        );
+    }
+    // Refusals exit 0 under the default codes; a batch that stopped or
+    // left a patch out still fails.
+    match first_failure {
+        None => 0,
+        Some(0) => 1,
+        Some(code) => code,
+    }
+}
+
+fn patch_text(entry: &Entry, base: &Path) -> Result<String, String> {
+    match (&entry.patch, &entry.patch_text) {
+        (Some(path), None) => {
+            let path = base.join(path);
+            std::fs::read_to_string(&path)
+                .map_err(|err| format!("failed to read {}: {err}", path.display()))
+        }
+        (None, Some(text)) => Ok(text.clone()),
+        _ => Err("each batch entry needs exactly one of `patch` and `patch_text`.".to_string()),
+    }
+}
+
+/// Runs apply_patch with `args` in `cwd`, with `patch` on stdin.
+fn run_one(exe: &Path, args: &[String], cwd: &Path, patch: &str) -> (i32, String, String) {
+    let child = Command::new(exe)
+        .args(args)
+        .current_dir(cwd)
+        .env(exit::EXIT_CODES_ENV, "detailed")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .and_then(|mut child| {
+            if let Some(mut input) = child.stdin.take() {
+                // The run may exit before reading a patch it refuses early.
+                let _ = input.write_all(patch.as_bytes());
+            }
+            child.wait_with_output()
+        });
+    match child {
+        Ok(output) => (
+            output.status.code().unwrap_or(1),
+            String::from_utf8_lossy(&output.stdout).into_owned(),
+            String::from_utf8_lossy(&output.stderr).into_owned(),
+        ),
+        Err(err) => (
+            1,
+            String::new(),
+            format!("Error: failed to run {}: {err}\n", exe.display()),
+        ),
+    }
+}
+
+fn print_outcome(outcome: &Outcome, n: usize, total: usize) {
+    let status = match (outcome.status, outcome.exit_code) {
+        (Status::Applied, _) => "applied".to_string(),
+        (Status::Refused, _) => "refused".to_string(),
+        (Status::Failed, Some(code)) => format!("failed (exit {code})"),
+        (Status::Failed, None) => "failed".to_string(),
+        (Status::Skipped, _) => "skipped".to_string(),
+    };
+    println!(
+        "== [{n}/{total}] {} in {}: {status}",
+        outcome.name, outcome.cwd
+    );
+    for line in outcome.stdout.lines().chain(outcome.stderr.lines()) {
+        println!("   {line}");
+    }
+}
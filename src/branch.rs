@@ -0,0 +1,197 @@
+//! `--to-branch NAME`: apply a patch on a new branch instead of the
+//! working tree.
+//!
+//! The patch goes through the usual pipeline inside a temporary git
+//! worktree checked out at `HEAD` on the new branch, is committed there,
+//! and the worktree is removed again, so the current checkout is never
+//! touched and the branch is ready to push for review. `--open-pr` then
+//! pushes it and opens a pull request (see `pullrequest`).
+
+use crate::Config;
+use crate::Decision;
+use crate::Outcome;
+use crate::RunOptions;
+use crate::diagnostics;
+use crate::exit::Exit;
+use crate::patch::Patch;
+use crate::pullrequest;
+use crate::trailers;
+use std::path::Path;
+use std::path::PathBuf;
+use std::process::Command;
+
+/// Identity used for the commit when the repository has none configured.
+pub(crate) const FALLBACK_NAME: &str = "apply_patch";
+pub(crate) const FALLBACK_EMAIL: &str = "apply_patch@localhost";
+
+pub(crate) fn apply_to_branch(
+    cfg: &Config,
+    patch_arg: &str,
+    options: &RunOptions,
+    name: &str,
+) -> i32 {
+    match run(cfg, patch_arg, options, name) {
+        Ok(outcome) => outcome.code,
+        Err(msg) => {
+            diagnostics::error(msg);
+            1
+        }
+    }
+}
+
+fn run(cfg: &Config, patch_arg: &str, options: &RunOptions, name: &str) -> Result<Outcome, String> {
+    let cwd = std::env::current_dir()
+        .map_err(|err| format!("cannot read the current directory: {err}"))?;
+    let top = PathBuf::from(git(&cwd, &["rev-parse", "--show-toplevel"])?);
+    let prefix = git(&cwd, &["rev-parse", "--show-prefix"])?;
+    git(&cwd, &["check-ref-format", "--branch", name])
+        .map_err(|_| format!("invalid branch name: {name}"))?;
+    if git(
+        &cwd,
+        &[
+            "rev-parse",
+            "--verify",
+            "--quiet",
+            &format!("refs/heads/{name}"),
+        ],
+    )
+    .is_ok()
+    {
+        return Err(format!("branch {name} already exists"));
+    }
+    let base = match &cfg.pull_request.base {
+        Some(base) => base.clone(),
+        None => git(&cwd, &["rev-parse", "--abbrev-ref", "HEAD"])?,
+    };
+    if options.open_pr && base == "HEAD" {
+        return Err(
+            "cannot open a pull request from a detached HEAD; set pull_request.base".to_string(),
+        );
+    }
+
+    let worktree = std::env::temp_dir().join(format!(
+        "apply_patch-worktree-{}-{}",
+        std::process::id(),
+        crate::jsonl::timestamp()
+    ));
+    let worktree_arg = worktree.to_string_lossy().into_owned();
+    git(
+        &top,
+        &[
+            "worktree",
+            "add",
+            "--quiet",
+            "-b",
+            name,
+            &worktree_arg,
+            "HEAD",
+        ],
+    )?;
+
+    let mut result = apply_and_commit(cfg, patch_arg, options, &worktree, &prefix, name);
+
+    // Always restore the caller's directory and drop the worktree; the branch
+    // only survives when the commit succeeded.
+    let _ = std::env::set_current_dir(&cwd);
+    let _ = git(&top, &["worktree", "remove", "--force", &worktree_arg]);
+    match result.as_ref().map(|outcome| outcome.decision) {
+        Ok(Decision::Applied | Decision::Partial) => {}
+        _ => {
+            let _ = git(&top, &["branch", "-D", name]);
+        }
+    }
+    if options.open_pr
+        && let Ok(outcome) = &mut result
+        && matches!(outcome.decision, Decision::Applied | Decision::Partial)
+    {
+        let message = commit_message(patch_arg);
+        let (title, body) = message.split_once("\n\n").unwrap_or((&message, ""));
+        match pullrequest::open(&cfg.pull_request, &top, name, &base, title, body.trim_end()) {
+            Ok(url) => println!("Opened pull request: {url}"),
+            // The commit stays on the branch; only the review step failed.
+            Err(msg) => {
+                diagnostics::error(msg);
+                outcome.code = Exit::Failed.code();
+            }
+        }
+    }
+    result
+}
+
+fn apply_and_commit(
+    cfg: &Config,
+    patch_arg: &str,
+    options: &RunOptions,
+    worktree: &Path,
+    prefix: &str,
+    name: &str,
+) -> Result<Outcome, String> {
+    let workdir = worktree.join(prefix);
+    std::env::set_current_dir(&workdir)
+        .map_err(|err| format!("cannot enter worktree {}: {err}", workdir.display()))?;
+    // Errors were already reported; keep their exit code.
+    let outcome = match crate::process_patch(cfg, patch_arg, options) {
+        Ok(outcome) => outcome,
+        Err(code) => Outcome {
+            decision: Decision::Failed,
+            code,
+        },
+    };
+    if !matches!(outcome.decision, Decision::Applied | Decision::Partial) {
+        return Ok(outcome);
+    }
+
+    git(worktree, &["add", "-A"])?;
+    let mut commit: Vec<String> = Vec::new();
+    if git(worktree, &["config", "user.email"]).is_err() {
+        commit.extend(["-c".into(), format!("user.name={FALLBACK_NAME}")]);
+        commit.extend(["-c".into(), format!("user.email={FALLBACK_EMAIL}")]);
+    }
+    commit.extend([
+        "commit".into(),
+        "--quiet".into(),
+        "-m".into(),
+        commit_message(patch_arg),
+    ]);
+    let args: Vec<&str> = commit.iter().map(String::as_str).collect();
+    git(worktree, &args)?;
+    let hash = git(worktree, &["rev-parse", "--short", "HEAD"])?;
+    println!("Committed {hash} to branch {name}.");
+    Ok(outcome)
+}
+
+/// The commit message for `patch_arg`, ending with its trailers.
+fn commit_message(patch_arg: &str) -> String {
+    let (patch_arg, trailers) = trailers::strip(patch_arg);
+    let mut message = match Patch::parse(&patch_arg) {
+        Some(patch) => format!(
+            "Apply patch to {} file(s)\n\n{}",
+            patch.sections.len(),
+            patch.summary()
+        ),
+        None => "Apply patch".to_string(),
+    };
+    if !trailers.is_empty() {
+        message = format!(
+            "{}\n\n{}",
+            message.trim_end(),
+            trailers::commit_block(&trailers)
+        );
+    }
+    message
+}
+
+/// Runs git in `dir` and returns its trimmed stdout, or stderr as the error.
+fn git(dir: &Path, args: &[&str]) -> Result<String, String> {
+    let output = Command::new("git")
+        .current_dir(dir)
+        .args(args)
+        .output()
+        .map_err(|err| format!("failed to run git: {err}"))?;
+    if output.status.success() {
+        Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+    } else {
+        let stderr = String::from_utf8_lossy(&output.stderr).trim().to_string();
+        Err(format!("git {} failed: {stderr}", args.join(" ")))
+    }
+}
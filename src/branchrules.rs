@@ -0,0 +1,70 @@
+//! Branch rules (`branch_rules` in the config): a mode per git branch, so
+//! agents can patch freely on feature branches but never directly on
+//! protected ones.
+//!
+//! The current branch comes from `git rev-parse --abbrev-ref HEAD` (`HEAD`
+//! when detached). The first rule whose glob matches the whole branch name
+//! applies; like the other guards it can only raise the mode. Outside a
+//! repository, or without git, no rule applies.
+
+use crate::Mode;
+use crate::glob::glob_match_whole;
+use crate::policy::severity;
+use serde::Deserialize;
+use serde::Serialize;
+use std::process::Command;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct BranchRule {
+    /// Glob over the branch name, e.g. `main` or `release/*`.
+    pub(crate) branch: String,
+    pub(crate) mode: Mode,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct BranchGuard {
+    pub(crate) branch: String,
+    /// Index of the matching rule.
+    pub(crate) rule: usize,
+    pub(crate) to: Mode,
+}
+
+impl BranchGuard {
+    pub(crate) fn describe(&self, rules: &[BranchRule]) -> String {
+        format!(
+            "Branch rule ({} mode): the working tree is on {}, matched by branch_rules[{}] ({}).",
+            self.to.as_str(),
+            self.branch,
+            self.rule,
+            rules[self.rule].branch
+        )
+    }
+}
+
+/// Applies the first rule matching the current branch. Returns `None` when
+/// there are no rules, git isn't usable here, no rule matches, or the
+/// matching rule doesn't raise `mode`.
+pub(crate) fn guard(rules: &[BranchRule], mode: Mode) -> Option<BranchGuard> {
+    if rules.is_empty() {
+        return None;
+    }
+    let branch = current_branch()?;
+    let (rule, matched) = rules
+        .iter()
+        .enumerate()
+        .find(|(_, rule)| glob_match_whole(&rule.branch, &branch))?;
+    (severity(matched.mode) > severity(mode)).then_some(BranchGuard {
+        branch,
+        rule,
+        to: matched.mode,
+    })
+}
+
+fn current_branch() -> Option<String> {
+    let output = Command::new("git")
+        .args(["rev-parse", "--abbrev-ref", "HEAD"])
+        .output()
+        .ok()?;
+    let branch = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    (output.status.success() && !branch.is_empty()).then_some(branch)
+}
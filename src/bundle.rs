@@ -0,0 +1,478 @@
+//! `--archive FILE`: apply a patch to the files inside a zip or tar
+//! (optionally gzip-compressed) archive instead of the working tree.
+//!
+//! The archive is read into memory, the patch is simulated against its
+//! entries, and the archive is written back in place (or to
+//! `--archive-out`) with only the touched entries replaced. Nothing is
+//! extracted to disk, and untouched entries are copied as they are. Changed
+//! and added entries are stored uncompressed, and a `.tar.gz` is written
+//! back as gzip with stored blocks, so the archive may grow.
+
+use crate::archive::TarEntry;
+use crate::archive::contained_name;
+use crate::archive::tar_entries;
+use crate::exit;
+use crate::inflate::crc32;
+use crate::inflate::gunzip;
+use crate::inflate::inflate_raw;
+use crate::patch::Patch;
+use crate::simulate::FileChange;
+use crate::simulate::simulate_with;
+use std::collections::HashMap;
+use std::path::Path;
+
+const ZIP_LOCAL_SIG: u32 = 0x0403_4b50;
+const ZIP_CENTRAL_SIG: u32 = 0x0201_4b50;
+const ZIP_END_SIG: u32 = 0x0605_4b50;
+
+enum Entries<'a> {
+    Zip(Vec<ZipEntry<'a>>),
+    Tar(Vec<TarEntry<'a>>),
+}
+
+struct ZipEntry<'a> {
+    name: String,
+    version_made_by: u16,
+    flags: u16,
+    method: u16,
+    time: u16,
+    date: u16,
+    crc: u32,
+    size: u32,
+    external_attr: u32,
+    data: &'a [u8],
+}
+
+impl ZipEntry<'_> {
+    fn contents(&self) -> Result<Vec<u8>, String> {
+        if self.flags & 1 != 0 {
+            return Err(format!("{} is encrypted", self.name));
+        }
+        match self.method {
+            0 => Ok(self.data.to_vec()),
+            8 => inflate_raw(self.data, self.size as usize),
+            method => Err(format!(
+                "{} uses unsupported compression method {method}",
+                self.name
+            )),
+        }
+    }
+}
+
+/// Applies `patch_arg` to the files in `archive`, writing the result to
+/// `out` (or back to `archive`). Errors are printed; `Err` carries their
+/// kind and message.
+pub(crate) fn apply(
+    archive: &Path,
+    out: Option<&Path>,
+    patch_arg: &str,
+    dry_run: bool,
+) -> Result<(), (exit::Exit, String)> {
+    let result = run(archive, out, patch_arg, dry_run);
+    if let Err((_, msg)) = &result {
+        eprintln!("{msg}");
+    }
+    result
+}
+
+fn run(
+    archive: &Path,
+    out: Option<&Path>,
+    patch_arg: &str,
+    dry_run: bool,
+) -> Result<(), (exit::Exit, String)> {
+    let failed = |msg: String| (exit::Exit::Failed, msg);
+    let Some(patch) = Patch::parse(patch_arg) else {
+        return Err((
+            exit::Exit::ParseError,
+            "Invalid patch: The first line of the patch must be '*** Begin Patch'".to_string(),
+        ));
+    };
+    let data = std::fs::read(archive)
+        .map_err(|err| failed(format!("Error: cannot read {}: {err}", archive.display())))?;
+    let unreadable = |msg: String| failed(format!("Error: {}: {msg}", archive.display()));
+    let gzipped = data.starts_with(&[0x1f, 0x8b]);
+    let tar = if gzipped {
+        gunzip(&data, usize::MAX).map_err(unreadable)?
+    } else {
+        Vec::new()
+    };
+    let entries = if data.starts_with(b"PK\x03\x04") || data.starts_with(b"PK\x05\x06") {
+        Entries::Zip(zip_entries(&data).map_err(unreadable)?)
+    } else if gzipped {
+        Entries::Tar(tar_entries(&tar).map_err(unreadable)?)
+    } else {
+        Entries::Tar(tar_entries(&data).map_err(unreadable)?)
+    };
+
+    // Starting contents of every path the patch touches that the archive has.
+    let mut base: HashMap<String, String> = HashMap::new();
+    for path in patch.touched_paths() {
+        if contained_name(&path).as_deref() != Some(path.as_str()) {
+            return Err(unreadable(format!("{path} is outside the archive")));
+        }
+        let contents = match &entries {
+            Entries::Zip(entries) => entries
+                .iter()
+                .find(|entry| contained_name(&entry.name).as_deref() == Some(path.as_str()))
+                .map(|entry| {
+                    if entry.name.ends_with('/') {
+                        return Err(format!("{path} is not a regular file"));
+                    }
+                    entry.contents()
+                }),
+            Entries::Tar(entries) => entries
+                .iter()
+                .find(|entry| contained_name(&entry.name).as_deref() == Some(path.as_str()))
+                .map(|entry| match entry.kind {
+                    b'0' | 0 => Ok(entry.body.to_vec()),
+                    _ => Err(format!("{path} is not a regular file")),
+                }),
+        };
+        let Some(contents) = contents.transpose().map_err(unreadable)? else {
+            continue;
+        };
+        let text = String::from_utf8(contents)
+            .map_err(|_| unreadable(format!("{path} is not UTF-8 text")))?;
+        base.insert(path, text);
+    }
+    let changes = simulate_with(&patch, |path| base.get(path).cloned())
+        .map_err(|err| (exit::Exit::ContextMismatch, err))?;
+
+    let target = out.unwrap_or(archive);
+    if dry_run {
+        println!(
+            "Dry run: nothing was written. The patch would update the following files in {}:",
+            target.display()
+        );
+    } else {
+        let rebuilt = match &entries {
+            Entries::Zip(entries) => write_zip(entries, &changes).map_err(unreadable)?,
+            Entries::Tar(entries) if gzipped => gzip_stored(&write_tar(entries, &changes)),
+            Entries::Tar(entries) => write_tar(entries, &changes),
+        };
+        let mut tmp = target.as_os_str().to_owned();
+        tmp.push(".tmp");
+        std::fs::write(&tmp, rebuilt)
+            .and_then(|()| std::fs::rename(&tmp, target))
+            .map_err(|err| failed(format!("Error: cannot write {}: {err}", target.display())))?;
+        println!(
+            "Success. Updated the following files in {}:",
+            target.display()
+        );
+    }
+    for change in &changes {
+        println!("{} {}", change.status(), change.path);
+    }
+    Ok(())
+}
+
+/// New contents by path (`None` for deleted files), and the paths that
+/// aren't in the archive yet, in patch order.
+fn updates(
+    changes: &[FileChange],
+    present: impl Fn(&str) -> bool,
+) -> (HashMap<&str, Option<&str>>, Vec<&FileChange>) {
+    let by_path = changes
+        .iter()
+        .map(|change| (change.path.as_str(), change.after.as_deref()))
+        .collect();
+    let added = changes
+        .iter()
+        .filter(|change| change.after.is_some() && !present(&change.path))
+        .collect();
+    (by_path, added)
+}
+
+fn write_tar(entries: &[TarEntry<'_>], changes: &[FileChange]) -> Vec<u8> {
+    let present = |path: &str| {
+        entries
+            .iter()
+            .any(|entry| contained_name(&entry.name).as_deref() == Some(path))
+    };
+    let (mut by_path, added) = updates(changes, present);
+    let mtime = crate::jsonl::timestamp();
+    let mut out: Vec<u8> = Vec::new();
+    for entry in entries {
+        let update = contained_name(&entry.name).and_then(|name| by_path.remove(name.as_str()));
+        match update {
+            None => {
+                out.extend_from_slice(entry.extended);
+                out.extend_from_slice(entry.header);
+                push_padded(&mut out, entry.body);
+            }
+            Some(None) => {}
+            Some(Some(contents)) => {
+                let mut header = entry.header.to_vec();
+                octal(&mut header[124..136], contents.len() as u64);
+                octal(&mut header[136..148], mtime);
+                checksum(&mut header);
+                out.extend_from_slice(entry.extended);
+                out.extend_from_slice(&header);
+                push_padded(&mut out, contents.as_bytes());
+            }
+        }
+    }
+    for change in added {
+        let contents = change.after.as_deref().unwrap_or_default();
+        let name = change.path.as_bytes();
+        if name.len() > 100 {
+            // GNU long name, for paths that don't fit the header.
+            let mut long_name = name.to_vec();
+            long_name.push(0);
+            out.extend_from_slice(&tar_header(b"././@LongLink", b'L', long_name.len(), mtime));
+            push_padded(&mut out, &long_name);
+        }
+        out.extend_from_slice(&tar_header(
+            &name[..name.len().min(100)],
+            b'0',
+            contents.len(),
+            mtime,
+        ));
+        push_padded(&mut out, contents.as_bytes());
+    }
+    out.extend_from_slice(&[0; 1024]);
+    out
+}
+
+fn tar_header(name: &[u8], kind: u8, size: usize, mtime: u64) -> [u8; 512] {
+    let mut header = [0u8; 512];
+    header[..name.len()].copy_from_slice(name);
+    octal(&mut header[100..108], 0o644);
+    octal(&mut header[108..116], 0);
+    octal(&mut header[116..124], 0);
+    octal(&mut header[124..136], size as u64);
+    octal(&mut header[136..148], mtime);
+    header[156] = kind;
+    header[257..263].copy_from_slice(b"ustar\0");
+    header[263..265].copy_from_slice(b"00");
+    checksum(&mut header);
+    header
+}
+
+/// Writes `value` as zero-padded octal followed by a NUL.
+fn octal(field: &mut [u8], value: u64) {
+    let width = field.len() - 1;
+    field[..width].copy_from_slice(format!("{value:0width$o}").as_bytes());
+    field[width] = 0;
+}
+
+fn checksum(header: &mut [u8]) {
+    header[148..156].fill(b' ');
+    let sum: u32 = header.iter().map(|b| u32::from(*b)).sum();
+    header[148..156].copy_from_slice(format!("{sum:06o}\0 ").as_bytes());
+}
+
+fn push_padded(out: &mut Vec<u8>, body: &[u8]) {
+    out.extend_from_slice(body);
+    out.resize(out.len() + body.len().next_multiple_of(512) - body.len(), 0);
+}
+
+/// A gzip member holding `data` in stored (uncompressed) DEFLATE blocks.
+fn gzip_stored(data: &[u8]) -> Vec<u8> {
+    let mut out = vec![0x1f, 0x8b, 8, 0, 0, 0, 0, 0, 0, 0xff];
+    let mut blocks = data.chunks(usize::from(u16::MAX)).peekable();
+    if blocks.peek().is_none() {
+        out.extend_from_slice(&[1, 0, 0, 0xff, 0xff]);
+    }
+    while let Some(block) = blocks.next() {
+        let len = block.len() as u16;
+        out.push(u8::from(blocks.peek().is_none()));
+        out.extend_from_slice(&len.to_le_bytes());
+        out.extend_from_slice(&(!len).to_le_bytes());
+        out.extend_from_slice(block);
+    }
+    out.extend_from_slice(&crc32(data).to_le_bytes());
+    out.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    out
+}
+
+fn zip_entries(data: &[u8]) -> Result<Vec<ZipEntry<'_>>, String> {
+    let truncated = || "truncated zip archive".to_string();
+    // The end record sits in the last 64 KiB + 22 bytes, before the comment.
+    let end = (data.len().saturating_sub(u16::MAX as usize + 22)..=data.len().saturating_sub(22))
+        .rev()
+        .find(|pos| le32(data, *pos) == Some(ZIP_END_SIG))
+        .ok_or("not a zip archive")?;
+    let count = le16(data, end + 10).ok_or_else(truncated)?;
+    let mut pos = le32(data, end + 16).ok_or_else(truncated)? as usize;
+    if count == u16::MAX || pos == u32::MAX as usize {
+        return Err("zip64 archives are not supported".to_string());
+    }
+    let mut entries = Vec::with_capacity(usize::from(count));
+    for _ in 0..count {
+        let field16 = |offset: usize| le16(data, pos + offset).ok_or_else(truncated);
+        let field32 = |offset: usize| le32(data, pos + offset).ok_or_else(truncated);
+        if field32(0)? != ZIP_CENTRAL_SIG {
+            return Err("corrupt zip central directory".to_string());
+        }
+        let name_len = usize::from(field16(28)?);
+        let extra_len = usize::from(field16(30)?);
+        let comment_len = usize::from(field16(32)?);
+        let compressed = field32(20)? as usize;
+        let local = field32(42)? as usize;
+        let name = data
+            .get(pos + 46..pos + 46 + name_len)
+            .ok_or_else(truncated)?;
+        if le32(data, local) != Some(ZIP_LOCAL_SIG) {
+            return Err("corrupt zip local header".to_string());
+        }
+        let data_start = local
+            + 30
+            + usize::from(le16(data, local + 26).ok_or_else(truncated)?)
+            + usize::from(le16(data, local + 28).ok_or_else(truncated)?);
+        entries.push(ZipEntry {
+            name: String::from_utf8_lossy(name).into_owned(),
+            version_made_by: field16(4)?,
+            flags: field16(8)?,
+            method: field16(10)?,
+            time: field16(12)?,
+            date: field16(14)?,
+            crc: field32(16)?,
+            size: field32(24)?,
+            external_attr: field32(38)?,
+            data: data
+                .get(data_start..data_start + compressed)
+                .ok_or_else(truncated)?,
+        });
+        pos += 46 + name_len + extra_len + comment_len;
+    }
+    Ok(entries)
+}
+
+fn write_zip(entries: &[ZipEntry<'_>], changes: &[FileChange]) -> Result<Vec<u8>, String> {
+    let present = |path: &str| {
+        entries
+            .iter()
+            .any(|entry| contained_name(&entry.name).as_deref() == Some(path))
+    };
+    let (mut by_path, added) = updates(changes, present);
+    let (time, date) = dos_now();
+    let stored = |name: &str, contents: &str, version_made_by: u16, external_attr: u32| ZipEntry {
+        name: name.to_string(),
+        version_made_by,
+        // Names are UTF-8.
+        flags: 0x0800,
+        method: 0,
+        time,
+        date,
+        crc: crc32(contents.as_bytes()),
+        size: contents.len() as u32,
+        external_attr,
+        data: &[],
+    };
+
+    let mut written: Vec<(ZipEntry<'_>, &[u8])> = Vec::new();
+    for entry in entries {
+        let update = contained_name(&entry.name).and_then(|name| by_path.remove(name.as_str()));
+        match update {
+            None => written.push((
+                ZipEntry {
+                    name: entry.name.clone(),
+                    // Sizes are known up front, so no data descriptor.
+                    flags: entry.flags & !0x0008,
+                    ..*entry
+                },
+                entry.data,
+            )),
+            Some(None) => {}
+            Some(Some(contents)) => written.push((
+                stored(
+                    &entry.name,
+                    contents,
+                    entry.version_made_by,
+                    entry.external_attr,
+                ),
+                contents.as_bytes(),
+            )),
+        }
+    }
+    for change in added {
+        let contents = change.after.as_deref().unwrap_or_default();
+        // Unix, spec version 2.0, regular file with mode 0644.
+        let entry = stored(&change.path, contents, 0x0314, 0o100_644 << 16);
+        written.push((entry, contents.as_bytes()));
+    }
+    if written.len() >= usize::from(u16::MAX) {
+        return Err("too many entries for a zip archive without zip64".to_string());
+    }
+
+    let mut out: Vec<u8> = Vec::new();
+    let mut central: Vec<u8> = Vec::new();
+    for (entry, data) in &written {
+        let offset = u32::try_from(out.len())
+            .map_err(|_| "archive too large for zip without zip64".to_string())?;
+        let common = [
+            20u16.to_le_bytes(),
+            entry.flags.to_le_bytes(),
+            entry.method.to_le_bytes(),
+            entry.time.to_le_bytes(),
+            entry.date.to_le_bytes(),
+        ]
+        .concat();
+        let sizes = [
+            entry.crc.to_le_bytes(),
+            (data.len() as u32).to_le_bytes(),
+            entry.size.to_le_bytes(),
+        ]
+        .concat();
+        let name_len = (entry.name.len() as u16).to_le_bytes();
+
+        out.extend_from_slice(&ZIP_LOCAL_SIG.to_le_bytes());
+        out.extend_from_slice(&common);
+        out.extend_from_slice(&sizes);
+        out.extend_from_slice(&name_len);
+        out.extend_from_slice(&0u16.to_le_bytes());
+        out.extend_from_slice(entry.name.as_bytes());
+        out.extend_from_slice(data);
+
+        central.extend_from_slice(&ZIP_CENTRAL_SIG.to_le_bytes());
+        central.extend_from_slice(&entry.version_made_by.to_le_bytes());
+        central.extend_from_slice(&common);
+        central.extend_from_slice(&sizes);
+        central.extend_from_slice(&name_len);
+        // Extra field, comment, disk number and internal attributes.
+        central.extend_from_slice(&[0; 8]);
+        central.extend_from_slice(&entry.external_attr.to_le_bytes());
+        central.extend_from_slice(&offset.to_le_bytes());
+        central.extend_from_slice(entry.name.as_bytes());
+    }
+    let central_offset = out.len() as u32;
+    let count = (written.len() as u16).to_le_bytes();
+    out.extend_from_slice(&central);
+    out.extend_from_slice(&ZIP_END_SIG.to_le_bytes());
+    out.extend_from_slice(&[0; 4]);
+    out.extend_from_slice(&count);
+    out.extend_from_slice(&count);
+    out.extend_from_slice(&(central.len() as u32).to_le_bytes());
+    out.extend_from_slice(&central_offset.to_le_bytes());
+    out.extend_from_slice(&[0; 2]);
+    Ok(out)
+}
+
+fn le16(data: &[u8], pos: usize) -> Option<u16> {
+    Some(u16::from_le_bytes(data.get(pos..pos + 2)?.try_into().ok()?))
+}
+
+fn le32(data: &[u8], pos: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(data.get(pos..pos + 4)?.try_into().ok()?))
+}
+
+/// The current UTC time as zip's MS-DOS `(time, date)`.
+fn dos_now() -> (u16, u16) {
+    let secs = crate::jsonl::timestamp();
+    let (days, rem) = ((secs / 86_400) as i64, secs % 86_400);
+    // Days since 1970-01-01 to a civil date (Howard Hinnant's algorithm).
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1_460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    let time = ((rem / 3_600) << 11) | ((rem % 3_600 / 60) << 5) | ((rem % 60) / 2);
+    let date = ((year - 1980).max(0) << 9) | (month << 5) | day;
+    (time as u16, date as u16)
+}
@@ -0,0 +1,100 @@
+//! Captures what a run prints, for `serve-http`: patches run in-process
+//! there, but each answer carries the stdout and stderr the CLI would have
+//! printed.
+//!
+//! Output is printed with `println!` and `eprintln!` throughout, so the
+//! capture points file descriptors 1 and 2 at pipes while the run lasts.
+//! That is process-wide: two captures must not overlap, and whatever
+//! another thread prints meanwhile is captured too.
+
+use std::io::Read;
+use std::io::Write;
+
+/// Runs `run` and returns its result with what it printed on stdout and
+/// stderr.
+#[cfg(unix)]
+pub(crate) fn capture<T>(run: impl FnOnce() -> T) -> std::io::Result<(T, String, String)> {
+    let (out_reader, out_writer) = std::io::pipe()?;
+    let (err_reader, err_writer) = std::io::pipe()?;
+    // Drained as the run goes, so a run that prints more than a pipe holds
+    // doesn't block.
+    let out = std::thread::spawn(move || drain(out_reader));
+    let err = std::thread::spawn(move || drain(err_reader));
+    let value = {
+        let _stdout = fd::Redirect::new(1, out_writer)?;
+        let _stderr = fd::Redirect::new(2, err_writer)?;
+        run()
+    };
+    // Both write ends are closed now, so the readers see end of file.
+    let stdout = out.join().unwrap_or_default();
+    let stderr = err.join().unwrap_or_default();
+    Ok((value, stdout, stderr))
+}
+
+#[cfg(not(unix))]
+pub(crate) fn capture<T>(_run: impl FnOnce() -> T) -> std::io::Result<(T, String, String)> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "capturing a run's output needs Unix file descriptors",
+    ))
+}
+
+fn drain(mut reader: std::io::PipeReader) -> String {
+    let mut buf = Vec::new();
+    let _ = reader.read_to_end(&mut buf);
+    String::from_utf8_lossy(&buf).into_owned()
+}
+
+#[cfg(unix)]
+mod fd {
+    use super::Write;
+    use std::os::fd::AsRawFd;
+    use std::os::fd::FromRawFd;
+    use std::os::fd::OwnedFd;
+
+    unsafe extern "C" {
+        fn dup(fd: i32) -> i32;
+        fn dup2(from: i32, to: i32) -> i32;
+    }
+
+    /// Descriptor `target` pointed at a pipe; what it pointed at before is
+    /// put back when this is dropped, even if the run panicked.
+    pub(super) struct Redirect {
+        target: i32,
+        saved: OwnedFd,
+    }
+
+    fn flush() {
+        let _ = std::io::stdout().flush();
+        let _ = std::io::stderr().flush();
+    }
+
+    impl Redirect {
+        pub(super) fn new(target: i32, to: std::io::PipeWriter) -> std::io::Result<Self> {
+            flush();
+            // SAFETY: `dup` only reads its argument; a new descriptor it
+            // returns is owned by nothing else.
+            let saved = unsafe { dup(target) };
+            if saved < 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+            // SAFETY: `saved` was just returned by `dup`.
+            let saved = unsafe { OwnedFd::from_raw_fd(saved) };
+            // SAFETY: both descriptors are open; `to` is closed when it
+            // drops, leaving `target` as the only write end.
+            if unsafe { dup2(to.as_raw_fd(), target) } < 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+            Ok(Self { target, saved })
+        }
+    }
+
+    impl Drop for Redirect {
+        fn drop(&mut self) {
+            flush();
+            // SAFETY: both descriptors are open; this closes the pipe end
+            // `target` held.
+            unsafe { dup2(self.saved.as_raw_fd(), self.target) };
+        }
+    }
+}
@@ -0,0 +1,237 @@
+//! `--check`: reports, file by file, whether a patch would apply, without
+//! writing anything or running it through modes and guards.
+//!
+//! Each file section is `clean` (every hunk matches exactly), `fuzzy` (every
+//! hunk matches, some only with the applier's looser comparisons),
+//! `conflict` (some hunk can't be placed) or `missing-file` (an update or
+//! delete of a file that isn't there). Hunks carry the 1-based line they
+//! start at in the current file, so editor extensions can draw gutter
+//! markers for a proposed patch before it is accepted; `--format json`
+//! prints the report as one JSON document for them. Sections are checked
+//! against the tree as it is, independently of each other.
+
+use crate::diagnostics;
+use crate::exit::Exit;
+use crate::freshness;
+use crate::patch::Chunk;
+use crate::patch::Patch;
+use crate::patch::Section;
+use crate::patch::SectionKind;
+use crate::progress::OutputFormat;
+use crate::simulate::Fuzz;
+use crate::simulate::seek_sequence;
+use crate::simulate::seek_sequence_fuzz;
+use serde::Serialize;
+use std::path::Path;
+
+/// Ordered from best to worst, so a file takes its worst hunk's status.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum FileStatus {
+    Clean,
+    Fuzzy,
+    Conflict,
+    MissingFile,
+}
+
+impl FileStatus {
+    fn as_str(self) -> &'static str {
+        match self {
+            FileStatus::Clean => "clean",
+            FileStatus::Fuzzy => "fuzzy",
+            FileStatus::Conflict => "conflict",
+            FileStatus::MissingFile => "missing-file",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct HunkCheck {
+    /// 1-based hunk number within the section.
+    pub(crate) hunk: usize,
+    pub(crate) status: FileStatus,
+    /// 1-based line in the current file where the hunk's old lines start
+    /// (or where its lines are inserted); absent for conflicts.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) line: Option<usize>,
+    pub(crate) old_lines: usize,
+    pub(crate) new_lines: usize,
+    /// The comparison a fuzzy hunk needed.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) fuzz: Option<Fuzz>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct FileCheck {
+    pub(crate) path: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) move_to: Option<String>,
+    pub(crate) kind: &'static str,
+    pub(crate) status: FileStatus,
+    pub(crate) hunks: Vec<HunkCheck>,
+}
+
+#[derive(Debug, Serialize)]
+struct Report<'a> {
+    applies: bool,
+    files: &'a [FileCheck],
+}
+
+/// Checks every section of `patch` against the files under `root`.
+pub(crate) fn check(patch: &Patch, root: &Path) -> Result<Vec<FileCheck>, String> {
+    patch
+        .sections
+        .iter()
+        .map(|section| {
+            let current = std::fs::read_to_string(root.join(&section.path)).ok();
+            let (kind, status, hunks) = match section.kind {
+                SectionKind::Add => ("add", FileStatus::Clean, Vec::new()),
+                SectionKind::Delete if current.is_none() => {
+                    ("delete", FileStatus::MissingFile, Vec::new())
+                }
+                SectionKind::Delete => ("delete", FileStatus::Clean, Vec::new()),
+                SectionKind::Update => match current {
+                    None => ("update", FileStatus::MissingFile, Vec::new()),
+                    Some(text) => {
+                        let hunks = check_update(&text, section)?;
+                        let status = hunks
+                            .iter()
+                            .map(|hunk| hunk.status)
+                            .max()
+                            .unwrap_or(FileStatus::Clean);
+                        ("update", status, hunks)
+                    }
+                },
+            };
+            Ok(FileCheck {
+                path: section.path.clone(),
+                move_to: section.move_to().map(str::to_string),
+                kind,
+                status,
+                hunks,
+            })
+        })
+        .collect()
+}
+
+/// Places each hunk the way the applier does, continuing after a
+/// conflicting hunk from where the previous one ended.
+fn check_update(text: &str, section: &Section) -> Result<Vec<HunkCheck>, String> {
+    let mut lines: Vec<String> = text.split('\n').map(str::to_string).collect();
+    if lines.last().is_some_and(String::is_empty) {
+        lines.pop();
+    }
+    let mut line_index = 0;
+    let mut hunks = Vec::new();
+    for (i, chunk) in section.chunks()?.iter().enumerate() {
+        let placed = place(&lines, chunk, line_index);
+        let (status, line, fuzz) = match placed {
+            Some((found, len, fuzz)) => {
+                line_index = found + len;
+                let status = if fuzz.is_some() {
+                    FileStatus::Fuzzy
+                } else {
+                    FileStatus::Clean
+                };
+                (status, Some(found + 1), fuzz)
+            }
+            None => (FileStatus::Conflict, None, None),
+        };
+        hunks.push(HunkCheck {
+            hunk: i + 1,
+            status,
+            line,
+            old_lines: chunk.old_lines.len(),
+            new_lines: chunk.new_lines.len(),
+            fuzz,
+        });
+    }
+    Ok(hunks)
+}
+
+/// Where `chunk` goes: its start, the number of lines it replaces and the
+/// comparison needed, or `None` if it can't be placed.
+fn place(
+    lines: &[String],
+    chunk: &Chunk,
+    mut line_index: usize,
+) -> Option<(usize, usize, Option<Fuzz>)> {
+    if let Some(context) = &chunk.change_context {
+        line_index = seek_sequence(lines, std::slice::from_ref(context), line_index, false)? + 1;
+    }
+    if chunk.old_lines.is_empty() {
+        return Some((lines.len(), 0, None));
+    }
+    let mut pattern: &[String] = &chunk.old_lines;
+    let mut found = seek_sequence_fuzz(lines, pattern, line_index, chunk.is_end_of_file);
+    if found.is_none() && pattern.last().is_some_and(String::is_empty) {
+        pattern = &pattern[..pattern.len() - 1];
+        found = seek_sequence_fuzz(lines, pattern, line_index, chunk.is_end_of_file);
+    }
+    found.map(|(start, fuzz)| (start, pattern.len(), fuzz))
+}
+
+/// Runs `--check` on `patch_text`, printing the report in `format`. Exits
+/// with the context-mismatch code if anything wouldn't apply.
+pub(crate) fn run_check(patch_text: &str, format: OutputFormat) -> i32 {
+    // Read times don't matter here.
+    let patch_text = match freshness::strip(patch_text) {
+        Ok((text, _)) => text,
+        Err(err) => {
+            eprintln!("{err}");
+            return Exit::ParseError.code();
+        }
+    };
+    let Some(patch) = Patch::parse(&patch_text) else {
+        eprintln!("Invalid patch: The first line of the patch must be '*** Begin Patch'");
+        return Exit::ParseError.code();
+    };
+    let files = match check(&patch, Path::new(".")) {
+        Ok(files) => files,
+        Err(err) => {
+            eprintln!("Invalid patch: {err}");
+            return Exit::ParseError.code();
+        }
+    };
+    let applies = files
+        .iter()
+        .all(|file| matches!(file.status, FileStatus::Clean | FileStatus::Fuzzy));
+
+    if format == OutputFormat::Json {
+        match serde_json::to_string(&Report {
+            applies,
+            files: &files,
+        }) {
+            Ok(line) => println!("{line}"),
+            Err(err) => {
+                diagnostics::error(format!("failed to serialize check: {err}"));
+                return 1;
+            }
+        }
+    } else {
+        for file in &files {
+            println!("{:<12} {}", file.status.as_str(), file.path);
+            for hunk in file.hunks.iter().filter(|h| h.status != FileStatus::Clean) {
+                match hunk.line {
+                    Some(line) => println!(
+                        "{:<12} {} @@ {} (line {line})",
+                        hunk.status.as_str(),
+                        file.path,
+                        hunk.hunk
+                    ),
+                    None => println!(
+                        "{:<12} {} @@ {}",
+                        hunk.status.as_str(),
+                        file.path,
+                        hunk.hunk
+                    ),
+                }
+            }
+        }
+    }
+    if applies {
+        0
+    } else {
+        Exit::ContextMismatch.code()
+    }
+}
@@ -0,0 +1,136 @@
+//! Clean-target check (`require_clean` in the config): raises the mode when
+//! a patch targets files with uncommitted git changes, so an agent's edits
+//! don't silently mix with a human's in-progress work in the same files.
+//!
+//! Uses `git status`; outside a repository, or without git, nothing is
+//! checked.
+
+use crate::Mode;
+use crate::policy::severity;
+use serde::Deserialize;
+use serde::Serialize;
+use std::process::Command;
+
+/// Which changes make a target dirty.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum CleanCheck {
+    #[default]
+    Off,
+    /// Only changes staged in the index.
+    Staged,
+    /// Staged or unstaged changes, including untracked files.
+    Any,
+}
+
+impl CleanCheck {
+    pub(crate) fn as_str(self) -> &'static str {
+        match self {
+            CleanCheck::Off => "off",
+            CleanCheck::Staged => "staged",
+            CleanCheck::Any => "any",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct RequireCleanConfig {
+    #[serde(default)]
+    pub(crate) check: CleanCheck,
+    /// Mode to enforce when a target is dirty (default: refuse).
+    #[serde(default = "default_action")]
+    pub(crate) action: Mode,
+}
+
+fn default_action() -> Mode {
+    Mode::Refuse
+}
+
+impl Default for RequireCleanConfig {
+    fn default() -> Self {
+        Self {
+            check: CleanCheck::Off,
+            action: default_action(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct DirtyFile {
+    pub(crate) path: String,
+    pub(crate) staged: bool,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct CleanGuard {
+    pub(crate) dirty: Vec<DirtyFile>,
+    pub(crate) to: Mode,
+}
+
+impl CleanGuard {
+    pub(crate) fn describe(&self) -> Vec<String> {
+        let mode = self.to.as_str();
+        self.dirty
+            .iter()
+            .map(|file| {
+                let what = if file.staged { "staged" } else { "uncommitted" };
+                format!(
+                    "Require clean ({mode} mode): {} has {what} changes; commit or stash them before patching it.",
+                    file.path
+                )
+            })
+            .collect()
+    }
+}
+
+/// Checks `files` (relative to the current directory) with `git status`.
+/// Returns `None` when the check is off, git isn't usable here, or every
+/// target is clean.
+pub(crate) fn guard(cfg: &RequireCleanConfig, files: &[String], mode: Mode) -> Option<CleanGuard> {
+    if cfg.check == CleanCheck::Off || files.is_empty() {
+        return None;
+    }
+    let prefix = git(&["rev-parse", "--show-prefix"])?;
+    let prefix = prefix.trim_end();
+    let mut args = vec!["status", "--porcelain=v1", "-z", "--"];
+    args.extend(files.iter().map(String::as_str));
+    let status = git(&args)?;
+
+    let mut dirty: Vec<DirtyFile> = Vec::new();
+    let mut entries = status.split('\0').filter(|e| !e.is_empty());
+    while let Some(entry) = entries.next() {
+        let (Some(code), Some(path)) = (entry.get(..2), entry.get(3..)) else {
+            continue;
+        };
+        let (index, worktree) = (code.as_bytes()[0], code.as_bytes()[1]);
+        if matches!(index, b'R' | b'C') {
+            // The rename/copy source follows as its own entry.
+            entries.next();
+        }
+        let staged = !matches!(index, b' ' | b'?' | b'!');
+        if !staged && (cfg.check == CleanCheck::Staged || worktree == b' ') {
+            continue;
+        }
+        dirty.push(DirtyFile {
+            path: path.strip_prefix(prefix).unwrap_or(path).to_string(),
+            staged,
+        });
+    }
+    if dirty.is_empty() {
+        return None;
+    }
+    let to = if severity(cfg.action) > severity(mode) {
+        cfg.action
+    } else {
+        mode
+    };
+    Some(CleanGuard { dirty, to })
+}
+
+fn git(args: &[&str]) -> Option<String> {
+    let output = Command::new("git").args(args).output().ok()?;
+    output
+        .status
+        .success()
+        .then(|| String::from_utf8_lossy(&output.stdout).into_owned())
+}
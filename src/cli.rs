@@ -0,0 +1,903 @@
+//! The CLI surface as data: flags, subcommands, config keys, environment
+//! variables and exit codes.
+//!
+//! The argument parser looks flags up here, and both `--help` and
+//! `generate-docs` render from the same tables, so documentation can't drift
+//! from what the binary actually accepts.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum FlagGroup {
+    /// Persisted into the config file.
+    Config,
+    /// Affects the current invocation only.
+    Run,
+    /// Informational (help and friends).
+    Info,
+}
+
+#[derive(Debug)]
+pub(crate) struct FlagSpec {
+    pub(crate) name: &'static str,
+    pub(crate) short: Option<&'static str>,
+    /// Placeholder for the flag's value, if it takes one.
+    pub(crate) value: Option<&'static str>,
+    pub(crate) group: FlagGroup,
+    pub(crate) help: &'static str,
+}
+
+pub(crate) const FLAGS: &[FlagSpec] = &[
+    FlagSpec {
+        name: "--show-config",
+        short: None,
+        value: None,
+        group: FlagGroup::Config,
+        help: "Print the config file path and the effective settings.",
+    },
+    FlagSpec {
+        name: "--mode",
+        short: None,
+        value: Some("apply|refuse|warn|forward"),
+        group: FlagGroup::Config,
+        help: "Set the base mode.",
+    },
+    FlagSpec {
+        name: "--apply",
+        short: None,
+        value: None,
+        group: FlagGroup::Config,
+        help: "Alias for --mode apply: apply patches without a banner (default).",
+    },
+    FlagSpec {
+        name: "--refuse",
+        short: None,
+        value: None,
+        group: FlagGroup::Config,
+        help: "Alias for --mode refuse: change nothing and print the refuse banner.",
+    },
+    FlagSpec {
+        name: "--warn",
+        short: None,
+        value: None,
+        group: FlagGroup::Config,
+        help: "Alias for --mode warn: apply patches, then print the warn banner.",
+    },
+    FlagSpec {
+        name: "--set-refuse-message",
+        short: None,
+        value: Some("text"),
+        group: FlagGroup::Config,
+        help: "Use a custom refuse banner.",
+    },
+    FlagSpec {
+        name: "--clear-refuse-message",
+        short: None,
+        value: None,
+        group: FlagGroup::Config,
+        help: "Revert to the built-in refuse banner.",
+    },
+    FlagSpec {
+        name: "--set-warn-message",
+        short: None,
+        value: Some("text"),
+        group: FlagGroup::Config,
+        help: "Use a custom warn banner.",
+    },
+    FlagSpec {
+        name: "--clear-warn-message",
+        short: None,
+        value: None,
+        group: FlagGroup::Config,
+        help: "Revert to the built-in warn banner.",
+    },
+    FlagSpec {
+        name: "--set-apply-message",
+        short: None,
+        value: Some("text"),
+        group: FlagGroup::Config,
+        help: "Print this note after every successful apply in apply mode.",
+    },
+    FlagSpec {
+        name: "--clear-apply-message",
+        short: None,
+        value: None,
+        group: FlagGroup::Config,
+        help: "Stop printing a note after successful applies.",
+    },
+    FlagSpec {
+        name: "--force-delete",
+        short: None,
+        value: None,
+        group: FlagGroup::Run,
+        help: "Delete files even if their content differs from the content given in the patch.",
+    },
+    FlagSpec {
+        name: "--dry-run",
+        short: None,
+        value: None,
+        group: FlagGroup::Run,
+        help: "Run the patch through modes, guards and the matcher and print what it would change, without writing anything.",
+    },
+    FlagSpec {
+        name: "--partial",
+        short: None,
+        value: None,
+        group: FlagGroup::Run,
+        help: "Apply each file section on its own, keep the ones that succeed and report the rest (exit 3 if only some applied).",
+    },
+    FlagSpec {
+        name: "--residual",
+        short: None,
+        value: Some("file"),
+        group: FlagGroup::Run,
+        help: "With --partial, write the sections that failed to this file as a patch.",
+    },
+    FlagSpec {
+        name: "--tenant",
+        short: None,
+        value: Some("name"),
+        group: FlagGroup::Run,
+        help: "Use the tenants.<name> section of the config and per-tenant log files; with config flags, edit that section.",
+    },
+    FlagSpec {
+        name: "--cwd",
+        short: None,
+        value: Some("dir"),
+        group: FlagGroup::Run,
+        help: "Resolve relative patch paths from DIR instead of the current directory.",
+    },
+    FlagSpec {
+        name: "--input",
+        short: None,
+        value: Some("patch|tool-json"),
+        group: FlagGroup::Run,
+        help: "With tool-json, read a tool-call object {\"patch\", \"cwd\", \"metadata\"} instead of a bare patch; metadata goes to the audit log and {metadata.KEY} in banners.",
+    },
+    FlagSpec {
+        name: "--format",
+        short: None,
+        value: Some("text|json-stream|json"),
+        group: FlagGroup::Run,
+        help: "With json-stream, report progress as JSON lines on stderr while files are written (default: text, a progress bar for large patches on a terminal). With --check, json prints the report as one JSON document.",
+    },
+    FlagSpec {
+        name: "--event-fd",
+        short: None,
+        value: Some("n"),
+        group: FlagGroup::Run,
+        help: "Write JSON-lines lifecycle events (started, policy-decision, file-applied, finished) to file descriptor N.",
+    },
+    FlagSpec {
+        name: "--read-manifest",
+        short: None,
+        value: Some("file"),
+        group: FlagGroup::Run,
+        help: "JSON object mapping paths to the time the model read them (Unix seconds or RFC 3339); targets modified since are refused. `*** Read At:` lines in the patch take precedence.",
+    },
+    FlagSpec {
+        name: "--expected-files",
+        short: None,
+        value: Some("list"),
+        group: FlagGroup::Run,
+        help: "Comma-separated paths (or @FILE, a JSON array or one path per line) the patch may touch; a patch touching any other path is refused with the expected/actual difference.",
+    },
+    FlagSpec {
+        name: "--check",
+        short: None,
+        value: None,
+        group: FlagGroup::Run,
+        help: "Report for each file section whether it applies cleanly, only fuzzily, conflicts or targets a missing file, with the line each hunk lands on; writes nothing and exits 1 if anything wouldn't apply.",
+    },
+    FlagSpec {
+        name: "--patch-fd",
+        short: None,
+        value: Some("n"),
+        group: FlagGroup::Run,
+        help: "Read the patch from inherited file descriptor N instead of stdin, which stays free for prompts.",
+    },
+    FlagSpec {
+        name: "--no-config",
+        short: None,
+        value: None,
+        group: FlagGroup::Run,
+        help: "Ignore the config file, APPLY_PATCH_* overrides and saved state; run with the built-in defaults and the flags given.",
+    },
+    FlagSpec {
+        name: "--to-branch",
+        short: None,
+        value: Some("name"),
+        group: FlagGroup::Run,
+        help: "Apply in a temporary git worktree on new branch NAME and commit there, leaving the current checkout untouched.",
+    },
+    FlagSpec {
+        name: "--open-pr",
+        short: None,
+        value: None,
+        group: FlagGroup::Run,
+        help: "With --to-branch, push the new branch to pull_request.remote and open a pull request with gh, printing its URL.",
+    },
+    FlagSpec {
+        name: "--base",
+        short: None,
+        value: Some("ref"),
+        group: FlagGroup::Run,
+        help: "Apply the patch to the files as of git REF, then carry the result over to the working tree as a rebased patch.",
+    },
+    FlagSpec {
+        name: "--rebased-out",
+        short: None,
+        value: Some("file"),
+        group: FlagGroup::Run,
+        help: "With --base, write the rebased patch to this file instead of applying it.",
+    },
+    FlagSpec {
+        name: "--3way",
+        short: None,
+        value: None,
+        group: FlagGroup::Run,
+        help: "When the built-in applier can't place the patch, merge it into the working tree with the git index as the base, marking only true overlaps as conflicts.",
+    },
+    FlagSpec {
+        name: "--stash-before",
+        short: None,
+        value: None,
+        group: FlagGroup::Run,
+        help: "In a git repository, record the current state of the files about to be patched as a stash entry (stash@{0}) before writing.",
+    },
+    FlagSpec {
+        name: "--deny-warnings",
+        short: None,
+        value: None,
+        group: FlagGroup::Run,
+        help: "Exit with a failure when the run printed any warnings, such as a diverged mirror or an unwritable audit log.",
+    },
+    FlagSpec {
+        name: "--jail",
+        short: None,
+        value: None,
+        group: FlagGroup::Run,
+        help: "Linux: run the apply in an unprivileged user namespace where everything but the working directory and apply_patch's state/log directories is read-only; warns and applies unjailed where namespaces aren't available.",
+    },
+    FlagSpec {
+        name: "--timings",
+        short: None,
+        value: None,
+        group: FlagGroup::Run,
+        help: "Print the run's wall time, CPU time, peak memory, bytes read and written and file count on stderr (also recorded in the audit log).",
+    },
+    FlagSpec {
+        name: "--strict",
+        short: None,
+        value: None,
+        group: FlagGroup::Run,
+        help: "Refuse patches with anything the applier would silently forgive (heredoc wrappers, whitespace around markers, blank lines between sections, duplicate file sections, ...), listing each by line.",
+    },
+    FlagSpec {
+        name: "--concise",
+        short: None,
+        value: None,
+        group: FlagGroup::Run,
+        help: "Print the success summary as one line and cut the warn, refuse and apply messages (and notices) to concise.message_bytes, to save the caller's context window.",
+    },
+    FlagSpec {
+        name: "--archive",
+        short: None,
+        value: Some("file"),
+        group: FlagGroup::Run,
+        help: "Apply the patch to the files inside this zip, tar or tar.gz archive instead of the working tree, updating it in place.",
+    },
+    FlagSpec {
+        name: "--archive-out",
+        short: None,
+        value: Some("file"),
+        group: FlagGroup::Run,
+        help: "With --archive, write the patched archive to this file instead.",
+    },
+    FlagSpec {
+        name: "--max-depth",
+        short: None,
+        value: Some("n"),
+        group: FlagGroup::Run,
+        help: "Refuse patches creating more than N directory levels for one file (overrides new_dirs.max_depth).",
+    },
+    FlagSpec {
+        name: "--watch",
+        short: None,
+        value: Some("dir"),
+        group: FlagGroup::Run,
+        help: "Keep running and apply every *.patch file dropped into DIR, then move it to DIR/applied/ or DIR/failed/.",
+    },
+    FlagSpec {
+        name: "--explain-exit-codes",
+        short: None,
+        value: None,
+        group: FlagGroup::Info,
+        help: "Print the exit codes in effect (see APPLY_PATCH_EXIT_CODES) as JSON.",
+    },
+    FlagSpec {
+        name: "--help",
+        short: Some("-h"),
+        value: None,
+        group: FlagGroup::Info,
+        help: "Print this help.",
+    },
+];
+
+#[derive(Debug)]
+pub(crate) struct SubcommandSpec {
+    pub(crate) name: &'static str,
+    pub(crate) usage: &'static str,
+    pub(crate) help: &'static str,
+}
+
+pub(crate) const SUBCOMMANDS: &[SubcommandSpec] = &[
+    SubcommandSpec {
+        name: "generate-docs",
+        usage: "generate-docs <man|markdown> [--out DIR]",
+        help: "Render this reference as a man page or Markdown, to DIR or stdout.",
+    },
+    SubcommandSpec {
+        name: "preview",
+        usage: "preview --out DIR [--] [PATCH]",
+        help: "Write before/<path> and after/<path> copies of every touched file into DIR without changing the working tree.",
+    },
+    SubcommandSpec {
+        name: "stage",
+        usage: "stage [--expected-files LIST] [--] [PATCH]",
+        help: "Run the patch through the mode, policies and guards, and write the files it would produce into a staging area in the state directory, without touching the working tree, so they can be reviewed and edited before commit-staged.",
+    },
+    SubcommandSpec {
+        name: "commit-staged",
+        usage: "commit-staged",
+        help: "Check the staged files against the mode, policies and guards again, then move them into the working tree, all or none; refuses if a target changed since it was staged. A staged file that was removed is left alone.",
+    },
+    SubcommandSpec {
+        name: "discard-staged",
+        usage: "discard-staged",
+        help: "Throw away the changes staged for the working directory.",
+    },
+    SubcommandSpec {
+        name: "assess",
+        usage: "assess [--json] [--] [PATCH]",
+        help: "Print a risk report and score for the patch without applying it; policy rules see the score as `risk`.",
+    },
+    SubcommandSpec {
+        name: "compare",
+        usage: "compare [--json] [--] [PATCH]",
+        help: "Report for each hunk whether it still applies, is already applied, or has diverged from the current tree, without applying anything.",
+    },
+    SubcommandSpec {
+        name: "squash",
+        usage: "squash [--out FILE] [--] PATCH...",
+        help: "Compose patch files meant to be applied in order into one equivalent patch, with one section per file; fails naming the first patch that doesn't apply on top of the earlier ones.",
+    },
+    SubcommandSpec {
+        name: "batch",
+        usage: "batch [--fail-fast|--continue-on-error] [--json] MANIFEST",
+        help: "Apply the patches listed in a JSON or YAML manifest in order, each in its own directory with its own options, and print one consolidated report; stops at the first failure or refusal unless on_error is \"continue\".",
+    },
+    SubcommandSpec {
+        name: "roundtrip",
+        usage: "roundtrip [FILE...]",
+        help: "Check that each patch (or stdin, read as lossy UTF-8) parses and renders back to itself; exits 1 naming the first that doesn't. Non-patches pass; for fuzzers and regression fixtures.",
+    },
+    SubcommandSpec {
+        name: "make-patch",
+        usage: "make-patch [--hashline] PATH [NEW]",
+        help: "Print the patch that turns PATH into the contents of NEW (or stdin); --hashline prefixes context and removed lines with a hash of their text so the patch still lands after surrounding lines move.",
+    },
+    SubcommandSpec {
+        name: "why",
+        usage: "why [--json]",
+        help: "Explain the most recent decision: the config layers, the policy rule that matched, the configured checks, guard notices, the refusal reason and the message printed.",
+    },
+    SubcommandSpec {
+        name: "wal",
+        usage: "wal <show [--all] [--json]|clear>",
+        help: "List applies the write-ahead log shows were interrupted, with what each file holds now (--all: every logged apply), or remove the log.",
+    },
+    SubcommandSpec {
+        name: "revert-file",
+        usage: "revert-file [--run ID] PATH",
+        help: "Put back what PATH held before the last applied patch that touched it (or run ID from the write-ahead log), if it still holds what that patch left.",
+    },
+    SubcommandSpec {
+        name: "redact",
+        usage: "redact [--out FILE] [--pattern RE]... [PATCH]",
+        help: "Print PATCH (or stdin) with string literals, email addresses and redact.patterns matches replaced by placeholders, keeping its structure, for attaching to bug reports.",
+    },
+    SubcommandSpec {
+        name: "lint",
+        usage: "lint [--json] [--] [PATCH]",
+        help: "Check the lines a patch adds against the lint rules; exits 1 if any finding is an error.",
+    },
+    SubcommandSpec {
+        name: "init",
+        usage: "init [--strict|--relaxed] [--force]",
+        help: "Write a commented starter config after asking a few questions, or from the strict or relaxed preset; refuses to replace an existing config without --force.",
+    },
+    SubcommandSpec {
+        name: "migrate-config",
+        usage: "migrate-config",
+        help: "Rewrite a config file from an older release at the current schema version, keeping the original as config.json.v<old version>.bak; other keys are left as they are.",
+    },
+    SubcommandSpec {
+        name: "migrate-state",
+        usage: "migrate-state",
+        help: "Move state files left next to the config by older releases into the state directory.",
+    },
+    SubcommandSpec {
+        name: "cache",
+        usage: "cache <list|clear>",
+        help: "List the entries of the patch cache (see patch_cache) or remove them all.",
+    },
+    SubcommandSpec {
+        name: "wrap-shim",
+        usage: "wrap-shim <install|uninstall|status> [--dir DIR] [--force]",
+        help: "Manage sh shims named apply_patch, applypatch and apply-patch in DIR (default: ~/.local/bin) that run this binary; other files of those names are left alone unless --force, and other tools found first on PATH are reported.",
+    },
+    SubcommandSpec {
+        name: "serve-http",
+        usage: "serve-http [--bind ADDR]",
+        help: "Serve POST /apply, POST /validate and GET /status over HTTP on ADDR (default: 127.0.0.1:8787), answering with the CLI's exit code and output as JSON; requires $APPLY_PATCH_HTTP_TOKEN as a bearer token.",
+    },
+    SubcommandSpec {
+        name: "audit-collector",
+        usage: "audit-collector [--socket PATH] [--out FILE]",
+        help: "Listen on the Unix socket PATH (default: audit.forward_socket) and write every audit event sent there as a JSON line to FILE (default: stdout), until killed.",
+    },
+];
+
+#[derive(Debug)]
+pub(crate) struct ConfigKeySpec {
+    pub(crate) key: &'static str,
+    pub(crate) help: &'static str,
+}
+
+pub(crate) const CONFIG_KEYS: &[ConfigKeySpec] = &[
+    ConfigKeySpec {
+        key: "version",
+        help: "Config schema version. Older files are upgraded in place (the original is kept as config.json.vN.bak); newer ones are rejected.",
+    },
+    ConfigKeySpec {
+        key: "mode",
+        help: "Base mode: apply, refuse, warn or forward (default: apply).",
+    },
+    ConfigKeySpec {
+        key: "refuse_message",
+        help: "Custom refuse banner (default: built-in).",
+    },
+    ConfigKeySpec {
+        key: "warn_message",
+        help: "Custom warn banner (default: built-in).",
+    },
+    ConfigKeySpec {
+        key: "warn_messages",
+        help: "List of warn banners, replacing warn_message; each repeated warning in a session shows the next one.",
+    },
+    ConfigKeySpec {
+        key: "warn_rotation",
+        help: "How warn_messages are picked: round-robin (default) or escalate (stay on the last one).",
+    },
+    ConfigKeySpec {
+        key: "apply_message",
+        help: "Note printed after successful applies in apply mode (default: none).",
+    },
+    ConfigKeySpec {
+        key: "parse_error_message",
+        help: "Guidance printed to stderr when the patch can't be parsed, with {error}, {line_number} and {line} filled in (default: an explanation of the patch grammar).",
+    },
+    ConfigKeySpec {
+        key: "locales",
+        help: "Messages per locale, e.g. {\"de\": {\"refuse_message\": \"...\"}}: refuse_message, warn_message, apply_message and parse_error_message replace the top-level ones when that locale is in effect. Keys match the full locale (pt_BR) before the language (pt).",
+    },
+    ConfigKeySpec {
+        key: "output.locale",
+        help: "Locale for the messages in locales (default: $LC_ALL, $LC_MESSAGES or $LANG).",
+    },
+    ConfigKeySpec {
+        key: "refuse_echo",
+        help: "After the refuse banner, echo the patch back: none, summary (one line per file) or full (default: none).",
+    },
+    ConfigKeySpec {
+        key: "refusal_reason_line",
+        help: "Print a machine-readable `REFUSED: <code> <detail>` line before the refuse banner (default: false).",
+    },
+    ConfigKeySpec {
+        key: "readonly",
+        help: "Run every patch as if --dry-run were given, so nothing is ever written (default: false).",
+    },
+    ConfigKeySpec {
+        key: "drop_paths",
+        help: "Globs for files whose sections are removed from every patch (reported as dropped) while the rest still applies.",
+    },
+    ConfigKeySpec {
+        key: "skip_whitespace_only_hunks",
+        help: "Remove update hunks whose only change is indentation or trailing whitespace, reporting each, while the rest still applies (default: false).",
+    },
+    ConfigKeySpec {
+        key: "policies",
+        help: "Ordered list of {\"when\": EXPR, \"mode\": MODE} rules; the first match selects the mode. Rules with \"shadow\": true are only evaluated and recorded.",
+    },
+    ConfigKeySpec {
+        key: "escalate.large_patch_lines",
+        help: "Escalate patches changing at least this many lines.",
+    },
+    ConfigKeySpec {
+        key: "escalate.to",
+        help: "Mode to escalate large patches to (default: warn).",
+    },
+    ConfigKeySpec {
+        key: "escalate.shadow",
+        help: "Evaluate and record the escalation without enforcing it (default: false).",
+    },
+    ConfigKeySpec {
+        key: "editor_guard.action",
+        help: "Mode to enforce (warn or refuse) when a target has an editor lock/swap file next to it; off when unset.",
+    },
+    ConfigKeySpec {
+        key: "editor_guard.patterns",
+        help: "Sibling file names that mark a file as open, with {name} for its file name (default: .{name}.swp, .{name}.swo, .#{name}, {name}~).",
+    },
+    ConfigKeySpec {
+        key: "git_boundaries.action",
+        help: "Mode to enforce (warn or refuse) when a target is inside a submodule/nested repository or outside the sparse-checkout cone; off when unset.",
+    },
+    ConfigKeySpec {
+        key: "require_clean.check",
+        help: "Which git changes make a target dirty: staged, any (staged, unstaged or untracked) or off (default: off).",
+    },
+    ConfigKeySpec {
+        key: "require_clean.action",
+        help: "Mode to enforce when a target is dirty (default: refuse).",
+    },
+    ConfigKeySpec {
+        key: "branch_rules",
+        help: "List of {branch, mode} rules over the current git branch, first match wins; e.g. refuse on main. Rules only raise the mode.",
+    },
+    ConfigKeySpec {
+        key: "codeowners.owners",
+        help: "Owners, as written in CODEOWNERS (e.g. @org/team), whose paths the agent may patch.",
+    },
+    ConfigKeySpec {
+        key: "codeowners.action",
+        help: "Mode to enforce (warn or refuse) when a target is owned by someone else in .github/CODEOWNERS; off when unset.",
+    },
+    ConfigKeySpec {
+        key: "codeowners.allow_unowned",
+        help: "Allow targets that no CODEOWNERS line owns (default: true).",
+    },
+    ConfigKeySpec {
+        key: "symlinks.action",
+        help: "What to do when a target is a symlink or under one: follow, refuse or replace-link (replace the target link with a regular copy before applying; default: follow). Links resolving outside the working directory are always refused.",
+    },
+    ConfigKeySpec {
+        key: "new_dirs.action",
+        help: "Whether added or moved files may create new directories: allow, deny or ask (confirm on the terminal; refused without one) (default: allow).",
+    },
+    ConfigKeySpec {
+        key: "new_dirs.max_depth",
+        help: "Refuse patches creating more than this many directory levels for one file; unlimited when unset.",
+    },
+    ConfigKeySpec {
+        key: "new_dirs.top_level",
+        help: "Allow new directories at the repository root (default: true).",
+    },
+    ConfigKeySpec {
+        key: "allow_dir_ops",
+        help: "Allow `*** Add Directory:` and `*** Delete Directory:` sections (default: false).",
+    },
+    ConfigKeySpec {
+        key: "max_dir_delete_files",
+        help: "Refuse directory deletes that would remove more than this many files (default: 100).",
+    },
+    ConfigKeySpec {
+        key: "replace_file.allow",
+        help: "Allow `*** Replace File:` sections, whose body is a file's complete new content (default: true).",
+    },
+    ConfigKeySpec {
+        key: "replace_file.max_bytes",
+        help: "Reject Replace File sections whose new content is larger than this many bytes (default: 1048576).",
+    },
+    ConfigKeySpec {
+        key: "lint.enforce",
+        help: "Refuse patches with error-level lint findings and print warnings before applying (default: false).",
+    },
+    ConfigKeySpec {
+        key: "lint.max_line_length",
+        help: "Flag added lines longer than this many characters with the long_lines rule (warn unless lint.rules says otherwise); unchecked when unset.",
+    },
+    ConfigKeySpec {
+        key: "lint.rules",
+        help: "Map of rule name to off, warn or error: conflict_markers (default: error), mixed_indentation (warn), todo_added (warn), invisible_chars (error), homoglyphs (warn).",
+    },
+    ConfigKeySpec {
+        key: "failure_guard.threshold",
+        help: "After this many identical apply failures in a row, follow the error with recovery instructions; off when unset.",
+    },
+    ConfigKeySpec {
+        key: "failure_guard.cooldown_secs",
+        help: "Once the failure threshold is hit, refuse every patch for this many seconds (default: no cooldown).",
+    },
+    ConfigKeySpec {
+        key: "notify.desktop",
+        help: "Show a desktop notification (notify-send or osascript) when a patch is refused or over the escalate threshold (default: false).",
+    },
+    ConfigKeySpec {
+        key: "notify.command",
+        help: "Shell command run when a patch is refused or over the escalate threshold, with APPLY_PATCH_NOTIFY_* variables describing it.",
+    },
+    ConfigKeySpec {
+        key: "forward.url",
+        help: "In forward mode, POST the patch as JSON to this URL (with curl) instead of applying it, and print the answer's summary.",
+    },
+    ConfigKeySpec {
+        key: "forward.command",
+        help: "In forward mode without forward.url, pipe the patch to this shell command instead of applying it, and print its output.",
+    },
+    ConfigKeySpec {
+        key: "archives.max_files",
+        help: "Most files one `*** Add Files From Archive:` section may add (default: 200).",
+    },
+    ConfigKeySpec {
+        key: "archives.max_bytes",
+        help: "Largest archive in bytes, after base64 decoding and decompression (default: 1048576).",
+    },
+    ConfigKeySpec {
+        key: "write_strategy",
+        help: "How whole-patch applies write files: in-place (default; keeps hard links, bind mounts and file watches) or replace (temporary file renamed over the target; files with several hard links or that can't be renamed over are still written in place, with a note).",
+    },
+    ConfigKeySpec {
+        key: "staging_dir",
+        help: "Directory for the temporary files that replace writes, streaming applies and commit-staged rename over targets; used for targets on its filesystem (default: $TMPDIR when on the target's filesystem, else next to the target).",
+    },
+    ConfigKeySpec {
+        key: "pull_request.remote",
+        help: "Remote that --open-pr pushes the branch to (default: origin).",
+    },
+    ConfigKeySpec {
+        key: "pull_request.base",
+        help: "Branch --open-pr pull requests merge into (default: the branch checked out where apply_patch runs).",
+    },
+    ConfigKeySpec {
+        key: "pull_request.token_env",
+        help: "Environment variable whose value is passed to gh as GH_TOKEN for --open-pr (default: gh's own login or $GH_TOKEN).",
+    },
+    ConfigKeySpec {
+        key: "pull_request.draft",
+        help: "Open --open-pr pull requests as drafts.",
+    },
+    ConfigKeySpec {
+        key: "conflict_guard",
+        help: "Watch the target files while a whole patch is written and roll it back if another process writes to them meanwhile (default: false).",
+    },
+    ConfigKeySpec {
+        key: "write_ahead_log",
+        help: "Append each apply's files, operations and before/after digests to wal.jsonl in the state directory before writing, and mark it finished afterwards (default: false).",
+    },
+    ConfigKeySpec {
+        key: "format_on_apply",
+        help: "Map of path glob to formatter command, run through sh -c with the matching files the patch wrote as arguments after it applies; failures are warnings.",
+    },
+    ConfigKeySpec {
+        key: "redact.patterns",
+        help: "Regular expressions whose matches `apply_patch redact` replaces with REDACTEDn, besides string literals and email addresses.",
+    },
+    ConfigKeySpec {
+        key: "limits.max_target_file_bytes",
+        help: "Refuse patches that update or delete a file larger than this many bytes, before reading it (default: 268435456, 256 MiB; null for no limit).",
+    },
+    ConfigKeySpec {
+        key: "limits.stream_above_bytes",
+        help: "Apply patches that update a file larger than this many bytes a line at a time instead of reading it whole; verify_writes, conflict_guard, the patch cache, mirror_dir, format_on_apply and the git-apply fallback are skipped for them (default: 52428800, 50 MiB; null to never stream).",
+    },
+    ConfigKeySpec {
+        key: "fallback",
+        help: "none (default) or git-apply: when the built-in applier can't place a whole patch's hunks, convert it to a unified diff and apply it with `git apply --recount -C1`, saying which engine applied it.",
+    },
+    ConfigKeySpec {
+        key: "final_newline",
+        help: "Trailing newlines after a whole-patch apply: preserve (default; updated files keep the ending they had, new files get one), always or never. A file that loses its trailing newline is reported.",
+    },
+    ConfigKeySpec {
+        key: "concise.enabled",
+        help: "Always print concise output, as with --concise.",
+    },
+    ConfigKeySpec {
+        key: "concise.message_bytes",
+        help: "Longest echoed message in concise output, in bytes (default: 200); longer ones end in an ellipsis.",
+    },
+    ConfigKeySpec {
+        key: "verify_writes",
+        help: "After applying a whole patch, read back every written file and fail unless it matches the simulated result (default: true).",
+    },
+    ConfigKeySpec {
+        key: "reanchor.min_similarity",
+        help: "Re-anchor hunks whose context no longer matches at a line unique to both hunk and file, if this share (0.0-1.0) of their lines match there; off when unset.",
+    },
+    ConfigKeySpec {
+        key: "disk_space.check",
+        help: "Before writing, fail if the patch needs more bytes or new files than `df` reports available on the target filesystems (default: true).",
+    },
+    ConfigKeySpec {
+        key: "disk_space.reserve_bytes",
+        help: "Bytes the disk space check keeps free on each filesystem after the patch (default: 0).",
+    },
+    ConfigKeySpec {
+        key: "mirror_dir",
+        help: "Replay every successful whole-patch apply into this second tree, skipping (and warning about) files whose contents there differ from the working tree's.",
+    },
+    ConfigKeySpec {
+        key: "patch_cache",
+        help: "Cache every successful whole-patch apply by digest in the state directory and replay it without context matching when the same patch meets the same files again (default: false).",
+    },
+    ConfigKeySpec {
+        key: "state_dir",
+        help: "Directory for state kept between runs, such as failure streaks (default: $XDG_STATE_HOME/apply_patch, else ~/.local/state/apply_patch).",
+    },
+    ConfigKeySpec {
+        key: "audit_log",
+        help: "Append one JSON line per invocation to this file.",
+    },
+    ConfigKeySpec {
+        key: "audit.forward_socket",
+        help: "Also send each audit entry as a datagram to this Unix socket, such as one `apply_patch audit-collector` listens on; dropped when nothing is listening.",
+    },
+    ConfigKeySpec {
+        key: "tenants",
+        help: "Map of tenant name to settings layered over the top-level ones for --tenant/$APPLY_PATCH_TENANT.",
+    },
+];
+
+pub(crate) const ENV_VARS: &[(&str, &str)] = &[
+    (
+        "APPLY_PATCH_CONFIG",
+        "Config file path (overrides the XDG/HOME location).",
+    ),
+    (
+        "APPLY_PATCH_NO_CONFIG",
+        "Set to 1 to act as if --no-config were given.",
+    ),
+    (
+        "XDG_CONFIG_HOME",
+        "Config lives at $XDG_CONFIG_HOME/.apply_patch/config.json when set.",
+    ),
+    (
+        "HOME",
+        "Otherwise config lives at ~/.apply_patch/config.json.",
+    ),
+    (
+        "XDG_STATE_HOME",
+        "State kept between runs lives in $XDG_STATE_HOME/apply_patch (default: ~/.local/state/apply_patch) unless state_dir is set.",
+    ),
+    (
+        "LC_ALL, LC_MESSAGES, LANG",
+        "Pick the locales entry whose messages are used, unless output.locale is set.",
+    ),
+    (
+        "APPLY_PATCH_FEEDBACK_FILE",
+        "Also append warn/refuse banners and decisions here as JSON lines.",
+    ),
+    (
+        "APPLY_PATCH_TENANT",
+        "Default tenant when --tenant is not given.",
+    ),
+    ("APPLY_PATCH_CWD", "Default directory for --cwd."),
+    (
+        "APPLY_PATCH_EVENT_FD",
+        "Default file descriptor for --event-fd.",
+    ),
+    (
+        "APPLY_PATCH_EXIT_CODES",
+        "Set to `detailed` to give every outcome its own exit code (see below).",
+    ),
+    (
+        "APPLY_PATCH_AGENT",
+        "Agent name exposed to policy rules as `agent`.",
+    ),
+    (
+        "APPLY_PATCH_SESSION",
+        "Session id for the {warn_count} and {session_apply_count} banner counts (default: a session ends after 30 idle minutes).",
+    ),
+    (
+        "APPLY_PATCH_HTTP_TOKEN",
+        "Bearer token every serve-http request must send; serve-http won't start without it.",
+    ),
+];
+
+pub(crate) const EXIT_CODES: &[(i32, &str)] = &[
+    (
+        0,
+        "Patch applied, or refused by mode/policy (the banner explains which).",
+    ),
+    (
+        1,
+        "Patch failed to parse or apply, or a guardrail/config error occurred.",
+    ),
+    (2, "Usage error (bad flags or arguments)."),
+    (
+        3,
+        "With --partial: some file sections applied and others failed.",
+    ),
+];
+
+#[derive(Debug)]
+pub(crate) struct ExitCodeSpec {
+    pub(crate) code: i32,
+    /// Stable name reported by `--explain-exit-codes`.
+    pub(crate) name: &'static str,
+    pub(crate) help: &'static str,
+}
+
+/// Exit codes with `APPLY_PATCH_EXIT_CODES=detailed`.
+pub(crate) const DETAILED_EXIT_CODES: &[ExitCodeSpec] = &[
+    ExitCodeSpec {
+        code: 0,
+        name: "applied",
+        help: "Patch applied.",
+    },
+    ExitCodeSpec {
+        code: 1,
+        name: "failed",
+        help: "Any other failure, e.g. a file could not be read or written.",
+    },
+    ExitCodeSpec {
+        code: 2,
+        name: "usage_error",
+        help: "Usage error (bad flags or arguments).",
+    },
+    ExitCodeSpec {
+        code: 3,
+        name: "partial",
+        help: "With --partial: some file sections applied and others failed.",
+    },
+    ExitCodeSpec {
+        code: 4,
+        name: "applied_with_warning",
+        help: "Patch applied in warn mode; the warn banner was printed.",
+    },
+    ExitCodeSpec {
+        code: 5,
+        name: "refused_by_mode",
+        help: "Refused because the base mode is refuse.",
+    },
+    ExitCodeSpec {
+        code: 6,
+        name: "refused_by_policy",
+        help: "Refused by a policy rule, escalation or guard.",
+    },
+    ExitCodeSpec {
+        code: 7,
+        name: "parse_error",
+        help: "The patch could not be parsed.",
+    },
+    ExitCodeSpec {
+        code: 8,
+        name: "context_mismatch",
+        help: "The files on disk don't match what the patch expects.",
+    },
+    ExitCodeSpec {
+        code: 9,
+        name: "config_error",
+        help: "The config file or a policy expression is invalid.",
+    },
+];
+
+pub(crate) fn flag(arg: &str) -> Option<&'static FlagSpec> {
+    FLAGS.iter().find(|f| f.name == arg || f.short == Some(arg))
+}
+
+pub(crate) fn subcommand(arg: &str) -> Option<&'static SubcommandSpec> {
+    SUBCOMMANDS.iter().find(|s| s.name == arg)
+}
+
+/// `--name <value>` as shown in help output.
+pub(crate) fn flag_usage(spec: &FlagSpec) -> String {
+    let mut usage = match spec.short {
+        Some(short) => format!("{short}, {}", spec.name),
+        None => spec.name.to_string(),
+    };
+    if let Some(value) = spec.value {
+        usage.push_str(&format!(" <{value}>"));
+    }
+    usage
+}
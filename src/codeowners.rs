@@ -0,0 +1,174 @@
+//! CODEOWNERS lanes (`codeowners` in the config): raises the mode when a
+//! patch touches paths owned by someone other than the configured owners,
+//! so an autonomous agent in a monorepo stays inside its team's area.
+//!
+//! The file is read from `.github/CODEOWNERS`, `CODEOWNERS` or
+//! `docs/CODEOWNERS` at the repository root, first found wins, and, as on
+//! GitHub, the last matching line decides a path's owners. Outside a
+//! repository, or without a CODEOWNERS file, nothing is checked.
+
+use crate::Mode;
+use crate::gitscope::normalize;
+use crate::glob::glob_match_whole;
+use crate::policy::severity;
+use serde::Deserialize;
+use serde::Serialize;
+use std::path::Path;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct CodeownersConfig {
+    /// Owners whose paths may be patched, as written in CODEOWNERS (e.g.
+    /// `@org/team`).
+    #[serde(default)]
+    pub(crate) owners: Vec<String>,
+    /// Mode to enforce for other owners' paths; off when unset.
+    #[serde(default)]
+    pub(crate) action: Option<Mode>,
+    /// Whether paths without any owner may be patched (default: true).
+    #[serde(default = "default_allow_unowned")]
+    pub(crate) allow_unowned: bool,
+}
+
+fn default_allow_unowned() -> bool {
+    true
+}
+
+impl Default for CodeownersConfig {
+    fn default() -> Self {
+        Self {
+            owners: Vec::new(),
+            action: None,
+            allow_unowned: default_allow_unowned(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct ForeignFile {
+    pub(crate) path: String,
+    /// The path's owners; empty when it has none.
+    pub(crate) owners: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct CodeownersGuard {
+    pub(crate) files: Vec<ForeignFile>,
+    pub(crate) to: Mode,
+}
+
+impl CodeownersGuard {
+    pub(crate) fn describe(&self) -> Vec<String> {
+        let mode = self.to.as_str();
+        self.files
+            .iter()
+            .map(|file| match file.owners.as_slice() {
+                [] => format!(
+                    "CODEOWNERS ({mode} mode): {} has no owner, and unowned paths are outside this agent's lane.",
+                    file.path
+                ),
+                owners => format!(
+                    "CODEOWNERS ({mode} mode): {} is owned by {}; leave it to them.",
+                    file.path,
+                    owners.join(" ")
+                ),
+            })
+            .collect()
+    }
+}
+
+/// Checks `files` (relative to the current directory) against the
+/// repository's CODEOWNERS. Returns `None` when the guard is off, there is
+/// no CODEOWNERS file, or every file is in the configured owners' lane.
+pub(crate) fn guard(
+    cfg: &CodeownersConfig,
+    files: &[String],
+    mode: Mode,
+) -> Option<CodeownersGuard> {
+    let action = cfg.action?;
+    let cwd = std::env::current_dir().ok()?;
+    let root = cwd.ancestors().find(|dir| dir.join(".git").exists())?;
+    let rules = load(root)?;
+    let foreign: Vec<ForeignFile> = files
+        .iter()
+        .filter_map(|file| {
+            let absolute = normalize(&cwd.join(file));
+            let relative = absolute.strip_prefix(root).ok()?;
+            let owners = owners_of(&rules, &relative.to_string_lossy().replace('\\', "/"));
+            let allowed = match owners {
+                [] => cfg.allow_unowned,
+                owners => owners.iter().any(|owner| cfg.owners.contains(owner)),
+            };
+            (!allowed).then(|| ForeignFile {
+                path: file.clone(),
+                owners: owners.to_vec(),
+            })
+        })
+        .collect();
+    if foreign.is_empty() {
+        return None;
+    }
+    let to = if severity(action) > severity(mode) {
+        action
+    } else {
+        mode
+    };
+    Some(CodeownersGuard { files: foreign, to })
+}
+
+/// A CODEOWNERS line: a pattern and its owners (possibly none, which
+/// removes ownership).
+struct Rule {
+    pattern: String,
+    owners: Vec<String>,
+}
+
+fn load(root: &Path) -> Option<Vec<Rule>> {
+    let contents = [".github/CODEOWNERS", "CODEOWNERS", "docs/CODEOWNERS"]
+        .iter()
+        .find_map(|name| std::fs::read_to_string(root.join(name)).ok())?;
+    Some(parse(&contents))
+}
+
+fn parse(contents: &str) -> Vec<Rule> {
+    contents
+        .lines()
+        .filter_map(|line| {
+            let line = line.split_once(" #").map_or(line, |(rule, _)| rule).trim();
+            if line.is_empty() || line.starts_with('#') {
+                return None;
+            }
+            let mut fields = line.split_whitespace();
+            let pattern = fields.next()?.to_string();
+            Some(Rule {
+                pattern,
+                owners: fields.map(str::to_string).collect(),
+            })
+        })
+        .collect()
+}
+
+/// Owners of `path` (relative to the repository root) by the last
+/// matching rule.
+fn owners_of<'a>(rules: &'a [Rule], path: &str) -> &'a [String] {
+    rules
+        .iter()
+        .rev()
+        .find(|rule| pattern_matches(&rule.pattern, path))
+        .map_or(&[], |rule| rule.owners.as_slice())
+}
+
+/// gitignore-style matching: a pattern with a leading or inner `/` is
+/// anchored at the root, others match at any depth, a match on a directory
+/// covers everything under it, and a trailing `/` only matches directories.
+fn pattern_matches(pattern: &str, path: &str) -> bool {
+    let dir_only = pattern.ends_with('/');
+    let trimmed = pattern.trim_end_matches('/');
+    let anchored = trimmed.contains('/');
+    let trimmed = trimmed.trim_start_matches('/');
+    let base = if anchored {
+        trimmed.to_string()
+    } else {
+        format!("**/{trimmed}")
+    };
+    (!dir_only && glob_match_whole(&base, path)) || glob_match_whole(&format!("{base}/**"), path)
+}
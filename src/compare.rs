@@ -0,0 +1,211 @@
+//! `apply_patch compare [--json] [PATCH]`: checks each hunk of a patch
+//! against the current tree without applying it.
+//!
+//! Every hunk is reported as `applies` (its pre-image is there), `applied`
+//! (the file already has its post-image) or `diverged` (neither), so a
+//! stored patch can be triaged after other changes have landed. Hunks are
+//! checked independently, with the same fuzzy matching as the applier.
+
+use crate::diagnostics;
+use crate::patch::Chunk;
+use crate::patch::Patch;
+use crate::patch::Section;
+use crate::patch::SectionKind;
+use crate::simulate::seek_sequence;
+use serde::Serialize;
+use std::path::Path;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum HunkStatus {
+    Applies,
+    Applied,
+    Diverged,
+}
+
+impl HunkStatus {
+    fn as_str(self) -> &'static str {
+        match self {
+            HunkStatus::Applies => "applies",
+            HunkStatus::Applied => "applied",
+            HunkStatus::Diverged => "diverged",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct HunkReport {
+    pub(crate) path: String,
+    /// 1-based chunk number within an update section; `None` for add and
+    /// delete sections, which are compared as a whole.
+    pub(crate) hunk: Option<usize>,
+    pub(crate) status: HunkStatus,
+}
+
+/// Compares every section of `patch` with the files under `root`.
+pub(crate) fn compare(patch: &Patch, root: &Path) -> Result<Vec<HunkReport>, String> {
+    let mut reports = Vec::new();
+    for section in &patch.sections {
+        let current = std::fs::read_to_string(root.join(&section.path)).ok();
+        let whole = |status| HunkReport {
+            path: section.path.clone(),
+            hunk: None,
+            status,
+        };
+        match section.kind {
+            SectionKind::Add => reports.push(whole(match current {
+                None => HunkStatus::Applies,
+                Some(text) if text == section.added_contents() => HunkStatus::Applied,
+                Some(_) => HunkStatus::Diverged,
+            })),
+            SectionKind::Delete => reports.push(whole(match current {
+                Some(_) => HunkStatus::Applies,
+                None => HunkStatus::Applied,
+            })),
+            SectionKind::Update => reports.extend(compare_update(section, current, root)?),
+        }
+    }
+    Ok(reports)
+}
+
+fn compare_update(
+    section: &Section,
+    current: Option<String>,
+    root: &Path,
+) -> Result<Vec<HunkReport>, String> {
+    let chunks = section.chunks()?;
+    // A moved file that is only at its destination was probably moved by
+    // an earlier apply of this patch.
+    let (current, moved) = match (current, section.move_to()) {
+        (Some(text), _) => (Some(text), false),
+        (None, Some(dest)) => (std::fs::read_to_string(root.join(dest)).ok(), true),
+        (None, None) => (None, false),
+    };
+    let lines: Vec<String> = current
+        .as_deref()
+        .map(|text| {
+            let mut lines: Vec<String> = text.split('\n').map(str::to_string).collect();
+            if lines.last().is_some_and(String::is_empty) {
+                lines.pop();
+            }
+            lines
+        })
+        .unwrap_or_default();
+    Ok(chunks
+        .iter()
+        .enumerate()
+        .map(|(i, chunk)| HunkReport {
+            path: section.path.clone(),
+            hunk: Some(i + 1),
+            status: match &current {
+                None => HunkStatus::Diverged,
+                Some(_) => match chunk_status(&lines, chunk) {
+                    // Only the post-image can be at the move destination.
+                    HunkStatus::Applies if moved => HunkStatus::Diverged,
+                    status => status,
+                },
+            },
+        })
+        .collect())
+}
+
+fn chunk_status(lines: &[String], chunk: &Chunk) -> HunkStatus {
+    let start = match &chunk.change_context {
+        Some(context) => match seek_sequence(lines, std::slice::from_ref(context), 0, false) {
+            Some(found) => found + 1,
+            None => return HunkStatus::Diverged,
+        },
+        None => 0,
+    };
+    if chunk.old_lines.is_empty() {
+        // Pure insertion at the end of the file.
+        let tail = lines.len().saturating_sub(chunk.new_lines.len());
+        return if !chunk.new_lines.is_empty() && lines[tail..] == chunk.new_lines[..] {
+            HunkStatus::Applied
+        } else {
+            HunkStatus::Applies
+        };
+    }
+    let old = find(lines, &chunk.old_lines, start, chunk.is_end_of_file);
+    let new = find(lines, &chunk.new_lines, start, chunk.is_end_of_file);
+    match (old, new) {
+        // When both are present (e.g. the post-image is just context), the
+        // longer, more specific one decides.
+        (true, true) if chunk.new_lines.len() > chunk.old_lines.len() => HunkStatus::Applied,
+        (true, _) => HunkStatus::Applies,
+        (false, true) => HunkStatus::Applied,
+        (false, false) => HunkStatus::Diverged,
+    }
+}
+
+/// Like the applier, retries without a trailing empty line.
+fn find(lines: &[String], pattern: &[String], start: usize, eof: bool) -> bool {
+    if pattern.is_empty() {
+        return false;
+    }
+    if seek_sequence(lines, pattern, start, eof).is_some() {
+        return true;
+    }
+    pattern.last().is_some_and(String::is_empty)
+        && pattern.len() > 1
+        && seek_sequence(lines, &pattern[..pattern.len() - 1], start, eof).is_some()
+}
+
+pub(crate) fn run_compare(args: &[String]) -> i32 {
+    let mut json = false;
+    let mut positional: Vec<String> = Vec::new();
+    for (i, arg) in args.iter().enumerate() {
+        match arg.as_str() {
+            "--json" => json = true,
+            "--" => {
+                positional.extend(args[i + 1..].iter().cloned());
+                break;
+            }
+            other if other.starts_with('-') => {
+                diagnostics::error(format!("unknown option: {other}"));
+                return 2;
+            }
+            other => positional.push(other.to_string()),
+        }
+    }
+    let patch_text = match crate::read_patch(&positional) {
+        Ok(s) => s,
+        Err(code) => return code,
+    };
+    let Some(patch) = Patch::parse(&patch_text) else {
+        eprintln!("Invalid patch: The first line of the patch must be '*** Begin Patch'");
+        return 1;
+    };
+    let reports = match compare(&patch, Path::new(".")) {
+        Ok(reports) => reports,
+        Err(err) => {
+            eprintln!("Invalid patch: {err}");
+            return 1;
+        }
+    };
+
+    if json {
+        match serde_json::to_string(&reports) {
+            Ok(line) => println!("{line}"),
+            Err(err) => {
+                diagnostics::error(format!("failed to serialize comparison: {err}"));
+                return 1;
+            }
+        }
+        return 0;
+    }
+    for report in &reports {
+        match report.hunk {
+            Some(n) => println!("{:<8} {} @@ {n}", report.status.as_str(), report.path),
+            None => println!("{:<8} {}", report.status.as_str(), report.path),
+        }
+    }
+    let count = |status| reports.iter().filter(|r| r.status == status).count();
+    println!(
+        "{} applies, {} applied, {} diverged",
+        count(HunkStatus::Applies),
+        count(HunkStatus::Applied),
+        count(HunkStatus::Diverged)
+    );
+    0
+}
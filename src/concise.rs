@@ -0,0 +1,99 @@
+//! Concise output (`--concise`, or `concise.enabled` in the config), for
+//! callers whose output goes straight into a model's context window.
+//!
+//! The success summary becomes a single `Success. A new.rs, M lib.rs` line
+//! instead of a header plus one line per file, and echoed messages (the
+//! warn, refuse and apply messages and the notices before them) are cut to
+//! `concise.message_bytes` bytes, between words where possible, ending in
+//! `…`. Everything else, errors included, is printed as usual.
+
+use serde::Deserialize;
+use serde::Serialize;
+use std::borrow::Cow;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct ConciseConfig {
+    #[serde(default)]
+    pub(crate) enabled: bool,
+    /// Longest echoed message, in bytes (default: 200).
+    #[serde(default = "default_message_bytes")]
+    pub(crate) message_bytes: usize,
+}
+
+fn default_message_bytes() -> usize {
+    200
+}
+
+impl Default for ConciseConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            message_bytes: default_message_bytes(),
+        }
+    }
+}
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static MESSAGE_BYTES: AtomicUsize = AtomicUsize::new(usize::MAX);
+
+/// Turns concise output on or off for the rest of the process.
+pub(crate) fn configure(enabled: bool, cfg: &ConciseConfig) {
+    ENABLED.store(enabled, Ordering::Relaxed);
+    MESSAGE_BYTES.store(cfg.message_bytes, Ordering::Relaxed);
+}
+
+pub(crate) fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Prints the success summary: the applier's header and `lines` (`A path`,
+/// `M path`, ...), or one line when concise.
+pub(crate) fn print_success(lines: &[String]) {
+    if enabled() {
+        if lines.is_empty() {
+            println!("Success.");
+        } else {
+            println!("Success. {}", lines.join(", "));
+        }
+        return;
+    }
+    println!("Success. Updated the following files:");
+    for line in lines {
+        println!("{line}");
+    }
+}
+
+/// Prints what the applier wrote to stdout, compressing its success
+/// summary when concise.
+pub(crate) fn print_applier_output(output: &[u8]) {
+    let text = String::from_utf8_lossy(output);
+    let mut lines = text.lines();
+    if lines.next() == Some("Success. Updated the following files:") {
+        print_success(&lines.map(str::to_string).collect::<Vec<_>>());
+    } else {
+        print!("{text}");
+    }
+}
+
+/// `message`, cut to the byte budget when concise.
+pub(crate) fn clip(message: &str) -> Cow<'_, str> {
+    let budget = MESSAGE_BYTES.load(Ordering::Relaxed);
+    if !enabled() || message.len() <= budget {
+        return Cow::Borrowed(message);
+    }
+    let ellipsis = "…";
+    let mut end = budget.saturating_sub(ellipsis.len());
+    while !message.is_char_boundary(end) {
+        end -= 1;
+    }
+    // Cut between words where there is one to cut at.
+    if !message[end..].starts_with(char::is_whitespace)
+        && let Some(space) = message[..end].rfind(char::is_whitespace)
+    {
+        end = space;
+    }
+    Cow::Owned(format!("{}{ellipsis}", message[..end].trim_end()))
+}
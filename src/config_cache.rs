@@ -0,0 +1,75 @@
+//! In-memory config cache for long-running modes such as `--watch` and
+//! `serve-http`.
+//!
+//! The file is re-parsed only when its modification time or size changes,
+//! so a busy watcher or server doesn't re-read and re-parse it for every
+//! patch while edits still take effect without a restart.
+
+use crate::Config;
+use crate::RunOptions;
+use crate::diagnostics;
+use std::time::SystemTime;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Stamp {
+    modified: Option<SystemTime>,
+    len: u64,
+}
+
+#[derive(Default)]
+pub(crate) struct ConfigCache {
+    /// The stamp of the file last loaded (`None` when there was no file) and
+    /// the config it produced, or why it failed to load.
+    loaded: Option<(Option<Stamp>, Result<Config, String>)>,
+}
+
+impl ConfigCache {
+    /// The effective config, reloaded if the file changed since last time.
+    /// `None` while the file can't be loaded; the error is printed once per
+    /// change.
+    pub(crate) fn get(&mut self, options: &RunOptions) -> Option<&Config> {
+        if self.refresh(options)
+            && let Some((_, Err(err))) = &self.loaded
+        {
+            diagnostics::error(err);
+        }
+        self.current(options).ok()
+    }
+
+    /// The effective config, reloaded if the file changed since last time,
+    /// or why it can't be loaded.
+    pub(crate) fn current(&mut self, options: &RunOptions) -> Result<&Config, &str> {
+        self.refresh(options);
+        match &self.loaded {
+            Some((_, Ok(cfg))) => Ok(cfg),
+            Some((_, Err(err))) => Err(err),
+            None => Err("the config has not been loaded"),
+        }
+    }
+
+    /// Reads the config again even if the file looks unchanged, e.g. after
+    /// an edit that kept its size within the clock's resolution.
+    pub(crate) fn reload(&mut self, options: &RunOptions) -> Result<&Config, &str> {
+        self.loaded = None;
+        self.current(options)
+    }
+
+    /// Loads the config if the file changed since last time. Returns whether
+    /// it did.
+    fn refresh(&mut self, options: &RunOptions) -> bool {
+        let stamp = crate::config_path()
+            .and_then(|path| std::fs::metadata(path).ok())
+            .map(|meta| Stamp {
+                modified: meta.modified().ok(),
+                len: meta.len(),
+            });
+        let fresh = self
+            .loaded
+            .as_ref()
+            .is_some_and(|(loaded_stamp, _)| *loaded_stamp == stamp);
+        if !fresh {
+            self.loaded = Some((stamp, crate::effective_config(options)));
+        }
+        !fresh
+    }
+}
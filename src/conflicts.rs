@@ -0,0 +1,175 @@
+//! Concurrent-write detection (`conflict_guard` in the config).
+//!
+//! While a whole patch is being written, a background thread polls the
+//! files it touches (every 50ms; the check once the applier is done catches
+//! what falls between polls). A file whose contents are neither what it held before
+//! the patch nor (part of) what the patch writes has been changed by
+//! someone else; so has one that doesn't hold the patch's result once the
+//! applier is done. Either way the patch is rolled back: the other files
+//! get their old contents back and the raced file is left with the other
+//! writer's version. The check also runs when the applier fails, since a
+//! concurrent write is a likely reason for the failure. Polling, like
+//! inotify, can't tell which process wrote, so only the file is named.
+
+use crate::simulate::FileChange;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+use std::thread::JoinHandle;
+use std::time::Duration;
+use std::time::SystemTime;
+
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// A file changed by someone else, and the contents they left
+/// (`None`: they deleted it).
+struct Race {
+    path: String,
+    seen: Option<String>,
+}
+
+/// Whether `current` is a state the patch can leave `change` in, on the
+/// way or at the end: the old contents, or a prefix of the new ones (the
+/// applier truncates before it writes), give or take the final newline.
+fn expected_state(change: &FileChange, current: Option<&str>) -> bool {
+    match current {
+        None => change.before.is_none() || change.after.is_none(),
+        Some(current) => {
+            change.before.as_deref() == Some(current)
+                || change.after.as_deref().is_some_and(|after| {
+                    after.starts_with(current) || current.strip_suffix('\n') == Some(after)
+                })
+        }
+    }
+}
+
+type Stamp = Option<(u64, Option<SystemTime>)>;
+
+fn stamp(path: &str) -> Stamp {
+    let meta = std::fs::metadata(path).ok()?;
+    Some((meta.len(), meta.modified().ok()))
+}
+
+pub(crate) struct Guard {
+    stop: Arc<AtomicBool>,
+    race: Arc<Mutex<Option<Race>>>,
+    poller: Option<JoinHandle<()>>,
+}
+
+impl Guard {
+    /// Starts watching the files in `changes` (as simulated before
+    /// writing).
+    pub(crate) fn start(changes: &[FileChange]) -> Self {
+        let changes = changes.to_vec();
+        let stop = Arc::new(AtomicBool::new(false));
+        let race: Arc<Mutex<Option<Race>>> = Arc::new(Mutex::new(None));
+        let poller = {
+            let (stop, race) = (stop.clone(), race.clone());
+            std::thread::spawn(move || {
+                // Nothing read yet, so the first pass also catches writes
+                // since the patch was simulated.
+                let mut stamps: Vec<Option<Stamp>> = vec![None; changes.len()];
+                while !stop.load(Ordering::Relaxed) {
+                    for (change, last) in changes.iter().zip(stamps.iter_mut()) {
+                        let now = Some(stamp(&change.path));
+                        if now == *last {
+                            continue;
+                        }
+                        *last = now;
+                        let current = std::fs::read_to_string(&change.path).ok();
+                        if !expected_state(change, current.as_deref()) {
+                            if let Ok(mut race) = race.lock() {
+                                race.get_or_insert(Race {
+                                    path: change.path.clone(),
+                                    seen: current,
+                                });
+                            }
+                            return;
+                        }
+                    }
+                    // Woken early by `finish`.
+                    std::thread::park_timeout(POLL_INTERVAL);
+                }
+            })
+        };
+        Self {
+            stop,
+            race,
+            poller: Some(poller),
+        }
+    }
+
+    /// Stops watching once the applier has finished, `applied` or not. If
+    /// another process wrote to a target meanwhile, or a target doesn't
+    /// hold what the patch can leave it in (its result as `expected` now
+    /// records it, or after a failure also its old contents), rolls the
+    /// patch back and says which file raced.
+    pub(crate) fn finish(mut self, expected: &[FileChange], applied: bool) -> Result<(), String> {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(poller) = self.poller.take() {
+            poller.thread().unpark();
+            let _ = poller.join();
+        }
+        let polled = self.race.lock().ok().and_then(|mut race| race.take());
+        let race = polled.or_else(|| {
+            expected.iter().find_map(|change| {
+                let current = std::fs::read_to_string(&change.path).ok();
+                let raced = if applied {
+                    current != change.after
+                } else {
+                    !expected_state(change, current.as_deref())
+                };
+                raced.then(|| Race {
+                    path: change.path.clone(),
+                    seen: current,
+                })
+            })
+        });
+        let Some(race) = race else {
+            return Ok(());
+        };
+        for change in expected {
+            let restore = if change.path == race.path {
+                // Put back the other writer's version if the applier
+                // overwrote it.
+                let current = std::fs::read_to_string(&change.path).ok();
+                if current != change.after {
+                    continue;
+                }
+                &race.seen
+            } else {
+                &change.before
+            };
+            if std::fs::read_to_string(&change.path).ok() == *restore {
+                continue;
+            }
+            let result = match restore {
+                Some(contents) => std::fs::write(&change.path, contents),
+                None => std::fs::remove_file(&change.path).or_else(|err| {
+                    if err.kind() == std::io::ErrorKind::NotFound {
+                        Ok(())
+                    } else {
+                        Err(err)
+                    }
+                }),
+            };
+            if let Err(err) = result {
+                return Err(format!(
+                    "{} was changed by another process while the patch was being applied, and rolling back {} failed: {err}",
+                    race.path, change.path
+                ));
+            }
+        }
+        Err(format!(
+            "{} was changed by another process while the patch was being applied, so the patch was rolled back. {} now holds the other process's version; read it again before patching it.",
+            race.path, race.path
+        ))
+    }
+}
+
+impl Drop for Guard {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+    }
+}
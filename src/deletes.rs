@@ -0,0 +1,94 @@
+//! Content verification for `*** Delete File:` sections.
+//!
+//! A delete section may carry the file's expected current content as `-`
+//! lines and/or its digest as `*** Expected SHA256: <hex>`. The deletion is
+//! refused when the file on disk differs, so a model cannot delete a file it
+//! has not actually read. `--force-delete` skips the comparison.
+
+use crate::digest::sha256_hex;
+use crate::patch::Patch;
+use crate::patch::SectionKind;
+
+pub(crate) const EXPECTED_SHA256_MARKER: &str = "*** Expected SHA256: ";
+
+#[derive(Debug, Default)]
+struct Expectation {
+    lines: Option<Vec<String>>,
+    sha256: Option<String>,
+}
+
+/// Checks every delete section that carries an expectation against the file
+/// on disk, then strips the expectations so the applier sees a plain delete.
+/// Returns the patch text to apply, or the error to report.
+pub(crate) fn verify_deletes(patch_text: &str, force: bool) -> Result<String, String> {
+    let Some(mut patch) = Patch::parse(patch_text) else {
+        return Ok(patch_text.to_string());
+    };
+
+    let mut stripped_any = false;
+    for section in patch
+        .sections
+        .iter_mut()
+        .filter(|s| s.kind == SectionKind::Delete)
+    {
+        let Some(expectation) = take_expectation(&mut section.body) else {
+            continue;
+        };
+        stripped_any = true;
+        if !force {
+            check_expectation(&section.path, &expectation)?;
+        }
+    }
+
+    if stripped_any {
+        Ok(patch.render())
+    } else {
+        Ok(patch_text.to_string())
+    }
+}
+
+fn take_expectation(body: &mut Vec<String>) -> Option<Expectation> {
+    let mut expectation = Expectation::default();
+    body.retain(|line| {
+        if let Some(hex) = line.trim().strip_prefix(EXPECTED_SHA256_MARKER) {
+            expectation.sha256 = Some(hex.trim().to_ascii_lowercase());
+            return false;
+        }
+        if let Some(content) = line.strip_prefix('-') {
+            expectation
+                .lines
+                .get_or_insert_with(Vec::new)
+                .push(content.to_string());
+            return false;
+        }
+        true
+    });
+    if expectation.lines.is_none() && expectation.sha256.is_none() {
+        return None;
+    }
+    Some(expectation)
+}
+
+fn check_expectation(path: &str, expectation: &Expectation) -> Result<(), String> {
+    // A missing or unreadable file is left for the applier to report.
+    let Ok(bytes) = std::fs::read(path) else {
+        return Ok(());
+    };
+
+    let lines_match = expectation.lines.as_ref().is_none_or(|expected| {
+        let actual = String::from_utf8_lossy(&bytes);
+        actual.lines().eq(expected.iter().map(String::as_str))
+    });
+    let digest_match = expectation
+        .sha256
+        .as_ref()
+        .is_none_or(|expected| *expected == sha256_hex(&bytes));
+
+    if lines_match && digest_match {
+        return Ok(());
+    }
+    Err(format!(
+        "Error: refusing to delete {path}: its current content does not match the content given in the patch.\n\
+         Re-read the file before deleting it, or pass --force-delete to skip this check."
+    ))
+}
@@ -0,0 +1,78 @@
+//! Warnings and errors reported while a patch is processed.
+//!
+//! Messages go to stderr as `Warning: ...` or `Error: ...` as they happen
+//! and are counted in one process-wide sink, so the run can end with a
+//! `2 warnings, 0 errors` line and `--deny-warnings` can turn warnings into
+//! a failure. Every `Error: ...` line goes through [`error`], so the count
+//! matches what was printed; applier failures print their own message and
+//! are counted by [`finish`].
+
+use std::fmt::Display;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+
+struct Diagnostics {
+    warnings: AtomicUsize,
+    errors: AtomicUsize,
+}
+
+static SINK: Diagnostics = Diagnostics {
+    warnings: AtomicUsize::new(0),
+    errors: AtomicUsize::new(0),
+};
+
+pub(crate) fn warning(message: impl Display) {
+    eprintln!("Warning: {message}");
+    SINK.warnings.fetch_add(1, Ordering::Relaxed);
+}
+
+pub(crate) fn error(message: impl Display) {
+    eprintln!("Error: {message}");
+    SINK.errors.fetch_add(1, Ordering::Relaxed);
+}
+
+/// The warnings reported so far, so `--deny-warnings` can stop a patch
+/// before it is written.
+pub(crate) fn warnings() -> usize {
+    SINK.warnings.load(Ordering::Relaxed)
+}
+
+/// Forgets what was counted, so each run in a long-lived process
+/// (`--watch`, `serve-http`, the C ABI) starts from zero whatever was
+/// reported between runs.
+pub(crate) fn discard() {
+    SINK.warnings.store(0, Ordering::Relaxed);
+    SINK.errors.store(0, Ordering::Relaxed);
+}
+
+/// Ends a patch's diagnostics: prints the counts if there were warnings,
+/// with a run that `failed` counting as at least one error, and starts the
+/// next patch from zero. A failure without warnings already ends with its
+/// error, so it gets no count line. Returns whether `deny_warnings` makes
+/// the run fail.
+pub(crate) fn finish(failed: bool, deny_warnings: bool) -> bool {
+    let warnings = SINK.warnings.swap(0, Ordering::Relaxed);
+    let errors = SINK
+        .errors
+        .swap(0, Ordering::Relaxed)
+        .max(usize::from(failed));
+    if warnings == 0 {
+        return false;
+    }
+    let denied = deny_warnings;
+    eprintln!(
+        "{warnings} warning{}, {errors} error{}{}",
+        plural(warnings),
+        plural(errors),
+        if denied {
+            "; failing because of --deny-warnings"
+        } else {
+            ""
+        }
+    );
+    denied
+}
+
+fn plural(n: usize) -> &'static str {
+    if n == 1 { "" } else { "s" }
+}
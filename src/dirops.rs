@@ -0,0 +1,197 @@
+//! `*** Delete Directory: DIR` and `*** Add Directory: DIR` sections, for
+//! agents restructuring a project. Both need `allow_dir_ops` in the config.
+//!
+//! A directory delete is expanded into one `*** Delete File:` section per
+//! file under DIR before anything else looks at the patch, so policies and
+//! guards see every file it removes; the emptied directories are removed
+//! once the patch has applied. A delete covering more than
+//! `max_dir_delete_files` files is refused. An add creates the (possibly
+//! empty) directory after the patch applies; its path is among the patch's
+//! files for policies and the path guards, and `new_dirs` judges it like a
+//! directory created for a file.
+
+use crate::Mode;
+use crate::archive::contained_name;
+use crate::diagnostics;
+use crate::patch::DELETE_FILE_MARKER;
+use serde::Serialize;
+use std::borrow::Cow;
+use std::path::Path;
+
+pub(crate) const ADD_DIRECTORY_MARKER: &str = "*** Add Directory: ";
+pub(crate) const DELETE_DIRECTORY_MARKER: &str = "*** Delete Directory: ";
+
+pub(crate) fn default_max_dir_delete_files() -> usize {
+    100
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct DeletedDir {
+    pub(crate) path: String,
+    /// Files under the directory, each deleted by its own section.
+    pub(crate) files: usize,
+}
+
+/// The directory sections of one patch.
+#[derive(Debug, Default)]
+pub(crate) struct DirOps {
+    pub(crate) added: Vec<String>,
+    pub(crate) deleted: Vec<DeletedDir>,
+}
+
+impl DirOps {
+    pub(crate) fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.deleted.is_empty()
+    }
+
+    /// The first delete over `max_files`, if any.
+    pub(crate) fn too_large(&self, max_files: usize) -> Option<&DeletedDir> {
+        self.deleted.iter().find(|dir| dir.files > max_files)
+    }
+
+    /// Removes the directories the patch emptied and creates the added
+    /// ones, printing a summary line for each. A dry run only prints.
+    pub(crate) fn finish(&self, dry_run: bool) {
+        for dir in &self.deleted {
+            if !dry_run {
+                remove_empty(Path::new(&dir.path));
+            }
+            println!("D {}/", dir.path);
+        }
+        for dir in &self.added {
+            if !dry_run && let Err(err) = std::fs::create_dir_all(dir) {
+                diagnostics::warning(format!("failed to create directory {dir}: {err}"));
+                continue;
+            }
+            println!("A {dir}/");
+        }
+    }
+}
+
+/// A refused directory delete, reported like the other guards.
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct DirDeleteGuard {
+    pub(crate) dir: DeletedDir,
+    pub(crate) max_files: usize,
+    pub(crate) to: Mode,
+}
+
+impl DirDeleteGuard {
+    pub(crate) fn describe(&self) -> String {
+        format!(
+            "Directory delete (refuse mode): {}/ holds {} files, more than max_dir_delete_files ({}).",
+            self.dir.path, self.dir.files, self.max_files
+        )
+    }
+}
+
+pub(crate) fn guard(ops: &DirOps, max_files: usize) -> Option<DirDeleteGuard> {
+    ops.too_large(max_files).map(|dir| DirDeleteGuard {
+        dir: dir.clone(),
+        max_files,
+        to: Mode::Refuse,
+    })
+}
+
+/// Replaces every directory section in `patch_text`: deletes become one
+/// delete section per file, adds are removed and returned for
+/// [`DirOps::finish`]. Patches without directory sections are returned
+/// unchanged.
+pub(crate) fn expand(patch_text: &str, allowed: bool) -> Result<(Cow<'_, str>, DirOps), String> {
+    let is_dir_section = |line: &str| {
+        let line = line.trim_start();
+        line.starts_with(ADD_DIRECTORY_MARKER) || line.starts_with(DELETE_DIRECTORY_MARKER)
+    };
+    if !patch_text.lines().any(is_dir_section) {
+        return Ok((Cow::Borrowed(patch_text), DirOps::default()));
+    }
+    if !allowed {
+        return Err(
+            "Directory operations are disabled: set allow_dir_ops in the config to use `*** Add Directory:` and `*** Delete Directory:`."
+                .to_string(),
+        );
+    }
+    let mut ops = DirOps::default();
+    let mut out = String::new();
+    let mut lines = patch_text.lines().peekable();
+    while let Some(line) = lines.next() {
+        if !is_dir_section(line) {
+            out.push_str(line);
+            out.push('\n');
+            continue;
+        }
+        let line = line.trim();
+        let (delete, raw) = match line.strip_prefix(DELETE_DIRECTORY_MARKER) {
+            Some(raw) => (true, raw.trim()),
+            None => (
+                false,
+                line.strip_prefix(ADD_DIRECTORY_MARKER)
+                    .unwrap_or_default()
+                    .trim(),
+            ),
+        };
+        let fail = |msg: &str| format!("Invalid directory section for {raw}: {msg}");
+        while let Some(body) = lines.next_if(|l| !l.trim_start().starts_with("***")) {
+            if !body.trim().is_empty() {
+                return Err(fail("directory sections take no body"));
+            }
+        }
+        let Some(path) = contained_name(raw) else {
+            return Err(fail(
+                "the path must be relative and stay in the working directory",
+            ));
+        };
+        let meta = std::fs::symlink_metadata(&path).ok();
+        if delete {
+            if !meta.as_ref().is_some_and(|meta| meta.is_dir()) {
+                return Err(fail("not a directory"));
+            }
+            let mut files = Vec::new();
+            collect_files(Path::new(&path), &mut files)
+                .map_err(|err| fail(&format!("cannot read it: {err}")))?;
+            for file in &files {
+                out.push_str(DELETE_FILE_MARKER);
+                out.push_str(file);
+                out.push('\n');
+            }
+            ops.deleted.push(DeletedDir {
+                path,
+                files: files.len(),
+            });
+        } else {
+            if meta.is_some_and(|meta| !meta.is_dir()) {
+                return Err(fail("a file with that name exists"));
+            }
+            ops.added.push(path);
+        }
+    }
+    Ok((Cow::Owned(out), ops))
+}
+
+/// Every non-directory under `dir`, sorted, with symlinks not followed.
+fn collect_files(dir: &Path, files: &mut Vec<String>) -> std::io::Result<()> {
+    let mut entries = std::fs::read_dir(dir)?.collect::<Result<Vec<_>, _>>()?;
+    entries.sort_by_key(|entry| entry.file_name());
+    for entry in entries {
+        let path = dir.join(entry.file_name());
+        if entry.file_type()?.is_dir() {
+            collect_files(&path, files)?;
+        } else {
+            files.push(path.to_string_lossy().into_owned());
+        }
+    }
+    Ok(())
+}
+
+/// Removes `dir` and the directories under it that are empty, bottom-up.
+/// Anything still holding files is left alone.
+fn remove_empty(dir: &Path) {
+    if let Ok(entries) = std::fs::read_dir(dir) {
+        for entry in entries.flatten() {
+            if entry.file_type().is_ok_and(|kind| kind.is_dir()) {
+                remove_empty(&entry.path());
+            }
+        }
+    }
+    let _ = std::fs::remove_dir(dir);
+}
@@ -0,0 +1,149 @@
+//! Free-space check before writing (`disk_space` in the config).
+//!
+//! The applier writes files one after another, so running out of space
+//! midway leaves a half-applied patch. Before anything is written, the
+//! bytes each filesystem has to gain (how much the patched files grow) and
+//! the files it has to create are compared with what `df` reports as
+//! available, and the run fails early if either doesn't fit. Without `df`,
+//! or when its output can't be read, nothing is checked.
+
+use crate::simulate::FileChange;
+use serde::Deserialize;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::path::PathBuf;
+use std::process::Command;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct DiskSpaceConfig {
+    /// Whether to check at all (default: true).
+    #[serde(default = "default_check")]
+    pub(crate) check: bool,
+    /// Bytes that must stay free on each filesystem after the patch.
+    #[serde(default)]
+    pub(crate) reserve_bytes: u64,
+}
+
+fn default_check() -> bool {
+    true
+}
+
+impl Default for DiskSpaceConfig {
+    fn default() -> Self {
+        Self {
+            check: default_check(),
+            reserve_bytes: 0,
+        }
+    }
+}
+
+/// What the patch needs from one filesystem.
+#[derive(Debug, Default)]
+struct Need {
+    bytes: u64,
+    files: u64,
+}
+
+/// Fails if writing `changes` would need more space or inodes than some
+/// filesystem has available.
+pub(crate) fn check(changes: &[FileChange], cfg: &DiskSpaceConfig) -> Result<(), String> {
+    if !cfg.check {
+        return Ok(());
+    }
+    let mut needs: BTreeMap<PathBuf, Need> = BTreeMap::new();
+    for change in changes {
+        let Some(after) = &change.after else {
+            continue;
+        };
+        let before = change.before.as_ref().map_or(0, String::len);
+        let need = needs.entry(existing_dir(&change.path)).or_default();
+        need.bytes += after.len().saturating_sub(before) as u64;
+        need.files += u64::from(change.before.is_none());
+    }
+    needs.retain(|_, need| need.bytes > 0 || need.files > 0);
+    if needs.is_empty() {
+        return Ok(());
+    }
+    let dirs: Vec<&PathBuf> = needs.keys().collect();
+    let (Some(blocks), inodes) = (df(&dirs, "-Pk"), df(&dirs, "-Pi")) else {
+        return Ok(());
+    };
+
+    // Several directories can share a filesystem; add up their needs.
+    let mut by_mount: BTreeMap<&str, (Need, u64, Option<u64>)> = BTreeMap::new();
+    for (i, need) in needs.values().enumerate() {
+        let (mount, available_kib) = &blocks[i];
+        let free_inodes = inodes.as_ref().map(|rows| rows[i].1);
+        let entry = by_mount.entry(mount.as_str()).or_insert((
+            Need::default(),
+            available_kib.saturating_mul(1024),
+            free_inodes,
+        ));
+        entry.0.bytes += need.bytes;
+        entry.0.files += need.files;
+    }
+    for (mount, (need, available, free_inodes)) in by_mount {
+        if need.bytes.saturating_add(cfg.reserve_bytes) > available {
+            let reserve = if cfg.reserve_bytes > 0 {
+                format!(
+                    " and disk_space.reserve_bytes keeps {} free",
+                    cfg.reserve_bytes
+                )
+            } else {
+                String::new()
+            };
+            return Err(format!(
+                "Not enough disk space on {mount}: the patch needs {} more bytes{reserve}, but only {available} are available. Nothing was changed.",
+                need.bytes
+            ));
+        }
+        if let Some(free) = free_inodes
+            && need.files > free
+        {
+            return Err(format!(
+                "Not enough inodes on {mount}: the patch adds {} files, but only {free} are free. Nothing was changed.",
+                need.files
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// The nearest existing directory that will hold `path`.
+fn existing_dir(path: &str) -> PathBuf {
+    let mut dir = Path::new(path).parent();
+    while let Some(candidate) = dir {
+        if candidate.as_os_str().is_empty() {
+            break;
+        }
+        if candidate.is_dir() {
+            return candidate.to_path_buf();
+        }
+        dir = candidate.parent();
+    }
+    PathBuf::from(".")
+}
+
+/// Runs `df FLAG DIRS...` and returns the mount point and the "available"
+/// column for each directory, in order. Filesystems that don't report
+/// inodes (a total of 0) count as unlimited.
+fn df(dirs: &[&PathBuf], flag: &str) -> Option<Vec<(String, u64)>> {
+    let output = Command::new("df").arg(flag).args(dirs).output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    let text = String::from_utf8(output.stdout).ok()?;
+    let rows: Vec<(String, u64)> = text
+        .lines()
+        .skip(1)
+        .map(|line| {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let total: u64 = fields.get(1)?.parse().ok()?;
+            let available: u64 = fields.get(3)?.parse().ok()?;
+            let available = if total == 0 { u64::MAX } else { available };
+            Some((fields.get(5..)?.join(" "), available))
+        })
+        .collect::<Option<_>>()?;
+    (rows.len() == dirs.len()).then_some(rows)
+}
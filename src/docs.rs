@@ -0,0 +1,248 @@
+//! `--help` text and the `generate-docs` subcommand (man page / Markdown).
+//!
+//! Everything is rendered from the tables in `cli`, so packagers can
+//! generate reference docs at build time that match the binary exactly.
+
+use crate::cli;
+use crate::cli::FlagGroup;
+use crate::diagnostics;
+use std::fmt::Write as _;
+use std::path::PathBuf;
+
+const NAME: &str = "apply_patch";
+const SUMMARY: &str = "apply Codex-style patches from the shell, with LLM guardrails";
+const DESCRIPTION: &str =
+    "Applies Codex-style *** Begin Patch patches from stdin (or a single PATCH argument).";
+const SEPARATOR_NOTE: &str =
+    "Arguments after `--` are never treated as flags, so wrappers can pass any PATCH safely.";
+
+fn group_title(group: FlagGroup) -> &'static str {
+    match group {
+        FlagGroup::Config => "Config flags (persist in your home directory)",
+        FlagGroup::Run => "Run flags (apply to this invocation only)",
+        FlagGroup::Info => "Other flags",
+    }
+}
+
+const GROUPS: [FlagGroup; 3] = [FlagGroup::Config, FlagGroup::Run, FlagGroup::Info];
+
+pub(crate) fn help_text() -> String {
+    let mut out = String::new();
+    let _ = writeln!(out, "{NAME}\n\n{DESCRIPTION}\n");
+    let _ = writeln!(out, "Usage: {NAME} [RUN FLAGS] [--] [PATCH]");
+    let _ = writeln!(out, "       {NAME} <COMMAND> [ARGS]");
+    let _ = writeln!(out, "  {SEPARATOR_NOTE}");
+    for group in GROUPS {
+        let _ = writeln!(out, "\n{}:", group_title(group));
+        for spec in cli::FLAGS.iter().filter(|f| f.group == group) {
+            let _ = writeln!(out, "  {:<34} {}", cli::flag_usage(spec), spec.help);
+        }
+    }
+    let _ = writeln!(out, "\nCommands:");
+    for cmd in cli::SUBCOMMANDS {
+        let _ = writeln!(out, "  {}\n      {}", cmd.usage, cmd.help);
+    }
+    let _ = writeln!(out, "\nNotes:");
+    let _ = writeln!(
+        out,
+        "  - Config is stored at $XDG_CONFIG_HOME/.apply_patch/config.json (or ~/.apply_patch/config.json)."
+    );
+    let _ = writeln!(
+        out,
+        "  - You can override the config path with $APPLY_PATCH_CONFIG."
+    );
+    let _ = write!(
+        out,
+        "  - Run `{NAME} generate-docs markdown` for config keys, environment variables and exit codes."
+    );
+    out
+}
+
+fn roff_escape(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('-', "\\-")
+}
+
+pub(crate) fn render_man() -> String {
+    let mut out = String::new();
+    let _ = writeln!(out, ".TH APPLY_PATCH 1");
+    let _ = writeln!(
+        out,
+        ".SH NAME\n{} \\- {}",
+        roff_escape(NAME),
+        roff_escape(SUMMARY)
+    );
+    let _ = writeln!(
+        out,
+        ".SH SYNOPSIS\n.B {0}\n[\\fIRUN FLAGS\\fR] [\\fB\\-\\-\\fR] [\\fIPATCH\\fR]\n.br\n.B {0}\n\\fICOMMAND\\fR [\\fIARGS\\fR]",
+        roff_escape(NAME)
+    );
+    let _ = writeln!(
+        out,
+        ".SH DESCRIPTION\n{}\n.PP\n{}",
+        roff_escape(DESCRIPTION),
+        roff_escape(SEPARATOR_NOTE)
+    );
+    for group in GROUPS {
+        let _ = writeln!(out, ".SS {}", roff_escape(group_title(group)));
+        for spec in cli::FLAGS.iter().filter(|f| f.group == group) {
+            let _ = writeln!(
+                out,
+                ".TP\n.B {}\n{}",
+                roff_escape(&cli::flag_usage(spec)),
+                roff_escape(spec.help)
+            );
+        }
+    }
+    let _ = writeln!(out, ".SH COMMANDS");
+    for cmd in cli::SUBCOMMANDS {
+        let _ = writeln!(
+            out,
+            ".TP\n.B {}\n{}",
+            roff_escape(cmd.usage),
+            roff_escape(cmd.help)
+        );
+    }
+    let _ = writeln!(out, ".SH CONFIGURATION");
+    for key in cli::CONFIG_KEYS {
+        let _ = writeln!(
+            out,
+            ".TP\n.B {}\n{}",
+            roff_escape(key.key),
+            roff_escape(key.help)
+        );
+    }
+    let _ = writeln!(out, ".SH ENVIRONMENT");
+    for (name, help) in cli::ENV_VARS {
+        let _ = writeln!(out, ".TP\n.B {}\n{}", roff_escape(name), roff_escape(help));
+    }
+    let _ = writeln!(out, ".SH EXIT STATUS");
+    for (code, help) in cli::EXIT_CODES {
+        let _ = writeln!(out, ".TP\n.B {code}\n{}", roff_escape(help));
+    }
+    let _ = writeln!(out, ".PP\nWith APPLY_PATCH_EXIT_CODES=detailed:");
+    for spec in cli::DETAILED_EXIT_CODES {
+        let _ = writeln!(
+            out,
+            ".TP\n.B {}\n{} ({})",
+            spec.code,
+            roff_escape(spec.help),
+            roff_escape(spec.name)
+        );
+    }
+    out
+}
+
+fn md_cell(s: &str) -> String {
+    s.replace('|', "\\|")
+}
+
+pub(crate) fn render_markdown() -> String {
+    let mut out = String::new();
+    let _ = writeln!(out, "# `{NAME}` reference\n\n{DESCRIPTION}\n");
+    let _ = writeln!(
+        out,
+        "```text\n{NAME} [RUN FLAGS] [--] [PATCH]\n{NAME} <COMMAND> [ARGS]\n```\n\n{SEPARATOR_NOTE}"
+    );
+    for group in GROUPS {
+        let _ = writeln!(
+            out,
+            "\n## {}\n\n| Flag | Description |\n| --- | --- |",
+            group_title(group)
+        );
+        for spec in cli::FLAGS.iter().filter(|f| f.group == group) {
+            let _ = writeln!(
+                out,
+                "| `{}` | {} |",
+                md_cell(&cli::flag_usage(spec)),
+                md_cell(spec.help)
+            );
+        }
+    }
+    let _ = writeln!(
+        out,
+        "\n## Commands\n\n| Command | Description |\n| --- | --- |"
+    );
+    for cmd in cli::SUBCOMMANDS {
+        let _ = writeln!(out, "| `{}` | {} |", md_cell(cmd.usage), md_cell(cmd.help));
+    }
+    let _ = writeln!(
+        out,
+        "\n## Config keys\n\n| Key | Description |\n| --- | --- |"
+    );
+    for key in cli::CONFIG_KEYS {
+        let _ = writeln!(out, "| `{}` | {} |", md_cell(key.key), md_cell(key.help));
+    }
+    let _ = writeln!(
+        out,
+        "\n## Environment\n\n| Variable | Description |\n| --- | --- |"
+    );
+    for (name, help) in cli::ENV_VARS {
+        let _ = writeln!(out, "| `{name}` | {} |", md_cell(help));
+    }
+    let _ = writeln!(out, "\n## Exit codes\n\n| Code | Meaning |\n| --- | --- |");
+    for (code, help) in cli::EXIT_CODES {
+        let _ = writeln!(out, "| `{code}` | {} |", md_cell(help));
+    }
+    let _ = writeln!(
+        out,
+        "\nWith `APPLY_PATCH_EXIT_CODES=detailed`:\n\n| Code | Name | Meaning |\n| --- | --- | --- |"
+    );
+    for spec in cli::DETAILED_EXIT_CODES {
+        let _ = writeln!(
+            out,
+            "| `{}` | `{}` | {} |",
+            spec.code,
+            spec.name,
+            md_cell(spec.help)
+        );
+    }
+    out
+}
+
+/// `apply_patch generate-docs <man|markdown> [--out DIR]`
+pub(crate) fn run_generate_docs(args: &[String]) -> i32 {
+    let mut format: Option<&str> = None;
+    let mut out_dir: Option<PathBuf> = None;
+    let mut i = 0;
+    while i < args.len() {
+        match args[i].as_str() {
+            "--out" => {
+                let Some(dir) = args.get(i + 1) else {
+                    diagnostics::error("--out requires a value.");
+                    return 2;
+                };
+                out_dir = Some(PathBuf::from(dir));
+                i += 2;
+            }
+            "man" | "markdown" if format.is_none() => {
+                format = Some(args[i].as_str());
+                i += 1;
+            }
+            other => {
+                diagnostics::error(format!("unexpected generate-docs argument: {other}"));
+                return 2;
+            }
+        }
+    }
+
+    let (contents, file_name) = match format {
+        Some("man") => (render_man(), format!("{NAME}.1")),
+        Some(_) => (render_markdown(), format!("{NAME}.md")),
+        None => {
+            diagnostics::error("generate-docs requires a format: man or markdown.");
+            return 2;
+        }
+    };
+
+    let Some(dir) = out_dir else {
+        print!("{contents}");
+        return 0;
+    };
+    let path = dir.join(file_name);
+    if let Err(err) = std::fs::create_dir_all(&dir).and_then(|()| std::fs::write(&path, contents)) {
+        diagnostics::error(format!("failed to write {}: {err}", path.display()));
+        return 1;
+    }
+    println!("Wrote {}", path.display());
+    0
+}
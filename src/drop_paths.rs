@@ -0,0 +1,35 @@
+//! `drop_paths` in the config: globs for files agents may not touch, whose
+//! sections are quietly removed from a patch instead of refusing it.
+//!
+//! Dropping happens before policies and guards run, so they only see what
+//! will actually be applied. Typical entries are `CHANGELOG.md` or
+//! lockfiles that should only ever be regenerated by tooling.
+
+use crate::glob::glob_match;
+use crate::patch::Patch;
+use std::borrow::Cow;
+
+/// Removes every section whose path or move destination matches one of
+/// `globs`. Returns the remaining patch text and the dropped paths.
+pub(crate) fn strip<'a>(patch_text: &'a str, globs: &[String]) -> (Cow<'a, str>, Vec<String>) {
+    if globs.is_empty() {
+        return (Cow::Borrowed(patch_text), Vec::new());
+    }
+    let Some(mut patch) = Patch::parse(patch_text) else {
+        return (Cow::Borrowed(patch_text), Vec::new());
+    };
+    let mut dropped: Vec<String> = Vec::new();
+    patch.sections.retain(|section| {
+        let matched = std::iter::once(section.path.as_str())
+            .chain(section.move_to())
+            .any(|path| globs.iter().any(|glob| glob_match(glob, path)));
+        if matched {
+            dropped.push(section.path.clone());
+        }
+        !matched
+    });
+    if dropped.is_empty() {
+        return (Cow::Borrowed(patch_text), dropped);
+    }
+    (Cow::Owned(patch.render()), dropped)
+}
@@ -0,0 +1,263 @@
+//! `*** Edit Line N of PATH: s/old/new/` sections: replace text inside one
+//! line without reproducing the line, for small fixes where retyping the
+//! whole line (and its context) is what goes wrong.
+//!
+//! The header is the whole section. `old` must occur exactly once in line
+//! `N` (1-based) of the current file and is replaced literally, not as a
+//! regular expression; any punctuation character can stand in for `/`, and
+//! `\` escapes it inside `old` and `new`. A line that doesn't hold `old`
+//! fails the patch and the error quotes what the line says now.
+//!
+//! The edits of one file are expanded into a single `*** Update File:`
+//! section over the file's actual lines, so the applier, policies and
+//! guards see an ordinary modification. Each hunk carries enough leading
+//! context that it can only match where the edit is.
+
+use crate::archive::contained_name;
+use crate::patch::ADD_FILE_MARKER;
+use crate::patch::DELETE_FILE_MARKER;
+use crate::patch::MOVE_TO_MARKER;
+use crate::patch::UPDATE_FILE_MARKER;
+use std::borrow::Cow;
+
+pub(crate) const EDIT_LINE_MARKER: &str = "*** Edit Line ";
+
+/// Lines of context around each edit, as in a unified diff.
+const CONTEXT: usize = 3;
+
+struct Edit {
+    header: String,
+    line: usize,
+    old: String,
+    new: String,
+}
+
+/// Whether `patch_text` has any edit line sections.
+pub(crate) fn contains(patch_text: &str) -> bool {
+    patch_text
+        .lines()
+        .any(|line| line.trim_start().starts_with(EDIT_LINE_MARKER))
+}
+
+/// Splits `s/old/new/` (after the `s`) into `old` and `new`.
+fn substitution(expr: &str) -> Result<(String, String), String> {
+    let mut chars = expr.chars();
+    let delimiter = chars
+        .next()
+        .filter(|c| c.is_ascii_punctuation() && *c != '\\')
+        .ok_or("expected `s/old/new/` after the path")?;
+    let mut parts: Vec<String> = Vec::new();
+    let mut part = String::new();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next() {
+                Some(next) if next == delimiter || next == '\\' => part.push(next),
+                Some(next) => {
+                    part.push('\\');
+                    part.push(next);
+                }
+                None => part.push('\\'),
+            },
+            c if c == delimiter => parts.push(std::mem::take(&mut part)),
+            c => part.push(c),
+        }
+    }
+    parts.push(part);
+    match <[String; 3]>::try_from(parts) {
+        Ok([old, new, rest]) if rest.is_empty() => {
+            if old.is_empty() {
+                return Err("the text to replace is empty".to_string());
+            }
+            Ok((old, new))
+        }
+        _ => Err(format!(
+            "expected `s{delimiter}old{delimiter}new{delimiter}` after the path (escape a literal `{delimiter}` as `\\{delimiter}`)"
+        )),
+    }
+}
+
+/// Parses the part of a header after the marker: `N of PATH: s/old/new/`.
+fn parse(rest: &str) -> Result<(String, usize, String, String), String> {
+    let (number, tail) = rest
+        .split_once(" of ")
+        .ok_or("expected `*** Edit Line N of PATH: s/old/new/`")?;
+    let line: usize = number
+        .trim()
+        .parse()
+        .ok()
+        .filter(|n| *n > 0)
+        .ok_or_else(|| {
+            format!(
+                "`{}` is not a line number (lines count from 1)",
+                number.trim()
+            )
+        })?;
+    let (path, expr) = tail
+        .split_once(": s")
+        .ok_or("expected `: s/old/new/` after the path")?;
+    let path = contained_name(path.trim())
+        .ok_or("the path must be relative and stay in the working directory")?;
+    let (old, new) = substitution(expr.trim_end())?;
+    Ok((path, line, old, new))
+}
+
+/// The update hunks for `edits`, validated against `contents`.
+fn hunks(path: &str, edits: &mut [Edit], contents: &str) -> Result<Vec<String>, String> {
+    let lines: Vec<&str> = contents.lines().collect();
+    edits.sort_by_key(|edit| edit.line);
+    let mut changed: Vec<(usize, String)> = Vec::new();
+    for edit in edits.iter() {
+        let fail = |msg: String| format!("Invalid Edit Line section `{}`: {msg}", edit.header);
+        if changed.last().is_some_and(|(i, _)| *i + 1 == edit.line) {
+            return Err(fail(format!(
+                "line {} of {path} already has an Edit Line section; combine them",
+                edit.line
+            )));
+        }
+        let Some(current) = lines.get(edit.line - 1) else {
+            return Err(fail(format!("{path} has only {} line(s)", lines.len())));
+        };
+        match current.matches(edit.old.as_str()).count() {
+            1 => changed.push((edit.line - 1, current.replacen(&edit.old, &edit.new, 1))),
+            0 => {
+                return Err(fail(format!(
+                    "`{}` is not on line {} of {path}, which reads `{current}`",
+                    edit.old, edit.line
+                )));
+            }
+            n => {
+                return Err(fail(format!(
+                    "`{}` occurs {n} times on line {} of {path}, which reads `{current}`; include more of the line",
+                    edit.old, edit.line
+                )));
+            }
+        }
+    }
+
+    let mut groups: Vec<(usize, usize)> = Vec::new();
+    for (i, _) in &changed {
+        let start = i.saturating_sub(CONTEXT);
+        let end = (i + 1 + CONTEXT).min(lines.len());
+        match groups.last_mut() {
+            Some((_, group_end)) if start <= *group_end => *group_end = end,
+            _ => groups.push((start, end)),
+        }
+    }
+    let mut out: Vec<String> = Vec::new();
+    let mut cursor = 0;
+    for (start, end) in groups {
+        // The applier takes the first match after the previous hunk; add
+        // leading context until that is this one.
+        let mut start = start.max(cursor);
+        while start > cursor {
+            let block = &lines[start..end];
+            let first =
+                (cursor..=lines.len() - block.len()).find(|&i| lines[i..i + block.len()] == *block);
+            if first == Some(start) {
+                break;
+            }
+            start -= 1;
+        }
+        out.push("@@".to_string());
+        for (i, line) in lines.iter().enumerate().take(end).skip(start) {
+            match changed.iter().find(|(at, _)| *at == i) {
+                Some((_, new)) => {
+                    out.push(format!("-{line}"));
+                    out.push(format!("+{new}"));
+                }
+                None => out.push(format!(" {line}")),
+            }
+        }
+        cursor = end;
+    }
+    Ok(out)
+}
+
+/// Rewrites every edit line section in `patch_text` as an update of the
+/// file's current contents, one section per file where its first edit
+/// was. Patches without edit line sections are returned unchanged.
+pub(crate) fn expand(patch_text: &str) -> Result<Cow<'_, str>, String> {
+    if !contains(patch_text) {
+        return Ok(Cow::Borrowed(patch_text));
+    }
+    let mut files: Vec<(String, Vec<Edit>)> = Vec::new();
+    let mut others: Vec<String> = Vec::new();
+    for line in patch_text.lines() {
+        let line = line.trim();
+        if let Some(rest) = line.strip_prefix(EDIT_LINE_MARKER) {
+            let (path, number, old, new) =
+                parse(rest).map_err(|msg| format!("Invalid Edit Line section `{line}`: {msg}"))?;
+            let edit = Edit {
+                header: line.to_string(),
+                line: number,
+                old,
+                new,
+            };
+            match files.iter_mut().find(|(p, _)| *p == path) {
+                Some((_, edits)) => edits.push(edit),
+                None => files.push((path, vec![edit])),
+            }
+            continue;
+        }
+        for marker in [
+            ADD_FILE_MARKER,
+            UPDATE_FILE_MARKER,
+            DELETE_FILE_MARKER,
+            MOVE_TO_MARKER,
+        ] {
+            if let Some(path) = line.strip_prefix(marker) {
+                others.extend(contained_name(path.trim()));
+            }
+        }
+    }
+
+    let mut sections: Vec<(String, Vec<String>)> = Vec::new();
+    for (path, edits) in &mut files {
+        if others.contains(path) {
+            return Err(format!(
+                "Invalid Edit Line section `{}`: the patch has another section for {path}; put the change in that section instead",
+                edits[0].header
+            ));
+        }
+        let contents = std::fs::read(&*path)
+            .map_err(|_| {
+                format!(
+                    "Invalid Edit Line section `{}`: no such file",
+                    edits[0].header
+                )
+            })
+            .and_then(|bytes| {
+                String::from_utf8(bytes).map_err(|_| {
+                    format!(
+                        "Invalid Edit Line section `{}`: {path} is not UTF-8 text",
+                        edits[0].header
+                    )
+                })
+            })?;
+        let body = hunks(path, edits, &contents)?;
+        sections.push((path.clone(), body));
+    }
+
+    let mut out = String::new();
+    for line in patch_text.lines() {
+        if !line.trim_start().starts_with(EDIT_LINE_MARKER) {
+            out.push_str(line);
+            out.push('\n');
+            continue;
+        }
+        let (path, _, _, _) = parse(line.trim().strip_prefix(EDIT_LINE_MARKER).unwrap_or(""))?;
+        let Some(index) = sections.iter().position(|(p, _)| *p == path) else {
+            // Already written with the file's first edit.
+            continue;
+        };
+        let (path, body) = sections.remove(index);
+        out.push_str(UPDATE_FILE_MARKER);
+        out.push_str(&path);
+        out.push('\n');
+        for line in body {
+            out.push_str(&line);
+            out.push('\n');
+        }
+    }
+    Ok(Cow::Owned(out))
+}
@@ -0,0 +1,104 @@
+//! Editor guard (`editor_guard` in the config): raises the mode when a
+//! patch targets a file that looks open in an editor, judged by lock/swap
+//! files next to it such as `.name.swp` (Vim) or `.#name` (Emacs), so an
+//! agent doesn't clobber a human's unsaved edits.
+
+use crate::Mode;
+use crate::policy::severity;
+use serde::Deserialize;
+use serde::Serialize;
+use std::path::Path;
+
+/// Placeholder in `patterns` replaced by the target's file name.
+const NAME_PLACEHOLDER: &str = "{name}";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct EditorGuardConfig {
+    /// Mode to enforce when an artifact is found; the guard is off when unset.
+    #[serde(default)]
+    pub(crate) action: Option<Mode>,
+    /// Sibling file names that mark a target as open, with `{name}` standing
+    /// for the target's file name.
+    #[serde(default = "default_patterns")]
+    pub(crate) patterns: Vec<String>,
+}
+
+fn default_patterns() -> Vec<String> {
+    [".{name}.swp", ".{name}.swo", ".#{name}", "{name}~"]
+        .into_iter()
+        .map(str::to_string)
+        .collect()
+}
+
+impl Default for EditorGuardConfig {
+    fn default() -> Self {
+        Self {
+            action: None,
+            patterns: default_patterns(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct EditorArtifact {
+    pub(crate) target: String,
+    pub(crate) artifact: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct EditorGuard {
+    pub(crate) artifacts: Vec<EditorArtifact>,
+    pub(crate) to: Mode,
+}
+
+impl EditorGuard {
+    pub(crate) fn describe(&self) -> Vec<String> {
+        self.artifacts
+            .iter()
+            .map(|a| {
+                format!(
+                    "Editor guard ({} mode): {} suggests {} has unsaved changes in an editor.",
+                    self.to.as_str(),
+                    a.artifact,
+                    a.target
+                )
+            })
+            .collect()
+    }
+}
+
+/// Looks for editor artifacts next to `files`. Returns `None` when the guard
+/// is off or nothing was found; otherwise `to` is the stricter of `mode` and
+/// the configured action.
+pub(crate) fn guard(cfg: &EditorGuardConfig, files: &[String], mode: Mode) -> Option<EditorGuard> {
+    let action = cfg.action?;
+    let artifacts: Vec<EditorArtifact> = files
+        .iter()
+        .flat_map(|target| find_artifacts(&cfg.patterns, target))
+        .collect();
+    if artifacts.is_empty() {
+        return None;
+    }
+    let to = if severity(action) > severity(mode) {
+        action
+    } else {
+        mode
+    };
+    Some(EditorGuard { artifacts, to })
+}
+
+fn find_artifacts(patterns: &[String], target: &str) -> Vec<EditorArtifact> {
+    let path = Path::new(target);
+    let Some(name) = path.file_name().map(|n| n.to_string_lossy()) else {
+        return Vec::new();
+    };
+    patterns
+        .iter()
+        .map(|pattern| path.with_file_name(pattern.replace(NAME_PLACEHOLDER, &name)))
+        .filter(|candidate| candidate.symlink_metadata().is_ok())
+        .map(|candidate| EditorArtifact {
+            target: target.to_string(),
+            artifact: candidate.display().to_string(),
+        })
+        .collect()
+}
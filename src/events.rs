@@ -0,0 +1,91 @@
+//! `--event-fd N`: lifecycle events for supervisors.
+//!
+//! With `--event-fd N` (or `$APPLY_PATCH_EVENT_FD`), each patch produces
+//! JSON lines on file descriptor N: `started`, `policy-decision` once modes
+//! and guards have settled, `file-applied` for every file written, and
+//! `finished` with the decision and exit code. Stdout and stderr stay
+//! exactly as they are, so the text an agent reads is unaffected.
+
+use crate::Decision;
+use crate::Mode;
+use crate::jsonl;
+use crate::policy::ShadowDecision;
+use crate::reason::RefusalReason;
+use serde::Serialize;
+use std::fs::File;
+use std::io::Write;
+
+pub(crate) const EVENT_FD_ENV: &str = "APPLY_PATCH_EVENT_FD";
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "event", rename_all = "kebab-case")]
+pub(crate) enum Event<'a> {
+    Started {
+        pid: u32,
+        cwd: Option<String>,
+        files: &'a [String],
+    },
+    PolicyDecision {
+        /// The configured mode, before policies and guards.
+        configured: Mode,
+        mode: Mode,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        policy: Option<usize>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        reason: Option<&'a RefusalReason>,
+        notices: &'a [String],
+        /// What shadow rules would have enforced, when that differs.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        shadow: Option<&'a ShadowDecision>,
+    },
+    FileApplied {
+        path: &'a str,
+        status: char,
+    },
+    Finished {
+        /// Absent when the run stopped before a decision, e.g. on a
+        /// malformed patch.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        decision: Option<Decision>,
+        exit_code: i32,
+    },
+}
+
+#[derive(Serialize)]
+struct Record<'a> {
+    timestamp: u64,
+    #[serde(flatten)]
+    event: &'a Event<'a>,
+}
+
+#[derive(Debug)]
+pub(crate) struct EventStream {
+    file: File,
+}
+
+impl EventStream {
+    /// Opens file descriptor `fd` (as given on the command line) for events.
+    pub(crate) fn open(fd: &str) -> Result<Self, String> {
+        let Ok(fd) = fd.parse::<u32>() else {
+            return Err(format!("invalid event file descriptor: {fd}"));
+        };
+        std::fs::OpenOptions::new()
+            .append(true)
+            .open(format!("/dev/fd/{fd}"))
+            .map(|file| Self { file })
+            .map_err(|err| format!("cannot write events to file descriptor {fd}: {err}"))
+    }
+
+    /// Writes one event line. Write errors are ignored: telemetry never
+    /// changes the outcome of a run.
+    pub(crate) fn emit(&self, event: &Event<'_>) {
+        let record = Record {
+            timestamp: jsonl::timestamp(),
+            event,
+        };
+        if let Ok(mut line) = serde_json::to_vec(&record) {
+            line.push(b'\n');
+            let _ = (&self.file).write_all(&line);
+        }
+    }
+}
@@ -0,0 +1,133 @@
+//! Exit codes.
+//!
+//! By default the historical codes are kept: refusals exit 0 and every
+//! failure exits 1. With `APPLY_PATCH_EXIT_CODES=detailed` each outcome gets
+//! its own code from `cli::DETAILED_EXIT_CODES`, so harnesses can tell a
+//! refusal from an apply, or a stale patch from a malformed one, without
+//! parsing output.
+
+use crate::cli;
+use serde::Serialize;
+
+pub(crate) const EXIT_CODES_ENV: &str = "APPLY_PATCH_EXIT_CODES";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Exit {
+    Applied,
+    AppliedWithWarning,
+    Partial,
+    RefusedByMode,
+    /// Refused by a policy rule, escalation or guard.
+    RefusedByPolicy,
+    ParseError,
+    /// The files on disk don't match what the patch expects.
+    ContextMismatch,
+    Failed,
+    ConfigError,
+}
+
+impl Exit {
+    fn name(self) -> &'static str {
+        match self {
+            Exit::Applied => "applied",
+            Exit::AppliedWithWarning => "applied_with_warning",
+            Exit::Partial => "partial",
+            Exit::RefusedByMode => "refused_by_mode",
+            Exit::RefusedByPolicy => "refused_by_policy",
+            Exit::ParseError => "parse_error",
+            Exit::ContextMismatch => "context_mismatch",
+            Exit::Failed => "failed",
+            Exit::ConfigError => "config_error",
+        }
+    }
+
+    fn default_code(self) -> i32 {
+        match self {
+            Exit::Applied
+            | Exit::AppliedWithWarning
+            | Exit::RefusedByMode
+            | Exit::RefusedByPolicy => 0,
+            Exit::ParseError | Exit::ContextMismatch | Exit::Failed | Exit::ConfigError => 1,
+            Exit::Partial => 3,
+        }
+    }
+
+    pub(crate) fn code(self) -> i32 {
+        if !detailed() {
+            return self.default_code();
+        }
+        cli::DETAILED_EXIT_CODES
+            .iter()
+            .find(|spec| spec.name == self.name())
+            .map_or(1, |spec| spec.code)
+    }
+}
+
+fn detailed() -> bool {
+    std::env::var(EXIT_CODES_ENV).is_ok_and(|v| v == "detailed") && !crate::hermetic::enabled()
+}
+
+#[derive(Serialize)]
+struct Explanation {
+    scheme: &'static str,
+    codes: Vec<ExplainedCode>,
+}
+
+#[derive(Serialize)]
+struct ExplainedCode {
+    code: i32,
+    name: &'static str,
+    description: &'static str,
+}
+
+/// `--explain-exit-codes`: the codes in effect for this environment, as
+/// JSON.
+pub(crate) fn explain() -> String {
+    let detailed = detailed();
+    let codes = cli::DETAILED_EXIT_CODES
+        .iter()
+        .map(|spec| ExplainedCode {
+            code: if detailed {
+                spec.code
+            } else {
+                default_code_for(spec.name, spec.code)
+            },
+            name: spec.name,
+            description: spec.help,
+        })
+        .collect();
+    let explanation = Explanation {
+        scheme: if detailed { "detailed" } else { "default" },
+        codes,
+    };
+    serde_json::to_string_pretty(&explanation).unwrap_or_default()
+}
+
+const ALL: [Exit; 9] = [
+    Exit::Applied,
+    Exit::AppliedWithWarning,
+    Exit::Partial,
+    Exit::RefusedByMode,
+    Exit::RefusedByPolicy,
+    Exit::ParseError,
+    Exit::ContextMismatch,
+    Exit::Failed,
+    Exit::ConfigError,
+];
+
+/// Default-scheme code for a detailed entry; usage errors are 2 in both.
+fn default_code_for(name: &str, detailed_code: i32) -> i32 {
+    ALL.iter()
+        .find(|exit| exit.name() == name)
+        .map_or(detailed_code, |exit| exit.default_code())
+}
+
+/// The outcome behind a detailed exit code, e.g. from a child run with
+/// `APPLY_PATCH_EXIT_CODES=detailed`. `None` for usage errors and codes
+/// outside the scheme.
+pub(crate) fn from_detailed(code: i32) -> Option<Exit> {
+    let spec = cli::DETAILED_EXIT_CODES
+        .iter()
+        .find(|spec| spec.code == code)?;
+    ALL.into_iter().find(|exit| exit.name() == spec.name)
+}
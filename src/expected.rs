@@ -0,0 +1,95 @@
+//! `--expected-files`: the paths a patch may touch this turn.
+//!
+//! An orchestrator that told the model which files to edit passes them as
+//! `--expected-files a.rs,b.rs`, or as `@FILE` naming a manifest (a JSON
+//! array of paths, or one path per line with `#` comments). A patch that
+//! touches any other path, move destinations included, is refused, and the
+//! notice lists the difference: `+` for paths touched but not expected, `-`
+//! for expected paths the patch leaves alone. Paths are compared after
+//! dropping `./` and doubled separators.
+
+use crate::Mode;
+use serde::Serialize;
+use std::path::Component;
+use std::path::Path;
+
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct ExpectedFilesGuard {
+    /// Touched but not expected.
+    pub(crate) unexpected: Vec<String>,
+    /// Expected but not touched.
+    pub(crate) untouched: Vec<String>,
+    #[serde(skip)]
+    pub(crate) to: Mode,
+}
+
+impl ExpectedFilesGuard {
+    pub(crate) fn describe(&self) -> Vec<String> {
+        let mut lines = vec![format!(
+            "Expected files (refuse mode): the patch touches {} file(s) not in --expected-files (+ touched, not expected; - expected, not touched):",
+            self.unexpected.len()
+        )];
+        lines.extend(self.unexpected.iter().map(|path| format!("  + {path}")));
+        lines.extend(self.untouched.iter().map(|path| format!("  - {path}")));
+        lines
+    }
+}
+
+fn normalize(path: &str) -> String {
+    Path::new(path.trim())
+        .components()
+        .filter(|c| !matches!(c, Component::CurDir))
+        .map(|c| c.as_os_str().to_string_lossy().into_owned())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// The paths named by the `--expected-files` value.
+pub(crate) fn parse(value: &str) -> Result<Vec<String>, String> {
+    let paths: Vec<String> = match value.strip_prefix('@') {
+        Some(file) => {
+            let text = std::fs::read_to_string(file)
+                .map_err(|err| format!("failed to read --expected-files manifest {file}: {err}"))?;
+            if text.trim_start().starts_with('[') {
+                serde_json::from_str(&text)
+                    .map_err(|err| format!("invalid --expected-files manifest {file}: {err}"))?
+            } else {
+                text.lines()
+                    .filter(|line| !line.trim().is_empty() && !line.trim().starts_with('#'))
+                    .map(str::to_string)
+                    .collect()
+            }
+        }
+        None => value
+            .split(',')
+            .filter(|path| !path.trim().is_empty())
+            .map(str::to_string)
+            .collect(),
+    };
+    Ok(paths.iter().map(|path| normalize(path)).collect())
+}
+
+/// Compares the patch's `files` with `expected`. Returns `None` when every
+/// touched path is expected.
+pub(crate) fn guard(expected: &[String], files: &[String]) -> Option<ExpectedFilesGuard> {
+    let touched: Vec<String> = files.iter().map(|file| normalize(file)).collect();
+    let unexpected: Vec<String> = files
+        .iter()
+        .zip(&touched)
+        .filter(|(_, normalized)| !expected.contains(normalized))
+        .map(|(file, _)| file.clone())
+        .collect();
+    if unexpected.is_empty() {
+        return None;
+    }
+    let untouched = expected
+        .iter()
+        .filter(|path| !touched.contains(path))
+        .cloned()
+        .collect();
+    Some(ExpectedFilesGuard {
+        unexpected,
+        untouched,
+        to: Mode::Refuse,
+    })
+}
@@ -0,0 +1,120 @@
+//! Failure guard (`failure_guard` in the config): notices when the same
+//! patch keeps failing the same way.
+//!
+//! Consecutive failed applies with an identical error are counted in the
+//! state file. Once `threshold` is reached the usual error is followed by
+//! explicit recovery instructions, and with `cooldown_secs` every patch is
+//! refused for that long, so an agent stuck retrying a stale patch is pushed
+//! to re-read the files instead. A successful apply resets the count.
+//! Failed `--partial` runs are not counted.
+
+use crate::Decision;
+use crate::jsonl;
+use crate::state;
+use serde::Deserialize;
+use serde::Serialize;
+use std::path::Path;
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub(crate) struct FailureGuardConfig {
+    /// Identical failures in a row before the guidance escalates; off when
+    /// unset.
+    #[serde(default)]
+    pub(crate) threshold: Option<u32>,
+    /// Refuse every patch for this many seconds once `threshold` is hit.
+    #[serde(default)]
+    pub(crate) cooldown_secs: Option<u64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct FailureStreak {
+    /// First line of the error, e.g. `Failed to find expected lines in a.rs:`.
+    error: String,
+    count: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    cooldown_until: Option<u64>,
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct Cooldown {
+    pub(crate) remaining_secs: u64,
+    failures: u32,
+}
+
+impl Cooldown {
+    pub(crate) fn describe(&self) -> String {
+        format!(
+            "Failure guard (refuse mode): the last {} patches failed with the same error; patches are refused for another {}s. Re-read the affected files before trying again.",
+            self.failures, self.remaining_secs
+        )
+    }
+}
+
+/// The active cooldown, if the guard is on and one hasn't expired yet.
+pub(crate) fn cooldown(
+    cfg: &FailureGuardConfig,
+    state_dir: Option<&Path>,
+    tenant: Option<&str>,
+) -> Option<Cooldown> {
+    cfg.threshold?;
+    let streak = state::load(&state::path(state_dir, tenant)?).failures?;
+    let until = streak.cooldown_until?;
+    let now = jsonl::timestamp();
+    (until > now).then_some(Cooldown {
+        remaining_secs: until - now,
+        failures: streak.count,
+    })
+}
+
+/// Updates the streak after an apply: `error` is the first line of the
+/// failure for `Decision::Failed`. Prints the recovery guidance once the
+/// threshold is reached.
+pub(crate) fn record(
+    cfg: &FailureGuardConfig,
+    state_dir: Option<&Path>,
+    tenant: Option<&str>,
+    decision: Decision,
+    error: Option<&str>,
+) {
+    let Some(threshold) = cfg.threshold else {
+        return;
+    };
+    let Some(path) = state::path(state_dir, tenant) else {
+        return;
+    };
+    let mut state = state::load(&path);
+    match (decision, error) {
+        (Decision::Applied, _) => {
+            if state.failures.take().is_none() {
+                return;
+            }
+        }
+        (Decision::Failed, Some(error)) => {
+            let count = match &state.failures {
+                Some(streak) if streak.error == error => streak.count + 1,
+                _ => 1,
+            };
+            let mut cooldown_until = None;
+            if count >= threshold {
+                print_guidance(count, error, cfg.cooldown_secs);
+                cooldown_until = cfg.cooldown_secs.map(|secs| jsonl::timestamp() + secs);
+            }
+            state.failures = Some(FailureStreak {
+                error: error.to_string(),
+                count,
+                cooldown_until,
+            });
+        }
+        _ => return,
+    }
+    state::save(&path, &state);
+}
+
+fn print_guidance(count: u32, error: &str, cooldown_secs: Option<u64>) {
+    eprintln!(
+        "\nThis patch has now failed {count} times in a row with the same error:\n  {error}\nThe file has most likely changed since it was last read, so retrying the same patch will keep failing. Re-read the affected files and rebuild the patch from their current contents."
+    );
+    if let Some(secs) = cooldown_secs {
+        eprintln!("apply_patch will refuse further patches for the next {secs}s.");
+    }
+}
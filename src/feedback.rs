@@ -0,0 +1,69 @@
+//! Out-of-band feedback channel for agent harnesses.
+//!
+//! When `$APPLY_PATCH_FEEDBACK_FILE` is set, every banner is also
+//! appended to that file as a JSON line together with the decision that
+//! produced it, so orchestrators see the guidance even when tool stdout is
+//! truncated before it reaches them. Under a tenant the tenant name is
+//! spliced into the file name, as for the audit log.
+
+use crate::Decision;
+use crate::Mode;
+use crate::diagnostics;
+use crate::jsonl;
+use crate::reason::RefusalReason;
+use serde::Serialize;
+use std::path::PathBuf;
+
+const FEEDBACK_FILE_ENV: &str = "APPLY_PATCH_FEEDBACK_FILE";
+
+#[derive(Debug, Serialize)]
+struct FeedbackRecord<'a> {
+    timestamp: u64,
+    mode: Mode,
+    decision: Decision,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reason: Option<&'a RefusalReason>,
+    cwd: Option<String>,
+    message: &'a str,
+}
+
+/// The feedback file for `tenant`, if one is configured.
+pub(crate) fn feedback_path(tenant: Option<&str>) -> Option<PathBuf> {
+    let path = std::env::var_os(FEEDBACK_FILE_ENV)
+        .filter(|v| !v.is_empty() && !crate::hermetic::enabled())
+        .map(PathBuf::from)?;
+    Some(match tenant {
+        Some(name) => crate::tenant::scoped_path(&path, name),
+        None => path,
+    })
+}
+
+/// Appends the banner and decision to the feedback file, if one is configured.
+/// Failures are reported on stderr but never change the outcome of the run.
+pub(crate) fn record(
+    mode: Mode,
+    decision: Decision,
+    reason: Option<&RefusalReason>,
+    message: &str,
+    tenant: Option<&str>,
+) {
+    let Some(path) = feedback_path(tenant) else {
+        return;
+    };
+    let record = FeedbackRecord {
+        timestamp: jsonl::timestamp(),
+        mode,
+        decision,
+        reason,
+        cwd: std::env::current_dir()
+            .ok()
+            .map(|p| p.display().to_string()),
+        message,
+    };
+    if let Err(err) = jsonl::append(&path, &record) {
+        diagnostics::warning(format!(
+            "failed to write feedback file {}: {err}",
+            path.display()
+        ));
+    }
+}
@@ -0,0 +1,185 @@
+//! C ABI for runtimes that embed the applier instead of running
+//! `apply_patch` (declared in `include/apply_patch.h`).
+//!
+//! `apply_patch_run` does what `apply_patch` does with the patch on stdin
+//! and answers with the JSON `serve-http` gives for `/apply`: the exit code
+//! and what the CLI would have printed. The output is captured the way
+//! `serve-http` captures it, by pointing descriptors 1 and 2 at pipes, so
+//! runs take turns and whatever other threads print meanwhile ends up in
+//! the answer. Capturing needs Unix; elsewhere every run fails with the
+//! reason in `stderr`.
+
+use crate::capture::capture;
+use crate::diagnostics;
+use crate::exit;
+use crate::serve::in_dir;
+use serde::Deserialize;
+use serde::Serialize;
+use std::ffi::CStr;
+use std::ffi::CString;
+use std::ffi::c_char;
+use std::ffi::c_int;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::sync::PoisonError;
+
+/// Runs share the process's working directory, stdout and stderr.
+static RUNS: Mutex<()> = Mutex::new(());
+
+/// The `options` JSON object; every field may be left out.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct Options {
+    /// Directory to apply in, relative to the process's working directory.
+    cwd: Option<PathBuf>,
+    /// Config file, instead of `$APPLY_PATCH_CONFIG` or the default path.
+    config: Option<PathBuf>,
+    /// Instead of `$APPLY_PATCH_TENANT`.
+    tenant: Option<String>,
+    /// As `--dry-run`.
+    dry_run: bool,
+}
+
+#[derive(Serialize)]
+struct Answer {
+    exit_code: i32,
+    stdout: String,
+    stderr: String,
+}
+
+impl Answer {
+    /// A run that stopped before the applier started.
+    fn usage(message: &str) -> Self {
+        Self {
+            exit_code: 2,
+            stdout: String::new(),
+            stderr: format!("Error: {message}\n"),
+        }
+    }
+}
+
+/// Applies `patch` as `apply_patch` would and returns its exit code. When
+/// `out_json` isn't null it receives `{"exit_code": N, "stdout": "...",
+/// "stderr": "..."}`, which the caller releases with [`apply_patch_free`].
+///
+/// # Safety
+///
+/// `patch` must be a NUL-terminated string and `options` either null or a
+/// NUL-terminated JSON object. `out_json` must be null or valid for a
+/// pointer write.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn apply_patch_run(
+    patch: *const c_char,
+    options: *const c_char,
+    out_json: *mut *mut c_char,
+) -> c_int {
+    // SAFETY: the caller passes NUL-terminated strings or null.
+    let answer = run(unsafe { text(patch) }, unsafe { text(options) });
+    if !out_json.is_null() {
+        // JSON escapes NUL, so the answer never contains one.
+        let json = serde_json::to_string(&answer)
+            .ok()
+            .and_then(|json| CString::new(json).ok())
+            .unwrap_or_default();
+        // SAFETY: the caller passes a pointer valid for writes.
+        unsafe { *out_json = json.into_raw() };
+    }
+    answer.exit_code
+}
+
+/// Releases an answer from [`apply_patch_run`]. Null is ignored.
+///
+/// # Safety
+///
+/// `json` must be null or a pointer `apply_patch_run` wrote, not yet
+/// released.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn apply_patch_free(json: *mut c_char) {
+    if !json.is_null() {
+        // SAFETY: it came from `CString::into_raw` in `apply_patch_run`.
+        drop(unsafe { CString::from_raw(json) });
+    }
+}
+
+/// The string at `ptr`: `None` for null, `Err` for invalid UTF-8.
+///
+/// # Safety
+///
+/// `ptr` must be null or a NUL-terminated string that outlives the result.
+unsafe fn text<'a>(ptr: *const c_char) -> Option<Result<&'a str, std::str::Utf8Error>> {
+    if ptr.is_null() {
+        return None;
+    }
+    // SAFETY: the caller passes a NUL-terminated string.
+    Some(unsafe { CStr::from_ptr(ptr) }.to_str())
+}
+
+fn run(
+    patch: Option<Result<&str, std::str::Utf8Error>>,
+    options: Option<Result<&str, std::str::Utf8Error>>,
+) -> Answer {
+    let patch = match patch {
+        Some(Ok(patch)) => patch,
+        Some(Err(_)) => return Answer::usage("apply_patch requires a UTF-8 PATCH argument."),
+        None => return Answer::usage("the patch is null."),
+    };
+    let options: Options = match options {
+        None => Options::default(),
+        Some(Ok(json)) => match serde_json::from_str(json) {
+            Ok(options) => options,
+            Err(err) => return Answer::usage(&format!("invalid options: {err}")),
+        },
+        Some(Err(_)) => return Answer::usage("invalid options: not UTF-8"),
+    };
+    let _turn = RUNS.lock().unwrap_or_else(PoisonError::into_inner);
+    let home = match std::env::current_dir() {
+        Ok(home) => home,
+        Err(err) => {
+            return Answer::usage(&format!("cannot resolve the working directory: {err}"));
+        }
+    };
+    let Options {
+        cwd,
+        config,
+        tenant,
+        dry_run,
+    } = options;
+    diagnostics::discard();
+    let captured = capture(|| {
+        in_dir(&home, cwd, || {
+            let mut run_options = crate::RunOptions {
+                dry_run,
+                ..Default::default()
+            };
+            run_options.tenant = match crate::tenant::resolve(tenant) {
+                Ok(tenant) => tenant,
+                Err(err) => {
+                    diagnostics::error(err);
+                    return 2;
+                }
+            };
+            let path = config.or_else(crate::config_path);
+            let cfg = match crate::config_at(path, &run_options) {
+                Ok(cfg) => cfg,
+                Err(err) => {
+                    diagnostics::error(err);
+                    return exit::Exit::ConfigError.code();
+                }
+            };
+            run_options.dry_run |= cfg.readonly;
+            crate::process_patch(&cfg, patch, &run_options).map_or_else(|code| code, |o| o.code)
+        })
+    });
+    match captured {
+        Ok((exit_code, stdout, stderr)) => Answer {
+            exit_code,
+            stdout,
+            stderr,
+        },
+        Err(err) => Answer {
+            exit_code: exit::Exit::Failed.code(),
+            stdout: String::new(),
+            stderr: format!("Error: cannot capture the run's output: {err}\n"),
+        },
+    }
+}
@@ -0,0 +1,86 @@
+//! Where a patch landed: host, user, repository and branch.
+//!
+//! Banners can name them with `{hostname}`, `{user}`, `{repo}` (the
+//! checkout's top-level directory) and `{branch}`, which matters when one
+//! agent session spans several checkouts, and the audit log records them
+//! under `fingerprint`. What can't be determined (no git, outside a
+//! repository) is left out of the audit entry and renders as empty. The
+//! lookup is cached per working directory.
+
+use serde::Serialize;
+use std::path::PathBuf;
+use std::process::Command;
+use std::sync::Mutex;
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub(crate) struct Fingerprint {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) hostname: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) user: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) repo: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) branch: Option<String>,
+}
+
+static CACHE: Mutex<Option<(PathBuf, Fingerprint)>> = Mutex::new(None);
+
+fn output(program: &str, args: &[&str]) -> Option<String> {
+    let output = Command::new(program).args(args).output().ok()?;
+    let text = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    (output.status.success() && !text.is_empty()).then_some(text)
+}
+
+fn non_empty(value: String) -> Option<String> {
+    let value = value.trim().to_string();
+    (!value.is_empty()).then_some(value)
+}
+
+fn hostname() -> Option<String> {
+    ["/proc/sys/kernel/hostname", "/etc/hostname"]
+        .iter()
+        .find_map(|path| std::fs::read_to_string(path).ok().and_then(non_empty))
+        .or_else(|| std::env::var("HOSTNAME").ok().and_then(non_empty))
+        .or_else(|| output("hostname", &[]))
+}
+
+fn user() -> Option<String> {
+    ["USER", "LOGNAME", "USERNAME"]
+        .iter()
+        .find_map(|key| std::env::var(key).ok().and_then(non_empty))
+        .or_else(|| output("id", &["-un"]))
+}
+
+/// The branch checked out (born or not), or `detached at <commit>`.
+fn branch() -> Option<String> {
+    output("git", &["symbolic-ref", "--quiet", "--short", "HEAD"]).or_else(|| {
+        output("git", &["rev-parse", "--short", "HEAD"])
+            .map(|commit| format!("detached at {commit}"))
+    })
+}
+
+fn lookup() -> Fingerprint {
+    Fingerprint {
+        hostname: hostname(),
+        user: user(),
+        repo: output("git", &["rev-parse", "--show-toplevel"]),
+        branch: branch(),
+    }
+}
+
+/// The fingerprint of the current working directory.
+pub(crate) fn current() -> Fingerprint {
+    let cwd = std::env::current_dir().unwrap_or_default();
+    let Ok(mut cache) = CACHE.lock() else {
+        return lookup();
+    };
+    match &*cache {
+        Some((dir, fingerprint)) if *dir == cwd => fingerprint.clone(),
+        _ => {
+            let fingerprint = lookup();
+            *cache = Some((cwd, fingerprint.clone()));
+            fingerprint
+        }
+    }
+}
@@ -0,0 +1,107 @@
+//! Formatting written files (`format_on_apply` in the config).
+//!
+//! `format_on_apply` maps path globs to formatter commands, e.g.
+//! `{"*.rs": "rustfmt --edition 2024", "*.py": "black -q"}`. After a patch
+//! applies, each command runs once through `sh -c`, with the files it
+//! wrote that match the glob appended as arguments, so an agent's next
+//! commit doesn't carry formatting churn. Relative paths are passed as
+//! `./path`, so a file the patch named `-rf` or `--config=x` reaches the
+//! formatter as a file, not an option. A file matching several globs is
+//! run through each, in glob order. A formatter that fails is a warning;
+//! the patch stays applied either way.
+
+use crate::diagnostics;
+use crate::glob::glob_match;
+use crate::patch::Patch;
+use crate::patch::SectionKind;
+use crate::simulate::FileChange;
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::process::Command;
+use std::process::Stdio;
+
+/// Glob -> formatter command.
+pub(crate) type FormatOnApply = BTreeMap<String, String>;
+
+/// The files `patch_arg` added or updated that exist now.
+fn written_paths(patch_arg: &str) -> Vec<String> {
+    let Some(patch) = Patch::parse(patch_arg) else {
+        return Vec::new();
+    };
+    let mut paths: Vec<String> = Vec::new();
+    for section in &patch.sections {
+        if section.kind == SectionKind::Delete {
+            continue;
+        }
+        let path = section.move_to().unwrap_or(&section.path);
+        if Path::new(path).is_file() && !paths.iter().any(|p| p == path) {
+            paths.push(path.to_string());
+        }
+    }
+    paths
+}
+
+/// `path` as a formatter argument that can't be read as an option.
+fn as_operand(path: &str) -> std::path::PathBuf {
+    let path = Path::new(path);
+    if path.is_absolute() {
+        path.to_path_buf()
+    } else {
+        Path::new(".").join(path)
+    }
+}
+
+/// Runs the formatters on the files `patch_arg` wrote, then refreshes the
+/// contents recorded in `changes` so later steps see the formatted files.
+pub(crate) fn run(
+    formatters: &FormatOnApply,
+    patch_arg: &str,
+    changes: Option<&mut Vec<FileChange>>,
+) {
+    if formatters.is_empty() {
+        return;
+    }
+    let paths = written_paths(patch_arg);
+    for (pattern, command) in formatters {
+        let files: Vec<&String> = paths.iter().filter(|p| glob_match(pattern, p)).collect();
+        if files.is_empty() {
+            continue;
+        }
+        let listed = files
+            .iter()
+            .map(|f| f.as_str())
+            .collect::<Vec<_>>()
+            .join(", ");
+        let output = Command::new("sh")
+            .arg("-c")
+            .arg(format!("{command} \"$@\""))
+            .arg("sh")
+            .args(files.iter().map(|f| as_operand(f)))
+            .stdin(Stdio::null())
+            .output();
+        match output {
+            Ok(output) if output.status.success() => {
+                println!("Formatted {listed} with `{command}`.");
+            }
+            Ok(output) => {
+                let stderr = String::from_utf8_lossy(&output.stderr);
+                let detail = stderr.lines().find(|l| !l.trim().is_empty());
+                diagnostics::warning(format!(
+                    "format_on_apply `{command}` failed on {listed} ({}){}",
+                    output.status,
+                    detail
+                        .map(|d| format!(": {}", d.trim()))
+                        .unwrap_or_default()
+                ));
+            }
+            Err(err) => {
+                diagnostics::warning(format!("failed to run format_on_apply `{command}`: {err}"))
+            }
+        }
+    }
+    for change in changes.into_iter().flatten() {
+        if change.after.is_some() {
+            change.after = std::fs::read_to_string(&change.path).ok();
+        }
+    }
+}
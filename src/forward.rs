@@ -0,0 +1,140 @@
+//! `"mode": "forward"`: hand the patch to the sanctioned editing pathway
+//! instead of applying it.
+//!
+//! The patch is POSTed as JSON to `forward.url` (with `curl`), or piped to
+//! `forward.command` (through `sh -c`), and whatever the endpoint answers
+//! is relayed to the model, so the shell wrapper becomes a bridge to the
+//! native tool rather than a dead end. A JSON answer's `summary` (or
+//! `message`) string is printed; any other answer is printed as it is.
+//! Nothing is written locally either way.
+
+use crate::policy::PatchFacts;
+use serde::Deserialize;
+use serde::Serialize;
+use std::io::Write;
+use std::process::Command;
+use std::process::Stdio;
+
+/// How long `curl` may take to send the patch and read the answer.
+const TIMEOUT_SECS: &str = "60";
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub(crate) struct ForwardConfig {
+    /// Endpoint the patch is POSTed to.
+    #[serde(default)]
+    pub(crate) url: Option<String>,
+    /// Shell command the patch is piped to; used when `url` is unset.
+    #[serde(default)]
+    pub(crate) command: Option<String>,
+}
+
+impl ForwardConfig {
+    /// Where patches go, for messages and `show-config`.
+    pub(crate) fn target(&self) -> Option<&str> {
+        self.url.as_deref().or(self.command.as_deref())
+    }
+}
+
+/// The endpoint's answer, reduced to what the model should read.
+fn summary(answer: &str) -> String {
+    let text = answer.trim();
+    serde_json::from_str::<serde_json::Value>(text)
+        .ok()
+        .and_then(|value| {
+            ["summary", "message"]
+                .iter()
+                .find_map(|key| value.get(key)?.as_str().map(str::to_string))
+        })
+        .unwrap_or_else(|| text.to_string())
+}
+
+fn run(mut cmd: Command, input: &[u8], what: &str) -> Result<String, String> {
+    let mut child = cmd
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|err| format!("failed to run {what}: {err}"))?;
+    if let Some(mut stdin) = child.stdin.take() {
+        // A command that doesn't read its input still gets to answer.
+        let _ = stdin.write_all(input);
+    }
+    let output = child
+        .wait_with_output()
+        .map_err(|err| format!("failed to run {what}: {err}"))?;
+    let stdout = String::from_utf8_lossy(&output.stdout).to_string();
+    if output.status.success() {
+        return Ok(stdout);
+    }
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let detail = stderr
+        .lines()
+        .chain(stdout.lines())
+        .find(|line| !line.trim().is_empty())
+        .unwrap_or("no output")
+        .trim()
+        .to_string();
+    Err(match output.status.code() {
+        Some(code) => format!("{what} exited with status {code}: {detail}"),
+        None => format!("{what} was terminated by a signal: {detail}"),
+    })
+}
+
+fn post(url: &str, body: &[u8]) -> Result<String, String> {
+    let mut cmd = Command::new("curl");
+    cmd.args([
+        "-sS",
+        "--max-time",
+        TIMEOUT_SECS,
+        "-X",
+        "POST",
+        "-H",
+        "Content-Type: application/json",
+        "--data-binary",
+        "@-",
+        "-w",
+        "\n%{http_code}",
+        url,
+    ]);
+    let answer = run(cmd, body, "curl")?;
+    let (answer, status) = answer.rsplit_once('\n').unwrap_or(("", &answer));
+    match status.trim().parse::<u16>() {
+        Ok(status) if (200..300).contains(&status) => Ok(answer.to_string()),
+        Ok(status) => Err(format!("{url} answered HTTP {status}: {}", summary(answer))),
+        Err(_) => Err(format!("{url} sent no HTTP status")),
+    }
+}
+
+/// Sends `patch_arg` to the configured endpoint and returns the summary of
+/// its answer.
+pub(crate) fn send(
+    cfg: &ForwardConfig,
+    patch_arg: &str,
+    facts: &PatchFacts,
+) -> Result<String, String> {
+    let answer = match (&cfg.url, &cfg.command) {
+        (Some(url), _) => {
+            let body = serde_json::json!({
+                "patch": patch_arg,
+                "cwd": std::env::current_dir().ok().map(|dir| dir.display().to_string()),
+                "files": facts.files,
+                "agent": facts.agent,
+                "metadata": facts.metadata,
+            });
+            post(url, body.to_string().as_bytes())?
+        }
+        (None, Some(command)) => {
+            let mut cmd = Command::new("sh");
+            cmd.arg("-c")
+                .arg(command)
+                .env("APPLY_PATCH_FORWARD_FILES", facts.files.join("\n"));
+            run(cmd, patch_arg.as_bytes(), "forward.command")?
+        }
+        (None, None) => {
+            return Err(
+                "mode \"forward\" needs forward.url or forward.command in the config".to_string(),
+            );
+        }
+    };
+    Ok(summary(&answer))
+}
@@ -0,0 +1,285 @@
+//! Stale-read check: refuses to patch files that changed after the model
+//! read them, so a patch written from an old view of a file doesn't clobber
+//! a concurrent human edit.
+//!
+//! The read times come from `*** Read At: <time>` lines after a file
+//! section's header, or from a `--read-manifest` file mapping paths to
+//! times (a line in the patch wins). Times are Unix seconds (`1735689600`,
+//! optionally with a fraction) or RFC 3339 (`2025-01-01T00:00:00Z`). The
+//! lines are removed before anything else sees the patch. A target whose
+//! modification time is after its read time refuses the patch with a
+//! request to re-read it; files without a read time aren't checked.
+
+use crate::Mode;
+use crate::patch::ADD_FILE_MARKER;
+use crate::patch::DELETE_FILE_MARKER;
+use crate::patch::UPDATE_FILE_MARKER;
+use serde::Serialize;
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::time::UNIX_EPOCH;
+
+pub(crate) const READ_AT_MARKER: &str = "*** Read At: ";
+
+/// When a file was read, as given.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct ReadAt {
+    text: String,
+    nanos: i128,
+    /// Whether the time had a fractional part; whole seconds are compared
+    /// with modification times truncated to the second.
+    precise: bool,
+}
+
+impl ReadAt {
+    pub(crate) fn parse(text: &str) -> Option<Self> {
+        let text = text.trim();
+        let (nanos, precise) = match parse_unix(text) {
+            Some(parsed) => parsed,
+            None => parse_rfc3339(text)?,
+        };
+        Some(Self {
+            text: text.to_string(),
+            nanos,
+            precise,
+        })
+    }
+}
+
+/// Seconds since the epoch, optionally with a fraction.
+fn parse_unix(text: &str) -> Option<(i128, bool)> {
+    let (secs, frac) = match text.split_once('.') {
+        Some((secs, frac)) => (secs, Some(frac)),
+        None => (text, None),
+    };
+    if secs.is_empty() || !secs.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let nanos = secs.parse::<i128>().ok()? * 1_000_000_000;
+    match frac {
+        None => Some((nanos, false)),
+        Some(frac) => Some((nanos + fraction_nanos(frac)?, true)),
+    }
+}
+
+/// `.123` as nanoseconds.
+fn fraction_nanos(frac: &str) -> Option<i128> {
+    if frac.is_empty() || !frac.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let digits: String = frac.chars().chain(std::iter::repeat('0')).take(9).collect();
+    digits.parse().ok()
+}
+
+/// `YYYY-MM-DDTHH:MM:SS[.frac](Z|±HH:MM)`.
+fn parse_rfc3339(text: &str) -> Option<(i128, bool)> {
+    let b = text.as_bytes();
+    if b.len() < 20 || b[4] != b'-' || b[7] != b'-' || !matches!(b[10], b'T' | b't' | b' ') {
+        return None;
+    }
+    let num = |range: std::ops::Range<usize>| -> Option<i64> {
+        let part = text.get(range)?;
+        part.bytes()
+            .all(|b| b.is_ascii_digit())
+            .then(|| part.parse().ok())?
+    };
+    let (year, month, day) = (num(0..4)?, num(5..7)?, num(8..10)?);
+    if b[13] != b':' || b[16] != b':' {
+        return None;
+    }
+    let (hour, minute, second) = (num(11..13)?, num(14..16)?, num(17..19)?);
+    if !(1..=12).contains(&month)
+        || !(1..=31).contains(&day)
+        || hour > 23
+        || minute > 59
+        || second > 60
+    {
+        return None;
+    }
+    let mut rest = &text[19..];
+    let mut frac_nanos = 0;
+    let mut precise = false;
+    if let Some(after_dot) = rest.strip_prefix('.') {
+        let end = after_dot
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(after_dot.len());
+        frac_nanos = fraction_nanos(&after_dot[..end])?;
+        precise = true;
+        rest = &after_dot[end..];
+    }
+    let offset_secs = match rest {
+        "Z" | "z" => 0,
+        _ => {
+            let sign = match rest.as_bytes().first()? {
+                b'+' => 1,
+                b'-' => -1,
+                _ => return None,
+            };
+            let (hours, minutes) = rest[1..].split_once(':')?;
+            if hours.len() != 2 || minutes.len() != 2 {
+                return None;
+            }
+            sign * (hours.parse::<i64>().ok()? * 3600 + minutes.parse::<i64>().ok()? * 60)
+        }
+    };
+    let secs = days_from_civil(year, month, day) * 86_400 + hour * 3600 + minute * 60 + second
+        - offset_secs;
+    Some((i128::from(secs) * 1_000_000_000 + frac_nanos, precise))
+}
+
+/// Days since 1970-01-01 of a proleptic Gregorian date.
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year - era * 400;
+    let mp = (month + 9) % 12;
+    let doy = (153 * mp + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146_097 + doe - 719_468
+}
+
+/// `secs` since the epoch as `YYYY-MM-DDTHH:MM:SSZ`.
+fn format_rfc3339(secs: i64) -> String {
+    let (days, rem) = (secs.div_euclid(86_400), secs.rem_euclid(86_400));
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!(
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}Z",
+        rem / 3600,
+        rem % 3600 / 60,
+        rem % 60
+    )
+}
+
+fn normalize(path: &str) -> &str {
+    path.trim().trim_start_matches("./")
+}
+
+/// Removes every `*** Read At:` line from `patch_text`, returning the rest
+/// and the read time recorded for each section's path.
+pub(crate) fn strip(patch_text: &str) -> Result<(Cow<'_, str>, BTreeMap<String, ReadAt>), String> {
+    let mut reads = BTreeMap::new();
+    if !patch_text
+        .lines()
+        .any(|line| line.trim_start().starts_with(READ_AT_MARKER))
+    {
+        return Ok((Cow::Borrowed(patch_text), reads));
+    }
+    let mut out = String::new();
+    let mut section: Option<&str> = None;
+    for line in patch_text.lines() {
+        let trimmed = line.trim();
+        if let Some(path) = [ADD_FILE_MARKER, DELETE_FILE_MARKER, UPDATE_FILE_MARKER]
+            .iter()
+            .find_map(|marker| trimmed.strip_prefix(marker))
+        {
+            section = Some(normalize(path));
+        }
+        let Some(time) = trimmed.strip_prefix(READ_AT_MARKER) else {
+            out.push_str(line);
+            out.push('\n');
+            continue;
+        };
+        let Some(path) = section else {
+            return Err(format!(
+                "Invalid patch: `{}` must follow a file header.",
+                READ_AT_MARKER.trim_end()
+            ));
+        };
+        let Some(read_at) = ReadAt::parse(time) else {
+            return Err(format!(
+                "Invalid Read At time for {path}: {time} (use Unix seconds or RFC 3339, e.g. 2025-01-01T00:00:00Z)."
+            ));
+        };
+        reads.insert(path.to_string(), read_at);
+    }
+    Ok((Cow::Owned(out), reads))
+}
+
+/// Reads a `--read-manifest` file: a JSON object mapping paths to read
+/// times, as numbers or strings.
+pub(crate) fn load_manifest(path: &Path) -> Result<BTreeMap<String, ReadAt>, String> {
+    let invalid = |err: String| format!("invalid read manifest {}: {err}", path.display());
+    let text = std::fs::read_to_string(path).map_err(|err| invalid(err.to_string()))?;
+    let entries: BTreeMap<String, serde_json::Value> =
+        serde_json::from_str(&text).map_err(|err| invalid(err.to_string()))?;
+    entries
+        .into_iter()
+        .map(|(file, value)| {
+            let time = match &value {
+                serde_json::Value::String(s) => s.clone(),
+                serde_json::Value::Number(n) => n.to_string(),
+                _ => String::new(),
+            };
+            ReadAt::parse(&time)
+                .map(|read_at| (normalize(&file).to_string(), read_at))
+                .ok_or_else(|| invalid(format!("invalid read time for {file}: {value}")))
+        })
+        .collect()
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct StaleFile {
+    pub(crate) path: String,
+    /// The read time, as given.
+    pub(crate) read_at: String,
+    /// The file's modification time, in RFC 3339.
+    pub(crate) modified: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct StaleReadGuard {
+    pub(crate) files: Vec<StaleFile>,
+    pub(crate) to: Mode,
+}
+
+impl StaleReadGuard {
+    pub(crate) fn describe(&self) -> Vec<String> {
+        self.files
+            .iter()
+            .map(|file| {
+                format!(
+                    "Stale read (refuse mode): {} was modified at {}, after it was read at {}; re-read it and write the patch again.",
+                    file.path, file.modified, file.read_at
+                )
+            })
+            .collect()
+    }
+}
+
+/// Checks each of `files` with a read time against its modification time.
+/// Missing files aren't checked; the applier reports them.
+pub(crate) fn guard(reads: &BTreeMap<String, ReadAt>, files: &[String]) -> Option<StaleReadGuard> {
+    if reads.is_empty() {
+        return None;
+    }
+    let stale: Vec<StaleFile> = files
+        .iter()
+        .filter_map(|file| {
+            let read_at = reads.get(normalize(file))?;
+            let modified = std::fs::metadata(file).ok()?.modified().ok()?;
+            let since_epoch = modified.duration_since(UNIX_EPOCH).ok()?;
+            let mut nanos = since_epoch.as_nanos() as i128;
+            if !read_at.precise {
+                nanos -= nanos % 1_000_000_000;
+            }
+            (nanos > read_at.nanos).then(|| StaleFile {
+                path: file.clone(),
+                read_at: read_at.text.clone(),
+                modified: format_rfc3339(since_epoch.as_secs() as i64),
+            })
+        })
+        .collect();
+    (!stale.is_empty()).then_some(StaleReadGuard {
+        files: stale,
+        to: Mode::Refuse,
+    })
+}
@@ -0,0 +1,355 @@
+//! `git apply` fallback (`fallback` in the config).
+//!
+//! With `"fallback": "git-apply"`, a whole patch whose hunks the built-in
+//! applier can't place is converted to a unified diff and handed to
+//! `git apply --recount -C1` instead, which also places a hunk when only its
+//! outermost context lines have drifted. The fallback is chosen up front, by
+//! simulating the patch, so the tree is never left half-patched by one
+//! engine before the other runs. The output says which engine applied the
+//! patch.
+//!
+//! `git apply --3way` isn't used: it refuses files whose working copy
+//! differs from the index and stages what it applies, and a converted diff
+//! carries no blob ids for it to merge from anyway. The `--3way` flag does
+//! the merge itself instead: the diff is applied with `git apply --cached`
+//! to the index versions in a scratch index, and `git merge-file` carries
+//! each result over to the working copy, so only lines both sides changed
+//! end up as conflict markers.
+
+use crate::concise;
+use crate::diagnostics;
+use crate::patch::EOF_MARKER;
+use crate::patch::Patch;
+use crate::patch::Section;
+use crate::patch::SectionKind;
+use serde::Deserialize;
+use serde::Serialize;
+use std::io::Write;
+use std::path::Path;
+use std::process::Command;
+use std::process::Output;
+use std::process::Stdio;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum Fallback {
+    #[default]
+    None,
+    GitApply,
+}
+
+impl Fallback {
+    pub(crate) fn as_str(self) -> &'static str {
+        match self {
+            Fallback::None => "none",
+            Fallback::GitApply => "git-apply",
+        }
+    }
+}
+
+/// `patch` as a unified diff against the files in the current directory,
+/// or an error naming what can't be converted.
+fn to_unified_diff(patch: &Patch) -> Result<String, String> {
+    let mut out = String::new();
+    for section in &patch.sections {
+        let path = &section.path;
+        match section.kind {
+            SectionKind::Add => {
+                let contents = section.added_contents();
+                out.push_str(&format!(
+                    "diff --git a/{path} b/{path}\nnew file mode 100644\n--- /dev/null\n+++ b/{path}\n"
+                ));
+                let lines: Vec<&str> = contents.lines().collect();
+                if !lines.is_empty() {
+                    out.push_str(&format!("@@ -0,0 +1,{} @@\n", lines.len()));
+                    for line in lines {
+                        out.push_str(&format!("+{line}\n"));
+                    }
+                }
+            }
+            SectionKind::Delete => {
+                let current = std::fs::read_to_string(path)
+                    .map_err(|err| format!("cannot read {path}: {err}"))?;
+                out.push_str(&format!(
+                    "diff --git a/{path} b/{path}\ndeleted file mode 100644\n--- a/{path}\n+++ /dev/null\n"
+                ));
+                let lines: Vec<&str> = current.lines().collect();
+                if !lines.is_empty() {
+                    out.push_str(&format!("@@ -1,{} +0,0 @@\n", lines.len()));
+                    for line in lines {
+                        out.push_str(&format!("-{line}\n"));
+                    }
+                    if !current.ends_with('\n') {
+                        out.push_str("\\ No newline at end of file\n");
+                    }
+                }
+            }
+            SectionKind::Update => {
+                let dest = section.move_to().unwrap_or(path);
+                out.push_str(&format!("diff --git a/{path} b/{dest}\n"));
+                if dest != path {
+                    out.push_str(&format!("rename from {path}\nrename to {dest}\n"));
+                }
+                out.push_str(&format!("--- a/{path}\n+++ b/{dest}\n"));
+                let current = std::fs::read_to_string(path)
+                    .map_err(|err| format!("cannot read {path}: {err}"))?;
+                let line_count = current.lines().count();
+                for hunk in hunks(section) {
+                    let old = hunk.iter().filter(|l| !l.starts_with('+')).count();
+                    let new = hunk.iter().filter(|l| !l.starts_with('-')).count();
+                    // Positions are placeholders for git's search, except
+                    // that a hunk without old lines is appended, as the
+                    // built-in applier does. Line 1 would pin a hunk to the
+                    // start of the file.
+                    let header = if old == 0 {
+                        format!("@@ -{line_count},0 +{},{new} @@\n", line_count + 1)
+                    } else {
+                        format!("@@ -2,{old} +2,{new} @@\n")
+                    };
+                    out.push_str(&header);
+                    for line in hunk {
+                        out.push_str(&line);
+                        out.push('\n');
+                    }
+                }
+            }
+        }
+    }
+    Ok(out)
+}
+
+/// The hunks of an update section as unified-diff lines (` `, `+` or `-`
+/// followed by the text). `@@` context markers only separate hunks.
+fn hunks(section: &Section) -> Vec<Vec<String>> {
+    let mut body: &[String] = &section.body;
+    if section.move_to().is_some() {
+        body = &body[1..];
+    }
+    let mut hunks: Vec<Vec<String>> = Vec::new();
+    let mut current: Option<Vec<String>> = None;
+    for line in body {
+        if line.starts_with("@@") {
+            hunks.extend(current.take().filter(|h| !h.is_empty()));
+            current = Some(Vec::new());
+            continue;
+        }
+        // `*** End of File` has no unified-diff equivalent.
+        if line.starts_with(EOF_MARKER) {
+            continue;
+        }
+        let diff_line = match line.chars().next() {
+            None => " ".to_string(),
+            Some(' ' | '+' | '-') => line.clone(),
+            Some(_) => continue,
+        };
+        if current.is_none() && line.trim().is_empty() {
+            continue;
+        }
+        current.get_or_insert_with(Vec::new).push(diff_line);
+    }
+    hunks.extend(current.filter(|h| !h.is_empty()));
+    hunks
+}
+
+/// Applies `patch` with `git apply`, printing the usual summary and the
+/// engine used. `native_error` is why the built-in applier couldn't.
+pub(crate) fn apply(patch: &Patch, native_error: &str) -> Result<(), String> {
+    let diff = to_unified_diff(patch)?;
+    let child = git(&apply_args(&[]), None, Some(&diff))
+        .map_err(|err| format!("cannot run git apply: {err}"))?;
+    if !child.status.success() {
+        return Err(format!(
+            "git apply couldn't apply it either: {}",
+            reason(&child)
+        ));
+    }
+    concise::print_success(&patch.applied_summary());
+    println!(
+        "Applied with git apply; the built-in applier couldn't: {}",
+        native_error.lines().next().unwrap_or_default()
+    );
+    Ok(())
+}
+
+/// Applies `patch` by three-way merge (`--3way`) with the index versions
+/// of its files as the base, printing the usual summary and the conflicts
+/// left in the files. `native_error` is why the built-in applier couldn't.
+pub(crate) fn apply_three_way(patch: &Patch, native_error: &str) -> Result<(), String> {
+    let diff = to_unified_diff(patch)?;
+    let tree = git(&["write-tree"], None, None)
+        .ok()
+        .filter(|output| output.status.success())
+        .ok_or("--3way needs a git work tree whose index can be the base")?;
+    let tree = String::from_utf8_lossy(&tree.stdout).trim().to_string();
+    let scratch = std::env::temp_dir().join(format!("apply_patch-3way-{}", std::process::id()));
+    let merged = std::fs::create_dir_all(&scratch)
+        .map_err(|err| format!("cannot create {}: {err}", scratch.display()))
+        .and_then(|()| merge_patch(patch, &diff, &tree, &scratch));
+    let _ = std::fs::remove_dir_all(&scratch);
+    let (writes, conflicts) = merged?;
+    for (path, contents) in writes {
+        let result = match contents {
+            Some(contents) => Path::new(&path)
+                .parent()
+                .filter(|parent| !parent.as_os_str().is_empty())
+                .map_or(Ok(()), std::fs::create_dir_all)
+                .and_then(|()| std::fs::write(&path, contents)),
+            None => std::fs::remove_file(&path),
+        };
+        result.map_err(|err| format!("cannot write {path}: {err}"))?;
+    }
+    concise::print_success(&patch.applied_summary());
+    println!(
+        "Merged with the index versions as the base (--3way); the built-in applier couldn't: {}",
+        native_error.lines().next().unwrap_or_default()
+    );
+    for (path, count) in conflicts {
+        diagnostics::warning(format!(
+            "{path}: {count} conflict(s) with the working tree, left between <<<<<<< and >>>>>>> markers."
+        ));
+    }
+    Ok(())
+}
+
+/// What a merged patch writes (`None`: removes), and the files left with
+/// conflicts with how many.
+type Merged = (Vec<(String, Option<String>)>, Vec<(String, i32)>);
+
+/// Applies `diff` to `tree` (the index) in a scratch index under `scratch`
+/// and merges each updated file with its working copy.
+fn merge_patch(patch: &Patch, diff: &str, tree: &str, scratch: &Path) -> Result<Merged, String> {
+    let index = scratch.join("index");
+    git(&["read-tree", tree], Some(&index), None)
+        .ok()
+        .filter(|output| output.status.success())
+        .ok_or("cannot build a scratch index")?;
+    let applied = git(&apply_args(&["--cached"]), Some(&index), Some(diff))
+        .map_err(|err| format!("cannot run git apply: {err}"))?;
+    if !applied.status.success() {
+        return Err(format!(
+            "git apply couldn't apply it to the index versions either: {}",
+            reason(&applied)
+        ));
+    }
+    let show = |path: &str, index: Option<&Path>| {
+        git(&["show", &format!(":./{path}")], index, None)
+            .ok()
+            .filter(|output| output.status.success())
+            .map(|output| String::from_utf8_lossy(&output.stdout).into_owned())
+            .ok_or_else(|| {
+                format!("{path} is not in the git index, so there is no base to merge from")
+            })
+    };
+    let mut writes = Vec::new();
+    let mut conflicts = Vec::new();
+    for section in &patch.sections {
+        let path = section.path.as_str();
+        match section.kind {
+            SectionKind::Add => writes.push((path.to_string(), Some(section.added_contents()))),
+            SectionKind::Delete => writes.push((path.to_string(), None)),
+            SectionKind::Update => {
+                let dest = section.move_to().unwrap_or(path);
+                let base = show(path, None)?;
+                let theirs = show(dest, Some(&index))?;
+                let ours = std::fs::read_to_string(path)
+                    .map_err(|err| format!("cannot read {path}: {err}"))?;
+                let (merged, count) = merge_file(scratch, path, [&ours, &base, &theirs])?;
+                if dest != path {
+                    writes.push((path.to_string(), None));
+                }
+                writes.push((dest.to_string(), Some(merged)));
+                if count > 0 {
+                    conflicts.push((dest.to_string(), count));
+                }
+            }
+        }
+    }
+    Ok((writes, conflicts))
+}
+
+/// `git merge-file` of the working copy, the base and the patched base:
+/// the merged text and its number of conflicts.
+fn merge_file(scratch: &Path, path: &str, sides: [&str; 3]) -> Result<(String, i32), String> {
+    let names = ["ours", "base", "theirs"];
+    for (name, contents) in names.iter().zip(sides) {
+        std::fs::write(scratch.join(name), contents)
+            .map_err(|err| format!("cannot write {}: {err}", scratch.display()))?;
+    }
+    let files = names.map(|name| scratch.join(name).display().to_string());
+    let output = git(
+        &[
+            "merge-file",
+            "-p",
+            "-L",
+            path,
+            "-L",
+            "index",
+            "-L",
+            "patch",
+            &files[0],
+            &files[1],
+            &files[2],
+        ],
+        None,
+        None,
+    )
+    .map_err(|err| format!("cannot run git merge-file: {err}"))?;
+    // The exit code is the number of conflicts; negative ones are errors.
+    match output.status.code() {
+        Some(count @ 0..=127) => Ok((String::from_utf8_lossy(&output.stdout).into_owned(), count)),
+        _ => Err(format!(
+            "git merge-file couldn't merge {path}: {}",
+            reason(&output)
+        )),
+    }
+}
+
+/// `git apply` arguments for a converted diff, with `extra` options.
+fn apply_args(extra: &[&str]) -> Vec<String> {
+    let mut args: Vec<String> = ["apply", "--recount", "-C1", "--whitespace=nowarn"]
+        .into_iter()
+        .chain(extra.iter().copied())
+        .map(str::to_string)
+        .collect();
+    // Inside a repository git resolves paths from the top of the work tree.
+    if let Ok(output) = git(&["rev-parse", "--show-prefix"], None, None)
+        && output.status.success()
+    {
+        let prefix = String::from_utf8_lossy(&output.stdout).trim().to_string();
+        if !prefix.is_empty() {
+            args.push(format!("--directory={prefix}"));
+        }
+    }
+    args
+}
+
+/// The last `error:` line git printed.
+fn reason(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stderr)
+        .lines()
+        .filter_map(|line| line.strip_prefix("error: "))
+        .next_back()
+        .unwrap_or("it failed")
+        .to_string()
+}
+
+/// Runs git with `input` on stdin and `GIT_INDEX_FILE` set to `index`.
+fn git(
+    args: &[impl AsRef<std::ffi::OsStr>],
+    index: Option<&Path>,
+    input: Option<&str>,
+) -> std::io::Result<Output> {
+    let mut cmd = Command::new("git");
+    cmd.args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+    if let Some(index) = index {
+        cmd.env("GIT_INDEX_FILE", index);
+    }
+    let mut child = cmd.spawn()?;
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin.write_all(input.unwrap_or_default().as_bytes())?;
+    }
+    child.wait_with_output()
+}
@@ -0,0 +1,235 @@
+//! Git boundary guard (`git_boundaries` in the config): raises the mode
+//! when a patch targets paths inside a submodule (or other nested
+//! repository) or outside the sparse-checkout cone, where new files would
+//! end up in another repository's history or as untracked junk.
+//!
+//! Only cone-mode sparse checkouts are understood; other sparse patterns
+//! are ignored.
+
+use crate::Mode;
+use crate::policy::severity;
+use serde::Deserialize;
+use serde::Serialize;
+use std::path::Component;
+use std::path::Path;
+use std::path::PathBuf;
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub(crate) struct GitBoundariesConfig {
+    /// Mode to enforce when a target crosses a boundary; off when unset.
+    #[serde(default)]
+    pub(crate) action: Option<Mode>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub(crate) enum Boundary {
+    /// The path belongs to the repository checked out at `root`.
+    Submodule { path: String, root: String },
+    /// The path is outside the sparse-checkout cone.
+    OutsideSparseCheckout { path: String },
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct GitBoundaryGuard {
+    pub(crate) boundaries: Vec<Boundary>,
+    pub(crate) to: Mode,
+}
+
+impl GitBoundaryGuard {
+    pub(crate) fn describe(&self) -> Vec<String> {
+        let mode = self.to.as_str();
+        self.boundaries
+            .iter()
+            .map(|b| match b {
+                Boundary::Submodule { path, root } => format!(
+                    "Git boundary ({mode} mode): {path} is inside the submodule or nested repository at {root}; change it in that repository instead."
+                ),
+                Boundary::OutsideSparseCheckout { path } => format!(
+                    "Git boundary ({mode} mode): {path} is outside the sparse-checkout cone; git would treat it as untracked."
+                ),
+            })
+            .collect()
+    }
+}
+
+/// Checks `files` (relative to the current directory) against the
+/// enclosing repository. Returns `None` when the guard is off, there is no
+/// repository, or nothing crosses a boundary.
+pub(crate) fn guard(
+    cfg: &GitBoundariesConfig,
+    files: &[String],
+    mode: Mode,
+) -> Option<GitBoundaryGuard> {
+    let action = cfg.action?;
+    let cwd = std::env::current_dir().ok()?;
+    let repo = Repo::discover(&cwd)?;
+    let boundaries: Vec<Boundary> = files
+        .iter()
+        .filter_map(|file| repo.check(&cwd, file))
+        .collect();
+    if boundaries.is_empty() {
+        return None;
+    }
+    let to = if severity(action) > severity(mode) {
+        action
+    } else {
+        mode
+    };
+    Some(GitBoundaryGuard { boundaries, to })
+}
+
+struct Repo {
+    root: PathBuf,
+    /// `path = ...` entries from `.gitmodules`, relative to `root`.
+    submodules: Vec<String>,
+    sparse: Option<SparseCone>,
+}
+
+impl Repo {
+    fn discover(start: &Path) -> Option<Self> {
+        let root = start.ancestors().find(|dir| dir.join(".git").exists())?;
+        let git_dir = resolve_git_dir(root)?;
+        Some(Self {
+            root: root.to_path_buf(),
+            submodules: read_submodule_paths(&root.join(".gitmodules")),
+            sparse: SparseCone::load(&git_dir),
+        })
+    }
+
+    fn check(&self, cwd: &Path, file: &str) -> Option<Boundary> {
+        let absolute = normalize(&cwd.join(file));
+        let relative = absolute.strip_prefix(&self.root).ok()?;
+        let rel = relative.to_string_lossy().replace('\\', "/");
+
+        if let Some(sub) = self
+            .submodules
+            .iter()
+            .find(|sub| rel.starts_with(&format!("{sub}/")))
+        {
+            return Some(Boundary::Submodule {
+                path: file.to_string(),
+                root: sub.clone(),
+            });
+        }
+        // Nested repositories that aren't registered as submodules.
+        if let Some(dir) = absolute
+            .ancestors()
+            .skip(1)
+            .take_while(|dir| *dir != self.root)
+            .find(|dir| dir.join(".git").exists())
+        {
+            let root = dir.strip_prefix(&self.root).unwrap_or(dir);
+            return Some(Boundary::Submodule {
+                path: file.to_string(),
+                root: root.to_string_lossy().replace('\\', "/"),
+            });
+        }
+        match &self.sparse {
+            Some(cone) if !cone.contains(&rel) => Some(Boundary::OutsideSparseCheckout {
+                path: file.to_string(),
+            }),
+            _ => None,
+        }
+    }
+}
+
+/// The repository's git directory, following a `gitdir:` file (worktrees
+/// and submodules).
+fn resolve_git_dir(root: &Path) -> Option<PathBuf> {
+    let dot_git = root.join(".git");
+    if dot_git.is_dir() {
+        return Some(dot_git);
+    }
+    let contents = std::fs::read_to_string(&dot_git).ok()?;
+    let target = contents.trim().strip_prefix("gitdir:")?.trim();
+    Some(root.join(target))
+}
+
+fn read_submodule_paths(gitmodules: &Path) -> Vec<String> {
+    let Ok(contents) = std::fs::read_to_string(gitmodules) else {
+        return Vec::new();
+    };
+    contents
+        .lines()
+        .filter_map(|line| {
+            let (key, value) = line.split_once('=')?;
+            (key.trim() == "path").then(|| value.trim().trim_end_matches('/').to_string())
+        })
+        .collect()
+}
+
+/// Lexically resolves `.` and `..` components.
+pub(crate) fn normalize(path: &Path) -> PathBuf {
+    let mut out = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                out.pop();
+            }
+            other => out.push(other),
+        }
+    }
+    out
+}
+
+/// A cone-mode sparse checkout: top-level files, everything under the
+/// `recursive` directories, and the files directly inside `parents`.
+struct SparseCone {
+    recursive: Vec<String>,
+    parents: Vec<String>,
+}
+
+impl SparseCone {
+    fn load(git_dir: &Path) -> Option<Self> {
+        let config = std::fs::read_to_string(git_dir.join("config")).ok()?;
+        let enabled = config.lines().any(|line| {
+            let line = line.trim().to_ascii_lowercase().replace(' ', "");
+            line == "sparsecheckout=true"
+        });
+        if !enabled {
+            return None;
+        }
+        let patterns =
+            std::fs::read_to_string(git_dir.join("info").join("sparse-checkout")).ok()?;
+        Self::parse(&patterns)
+    }
+
+    /// Parses the cone-mode pattern file; anything else returns `None`.
+    fn parse(patterns: &str) -> Option<Self> {
+        let lines: Vec<&str> = patterns
+            .lines()
+            .map(str::trim)
+            .filter(|l| !l.is_empty() && !l.starts_with('#'))
+            .collect();
+        if lines.first() != Some(&"/*") || lines.get(1) != Some(&"!/*/") {
+            return None;
+        }
+        let mut dirs: Vec<String> = Vec::new();
+        let mut parents: Vec<String> = Vec::new();
+        for line in &lines[2..] {
+            if let Some(dir) = line.strip_prefix("!/").and_then(|l| l.strip_suffix("/*/")) {
+                parents.push(dir.to_string());
+            } else if let Some(dir) = line.strip_prefix('/').and_then(|l| l.strip_suffix('/')) {
+                dirs.push(dir.to_string());
+            } else {
+                return None;
+            }
+        }
+        let recursive = dirs.into_iter().filter(|d| !parents.contains(d)).collect();
+        Some(Self { recursive, parents })
+    }
+
+    fn contains(&self, rel: &str) -> bool {
+        let parent = match rel.rsplit_once('/') {
+            Some((parent, _)) => parent,
+            None => return true,
+        };
+        self.parents.iter().any(|p| p == parent)
+            || self
+                .recursive
+                .iter()
+                .any(|dir| rel.starts_with(&format!("{dir}/")))
+    }
+}
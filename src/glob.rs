@@ -0,0 +1,55 @@
+//! Path globs for policy and path rules.
+//!
+//! `*` and `?` never cross a `/`, `**` matches any number of path components
+//! (including none). A pattern without a `/` is matched against the file name
+//! only, so `*.lock` catches lockfiles at any depth.
+
+/// Returns true when `path` matches `pattern`.
+pub(crate) fn glob_match(pattern: &str, path: &str) -> bool {
+    let path = path.strip_prefix("./").unwrap_or(path);
+    let pattern = pattern.strip_prefix("./").unwrap_or(pattern);
+    let subject = if pattern.contains('/') {
+        path
+    } else {
+        path.rsplit('/').next().unwrap_or(path)
+    };
+    let p: Vec<char> = pattern.chars().collect();
+    let s: Vec<char> = subject.chars().collect();
+    matches(&p, &s)
+}
+
+/// Returns true when all of `subject` matches `pattern`, even if the
+/// pattern has no `/`; for names like git branches rather than paths.
+pub(crate) fn glob_match_whole(pattern: &str, subject: &str) -> bool {
+    let p: Vec<char> = pattern.chars().collect();
+    let s: Vec<char> = subject.chars().collect();
+    matches(&p, &s)
+}
+
+fn matches(p: &[char], s: &[char]) -> bool {
+    match p.first() {
+        None => s.is_empty(),
+        Some('*') if p.get(1) == Some(&'*') => {
+            let rest = &p[2..];
+            if let Some(after_slash) = rest.strip_prefix(&['/'])
+                && matches(after_slash, s)
+            {
+                return true;
+            }
+            (0..=s.len()).any(|i| matches(rest, &s[i..]))
+        }
+        Some('*') => {
+            for i in 0..=s.len() {
+                if matches(&p[1..], &s[i..]) {
+                    return true;
+                }
+                if s.get(i) == Some(&'/') {
+                    break;
+                }
+            }
+            false
+        }
+        Some('?') => s.first().is_some_and(|c| *c != '/') && matches(&p[1..], &s[1..]),
+        Some(c) => s.first() == Some(c) && matches(&p[1..], &s[1..]),
+    }
+}
@@ -0,0 +1,66 @@
+//! Guidance for patches the applier can't parse.
+//!
+//! The applier's parse errors are terse, and a model that only sees
+//! `Invalid patch hunk on line 7` tends to resend the same malformed patch.
+//! After the error, a model-directed explanation of the patch grammar is
+//! printed to stderr with the offending line quoted. `parse_error_message`
+//! in the config replaces it; `{error}`, `{line_number}` and `{line}` are
+//! filled in (empty when the error has no line).
+
+use codex_apply_patch::ParseError;
+
+const GRAMMAR: &str = "\
+A patch must have exactly this shape:
+*** Begin Patch
+*** Add File: path/to/new.txt
++each line of the new file, starting with '+'
+*** Update File: path/to/existing.txt
+@@ optional context, such as the enclosing function's signature
+ an unchanged context line, starting with a space
+-a removed line
++an added line
+*** Delete File: path/to/old.txt
+*** End Patch
+Every section starts with one of the three `*** ... File:` headers. In an
+update, each hunk starts with `@@` and every line after it starts with ' ',
+'-' or '+'. Paths are relative. Fix the patch and resend all of it.";
+
+/// The 1-based line `err` points at and its text, if it points at one.
+fn location(patch_text: &str, err: &ParseError) -> Option<(usize, String)> {
+    let number = match err {
+        ParseError::InvalidHunkError { line_number, .. } => *line_number,
+        ParseError::InvalidPatchError(message) if message.contains("first line") => 1,
+        ParseError::InvalidPatchError(message) if message.contains("last line") => {
+            patch_text.lines().count()
+        }
+        ParseError::InvalidPatchError(_) => return None,
+    };
+    let line = patch_text.lines().nth(number.checked_sub(1)?)?;
+    Some((number, line.to_string()))
+}
+
+/// Prints the guidance for `err`, raised while parsing `patch_text`.
+/// `error` is the applier's one-line message.
+pub(crate) fn explain(patch_text: &str, err: &ParseError, error: &str, template: Option<&str>) {
+    let location = location(patch_text, err);
+    let message = match template {
+        Some(template) => {
+            let (number, line) = location
+                .map(|(number, line)| (number.to_string(), line))
+                .unwrap_or_default();
+            template
+                .replace("{error}", error)
+                .replace("{line_number}", &number)
+                .replace("{line}", &line)
+        }
+        None => {
+            let at = location
+                .map(|(number, line)| {
+                    format!("\nThe problem is at line {number} of the patch:\n{line}")
+                })
+                .unwrap_or_default();
+            format!("\nNOTE TO LLM: the patch could not be parsed ({error}).{at}\n\n{GRAMMAR}")
+        }
+    };
+    eprintln!("{message}");
+}
@@ -0,0 +1,308 @@
+//! Hashline addressing: update hunks whose lines carry a hash of their text.
+//!
+//! In a hashline hunk every context and removed line starts with `#`, the
+//! first hex digits (at least four) of the sha256 of the line's text and `|`:
+//!
+//! ```text
+//! *** Update File: src/app.rs
+//! @@
+//! #1f0e| fn main() {
+//! #a3f1|-    let x = 1;
+//! +    let x = 2;
+//! #77c4|     run(x);
+//! ```
+//!
+//! Each hashed line is looked up in the current file by its hash (trailing
+//! whitespace ignored), lines whose hash occurs once first, so a hunk still
+//! lands when lines were inserted between its lines or the block moved.
+//! Context lines that no longer exist are skipped; a removed line that can't
+//! be found fails the patch. The hunk is then rewritten over the file's
+//! actual lines, so the applier sees exact context.
+//! `apply_patch make-patch --hashline` writes patches in this form.
+
+use crate::digest::sha256_hex;
+use crate::patch::EOF_MARKER;
+use crate::patch::MOVE_TO_MARKER;
+use crate::patch::Patch;
+use crate::patch::SectionKind;
+use std::borrow::Cow;
+
+/// Hex digits `make-patch --hashline` writes, and the fewest accepted.
+const HASH_LEN: usize = 4;
+/// File lines kept as context around a rewritten hunk.
+const CONTEXT: usize = 3;
+
+/// The full hash of a line's text.
+pub(crate) fn line_hash(text: &str) -> String {
+    sha256_hex(text.trim_end().as_bytes())
+}
+
+/// `line` with a `#hash|` prefix for its text (without the ` `/`-` prefix).
+pub(crate) fn annotate(line: &str) -> String {
+    match line.strip_prefix([' ', '-']) {
+        Some(text) => format!("#{}|{line}", &line_hash(text)[..HASH_LEN]),
+        None => line.to_string(),
+    }
+}
+
+/// The hash and the rest of a `#hash|...` line.
+pub(crate) fn split(line: &str) -> Option<(&str, &str)> {
+    let (hash, rest) = line.strip_prefix('#')?.split_once('|')?;
+    let valid = hash.len() >= HASH_LEN && hash.chars().all(|c| c.is_ascii_hexdigit());
+    valid.then_some((hash, rest))
+}
+
+enum Item {
+    Hashed {
+        hash: String,
+        text: String,
+        removed: bool,
+    },
+    Added(String),
+}
+
+#[derive(Default)]
+struct Hunk {
+    items: Vec<Item>,
+    end_of_file: bool,
+}
+
+fn parse_hunks(path: &str, body: &[String]) -> Result<Vec<Hunk>, String> {
+    let mut hunks: Vec<Hunk> = Vec::new();
+    let mut current: Option<Hunk> = None;
+    for line in body {
+        let header = line == "@@" || line.starts_with("@@ ");
+        let started = header || current.is_none();
+        if header {
+            hunks.extend(current.take());
+        }
+        let hunk = current.get_or_insert_with(Hunk::default);
+        if started && line.starts_with("@@") {
+            continue;
+        }
+        if line.trim_end() == EOF_MARKER {
+            hunk.end_of_file = true;
+        } else if let Some((hash, rest)) = split(line) {
+            hunk.items.push(match rest.chars().next() {
+                Some('+') => Item::Added(rest[1..].to_string()),
+                Some(prefix @ (' ' | '-')) => Item::Hashed {
+                    hash: hash.to_ascii_lowercase(),
+                    text: rest[1..].to_string(),
+                    removed: prefix == '-',
+                },
+                _ => {
+                    return Err(format!(
+                        "Invalid hashline hunk for {path}: `{line}` needs ` `, `-` or `+` after the `|`"
+                    ));
+                }
+            });
+        } else if let Some(text) = line.strip_prefix('+') {
+            hunk.items.push(Item::Added(text.to_string()));
+        } else if !line.trim().is_empty() {
+            return Err(format!(
+                "Invalid hashline hunk for {path}: `{line}` has no #hash| prefix; in a hashline section every context and removed line needs one"
+            ));
+        }
+    }
+    hunks.extend(current);
+    Ok(hunks)
+}
+
+/// File positions of the hunk's hashed items (`None`: an added line, or
+/// context that no longer exists), all at or after `from` and increasing.
+fn resolve(
+    path: &str,
+    hunk: &Hunk,
+    lines: &[&str],
+    hashes: &[String],
+    from: usize,
+) -> Result<Vec<Option<usize>>, String> {
+    let candidates: Vec<Vec<usize>> = hunk
+        .items
+        .iter()
+        .map(|item| {
+            let Item::Hashed { hash, text, .. } = item else {
+                return Vec::new();
+            };
+            let found: Vec<usize> = (from..lines.len())
+                .filter(|&i| hashes[i].starts_with(hash.as_str()))
+                .collect();
+            // A hash shared with other text is a collision; prefer the text.
+            let exact: Vec<usize> = found
+                .iter()
+                .copied()
+                .filter(|&i| lines[i].trim_end() == text.trim_end())
+                .collect();
+            if exact.is_empty() { found } else { exact }
+        })
+        .collect();
+    // Lines found exactly once pin the hunk; the rest are placed between.
+    let mut positions: Vec<Option<usize>> = vec![None; hunk.items.len()];
+    let mut last: Option<usize> = None;
+    for (k, found) in candidates.iter().enumerate() {
+        if let [only] = found[..]
+            && last.is_none_or(|last| only > last)
+        {
+            positions[k] = Some(only);
+            last = Some(only);
+        }
+    }
+    let mut after = from;
+    for k in 0..hunk.items.len() {
+        if let Some(pinned) = positions[k] {
+            after = pinned + 1;
+            continue;
+        }
+        let before = positions[k..].iter().flatten().next().copied();
+        positions[k] = candidates[k]
+            .iter()
+            .copied()
+            .find(|&i| i >= after && before.is_none_or(|before| i < before));
+        if let Some(found) = positions[k] {
+            after = found + 1;
+        } else if let Item::Hashed {
+            hash,
+            text,
+            removed: true,
+        } = &hunk.items[k]
+        {
+            return Err(format!(
+                "Hashline #{hash} ({text}) in {path} matches no line where the hunk can go; nothing was changed."
+            ));
+        }
+    }
+    if positions.iter().all(Option::is_none) {
+        return Err(format!(
+            "No hashed line of a hunk for {path} matches the file; nothing was changed."
+        ));
+    }
+    Ok(positions)
+}
+
+/// A hunk placed in the file: the lines it spans, the ones it removes and
+/// the lines it adds before a given line.
+struct Placed<'a> {
+    first: usize,
+    last: usize,
+    end_of_file: bool,
+    removed: Vec<usize>,
+    added: Vec<(usize, &'a str)>,
+}
+
+fn place<'a>(hunk: &'a Hunk, positions: &[Option<usize>]) -> Placed<'a> {
+    let resolved: Vec<usize> = positions.iter().flatten().copied().collect();
+    let mut placed = Placed {
+        first: resolved[0],
+        last: resolved[resolved.len() - 1],
+        end_of_file: hunk.end_of_file,
+        removed: Vec::new(),
+        added: Vec::new(),
+    };
+    for (k, item) in hunk.items.iter().enumerate() {
+        match item {
+            Item::Hashed { removed: true, .. } => placed.removed.extend(positions[k]),
+            Item::Hashed { .. } => {}
+            // After the hashed line before it, or else before the next one.
+            Item::Added(text) => {
+                let at = positions[..k]
+                    .iter()
+                    .rev()
+                    .flatten()
+                    .next()
+                    .map(|i| i + 1)
+                    .or_else(|| positions[k..].iter().flatten().next().copied())
+                    .unwrap_or(placed.first);
+                placed.added.push((at, text));
+            }
+        }
+    }
+    placed
+}
+
+/// The section body with its hashline hunks rewritten over `contents`.
+/// Hunks whose context would overlap are merged.
+fn rewrite(path: &str, body: &[String], contents: &str) -> Result<Vec<String>, String> {
+    let (head, body) = match body.first() {
+        Some(first) if first.starts_with(MOVE_TO_MARKER) => (Some(first.clone()), &body[1..]),
+        _ => (None, body),
+    };
+    let lines: Vec<&str> = contents.lines().collect();
+    let hashes: Vec<String> = lines.iter().map(|line| line_hash(line)).collect();
+    let hunks = parse_hunks(path, body)?;
+    let mut placed: Vec<Placed<'_>> = Vec::new();
+    let mut from = 0;
+    for hunk in &hunks {
+        let positions = resolve(path, hunk, &lines, &hashes, from)?;
+        let hunk = place(hunk, &positions);
+        from = hunk.last + 1;
+        placed.push(hunk);
+    }
+
+    let mut groups: Vec<(usize, usize, Vec<Placed<'_>>)> = Vec::new();
+    for hunk in placed {
+        let start = hunk.first.saturating_sub(CONTEXT);
+        let end = if hunk.end_of_file {
+            lines.len()
+        } else {
+            (hunk.last + 1 + CONTEXT).min(lines.len())
+        };
+        match groups.last_mut() {
+            Some((_, group_end, group)) if start <= *group_end => {
+                *group_end = (*group_end).max(end);
+                group.push(hunk);
+            }
+            _ => groups.push((start, end, vec![hunk])),
+        }
+    }
+    let mut out: Vec<String> = head.into_iter().collect();
+    for (start, end, group) in groups {
+        out.push("@@".to_string());
+        for i in start..=end {
+            for hunk in &group {
+                for (_, text) in hunk.added.iter().filter(|(at, _)| *at == i) {
+                    out.push(format!("+{text}"));
+                }
+            }
+            if let Some(line) = lines[..end].get(i) {
+                let removed = group.iter().any(|hunk| hunk.removed.contains(&i));
+                out.push(format!("{}{line}", if removed { '-' } else { ' ' }));
+            }
+        }
+        if group.iter().any(|hunk| hunk.end_of_file) {
+            out.push(EOF_MARKER.to_string());
+        }
+    }
+    Ok(out)
+}
+
+/// Rewrites every update section with hashline hunks as a plain update of
+/// the file's current contents. Patches without them are returned
+/// unchanged.
+pub(crate) fn expand(patch_text: &str) -> Result<Cow<'_, str>, String> {
+    let Some(mut patch) = Patch::parse(patch_text) else {
+        return Ok(Cow::Borrowed(patch_text));
+    };
+    let mut changed = false;
+    for section in patch
+        .sections
+        .iter_mut()
+        .filter(|s| s.kind == SectionKind::Update)
+    {
+        if !section.body.iter().any(|line| split(line).is_some()) {
+            continue;
+        }
+        let contents = std::fs::read_to_string(&section.path).map_err(|err| {
+            format!(
+                "Failed to read {} for its hashline hunks: {err}",
+                section.path
+            )
+        })?;
+        section.body = rewrite(&section.path, &section.body, &contents)?;
+        changed = true;
+    }
+    Ok(if changed {
+        Cow::Owned(patch.render())
+    } else {
+        Cow::Borrowed(patch_text)
+    })
+}
@@ -0,0 +1,24 @@
+//! `--no-config` / `$APPLY_PATCH_NO_CONFIG=1`: run with the compiled-in
+//! defaults and nothing from the machine, for reproducible CI and tests.
+//!
+//! The config file is not read, the `APPLY_PATCH_*` variables that stand in
+//! for flags (`_CWD`, `_EVENT_FD`, `_TENANT`, `_FEEDBACK_FILE`, `_EXIT_CODES`)
+//! are ignored, and no state is carried between runs. Flags given on the
+//! command line still apply.
+
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+
+pub(crate) const NO_CONFIG_ENV: &str = "APPLY_PATCH_NO_CONFIG";
+
+static NO_CONFIG: AtomicBool = AtomicBool::new(false);
+
+/// Ignores config and environment overrides for the rest of the process.
+pub(crate) fn enable() {
+    NO_CONFIG.store(true, Ordering::Relaxed);
+}
+
+/// Whether `--no-config` was given or `$APPLY_PATCH_NO_CONFIG` is `1`.
+pub(crate) fn enabled() -> bool {
+    NO_CONFIG.load(Ordering::Relaxed) || std::env::var(NO_CONFIG_ENV).is_ok_and(|v| v == "1")
+}
@@ -0,0 +1,303 @@
+//! Minimal gzip/DEFLATE decoder (RFC 1951/1952), so archive operations
+//! don't need an extra dependency.
+
+const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131,
+    163, 195, 227, 258,
+];
+const LENGTH_EXTRA: [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+const DIST_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537,
+    2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+const DIST_EXTRA: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13,
+    13,
+];
+/// Order in which code length code lengths are stored in a dynamic block.
+const CODE_LENGTH_ORDER: [usize; 19] = [
+    16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15,
+];
+
+/// Decompresses a raw DEFLATE stream (as stored in zip entries), failing
+/// if the output would exceed `limit` bytes.
+pub(crate) fn inflate_raw(data: &[u8], limit: usize) -> Result<Vec<u8>, String> {
+    inflate(&mut BitReader::new(data), limit)
+}
+
+/// Decompresses a gzip member, failing if the output would exceed `limit`
+/// bytes or the checksum doesn't match.
+pub(crate) fn gunzip(data: &[u8], limit: usize) -> Result<Vec<u8>, String> {
+    if data.len() < 18 || data[0] != 0x1f || data[1] != 0x8b || data[2] != 8 {
+        return Err("not a gzip stream".to_string());
+    }
+    let flags = data[3];
+    let mut pos = 10;
+    if flags & 0x04 != 0 {
+        let extra = usize::from(u16::from_le_bytes([data[pos], data[pos + 1]]));
+        pos += 2 + extra;
+    }
+    for flag in [0x08, 0x10] {
+        if flags & flag != 0 {
+            let end = data[pos.min(data.len())..]
+                .iter()
+                .position(|b| *b == 0)
+                .ok_or("truncated gzip header")?;
+            pos += end + 1;
+        }
+    }
+    if flags & 0x02 != 0 {
+        pos += 2;
+    }
+    if pos > data.len() {
+        return Err("truncated gzip header".to_string());
+    }
+
+    let mut reader = BitReader::new(&data[pos..]);
+    let out = inflate(&mut reader, limit)?;
+    let trailer = reader
+        .remaining()
+        .get(..8)
+        .ok_or("truncated gzip trailer")?;
+    let crc = u32::from_le_bytes([trailer[0], trailer[1], trailer[2], trailer[3]]);
+    if crc != crc32(&out) {
+        return Err("gzip checksum mismatch".to_string());
+    }
+    Ok(out)
+}
+
+fn inflate(reader: &mut BitReader<'_>, limit: usize) -> Result<Vec<u8>, String> {
+    let mut out: Vec<u8> = Vec::new();
+    loop {
+        let last = reader.bits(1)? == 1;
+        match reader.bits(2)? {
+            0 => {
+                reader.align();
+                let header = reader.bytes(4)?;
+                let len = u16::from_le_bytes([header[0], header[1]]);
+                let nlen = u16::from_le_bytes([header[2], header[3]]);
+                if len != !nlen {
+                    return Err("corrupt stored block".to_string());
+                }
+                let block = reader.bytes(usize::from(len))?;
+                if out.len() + block.len() > limit {
+                    return Err(too_large(limit));
+                }
+                out.extend_from_slice(block);
+            }
+            1 => {
+                let mut lengths = [0u8; 288];
+                lengths[..144].fill(8);
+                lengths[144..256].fill(9);
+                lengths[256..280].fill(7);
+                lengths[280..].fill(8);
+                let lit = Huffman::new(&lengths);
+                let dist = Huffman::new(&[5; 30]);
+                codes(reader, &mut out, &lit, &dist, limit)?;
+            }
+            2 => {
+                let (lit, dist) = dynamic_tables(reader)?;
+                codes(reader, &mut out, &lit, &dist, limit)?;
+            }
+            _ => return Err("invalid deflate block type".to_string()),
+        }
+        if last {
+            reader.align();
+            return Ok(out);
+        }
+    }
+}
+
+fn dynamic_tables(reader: &mut BitReader<'_>) -> Result<(Huffman, Huffman), String> {
+    let nlen = reader.bits(5)? as usize + 257;
+    let ndist = reader.bits(5)? as usize + 1;
+    let ncode = reader.bits(4)? as usize + 4;
+    let mut code_lengths = [0u8; 19];
+    for &index in &CODE_LENGTH_ORDER[..ncode] {
+        code_lengths[index] = reader.bits(3)? as u8;
+    }
+    let code = Huffman::new(&code_lengths);
+
+    let mut lengths: Vec<u8> = Vec::with_capacity(nlen + ndist);
+    while lengths.len() < nlen + ndist {
+        let symbol = code.decode(reader)?;
+        let (value, repeat) = match symbol {
+            0..=15 => (symbol as u8, 1),
+            16 => {
+                let prev = *lengths.last().ok_or("repeat with no previous length")?;
+                (prev, 3 + reader.bits(2)? as usize)
+            }
+            17 => (0, 3 + reader.bits(3)? as usize),
+            _ => (0, 11 + reader.bits(7)? as usize),
+        };
+        if lengths.len() + repeat > nlen + ndist {
+            return Err("too many code lengths".to_string());
+        }
+        lengths.extend(std::iter::repeat_n(value, repeat));
+    }
+    Ok((
+        Huffman::new(&lengths[..nlen]),
+        Huffman::new(&lengths[nlen..]),
+    ))
+}
+
+fn codes(
+    reader: &mut BitReader<'_>,
+    out: &mut Vec<u8>,
+    lit: &Huffman,
+    dist: &Huffman,
+    limit: usize,
+) -> Result<(), String> {
+    loop {
+        let symbol = lit.decode(reader)?;
+        match symbol {
+            0..=255 => {
+                if out.len() >= limit {
+                    return Err(too_large(limit));
+                }
+                out.push(symbol as u8);
+            }
+            256 => return Ok(()),
+            _ => {
+                let index = symbol - 257;
+                if index >= LENGTH_BASE.len() {
+                    return Err("invalid length code".to_string());
+                }
+                let len = usize::from(LENGTH_BASE[index])
+                    + reader.bits(LENGTH_EXTRA[index].into())? as usize;
+                let index = dist.decode(reader)?;
+                if index >= DIST_BASE.len() {
+                    return Err("invalid distance code".to_string());
+                }
+                let distance =
+                    usize::from(DIST_BASE[index]) + reader.bits(DIST_EXTRA[index].into())? as usize;
+                if distance > out.len() {
+                    return Err("distance too far back".to_string());
+                }
+                if out.len() + len > limit {
+                    return Err(too_large(limit));
+                }
+                let start = out.len() - distance;
+                for i in 0..len {
+                    out.push(out[start + i]);
+                }
+            }
+        }
+    }
+}
+
+fn too_large(limit: usize) -> String {
+    format!("archive is larger than {limit} bytes once decompressed")
+}
+
+/// Canonical Huffman code, decoded one bit at a time.
+struct Huffman {
+    /// Number of codes of each length.
+    counts: [u16; 16],
+    /// Symbols ordered by code.
+    symbols: Vec<usize>,
+}
+
+impl Huffman {
+    fn new(lengths: &[u8]) -> Self {
+        let mut counts = [0u16; 16];
+        for &len in lengths {
+            counts[usize::from(len)] += 1;
+        }
+        counts[0] = 0;
+        let mut offsets = [0usize; 16];
+        for len in 1..16 {
+            offsets[len] = offsets[len - 1] + usize::from(counts[len - 1]);
+        }
+        let mut symbols = vec![0; lengths.len()];
+        for (symbol, &len) in lengths.iter().enumerate() {
+            if len != 0 {
+                symbols[offsets[usize::from(len)]] = symbol;
+                offsets[usize::from(len)] += 1;
+            }
+        }
+        Self { counts, symbols }
+    }
+
+    fn decode(&self, reader: &mut BitReader<'_>) -> Result<usize, String> {
+        let (mut code, mut first, mut index) = (0usize, 0usize, 0usize);
+        for len in 1..16 {
+            code |= reader.bits(1)? as usize;
+            let count = usize::from(self.counts[len]);
+            if code < first + count {
+                return Ok(self.symbols[index + code - first]);
+            }
+            index += count;
+            first = (first + count) << 1;
+            code <<= 1;
+        }
+        Err("invalid Huffman code".to_string())
+    }
+}
+
+struct BitReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+    bit_buf: u32,
+    bit_count: u32,
+}
+
+impl<'a> BitReader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self {
+            data,
+            pos: 0,
+            bit_buf: 0,
+            bit_count: 0,
+        }
+    }
+
+    fn bits(&mut self, n: u32) -> Result<u32, String> {
+        while self.bit_count < n {
+            let byte = *self.data.get(self.pos).ok_or("truncated deflate stream")?;
+            self.pos += 1;
+            self.bit_buf |= u32::from(byte) << self.bit_count;
+            self.bit_count += 8;
+        }
+        let value = self.bit_buf & ((1u32 << n) - 1);
+        self.bit_buf >>= n;
+        self.bit_count -= n;
+        Ok(value)
+    }
+
+    /// Drops the bits left in the current byte.
+    fn align(&mut self) {
+        self.bit_buf = 0;
+        self.bit_count = 0;
+    }
+
+    fn bytes(&mut self, n: usize) -> Result<&'a [u8], String> {
+        let data = self.data;
+        let slice = data
+            .get(self.pos..self.pos + n)
+            .ok_or("truncated deflate stream")?;
+        self.pos += n;
+        Ok(slice)
+    }
+
+    fn remaining(&self) -> &'a [u8] {
+        &self.data[self.pos..]
+    }
+}
+
+pub(crate) fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= u32::from(byte);
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
@@ -0,0 +1,284 @@
+//! `apply_patch init`: writes a starter config.
+//!
+//! The config is plain JSON, so the starter file documents itself with
+//! comment keys: every key starting with `//` is ignored when the config is
+//! loaded. A preset (`--strict` or `--relaxed`) writes the file directly;
+//! without one, a few questions are asked on stdin, each defaulting to the
+//! recommended preset when left blank.
+
+use crate::Mode;
+use crate::diagnostics;
+use serde_json::Value;
+use serde_json::json;
+use std::io::BufRead;
+use std::io::Write;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Preset {
+    Strict,
+    Recommended,
+    Relaxed,
+}
+
+impl Preset {
+    fn parse(s: &str) -> Option<Self> {
+        match s {
+            "strict" => Some(Preset::Strict),
+            "recommended" => Some(Preset::Recommended),
+            "relaxed" => Some(Preset::Relaxed),
+            _ => None,
+        }
+    }
+
+    fn as_str(self) -> &'static str {
+        match self {
+            Preset::Strict => "strict",
+            Preset::Recommended => "recommended",
+            Preset::Relaxed => "relaxed",
+        }
+    }
+
+    fn mode(self) -> Mode {
+        match self {
+            Preset::Strict => Mode::Warn,
+            Preset::Recommended | Preset::Relaxed => Mode::Apply,
+        }
+    }
+}
+
+const DEFAULT_PROTECTED: &[&str] = &[".git/**", ".github/workflows/**", ".env*", "**/*.pem"];
+
+/// The answers a starter config is built from.
+#[derive(Debug)]
+struct Answers {
+    preset: Preset,
+    mode: Mode,
+    protected: Vec<String>,
+    notify_command: Option<String>,
+}
+
+impl Answers {
+    fn from_preset(preset: Preset) -> Self {
+        Self {
+            preset,
+            mode: preset.mode(),
+            protected: DEFAULT_PROTECTED.iter().map(|s| s.to_string()).collect(),
+            notify_command: None,
+        }
+    }
+}
+
+/// Asks for each answer on stderr and reads it from `input`. Blank answers,
+/// and a closed input, keep the default.
+fn prompt(mut input: impl BufRead) -> Result<Answers, String> {
+    let mut ask = |question: &str, default: &str| -> String {
+        eprint!("{question} ({default}): ");
+        let _ = std::io::stderr().flush();
+        let mut line = String::new();
+        let _ = input.read_line(&mut line);
+        let line = line.trim();
+        if line.is_empty() {
+            default.to_string()
+        } else {
+            line.to_string()
+        }
+    };
+
+    let preset = ask("Preset [strict/recommended/relaxed]", "recommended");
+    let preset = Preset::parse(&preset).ok_or(format!("invalid preset: {preset}"))?;
+    let mut answers = Answers::from_preset(preset);
+
+    let mode = ask(
+        "Mode for patches no rule matches [apply/warn/refuse]",
+        answers.mode.as_str(),
+    );
+    answers.mode = crate::parse_mode(&mode).ok_or(format!("invalid mode: {mode}"))?;
+
+    let protected = ask(
+        "Protected paths, as comma-separated globs",
+        &answers.protected.join(", "),
+    );
+    answers.protected = protected
+        .split(',')
+        .map(str::trim)
+        .filter(|glob| !glob.is_empty())
+        .map(str::to_string)
+        .collect();
+
+    let command = ask(
+        "Command to run when a patch is refused or escalated",
+        "none",
+    );
+    answers.notify_command = (command != "none").then_some(command);
+    Ok(answers)
+}
+
+/// The `when` expression matching any protected path, if there are any.
+fn protected_rule(globs: &[String]) -> Result<Option<String>, String> {
+    if let Some(glob) = globs.iter().find(|glob| glob.contains('\'')) {
+        return Err(format!("protected path globs can't contain quotes: {glob}"));
+    }
+    Ok((!globs.is_empty()).then(|| {
+        globs
+            .iter()
+            .map(|glob| format!("files ~ '{glob}'"))
+            .collect::<Vec<_>>()
+            .join(" || ")
+    }))
+}
+
+/// Renders the starter config: each key preceded by a `//KEY` comment key.
+fn render(answers: &Answers) -> Result<String, String> {
+    let preset = answers.preset;
+    let strict = preset == Preset::Strict;
+    let mut policies = Vec::new();
+    if let Some(when) = protected_rule(&answers.protected)? {
+        let mode = if preset == Preset::Relaxed {
+            "warn"
+        } else {
+            "refuse"
+        };
+        policies.push(json!({ "when": when, "mode": mode }));
+    }
+    let large_patch_lines = match preset {
+        Preset::Strict => Some(200),
+        Preset::Recommended => Some(500),
+        Preset::Relaxed => None,
+    };
+
+    let entries: Vec<(&str, &str, Value)> = vec![
+        ("version", "", json!(crate::migrate::CONFIG_VERSION)),
+        (
+            "mode",
+            "What to do with patches no policy matches: apply, warn (apply with a notice) or refuse (send the model to its native editing tool).",
+            json!(answers.mode.as_str()),
+        ),
+        (
+            "policies",
+            "Rules tried in order; the first whose `when` matches picks the mode. Facts include files, lines_changed, adds, deletes and agent. The rule below protects the paths given to init.",
+            Value::Array(policies),
+        ),
+        (
+            "escalate",
+            "Patches changing at least large_patch_lines lines get mode `to` when that is stricter; null turns this off.",
+            json!({
+                "large_patch_lines": large_patch_lines,
+                "to": if strict { "refuse" } else { "warn" },
+            }),
+        ),
+        (
+            "branch_rules",
+            "Raise the mode while the working tree is on a matching git branch.",
+            if strict {
+                json!([{ "branch": "main", "mode": "refuse" }, { "branch": "master", "mode": "refuse" }])
+            } else {
+                json!([])
+            },
+        ),
+        (
+            "editor_guard",
+            "Raise the mode to `action` when a target is open in an editor (swap and backup files); null turns this off.",
+            json!({ "action": if preset == Preset::Relaxed { None } else { Some("warn") } }),
+        ),
+        (
+            "require_clean",
+            "Raise the mode when a target has uncommitted changes: off, staged or any.",
+            json!({ "check": if strict { "any" } else { "off" }, "action": "refuse" }),
+        ),
+        (
+            "failure_guard",
+            "After `threshold` identical failures in a row, escalate the guidance (and refuse for cooldown_secs, if set).",
+            json!({
+                "threshold": if preset == Preset::Relaxed { None } else { Some(3) },
+                "cooldown_secs": if strict { Some(60) } else { None },
+            }),
+        ),
+        (
+            "notify",
+            "Hooks for refused and escalated patches: a desktop notification and/or a shell command.",
+            json!({ "desktop": false, "command": answers.notify_command }),
+        ),
+        (
+            "audit_log",
+            "JSON-lines file recording every decision; null for none.",
+            Value::Null,
+        ),
+    ];
+
+    let mut out = String::from("{\n");
+    out.push_str(&format!(
+        "  \"//\": {},\n",
+        json!(format!(
+            "Starter config written by `apply_patch init` ({} preset). Keys starting with // are comments and are ignored; `apply_patch --help` lists every key.",
+            preset.as_str()
+        ))
+    ));
+    for (i, (key, comment, value)) in entries.iter().enumerate() {
+        if !comment.is_empty() {
+            out.push_str(&format!("  \"//{key}\": {},\n", json!(comment)));
+        }
+        let value = serde_json::to_string_pretty(value).map_err(|err| err.to_string())?;
+        let comma = if i + 1 < entries.len() { "," } else { "" };
+        out.push_str(&format!(
+            "  \"{key}\": {}{comma}\n",
+            value.replace('\n', "\n  ")
+        ));
+    }
+    out.push_str("}\n");
+    Ok(out)
+}
+
+/// `apply_patch init [--strict|--relaxed] [--force]`.
+pub(crate) fn run_init(args: &[String]) -> i32 {
+    let mut preset = None;
+    let mut force = false;
+    for arg in args {
+        match arg.as_str() {
+            "--strict" | "--relaxed" => {
+                let chosen = Preset::parse(&arg[2..]);
+                if preset.is_some_and(|p| Some(p) != chosen) {
+                    diagnostics::error("--strict and --relaxed cannot be combined.");
+                    return 2;
+                }
+                preset = chosen;
+            }
+            "--force" => force = true,
+            _ => {
+                diagnostics::error(format!("unknown option: {arg}"));
+                return 2;
+            }
+        }
+    }
+    let Some(path) = crate::config_path() else {
+        diagnostics::error("could not determine config path (HOME/XDG_CONFIG_HOME not set).");
+        return 1;
+    };
+    if path.exists() && !force {
+        diagnostics::error(format!(
+            "{} already exists; pass --force to overwrite it.",
+            path.display()
+        ));
+        return 1;
+    }
+    let answers = match preset {
+        Some(preset) => Ok(Answers::from_preset(preset)),
+        None => prompt(std::io::stdin().lock()),
+    };
+    let text = match answers.and_then(|answers| render(&answers)) {
+        Ok(text) => text,
+        Err(err) => {
+            diagnostics::error(err);
+            return 2;
+        }
+    };
+    let written = path
+        .parent()
+        .map_or(Ok(()), std::fs::create_dir_all)
+        .and_then(|()| std::fs::write(&path, &text));
+    if let Err(err) = written {
+        diagnostics::error(format!("failed to write {}: {err}", path.display()));
+        return 1;
+    }
+    println!("Wrote a starter config to {}.", path.display());
+    0
+}
@@ -0,0 +1,240 @@
+//! `--jail`: run the apply in an unprivileged user namespace (Linux).
+//!
+//! The invocation re-executes itself under `unshare --user --map-root-user
+//! --mount` with every mount remounted read-only except the working
+//! directory and apply_patch's own record locations (the state directory,
+//! the audit log's and the feedback file's directories), so a path-handling
+//! bug in the applier (`..`, a link it shouldn't follow) can't write
+//! anywhere else. It is defense in depth, not a sandbox: reads are not
+//! restricted. Without Linux, `unshare` or unprivileged user namespaces, the
+//! run warns and goes ahead unjailed. A run that is told it is already in
+//! the jail checks its mounts before believing it.
+
+use crate::Config;
+use crate::diagnostics;
+use crate::exit;
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::path::PathBuf;
+use std::process::Command;
+use std::process::Stdio;
+
+/// Set in the jailed child so it doesn't jail itself again. Anyone can set
+/// it, so the child checks its mounts (see [`confined`]) before trusting it.
+const INSIDE_ENV: &str = "APPLY_PATCH_IN_JAIL";
+/// Newline-separated directories that stay writable.
+const WRITABLE_ENV: &str = "APPLY_PATCH_JAIL_WRITABLE";
+/// Exit code of the setup script when the mounts couldn't be made.
+const SETUP_FAILED: i32 = 125;
+/// Exit code of the setup script when a mount couldn't be made read-only.
+/// The run fails rather than go ahead in a jail that doesn't confine it.
+const REMOUNT_FAILED: i32 = 124;
+
+/// Binds the writable directories onto themselves, remounts everything
+/// else read-only and runs the command. The directory is entered again
+/// after binding so the working directory is the writable mount.
+const SCRIPT: &str = r#"
+IFS='
+'
+for dir in $APPLY_PATCH_JAIL_WRITABLE; do
+  mount --bind "$dir" "$dir" || exit 125
+done
+while IFS=' ' read -r _ point _; do
+  point=$(printf '%b' "$point")
+  case "
+$APPLY_PATCH_JAIL_WRITABLE
+" in
+    *"
+$point
+"*) continue ;;
+  esac
+  if ! mount -o remount,bind,ro "$point" 2>/dev/null; then
+    printf 'Error: --jail: could not make %s read-only; nothing was changed.\n' "$point" >&2
+    exit 124
+  fi
+done < /proc/self/mounts
+cd "$(pwd -P)" || exit 125
+exec "$@"
+"#;
+
+pub(crate) fn inside() -> bool {
+    std::env::var_os(INSIDE_ENV).is_some_and(|v| v == "1")
+}
+
+/// Whether the mounts confine this run the way the jail would: every mount
+/// other than the writable directories, worked out again from `cfg` rather
+/// than taken from the environment, is read-only. This is what makes
+/// `$APPLY_PATCH_IN_JAIL` safe to trust; a caller can set the variable but
+/// not the mount table.
+pub(crate) fn confined(cfg: &Config) -> Result<(), String> {
+    let writable = writable_dirs(cfg);
+    let mounts = std::fs::read_to_string("/proc/self/mounts")
+        .map_err(|err| format!("cannot read /proc/self/mounts: {err}"))?;
+    // A point mounted over more than once is listed once per mount; only the
+    // last, the one on top, can be reached.
+    let mut top: BTreeMap<String, &str> = BTreeMap::new();
+    for line in mounts.lines() {
+        let mut fields = line.split(' ');
+        if let (Some(point), Some(options)) = (fields.nth(1), fields.nth(1)) {
+            top.insert(unescape(point), options);
+        }
+    }
+    for (point, options) in top {
+        if writable.iter().any(|dir| *dir == Path::new(&point)) {
+            continue;
+        }
+        if !options.split(',').any(|option| option == "ro") {
+            return Err(format!(
+                "{INSIDE_ENV} is set but {point} is writable, so this is not a jail"
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// A mount point from `/proc/self/mounts`, where space, tab, newline and
+/// backslash are written as octal escapes.
+fn unescape(field: &str) -> String {
+    let mut out = String::with_capacity(field.len());
+    let mut rest = field;
+    while let Some(at) = rest.find('\\') {
+        out.push_str(&rest[..at]);
+        let code = rest
+            .get(at + 1..at + 4)
+            .and_then(|digits| u8::from_str_radix(digits, 8).ok());
+        match code {
+            Some(byte) => {
+                out.push(char::from(byte));
+                rest = &rest[at + 4..];
+            }
+            None => {
+                out.push('\\');
+                rest = &rest[at + 1..];
+            }
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+fn unjailed(why: &str) -> Option<i32> {
+    diagnostics::warning(format!("--jail: {why}; applying without a jail."));
+    None
+}
+
+/// The directories the jailed run may write to, created if missing so
+/// they can be bound.
+fn writable_dirs(cfg: &Config) -> Vec<PathBuf> {
+    let mut dirs: Vec<PathBuf> = Vec::new();
+    let mut add = |dir: Option<PathBuf>| {
+        let Some(dir) = dir.filter(|d| !d.as_os_str().is_empty()) else {
+            return;
+        };
+        let _ = std::fs::create_dir_all(&dir);
+        if let Ok(dir) = dir.canonicalize()
+            && !dirs.contains(&dir)
+        {
+            dirs.push(dir);
+        }
+    };
+    add(std::env::current_dir().ok());
+    add(crate::state::dir(cfg.state_dir.as_deref()));
+    let parent = |path: &Path| path.parent().map(Path::to_path_buf);
+    add(cfg.audit_log.as_deref().and_then(parent));
+    // A tenant only changes the feedback file's name, not its directory.
+    add(crate::feedback::feedback_path(None)
+        .as_deref()
+        .and_then(parent));
+    dirs
+}
+
+/// `args` with the value of `--cwd` made absolute, since the jailed run
+/// starts in that directory already. `$APPLY_PATCH_CWD` is cleared for the
+/// child for the same reason.
+fn child_args(args: &[String], cwd: &Path) -> Vec<String> {
+    let mut out: Vec<String> = Vec::with_capacity(args.len());
+    let mut rest = args.iter();
+    while let Some(arg) = rest.next() {
+        out.push(arg.clone());
+        if arg == "--" {
+            out.extend(rest.by_ref().cloned());
+            break;
+        }
+        if let Some(spec) = crate::cli::flag(arg).filter(|spec| spec.value.is_some())
+            && let Some(value) = rest.next()
+        {
+            out.push(if spec.name == "--cwd" {
+                cwd.display().to_string()
+            } else {
+                value.clone()
+            });
+        }
+    }
+    out
+}
+
+/// Runs this invocation (`args`) again inside the jail and returns its exit
+/// code, or `None` (after a warning) when the jail isn't available and the
+/// caller should go on unjailed.
+pub(crate) fn run(cfg: &Config, args: &[String]) -> Option<i32> {
+    if !cfg!(target_os = "linux") {
+        return unjailed("user namespaces are only available on Linux");
+    }
+    let probe = Command::new("unshare")
+        .args(["--user", "--map-root-user", "--mount", "true"])
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .output();
+    match probe {
+        Ok(output) if output.status.success() => {}
+        Ok(output) => {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            let detail = stderr.lines().next().unwrap_or("unshare failed").trim();
+            return unjailed(&format!(
+                "unprivileged user namespaces are not available ({detail})"
+            ));
+        }
+        Err(err) => return unjailed(&format!("failed to run unshare: {err}")),
+    }
+    let exe = match std::env::current_exe() {
+        Ok(exe) => exe,
+        Err(err) => return unjailed(&format!("cannot locate apply_patch: {err}")),
+    };
+    let cwd = match std::env::current_dir() {
+        Ok(cwd) => cwd,
+        Err(err) => return unjailed(&format!("cannot resolve the working directory: {err}")),
+    };
+    let writable = writable_dirs(cfg)
+        .iter()
+        .map(|dir| dir.display().to_string())
+        .collect::<Vec<_>>()
+        .join("\n");
+    let status = Command::new("unshare")
+        .args([
+            "--user",
+            "--map-root-user",
+            "--mount",
+            "--propagation",
+            "private",
+            "--",
+            "sh",
+            "-c",
+            SCRIPT,
+            "sh",
+        ])
+        .arg(exe)
+        .args(child_args(args, &cwd))
+        .env_remove(crate::CWD_ENV)
+        .env(INSIDE_ENV, "1")
+        .env(WRITABLE_ENV, writable)
+        .status();
+    match status {
+        Ok(status) if status.code() == Some(SETUP_FAILED) => {
+            unjailed("the jail's mounts could not be set up")
+        }
+        Ok(status) if status.code() == Some(REMOUNT_FAILED) => Some(exit::Exit::Failed.code()),
+        Ok(status) => Some(status.code().unwrap_or(1)),
+        Err(err) => unjailed(&format!("failed to run unshare: {err}")),
+    }
+}
@@ -0,0 +1,29 @@
+//! Shared helpers for the JSON-lines files we append records to.
+
+use serde::Serialize;
+use std::io::Write;
+use std::path::Path;
+
+/// Seconds since the Unix epoch, or 0 if the clock is before it.
+pub(crate) fn timestamp() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+/// Appends `record` as a single JSON line, creating parent directories.
+pub(crate) fn append(path: &Path, record: &impl Serialize) -> std::io::Result<()> {
+    if let Some(parent) = path.parent()
+        && !parent.as_os_str().is_empty()
+    {
+        std::fs::create_dir_all(parent)?;
+    }
+    let mut line = serde_json::to_vec(record).map_err(std::io::Error::other)?;
+    line.push(b'\n');
+    let mut file = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)?;
+    file.write_all(&line)
+}
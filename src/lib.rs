@@ -0,0 +1,2390 @@
+//! The applier as a library. The `apply_patch` binary is [`main`]; other
+//! runtimes embed the same run through the C ABI in `ffi` (this crate is
+//! also built as a `cdylib`, with the declarations in
+//! `include/apply_patch.h`); and the patch parser and renderer are public
+//! for `cargo fuzz` targets (see `fuzz/`) and `apply_patch roundtrip`.
+
+use serde::Deserialize;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::io::Read;
+use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
+
+mod archive;
+mod audit;
+mod audit_collector;
+mod batch;
+mod branch;
+mod branchrules;
+mod bundle;
+mod capture;
+mod check;
+mod clean;
+mod cli;
+mod codeowners;
+mod compare;
+mod concise;
+mod config_cache;
+mod conflicts;
+mod deletes;
+mod diagnostics;
+mod digest;
+mod dirops;
+mod diskspace;
+mod docs;
+mod drop_paths;
+mod editline;
+mod editor;
+mod events;
+mod exit;
+mod expected;
+mod failures;
+mod feedback;
+mod ffi;
+mod fingerprint;
+mod formatters;
+mod forward;
+mod freshness;
+mod gitapply;
+mod gitscope;
+mod glob;
+mod grammar;
+mod hashline;
+mod hermetic;
+mod inflate;
+mod init;
+mod jail;
+mod jsonl;
+mod limits;
+mod lint;
+mod locale;
+mod makepatch;
+mod migrate;
+mod mirror;
+mod newdirs;
+mod newline;
+mod notify;
+mod ownstate;
+mod partial;
+mod patch;
+mod patchcache;
+mod policy;
+mod preview;
+mod progress;
+mod pullrequest;
+mod reanchor;
+mod reason;
+mod rebase;
+mod redact;
+mod regex;
+mod replace;
+mod resources;
+mod risk;
+mod roundtrip;
+mod scaffold;
+mod serve;
+mod shim;
+mod simulate;
+mod squash;
+mod staging;
+mod stash;
+mod state;
+mod stats;
+mod streaming;
+mod strict;
+mod symlinks;
+mod template;
+mod tenant;
+mod toolcall;
+mod trailers;
+mod truncation;
+mod verify;
+mod wal;
+mod watch;
+mod whitespace;
+mod why;
+mod writes;
+mod yaml;
+
+pub use ffi::apply_patch_free;
+pub use ffi::apply_patch_run;
+pub use roundtrip::ParsedPatch;
+pub use roundtrip::check_roundtrip;
+pub use roundtrip::parse_patch;
+pub use roundtrip::render_patch;
+
+const DEFAULT_REFUSE_MESSAGE: &str = r#"NOTE TO LLM:
+You just ran `apply_patch` as a shell command, not as a model-native editing tool.
+This environment is configured to refuse shell-based patching, so nothing was changed.
+
+Use your native editing tool (your built-in patch/editor tool) to apply this change now, and do not run `apply_patch` in the shell for future changes."#;
+
+const DEFAULT_WARN_MESSAGE: &str = r#"NOTE TO LLM:
+The patch was applied by a shell `apply_patch` wrapper.
+For future changes, use your native editing tool instead of running `apply_patch` in the shell."#;
+
+/// Default for `--cwd`.
+pub(crate) const CWD_ENV: &str = "APPLY_PATCH_CWD";
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Mode {
+    #[default]
+    Apply,
+    Refuse,
+    Warn,
+    /// Send the patch to `forward.url` or `forward.command` instead of
+    /// applying it.
+    Forward,
+}
+
+impl Mode {
+    fn as_str(self) -> &'static str {
+        match self {
+            Mode::Apply => "apply",
+            Mode::Refuse => "refuse",
+            Mode::Warn => "warn",
+            Mode::Forward => "forward",
+        }
+    }
+}
+
+/// What to print after the refuse banner so the model can redo the change
+/// with its native tool.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum RefuseEcho {
+    #[default]
+    None,
+    /// One line per file with its line counts.
+    Summary,
+    /// The whole patch, normalized.
+    Full,
+}
+
+impl RefuseEcho {
+    fn as_str(self) -> &'static str {
+        match self {
+            RefuseEcho::None => "none",
+            RefuseEcho::Summary => "summary",
+            RefuseEcho::Full => "full",
+        }
+    }
+}
+
+/// Outcome of a patch invocation, as recorded in feedback and audit files.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Decision {
+    Applied,
+    /// `--partial` only: some file sections applied, others failed.
+    Partial,
+    Refused,
+    Failed,
+    /// `--dry-run` or `readonly`: the patch would apply, but nothing was
+    /// written.
+    #[serde(rename = "dry_run")]
+    DryRun,
+    /// `forward` mode: the endpoint accepted the patch.
+    Forwarded,
+}
+
+/// A decision together with the exit code it maps to.
+#[derive(Debug, Clone, Copy)]
+struct Outcome {
+    decision: Decision,
+    code: i32,
+}
+
+impl Outcome {
+    fn new(decision: Decision, exit: exit::Exit) -> Self {
+        Self {
+            decision,
+            code: exit.code(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Config {
+    /// Schema version; see `migrate`.
+    #[serde(default)]
+    version: u32,
+    #[serde(default)]
+    mode: Mode,
+    #[serde(default)]
+    refuse_message: Option<String>,
+    #[serde(default)]
+    warn_message: Option<String>,
+    /// Warn banners rotated through in a session, replacing `warn_message`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    warn_messages: Vec<String>,
+    #[serde(default)]
+    warn_rotation: stats::WarnRotation,
+    #[serde(default)]
+    apply_message: Option<String>,
+    /// Replaces the grammar guidance printed after a parse error.
+    #[serde(default)]
+    parse_error_message: Option<String>,
+    /// Messages per locale, replacing the ones above (see `locale`).
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    locales: BTreeMap<String, locale::LocaleMessages>,
+    #[serde(default)]
+    output: locale::OutputConfig,
+    #[serde(default)]
+    refuse_echo: RefuseEcho,
+    /// Print `REFUSED: <code> <detail>` before the refuse banner.
+    #[serde(default)]
+    refusal_reason_line: bool,
+    /// Run every patch as `--dry-run`.
+    #[serde(default)]
+    readonly: bool,
+    /// Globs for file sections removed from patches before anything else.
+    #[serde(default)]
+    drop_paths: Vec<String>,
+    /// Remove update hunks that only change whitespace (see `whitespace`).
+    #[serde(default)]
+    skip_whitespace_only_hunks: bool,
+    #[serde(default)]
+    policies: Vec<policy::PolicyRule>,
+    #[serde(default)]
+    escalate: policy::EscalateConfig,
+    #[serde(default)]
+    editor_guard: editor::EditorGuardConfig,
+    #[serde(default)]
+    git_boundaries: gitscope::GitBoundariesConfig,
+    #[serde(default)]
+    require_clean: clean::RequireCleanConfig,
+    #[serde(default)]
+    branch_rules: Vec<branchrules::BranchRule>,
+    #[serde(default)]
+    codeowners: codeowners::CodeownersConfig,
+    #[serde(default)]
+    lint: lint::LintConfig,
+    #[serde(default)]
+    symlinks: symlinks::SymlinksConfig,
+    #[serde(default)]
+    new_dirs: newdirs::NewDirsConfig,
+    /// Allow `*** Add Directory:` and `*** Delete Directory:` sections.
+    #[serde(default)]
+    allow_dir_ops: bool,
+    /// Refuse directory deletes removing more files than this.
+    #[serde(default = "dirops::default_max_dir_delete_files")]
+    max_dir_delete_files: usize,
+    #[serde(default)]
+    replace_file: replace::ReplaceFileConfig,
+    #[serde(default)]
+    failure_guard: failures::FailureGuardConfig,
+    #[serde(default)]
+    notify: notify::NotifyConfig,
+    #[serde(default)]
+    forward: forward::ForwardConfig,
+    #[serde(default)]
+    archives: archive::ArchiveLimits,
+    #[serde(default)]
+    reanchor: reanchor::ReanchorConfig,
+    #[serde(default)]
+    write_strategy: writes::WriteStrategy,
+    /// Where temporary files renamed over targets are written (see
+    /// `writes`).
+    #[serde(default)]
+    staging_dir: Option<PathBuf>,
+    #[serde(default)]
+    concise: concise::ConciseConfig,
+    #[serde(default)]
+    final_newline: newline::FinalNewline,
+    #[serde(default)]
+    fallback: gitapply::Fallback,
+    #[serde(default)]
+    limits: limits::Limits,
+    #[serde(default)]
+    pull_request: pullrequest::PullRequestConfig,
+    #[serde(default)]
+    redact: redact::RedactConfig,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    format_on_apply: formatters::FormatOnApply,
+    /// Roll back a patch if another process writes to its files meanwhile.
+    #[serde(default)]
+    conflict_guard: bool,
+    /// Log each apply's intent before writing, for `apply_patch wal show`.
+    #[serde(default)]
+    write_ahead_log: bool,
+    /// Read back written files and compare them with the simulated result.
+    #[serde(default = "default_verify_writes")]
+    verify_writes: bool,
+    #[serde(default)]
+    disk_space: diskspace::DiskSpaceConfig,
+    /// Second tree that successful applies are replayed into.
+    #[serde(default)]
+    mirror_dir: Option<PathBuf>,
+    /// Cache applied patches by digest to replay them without matching.
+    #[serde(default)]
+    patch_cache: bool,
+    /// Directory for state kept between runs (see `state`).
+    #[serde(default)]
+    state_dir: Option<PathBuf>,
+    #[serde(default)]
+    audit_log: Option<PathBuf>,
+    #[serde(default)]
+    audit: audit::AuditConfig,
+    /// Per-tenant overrides, keyed by tenant name (see `tenant`).
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    tenants: BTreeMap<String, serde_json::Value>,
+    /// The file this config was read from, or would be read from if it
+    /// existed; patches may not touch it (see `ownstate`).
+    #[serde(skip)]
+    source: Option<PathBuf>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            version: migrate::CONFIG_VERSION,
+            mode: Mode::Apply,
+            refuse_message: None,
+            warn_message: None,
+            warn_messages: Vec::new(),
+            warn_rotation: stats::WarnRotation::default(),
+            apply_message: None,
+            parse_error_message: None,
+            locales: BTreeMap::new(),
+            output: locale::OutputConfig::default(),
+            refuse_echo: RefuseEcho::None,
+            refusal_reason_line: false,
+            readonly: false,
+            drop_paths: Vec::new(),
+            skip_whitespace_only_hunks: false,
+            policies: Vec::new(),
+            escalate: policy::EscalateConfig::default(),
+            editor_guard: editor::EditorGuardConfig::default(),
+            git_boundaries: gitscope::GitBoundariesConfig::default(),
+            require_clean: clean::RequireCleanConfig::default(),
+            branch_rules: Vec::new(),
+            codeowners: codeowners::CodeownersConfig::default(),
+            lint: lint::LintConfig::default(),
+            symlinks: symlinks::SymlinksConfig::default(),
+            new_dirs: newdirs::NewDirsConfig::default(),
+            allow_dir_ops: false,
+            max_dir_delete_files: dirops::default_max_dir_delete_files(),
+            replace_file: replace::ReplaceFileConfig::default(),
+            failure_guard: failures::FailureGuardConfig::default(),
+            notify: notify::NotifyConfig::default(),
+            forward: forward::ForwardConfig::default(),
+            archives: archive::ArchiveLimits::default(),
+            reanchor: reanchor::ReanchorConfig::default(),
+            write_strategy: writes::WriteStrategy::InPlace,
+            staging_dir: None,
+            concise: concise::ConciseConfig::default(),
+            final_newline: newline::FinalNewline::Preserve,
+            fallback: gitapply::Fallback::None,
+            limits: limits::Limits::default(),
+            pull_request: pullrequest::PullRequestConfig::default(),
+            redact: redact::RedactConfig::default(),
+            format_on_apply: BTreeMap::new(),
+            conflict_guard: false,
+            write_ahead_log: false,
+            verify_writes: true,
+            disk_space: diskspace::DiskSpaceConfig::default(),
+            mirror_dir: None,
+            patch_cache: false,
+            state_dir: None,
+            audit_log: None,
+            audit: audit::AuditConfig::default(),
+            tenants: BTreeMap::new(),
+            source: None,
+        }
+    }
+}
+
+fn default_verify_writes() -> bool {
+    true
+}
+
+fn config_path() -> Option<PathBuf> {
+    if hermetic::enabled() {
+        return None;
+    }
+    if let Some(path) = std::env::var_os("APPLY_PATCH_CONFIG") {
+        return Some(PathBuf::from(path));
+    }
+    let base = if let Some(xdg) = std::env::var_os("XDG_CONFIG_HOME") {
+        PathBuf::from(xdg)
+    } else {
+        PathBuf::from(std::env::var_os("HOME")?)
+    };
+    Some(base.join(".apply_patch").join("config.json"))
+}
+
+/// Loads the config, upgrading files written by older releases in place.
+/// A missing file is the default config; anything unreadable is an error
+/// rather than being silently replaced by defaults.
+fn load_config(path: &Path) -> Result<Config, String> {
+    let bytes = match std::fs::read(path) {
+        Ok(b) => b,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(Config::default()),
+        Err(err) => return Err(format!("failed to read config {}: {err}", path.display())),
+    };
+    let invalid = |err: String| format!("invalid config {}: {err}", path.display());
+    let mut value: serde_json::Value =
+        serde_json::from_slice(&bytes).map_err(|err| invalid(err.to_string()))?;
+    // Older files are read as upgraded; `migrate-config` rewrites them.
+    migrate::upgrade(&mut value).map_err(invalid)?;
+    serde_json::from_value(value).map_err(|err| invalid(err.to_string()))
+}
+
+fn save_config(path: &Path, cfg: &Config) -> std::io::Result<()> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    let tmp = path.with_extension("json.tmp");
+    let data = serde_json::to_vec_pretty(cfg).unwrap_or_else(|_| b"{}".to_vec());
+    std::fs::write(&tmp, data)?;
+    if path.exists() {
+        let _ = std::fs::remove_file(path);
+    }
+    std::fs::rename(tmp, path)?;
+    Ok(())
+}
+
+fn parse_mode(s: &str) -> Option<Mode> {
+    match s {
+        "apply" => Some(Mode::Apply),
+        "refuse" => Some(Mode::Refuse),
+        "warn" => Some(Mode::Warn),
+        "forward" => Some(Mode::Forward),
+        _ => None,
+    }
+}
+
+/// Flags that only affect the current invocation and are never persisted.
+#[derive(Debug, Default)]
+struct RunOptions {
+    force_delete: bool,
+    partial: bool,
+    residual: Option<PathBuf>,
+    tenant: Option<String>,
+    watch: Option<PathBuf>,
+    to_branch: Option<String>,
+    /// `--open-pr`: push the `--to-branch` branch and open a pull request.
+    open_pr: bool,
+    cwd: Option<PathBuf>,
+    format: progress::OutputFormat,
+    base: Option<String>,
+    rebased_out: Option<PathBuf>,
+    /// `--max-depth`, overriding `new_dirs.max_depth`.
+    max_depth: Option<usize>,
+    input: toolcall::InputFormat,
+    /// `metadata` from a tool-call payload.
+    metadata: BTreeMap<String, serde_json::Value>,
+    /// `--dry-run`, or `readonly` in the config.
+    dry_run: bool,
+    /// `--archive`: patch the files in this archive instead of the tree.
+    archive: Option<PathBuf>,
+    archive_out: Option<PathBuf>,
+    /// `--event-fd`: where lifecycle events go.
+    events: Option<events::EventStream>,
+    /// `--stash-before`: stash the targets in git before writing.
+    stash_before: bool,
+    /// `--deny-warnings`: fail runs that printed warnings.
+    deny_warnings: bool,
+    /// `--check`: report whether each file section applies, and stop.
+    check: bool,
+    /// `--read-manifest`: when each target was last read.
+    read_manifest: Option<PathBuf>,
+    /// `--patch-fd`: read the patch from this file descriptor, not stdin.
+    patch_fd: Option<u32>,
+    /// `--concise`: one-line summaries and clipped messages.
+    concise: bool,
+    /// `--strict`: refuse patches whose envelope isn't canonical.
+    strict: bool,
+    /// `--jail`: write only inside the working directory (Linux).
+    jail: bool,
+    /// `--timings`: print the run's resource usage on stderr.
+    timings: bool,
+    /// `--3way`: merge a patch the built-in applier can't place, with the
+    /// git index as the base.
+    three_way: bool,
+    /// `--expected-files`: the only paths the patch may touch.
+    expected_files: Option<Vec<String>>,
+    /// `stage` / `commit-staged`: what the apply step does instead of
+    /// applying the patch.
+    staging: Option<staging::Step>,
+}
+
+/// What the command line asked for once config flags have been handled.
+enum Invocation {
+    /// A config or help command already ran; exit with this code.
+    Exit(i32),
+    /// Apply (or refuse) the patch from stdin or the positional argument.
+    Patch {
+        positional: Vec<String>,
+        options: Box<RunOptions>,
+    },
+}
+
+fn print_help(mut out: impl Write) {
+    let _ = writeln!(out, "{}", docs::help_text());
+}
+
+fn run_config_command(args: &[String]) -> Invocation {
+    let mut show = false;
+    let mut options = RunOptions::default();
+    let mut mode: Option<Mode> = None;
+    let mut refuse_message: Option<Option<String>> = None;
+    let mut warn_message: Option<Option<String>> = None;
+    let mut apply_message: Option<Option<String>> = None;
+    let mut positional: Vec<String> = Vec::new();
+    let mut event_fd: Option<String> = None;
+
+    let mut i = 0;
+    while i < args.len() {
+        let arg = args[i].as_str();
+        if arg == "--" {
+            // Everything after `--` is positional, even if it looks like a flag.
+            positional.extend(args[i + 1..].iter().cloned());
+            break;
+        }
+        let Some(spec) = cli::flag(arg) else {
+            if arg.starts_with('-') {
+                diagnostics::error(format!("unknown option: {arg}"));
+                return Invocation::Exit(2);
+            }
+            positional.push(arg.to_string());
+            i += 1;
+            continue;
+        };
+        let value = match spec.value {
+            Some(_) => match args.get(i + 1) {
+                Some(val) => val.as_str(),
+                None => {
+                    diagnostics::error(format!("{} requires a value.", spec.name));
+                    return Invocation::Exit(2);
+                }
+            },
+            None => "",
+        };
+        i += if spec.value.is_some() { 2 } else { 1 };
+
+        match spec.name {
+            "--show-config" => show = true,
+            "--mode" => {
+                let Some(parsed) = parse_mode(value) else {
+                    diagnostics::error(format!("invalid --mode value: {value}"));
+                    return Invocation::Exit(2);
+                };
+                mode = Some(parsed);
+            }
+            "--apply" => mode = Some(Mode::Apply),
+            "--refuse" => mode = Some(Mode::Refuse),
+            "--warn" => mode = Some(Mode::Warn),
+            "--set-refuse-message" => refuse_message = Some(Some(value.to_string())),
+            "--clear-refuse-message" => refuse_message = Some(None),
+            "--set-warn-message" => warn_message = Some(Some(value.to_string())),
+            "--clear-warn-message" => warn_message = Some(None),
+            "--set-apply-message" => apply_message = Some(Some(value.to_string())),
+            "--clear-apply-message" => apply_message = Some(None),
+            "--force-delete" => options.force_delete = true,
+            "--dry-run" => options.dry_run = true,
+            "--check" => options.check = true,
+            "--partial" => options.partial = true,
+            "--residual" => options.residual = Some(PathBuf::from(value)),
+            "--tenant" => options.tenant = Some(value.to_string()),
+            "--watch" => options.watch = Some(PathBuf::from(value)),
+            "--to-branch" => options.to_branch = Some(value.to_string()),
+            "--cwd" => options.cwd = Some(PathBuf::from(value)),
+            "--base" => options.base = Some(value.to_string()),
+            "--rebased-out" => options.rebased_out = Some(PathBuf::from(value)),
+            "--archive" => options.archive = Some(PathBuf::from(value)),
+            "--archive-out" => options.archive_out = Some(PathBuf::from(value)),
+            "--read-manifest" => options.read_manifest = Some(PathBuf::from(value)),
+            "--event-fd" => event_fd = Some(value.to_string()),
+            "--stash-before" => options.stash_before = true,
+            "--deny-warnings" => options.deny_warnings = true,
+            "--concise" => options.concise = true,
+            "--strict" => options.strict = true,
+            "--jail" => options.jail = true,
+            "--timings" => options.timings = true,
+            "--open-pr" => options.open_pr = true,
+            "--3way" => options.three_way = true,
+            "--no-config" => hermetic::enable(),
+            "--patch-fd" => {
+                let Ok(fd) = value.parse() else {
+                    diagnostics::error(format!("invalid patch file descriptor: {value}"));
+                    return Invocation::Exit(2);
+                };
+                options.patch_fd = Some(fd);
+            }
+            "--expected-files" => match expected::parse(value) {
+                Ok(paths) => options.expected_files = Some(paths),
+                Err(err) => {
+                    diagnostics::error(err);
+                    return Invocation::Exit(2);
+                }
+            },
+            "--max-depth" => {
+                let Ok(depth) = value.parse() else {
+                    diagnostics::error(format!("invalid --max-depth value: {value}"));
+                    return Invocation::Exit(2);
+                };
+                options.max_depth = Some(depth);
+            }
+            "--input" => {
+                let Some(input) = toolcall::InputFormat::parse(value) else {
+                    diagnostics::error(format!("invalid --input value: {value}"));
+                    return Invocation::Exit(2);
+                };
+                options.input = input;
+            }
+            "--format" => {
+                let Some(format) = progress::OutputFormat::parse(value) else {
+                    diagnostics::error(format!("invalid --format value: {value}"));
+                    return Invocation::Exit(2);
+                };
+                options.format = format;
+            }
+            "--help" => {
+                print_help(std::io::stdout());
+                return Invocation::Exit(0);
+            }
+            "--explain-exit-codes" => {
+                println!("{}", exit::explain());
+                return Invocation::Exit(0);
+            }
+            _ => {}
+        }
+    }
+
+    let has_config_flags = show
+        || mode.is_some()
+        || refuse_message.is_some()
+        || warn_message.is_some()
+        || apply_message.is_some();
+
+    if hermetic::enabled() && has_config_flags {
+        diagnostics::error("--no-config cannot be combined with configuration flags.");
+        return Invocation::Exit(2);
+    }
+
+    options.tenant = match tenant::resolve(options.tenant.take()) {
+        Ok(tenant) => tenant,
+        Err(msg) => {
+            eprintln!("{msg}");
+            return Invocation::Exit(2);
+        }
+    };
+
+    if options.rebased_out.is_some() && options.base.is_none() {
+        diagnostics::error("--rebased-out requires --base.");
+        return Invocation::Exit(2);
+    }
+    if options.open_pr && options.to_branch.is_none() {
+        diagnostics::error("--open-pr requires --to-branch.");
+        return Invocation::Exit(2);
+    }
+    if options.archive_out.is_some() && options.archive.is_none() {
+        diagnostics::error("--archive-out requires --archive.");
+        return Invocation::Exit(2);
+    }
+    if options.archive.is_some() {
+        let conflicting = [
+            ("--watch", options.watch.is_some()),
+            ("--to-branch", options.to_branch.is_some()),
+            ("--base", options.base.is_some()),
+            ("--partial", options.partial),
+            ("--stash-before", options.stash_before),
+        ];
+        if let Some((flag, _)) = conflicting.iter().find(|(_, set)| *set) {
+            diagnostics::error(format!("--archive cannot be combined with {flag}."));
+            return Invocation::Exit(2);
+        }
+    }
+    if options.check {
+        let conflicting = [
+            ("--watch", options.watch.is_some()),
+            ("--to-branch", options.to_branch.is_some()),
+            ("--archive", options.archive.is_some()),
+            ("--partial", options.partial),
+            ("--stash-before", options.stash_before),
+        ];
+        if let Some((flag, _)) = conflicting.iter().find(|(_, set)| *set) {
+            diagnostics::error(format!("--check cannot be combined with {flag}."));
+            return Invocation::Exit(2);
+        }
+    } else if options.format == progress::OutputFormat::Json {
+        diagnostics::error("--format json requires --check.");
+        return Invocation::Exit(2);
+    }
+    if options.patch_fd.is_some() {
+        if !positional.is_empty() {
+            diagnostics::error("--patch-fd cannot be combined with a PATCH argument.");
+            return Invocation::Exit(2);
+        }
+        if options.watch.is_some() {
+            diagnostics::error("--patch-fd cannot be combined with --watch.");
+            return Invocation::Exit(2);
+        }
+    }
+
+    if options.cwd.is_none() && !hermetic::enabled() {
+        options.cwd = std::env::var_os(CWD_ENV)
+            .filter(|v| !v.is_empty())
+            .map(PathBuf::from);
+    }
+    if let Some(fd) = event_fd.or_else(|| {
+        std::env::var(events::EVENT_FD_ENV)
+            .ok()
+            .filter(|v| !v.is_empty() && !hermetic::enabled())
+    }) {
+        match events::EventStream::open(&fd) {
+            Ok(stream) => options.events = Some(stream),
+            Err(msg) => {
+                diagnostics::error(msg);
+                return Invocation::Exit(2);
+            }
+        }
+    }
+
+    if !has_config_flags {
+        return Invocation::Patch {
+            positional,
+            options: Box::new(options),
+        };
+    }
+
+    if !positional.is_empty() {
+        diagnostics::error("configuration flags cannot be combined with a PATCH argument.");
+        return Invocation::Exit(2);
+    }
+
+    let Some(path) = config_path() else {
+        diagnostics::error("could not determine config path (HOME/XDG_CONFIG_HOME not set).");
+        return Invocation::Exit(1);
+    };
+    let mut cfg = match load_config(&path) {
+        Ok(cfg) => cfg,
+        Err(err) => {
+            diagnostics::error(err);
+            return Invocation::Exit(exit::Exit::ConfigError.code());
+        }
+    };
+    let mode_changed = mode.is_some();
+    let refuse_message_changed = refuse_message.is_some();
+    let warn_message_changed = warn_message.is_some();
+    let apply_message_changed = apply_message.is_some();
+    if let Some(name) = &options.tenant {
+        tenant::set_overrides(
+            &mut cfg,
+            name,
+            [
+                ("mode", mode.map(|m| Some(m.as_str().to_string()))),
+                ("refuse_message", refuse_message),
+                ("warn_message", warn_message),
+                ("apply_message", apply_message),
+            ],
+        );
+    } else {
+        if let Some(m) = mode {
+            cfg.mode = m;
+        }
+        if let Some(val) = refuse_message {
+            cfg.refuse_message = val;
+        }
+        if let Some(val) = warn_message {
+            cfg.warn_message = val;
+        }
+        if let Some(val) = apply_message {
+            cfg.apply_message = val;
+        }
+    }
+
+    if (mode_changed || refuse_message_changed || warn_message_changed || apply_message_changed)
+        && let Err(err) = save_config(&path, &cfg)
+    {
+        diagnostics::error(format!("failed to write config: {err}"));
+        return Invocation::Exit(1);
+    }
+
+    if show {
+        if let Some(name) = &options.tenant {
+            cfg = match tenant::effective(cfg, name) {
+                Ok(cfg) => cfg,
+                Err(err) => {
+                    diagnostics::error(format!("invalid config {}: {err}", path.display()));
+                    return Invocation::Exit(exit::Exit::ConfigError.code());
+                }
+            };
+        }
+        let _ = writeln!(std::io::stdout(), "Config file: {}", path.display());
+        if let Some(name) = &options.tenant {
+            let _ = writeln!(std::io::stdout(), "tenant: {name}");
+        }
+        let _ = writeln!(std::io::stdout(), "mode: {}", cfg.mode.as_str());
+        if let Some(locale) = locale::resolve(&cfg.output)
+            && let Some(key) = locale::matching_key(&cfg, &locale)
+        {
+            let _ = writeln!(
+                std::io::stdout(),
+                "locale: {locale} (messages from locales.{key})"
+            );
+        }
+        locale::apply(&mut cfg);
+        let _ = writeln!(
+            std::io::stdout(),
+            "refuse_message: {}",
+            if cfg.refuse_message.is_some() {
+                "custom"
+            } else {
+                "default"
+            }
+        );
+        let _ = writeln!(
+            std::io::stdout(),
+            "warn_message: {}",
+            if cfg.warn_message.is_some() {
+                "custom"
+            } else {
+                "default"
+            }
+        );
+        if !cfg.warn_messages.is_empty() {
+            let _ = writeln!(
+                std::io::stdout(),
+                "warn_messages: {} ({})",
+                cfg.warn_messages.len(),
+                cfg.warn_rotation.as_str()
+            );
+        }
+        let _ = writeln!(
+            std::io::stdout(),
+            "apply_message: {}",
+            if cfg.apply_message.is_some() {
+                "custom"
+            } else {
+                "none"
+            }
+        );
+        if cfg.refuse_echo != RefuseEcho::None {
+            let _ = writeln!(
+                std::io::stdout(),
+                "refuse_echo: {}",
+                cfg.refuse_echo.as_str()
+            );
+        }
+        if cfg.readonly {
+            let _ = writeln!(std::io::stdout(), "readonly: on");
+        }
+        if !cfg.drop_paths.is_empty() {
+            let _ = writeln!(
+                std::io::stdout(),
+                "drop_paths: {}",
+                cfg.drop_paths.join(", ")
+            );
+        }
+        if cfg.skip_whitespace_only_hunks {
+            let _ = writeln!(std::io::stdout(), "skip_whitespace_only_hunks: on");
+        }
+        let shadow_rules = cfg.policies.iter().filter(|rule| rule.shadow).count();
+        if shadow_rules > 0 {
+            let _ = writeln!(
+                std::io::stdout(),
+                "policies: {} ({shadow_rules} shadow)",
+                cfg.policies.len()
+            );
+        } else {
+            let _ = writeln!(std::io::stdout(), "policies: {}", cfg.policies.len());
+        }
+        if let Some(lines) = cfg.escalate.large_patch_lines {
+            let _ = writeln!(
+                std::io::stdout(),
+                "escalate: {} at {lines}+ changed lines{}",
+                cfg.escalate.to.as_str(),
+                if cfg.escalate.shadow { " (shadow)" } else { "" }
+            );
+        }
+        if let Some(action) = cfg.editor_guard.action {
+            let _ = writeln!(std::io::stdout(), "editor_guard: {}", action.as_str());
+        }
+        if let Some(action) = cfg.git_boundaries.action {
+            let _ = writeln!(std::io::stdout(), "git_boundaries: {}", action.as_str());
+        }
+        if cfg.require_clean.check != clean::CleanCheck::Off {
+            let _ = writeln!(
+                std::io::stdout(),
+                "require_clean: {} ({})",
+                cfg.require_clean.check.as_str(),
+                cfg.require_clean.action.as_str()
+            );
+        }
+        if !cfg.branch_rules.is_empty() {
+            let rules: Vec<String> = cfg
+                .branch_rules
+                .iter()
+                .map(|rule| format!("{} {}", rule.branch, rule.mode.as_str()))
+                .collect();
+            let _ = writeln!(std::io::stdout(), "branch_rules: {}", rules.join(", "));
+        }
+        if let Some(action) = cfg.codeowners.action {
+            let _ = writeln!(
+                std::io::stdout(),
+                "codeowners: {} outside {}",
+                action.as_str(),
+                cfg.codeowners.owners.join(" ")
+            );
+        }
+        if cfg.lint.enforce {
+            let _ = writeln!(std::io::stdout(), "lint: enforced");
+        }
+        if let Some(max) = cfg.lint.max_line_length {
+            let _ = writeln!(std::io::stdout(), "lint: lines up to {max} characters");
+        }
+        if cfg.symlinks.action != symlinks::SymlinkAction::Follow {
+            let _ = writeln!(
+                std::io::stdout(),
+                "symlinks: {}",
+                cfg.symlinks.action.as_str()
+            );
+        }
+        if cfg.new_dirs.action != newdirs::NewDirsAction::Allow
+            || cfg.new_dirs.max_depth.is_some()
+            || !cfg.new_dirs.top_level
+        {
+            let depth = match cfg.new_dirs.max_depth {
+                Some(depth) => format!(", max depth {depth}"),
+                None => String::new(),
+            };
+            let top_level = if cfg.new_dirs.top_level {
+                ""
+            } else {
+                ", no new top-level directories"
+            };
+            let _ = writeln!(
+                std::io::stdout(),
+                "new_dirs: {}{depth}{top_level}",
+                cfg.new_dirs.action.as_str()
+            );
+        }
+        if cfg.allow_dir_ops {
+            let _ = writeln!(
+                std::io::stdout(),
+                "allow_dir_ops: on, at most {} files per directory delete",
+                cfg.max_dir_delete_files
+            );
+        }
+        if !cfg.replace_file.allow {
+            let _ = writeln!(std::io::stdout(), "replace_file: off");
+        } else if cfg.replace_file.max_bytes != replace::ReplaceFileConfig::default().max_bytes {
+            let _ = writeln!(
+                std::io::stdout(),
+                "replace_file: at most {} bytes",
+                cfg.replace_file.max_bytes
+            );
+        }
+        if let Some(threshold) = cfg.failure_guard.threshold {
+            let cooldown = match cfg.failure_guard.cooldown_secs {
+                Some(secs) => format!(", {secs}s cooldown"),
+                None => String::new(),
+            };
+            let _ = writeln!(
+                std::io::stdout(),
+                "failure_guard: {threshold} identical failures{cooldown}"
+            );
+        }
+        if cfg.notify.enabled() {
+            let mut via = Vec::new();
+            if cfg.notify.desktop {
+                via.push("desktop");
+            }
+            if cfg.notify.command.is_some() {
+                via.push("command");
+            }
+            let _ = writeln!(std::io::stdout(), "notify: {}", via.join(", "));
+        }
+        if let Some(target) = cfg.forward.target() {
+            let _ = writeln!(std::io::stdout(), "forward: {target}");
+        }
+        if let Some(similarity) = cfg.reanchor.min_similarity {
+            let _ = writeln!(std::io::stdout(), "reanchor: {similarity} similarity");
+        }
+        if cfg.conflict_guard {
+            let _ = writeln!(std::io::stdout(), "conflict_guard: on");
+        }
+        if cfg.write_ahead_log {
+            let _ = writeln!(std::io::stdout(), "write_ahead_log: on");
+        }
+        if cfg.write_strategy != writes::WriteStrategy::default() {
+            let _ = writeln!(
+                std::io::stdout(),
+                "write_strategy: {}",
+                cfg.write_strategy.as_str()
+            );
+        }
+        if let Some(dir) = &cfg.staging_dir {
+            let _ = writeln!(std::io::stdout(), "staging_dir: {}", dir.display());
+        }
+        match cfg.limits.max_target_file_bytes {
+            None => {
+                let _ = writeln!(std::io::stdout(), "max_target_file_bytes: unlimited");
+            }
+            Some(max) if Some(max) != limits::Limits::default().max_target_file_bytes => {
+                let _ = writeln!(std::io::stdout(), "max_target_file_bytes: {max}");
+            }
+            Some(_) => {}
+        }
+        match cfg.limits.stream_above_bytes {
+            None => {
+                let _ = writeln!(std::io::stdout(), "stream_above_bytes: never");
+            }
+            Some(bytes) if Some(bytes) != limits::Limits::default().stream_above_bytes => {
+                let _ = writeln!(std::io::stdout(), "stream_above_bytes: {bytes}");
+            }
+            Some(_) => {}
+        }
+        for (pattern, command) in &cfg.format_on_apply {
+            let _ = writeln!(std::io::stdout(), "format_on_apply: {pattern} -> {command}");
+        }
+        if !cfg.redact.patterns.is_empty() {
+            let _ = writeln!(
+                std::io::stdout(),
+                "redact: {} pattern(s)",
+                cfg.redact.patterns.len()
+            );
+        }
+        if cfg.fallback != gitapply::Fallback::default() {
+            let _ = writeln!(std::io::stdout(), "fallback: {}", cfg.fallback.as_str());
+        }
+        if cfg.final_newline != newline::FinalNewline::default() {
+            let _ = writeln!(
+                std::io::stdout(),
+                "final_newline: {}",
+                cfg.final_newline.as_str()
+            );
+        }
+        if cfg.concise.enabled {
+            let _ = writeln!(
+                std::io::stdout(),
+                "concise: messages up to {} bytes",
+                cfg.concise.message_bytes
+            );
+        }
+        if !cfg.verify_writes {
+            let _ = writeln!(std::io::stdout(), "verify_writes: off");
+        }
+        if let Some(mirror_dir) = &cfg.mirror_dir {
+            let _ = writeln!(std::io::stdout(), "mirror_dir: {}", mirror_dir.display());
+        }
+        if !cfg.disk_space.check {
+            let _ = writeln!(std::io::stdout(), "disk_space: not checked");
+        } else if cfg.disk_space.reserve_bytes > 0 {
+            let _ = writeln!(
+                std::io::stdout(),
+                "disk_space: keep {} bytes free",
+                cfg.disk_space.reserve_bytes
+            );
+        }
+        if cfg.patch_cache {
+            let _ = writeln!(std::io::stdout(), "patch_cache: on");
+        }
+        if let Some(state_dir) = &cfg.state_dir {
+            let _ = writeln!(std::io::stdout(), "state_dir: {}", state_dir.display());
+        }
+        if let Some(audit_log) = &cfg.audit_log {
+            let _ = writeln!(std::io::stdout(), "audit_log: {}", audit_log.display());
+        }
+        if let Some(socket) = &cfg.audit.forward_socket {
+            let _ = writeln!(
+                std::io::stdout(),
+                "audit.forward_socket: {}",
+                socket.display()
+            );
+        }
+    } else {
+        let _ = writeln!(std::io::stdout(), "Updated config: {}", path.display());
+    }
+
+    Invocation::Exit(0)
+}
+
+fn run_subcommand(name: &str, args: &[String]) -> i32 {
+    match name {
+        "generate-docs" => docs::run_generate_docs(args),
+        "preview" => preview::run_preview(args),
+        "stage" => staging::run_stage(args),
+        "commit-staged" => staging::run_commit_staged(args),
+        "discard-staged" => staging::run_discard_staged(args),
+        "assess" => risk::run_assess(args),
+        "compare" => compare::run_compare(args),
+        "lint" => lint::run_lint(args),
+        "init" => init::run_init(args),
+        "migrate-state" => state::run_migrate_state(args),
+        "migrate-config" => migrate::run_migrate_config(args),
+        "cache" => patchcache::run_cache(args),
+        "serve-http" => serve::run_serve_http(args),
+        "audit-collector" => audit_collector::run_audit_collector(args),
+        "wrap-shim" => shim::run_wrap_shim(args),
+        "squash" => squash::run_squash(args),
+        "batch" => batch::run_batch(args),
+        "redact" => redact::run_redact(args),
+        "roundtrip" => roundtrip::run_roundtrip(args),
+        "make-patch" => makepatch::run_make_patch(args),
+        "wal" => wal::run_wal(args),
+        "revert-file" => wal::run_revert_file(args),
+        "why" => why::run_why(args),
+        _ => {
+            diagnostics::error(format!("unknown command: {name}"));
+            2
+        }
+    }
+}
+
+fn read_patch_from_stdin() -> Result<String, i32> {
+    let mut buf = String::new();
+    match std::io::stdin().read_to_string(&mut buf) {
+        Ok(_) => {
+            if buf.is_empty() {
+                eprintln!("Usage: apply_patch 'PATCH'\n       echo 'PATCH' | apply-patch");
+                return Err(2);
+            }
+            Ok(buf)
+        }
+        Err(err) => {
+            diagnostics::error(format!("Failed to read PATCH from stdin.\n{err}"));
+            Err(1)
+        }
+    }
+}
+
+/// The PATCH from inherited file descriptor `fd` (`--patch-fd`), leaving
+/// stdin free.
+fn read_patch_from_fd(fd: u32) -> Result<String, i32> {
+    match std::fs::read_to_string(format!("/dev/fd/{fd}")) {
+        Ok(buf) if buf.is_empty() => {
+            diagnostics::error(format!("no patch on file descriptor {fd}."));
+            Err(2)
+        }
+        Ok(buf) => Ok(buf),
+        Err(err) => {
+            diagnostics::error(format!(
+                "Failed to read PATCH from file descriptor {fd}.\n{err}"
+            ));
+            Err(1)
+        }
+    }
+}
+
+/// The PATCH from the single positional argument, or from stdin if there is
+/// none.
+fn read_patch(positional: &[String]) -> Result<String, i32> {
+    match positional {
+        [] => read_patch_from_stdin(),
+        [body] => Ok(body.to_string()),
+        _ => {
+            diagnostics::error("apply_patch accepts exactly one argument.");
+            Err(2)
+        }
+    }
+}
+
+fn run_main() -> i32 {
+    let mut args_os = std::env::args_os();
+    let _argv0 = args_os.next();
+
+    let mut args: Vec<String> = Vec::new();
+    for arg in args_os {
+        match arg.into_string() {
+            Ok(s) => args.push(s),
+            Err(_) => {
+                diagnostics::error("apply_patch requires a UTF-8 PATCH argument.");
+                return 1;
+            }
+        }
+    }
+
+    if let Some(name) = args.first()
+        && let Some(cmd) = cli::subcommand(name)
+    {
+        return run_subcommand(cmd.name, &args[1..]);
+    }
+
+    let (positional, mut options) = match run_config_command(&args) {
+        Invocation::Exit(code) => return code,
+        Invocation::Patch {
+            positional,
+            options,
+        } => (positional, *options),
+    };
+
+    // Everything below resolves relative paths (patch targets, --residual,
+    // --watch) from here.
+    if let Some(dir) = &options.cwd
+        && let Err(err) = std::env::set_current_dir(dir)
+    {
+        diagnostics::error(format!(
+            "cannot use {} as the working directory: {err}",
+            dir.display()
+        ));
+        return 2;
+    }
+
+    if options.jail && (options.watch.is_some() || options.to_branch.is_some()) {
+        diagnostics::error("--jail cannot be combined with --watch or --to-branch.");
+        return 2;
+    }
+
+    if let Some(dir) = &options.watch {
+        if options.dry_run || effective_config(&options).is_ok_and(|cfg| cfg.readonly) {
+            diagnostics::error("--watch cannot be used with --dry-run or readonly.");
+            return 2;
+        }
+        if options.input != toolcall::InputFormat::Patch {
+            diagnostics::error("--watch cannot be combined with --input tool-json.");
+            return 2;
+        }
+        if !positional.is_empty() {
+            diagnostics::error("--watch cannot be combined with a PATCH argument.");
+            return 2;
+        }
+        return watch::run_watch(dir, &options);
+    }
+
+    let cfg = match effective_config(&options) {
+        Ok(cfg) => cfg,
+        Err(err) => {
+            diagnostics::error(err);
+            return exit::Exit::ConfigError.code();
+        }
+    };
+    options.dry_run |= cfg.readonly;
+    if options.dry_run && options.to_branch.is_some() {
+        diagnostics::error("--to-branch cannot be used with --dry-run or readonly.");
+        return 2;
+    }
+    if options.jail && jail::inside() {
+        if let Err(why) = jail::confined(&cfg) {
+            diagnostics::error(format!("--jail: {why}; nothing was changed."));
+            return exit::Exit::Failed.code();
+        }
+    } else if options.jail
+        && let Some(code) = jail::run(&cfg, &args)
+    {
+        return code;
+    }
+    let read = match options.patch_fd {
+        Some(fd) => read_patch_from_fd(fd),
+        None => read_patch(&positional),
+    };
+    let mut patch_arg = match read {
+        Ok(s) => s,
+        Err(code) => return code,
+    };
+    if options.input == toolcall::InputFormat::ToolJson {
+        let call = match toolcall::ToolCall::parse(&patch_arg) {
+            Ok(call) => call,
+            Err(err) => {
+                diagnostics::error(err);
+                return exit::Exit::ParseError.code();
+            }
+        };
+        // Relative to --cwd, if both are given.
+        if let Some(dir) = &call.cwd
+            && let Err(err) = std::env::set_current_dir(dir)
+        {
+            diagnostics::error(format!(
+                "cannot use {} as the working directory: {err}",
+                dir.display()
+            ));
+            return 2;
+        }
+        patch_arg = call.patch;
+        options.metadata = call.metadata;
+    }
+
+    if let Some(base) = &options.base {
+        patch_arg = match rebase::rebase(&patch_arg, base) {
+            Ok(rebased) => rebased,
+            Err((exit, msg)) => {
+                diagnostics::error(msg);
+                return exit.code();
+            }
+        };
+        if let Some(out) = &options.rebased_out {
+            return match std::fs::write(out, &patch_arg) {
+                Ok(()) => {
+                    println!("Rebased patch written to {}", out.display());
+                    0
+                }
+                Err(err) => {
+                    diagnostics::error(format!("failed to write {}: {err}", out.display()));
+                    1
+                }
+            };
+        }
+    }
+
+    if options.check {
+        return check::run_check(&patch_arg, options.format);
+    }
+
+    if let Some(name) = &options.to_branch {
+        return branch::apply_to_branch(&cfg, &patch_arg, &options, name);
+    }
+
+    match process_patch(&cfg, &patch_arg, &options) {
+        Ok(outcome) => outcome.code,
+        Err(code) => code,
+    }
+}
+
+/// The config for this invocation, with the tenant's section and the
+/// locale's messages applied.
+fn effective_config(options: &RunOptions) -> Result<Config, String> {
+    config_at(config_path(), options)
+}
+
+/// [`effective_config`] for the config file at `path`, or the defaults.
+fn config_at(path: Option<PathBuf>, options: &RunOptions) -> Result<Config, String> {
+    let cfg = match &path {
+        Some(path) => load_config(path)?,
+        None => Config::default(),
+    };
+    let mut cfg = match (&options.tenant, &path) {
+        (Some(name), Some(path)) => tenant::effective(cfg, name)
+            .map_err(|err| format!("invalid config {}: {err}", path.display()))?,
+        _ => cfg,
+    };
+    locale::apply(&mut cfg);
+    cfg.source = path;
+    Ok(cfg)
+}
+
+/// Runs one patch through policies, escalation and guards, then refuses or
+/// applies it and records the outcome. `Err` carries an exit code for
+/// errors that stop before any decision is made.
+fn process_patch(cfg: &Config, patch_arg: &str, options: &RunOptions) -> Result<Outcome, i32> {
+    concise::configure(options.concise || cfg.concise.enabled, &cfg.concise);
+    if let Some(events) = &options.events {
+        let files = patch::Patch::parse(patch_arg)
+            .map(|patch| patch.touched_paths())
+            .unwrap_or_default();
+        events.emit(&events::Event::Started {
+            pid: std::process::id(),
+            cwd: std::env::current_dir()
+                .ok()
+                .map(|dir| dir.display().to_string()),
+            files: &files,
+        });
+    }
+    let mut result = decide_and_apply(cfg, patch_arg, options);
+    if let Err(code) = &result {
+        why::record_stopped(cfg, options.tenant.as_deref(), *code);
+    }
+    let failed = !matches!(&result, Ok(outcome) if outcome.decision != Decision::Failed);
+    if diagnostics::finish(failed, options.deny_warnings)
+        && let Ok(outcome) = &mut result
+    {
+        outcome.code = exit::Exit::Failed.code();
+    }
+    if let Some(events) = &options.events {
+        let (decision, exit_code) = match &result {
+            Ok(outcome) => (Some(outcome.decision), outcome.code),
+            Err(code) => (None, *code),
+        };
+        events.emit(&events::Event::Finished {
+            decision,
+            exit_code,
+        });
+    }
+    result
+}
+
+/// [`process_patch`] without the lifecycle events.
+fn decide_and_apply(cfg: &Config, patch_arg: &str, options: &RunOptions) -> Result<Outcome, i32> {
+    let meter = resources::Meter::start();
+    let (patch_arg, trailers) = trailers::strip(patch_arg);
+    let patch_arg = patch_arg.as_ref();
+    if options.strict {
+        let problems = strict::problems(patch_arg);
+        if !problems.is_empty() {
+            eprintln!("Refused (--strict): the patch is not canonical. Nothing was changed.");
+            for problem in problems {
+                eprintln!("  {problem}");
+            }
+            return Err(exit::Exit::ParseError.code());
+        }
+    }
+    // Checked on the apply and warn paths only: refuse mode answers every
+    // input with its banner.
+    let truncated = truncation::detect(patch_arg);
+    let (patch_arg, mut reads) = match freshness::strip(patch_arg) {
+        Ok(stripped) => stripped,
+        Err(err) => {
+            eprintln!("{err}");
+            return Err(exit::Exit::ParseError.code());
+        }
+    };
+    if let Some(manifest) = &options.read_manifest {
+        match freshness::load_manifest(manifest) {
+            // A `*** Read At:` line in the patch wins over the manifest.
+            Ok(manifest) => {
+                for (path, read_at) in manifest {
+                    reads.entry(path).or_insert(read_at);
+                }
+            }
+            Err(err) => {
+                diagnostics::error(err);
+                return Err(2);
+            }
+        }
+    }
+    let patch_arg = match archive::expand(&patch_arg, &cfg.archives) {
+        Ok(expanded) => expanded,
+        Err(err) => {
+            eprintln!("{err}");
+            return Err(exit::Exit::ParseError.code());
+        }
+    };
+    let patch_arg = match scaffold::expand(&patch_arg) {
+        Ok(expanded) => expanded,
+        Err(err) => {
+            eprintln!("{err}");
+            return Err(exit::Exit::ParseError.code());
+        }
+    };
+    if options.archive.is_some() && replace::contains(&patch_arg) {
+        diagnostics::error("Replace File sections can't be used with --archive.");
+        return Err(exit::Exit::ParseError.code());
+    }
+    let patch_arg = match replace::expand(&patch_arg, &cfg.replace_file) {
+        Ok(expanded) => expanded,
+        Err(err) => {
+            eprintln!("{err}");
+            return Err(exit::Exit::ParseError.code());
+        }
+    };
+    if options.archive.is_some() && editline::contains(&patch_arg) {
+        diagnostics::error("Edit Line sections can't be used with --archive.");
+        return Err(exit::Exit::ParseError.code());
+    }
+    let patch_arg = match editline::expand(&patch_arg) {
+        Ok(expanded) => expanded,
+        Err(err) => {
+            eprintln!("{err}");
+            return Err(exit::Exit::ParseError.code());
+        }
+    };
+    let patch_arg = match hashline::expand(&patch_arg) {
+        Ok(expanded) => expanded,
+        Err(err) => {
+            eprintln!("{err}");
+            return Err(exit::Exit::ParseError.code());
+        }
+    };
+    let (patch_arg, dir_ops) = match dirops::expand(&patch_arg, cfg.allow_dir_ops) {
+        Ok(expanded) => expanded,
+        Err(err) => {
+            eprintln!("{err}");
+            return Err(exit::Exit::ParseError.code());
+        }
+    };
+    if !dir_ops.is_empty() && options.archive.is_some() {
+        diagnostics::error("directory sections can't be used with --archive.");
+        return Err(exit::Exit::ParseError.code());
+    }
+    let (patch_arg, dropped) = drop_paths::strip(patch_arg.as_ref(), &cfg.drop_paths);
+    let patch_arg = patch_arg.as_ref();
+    for path in &dropped {
+        println!("Dropped by policy: {path}");
+    }
+    let (patch_arg, skipped) = whitespace::strip(patch_arg, cfg.skip_whitespace_only_hunks);
+    let patch_arg = patch_arg.as_ref();
+    for note in &skipped {
+        println!("{note}");
+    }
+    if (!dropped.is_empty() || !skipped.is_empty())
+        && patch::Patch::parse(patch_arg).is_some_and(|p| p.sections.is_empty())
+    {
+        println!("Nothing left to apply.");
+        return Ok(Outcome::new(Decision::Applied, exit::Exit::Applied));
+    }
+    let mut facts = policy::PatchFacts::collect(patch::Patch::parse(patch_arg).as_ref());
+    // Added directories go through the path guards like files do.
+    for dir in &dir_ops.added {
+        if !facts.files.contains(dir) {
+            facts.files.push(dir.clone());
+        }
+    }
+    facts.metadata = options.metadata.clone();
+    for (key, value) in trailers {
+        facts
+            .metadata
+            .entry(key)
+            .or_insert(serde_json::Value::String(value));
+    }
+    let (policy_index, selected_mode) = match policy::evaluate(&cfg.policies, &facts) {
+        Ok(Some((idx, mode))) => (Some(idx), mode),
+        Ok(None) => (None, cfg.mode),
+        Err(err) => {
+            diagnostics::error(err);
+            return Err(exit::Exit::ConfigError.code());
+        }
+    };
+    // Each guard below may raise the mode but never lowers it. `refusal`
+    // names the first step that made it `refuse`, and `notices` explain
+    // every raise before the banner.
+    let mut mode = selected_mode;
+    let mut refusal = (mode == Mode::Refuse).then_some(match policy_index {
+        Some(index) => reason::RefusalReason::Policy(index),
+        None => reason::RefusalReason::Mode,
+    });
+    let mut notices: Vec<String> = Vec::new();
+
+    let escalation = policy::escalate(&cfg.escalate, &facts, mode);
+    if let Some(escalation) = &escalation {
+        mode = escalation.to;
+        notices.push(escalation.describe());
+        if refusal.is_none() && mode == Mode::Refuse {
+            refusal = Some(reason::RefusalReason::LargePatch(escalation.lines_changed));
+        }
+    }
+    let shadow = match policy::shadow(&cfg.policies, &cfg.escalate, &facts, cfg.mode, mode) {
+        Ok(shadow) => shadow,
+        Err(err) => {
+            diagnostics::error(err);
+            return Err(exit::Exit::ConfigError.code());
+        }
+    };
+    // Guards that inspect the working tree have nothing to look at when
+    // the patch targets an archive.
+    let on_disk = options.archive.is_none();
+    let own_state = on_disk
+        .then(|| {
+            ownstate::guard(
+                cfg.source.as_deref(),
+                cfg.state_dir.as_deref(),
+                cfg.audit_log.as_deref(),
+                options.tenant.as_deref(),
+                &facts.files,
+            )
+        })
+        .flatten();
+    if let Some(guard) = &own_state {
+        mode = guard.to;
+        notices.extend(guard.describe());
+        if refusal.is_none() {
+            refusal = Some(reason::RefusalReason::OwnState(guard.files[0].path.clone()));
+        }
+    }
+    let target_size = on_disk
+        .then(|| patch::Patch::parse(patch_arg))
+        .flatten()
+        .and_then(|patch| limits::guard(&patch, &cfg.limits));
+    if let Some(guard) = &target_size {
+        mode = guard.to;
+        notices.extend(guard.describe());
+        if refusal.is_none() {
+            refusal = Some(reason::RefusalReason::TooLarge(guard.files[0].path.clone()));
+        }
+    }
+    let expected_files = options
+        .expected_files
+        .as_ref()
+        .and_then(|expected| expected::guard(expected, &facts.files));
+    if let Some(guard) = &expected_files {
+        mode = guard.to;
+        notices.extend(guard.describe());
+        if refusal.is_none() {
+            let path = guard.unexpected[0].clone();
+            refusal = Some(reason::RefusalReason::UnexpectedFile(path));
+        }
+    }
+    let editor_guard = on_disk
+        .then(|| editor::guard(&cfg.editor_guard, &facts.files, mode))
+        .flatten();
+    if let Some(guard) = &editor_guard {
+        mode = guard.to;
+        notices.extend(guard.describe());
+        if refusal.is_none() && mode == Mode::Refuse {
+            let artifact = guard.artifacts[0].artifact.clone();
+            refusal = Some(reason::RefusalReason::EditorArtifact(artifact));
+        }
+    }
+    let git_boundaries = on_disk
+        .then(|| gitscope::guard(&cfg.git_boundaries, &facts.files, mode))
+        .flatten();
+    if let Some(guard) = &git_boundaries {
+        mode = guard.to;
+        notices.extend(guard.describe());
+        if refusal.is_none() && mode == Mode::Refuse {
+            refusal = Some(reason::RefusalReason::from_boundary(&guard.boundaries[0]));
+        }
+    }
+    let require_clean = on_disk
+        .then(|| clean::guard(&cfg.require_clean, &facts.files, mode))
+        .flatten();
+    if let Some(guard) = &require_clean {
+        mode = guard.to;
+        notices.extend(guard.describe());
+        if refusal.is_none() && mode == Mode::Refuse {
+            refusal = Some(reason::RefusalReason::Dirty(guard.dirty[0].path.clone()));
+        }
+    }
+    let stale_reads = on_disk
+        .then(|| freshness::guard(&reads, &facts.files))
+        .flatten();
+    if let Some(guard) = &stale_reads {
+        mode = guard.to;
+        notices.extend(guard.describe());
+        if refusal.is_none() {
+            refusal = Some(reason::RefusalReason::StaleRead(
+                guard.files[0].path.clone(),
+            ));
+        }
+    }
+    let branch_rule = on_disk
+        .then(|| branchrules::guard(&cfg.branch_rules, mode))
+        .flatten();
+    if let Some(guard) = &branch_rule {
+        mode = guard.to;
+        notices.push(guard.describe(&cfg.branch_rules));
+        if refusal.is_none() && mode == Mode::Refuse {
+            refusal = Some(reason::RefusalReason::Branch(guard.branch.clone()));
+        }
+    }
+    let codeowners = on_disk
+        .then(|| codeowners::guard(&cfg.codeowners, &facts.files, mode))
+        .flatten();
+    if let Some(guard) = &codeowners {
+        mode = guard.to;
+        notices.extend(guard.describe());
+        if refusal.is_none() && mode == Mode::Refuse {
+            refusal = Some(reason::RefusalReason::NotOwner(guard.files[0].path.clone()));
+        }
+    }
+    let symlinks = on_disk
+        .then(|| symlinks::guard(&cfg.symlinks, &facts.files, mode))
+        .flatten();
+    if let Some(guard) = &symlinks {
+        mode = guard.to;
+        notices.extend(guard.describe());
+        if refusal.is_none()
+            && let Some(link) = guard.refusing()
+        {
+            refusal = Some(reason::RefusalReason::Symlink(link.target.clone()));
+        }
+    }
+    let new_dirs = on_disk
+        .then(|| {
+            newdirs::guard(
+                &cfg.new_dirs,
+                options.max_depth,
+                patch::Patch::parse(patch_arg).as_ref(),
+                &dir_ops.added,
+                mode,
+            )
+        })
+        .flatten();
+    if let Some(guard) = &new_dirs {
+        mode = guard.to;
+        notices.extend(guard.describe());
+        if refusal.is_none()
+            && let Some(dir) = guard.blocking().next()
+        {
+            refusal = Some(reason::RefusalReason::NewDirectory(dir.path.clone()));
+        }
+    }
+    let dir_delete = dirops::guard(&dir_ops, cfg.max_dir_delete_files);
+    if let Some(guard) = &dir_delete {
+        mode = guard.to;
+        notices.push(guard.describe());
+        if refusal.is_none() {
+            refusal = Some(reason::RefusalReason::DirDelete(guard.dir.path.clone()));
+        }
+    }
+    let lint = match lint::guard(&cfg.lint, patch::Patch::parse(patch_arg).as_ref(), mode) {
+        Ok(guard) => guard,
+        Err(err) => {
+            diagnostics::error(err);
+            return Err(exit::Exit::ConfigError.code());
+        }
+    };
+    if let Some(guard) = &lint {
+        if guard.to == Mode::Refuse {
+            mode = guard.to;
+            notices.extend(guard.describe());
+            if refusal.is_none()
+                && let Some(finding) = guard.blocking()
+            {
+                refusal = Some(reason::RefusalReason::Lint(finding.rule.to_string()));
+            }
+        } else {
+            // Warnings don't block; show them with the apply output.
+            for line in guard.describe() {
+                println!("{line}");
+            }
+        }
+    }
+    if let Some(cooldown) = failures::cooldown(
+        &cfg.failure_guard,
+        cfg.state_dir.as_deref(),
+        options.tenant.as_deref(),
+    ) {
+        mode = Mode::Refuse;
+        notices.push(cooldown.describe());
+        if refusal.is_none() {
+            refusal = Some(reason::RefusalReason::Cooldown(cooldown.remaining_secs));
+        }
+    }
+
+    if let Some(events) = &options.events {
+        events.emit(&events::Event::PolicyDecision {
+            configured: cfg.mode,
+            mode,
+            policy: policy_index,
+            reason: refusal.as_ref(),
+            notices: &notices,
+            shadow: shadow.as_ref(),
+        });
+    }
+
+    let mut outcome = match mode {
+        Mode::Refuse => {
+            if cfg.refusal_reason_line
+                && let Some(reason) = &refusal
+            {
+                println!("{}", reason.line());
+            }
+            for notice in &notices {
+                println!("{}", concise::clip(notice));
+            }
+            let template = cfg
+                .refuse_message
+                .as_deref()
+                .unwrap_or(DEFAULT_REFUSE_MESSAGE);
+            let stats = stats::used_by(&[template]).then(|| {
+                stats::current(
+                    cfg.state_dir.as_deref(),
+                    options.tenant.as_deref(),
+                    &facts.agent,
+                )
+            });
+            let msg = template::render(template, mode, &facts, stats.as_ref());
+            println!("{}", concise::clip(&msg));
+            echo_refused_patch(cfg.refuse_echo, patch_arg);
+            feedback::record(
+                mode,
+                Decision::Refused,
+                refusal.as_ref(),
+                &msg,
+                options.tenant.as_deref(),
+            );
+            why::emitted(&msg);
+            let exit = match refusal {
+                Some(reason::RefusalReason::Mode) => exit::Exit::RefusedByMode,
+                _ => exit::Exit::RefusedByPolicy,
+            };
+            Outcome::new(Decision::Refused, exit)
+        }
+        Mode::Forward if options.staging.is_some() => {
+            diagnostics::error("a patch in forward mode can't be staged. Nothing was changed.");
+            Outcome::new(Decision::Failed, exit::Exit::Failed)
+        }
+        Mode::Forward if options.dry_run => {
+            println!(
+                "Dry run: the patch would be forwarded to {}; nothing was sent.",
+                cfg.forward.target().unwrap_or("(no forward target)")
+            );
+            Outcome::new(Decision::DryRun, exit::Exit::Applied)
+        }
+        Mode::Forward => match forward::send(&cfg.forward, patch_arg, &facts) {
+            Ok(summary) => {
+                println!("Forwarded the patch; nothing was applied here. The editor answered:");
+                println!("{}", summary.trim_end());
+                Outcome::new(Decision::Forwarded, exit::Exit::Applied)
+            }
+            Err(err) => {
+                diagnostics::error(format!(
+                    "forwarding the patch failed: {err}. Nothing was changed."
+                ));
+                Outcome::new(Decision::Failed, exit::Exit::Failed)
+            }
+        },
+        Mode::Apply | Mode::Warn if let Some(sign) = &truncated => {
+            eprintln!(
+                "Refused: the patch appears truncated ({sign}). Nothing was changed; resend the complete patch."
+            );
+            Outcome::new(Decision::Failed, exit::Exit::ParseError)
+        }
+        Mode::Apply | Mode::Warn => {
+            match symlinks
+                .as_ref()
+                .filter(|_| !options.dry_run)
+                .map_or(Ok(()), |guard| guard.replace_links())
+            {
+                Ok(()) => apply(cfg, mode, patch_arg, options, &facts, &notices, &dir_ops),
+                Err(err) => {
+                    diagnostics::error(err);
+                    Outcome::new(Decision::Failed, exit::Exit::Failed)
+                }
+            }
+        }
+    };
+
+    // Warnings printed while writing still fail the run; the records say so
+    // rather than that the patch applied.
+    if options.deny_warnings
+        && diagnostics::warnings() > 0
+        && matches!(outcome.decision, Decision::Applied | Decision::Partial)
+    {
+        outcome = Outcome::new(Decision::Failed, exit::Exit::Failed);
+    }
+
+    if cfg.notify.enabled() {
+        let event = if outcome.decision == Decision::Refused {
+            Some(notify::Event {
+                kind: notify::EventKind::Refused,
+                reason: refusal.as_ref().map(|reason| reason.code()),
+                detail: refusal
+                    .as_ref()
+                    .map_or_else(|| "REFUSED".to_string(), |reason| reason.line()),
+                files: &facts.files,
+            })
+        } else {
+            escalation.as_ref().map(|escalation| notify::Event {
+                kind: notify::EventKind::Escalated,
+                reason: None,
+                detail: escalation.describe(),
+                files: &facts.files,
+            })
+        };
+        if let Some(event) = event {
+            notify::send(&cfg.notify, &event);
+        }
+    }
+
+    let usage = meter.finish(facts.files.len());
+    if options.timings {
+        eprintln!("{}", usage.describe());
+    }
+    why::record(
+        cfg,
+        options.tenant.as_deref(),
+        &why::Decided {
+            facts: &facts,
+            policy: policy_index,
+            mode,
+            notices: &notices,
+            reason: refusal.as_ref(),
+            decision: outcome.decision,
+            exit_code: outcome.code,
+        },
+    );
+    if cfg.audit_log.is_some() || cfg.audit.forward_socket.is_some() {
+        let mut entry = audit::AuditEntry::new(cfg.mode, mode, outcome.decision, &facts);
+        entry.policy = policy_index;
+        entry.dropped = &dropped;
+        entry.escalation = escalation.as_ref();
+        entry.shadow = shadow.as_ref();
+        entry.editor_guard = editor_guard.as_ref();
+        entry.git_boundaries = git_boundaries.as_ref();
+        entry.require_clean = require_clean.as_ref();
+        entry.stale_reads = stale_reads.as_ref();
+        entry.branch_rule = branch_rule.as_ref();
+        entry.codeowners = codeowners.as_ref();
+        entry.symlinks = symlinks.as_ref();
+        entry.new_dirs = new_dirs.as_ref();
+        entry.dir_delete = dir_delete.as_ref();
+        entry.lint = lint.as_ref();
+        entry.expected_files = expected_files.as_ref();
+        entry.target_size = target_size.as_ref();
+        entry.reason = refusal.as_ref();
+        entry.tenant = options.tenant.as_deref();
+        let fingerprint = fingerprint::current();
+        entry.fingerprint = Some(&fingerprint);
+        entry.resources = Some(&usage);
+        if let Some(audit_log) = &cfg.audit_log {
+            audit::record(audit_log, &entry);
+        }
+        if let Some(socket) = &cfg.audit.forward_socket {
+            audit::forward(socket, &entry);
+        }
+    }
+
+    Ok(outcome)
+}
+
+fn echo_refused_patch(echo: RefuseEcho, patch_arg: &str) {
+    let parsed = patch::Patch::parse(patch_arg);
+    match (echo, parsed) {
+        (RefuseEcho::None, _) => {}
+        (RefuseEcho::Summary, Some(patch)) => {
+            print!("\nRefused patch summary:\n{}", patch.summary())
+        }
+        (RefuseEcho::Summary, None) => {}
+        (RefuseEcho::Full, Some(patch)) => print!("\nRefused patch:\n{}", patch.render()),
+        (RefuseEcho::Full, None) => println!("\nRefused patch:\n{}", patch_arg.trim_end()),
+    }
+}
+
+/// With `--deny-warnings`, a failure once any warning has been printed, so
+/// the patch isn't written only for the run to fail afterwards.
+fn deny_warnings(options: &RunOptions) -> Option<Outcome> {
+    if !options.deny_warnings || options.dry_run || diagnostics::warnings() == 0 {
+        return None;
+    }
+    diagnostics::error("--deny-warnings: the warnings above stop the patch. Nothing was changed.");
+    Some(Outcome::new(Decision::Failed, exit::Exit::Failed))
+}
+
+fn apply(
+    cfg: &Config,
+    mode: Mode,
+    patch_arg: &str,
+    options: &RunOptions,
+    facts: &policy::PatchFacts,
+    notices: &[String],
+    dir_ops: &dirops::DirOps,
+) -> Outcome {
+    let reanchored = reanchor::reanchor(patch_arg, &cfg.reanchor);
+    let patch_arg = match &reanchored {
+        Some((text, notes)) => {
+            for note in notes {
+                println!("{note}");
+            }
+            text.as_str()
+        }
+        None => patch_arg,
+    };
+    // Files too big to hold in memory are applied a line at a time, without
+    // the checks that would read them whole.
+    let streaming = options.archive.is_none()
+        && options.staging.is_none()
+        && !options.partial
+        && patch::Patch::parse(patch_arg)
+            .is_some_and(|patch| streaming::wanted(&patch, cfg.limits.stream_above_bytes));
+    if !options.dry_run
+        && options.archive.is_none()
+        && !streaming
+        && let Some(changes) = verify::expected(patch_arg)
+        && let Err(msg) = diskspace::check(&changes, &cfg.disk_space)
+    {
+        diagnostics::error(msg);
+        return Outcome::new(Decision::Failed, exit::Exit::Failed);
+    }
+    // Checked before stashing too, since a failure after it would leave the
+    // files stashed.
+    if let Some(denied) = deny_warnings(options) {
+        return denied;
+    }
+    if options.stash_before
+        && !options.dry_run
+        && options.archive.is_none()
+        && let Some(patch) = patch::Patch::parse(patch_arg)
+    {
+        match stash::save(&patch.touched_paths()) {
+            Ok(Some(hash)) => {
+                println!("Stashed the files about to be patched as stash@{{0}} ({hash}).")
+            }
+            Ok(None) => {
+                diagnostics::warning("--stash-before: not in a git work tree; nothing was stashed.")
+            }
+            Err(err) => {
+                diagnostics::error(format!("--stash-before: {err}. Nothing was changed."));
+                return Outcome::new(Decision::Failed, exit::Exit::Failed);
+            }
+        }
+    }
+    if let Some(denied) = deny_warnings(options) {
+        return denied;
+    }
+    // Simulating first means the built-in applier never half-applies a
+    // patch that git then has to apply on top of.
+    let native_error = ((cfg.fallback == gitapply::Fallback::GitApply || options.three_way)
+        && !options.dry_run
+        && !options.partial
+        && !streaming)
+        .then(|| patch::Patch::parse(patch_arg))
+        .flatten()
+        .and_then(|patch| simulate::simulate(&patch, Path::new(".")).err());
+    let intent =
+        (cfg.write_ahead_log && !options.dry_run && options.archive.is_none() && !streaming)
+            .then(|| {
+                wal::begin(
+                    cfg.state_dir.as_deref(),
+                    options.tenant.as_deref(),
+                    patch_arg,
+                    verify::expected(patch_arg).as_deref(),
+                )
+            })
+            .flatten();
+    let (decision, failure) = match (patch::Patch::parse(patch_arg), options.archive.as_deref()) {
+        (_, Some(archive)) => {
+            match bundle::apply(
+                archive,
+                options.archive_out.as_deref(),
+                patch_arg,
+                options.dry_run,
+            ) {
+                Ok(()) if options.dry_run => (Decision::DryRun, None),
+                Ok(()) => (Decision::Applied, None),
+                Err(failure) => (Decision::Failed, Some(failure)),
+            }
+        }
+        (_, None) if let Some(step) = &options.staging => {
+            match staging::run_step(
+                step,
+                patch_arg,
+                options.expected_files.as_deref(),
+                cfg.staging_dir.as_deref(),
+            ) {
+                // Staging writes nothing to the tree.
+                Ok(()) if options.dry_run => (Decision::DryRun, None),
+                Ok(()) => (Decision::Applied, None),
+                Err(failure) => (Decision::Failed, Some(failure)),
+            }
+        }
+        (Some(patch), None) if streaming => {
+            // Delete expectations are checked as the applier path does, only
+            // when writing.
+            let force = options.force_delete || options.dry_run;
+            match deletes::verify_deletes(patch_arg, force) {
+                Ok(_) => match streaming::apply(
+                    &patch,
+                    options.dry_run,
+                    cfg.final_newline,
+                    cfg.write_strategy,
+                    cfg.staging_dir.as_deref(),
+                ) {
+                    Ok(()) if options.dry_run => (Decision::DryRun, None),
+                    Ok(()) => (Decision::Applied, None),
+                    Err(failure) => (Decision::Failed, Some(failure)),
+                },
+                Err(msg) => {
+                    eprintln!("{msg}");
+                    let first = msg.lines().next().unwrap_or_default().to_string();
+                    (Decision::Failed, Some((exit::Exit::ContextMismatch, first)))
+                }
+            }
+        }
+        _ if options.dry_run => match dry_run(patch_arg) {
+            Ok(()) => (Decision::DryRun, None),
+            Err(failure) => (Decision::Failed, Some(failure)),
+        },
+        // Nothing for the applier when the patch only has directory sections.
+        (Some(patch), None) if patch.sections.is_empty() && !dir_ops.is_empty() => {
+            concise::print_success(&[]);
+            (Decision::Applied, None)
+        }
+        (Some(patch), None) if options.partial => {
+            let decision = partial::apply_sections(
+                &patch,
+                options.force_delete,
+                options.residual.as_deref(),
+                progress::Progress::new(options.format, patch.sections.len()).as_ref(),
+                options.events.as_ref(),
+            );
+            if matches!(decision, Decision::Applied | Decision::Partial) {
+                formatters::run(&cfg.format_on_apply, patch_arg, None);
+            }
+            (decision, None)
+        }
+        (Some(_), None) if native_error.is_some() => {
+            let native_error = native_error.as_deref().unwrap_or_default();
+            match apply_with_git(
+                patch_arg,
+                options.force_delete,
+                options.three_way,
+                native_error,
+            ) {
+                Ok(()) => {
+                    formatters::run(&cfg.format_on_apply, patch_arg, None);
+                    (Decision::Applied, None)
+                }
+                Err(failure) => (Decision::Failed, Some(failure)),
+            }
+        }
+        _ => {
+            let cache = cfg
+                .patch_cache
+                .then(|| patchcache::PatchCache::open(cfg.state_dir.as_deref()))
+                .flatten();
+            let cached = cache.as_ref().and_then(|cache| cache.replay(patch_arg));
+            let (result, changes) = match cached {
+                Some(Ok(changes)) => (Ok(()), Some(changes)),
+                Some(Err(failure)) => (Err(failure), None),
+                None => {
+                    let mut expected = (cfg.verify_writes
+                        || cfg.mirror_dir.is_some()
+                        || cache.is_some()
+                        || cfg.conflict_guard
+                        || cfg.final_newline != newline::FinalNewline::Always)
+                        .then(|| verify::expected(patch_arg))
+                        .flatten();
+                    let guard = expected
+                        .as_deref()
+                        .filter(|_| cfg.conflict_guard)
+                        .map(conflicts::Guard::start);
+                    let applied = apply_whole(
+                        patch_arg,
+                        options.force_delete,
+                        options.format,
+                        cfg.parse_error_message.as_deref(),
+                        cfg.write_strategy,
+                        cfg.staging_dir.as_deref(),
+                    )
+                    .and_then(|()| match &mut expected {
+                        Some(expected) => {
+                            newline::enforce(expected, cfg.final_newline).map_err(|msg| {
+                                eprintln!("{msg}");
+                                (exit::Exit::Failed, msg)
+                            })
+                        }
+                        None => Ok(()),
+                    });
+                    // A failed apply is checked too: a concurrent write may
+                    // be why it failed.
+                    let result = match (guard, &expected) {
+                        (Some(guard), Some(expected)) => guard
+                            .finish(expected, applied.is_ok())
+                            .map_err(|msg| {
+                                diagnostics::error(&msg);
+                                (exit::Exit::ContextMismatch, msg)
+                            })
+                            .and(applied),
+                        _ => applied,
+                    }
+                    .and_then(|()| match &expected {
+                        Some(expected) if cfg.verify_writes => {
+                            verify::check(expected).map_err(|msg| {
+                                eprintln!("{msg}");
+                                (exit::Exit::Failed, msg)
+                            })
+                        }
+                        _ => Ok(()),
+                    });
+                    if let (Ok(()), Some(cache), Some(changes)) = (&result, &cache, &expected) {
+                        cache.store(patch_arg, changes);
+                    }
+                    (result, expected)
+                }
+            };
+            match result {
+                Ok(()) => {
+                    let mut changes = changes;
+                    formatters::run(&cfg.format_on_apply, patch_arg, changes.as_mut());
+                    if let (Some(dir), Some(changes)) = (&cfg.mirror_dir, &changes) {
+                        mirror::replay(dir, changes);
+                    }
+                    (Decision::Applied, None)
+                }
+                Err(failure) => (Decision::Failed, Some(failure)),
+            }
+        }
+    };
+    if let Some(intent) = intent {
+        intent.finish(decision);
+    }
+    if matches!(
+        decision,
+        Decision::Applied | Decision::Partial | Decision::DryRun
+    ) {
+        dir_ops.finish(options.dry_run);
+    }
+    // Partial applies report their files as they go.
+    if decision == Decision::Applied
+        && !options.partial
+        && let Some(events) = &options.events
+        && let Some(patch) = patch::Patch::parse(patch_arg)
+    {
+        for section in &patch.sections {
+            events.emit(&events::Event::FileApplied {
+                path: section.move_to().unwrap_or(&section.path),
+                status: section.status(),
+            });
+        }
+    }
+    // A dry run leaves no trace in state kept between runs.
+    if !options.dry_run {
+        failures::record(
+            &cfg.failure_guard,
+            cfg.state_dir.as_deref(),
+            options.tenant.as_deref(),
+            decision,
+            failure.as_ref().map(|(_, error)| error.as_str()),
+        );
+    }
+    if decision == Decision::Failed {
+        let exit = failure.map_or(exit::Exit::Failed, |(exit, _)| exit);
+        return Outcome::new(decision, exit);
+    }
+    let exit = match (decision, mode) {
+        (Decision::Partial, _) => exit::Exit::Partial,
+        (_, Mode::Warn) => exit::Exit::AppliedWithWarning,
+        _ => exit::Exit::Applied,
+    };
+    let warn_templates: Vec<&str> = if cfg.warn_messages.is_empty() {
+        vec![cfg.warn_message.as_deref().unwrap_or(DEFAULT_WARN_MESSAGE)]
+    } else {
+        cfg.warn_messages.iter().map(String::as_str).collect()
+    };
+    let apply_template = cfg.apply_message.as_deref();
+    let stats = (!options.dry_run
+        && (warn_templates.len() > 1
+            || stats::used_by(
+                &[&warn_templates[..], &[apply_template.unwrap_or_default()]].concat(),
+            )))
+    .then(|| {
+        stats::record(
+            cfg.state_dir.as_deref(),
+            options.tenant.as_deref(),
+            &facts.agent,
+            mode,
+        )
+    });
+    if mode == Mode::Warn {
+        for notice in notices {
+            println!("{}", concise::clip(notice));
+        }
+        let warn_template = stats::rotate(&warn_templates, cfg.warn_rotation, stats.as_ref());
+        let msg = template::render(warn_template, mode, facts, stats.as_ref());
+        println!("{}", concise::clip(&msg));
+        feedback::record(mode, decision, None, &msg, options.tenant.as_deref());
+        why::emitted(&msg);
+    } else if let Some(template) = apply_template {
+        let msg = template::render(template, mode, facts, stats.as_ref());
+        println!("{}", concise::clip(&msg));
+        feedback::record(mode, decision, None, &msg, options.tenant.as_deref());
+        why::emitted(&msg);
+    }
+    Outcome::new(decision, exit)
+}
+
+/// Runs the patch through the matcher without writing anything and prints
+/// what it would change. `Err` carries the failure's kind and message.
+fn dry_run(patch_arg: &str) -> Result<(), (exit::Exit, String)> {
+    let Some(patch) = patch::Patch::parse(patch_arg) else {
+        let msg = "Invalid patch: The first line of the patch must be '*** Begin Patch'";
+        eprintln!("{msg}");
+        return Err((exit::Exit::ParseError, msg.to_string()));
+    };
+    let changes = simulate::simulate(&patch, Path::new(".")).map_err(|err| {
+        eprintln!("{err}");
+        (exit::Exit::ContextMismatch, err)
+    })?;
+    println!("Dry run: nothing was written. The patch would update the following files:");
+    for change in &changes {
+        println!("{} {}", change.status(), change.path);
+    }
+    Ok(())
+}
+
+/// Applies the patch with `git apply`, or by merge with `three_way`, after
+/// the built-in applier failed to place it with `native_error`.
+fn apply_with_git(
+    patch_arg: &str,
+    force_delete: bool,
+    three_way: bool,
+    native_error: &str,
+) -> Result<(), (exit::Exit, String)> {
+    let patch_arg = deletes::verify_deletes(patch_arg, force_delete).map_err(|msg| {
+        eprintln!("{msg}");
+        let first = msg.lines().next().unwrap_or_default().to_string();
+        (exit::Exit::ContextMismatch, first)
+    })?;
+    let Some(patch) = patch::Patch::parse(&patch_arg) else {
+        return Err((exit::Exit::ParseError, native_error.to_string()));
+    };
+    let result = if three_way {
+        gitapply::apply_three_way(&patch, native_error)
+    } else {
+        gitapply::apply(&patch, native_error)
+    };
+    result.map_err(|msg| {
+        eprintln!("{native_error}");
+        eprintln!("{msg}");
+        (exit::Exit::ContextMismatch, native_error.to_string())
+    })
+}
+
+/// Applies the whole patch at once. Errors are printed as they happen; `Err`
+/// carries their kind and first line.
+fn apply_whole(
+    patch_arg: &str,
+    force_delete: bool,
+    format: progress::OutputFormat,
+    parse_error_message: Option<&str>,
+    strategy: writes::WriteStrategy,
+    staging_dir: Option<&Path>,
+) -> Result<(), (exit::Exit, String)> {
+    let patch_arg = match deletes::verify_deletes(patch_arg, force_delete) {
+        Ok(patch) => patch,
+        Err(msg) => {
+            eprintln!("{msg}");
+            let first = msg.lines().next().unwrap_or_default().to_string();
+            return Err((exit::Exit::ContextMismatch, first));
+        }
+    };
+    // Patches that can't be simulated go to the applier, which reports why.
+    if strategy == writes::WriteStrategy::Replace
+        && let Some(patch) = patch::Patch::parse(&patch_arg)
+        && let Ok(changes) = simulate::simulate(&patch, Path::new("."))
+    {
+        return writes::apply_replacing(&patch, &changes, staging_dir).map_err(|msg| {
+            eprintln!("{msg}");
+            (exit::Exit::Failed, msg)
+        });
+    }
+    // Progress needs the sections applied one at a time; only do that for
+    // patches that parse, so malformed ones still fail before any write.
+    if let Some(patch) = patch::Patch::parse(&patch_arg)
+        && patch
+            .sections
+            .iter()
+            .all(|s| s.kind != patch::SectionKind::Update || s.chunks().is_ok())
+        && let Some(progress) = progress::Progress::new(format, patch.sections.len())
+    {
+        return progress::apply_sections(&patch, &progress);
+    }
+    let mut stderr: Vec<u8> = Vec::new();
+    let result = if concise::enabled() {
+        let mut stdout: Vec<u8> = Vec::new();
+        let result = codex_apply_patch::apply_patch(&patch_arg, &mut stdout, &mut stderr);
+        concise::print_applier_output(&stdout);
+        result
+    } else {
+        let mut stdout = std::io::stdout();
+        let result = codex_apply_patch::apply_patch(&patch_arg, &mut stdout, &mut stderr);
+        let _ = stdout.flush();
+        result
+    };
+    let _ = std::io::stderr().write_all(&stderr);
+    result.map_err(|err| {
+        let failure = apply_failure(&err, &stderr);
+        if let codex_apply_patch::ApplyPatchError::ParseError(parse) = &err {
+            grammar::explain(&patch_arg, parse, &failure.1, parse_error_message);
+        }
+        failure
+    })
+}
+
+/// Exit code and one-line summary for an applier error, given what it
+/// wrote to stderr.
+fn apply_failure(err: &codex_apply_patch::ApplyPatchError, stderr: &[u8]) -> (exit::Exit, String) {
+    let message = String::from_utf8_lossy(stderr);
+    let first = match message.lines().find(|line| !line.trim().is_empty()) {
+        Some(line) => line.trim().to_string(),
+        None => err.to_string(),
+    };
+    let exit = match err {
+        codex_apply_patch::ApplyPatchError::ParseError(_) => exit::Exit::ParseError,
+        codex_apply_patch::ApplyPatchError::ComputeReplacements(_) => exit::Exit::ContextMismatch,
+        _ => exit::Exit::Failed,
+    };
+    (exit, first)
+}
+
+/// Runs `apply_patch` with the process's arguments and exits.
+pub fn main() -> ! {
+    let code = run_main();
+    std::process::exit(code);
+}
@@ -0,0 +1,96 @@
+//! Size limits on the files a patch targets (`limits` in the config).
+//!
+//! The applier reads each target into memory whole, and the checks around
+//! it (simulation, write verification, the mirror) hold further copies, so
+//! patching a generated file of hundreds of megabytes makes the process
+//! balloon. A patch that updates or deletes a file larger than
+//! `limits.max_target_file_bytes` (default 256 MiB; `null` for no limit) is
+//! refused, whatever the mode, before anything is read or written. Below that, a patch updating
+//! a file larger than `limits.stream_above_bytes` (default 50 MiB) is
+//! applied a line at a time by [`crate::streaming`].
+
+use crate::Mode;
+use crate::patch::Patch;
+use crate::patch::SectionKind;
+use serde::Deserialize;
+use serde::Serialize;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct Limits {
+    #[serde(default = "default_max_target_file_bytes")]
+    pub(crate) max_target_file_bytes: Option<u64>,
+    #[serde(default = "default_stream_above_bytes")]
+    pub(crate) stream_above_bytes: Option<u64>,
+}
+
+fn default_max_target_file_bytes() -> Option<u64> {
+    Some(256 * 1024 * 1024)
+}
+
+fn default_stream_above_bytes() -> Option<u64> {
+    Some(50 * 1024 * 1024)
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            max_target_file_bytes: default_max_target_file_bytes(),
+            stream_above_bytes: default_stream_above_bytes(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct OversizedFile {
+    pub(crate) path: String,
+    pub(crate) bytes: u64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct SizeGuard {
+    pub(crate) files: Vec<OversizedFile>,
+    /// `limits.max_target_file_bytes`.
+    pub(crate) max: u64,
+    #[serde(skip)]
+    pub(crate) to: Mode,
+}
+
+impl SizeGuard {
+    pub(crate) fn describe(&self) -> Vec<String> {
+        self.files
+            .iter()
+            .map(|f| {
+                format!(
+                    "Target file size (refuse mode): {} is {} bytes, more than limits.max_target_file_bytes ({}); edit it another way.",
+                    f.path, f.bytes, self.max
+                )
+            })
+            .collect()
+    }
+}
+
+/// Checks the existing targets of `patch` against the limit. Returns `None`
+/// when none is larger.
+pub(crate) fn guard(patch: &Patch, limits: &Limits) -> Option<SizeGuard> {
+    let max = limits.max_target_file_bytes?;
+    let files: Vec<OversizedFile> = patch
+        .sections
+        .iter()
+        .filter(|section| section.kind != SectionKind::Add)
+        .filter_map(|section| {
+            let bytes = std::fs::metadata(&section.path).ok()?.len();
+            (bytes > max).then(|| OversizedFile {
+                path: section.path.clone(),
+                bytes,
+            })
+        })
+        .collect();
+    if files.is_empty() {
+        return None;
+    }
+    Some(SizeGuard {
+        files,
+        max,
+        to: Mode::Refuse,
+    })
+}
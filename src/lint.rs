@@ -0,0 +1,502 @@
+//! Patch linting (`lint` in the config and `apply_patch lint`).
+//!
+//! Each rule looks at the lines a patch adds and reports findings at the
+//! rule's severity. `apply_patch lint` prints them; with `lint.enforce`,
+//! error-level findings refuse the patch and warnings are printed before it
+//! is applied.
+
+use crate::Mode;
+use crate::diagnostics;
+use crate::patch::Patch;
+use crate::patch::Section;
+use crate::patch::SectionKind;
+use serde::Deserialize;
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Severity {
+    Off,
+    Warn,
+    Error,
+}
+
+impl Severity {
+    fn as_str(self) -> &'static str {
+        match self {
+            Severity::Off => "off",
+            Severity::Warn => "warn",
+            Severity::Error => "error",
+        }
+    }
+}
+
+/// A lint rule and its severity when the config doesn't set one.
+pub(crate) struct Rule {
+    pub(crate) name: &'static str,
+    pub(crate) default: Severity,
+}
+
+pub(crate) const RULES: &[Rule] = &[
+    // Added lines that look like merge conflict markers.
+    Rule {
+        name: "conflict_markers",
+        default: Severity::Error,
+    },
+    // Added lines indented with tabs in a file indented with spaces, or the
+    // reverse.
+    Rule {
+        name: "mixed_indentation",
+        default: Severity::Warn,
+    },
+    // Added lines containing TODO or FIXME.
+    Rule {
+        name: "todo_added",
+        default: Severity::Warn,
+    },
+    // Added lines containing zero-width or bidi control characters, which
+    // can make code read differently from how it compiles.
+    Rule {
+        name: "invisible_chars",
+        default: Severity::Error,
+    },
+    // Added words mixing ASCII letters with look-alike letters from other
+    // scripts, such as a Cyrillic `а` in an identifier.
+    Rule {
+        name: "homoglyphs",
+        default: Severity::Warn,
+    },
+    // Added lines longer than `max_line_length`, a sign of content run
+    // together or a minified blob. Only checked when the limit is set.
+    Rule {
+        name: "long_lines",
+        default: Severity::Warn,
+    },
+];
+
+/// Invisible and bidi control characters flagged by `invisible_chars`.
+const INVISIBLE: &[(char, &str)] = &[
+    ('\u{00AD}', "SOFT HYPHEN"),
+    ('\u{061C}', "ARABIC LETTER MARK"),
+    ('\u{200B}', "ZERO WIDTH SPACE"),
+    ('\u{200C}', "ZERO WIDTH NON-JOINER"),
+    ('\u{200D}', "ZERO WIDTH JOINER"),
+    ('\u{200E}', "LEFT-TO-RIGHT MARK"),
+    ('\u{200F}', "RIGHT-TO-LEFT MARK"),
+    ('\u{202A}', "LEFT-TO-RIGHT EMBEDDING"),
+    ('\u{202B}', "RIGHT-TO-LEFT EMBEDDING"),
+    ('\u{202C}', "POP DIRECTIONAL FORMATTING"),
+    ('\u{202D}', "LEFT-TO-RIGHT OVERRIDE"),
+    ('\u{202E}', "RIGHT-TO-LEFT OVERRIDE"),
+    ('\u{2060}', "WORD JOINER"),
+    ('\u{2061}', "FUNCTION APPLICATION"),
+    ('\u{2062}', "INVISIBLE TIMES"),
+    ('\u{2063}', "INVISIBLE SEPARATOR"),
+    ('\u{2064}', "INVISIBLE PLUS"),
+    ('\u{2066}', "LEFT-TO-RIGHT ISOLATE"),
+    ('\u{2067}', "RIGHT-TO-LEFT ISOLATE"),
+    ('\u{2068}', "FIRST STRONG ISOLATE"),
+    ('\u{2069}', "POP DIRECTIONAL ISOLATE"),
+    ('\u{FEFF}', "ZERO WIDTH NO-BREAK SPACE"),
+];
+
+/// Cyrillic and Greek letters that look like ASCII letters, for
+/// `homoglyphs`. Fullwidth ASCII (U+FF01-U+FF5E) is checked separately.
+const CONFUSABLE: &[(char, char)] = &[
+    ('\u{0430}', 'a'),
+    ('\u{0432}', 'B'),
+    ('\u{0435}', 'e'),
+    ('\u{043A}', 'k'),
+    ('\u{043C}', 'M'),
+    ('\u{043D}', 'H'),
+    ('\u{043E}', 'o'),
+    ('\u{0440}', 'p'),
+    ('\u{0441}', 'c'),
+    ('\u{0442}', 'T'),
+    ('\u{0443}', 'y'),
+    ('\u{0445}', 'x'),
+    ('\u{0456}', 'i'),
+    ('\u{0458}', 'j'),
+    ('\u{0455}', 's'),
+    ('\u{0501}', 'd'),
+    ('\u{0410}', 'A'),
+    ('\u{0412}', 'B'),
+    ('\u{0415}', 'E'),
+    ('\u{041A}', 'K'),
+    ('\u{041C}', 'M'),
+    ('\u{041D}', 'H'),
+    ('\u{041E}', 'O'),
+    ('\u{0420}', 'P'),
+    ('\u{0421}', 'C'),
+    ('\u{0422}', 'T'),
+    ('\u{0425}', 'X'),
+    ('\u{0406}', 'I'),
+    ('\u{0408}', 'J'),
+    ('\u{0405}', 'S'),
+    ('\u{03B1}', 'a'),
+    ('\u{03BF}', 'o'),
+    ('\u{03BD}', 'v'),
+    ('\u{03C1}', 'p'),
+    ('\u{0391}', 'A'),
+    ('\u{0392}', 'B'),
+    ('\u{0395}', 'E'),
+    ('\u{0396}', 'Z'),
+    ('\u{0397}', 'H'),
+    ('\u{0399}', 'I'),
+    ('\u{039A}', 'K'),
+    ('\u{039C}', 'M'),
+    ('\u{039D}', 'N'),
+    ('\u{039F}', 'O'),
+    ('\u{03A1}', 'P'),
+    ('\u{03A4}', 'T'),
+    ('\u{03A5}', 'Y'),
+    ('\u{03A7}', 'X'),
+];
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub(crate) struct LintConfig {
+    /// Refuse patches with error-level findings.
+    #[serde(default)]
+    pub(crate) enforce: bool,
+    /// Severity overrides, keyed by rule name.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub(crate) rules: BTreeMap<String, Severity>,
+    /// Longest added line `long_lines` allows, in characters.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) max_line_length: Option<usize>,
+}
+
+impl LintConfig {
+    fn severity(&self, rule: &str) -> Severity {
+        match self.rules.get(rule) {
+            Some(severity) => *severity,
+            None => RULES
+                .iter()
+                .find(|r| r.name == rule)
+                .map_or(Severity::Off, |r| r.default),
+        }
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        match self
+            .rules
+            .keys()
+            .find(|name| !RULES.iter().any(|r| r.name == name.as_str()))
+        {
+            Some(name) => Err(format!("unknown lint rule: {name}")),
+            None => Ok(()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct Finding {
+    pub(crate) rule: &'static str,
+    pub(crate) severity: Severity,
+    pub(crate) path: String,
+    /// Line within an added file.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) line: Option<usize>,
+    /// Hunk number within an update.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) hunk: Option<usize>,
+    pub(crate) message: String,
+}
+
+impl Finding {
+    fn describe(&self) -> String {
+        let location = match (self.line, self.hunk) {
+            (Some(line), _) => format!("{}:{line}", self.path),
+            (None, Some(hunk)) => format!("{} @@ {hunk}", self.path),
+            (None, None) => self.path.clone(),
+        };
+        format!(
+            "{} {} {location}: {}",
+            self.severity.as_str(),
+            self.rule,
+            self.message
+        )
+    }
+}
+
+/// Lints every section of `patch`, skipping rules that are off.
+pub(crate) fn lint(cfg: &LintConfig, patch: &Patch) -> Result<Vec<Finding>, String> {
+    cfg.validate()?;
+    let mut findings = Vec::new();
+    for section in &patch.sections {
+        let added = added_lines(section);
+        let indent = match section.kind {
+            SectionKind::Add => indent_style(added.iter().map(|(_, _, line)| *line)),
+            _ => std::fs::read_to_string(&section.path)
+                .ok()
+                .and_then(|text| indent_style(text.lines())),
+        };
+        for &(line, hunk, text) in &added {
+            let mut report = |rule: &'static str, message: String| {
+                let severity = cfg.severity(rule);
+                if severity != Severity::Off {
+                    findings.push(Finding {
+                        rule,
+                        severity,
+                        path: section.path.clone(),
+                        line,
+                        hunk,
+                        message,
+                    });
+                }
+            };
+            if ["<<<<<<< ", ">>>>>>> "].iter().any(|m| text.starts_with(m))
+                || text.trim_end() == "======="
+            {
+                report(
+                    "conflict_markers",
+                    "adds a merge conflict marker".to_string(),
+                );
+            }
+            if let Some(style) = indent {
+                let leading = &text[..text.len() - text.trim_start().len()];
+                let mixed = match style {
+                    ' ' => leading.contains('\t'),
+                    _ => leading.starts_with(' '),
+                };
+                if mixed {
+                    let (used, file) = match style {
+                        ' ' => ("tabs", "spaces"),
+                        _ => ("spaces", "tabs"),
+                    };
+                    report(
+                        "mixed_indentation",
+                        format!("indents with {used} but the file uses {file}"),
+                    );
+                }
+            }
+            if text.contains("TODO") || text.contains("FIXME") {
+                report("todo_added", "adds a TODO/FIXME".to_string());
+            }
+            let invisible = invisible_chars(text);
+            if !invisible.is_empty() {
+                report(
+                    "invisible_chars",
+                    format!("adds invisible {}", invisible.join(", ")),
+                );
+            }
+            let length = text.chars().count();
+            if let Some(max) = cfg.max_line_length
+                && length > max
+            {
+                report(
+                    "long_lines",
+                    format!(
+                        "adds a line of {length} characters, more than max_line_length ({max})"
+                    ),
+                );
+            }
+            let lookalikes = homoglyphs(text);
+            if !lookalikes.is_empty() {
+                report(
+                    "homoglyphs",
+                    format!("adds look-alike {}", lookalikes.join(", ")),
+                );
+            }
+        }
+    }
+    Ok(findings)
+}
+
+/// Added lines of a section as `(line in added file, hunk, text)`.
+fn added_lines(section: &Section) -> Vec<(Option<usize>, Option<usize>, &str)> {
+    match section.kind {
+        SectionKind::Add => section
+            .body
+            .iter()
+            .filter_map(|line| line.strip_prefix('+'))
+            .enumerate()
+            .map(|(i, text)| (Some(i + 1), None, text))
+            .collect(),
+        SectionKind::Delete => Vec::new(),
+        SectionKind::Update => {
+            let mut hunk = 0;
+            let mut added = Vec::new();
+            for line in &section.body {
+                if line.starts_with("@@") {
+                    hunk += 1;
+                } else if let Some(text) = line.strip_prefix('+') {
+                    added.push((None, Some(hunk.max(1)), text));
+                }
+            }
+            added
+        }
+    }
+}
+
+/// `U+XXXX NAME at column N` for each invisible character in `text`.
+fn invisible_chars(text: &str) -> Vec<String> {
+    text.chars()
+        .enumerate()
+        .filter_map(|(i, c)| {
+            let (_, name) = INVISIBLE.iter().find(|(ch, _)| *ch == c)?;
+            Some(format!("U+{:04X} {name} at column {}", c as u32, i + 1))
+        })
+        .collect()
+}
+
+/// `U+XXXX (looks like 'x') at column N` for each look-alike letter in a
+/// word that also has ASCII letters.
+fn homoglyphs(text: &str) -> Vec<String> {
+    let chars: Vec<char> = text.chars().collect();
+    let mut found = Vec::new();
+    let mut start = 0;
+    while start < chars.len() {
+        let end = chars[start..]
+            .iter()
+            .position(|c| !(c.is_alphanumeric() || *c == '_'))
+            .map_or(chars.len(), |n| start + n);
+        let word = &chars[start..end];
+        if word.iter().any(char::is_ascii_alphabetic) {
+            for (i, c) in word.iter().enumerate() {
+                if let Some(ascii) = lookalike(*c) {
+                    found.push(format!(
+                        "U+{:04X} (looks like '{ascii}') at column {}",
+                        *c as u32,
+                        start + i + 1
+                    ));
+                }
+            }
+        }
+        start = end + 1;
+    }
+    found
+}
+
+/// The ASCII letter or digit `c` is mistaken for, if it is a look-alike.
+fn lookalike(c: char) -> Option<char> {
+    if ('\u{FF01}'..='\u{FF5E}').contains(&c) {
+        return char::from_u32(c as u32 - 0xFEE0);
+    }
+    CONFUSABLE
+        .iter()
+        .find(|(ch, _)| *ch == c)
+        .map(|(_, ascii)| *ascii)
+}
+
+/// The character most indented lines start with, if any are indented.
+fn indent_style<'a>(lines: impl Iterator<Item = &'a str>) -> Option<char> {
+    let (mut tabs, mut spaces) = (0, 0);
+    for line in lines.filter(|l| !l.trim().is_empty()) {
+        match line.chars().next() {
+            Some('\t') => tabs += 1,
+            Some(' ') => spaces += 1,
+            _ => {}
+        }
+    }
+    match (tabs, spaces) {
+        (0, 0) => None,
+        (tabs, spaces) if tabs > spaces => Some('\t'),
+        _ => Some(' '),
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct LintGuard {
+    pub(crate) findings: Vec<Finding>,
+    pub(crate) to: Mode,
+}
+
+impl LintGuard {
+    pub(crate) fn describe(&self) -> Vec<String> {
+        self.findings
+            .iter()
+            .map(|finding| format!("Lint: {}", finding.describe()))
+            .collect()
+    }
+
+    /// The first finding that blocks the patch.
+    pub(crate) fn blocking(&self) -> Option<&Finding> {
+        self.findings
+            .iter()
+            .find(|finding| finding.severity == Severity::Error)
+    }
+}
+
+/// With `enforce`, lints the patch and refuses it on error-level findings.
+/// Returns `None` when enforcement is off or nothing was found.
+pub(crate) fn guard(
+    cfg: &LintConfig,
+    patch: Option<&Patch>,
+    mode: Mode,
+) -> Result<Option<LintGuard>, String> {
+    let (true, Some(patch)) = (cfg.enforce, patch) else {
+        return Ok(None);
+    };
+    let findings = lint(cfg, patch)?;
+    if findings.is_empty() {
+        return Ok(None);
+    }
+    let to = if findings.iter().any(|f| f.severity == Severity::Error) {
+        Mode::Refuse
+    } else {
+        mode
+    };
+    Ok(Some(LintGuard { findings, to }))
+}
+
+/// `apply_patch lint [--json] [--] [PATCH]`
+pub(crate) fn run_lint(args: &[String]) -> i32 {
+    let mut json = false;
+    let mut positional: Vec<String> = Vec::new();
+    for (i, arg) in args.iter().enumerate() {
+        match arg.as_str() {
+            "--json" => json = true,
+            "--" => {
+                positional.extend(args[i + 1..].iter().cloned());
+                break;
+            }
+            other if other.starts_with('-') => {
+                diagnostics::error(format!("unknown option: {other}"));
+                return 2;
+            }
+            other => positional.push(other.to_string()),
+        }
+    }
+    let cfg = match crate::effective_config(&crate::RunOptions::default()) {
+        Ok(cfg) => cfg,
+        Err(err) => {
+            diagnostics::error(err);
+            return crate::exit::Exit::ConfigError.code();
+        }
+    };
+    let patch_text = match crate::read_patch(&positional) {
+        Ok(s) => s,
+        Err(code) => return code,
+    };
+    let Some(patch) = Patch::parse(&patch_text) else {
+        eprintln!("Invalid patch: The first line of the patch must be '*** Begin Patch'");
+        return 1;
+    };
+    let findings = match lint(&cfg.lint, &patch) {
+        Ok(findings) => findings,
+        Err(err) => {
+            diagnostics::error(err);
+            return crate::exit::Exit::ConfigError.code();
+        }
+    };
+
+    if json {
+        match serde_json::to_string(&findings) {
+            Ok(line) => println!("{line}"),
+            Err(err) => {
+                diagnostics::error(format!("failed to serialize lint findings: {err}"));
+                return 1;
+            }
+        }
+    } else {
+        if findings.is_empty() {
+            println!("No lint findings.");
+        }
+        for finding in &findings {
+            println!("{}", finding.describe());
+        }
+    }
+    i32::from(findings.iter().any(|f| f.severity == Severity::Error))
+}
@@ -0,0 +1,75 @@
+//! Per-locale guardrail messages (`locales` and `output.locale` in the
+//! config).
+//!
+//! The refuse, warn, apply and parse-error messages can be given per
+//! locale, so a team can brief its model, and read the banners, in its own
+//! language. The locale is `output.locale`, or else the first of
+//! `$LC_ALL`, `$LC_MESSAGES` and `$LANG` that is set (`C` and `POSIX` mean
+//! none). `locales` entries are matched on the full name (`pt_BR`) before
+//! the language (`pt`), and each message they set replaces the top-level
+//! one. Other output (usage errors, summaries, diagnostics) stays English.
+
+use crate::Config;
+use serde::Deserialize;
+use serde::Serialize;
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub(crate) struct OutputConfig {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) locale: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub(crate) struct LocaleMessages {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) refuse_message: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) warn_message: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) apply_message: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) parse_error_message: Option<String>,
+}
+
+/// The locale in effect, without encoding or modifier (`de_DE.UTF-8` ->
+/// `de_DE`).
+pub(crate) fn resolve(output: &OutputConfig) -> Option<String> {
+    let raw = output.locale.clone().or_else(|| {
+        ["LC_ALL", "LC_MESSAGES", "LANG"]
+            .iter()
+            .filter_map(|var| std::env::var(var).ok())
+            .find(|value| !value.is_empty())
+    })?;
+    let name = raw.split(['.', '@']).next().unwrap_or_default().trim();
+    (!name.is_empty() && name != "C" && name != "POSIX").then(|| name.to_string())
+}
+
+/// The `locales` key that applies to `locale`, if any.
+pub(crate) fn matching_key<'a>(cfg: &'a Config, locale: &str) -> Option<&'a str> {
+    let language = locale.split(['_', '-']).next().unwrap_or(locale);
+    [locale, language]
+        .into_iter()
+        .find_map(|name| cfg.locales.get_key_value(name).map(|(key, _)| key.as_str()))
+}
+
+/// Replaces `cfg`'s messages with those of the locale in effect.
+pub(crate) fn apply(cfg: &mut Config) {
+    let Some(locale) = resolve(&cfg.output) else {
+        return;
+    };
+    let Some(key) = matching_key(cfg, &locale).map(str::to_string) else {
+        return;
+    };
+    let Some(messages) = cfg.locales.remove(&key) else {
+        return;
+    };
+    let set = |field: &mut Option<String>, value: Option<String>| {
+        if value.is_some() {
+            *field = value;
+        }
+    };
+    set(&mut cfg.refuse_message, messages.refuse_message);
+    set(&mut cfg.warn_message, messages.warn_message);
+    set(&mut cfg.apply_message, messages.apply_message);
+    set(&mut cfg.parse_error_message, messages.parse_error_message);
+}
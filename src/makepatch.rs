@@ -0,0 +1,96 @@
+//! `apply_patch make-patch [--hashline] PATH [NEW]`: writes the patch that
+//! turns `PATH` as it is now into the contents of `NEW` (stdin when
+//! omitted).
+//!
+//! A missing `PATH` gives an add section, anything else an update diffed
+//! line by line. With `--hashline`, context and removed lines carry the
+//! hash of their text (see [`crate::hashline`]), so the patch still applies
+//! after the lines around a change have moved.
+
+use crate::diagnostics;
+use crate::hashline;
+use crate::patch::ADD_FILE_MARKER;
+use crate::patch::BEGIN_PATCH_MARKER;
+use crate::patch::END_PATCH_MARKER;
+use crate::patch::UPDATE_FILE_MARKER;
+use crate::rebase::diff_hunks;
+use std::io::Read;
+
+pub(crate) fn run_make_patch(args: &[String]) -> i32 {
+    let mut hashline = false;
+    let mut paths: Vec<&str> = Vec::new();
+    for arg in args {
+        match arg.as_str() {
+            "--hashline" => hashline = true,
+            "-" => paths.push(arg),
+            other if other.starts_with('-') => {
+                diagnostics::error(format!("unknown option: {other}"));
+                return 2;
+            }
+            other => paths.push(other),
+        }
+    }
+    let (path, new) = match paths[..] {
+        [path] => (path, "-"),
+        [path, new] => (path, new),
+        _ => {
+            eprintln!("Usage: apply_patch make-patch [--hashline] PATH [NEW]");
+            return 2;
+        }
+    };
+    let after = if new == "-" {
+        let mut text = String::new();
+        if let Err(err) = std::io::stdin().read_to_string(&mut text) {
+            diagnostics::error(format!("failed to read stdin: {err}"));
+            return 1;
+        }
+        text
+    } else {
+        match std::fs::read_to_string(new) {
+            Ok(text) => text,
+            Err(err) => {
+                diagnostics::error(format!("failed to read {new}: {err}"));
+                return 1;
+            }
+        }
+    };
+    let before = match std::fs::read_to_string(path) {
+        Ok(text) => Some(text),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => None,
+        Err(err) => {
+            diagnostics::error(format!("failed to read {path}: {err}"));
+            return 1;
+        }
+    };
+
+    let mut out = format!("{BEGIN_PATCH_MARKER}\n");
+    match before {
+        None => {
+            out.push_str(&format!("{ADD_FILE_MARKER}{path}\n"));
+            for line in after.lines() {
+                out.push_str(&format!("+{line}\n"));
+            }
+        }
+        Some(before) => {
+            let hunks = diff_hunks(&before, &after);
+            if hunks.is_empty() {
+                eprintln!("No changes: {path} already has those contents.");
+                return 1;
+            }
+            out.push_str(&format!("{UPDATE_FILE_MARKER}{path}\n"));
+            for line in hunks {
+                let line = if hashline {
+                    hashline::annotate(&line)
+                } else {
+                    line
+                };
+                out.push_str(&line);
+                out.push('\n');
+            }
+        }
+    }
+    out.push_str(END_PATCH_MARKER);
+    out.push('\n');
+    print!("{out}");
+    0
+}
@@ -0,0 +1,106 @@
+//! Versioned config schema.
+//!
+//! The config file records the schema `version` it was written with. Older
+//! files are upgraded step by step in memory when they are loaded, and
+//! files from a newer release are rejected instead of being silently
+//! misread. Loading never writes the file: `apply_patch migrate-config`
+//! rewrites it at the current version, keeping the original as a backup.
+//! Only the keys the migrations change are touched, so defaults the file
+//! leaves out stay defaults.
+
+use crate::diagnostics;
+use serde_json::Map;
+use serde_json::Value;
+use std::path::Path;
+
+/// Schema version written by this release.
+pub(crate) const CONFIG_VERSION: u32 = 1;
+
+/// `MIGRATIONS[n]` upgrades a version `n` config to version `n + 1`.
+const MIGRATIONS: [fn(&mut Map<String, Value>); CONFIG_VERSION as usize] = [
+    // 0 -> 1: files from before the version field; nothing else changed.
+    |_| {},
+];
+
+/// Brings `value` up to `CONFIG_VERSION`. Returns the version it started
+/// at if anything was migrated.
+pub(crate) fn upgrade(value: &mut Value) -> Result<Option<u32>, String> {
+    let Value::Object(map) = value else {
+        return Err("the config must be a JSON object".to_string());
+    };
+    let version = match map.get("version") {
+        None => 0,
+        Some(v) => v
+            .as_u64()
+            .and_then(|v| u32::try_from(v).ok())
+            .ok_or_else(|| format!("invalid config version: {v}"))?,
+    };
+    if version > CONFIG_VERSION {
+        return Err(format!(
+            "config version {version} is newer than this apply_patch supports ({CONFIG_VERSION}); upgrade apply_patch"
+        ));
+    }
+    if version == CONFIG_VERSION {
+        return Ok(None);
+    }
+    for migration in &MIGRATIONS[version as usize..] {
+        migration(map);
+    }
+    map.insert("version".to_string(), Value::from(CONFIG_VERSION));
+    Ok(Some(version))
+}
+
+/// Upgrades the config file at `path` on disk. Returns the version it was
+/// at, or `None` when it was current already.
+fn migrate_file(path: &Path) -> Result<Option<u32>, String> {
+    let bytes = std::fs::read(path)
+        .map_err(|err| format!("failed to read config {}: {err}", path.display()))?;
+    let invalid = |err: String| format!("invalid config {}: {err}", path.display());
+    let mut value: Value =
+        serde_json::from_slice(&bytes).map_err(|err| invalid(err.to_string()))?;
+    let Some(from) = upgrade(&mut value).map_err(invalid)? else {
+        return Ok(None);
+    };
+    let backup = path.with_extension(format!("json.v{from}.bak"));
+    let mut data = serde_json::to_vec_pretty(&value).map_err(|err| err.to_string())?;
+    data.push(b'\n');
+    let tmp = path.with_extension("json.tmp");
+    std::fs::write(&backup, &bytes)
+        .and_then(|()| std::fs::write(&tmp, data))
+        .and_then(|()| std::fs::rename(&tmp, path))
+        .map_err(|err| format!("failed to upgrade config {}: {err}", path.display()))?;
+    Ok(Some(from))
+}
+
+pub(crate) fn run_migrate_config(args: &[String]) -> i32 {
+    if let Some(arg) = args.first() {
+        diagnostics::error(format!("unknown option: {arg}"));
+        return 2;
+    }
+    let Some(path) = crate::config_path() else {
+        diagnostics::error("could not determine config path (HOME/XDG_CONFIG_HOME not set).");
+        return 1;
+    };
+    if !path.exists() {
+        println!("No config file at {}; nothing to migrate.", path.display());
+        return 0;
+    }
+    match migrate_file(&path) {
+        Ok(None) => {
+            println!("{} is already at version {CONFIG_VERSION}.", path.display());
+            0
+        }
+        Ok(Some(from)) => {
+            println!(
+                "Upgraded {} from version {from} to {CONFIG_VERSION}; the original is in {}.",
+                path.display(),
+                path.with_extension(format!("json.v{from}.bak")).display()
+            );
+            0
+        }
+        Err(err) => {
+            diagnostics::error(err);
+            crate::exit::Exit::ConfigError.code()
+        }
+    }
+}
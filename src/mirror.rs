@@ -0,0 +1,40 @@
+//! Replaying applied changes into a second tree (`mirror_dir` in the
+//! config), such as a backup checkout or a staged preview copy.
+//!
+//! After a whole patch applies, every file it changed is written to the
+//! same relative path under the mirror. A mirror file whose contents differ
+//! from what the working tree held before the patch has diverged: it is
+//! left alone and reported, and the other files are still mirrored. Mirror
+//! problems are warnings and never change the outcome of the run.
+
+use crate::diagnostics;
+use crate::simulate::FileChange;
+use std::path::Path;
+
+/// Writes `changes` under `dir`, warning about diverged or unwritable files.
+pub(crate) fn replay(dir: &Path, changes: &[FileChange]) {
+    for change in changes {
+        let target = dir.join(&change.path);
+        let current = std::fs::read_to_string(&target).ok();
+        if current != change.before {
+            diagnostics::warning(format!(
+                "mirror {} has diverged from the working tree; not updated.",
+                target.display()
+            ));
+            continue;
+        }
+        let result = match &change.after {
+            Some(contents) => target
+                .parent()
+                .map_or(Ok(()), std::fs::create_dir_all)
+                .and_then(|()| std::fs::write(&target, contents)),
+            None => std::fs::remove_file(&target),
+        };
+        if let Err(err) = result {
+            diagnostics::warning(format!(
+                "failed to update mirror {}: {err}",
+                target.display()
+            ));
+        }
+    }
+}
@@ -0,0 +1,255 @@
+//! New-directory policy (`new_dirs` in the config and `--max-depth`).
+//!
+//! The applier creates any missing parent directories for added and moved
+//! files, so a confused agent can scaffold a whole tree in one patch. This
+//! guard looks at the directories a patch would create, `*** Add Directory:`
+//! sections included: `deny` refuses any,
+//! `ask` confirms them on the terminal, and `max_depth` / `top_level` limit
+//! how deep they go and whether they may appear at the repository root.
+
+use crate::Mode;
+use crate::patch::Patch;
+use crate::patch::SectionKind;
+use serde::Deserialize;
+use serde::Serialize;
+use std::io::BufRead;
+use std::io::IsTerminal;
+use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum NewDirsAction {
+    #[default]
+    Allow,
+    Deny,
+    Ask,
+}
+
+impl NewDirsAction {
+    pub(crate) fn as_str(self) -> &'static str {
+        match self {
+            NewDirsAction::Allow => "allow",
+            NewDirsAction::Deny => "deny",
+            NewDirsAction::Ask => "ask",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct NewDirsConfig {
+    #[serde(default)]
+    pub(crate) action: NewDirsAction,
+    /// Most directory levels one file may create; unlimited when unset.
+    #[serde(default)]
+    pub(crate) max_depth: Option<usize>,
+    /// Whether new directories may be created at the repository root.
+    #[serde(default = "default_top_level")]
+    pub(crate) top_level: bool,
+}
+
+fn default_top_level() -> bool {
+    true
+}
+
+impl Default for NewDirsConfig {
+    fn default() -> Self {
+        Self {
+            action: NewDirsAction::Allow,
+            max_depth: None,
+            top_level: true,
+        }
+    }
+}
+
+impl NewDirsConfig {
+    fn is_default(&self) -> bool {
+        self.action == NewDirsAction::Allow && self.max_depth.is_none() && self.top_level
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct NewDir {
+    /// The outermost directory the patch would create.
+    pub(crate) path: String,
+    /// The file that needs it.
+    pub(crate) target: String,
+    /// Directory levels created for `target`.
+    pub(crate) depth: usize,
+    /// `path` would be created at the repository root.
+    pub(crate) top_level: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum Problem {
+    Denied,
+    TooDeep,
+    TopLevel,
+    Declined,
+    NoTerminal,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct NewDirGuard {
+    pub(crate) dirs: Vec<NewDir>,
+    /// Why the patch is refused, if it is.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) problem: Option<Problem>,
+    pub(crate) to: Mode,
+}
+
+impl NewDirGuard {
+    pub(crate) fn describe(&self) -> Vec<String> {
+        let Some(problem) = self.problem else {
+            return Vec::new();
+        };
+        self.blocking()
+            .map(|dir| {
+                let why = match problem {
+                    Problem::Denied => "new directories are denied (new_dirs.action)".to_string(),
+                    Problem::TooDeep => format!(
+                        "it creates {} directory levels, more than max_depth allows",
+                        dir.depth
+                    ),
+                    Problem::TopLevel => "new top-level directories are not allowed".to_string(),
+                    Problem::Declined => "creating it was declined".to_string(),
+                    Problem::NoTerminal => {
+                        "there is no terminal to confirm new directories on".to_string()
+                    }
+                };
+                format!(
+                    "New directory (refuse mode): {} for {}: {why}.",
+                    dir.path, dir.target
+                )
+            })
+            .collect()
+    }
+
+    /// The new directories that caused the refusal.
+    pub(crate) fn blocking(&self) -> impl Iterator<Item = &NewDir> {
+        let problem = self.problem;
+        self.dirs.iter().filter(move |dir| match problem {
+            Some(Problem::TopLevel) => dir.top_level,
+            Some(_) => true,
+            None => false,
+        })
+    }
+}
+
+/// Checks the directories `patch` would create, for its files and for its
+/// `*** Add Directory:` sections (`added_dirs`). Returns `None` when the
+/// policy is the default or no directory is created; `max_depth` overrides
+/// the configured limit (`--max-depth`).
+pub(crate) fn guard(
+    cfg: &NewDirsConfig,
+    max_depth: Option<usize>,
+    patch: Option<&Patch>,
+    added_dirs: &[String],
+    mode: Mode,
+) -> Option<NewDirGuard> {
+    let max_depth = max_depth.or(cfg.max_depth);
+    if cfg.is_default() && max_depth.is_none() {
+        return None;
+    }
+    let cwd = std::env::current_dir().ok()?;
+    let root = cwd
+        .ancestors()
+        .find(|dir| dir.join(".git").exists())
+        .unwrap_or(&cwd)
+        .to_path_buf();
+    let files = patch
+        .into_iter()
+        .flat_map(|patch| &patch.sections)
+        .filter_map(|section| match section.kind {
+            SectionKind::Add => Some(section.path.as_str()),
+            SectionKind::Update => section.move_to(),
+            SectionKind::Delete => None,
+        })
+        .map(|file| (file, false));
+    let added_dirs = added_dirs.iter().map(|dir| (dir.as_str(), true));
+    let mut dirs: Vec<NewDir> = Vec::new();
+    for (target, is_dir) in files.chain(added_dirs) {
+        let Some(dir) = new_dir(&cwd, &root, target, is_dir) else {
+            continue;
+        };
+        // One entry per created directory, with its deepest file.
+        match dirs.iter_mut().find(|d| d.path == dir.path) {
+            Some(seen) if seen.depth >= dir.depth => {}
+            Some(seen) => *seen = dir,
+            None => dirs.push(dir),
+        }
+    }
+    if dirs.is_empty() {
+        return None;
+    }
+    // Limits refuse outright; only patches within them are confirmed.
+    let mut problem = if cfg.action == NewDirsAction::Deny {
+        Some(Problem::Denied)
+    } else if max_depth.is_some_and(|max| dirs.iter().any(|d| d.depth > max)) {
+        dirs.retain(|d| max_depth.is_some_and(|max| d.depth > max));
+        Some(Problem::TooDeep)
+    } else if !cfg.top_level && dirs.iter().any(|d| d.top_level) {
+        Some(Problem::TopLevel)
+    } else {
+        None
+    };
+    if problem.is_none() && cfg.action == NewDirsAction::Ask && mode != Mode::Refuse {
+        problem = match confirm(&dirs) {
+            Some(true) => None,
+            Some(false) => Some(Problem::Declined),
+            None => Some(Problem::NoTerminal),
+        };
+    }
+    Some(NewDirGuard {
+        dirs,
+        problem,
+        to: if problem.is_some() {
+            Mode::Refuse
+        } else {
+            mode
+        },
+    })
+}
+
+/// The outermost missing directory above `target`, or at it when `target`
+/// is itself a directory to create, if any.
+fn new_dir(cwd: &Path, root: &Path, target: &str, is_dir: bool) -> Option<NewDir> {
+    let file = cwd.join(target);
+    let start = if is_dir {
+        Some(file.as_path())
+    } else {
+        file.parent()
+    };
+    let mut missing: Vec<&Path> = start
+        .into_iter()
+        .flat_map(Path::ancestors)
+        .take_while(|dir| !dir.exists())
+        .collect();
+    let outermost = missing.pop()?;
+    let shown = outermost
+        .strip_prefix(cwd)
+        .map_or_else(|_| outermost.to_path_buf(), PathBuf::from);
+    Some(NewDir {
+        path: shown.to_string_lossy().replace('\\', "/"),
+        target: target.to_string(),
+        depth: missing.len() + 1,
+        top_level: outermost.parent() == Some(root),
+    })
+}
+
+/// Asks on the controlling terminal whether to create `dirs`; `None` when
+/// there is no terminal to ask on.
+fn confirm(dirs: &[NewDir]) -> Option<bool> {
+    if !std::io::stderr().is_terminal() {
+        return None;
+    }
+    let tty = std::fs::File::open("/dev/tty").ok()?;
+    let list: Vec<&str> = dirs.iter().map(|d| d.path.as_str()).collect();
+    eprint!("Create new directories {}? [y/N] ", list.join(", "));
+    let _ = std::io::stderr().flush();
+    let mut answer = String::new();
+    std::io::BufReader::new(tty).read_line(&mut answer).ok()?;
+    Some(matches!(answer.trim(), "y" | "Y" | "yes"))
+}
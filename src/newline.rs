@@ -0,0 +1,98 @@
+//! Trailing newlines (`final_newline` in the config).
+//!
+//! The applier ends every file it writes with a newline, so patching a file
+//! that had none adds one, and the next commit carries a diff line nobody
+//! meant to change. After a whole patch is applied each written file is
+//! brought in line with the setting: `preserve` (the default) gives updated
+//! files the ending they had before and leaves new files as written,
+//! `always` ends every file with a newline, and `never` ends none with one.
+//! A file that loses its trailing newline is reported.
+
+use crate::simulate::FileChange;
+use serde::Deserialize;
+use serde::Serialize;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum FinalNewline {
+    #[default]
+    Preserve,
+    Always,
+    Never,
+}
+
+impl FinalNewline {
+    pub(crate) fn as_str(self) -> &'static str {
+        match self {
+            FinalNewline::Preserve => "preserve",
+            FinalNewline::Always => "always",
+            FinalNewline::Never => "never",
+        }
+    }
+}
+
+fn ends_with_newline(text: &str) -> bool {
+    text.ends_with('\n')
+}
+
+/// Whether `policy` wants a written file to end with a newline, given
+/// whether it ended with one before the patch (`None` if it was missing or
+/// empty). `None` leaves the file as written.
+pub(crate) fn wanted(policy: FinalNewline, before: Option<bool>) -> Option<bool> {
+    match (policy, before) {
+        (FinalNewline::Always, _) => Some(true),
+        (FinalNewline::Never, _) => Some(false),
+        (FinalNewline::Preserve, before) => before,
+    }
+}
+
+/// Prints that `path` lost its trailing newline under `policy`.
+pub(crate) fn report_lost(path: &str, policy: FinalNewline) {
+    println!(
+        "{path} no longer ends with a newline (final_newline: {}).",
+        policy.as_str()
+    );
+}
+
+/// `after` with the ending `policy` asks for, given the file's contents
+/// `before` the patch.
+fn adjust(after: &str, before: Option<&str>, policy: FinalNewline) -> Option<String> {
+    let before = before
+        .filter(|before| !before.is_empty())
+        .map(ends_with_newline);
+    let want = wanted(policy, before)?;
+    if after.is_empty() || ends_with_newline(after) == want {
+        return None;
+    }
+    if want {
+        let eol = if after.contains("\r\n") { "\r\n" } else { "\n" };
+        return Some(format!("{after}{eol}"));
+    }
+    let trimmed = after
+        .strip_suffix("\r\n")
+        .or_else(|| after.strip_suffix('\n'))
+        .unwrap_or(after);
+    Some(trimmed.to_string())
+}
+
+/// Rewrites the files in `changes` (as the applier just wrote them) whose
+/// ending doesn't match `policy`, updating `changes` to match, and prints a
+/// line for each file that no longer ends with a newline.
+pub(crate) fn enforce(changes: &mut [FileChange], policy: FinalNewline) -> Result<(), String> {
+    for change in changes {
+        let Some(after) = &change.after else {
+            continue;
+        };
+        let Some(adjusted) = adjust(after, change.before.as_deref(), policy) else {
+            continue;
+        };
+        std::fs::write(&change.path, &adjusted)
+            .map_err(|err| format!("Failed to write file {}: {err}", change.path))?;
+        if change.before.as_deref().is_some_and(ends_with_newline) && !ends_with_newline(&adjusted)
+        {
+            report_lost(&change.path, policy);
+        }
+        change.after = Some(adjusted);
+    }
+    Ok(())
+}
@@ -0,0 +1,121 @@
+//! Notifications for unattended sessions (`notify` in the config).
+//!
+//! When a patch is refused, or is over the `escalate` size limit, a desktop
+//! notification is shown (`notify.desktop`, via `notify-send` on Linux and
+//! `osascript` on macOS) and/or `notify.command` is run through `sh -c`
+//! with the details in `APPLY_PATCH_NOTIFY_*` environment variables, so
+//! whoever is supervising an agent hears about it without watching the
+//! terminal. Notifier failures are reported on stderr and never change the
+//! outcome of the run.
+
+use crate::diagnostics;
+use serde::Deserialize;
+use serde::Serialize;
+use std::process::Command;
+use std::process::Stdio;
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub(crate) struct NotifyConfig {
+    #[serde(default)]
+    pub(crate) desktop: bool,
+    /// Shell command run for each notification.
+    #[serde(default)]
+    pub(crate) command: Option<String>,
+}
+
+impl NotifyConfig {
+    pub(crate) fn enabled(&self) -> bool {
+        self.desktop || self.command.is_some()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum EventKind {
+    Refused,
+    /// Over the `escalate` threshold but not refused.
+    Escalated,
+}
+
+impl EventKind {
+    fn as_str(self) -> &'static str {
+        match self {
+            EventKind::Refused => "refused",
+            EventKind::Escalated => "escalated",
+        }
+    }
+
+    fn title(self) -> &'static str {
+        match self {
+            EventKind::Refused => "apply_patch refused a patch",
+            EventKind::Escalated => "apply_patch: patch over the size limit",
+        }
+    }
+}
+
+pub(crate) struct Event<'a> {
+    pub(crate) kind: EventKind,
+    /// Refusal code, as in `REFUSED: <code> ...`.
+    pub(crate) reason: Option<&'static str>,
+    /// One-line explanation, e.g. the refusal line or escalation notice.
+    pub(crate) detail: String,
+    pub(crate) files: &'a [String],
+}
+
+/// Sends `event` through every configured notifier.
+pub(crate) fn send(cfg: &NotifyConfig, event: &Event<'_>) {
+    let cwd = std::env::current_dir()
+        .map(|dir| dir.display().to_string())
+        .unwrap_or_default();
+    let message = format!("{} ({cwd})", event.detail);
+    if cfg.desktop
+        && let Err(err) = desktop(event.kind.title(), &message)
+    {
+        diagnostics::warning(format!("desktop notification failed: {err}"));
+    }
+    if let Some(command) = &cfg.command {
+        let status = Command::new("sh")
+            .arg("-c")
+            .arg(command)
+            .env("APPLY_PATCH_NOTIFY_EVENT", event.kind.as_str())
+            .env("APPLY_PATCH_NOTIFY_TITLE", event.kind.title())
+            .env("APPLY_PATCH_NOTIFY_MESSAGE", &message)
+            .env("APPLY_PATCH_NOTIFY_REASON", event.reason.unwrap_or(""))
+            .env("APPLY_PATCH_NOTIFY_FILES", event.files.join("\n"))
+            .env("APPLY_PATCH_NOTIFY_CWD", &cwd)
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .status();
+        match status {
+            Ok(status) if status.success() => {}
+            Ok(status) => diagnostics::warning(format!("notify.command failed ({status}).")),
+            Err(err) => diagnostics::warning(format!("failed to run notify.command: {err}")),
+        }
+    }
+}
+
+fn desktop(title: &str, message: &str) -> Result<(), String> {
+    let mut cmd = if cfg!(target_os = "macos") {
+        let quote = |s: &str| s.replace('\\', "\\\\").replace('"', "\\\"");
+        let mut cmd = Command::new("osascript");
+        cmd.arg("-e").arg(format!(
+            "display notification \"{}\" with title \"{}\"",
+            quote(message),
+            quote(title)
+        ));
+        cmd
+    } else {
+        let mut cmd = Command::new("notify-send");
+        cmd.arg(title).arg(message);
+        cmd
+    };
+    let status = cmd
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .status()
+        .map_err(|err| err.to_string())?;
+    if status.success() {
+        Ok(())
+    } else {
+        Err(status.to_string())
+    }
+}
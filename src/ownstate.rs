@@ -0,0 +1,126 @@
+//! Own-state guard: refuses patches that touch apply_patch's own records.
+//!
+//! The state directory (failure streaks, stats, the patch cache, the
+//! write-ahead log), the audit log and the feedback file are resolved at run
+//! time, and can sit inside the working tree, e.g. a project-local
+//! `"state_dir": ".apply_patch/state"`. A patch writing there could rewrite
+//! the history of the patches before it, and one writing the config file
+//! the run was read from (`$APPLY_PATCH_CONFIG` or the default path, even
+//! while it doesn't exist) could lift the guards for the next one, so such
+//! targets are always refused, whatever the mode. The config has no
+//! `include`s, so that one file is all of it.
+
+use crate::Mode;
+use std::path::Component;
+use std::path::Path;
+use std::path::PathBuf;
+
+#[derive(Debug, Clone)]
+pub(crate) struct OwnStateFile {
+    /// The patch target.
+    pub(crate) path: String,
+    /// What it belongs to, e.g. `the state directory /work/.apply_patch`.
+    pub(crate) owner: String,
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct OwnStateGuard {
+    pub(crate) files: Vec<OwnStateFile>,
+    pub(crate) to: Mode,
+}
+
+impl OwnStateGuard {
+    pub(crate) fn describe(&self) -> Vec<String> {
+        self.files
+            .iter()
+            .map(|f| {
+                format!(
+                    "Own state (refuse mode): {} is {}; patches can't change apply_patch's own records.",
+                    f.path, f.owner
+                )
+            })
+            .collect()
+    }
+}
+
+/// `path` made absolute against `cwd` and without `.`/`..`, with its
+/// longest existing ancestor canonicalized so links (`/tmp` on macOS, a
+/// symlinked checkout) don't hide a match.
+pub(crate) fn resolve(cwd: &Path, path: &Path) -> PathBuf {
+    let mut lexical = PathBuf::new();
+    for component in cwd.join(path).components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                lexical.pop();
+            }
+            other => lexical.push(other),
+        }
+    }
+    let mut rest: Vec<&std::ffi::OsStr> = Vec::new();
+    let mut base = lexical.as_path();
+    loop {
+        if let Ok(canonical) = base.canonicalize() {
+            return rest
+                .iter()
+                .rev()
+                .fold(canonical, |acc, part| acc.join(part));
+        }
+        match (base.parent(), base.file_name()) {
+            (Some(parent), Some(name)) => {
+                rest.push(name);
+                base = parent;
+            }
+            _ => return lexical,
+        }
+    }
+}
+
+/// Checks `files` (relative to the current directory) against the config
+/// file, the state directory, the audit log and the feedback file. Returns
+/// `None` when none of them is touched.
+pub(crate) fn guard(
+    config: Option<&Path>,
+    state_dir: Option<&Path>,
+    audit_log: Option<&Path>,
+    tenant: Option<&str>,
+    files: &[String],
+) -> Option<OwnStateGuard> {
+    let cwd = std::env::current_dir().ok()?;
+    let mut owned: Vec<(PathBuf, String)> = Vec::new();
+    if let Some(config) = config {
+        owned.push((resolve(&cwd, config), "the active config file".to_string()));
+    }
+    if let Some(dir) = crate::state::dir(state_dir) {
+        owned.push((
+            resolve(&cwd, &dir),
+            format!("in the state directory {}", dir.display()),
+        ));
+    }
+    if let Some(log) = audit_log {
+        owned.push((resolve(&cwd, log), "the audit log".to_string()));
+    }
+    if let Some(feedback) = crate::feedback::feedback_path(tenant) {
+        owned.push((resolve(&cwd, &feedback), "the feedback file".to_string()));
+    }
+    let files: Vec<OwnStateFile> = files
+        .iter()
+        .filter_map(|file| {
+            let target = resolve(&cwd, Path::new(file));
+            owned
+                .iter()
+                .find(|(owned, _)| target.starts_with(owned))
+                .map(|(_, owner)| OwnStateFile {
+                    path: file.clone(),
+                    owner: owner.clone(),
+                })
+        })
+        .collect();
+    if files.is_empty() {
+        return None;
+    }
+    Some(OwnStateGuard {
+        files,
+        to: Mode::Refuse,
+    })
+}
@@ -0,0 +1,124 @@
+//! `--partial`: apply each file section of a patch on its own.
+//!
+//! Sections that apply cleanly stay applied; failing ones are reported and
+//! can be saved as a residual patch for another attempt, so one stale file
+//! doesn't block the rest of a large patch.
+
+use crate::Decision;
+use crate::concise;
+use crate::deletes;
+use crate::diagnostics;
+use crate::events::Event;
+use crate::events::EventStream;
+use crate::patch::Patch;
+use crate::patch::Section;
+use crate::progress::Progress;
+use std::path::Path;
+
+/// Applies every section of `patch` separately and prints a combined
+/// summary. Returns `Applied`, `Partial` or `Failed` depending on how many
+/// sections went through.
+pub(crate) fn apply_sections(
+    patch: &Patch,
+    force_delete: bool,
+    residual: Option<&Path>,
+    progress: Option<&Progress>,
+    events: Option<&EventStream>,
+) -> Decision {
+    let mut applied: Vec<&Section> = Vec::new();
+    let mut failed: Vec<(&Section, String)> = Vec::new();
+    for (n, section) in patch.sections.iter().enumerate() {
+        if let Some(progress) = progress {
+            progress.update(n, Some(&section.path));
+        }
+        match apply_section(section, force_delete) {
+            Ok(()) => {
+                if let Some(events) = events {
+                    events.emit(&Event::FileApplied {
+                        path: section.move_to().unwrap_or(&section.path),
+                        status: section.status(),
+                    });
+                }
+                applied.push(section);
+            }
+            Err(err) => failed.push((section, err)),
+        }
+    }
+    if let Some(progress) = progress {
+        progress.update(patch.sections.len(), None);
+    }
+
+    let lines: Vec<String> = applied
+        .iter()
+        .map(|section| {
+            format!(
+                "{} {}",
+                section.status(),
+                section.move_to().unwrap_or(&section.path)
+            )
+        })
+        .collect();
+    if failed.is_empty() {
+        concise::print_success(&lines);
+    } else {
+        println!(
+            "Applied {} of {} file sections.",
+            applied.len(),
+            patch.sections.len()
+        );
+        if !applied.is_empty() {
+            println!("Updated the following files:");
+        }
+        for line in &lines {
+            println!("{line}");
+        }
+    }
+    if !failed.is_empty() {
+        println!("Failed to apply:");
+        for (section, err) in &failed {
+            let reason = err.lines().next().unwrap_or_default();
+            println!("{} {}: {reason}", section.status(), section.path);
+        }
+    }
+
+    if let Some(path) = residual
+        && !failed.is_empty()
+    {
+        let rest = Patch {
+            sections: failed
+                .iter()
+                .map(|(section, _)| (*section).clone())
+                .collect(),
+        };
+        match std::fs::write(path, rest.render()) {
+            Ok(()) => println!("Residual patch written to {}", path.display()),
+            Err(err) => diagnostics::warning(format!(
+                "failed to write residual patch {}: {err}",
+                path.display()
+            )),
+        }
+    }
+
+    match (applied.is_empty(), failed.is_empty()) {
+        (_, true) => Decision::Applied,
+        (true, false) => Decision::Failed,
+        (false, false) => Decision::Partial,
+    }
+}
+
+fn apply_section(section: &Section, force_delete: bool) -> Result<(), String> {
+    let single = Patch {
+        sections: vec![section.clone()],
+    };
+    let text = deletes::verify_deletes(&single.render(), force_delete)?;
+    let mut stdout: Vec<u8> = Vec::new();
+    let mut stderr: Vec<u8> = Vec::new();
+    codex_apply_patch::apply_patch(&text, &mut stdout, &mut stderr).map_err(|err| {
+        let message = String::from_utf8_lossy(&stderr).trim().to_string();
+        if message.is_empty() {
+            err.to_string()
+        } else {
+            message
+        }
+    })
+}
@@ -0,0 +1,310 @@
+//! Lightweight structural view of a Codex-style patch.
+//!
+//! The vendored applier stays the authority on whether a patch is valid and
+//! how it is applied. This module only splits the envelope into per-file
+//! sections so guardrails can inspect them (and strip extensions the applier
+//! does not understand) before the text is handed over.
+
+pub(crate) const BEGIN_PATCH_MARKER: &str = "*** Begin Patch";
+pub(crate) const END_PATCH_MARKER: &str = "*** End Patch";
+pub(crate) const ADD_FILE_MARKER: &str = "*** Add File: ";
+pub(crate) const DELETE_FILE_MARKER: &str = "*** Delete File: ";
+pub(crate) const UPDATE_FILE_MARKER: &str = "*** Update File: ";
+pub(crate) const MOVE_TO_MARKER: &str = "*** Move to: ";
+pub(crate) const EOF_MARKER: &str = "*** End of File";
+const CHANGE_CONTEXT_MARKER: &str = "@@ ";
+const EMPTY_CHANGE_CONTEXT_MARKER: &str = "@@";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum SectionKind {
+    Add,
+    Delete,
+    Update,
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct Section {
+    pub(crate) kind: SectionKind,
+    pub(crate) path: String,
+    /// The header line exactly as it appeared in the patch.
+    pub(crate) header: String,
+    /// Every line after the header up to the next section header.
+    pub(crate) body: Vec<String>,
+}
+
+impl Section {
+    /// Destination of the section's `*** Move to:` line, if any.
+    pub(crate) fn move_to(&self) -> Option<&str> {
+        if self.kind != SectionKind::Update {
+            return None;
+        }
+        self.body.first()?.strip_prefix(MOVE_TO_MARKER)
+    }
+
+    /// Single-letter status as printed in the applier's summary.
+    pub(crate) fn status(&self) -> char {
+        match self.kind {
+            SectionKind::Add => 'A',
+            SectionKind::Delete => 'D',
+            SectionKind::Update => 'M',
+        }
+    }
+
+    /// Number of added and removed lines in the section. Delete sections
+    /// count as zero because their body (if any) is only an expectation.
+    pub(crate) fn line_counts(&self) -> (usize, usize) {
+        if self.kind == SectionKind::Delete {
+            return (0, 0);
+        }
+        let added = self.body.iter().filter(|l| l.starts_with('+')).count();
+        let removed = self.body.iter().filter(|l| l.starts_with('-')).count();
+        (added, removed)
+    }
+
+    /// Contents written by an add section: every leading `+` line.
+    pub(crate) fn added_contents(&self) -> String {
+        let mut contents = String::new();
+        for line in &self.body {
+            let Some(text) = line.strip_prefix('+') else {
+                break;
+            };
+            contents.push_str(text);
+            contents.push('\n');
+        }
+        contents
+    }
+
+    /// Parses the chunks of an update section with the same rules as the
+    /// applier, so in-memory previews match what would be written.
+    pub(crate) fn chunks(&self) -> Result<Vec<Chunk>, String> {
+        let mut remaining: &[String] = &self.body;
+        if self.move_to().is_some() {
+            remaining = &remaining[1..];
+        }
+        let mut chunks = Vec::new();
+        while let Some(first) = remaining.first() {
+            if first.trim().is_empty() {
+                remaining = &remaining[1..];
+                continue;
+            }
+            if first.starts_with("***") {
+                break;
+            }
+            let (chunk, consumed) = parse_chunk(remaining, chunks.is_empty())?;
+            chunks.push(chunk);
+            remaining = &remaining[consumed..];
+        }
+        if chunks.is_empty() {
+            return Err(format!(
+                "Update file hunk for path '{}' is empty",
+                self.path
+            ));
+        }
+        Ok(chunks)
+    }
+}
+
+fn parse_chunk(lines: &[String], allow_missing_context: bool) -> Result<(Chunk, usize), String> {
+    let (change_context, start) = if lines[0] == EMPTY_CHANGE_CONTEXT_MARKER {
+        (None, 1)
+    } else if let Some(context) = lines[0].strip_prefix(CHANGE_CONTEXT_MARKER) {
+        (Some(context.to_string()), 1)
+    } else if allow_missing_context {
+        (None, 0)
+    } else {
+        return Err(format!(
+            "Expected update hunk to start with a @@ context marker, got: '{}'",
+            lines[0]
+        ));
+    };
+    if start >= lines.len() {
+        return Err("Update hunk does not contain any lines".to_string());
+    }
+
+    let mut chunk = Chunk {
+        change_context,
+        old_lines: Vec::new(),
+        new_lines: Vec::new(),
+        is_end_of_file: false,
+    };
+    let mut parsed = 0;
+    for line in &lines[start..] {
+        if line == EOF_MARKER {
+            if parsed == 0 {
+                return Err("Update hunk does not contain any lines".to_string());
+            }
+            chunk.is_end_of_file = true;
+            parsed += 1;
+            break;
+        }
+        match line.chars().next() {
+            None => {
+                chunk.old_lines.push(String::new());
+                chunk.new_lines.push(String::new());
+            }
+            Some(' ') => {
+                chunk.old_lines.push(line[1..].to_string());
+                chunk.new_lines.push(line[1..].to_string());
+            }
+            Some('+') => chunk.new_lines.push(line[1..].to_string()),
+            Some('-') => chunk.old_lines.push(line[1..].to_string()),
+            Some(_) => {
+                if parsed == 0 {
+                    return Err(format!(
+                        "Unexpected line found in update hunk: '{line}'. Every line should start with ' ' (context line), '+' (added line), or '-' (removed line)"
+                    ));
+                }
+                break;
+            }
+        }
+        parsed += 1;
+    }
+    Ok((chunk, parsed + start))
+}
+
+/// One `@@` chunk of an update section, as the applier interprets it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Chunk {
+    pub(crate) change_context: Option<String>,
+    pub(crate) old_lines: Vec<String>,
+    pub(crate) new_lines: Vec<String>,
+    pub(crate) is_end_of_file: bool,
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct Patch {
+    pub(crate) sections: Vec<Section>,
+}
+
+impl Patch {
+    /// Splits `text` into sections. Returns `None` when the envelope is not
+    /// recognizable; callers should then pass the text through untouched and
+    /// let the applier report the problem.
+    pub(crate) fn parse(text: &str) -> Option<Self> {
+        let lines: Vec<&str> = text.trim().lines().collect();
+        let inner = envelope_body(&lines)?;
+
+        let mut sections: Vec<Section> = Vec::new();
+        for line in inner {
+            if let Some((kind, path)) = section_header(line) {
+                sections.push(Section {
+                    kind,
+                    path: path.to_string(),
+                    header: (*line).to_string(),
+                    body: Vec::new(),
+                });
+                continue;
+            }
+            sections.last_mut()?.body.push((*line).to_string());
+        }
+        Some(Self { sections })
+    }
+
+    /// Every path the patch reads or writes, including move destinations,
+    /// in patch order without duplicates.
+    pub(crate) fn touched_paths(&self) -> Vec<String> {
+        let mut paths: Vec<String> = Vec::new();
+        for section in &self.sections {
+            for path in std::iter::once(section.path.as_str()).chain(section.move_to()) {
+                if !paths.iter().any(|p| p == path) {
+                    paths.push(path.to_string());
+                }
+            }
+        }
+        paths
+    }
+
+    /// The lines the applier prints after `Success. Updated the following
+    /// files:`: added, then modified (by destination), then deleted paths.
+    pub(crate) fn applied_summary(&self) -> Vec<String> {
+        let mut summary: Vec<String> = Vec::new();
+        for status in ['A', 'M', 'D'] {
+            for section in self.sections.iter().filter(|s| s.status() == status) {
+                let path = match status {
+                    'M' => section.move_to().unwrap_or(&section.path),
+                    _ => &section.path,
+                };
+                summary.push(format!("{status} {path}"));
+            }
+        }
+        summary
+    }
+
+    /// One line per section: status, path (with any move destination) and
+    /// line counts, e.g. `M src/lib.rs -> src/core.rs (+3 -1)`.
+    pub(crate) fn summary(&self) -> String {
+        let mut out = String::new();
+        for section in &self.sections {
+            out.push(section.status());
+            out.push(' ');
+            out.push_str(&section.path);
+            if let Some(dest) = section.move_to() {
+                out.push_str(" -> ");
+                out.push_str(dest);
+            }
+            if section.kind != SectionKind::Delete {
+                let (added, removed) = section.line_counts();
+                out.push_str(&format!(" (+{added} -{removed})"));
+            }
+            out.push('\n');
+        }
+        out
+    }
+
+    /// Renders the sections back into a canonical patch envelope.
+    pub(crate) fn render(&self) -> String {
+        let mut out = String::new();
+        out.push_str(BEGIN_PATCH_MARKER);
+        out.push('\n');
+        for section in &self.sections {
+            out.push_str(&section.header);
+            out.push('\n');
+            for line in &section.body {
+                out.push_str(line);
+                out.push('\n');
+            }
+        }
+        out.push_str(END_PATCH_MARKER);
+        out.push('\n');
+        out
+    }
+}
+
+/// Returns the lines between the begin/end markers, accepting the same
+/// heredoc wrapper (`<<'EOF'` … `EOF`) that the applier tolerates.
+fn envelope_body<'a>(lines: &'a [&'a str]) -> Option<&'a [&'a str]> {
+    let is_envelope = |l: &[&str]| {
+        l.len() >= 2 && l[0] == BEGIN_PATCH_MARKER && l[l.len() - 1] == END_PATCH_MARKER
+    };
+    if is_envelope(lines) {
+        return Some(&lines[1..lines.len() - 1]);
+    }
+    if lines.len() >= 4
+        && matches!(lines[0], "<<EOF" | "<<'EOF'" | "<<\"EOF\"")
+        && lines[lines.len() - 1].ends_with("EOF")
+    {
+        let inner = &lines[1..lines.len() - 1];
+        if is_envelope(inner) {
+            return Some(&inner[1..inner.len() - 1]);
+        }
+    }
+    None
+}
+
+fn section_header(line: &str) -> Option<(SectionKind, &str)> {
+    // Body lines always start with a diff prefix; headers never do.
+    if line.starts_with([' ', '+', '-']) {
+        return None;
+    }
+    let trimmed = line.trim();
+    if let Some(path) = trimmed.strip_prefix(ADD_FILE_MARKER) {
+        return Some((SectionKind::Add, path));
+    }
+    if let Some(path) = trimmed.strip_prefix(DELETE_FILE_MARKER) {
+        return Some((SectionKind::Delete, path));
+    }
+    if let Some(path) = trimmed.strip_prefix(UPDATE_FILE_MARKER) {
+        return Some((SectionKind::Update, path));
+    }
+    None
+}
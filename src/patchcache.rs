@@ -0,0 +1,259 @@
+//! Content-addressed patch cache (`patch_cache` in the config).
+//!
+//! Evaluation harnesses often reset a checkout and re-apply the same large
+//! patch every run. With the cache on, each whole patch that applies is
+//! stored under `patch-cache/` in the state directory, keyed by the SHA-256
+//! of its normalized text, with the hash of every touched file beforehand
+//! and its contents afterwards. When the same patch comes again and every
+//! file still hashes to what it was, the stored contents are written
+//! directly, skipping context matching, and read back to verify them. Any
+//! difference falls back to a normal apply. `apply_patch cache list` and
+//! `apply_patch cache clear` inspect and empty the cache.
+
+use crate::concise;
+use crate::diagnostics;
+use crate::digest::sha256_hex;
+use crate::exit::Exit;
+use crate::jsonl;
+use crate::patch::Patch;
+use crate::simulate::FileChange;
+use crate::verify;
+use serde::Deserialize;
+use serde::Serialize;
+use std::path::Path;
+use std::path::PathBuf;
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Entry {
+    created: u64,
+    #[serde(default)]
+    hits: u64,
+    files: Vec<CachedFile>,
+    /// The summary lines the apply printed, e.g. `M src/lib.rs`.
+    summary: Vec<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct CachedFile {
+    path: String,
+    /// Hash of the contents before the patch; `None` if it didn't exist.
+    before: Option<String>,
+    /// Contents after the patch; `None` if it was deleted.
+    after: Option<String>,
+}
+
+pub(crate) struct PatchCache {
+    dir: PathBuf,
+}
+
+impl PatchCache {
+    /// The cache under the state directory, or `None` without one.
+    pub(crate) fn open(state_dir: Option<&Path>) -> Option<Self> {
+        Some(Self {
+            dir: crate::state::dir(state_dir)?.join("patch-cache"),
+        })
+    }
+
+    fn entry_path(&self, patch_text: &str) -> PathBuf {
+        self.dir.join(format!(
+            "{}.json",
+            sha256_hex(normalize(patch_text).as_bytes())
+        ))
+    }
+
+    /// Applies `patch_text` from the cache if it has an entry whose
+    /// starting hashes match the working tree. `None` means the caller
+    /// should apply it normally; otherwise the changes written, with their
+    /// previous contents, or why verification failed.
+    pub(crate) fn replay(
+        &self,
+        patch_text: &str,
+    ) -> Option<Result<Vec<FileChange>, (Exit, String)>> {
+        let path = self.entry_path(patch_text);
+        let mut entry: Entry = serde_json::from_slice(&std::fs::read(&path).ok()?).ok()?;
+        let mut changes = Vec::with_capacity(entry.files.len());
+        for file in &entry.files {
+            let before = match std::fs::read(&file.path) {
+                Ok(bytes) => Some(String::from_utf8(bytes).ok()?),
+                Err(_) => None,
+            };
+            if before.as_deref().map(|text| sha256_hex(text.as_bytes())) != file.before {
+                return None;
+            }
+            changes.push(FileChange {
+                path: file.path.clone(),
+                before,
+                after: file.after.clone(),
+            });
+        }
+        for change in &changes {
+            let written = match &change.after {
+                Some(contents) => Path::new(&change.path)
+                    .parent()
+                    .filter(|parent| !parent.as_os_str().is_empty())
+                    .map_or(Ok(()), std::fs::create_dir_all)
+                    .and_then(|()| std::fs::write(&change.path, contents)),
+                None => std::fs::remove_file(&change.path),
+            };
+            if let Err(err) = written {
+                let msg = format!(
+                    "Failed to write {} from the patch cache: {err}",
+                    change.path
+                );
+                eprintln!("{msg}");
+                return Some(Err((Exit::Failed, msg)));
+            }
+        }
+        if let Err(msg) = verify::check(&changes) {
+            eprintln!("{msg}");
+            return Some(Err((Exit::Failed, msg)));
+        }
+        concise::print_success(&entry.summary);
+        entry.hits += 1;
+        write_entry(&path, &entry);
+        Some(Ok(changes))
+    }
+
+    /// Records a successful apply of `patch_text`. `changes` is its
+    /// simulated result; the stored contents are read back from disk so
+    /// they are exactly what the applier wrote.
+    pub(crate) fn store(&self, patch_text: &str, changes: &[FileChange]) {
+        let Some(patch) = Patch::parse(patch_text) else {
+            return;
+        };
+        let mut files = Vec::with_capacity(changes.len());
+        for change in changes {
+            let after = match &change.after {
+                Some(_) => match std::fs::read_to_string(&change.path) {
+                    Ok(contents) => Some(contents),
+                    Err(_) => return,
+                },
+                None => None,
+            };
+            files.push(CachedFile {
+                path: change.path.clone(),
+                before: change
+                    .before
+                    .as_deref()
+                    .map(|text| sha256_hex(text.as_bytes())),
+                after,
+            });
+        }
+        let entry = Entry {
+            created: jsonl::timestamp(),
+            hits: 0,
+            files,
+            summary: patch.applied_summary(),
+        };
+        write_entry(&self.entry_path(patch_text), &entry);
+    }
+
+    fn entries(&self) -> Vec<(String, u64, Entry)> {
+        let Ok(dir) = std::fs::read_dir(&self.dir) else {
+            return Vec::new();
+        };
+        let mut entries: Vec<(String, u64, Entry)> = dir
+            .flatten()
+            .filter_map(|item| {
+                let name = item.file_name().to_string_lossy().into_owned();
+                let digest = name.strip_suffix(".json")?.to_string();
+                let bytes = std::fs::read(item.path()).ok()?;
+                let entry = serde_json::from_slice(&bytes).ok()?;
+                Some((digest, bytes.len() as u64, entry))
+            })
+            .collect();
+        entries.sort_by(|a, b| a.2.created.cmp(&b.2.created).then(a.0.cmp(&b.0)));
+        entries
+    }
+}
+
+/// Line endings and surrounding whitespace don't change a patch's meaning,
+/// so they don't change its key either.
+fn normalize(patch_text: &str) -> String {
+    patch_text
+        .trim()
+        .lines()
+        .map(|line| line.trim_end_matches('\r'))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Cache writes are best effort: a failure only costs the next run a
+/// normal apply.
+fn write_entry(path: &Path, entry: &Entry) {
+    let result = (|| {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let tmp = path.with_extension("json.tmp");
+        std::fs::write(&tmp, serde_json::to_vec(entry)?)?;
+        std::fs::rename(&tmp, path)
+    })();
+    if let Err(err) = result {
+        diagnostics::warning(format!(
+            "failed to write patch cache entry {}: {err}",
+            path.display()
+        ));
+    }
+}
+
+/// `apply_patch cache list|clear`: shows or empties the patch cache.
+pub(crate) fn run_cache(args: &[String]) -> i32 {
+    let action = match args {
+        [action] if action == "list" || action == "clear" => action.as_str(),
+        [] => {
+            eprintln!("Usage: apply_patch cache <list|clear>");
+            return 2;
+        }
+        [arg, ..] => {
+            diagnostics::error(format!("unknown option: {arg}"));
+            return 2;
+        }
+    };
+    let cfg = match crate::effective_config(&crate::RunOptions::default()) {
+        Ok(cfg) => cfg,
+        Err(err) => {
+            diagnostics::error(err);
+            return Exit::ConfigError.code();
+        }
+    };
+    let Some(cache) = PatchCache::open(cfg.state_dir.as_deref()) else {
+        diagnostics::error(
+            "could not determine the state directory (HOME/XDG_STATE_HOME not set).",
+        );
+        return 1;
+    };
+    let entries = cache.entries();
+    if action == "clear" {
+        let mut code = 0;
+        for (digest, _, _) in &entries {
+            let path = cache.dir.join(format!("{digest}.json"));
+            if let Err(err) = std::fs::remove_file(&path) {
+                diagnostics::error(format!("failed to remove {}: {err}", path.display()));
+                code = 1;
+            }
+        }
+        println!(
+            "Removed {} cached patches from {}.",
+            entries.len(),
+            cache.dir.display()
+        );
+        return code;
+    }
+    let total: u64 = entries.iter().map(|(_, size, _)| size).sum();
+    println!(
+        "{} cached patches ({total} bytes) in {}",
+        entries.len(),
+        cache.dir.display()
+    );
+    for (digest, size, entry) in &entries {
+        println!(
+            "{}  {} files  {size} bytes  {} hits  created {}",
+            &digest[..12.min(digest.len())],
+            entry.files.len(),
+            entry.hits,
+            entry.created
+        );
+    }
+    0
+}
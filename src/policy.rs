@@ -0,0 +1,471 @@
+//! Policy rules: a small expression language evaluated against patch facts.
+//!
+//! A rule pairs a `when` expression with the mode to use when it matches,
+//! e.g. `files ~ 'src/**' && lines_changed > 500 && agent == 'codex'`.
+//! Rules are tried in order and the first match wins; when none match the
+//! configured base mode applies.
+//!
+//! Grammar:
+//!
+//! ```text
+//! expr       := and ( "||" and )*
+//! and        := unary ( "&&" unary )*
+//! unary      := "!" unary | "(" expr ")" | comparison
+//! comparison := IDENT OP ( STRING | INTEGER )
+//! OP         := "==" | "!=" | "<" | "<=" | ">" | ">=" | "~" | "!~"
+//! ```
+//!
+//! `~` is a glob match. Against `files` it holds when any touched path
+//! matches (`!~` when none does), and `==`/`!=` test membership.
+//!
+//! After rules pick a mode, size-based escalation (`escalate` in the config)
+//! can still raise it for large patches, but never lowers it.
+//!
+//! Rules and escalation marked `shadow` are evaluated but not enforced: the
+//! mode they would have produced is recorded (see [`shadow`]) so a stricter
+//! policy can be trialled against real traffic before it is turned on.
+
+use crate::Mode;
+use crate::glob::glob_match;
+use crate::patch::Patch;
+use crate::patch::SectionKind;
+use serde::Deserialize;
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+const AGENT_ENV: &str = "APPLY_PATCH_AGENT";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct PolicyRule {
+    pub(crate) when: String,
+    pub(crate) mode: Mode,
+    /// Evaluate and record this rule without enforcing it.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub(crate) shadow: bool,
+}
+
+/// `escalate` config: patches changing at least `large_patch_lines` lines
+/// are enforced with mode `to` instead of the (less strict) selected mode.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct EscalateConfig {
+    #[serde(default)]
+    pub(crate) large_patch_lines: Option<usize>,
+    #[serde(default = "default_escalate_to")]
+    pub(crate) to: Mode,
+    /// Evaluate and record the escalation without enforcing it.
+    #[serde(default)]
+    pub(crate) shadow: bool,
+}
+
+fn default_escalate_to() -> Mode {
+    Mode::Warn
+}
+
+impl Default for EscalateConfig {
+    fn default() -> Self {
+        Self {
+            large_patch_lines: None,
+            to: default_escalate_to(),
+            shadow: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct Escalation {
+    pub(crate) lines_changed: usize,
+    pub(crate) threshold: usize,
+    pub(crate) from: Mode,
+    pub(crate) to: Mode,
+}
+
+impl Escalation {
+    pub(crate) fn describe(&self) -> String {
+        format!(
+            "Escalated to {} mode: this patch changes {} lines (threshold: {}).",
+            self.to.as_str(),
+            self.lines_changed,
+            self.threshold
+        )
+    }
+}
+
+pub(crate) fn severity(mode: Mode) -> u8 {
+    match mode {
+        Mode::Apply => 0,
+        Mode::Warn => 1,
+        Mode::Forward => 2,
+        Mode::Refuse => 3,
+    }
+}
+
+/// Returns the escalation to apply on top of `mode`, if the patch is large
+/// enough and the escalation target is stricter than `mode`.
+pub(crate) fn escalate(cfg: &EscalateConfig, facts: &PatchFacts, mode: Mode) -> Option<Escalation> {
+    if cfg.shadow {
+        return None;
+    }
+    raise(cfg, facts, mode)
+}
+
+fn raise(cfg: &EscalateConfig, facts: &PatchFacts, mode: Mode) -> Option<Escalation> {
+    let threshold = cfg.large_patch_lines?;
+    let lines_changed = facts.lines_changed();
+    if lines_changed < threshold || severity(cfg.to) <= severity(mode) {
+        return None;
+    }
+    Some(Escalation {
+        lines_changed,
+        threshold,
+        from: mode,
+        to: cfg.to,
+    })
+}
+
+/// Facts about the current patch that expressions can refer to.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct PatchFacts {
+    pub(crate) files: Vec<String>,
+    pub(crate) adds: usize,
+    pub(crate) deletes: usize,
+    pub(crate) updates: usize,
+    pub(crate) lines_added: usize,
+    pub(crate) lines_removed: usize,
+    pub(crate) risk: u32,
+    pub(crate) agent: String,
+    /// `metadata` from a tool-call payload (`--input tool-json`) and the
+    /// patch's trailers.
+    pub(crate) metadata: BTreeMap<String, serde_json::Value>,
+}
+
+impl PatchFacts {
+    pub(crate) fn collect(patch: Option<&Patch>) -> Self {
+        let mut facts = Self {
+            agent: std::env::var(AGENT_ENV).unwrap_or_default(),
+            ..Self::default()
+        };
+        let Some(patch) = patch else {
+            return facts;
+        };
+        facts.files = patch.touched_paths();
+        for section in &patch.sections {
+            match section.kind {
+                SectionKind::Add => facts.adds += 1,
+                SectionKind::Delete => facts.deletes += 1,
+                SectionKind::Update => facts.updates += 1,
+            }
+            let (added, removed) = section.line_counts();
+            facts.lines_added += added;
+            facts.lines_removed += removed;
+        }
+        facts.risk = crate::risk::assess(patch).score;
+        facts
+    }
+
+    pub(crate) fn lines_changed(&self) -> usize {
+        self.lines_added + self.lines_removed
+    }
+
+    fn lookup(&self, name: &str) -> Option<Value> {
+        let int = |n: usize| Some(Value::Int(n as i64));
+        match name {
+            "files" => Some(Value::List(self.files.clone())),
+            "file_count" => int(self.files.len()),
+            "adds" => int(self.adds),
+            "deletes" => int(self.deletes),
+            "updates" => int(self.updates),
+            "lines_added" => int(self.lines_added),
+            "lines_removed" => int(self.lines_removed),
+            "lines_changed" => int(self.lines_changed()),
+            "risk" => int(self.risk as usize),
+            "agent" => Some(Value::Str(self.agent.clone())),
+            _ => None,
+        }
+    }
+}
+
+/// Returns the index and mode of the first rule whose expression holds, or
+/// an error naming the rule that failed to parse or evaluate.
+pub(crate) fn evaluate(
+    rules: &[PolicyRule],
+    facts: &PatchFacts,
+) -> Result<Option<(usize, Mode)>, String> {
+    first_match(
+        rules.iter().enumerate().filter(|(_, rule)| !rule.shadow),
+        facts,
+    )
+}
+
+/// What the policy stage would have enforced with shadow rules and
+/// escalation turned on.
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct ShadowDecision {
+    pub(crate) mode: Mode,
+    /// Index of the rule that would have selected the mode, if any.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) policy: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) escalation: Option<Escalation>,
+}
+
+/// Evaluates every rule and the escalation, shadow or not, starting from
+/// `base`. Returns `None` when nothing is marked `shadow` or the result is
+/// the `enforced` mode anyway.
+pub(crate) fn shadow(
+    rules: &[PolicyRule],
+    escalate: &EscalateConfig,
+    facts: &PatchFacts,
+    base: Mode,
+    enforced: Mode,
+) -> Result<Option<ShadowDecision>, String> {
+    if !escalate.shadow && !rules.iter().any(|rule| rule.shadow) {
+        return Ok(None);
+    }
+    let selected = first_match(rules.iter().enumerate(), facts)?;
+    let mode = selected.map_or(base, |(_, mode)| mode);
+    let escalation = raise(escalate, facts, mode);
+    let mode = escalation.as_ref().map_or(mode, |escalation| escalation.to);
+    Ok((mode != enforced).then(|| ShadowDecision {
+        mode,
+        policy: selected.map(|(idx, _)| idx),
+        escalation,
+    }))
+}
+
+fn first_match<'a>(
+    rules: impl Iterator<Item = (usize, &'a PolicyRule)>,
+    facts: &PatchFacts,
+) -> Result<Option<(usize, Mode)>, String> {
+    for (idx, rule) in rules {
+        let matched = Expr::parse(&rule.when)
+            .and_then(|expr| expr.eval(facts))
+            .map_err(|err| format!("invalid policy expression `{}`: {err}", rule.when))?;
+        if matched {
+            return Ok(Some((idx, rule.mode)));
+        }
+    }
+    Ok(None)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Value {
+    Int(i64),
+    Str(String),
+    List(Vec<String>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Op {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Glob,
+    NotGlob,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Literal {
+    Int(i64),
+    Str(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Ident(String),
+    Literal(Literal),
+    Op(Op),
+    And,
+    Or,
+    Not,
+    LParen,
+    RParen,
+}
+
+#[derive(Debug)]
+enum Expr {
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+    Not(Box<Expr>),
+    Compare {
+        ident: String,
+        op: Op,
+        literal: Literal,
+    },
+}
+
+impl Expr {
+    fn parse(src: &str) -> Result<Self, String> {
+        let tokens = tokenize(src)?;
+        let mut parser = Parser { tokens, pos: 0 };
+        let expr = parser.or()?;
+        if let Some(tok) = parser.tokens.get(parser.pos) {
+            return Err(format!("unexpected token {tok:?}"));
+        }
+        Ok(expr)
+    }
+
+    fn eval(&self, facts: &PatchFacts) -> Result<bool, String> {
+        match self {
+            Expr::And(a, b) => Ok(a.eval(facts)? && b.eval(facts)?),
+            Expr::Or(a, b) => Ok(a.eval(facts)? || b.eval(facts)?),
+            Expr::Not(e) => Ok(!e.eval(facts)?),
+            Expr::Compare { ident, op, literal } => {
+                let value = facts
+                    .lookup(ident)
+                    .ok_or_else(|| format!("unknown variable `{ident}`"))?;
+                compare(ident, &value, *op, literal)
+            }
+        }
+    }
+}
+
+fn compare(ident: &str, value: &Value, op: Op, literal: &Literal) -> Result<bool, String> {
+    match (value, literal) {
+        (Value::Int(v), Literal::Int(n)) => match op {
+            Op::Eq => Ok(v == n),
+            Op::Ne => Ok(v != n),
+            Op::Lt => Ok(v < n),
+            Op::Le => Ok(v <= n),
+            Op::Gt => Ok(v > n),
+            Op::Ge => Ok(v >= n),
+            Op::Glob | Op::NotGlob => Err(format!("`{ident}` is a number; `~` needs a string")),
+        },
+        (Value::Str(v), Literal::Str(s)) => match op {
+            Op::Eq => Ok(v == s),
+            Op::Ne => Ok(v != s),
+            Op::Glob => Ok(glob_match(s, v)),
+            Op::NotGlob => Ok(!glob_match(s, v)),
+            _ => Err(format!("`{ident}` is a string; use ==, !=, ~ or !~")),
+        },
+        (Value::List(items), Literal::Str(s)) => match op {
+            Op::Eq => Ok(items.iter().any(|i| i == s)),
+            Op::Ne => Ok(!items.iter().any(|i| i == s)),
+            Op::Glob => Ok(items.iter().any(|i| glob_match(s, i))),
+            Op::NotGlob => Ok(!items.iter().any(|i| glob_match(s, i))),
+            _ => Err(format!("`{ident}` is a list; use ==, !=, ~ or !~")),
+        },
+        (Value::Int(_), Literal::Str(_)) => Err(format!("`{ident}` must be compared to a number")),
+        (_, Literal::Int(_)) => Err(format!("`{ident}` must be compared to a string")),
+    }
+}
+
+fn tokenize(src: &str) -> Result<Vec<Token>, String> {
+    let chars: Vec<char> = src.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        let next = chars.get(i + 1).copied();
+        let (token, width) = match (c, next) {
+            (c, _) if c.is_whitespace() => {
+                i += 1;
+                continue;
+            }
+            ('&', Some('&')) => (Token::And, 2),
+            ('|', Some('|')) => (Token::Or, 2),
+            ('=', Some('=')) => (Token::Op(Op::Eq), 2),
+            ('!', Some('=')) => (Token::Op(Op::Ne), 2),
+            ('!', Some('~')) => (Token::Op(Op::NotGlob), 2),
+            ('<', Some('=')) => (Token::Op(Op::Le), 2),
+            ('>', Some('=')) => (Token::Op(Op::Ge), 2),
+            ('<', _) => (Token::Op(Op::Lt), 1),
+            ('>', _) => (Token::Op(Op::Gt), 1),
+            ('~', _) => (Token::Op(Op::Glob), 1),
+            ('!', _) => (Token::Not, 1),
+            ('(', _) => (Token::LParen, 1),
+            (')', _) => (Token::RParen, 1),
+            ('\'' | '"', _) => {
+                let end = chars[i + 1..]
+                    .iter()
+                    .position(|ch| *ch == c)
+                    .ok_or("unterminated string literal")?;
+                let text: String = chars[i + 1..i + 1 + end].iter().collect();
+                (Token::Literal(Literal::Str(text)), end + 2)
+            }
+            (c, _) if c.is_ascii_digit() => {
+                let len = chars[i..]
+                    .iter()
+                    .take_while(|ch| ch.is_ascii_digit())
+                    .count();
+                let text: String = chars[i..i + len].iter().collect();
+                let n = text
+                    .parse::<i64>()
+                    .map_err(|_| format!("number out of range: {text}"))?;
+                (Token::Literal(Literal::Int(n)), len)
+            }
+            (c, _) if c.is_ascii_alphabetic() || c == '_' => {
+                let len = chars[i..]
+                    .iter()
+                    .take_while(|ch| ch.is_ascii_alphanumeric() || **ch == '_')
+                    .count();
+                (Token::Ident(chars[i..i + len].iter().collect()), len)
+            }
+            (c, _) => return Err(format!("unexpected character `{c}`")),
+        };
+        tokens.push(token);
+        i += width;
+    }
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn next(&mut self) -> Option<Token> {
+        let tok = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        tok
+    }
+
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn or(&mut self) -> Result<Expr, String> {
+        let mut lhs = self.and()?;
+        while self.peek() == Some(&Token::Or) {
+            self.pos += 1;
+            lhs = Expr::Or(Box::new(lhs), Box::new(self.and()?));
+        }
+        Ok(lhs)
+    }
+
+    fn and(&mut self) -> Result<Expr, String> {
+        let mut lhs = self.unary()?;
+        while self.peek() == Some(&Token::And) {
+            self.pos += 1;
+            lhs = Expr::And(Box::new(lhs), Box::new(self.unary()?));
+        }
+        Ok(lhs)
+    }
+
+    fn unary(&mut self) -> Result<Expr, String> {
+        match self.next() {
+            Some(Token::Not) => Ok(Expr::Not(Box::new(self.unary()?))),
+            Some(Token::LParen) => {
+                let expr = self.or()?;
+                match self.next() {
+                    Some(Token::RParen) => Ok(expr),
+                    _ => Err("expected `)`".to_string()),
+                }
+            }
+            Some(Token::Ident(ident)) => {
+                let Some(Token::Op(op)) = self.next() else {
+                    return Err(format!("expected an operator after `{ident}`"));
+                };
+                let Some(Token::Literal(literal)) = self.next() else {
+                    return Err(format!("expected a string or number after `{ident}`"));
+                };
+                Ok(Expr::Compare { ident, op, literal })
+            }
+            Some(tok) => Err(format!("unexpected token {tok:?}")),
+            None => Err("unexpected end of expression".to_string()),
+        }
+    }
+}
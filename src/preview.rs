@@ -0,0 +1,105 @@
+//! `apply_patch preview --out DIR [PATCH]`: writes `before/<path>` and
+//! `after/<path>` copies of every file the patch touches into DIR, without
+//! modifying the working tree, so external diff tools can inspect it.
+
+use crate::diagnostics;
+use crate::patch::Patch;
+use crate::simulate::simulate;
+use std::path::Component;
+use std::path::Path;
+use std::path::PathBuf;
+
+pub(crate) fn run_preview(args: &[String]) -> i32 {
+    let mut out_dir: Option<PathBuf> = None;
+    let mut positional: Vec<String> = Vec::new();
+    let mut i = 0;
+    while i < args.len() {
+        match args[i].as_str() {
+            "--out" => {
+                let Some(dir) = args.get(i + 1) else {
+                    diagnostics::error("--out requires a value.");
+                    return 2;
+                };
+                out_dir = Some(PathBuf::from(dir));
+                i += 2;
+            }
+            "--" => {
+                positional.extend(args[i + 1..].iter().cloned());
+                break;
+            }
+            arg if arg.starts_with('-') => {
+                diagnostics::error(format!("unknown option: {arg}"));
+                return 2;
+            }
+            arg => {
+                positional.push(arg.to_string());
+                i += 1;
+            }
+        }
+    }
+    let Some(out_dir) = out_dir else {
+        diagnostics::error("preview requires --out DIR.");
+        return 2;
+    };
+    let patch_text = match crate::read_patch(&positional) {
+        Ok(s) => s,
+        Err(code) => return code,
+    };
+
+    let Some(patch) = Patch::parse(&patch_text) else {
+        eprintln!("Invalid patch: The first line of the patch must be '*** Begin Patch'");
+        return 1;
+    };
+    let changes = match simulate(&patch, Path::new(".")) {
+        Ok(changes) => changes,
+        Err(err) => {
+            eprintln!("{err}");
+            return 1;
+        }
+    };
+
+    for change in &changes {
+        let Some(rel) = contained_path(&change.path) else {
+            diagnostics::error(format!(
+                "cannot preview {}: path escapes the working tree.",
+                change.path
+            ));
+            return 1;
+        };
+        for (side, contents) in [("before", &change.before), ("after", &change.after)] {
+            let Some(contents) = contents else {
+                continue;
+            };
+            let dest = out_dir.join(side).join(&rel);
+            let written = match dest.parent() {
+                Some(parent) => std::fs::create_dir_all(parent),
+                None => Ok(()),
+            }
+            .and_then(|()| std::fs::write(&dest, contents));
+            if let Err(err) = written {
+                diagnostics::error(format!("failed to write {}: {err}", dest.display()));
+                return 1;
+            }
+        }
+    }
+
+    println!("Preview written to {}:", out_dir.display());
+    for change in &changes {
+        println!("{} {}", change.status(), change.path);
+    }
+    0
+}
+
+/// `path` as a relative path that stays inside its root, or `None` if it is
+/// absolute or climbs out with `..`.
+pub(crate) fn contained_path(path: &str) -> Option<PathBuf> {
+    let mut out = PathBuf::new();
+    for component in Path::new(path).components() {
+        match component {
+            Component::Normal(part) => out.push(part),
+            Component::CurDir => {}
+            Component::ParentDir | Component::RootDir | Component::Prefix(_) => return None,
+        }
+    }
+    Some(out)
+}
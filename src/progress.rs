@@ -0,0 +1,142 @@
+//! Progress reporting for large patches.
+//!
+//! The applier writes a whole patch in one call, which can take seconds for
+//! patches touching hundreds of files. When progress is wanted, file
+//! sections are applied one at a time instead and reported on stderr:
+//! as a progress bar when stderr is a terminal and the patch is large, or as
+//! JSON lines with `--format json-stream`. Stdout is unchanged.
+
+use crate::concise;
+use crate::exit;
+use crate::patch::Patch;
+use serde::Serialize;
+use std::io::IsTerminal;
+use std::io::Write;
+
+/// Smallest patch, in file sections, that gets a terminal progress bar.
+const BAR_MIN_FILES: usize = 20;
+const BAR_WIDTH: usize = 30;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) enum OutputFormat {
+    #[default]
+    Text,
+    JsonStream,
+    /// One JSON document; only for `--check`.
+    Json,
+}
+
+impl OutputFormat {
+    pub(crate) fn parse(value: &str) -> Option<Self> {
+        match value {
+            "text" => Some(Self::Text),
+            "json-stream" => Some(Self::JsonStream),
+            "json" => Some(Self::Json),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct Event<'a> {
+    event: &'static str,
+    completed: usize,
+    total: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    current: Option<&'a str>,
+}
+
+pub(crate) struct Progress {
+    format: OutputFormat,
+    total: usize,
+}
+
+impl Progress {
+    /// A reporter for a patch with `total` file sections, if progress should
+    /// be shown for it.
+    pub(crate) fn new(format: OutputFormat, total: usize) -> Option<Self> {
+        let wanted = match format {
+            OutputFormat::JsonStream => true,
+            OutputFormat::Text | OutputFormat::Json => {
+                total >= BAR_MIN_FILES && std::io::stderr().is_terminal()
+            }
+        };
+        wanted.then_some(Self { format, total })
+    }
+
+    /// Reports that `completed` sections are done and `current` is next.
+    pub(crate) fn update(&self, completed: usize, current: Option<&str>) {
+        let mut stderr = std::io::stderr().lock();
+        match self.format {
+            OutputFormat::JsonStream => {
+                let event = Event {
+                    event: if current.is_some() {
+                        "progress"
+                    } else {
+                        "finished"
+                    },
+                    completed,
+                    total: self.total,
+                    current,
+                };
+                if let Ok(line) = serde_json::to_string(&event) {
+                    let _ = writeln!(stderr, "{line}");
+                }
+            }
+            OutputFormat::Text | OutputFormat::Json => {
+                let filled = BAR_WIDTH * completed / self.total.max(1);
+                let bar = format!("{}{}", "#".repeat(filled), " ".repeat(BAR_WIDTH - filled));
+                let _ = match current {
+                    Some(path) => {
+                        write!(stderr, "\r\x1b[K[{bar}] {completed}/{} {path}", self.total)
+                    }
+                    None => write!(stderr, "\r\x1b[K"),
+                };
+            }
+        }
+        let _ = stderr.flush();
+    }
+}
+
+/// Applies `patch` one file section at a time, reporting progress, and
+/// stops at the first failure like a whole-patch apply. Prints the same
+/// summary as the applier on success.
+pub(crate) fn apply_sections(
+    patch: &Patch,
+    progress: &Progress,
+) -> Result<(), (exit::Exit, String)> {
+    let mut added: Vec<&str> = Vec::new();
+    let mut modified: Vec<&str> = Vec::new();
+    let mut deleted: Vec<&str> = Vec::new();
+    for (n, section) in patch.sections.iter().enumerate() {
+        progress.update(n, Some(&section.path));
+        let single = Patch {
+            sections: vec![section.clone()],
+        };
+        let mut stdout: Vec<u8> = Vec::new();
+        let mut stderr: Vec<u8> = Vec::new();
+        let result = codex_apply_patch::apply_patch(&single.render(), &mut stdout, &mut stderr);
+        if let Err(err) = result {
+            progress.update(n, None);
+            let _ = std::io::stderr().write_all(&stderr);
+            return Err(crate::apply_failure(&err, &stderr));
+        }
+        match section.status() {
+            'A' => added.push(&section.path),
+            'D' => deleted.push(&section.path),
+            _ => modified.push(section.move_to().unwrap_or(&section.path)),
+        }
+    }
+    progress.update(patch.sections.len(), None);
+
+    let lines: Vec<String> = [('A', added), ('M', modified), ('D', deleted)]
+        .into_iter()
+        .flat_map(|(status, paths)| {
+            paths
+                .into_iter()
+                .map(move |path| format!("{status} {path}"))
+        })
+        .collect();
+    concise::print_success(&lines);
+    Ok(())
+}
@@ -0,0 +1,98 @@
+//! `--open-pr`: after `--to-branch` commits a patch, push the branch and
+//! open a pull request for it with the GitHub CLI (`gh`).
+//!
+//! The branch is pushed to `pull_request.remote` (default `origin`) and the
+//! pull request targets `pull_request.base`, or the branch checked out where
+//! `apply_patch` ran. Its title is the commit's subject and its body the
+//! patch summary. `gh` reads its token as usual (`gh auth login`,
+//! `$GH_TOKEN`); `pull_request.token_env` names another variable to pass to
+//! it as `GH_TOKEN`, so agents can be given a token of their own.
+
+use serde::Deserialize;
+use serde::Serialize;
+use std::path::Path;
+use std::process::Command;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct PullRequestConfig {
+    #[serde(default = "default_remote")]
+    pub(crate) remote: String,
+    /// Branch to merge into (default: the caller's current branch).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) base: Option<String>,
+    /// Environment variable holding the token for `gh`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) token_env: Option<String>,
+    #[serde(default)]
+    pub(crate) draft: bool,
+}
+
+fn default_remote() -> String {
+    "origin".to_string()
+}
+
+impl Default for PullRequestConfig {
+    fn default() -> Self {
+        Self {
+            remote: default_remote(),
+            base: None,
+            token_env: None,
+            draft: false,
+        }
+    }
+}
+
+/// Pushes `branch` from the repository at `repo` and opens a pull request
+/// into `base`, returning its URL.
+pub(crate) fn open(
+    cfg: &PullRequestConfig,
+    repo: &Path,
+    branch: &str,
+    base: &str,
+    title: &str,
+    body: &str,
+) -> Result<String, String> {
+    let push = Command::new("git")
+        .current_dir(repo)
+        .args(["push", "--quiet", "--set-upstream", &cfg.remote, branch])
+        .output()
+        .map_err(|err| format!("failed to run git: {err}"))?;
+    if !push.status.success() {
+        return Err(format!(
+            "could not push {branch} to {}: {}",
+            cfg.remote,
+            String::from_utf8_lossy(&push.stderr).trim()
+        ));
+    }
+
+    let mut gh = Command::new("gh");
+    gh.current_dir(repo).args([
+        "pr", "create", "--head", branch, "--base", base, "--title", title, "--body", body,
+    ]);
+    if cfg.draft {
+        gh.arg("--draft");
+    }
+    if let Some(var) = &cfg.token_env {
+        let token = std::env::var(var)
+            .ok()
+            .filter(|token| !token.is_empty())
+            .ok_or_else(|| format!("pull_request.token_env names ${var}, which is not set"))?;
+        gh.env("GH_TOKEN", token);
+    }
+    let output = gh.output().map_err(|err| {
+        format!("pushed {branch}, but could not run gh to open the pull request: {err}")
+    })?;
+    if !output.status.success() {
+        return Err(format!(
+            "pushed {branch}, but gh could not open the pull request: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    // gh prints the new pull request's URL last.
+    String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .map(str::trim)
+        .rfind(|line| !line.is_empty())
+        .map(str::to_string)
+        .ok_or_else(|| format!("pushed {branch}, but gh printed no pull request URL"))
+}
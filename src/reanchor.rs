@@ -0,0 +1,194 @@
+//! Hunk re-anchoring (`reanchor` in the config): a last-resort way to place
+//! update hunks whose context has drifted.
+//!
+//! When a hunk's pre-image can't be found even with the applier's fuzzy
+//! matching, it is located by a context or removed line that occurs exactly
+//! once in both the hunk and the file. The hunk is accepted there if every
+//! removed line matches and enough of its other lines do too; its context
+//! is then rewritten to the file's actual lines so the applier finds it.
+//! Hunks must still be in file order.
+
+use crate::patch::Chunk;
+use crate::patch::Patch;
+use crate::patch::SectionKind;
+use crate::simulate::seek_sequence;
+use serde::Deserialize;
+use serde::Serialize;
+use std::collections::HashMap;
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub(crate) struct ReanchorConfig {
+    /// Share of a hunk's pre-image lines (0.0-1.0) that must match at the
+    /// new location; re-anchoring is off when unset.
+    #[serde(default)]
+    pub(crate) min_similarity: Option<f64>,
+}
+
+/// Rewrites hunks that don't match as written but can be re-anchored.
+/// Returns the new patch text and one note per moved hunk, or `None` if
+/// nothing changed.
+pub(crate) fn reanchor(patch_text: &str, cfg: &ReanchorConfig) -> Option<(String, Vec<String>)> {
+    let threshold = cfg.min_similarity?;
+    let mut patch = Patch::parse(patch_text)?;
+    let mut notes: Vec<String> = Vec::new();
+    for section in patch
+        .sections
+        .iter_mut()
+        .filter(|s| s.kind == SectionKind::Update)
+    {
+        let Ok(chunks) = section.chunks() else {
+            continue;
+        };
+        let Ok(contents) = std::fs::read_to_string(&section.path) else {
+            continue;
+        };
+        let mut lines: Vec<String> = contents.split('\n').map(str::to_string).collect();
+        if lines.last().is_some_and(String::is_empty) {
+            lines.pop();
+        }
+        let skip = usize::from(section.move_to().is_some());
+        let groups = split_groups(&section.body[skip..]);
+        if groups.len() != chunks.len() {
+            continue;
+        }
+
+        let mut body: Vec<String> = section.body[..skip].to_vec();
+        let mut changed = false;
+        for (n, (group, chunk)) in groups.iter().zip(&chunks).enumerate() {
+            if matches(&lines, chunk) {
+                body.extend(group.iter().cloned());
+                continue;
+            }
+            let Some((start, similarity)) = anchor(&lines, chunk, group, threshold) else {
+                body.extend(group.iter().cloned());
+                continue;
+            };
+            body.push("@@".to_string());
+            body.extend(rewrite(group, &lines[start..]));
+            changed = true;
+            notes.push(format!(
+                "Re-anchored hunk {} of {} at line {} ({:.0}% of its context matched).",
+                n + 1,
+                section.path,
+                start + 1,
+                similarity * 100.0
+            ));
+        }
+        if changed {
+            section.body = body;
+        }
+    }
+    (!notes.is_empty()).then(|| (patch.render(), notes))
+}
+
+/// Splits an update body into one group of raw lines per chunk; each group
+/// after the first starts at its `@@` line.
+fn split_groups(body: &[String]) -> Vec<Vec<String>> {
+    let mut groups: Vec<Vec<String>> = Vec::new();
+    for line in body {
+        if line.starts_with("@@") || groups.is_empty() {
+            if groups.is_empty() && line.trim().is_empty() {
+                continue;
+            }
+            groups.push(Vec::new());
+        }
+        if let Some(group) = groups.last_mut() {
+            group.push(line.clone());
+        }
+    }
+    groups
+}
+
+/// Whether the applier would find the chunk as written.
+fn matches(lines: &[String], chunk: &Chunk) -> bool {
+    let start = match &chunk.change_context {
+        Some(context) => match seek_sequence(lines, std::slice::from_ref(context), 0, false) {
+            Some(found) => found + 1,
+            None => return false,
+        },
+        None => 0,
+    };
+    let old = &chunk.old_lines;
+    old.is_empty()
+        || seek_sequence(lines, old, start, chunk.is_end_of_file).is_some()
+        || (old.last().is_some_and(String::is_empty)
+            && seek_sequence(lines, &old[..old.len() - 1], start, chunk.is_end_of_file).is_some())
+}
+
+/// Best start line for the chunk among positions implied by lines unique
+/// to both the chunk and the file, with its similarity.
+fn anchor(
+    lines: &[String],
+    chunk: &Chunk,
+    group: &[String],
+    threshold: f64,
+) -> Option<(usize, f64)> {
+    let old = &chunk.old_lines;
+    if old.is_empty() || old.len() > lines.len() {
+        return None;
+    }
+    let mut file_index: HashMap<&str, Vec<usize>> = HashMap::new();
+    for (i, line) in lines.iter().enumerate() {
+        file_index.entry(line.trim()).or_default().push(i);
+    }
+    let removed: Vec<bool> = old_line_kinds(group);
+    if removed.len() != old.len() {
+        return None;
+    }
+
+    let mut best: Option<(usize, f64)> = None;
+    for (offset, line) in old.iter().enumerate() {
+        let key = line.trim();
+        if key.is_empty() || old.iter().filter(|l| l.trim() == key).count() != 1 {
+            continue;
+        }
+        let Some([found]) = file_index.get(key).map(Vec::as_slice) else {
+            continue;
+        };
+        let Some(start) = found.checked_sub(offset) else {
+            continue;
+        };
+        if start + old.len() > lines.len() {
+            continue;
+        }
+        let window = &lines[start..start + old.len()];
+        let same = |k: usize| window[k].trim() == old[k].trim();
+        if (0..old.len()).any(|k| removed[k] && !same(k)) {
+            continue;
+        }
+        let similarity = (0..old.len()).filter(|&k| same(k)).count() as f64 / old.len() as f64;
+        if similarity >= threshold && best.is_none_or(|(_, s)| similarity > s) {
+            best = Some((start, similarity));
+        }
+    }
+    best
+}
+
+/// For each pre-image line of a chunk, whether it is a removed line.
+fn old_line_kinds(group: &[String]) -> Vec<bool> {
+    group
+        .iter()
+        .filter(|l| !l.starts_with("@@") && !l.starts_with("***") && !l.starts_with('+'))
+        .map(|l| l.starts_with('-'))
+        .collect()
+}
+
+/// The chunk with its context and removed lines replaced by the file's
+/// lines from `window` on.
+fn rewrite(group: &[String], window: &[String]) -> Vec<String> {
+    let mut k = 0;
+    let mut out = Vec::new();
+    for line in group {
+        if line.starts_with("@@") {
+            continue;
+        }
+        if line.starts_with('+') || line.starts_with("***") {
+            out.push(line.clone());
+            continue;
+        }
+        let prefix = if line.starts_with('-') { '-' } else { ' ' };
+        out.push(format!("{prefix}{}", window[k]));
+        k += 1;
+    }
+    out
+}
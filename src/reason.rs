@@ -0,0 +1,107 @@
+//! Machine-readable refusal reasons.
+//!
+//! Every refusal carries a short code plus detail, printed as
+//! `REFUSED: <code> <detail>` when `refusal_reason_line` is set and always
+//! recorded in the audit log and feedback file, so harnesses can react to
+//! different causes without parsing the human-facing banner.
+
+use crate::gitscope::Boundary;
+use serde::Serialize;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "code", content = "detail", rename_all = "snake_case")]
+pub(crate) enum RefusalReason {
+    /// The configured base mode is `refuse`.
+    Mode,
+    /// A policy rule (by index) selected `refuse`.
+    Policy(usize),
+    /// Large-patch escalation raised the mode; detail is the lines changed.
+    LargePatch(usize),
+    /// The editor guard found this lock/swap file.
+    EditorArtifact(String),
+    /// This target is inside a submodule or nested repository.
+    Submodule(String),
+    /// This target is outside the sparse-checkout cone.
+    SparseCheckout(String),
+    /// This target has uncommitted git changes (`require_clean`).
+    Dirty(String),
+    /// This target changed after the model read it.
+    StaleRead(String),
+    /// A `branch_rules` entry refuses patches on this branch.
+    Branch(String),
+    /// This target belongs to someone else in CODEOWNERS.
+    NotOwner(String),
+    /// This target is (or is under) a symlink that `symlinks` refuses.
+    Symlink(String),
+    /// `new_dirs` refused creating this directory.
+    NewDirectory(String),
+    /// A directory delete removes more than `max_dir_delete_files` files.
+    DirDelete(String),
+    /// `lint.enforce` found an error-level finding; detail is the rule.
+    Lint(String),
+    /// This target is in the state directory, or is the audit log or
+    /// feedback file.
+    OwnState(String),
+    /// `--expected-files` doesn't list this target.
+    UnexpectedFile(String),
+    /// This target is larger than `limits.max_target_file_bytes`.
+    TooLarge(String),
+    /// The failure guard's cooldown is active; detail is the seconds left.
+    Cooldown(u64),
+}
+
+impl RefusalReason {
+    pub(crate) fn code(&self) -> &'static str {
+        match self {
+            RefusalReason::Mode => "mode",
+            RefusalReason::Policy(_) => "policy",
+            RefusalReason::LargePatch(_) => "large_patch",
+            RefusalReason::EditorArtifact(_) => "editor_artifact",
+            RefusalReason::Submodule(_) => "submodule",
+            RefusalReason::SparseCheckout(_) => "sparse_checkout",
+            RefusalReason::Dirty(_) => "dirty",
+            RefusalReason::StaleRead(_) => "stale_read",
+            RefusalReason::Branch(_) => "branch",
+            RefusalReason::NotOwner(_) => "not_owner",
+            RefusalReason::Symlink(_) => "symlink",
+            RefusalReason::NewDirectory(_) => "new_dir",
+            RefusalReason::DirDelete(_) => "dir_delete",
+            RefusalReason::Lint(_) => "lint",
+            RefusalReason::OwnState(_) => "own_state",
+            RefusalReason::UnexpectedFile(_) => "unexpected_file",
+            RefusalReason::TooLarge(_) => "too_large",
+            RefusalReason::Cooldown(_) => "cooldown",
+        }
+    }
+
+    pub(crate) fn from_boundary(boundary: &Boundary) -> Self {
+        match boundary {
+            Boundary::Submodule { path, .. } => RefusalReason::Submodule(path.clone()),
+            Boundary::OutsideSparseCheckout { path } => RefusalReason::SparseCheckout(path.clone()),
+        }
+    }
+
+    /// `REFUSED: <code> <detail>`
+    pub(crate) fn line(&self) -> String {
+        let detail = match self {
+            RefusalReason::Mode => "refuse".to_string(),
+            RefusalReason::Policy(index) | RefusalReason::LargePatch(index) => index.to_string(),
+            RefusalReason::Cooldown(secs) => secs.to_string(),
+            RefusalReason::EditorArtifact(path)
+            | RefusalReason::Submodule(path)
+            | RefusalReason::SparseCheckout(path)
+            | RefusalReason::Dirty(path)
+            | RefusalReason::StaleRead(path)
+            | RefusalReason::Branch(path)
+            | RefusalReason::NotOwner(path)
+            | RefusalReason::Symlink(path)
+            | RefusalReason::NewDirectory(path)
+            | RefusalReason::DirDelete(path)
+            | RefusalReason::OwnState(path)
+            | RefusalReason::UnexpectedFile(path)
+            | RefusalReason::TooLarge(path) => path.clone(),
+            RefusalReason::Lint(rule) => rule.clone(),
+        };
+        format!("REFUSED: {} {detail}", self.code())
+    }
+}
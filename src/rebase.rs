@@ -0,0 +1,309 @@
+//! `--base REF`: apply a patch written against an older commit.
+//!
+//! The patch is applied in memory to the files as they are at `REF` (read
+//! with `git show`). The resulting changes are merged into the working-tree
+//! files wherever the lines they touch haven't changed since `REF`, and each
+//! update is re-derived as a fresh diff against the working tree. The
+//! rebased patch then goes through the normal pipeline, or is written out
+//! with `--rebased-out`. A change whose lines were also edited since `REF`
+//! is a conflict and nothing is applied.
+
+use crate::exit;
+use crate::patch::Patch;
+use crate::patch::Section;
+use crate::patch::SectionKind;
+use crate::simulate::derive_new_contents;
+use std::collections::HashMap;
+use std::process::Command;
+
+/// Unchanged lines kept around each change in a rebased hunk.
+const CONTEXT: usize = 3;
+
+type Overlay = HashMap<String, Option<String>>;
+
+/// Rebases `patch_text` from `base` onto the working tree. Errors carry the
+/// exit kind and message.
+pub(crate) fn rebase(patch_text: &str, base: &str) -> Result<String, (exit::Exit, String)> {
+    let Some(patch) = Patch::parse(patch_text) else {
+        return Err((
+            exit::Exit::ParseError,
+            "Invalid patch: The first line of the patch must be '*** Begin Patch'".to_string(),
+        ));
+    };
+    if git(&[
+        "rev-parse",
+        "--verify",
+        "--quiet",
+        &format!("{base}^{{commit}}"),
+    ])
+    .is_none()
+    {
+        return Err((exit::Exit::Failed, format!("unknown git ref: {base}")));
+    }
+
+    // Contents as the patch progresses, at the base and in the working tree.
+    let mut at_base: Overlay = HashMap::new();
+    let mut in_tree: Overlay = HashMap::new();
+    let read = |overlay: &Overlay, path: &str, from_base: bool| match overlay.get(path) {
+        Some(current) => current.clone(),
+        None if from_base => git(&["show", &format!("{base}:./{path}")]),
+        None => std::fs::read_to_string(path).ok(),
+    };
+    let mismatch = |msg: String| (exit::Exit::ContextMismatch, msg);
+    let mut sections: Vec<Section> = Vec::new();
+    for section in &patch.sections {
+        let path = section.path.as_str();
+        match section.kind {
+            SectionKind::Add => {
+                at_base.insert(path.to_string(), Some(section.added_contents()));
+                in_tree.insert(path.to_string(), Some(section.added_contents()));
+                sections.push(section.clone());
+            }
+            SectionKind::Delete => {
+                at_base.insert(path.to_string(), None);
+                in_tree.insert(path.to_string(), None);
+                sections.push(section.clone());
+            }
+            SectionKind::Update => {
+                let Some(before) = read(&at_base, path, true) else {
+                    return Err(mismatch(format!("{path} does not exist at {base}")));
+                };
+                let Some(current) = read(&in_tree, path, false) else {
+                    return Err(mismatch(format!("Failed to read file to update {path}")));
+                };
+                let chunks = section
+                    .chunks()
+                    .map_err(|err| (exit::Exit::ParseError, format!("Invalid patch: {err}")))?;
+                let after = derive_new_contents(&before, path, &chunks)
+                    .map_err(|err| mismatch(format!("{err} (at {base})")))?;
+                let merged = merge(&before, &current, &after).map_err(|line| {
+                    mismatch(format!(
+                        "conflict in {path}: line {line} at {base} was changed both by the patch and since {base}"
+                    ))
+                })?;
+                let hunks = diff_hunks(&current, &merged);
+                let dest = section.move_to().unwrap_or(path);
+                for overlay in [&mut at_base, &mut in_tree] {
+                    overlay.insert(path.to_string(), None);
+                }
+                at_base.insert(dest.to_string(), Some(after));
+                in_tree.insert(dest.to_string(), Some(merged));
+                if hunks.is_empty() {
+                    // Nothing changes in the tree; a move still has to
+                    // happen, and the applier needs a hunk for it.
+                    if section.move_to().is_some() {
+                        sections.push(section.clone());
+                    }
+                    continue;
+                }
+                let mut body = section.body[..usize::from(section.move_to().is_some())].to_vec();
+                body.extend(hunks);
+                sections.push(Section {
+                    body,
+                    ..section.clone()
+                });
+            }
+        }
+    }
+    Ok(Patch { sections }.render())
+}
+
+/// Carries the changes from `base` to `theirs` over to `ours`. Fails with
+/// the 1-based base line of the first change whose lines `ours` also
+/// changed.
+fn merge(base: &str, ours: &str, theirs: &str) -> Result<String, usize> {
+    let b: Vec<&str> = base.lines().collect();
+    let o: Vec<&str> = ours.lines().collect();
+    let t: Vec<&str> = theirs.lines().collect();
+
+    // Where each base line is in `ours`, if it is still there unchanged.
+    let mut in_ours: Vec<Option<usize>> = vec![None; b.len()];
+    for op in diff(&b, &o) {
+        if let Op::Equal(x, y) = op {
+            in_ours[x] = Some(y);
+        }
+    }
+
+    // (start, len) in `ours` and the lines replacing them.
+    let mut edits: Vec<(usize, usize, Vec<&str>)> = Vec::new();
+    let ops = diff(&b, &t);
+    let mut i = 0;
+    while i < ops.len() {
+        if let Op::Equal(..) = ops[i] {
+            i += 1;
+            continue;
+        }
+        // The base line this change starts at.
+        let start = ops[..i]
+            .iter()
+            .rev()
+            .find_map(|op| match op {
+                Op::Equal(x, _) | Op::Delete(x) => Some(x + 1),
+                Op::Insert(_) => None,
+            })
+            .unwrap_or(0);
+        let (mut removed, mut inserted) = (0, Vec::new());
+        while i < ops.len() && !matches!(ops[i], Op::Equal(..)) {
+            match ops[i] {
+                Op::Delete(_) => removed += 1,
+                Op::Insert(y) => inserted.push(t[y]),
+                Op::Equal(..) => {}
+            }
+            i += 1;
+        }
+        let at = if removed == 0 {
+            // Insert before the next base line, or after the last one.
+            if start < b.len() {
+                in_ours[start]
+            } else if start > 0 {
+                in_ours[start - 1].map(|y| y + 1)
+            } else {
+                Some(0)
+            }
+        } else {
+            in_ours[start].filter(|&y| (1..removed).all(|k| in_ours[start + k] == Some(y + k)))
+        };
+        let Some(at) = at else {
+            return Err(start + 1);
+        };
+        edits.push((at, removed, inserted));
+    }
+
+    let mut lines: Vec<&str> = o;
+    for (at, removed, inserted) in edits.into_iter().rev() {
+        lines.splice(at..at + removed, inserted);
+    }
+    let mut merged = lines.join("\n");
+    merged.push('\n');
+    Ok(merged)
+}
+
+/// Update-section hunks turning `before` into `after`.
+pub(crate) fn diff_hunks(before: &str, after: &str) -> Vec<String> {
+    let a: Vec<&str> = before.lines().collect();
+    let b: Vec<&str> = after.lines().collect();
+    let ops = diff(&a, &b);
+    let changed: Vec<usize> = (0..ops.len())
+        .filter(|&i| !matches!(ops[i], Op::Equal(..)))
+        .collect();
+
+    let mut out: Vec<String> = Vec::new();
+    let mut i = 0;
+    while i < changed.len() {
+        // Extend the hunk while the next change is close enough to share
+        // context.
+        let mut j = i;
+        while j + 1 < changed.len() && changed[j + 1] - changed[j] <= 2 * CONTEXT + 1 {
+            j += 1;
+        }
+        let start = changed[i].saturating_sub(CONTEXT);
+        let end = (changed[j] + CONTEXT + 1).min(ops.len());
+        out.push("@@".to_string());
+        for op in &ops[start..end] {
+            out.push(match *op {
+                Op::Equal(x, _) => format!(" {}", a[x]),
+                Op::Delete(x) => format!("-{}", a[x]),
+                Op::Insert(y) => format!("+{}", b[y]),
+            });
+        }
+        i = j + 1;
+    }
+    out
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Op {
+    Equal(usize, usize),
+    Delete(usize),
+    Insert(usize),
+}
+
+/// Shortest edit script from `a` to `b` (Myers), after trimming the common
+/// prefix and suffix.
+fn diff(a: &[&str], b: &[&str]) -> Vec<Op> {
+    let prefix = a.iter().zip(b).take_while(|(x, y)| x == y).count();
+    let suffix = a[prefix..]
+        .iter()
+        .rev()
+        .zip(b[prefix..].iter().rev())
+        .take_while(|(x, y)| x == y)
+        .count();
+    let (ma, mb) = (&a[prefix..a.len() - suffix], &b[prefix..b.len() - suffix]);
+
+    let mut ops: Vec<Op> = (0..prefix).map(|i| Op::Equal(i, i)).collect();
+    ops.extend(myers(ma, mb).into_iter().map(|op| match op {
+        Op::Equal(x, y) => Op::Equal(x + prefix, y + prefix),
+        Op::Delete(x) => Op::Delete(x + prefix),
+        Op::Insert(y) => Op::Insert(y + prefix),
+    }));
+    ops.extend((0..suffix).map(|i| Op::Equal(a.len() - suffix + i, b.len() - suffix + i)));
+    ops
+}
+
+fn myers(a: &[&str], b: &[&str]) -> Vec<Op> {
+    let (n, m) = (a.len() as isize, b.len() as isize);
+    let max = n + m;
+    let offset = max + 1;
+    let at = |k: isize| (k + offset) as usize;
+    let mut v = vec![0isize; (2 * max + 3) as usize];
+    let mut trace: Vec<Vec<isize>> = Vec::new();
+    'search: for d in 0..=max {
+        trace.push(v.clone());
+        for k in (-d..=d).step_by(2) {
+            let mut x = if k == -d || (k != d && v[at(k - 1)] < v[at(k + 1)]) {
+                v[at(k + 1)]
+            } else {
+                v[at(k - 1)] + 1
+            };
+            let mut y = x - k;
+            while x < n && y < m && a[x as usize] == b[y as usize] {
+                x += 1;
+                y += 1;
+            }
+            v[at(k)] = x;
+            if x >= n && y >= m {
+                break 'search;
+            }
+        }
+    }
+
+    let mut ops: Vec<Op> = Vec::new();
+    let (mut x, mut y) = (n, m);
+    for (d, v) in trace.iter().enumerate().rev() {
+        let d = d as isize;
+        let k = x - y;
+        let prev_k = if k == -d || (k != d && v[at(k - 1)] < v[at(k + 1)]) {
+            k + 1
+        } else {
+            k - 1
+        };
+        let prev_x = v[at(prev_k)];
+        let prev_y = prev_x - prev_k;
+        while x > prev_x && y > prev_y {
+            ops.push(Op::Equal((x - 1) as usize, (y - 1) as usize));
+            x -= 1;
+            y -= 1;
+        }
+        if d > 0 {
+            if x == prev_x {
+                ops.push(Op::Insert((y - 1) as usize));
+            } else {
+                ops.push(Op::Delete((x - 1) as usize));
+            }
+        }
+        x = prev_x;
+        y = prev_y;
+    }
+    ops.reverse();
+    ops
+}
+
+/// Runs git in the current directory and returns its stdout, or `None` if
+/// it failed.
+fn git(args: &[&str]) -> Option<String> {
+    let output = Command::new("git").args(args).output().ok()?;
+    output
+        .status
+        .success()
+        .then(|| String::from_utf8_lossy(&output.stdout).into_owned())
+}
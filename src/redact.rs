@@ -0,0 +1,293 @@
+//! `apply_patch redact [--out FILE] [--pattern RE]... [PATCH]`: rewrites a
+//! patch so it can be attached to a bug report without leaking the code it
+//! touches.
+//!
+//! String literals, email addresses and matches of the regular expressions
+//! in `redact.patterns` (and `--pattern`) become placeholders: `"s1"`,
+//! `user1@example.com`, `REDACTED1`. The same text always gets the same
+//! placeholder, so context and removed lines still line up with each other,
+//! and markers, file paths and line prefixes are kept, so the result is
+//! still a patch with the original's shape. Identifiers and comments are
+//! left alone; add patterns for anything else that must not leave the
+//! machine.
+
+use crate::diagnostics;
+use crate::exit;
+use crate::patch::Patch;
+use crate::regex::Regex;
+use serde::Deserialize;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::io::Read;
+use std::path::PathBuf;
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub(crate) struct RedactConfig {
+    /// Regular expressions whose matches are replaced with `REDACTEDn`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub(crate) patterns: Vec<String>,
+}
+
+const EMAIL_PATTERN: &str = r"[A-Za-z0-9._%+-]+@[A-Za-z0-9-]+(\.[A-Za-z0-9-]+)*\.[A-Za-z]{2,}";
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+enum Kind {
+    Pattern,
+    Email,
+    String,
+}
+
+/// Placeholders handed out so far, per kind.
+#[derive(Default)]
+struct Placeholders {
+    seen: HashMap<(Kind, String), String>,
+    counts: HashMap<Kind, usize>,
+}
+
+impl Placeholders {
+    fn get(&mut self, kind: Kind, original: &str) -> String {
+        if let Some(existing) = self.seen.get(&(kind, original.to_string())) {
+            return existing.clone();
+        }
+        let n = self.counts.entry(kind).or_default();
+        *n += 1;
+        let placeholder = match kind {
+            Kind::Pattern => format!("REDACTED{n}"),
+            Kind::Email => format!("user{n}@example.com"),
+            Kind::String => format!("s{n}"),
+        };
+        self.seen
+            .insert((kind, original.to_string()), placeholder.clone());
+        placeholder
+    }
+
+    fn count(&self, kind: Kind) -> usize {
+        self.counts.get(&kind).copied().unwrap_or(0)
+    }
+}
+
+struct Redactor {
+    patterns: Vec<Regex>,
+    email: Regex,
+    placeholders: Placeholders,
+}
+
+impl Redactor {
+    /// One patch line: markers are kept, the text after a line's prefix
+    /// (` `, `+`, `-` or `@@`) is redacted.
+    fn redact_line(&mut self, line: &str) -> String {
+        if line.starts_with("*** ") || line.trim().is_empty() {
+            return line.to_string();
+        }
+        let split = if line.starts_with("@@") {
+            2
+        } else if line.starts_with([' ', '+', '-']) {
+            1
+        } else {
+            0
+        };
+        let (prefix, rest) = line.split_at(split);
+        let mut text = rest.to_string();
+        for regex in &self.patterns {
+            text = replace_matches(regex, Kind::Pattern, &text, &mut self.placeholders);
+        }
+        text = replace_matches(&self.email, Kind::Email, &text, &mut self.placeholders);
+        format!("{prefix}{}", replace_strings(&text, &mut self.placeholders))
+    }
+}
+
+/// `text` with every match of `regex` replaced.
+fn replace_matches(
+    regex: &Regex,
+    kind: Kind,
+    text: &str,
+    placeholders: &mut Placeholders,
+) -> String {
+    let chars: Vec<char> = text.chars().collect();
+    let mut out = String::new();
+    let mut pos = 0;
+    while let Some((start, end)) = regex.find_at(&chars, pos) {
+        out.extend(&chars[pos..start]);
+        let original: String = chars[start..end].iter().collect();
+        out.push_str(&placeholders.get(kind, &original));
+        pos = end;
+    }
+    out.extend(&chars[pos..]);
+    out
+}
+
+/// `text` with the contents of its string literals replaced. A
+/// single-quoted literal must stand apart from words on both sides, so
+/// apostrophes and Rust lifetimes are left alone.
+fn replace_strings(text: &str, placeholders: &mut Placeholders) -> String {
+    let chars: Vec<char> = text.chars().collect();
+    let is_word = |i: usize| {
+        chars
+            .get(i)
+            .is_some_and(|c| c.is_alphanumeric() || *c == '_')
+    };
+    let mut out = String::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let quote = chars[i];
+        let opens = match quote {
+            '"' | '`' => true,
+            '\'' => i == 0 || !is_word(i - 1),
+            _ => false,
+        };
+        let close = opens
+            .then(|| closing_quote(&chars, i))
+            .flatten()
+            .filter(|&close| quote != '\'' || !is_word(close + 1));
+        let Some(close) = close else {
+            out.push(quote);
+            i += 1;
+            continue;
+        };
+        let contents: String = chars[i + 1..close].iter().collect();
+        out.push(quote);
+        if !contents.is_empty() {
+            out.push_str(&placeholders.get(Kind::String, &contents));
+        }
+        out.push(quote);
+        i = close + 1;
+    }
+    out
+}
+
+/// The index of the quote closing the literal opened at `open`, or `None`
+/// if it isn't closed on this line.
+fn closing_quote(chars: &[char], open: usize) -> Option<usize> {
+    let quote = chars[open];
+    let mut i = open + 1;
+    while i < chars.len() {
+        match chars[i] {
+            '\\' => i += 2,
+            c if c == quote => return Some(i),
+            _ => i += 1,
+        }
+    }
+    None
+}
+
+pub(crate) fn run_redact(args: &[String]) -> i32 {
+    let mut out: Option<PathBuf> = None;
+    let mut extra_patterns: Vec<String> = Vec::new();
+    let mut input: Option<&str> = None;
+    let mut i = 0;
+    while i < args.len() {
+        match args[i].as_str() {
+            flag @ ("--out" | "--pattern") => {
+                let Some(value) = args.get(i + 1) else {
+                    diagnostics::error(format!("{flag} requires a value."));
+                    return 2;
+                };
+                if flag == "--out" {
+                    out = Some(PathBuf::from(value));
+                } else {
+                    extra_patterns.push(value.clone());
+                }
+                i += 2;
+            }
+            arg if arg.starts_with('-') && arg != "-" => {
+                diagnostics::error(format!("unknown option: {arg}"));
+                return 2;
+            }
+            arg if input.is_none() => {
+                input = Some(arg);
+                i += 1;
+            }
+            _ => {
+                eprintln!("Usage: apply_patch redact [--out FILE] [--pattern RE]... [PATCH]");
+                return 2;
+            }
+        }
+    }
+
+    let cfg = match crate::effective_config(&crate::RunOptions::default()) {
+        Ok(cfg) => cfg,
+        Err(err) => {
+            diagnostics::error(err);
+            return exit::Exit::ConfigError.code();
+        }
+    };
+    let mut patterns = Vec::new();
+    for pattern in cfg.redact.patterns.iter().chain(&extra_patterns) {
+        match Regex::new(pattern) {
+            Ok(regex) => patterns.push(regex),
+            Err(err) => {
+                diagnostics::error(format!("invalid redact pattern `{pattern}`: {err}"));
+                return 2;
+            }
+        }
+    }
+    let Ok(email) = Regex::new(EMAIL_PATTERN) else {
+        diagnostics::error("invalid built-in email pattern.");
+        return 1;
+    };
+    let mut redactor = Redactor {
+        patterns,
+        email,
+        placeholders: Placeholders::default(),
+    };
+
+    let text = match input.filter(|path| *path != "-") {
+        Some(path) => match std::fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(err) => {
+                diagnostics::error(format!("failed to read {path}: {err}"));
+                return 1;
+            }
+        },
+        None => {
+            let mut buf = String::new();
+            if let Err(err) = std::io::stdin().read_to_string(&mut buf) {
+                diagnostics::error(format!("Failed to read PATCH from stdin.\n{err}"));
+                return 1;
+            }
+            buf
+        }
+    };
+    if Patch::parse(&text).is_none() {
+        eprintln!("Invalid patch: The first line of the patch must be '*** Begin Patch'");
+        return exit::Exit::ParseError.code();
+    }
+
+    let mut redacted = String::new();
+    for line in text.split_inclusive('\n') {
+        let (line, eol) = match line.strip_suffix("\r\n") {
+            Some(line) => (line, "\r\n"),
+            None => match line.strip_suffix('\n') {
+                Some(line) => (line, "\n"),
+                None => (line, ""),
+            },
+        };
+        redacted.push_str(&redactor.redact_line(line));
+        redacted.push_str(eol);
+    }
+    let placeholders = &redactor.placeholders;
+    let summary = format!(
+        "Redacted {} string literal(s), {} email address(es) and {} pattern match(es).",
+        placeholders.count(Kind::String),
+        placeholders.count(Kind::Email),
+        placeholders.count(Kind::Pattern)
+    );
+
+    match out {
+        Some(path) => match std::fs::write(&path, &redacted) {
+            Ok(()) => {
+                println!("{summary} Wrote {}.", path.display());
+                0
+            }
+            Err(err) => {
+                diagnostics::error(format!("failed to write {}: {err}", path.display()));
+                1
+            }
+        },
+        None => {
+            print!("{redacted}");
+            eprintln!("{summary}");
+            0
+        }
+    }
+}
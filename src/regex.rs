@@ -0,0 +1,330 @@
+//! A small backtracking regular-expression matcher for the patterns in
+//! `redact.patterns`, so the binary needs no regex crate.
+//!
+//! Supported: literals, `.`, classes (`[a-z_]`, `[^0-9]`), the escapes `\d`
+//! `\w` `\s` (and their negations) `\t` `\n` plus escaped punctuation, the
+//! anchors `^` and `$`, groups (`(...)`, `(?:...)`) with `|`, and the greedy
+//! quantifiers `*` `+` `?` `{n}` `{n,}` `{n,m}`. Lazy quantifiers,
+//! backreferences and lookaround are rejected.
+
+#[derive(Debug, Clone)]
+enum Node {
+    Char(char),
+    Any,
+    Class {
+        items: Vec<ClassItem>,
+        negated: bool,
+    },
+    Start,
+    End,
+    Group(Vec<Vec<Node>>),
+    Repeat {
+        node: Box<Node>,
+        min: usize,
+        max: Option<usize>,
+    },
+}
+
+#[derive(Debug, Clone, Copy)]
+enum ClassItem {
+    Range(char, char),
+    Digit(bool),
+    Word(bool),
+    Space(bool),
+}
+
+impl ClassItem {
+    fn matches(self, c: char) -> bool {
+        match self {
+            ClassItem::Range(lo, hi) => (lo..=hi).contains(&c),
+            ClassItem::Digit(negated) => c.is_ascii_digit() != negated,
+            ClassItem::Word(negated) => (c.is_alphanumeric() || c == '_') != negated,
+            ClassItem::Space(negated) => c.is_whitespace() != negated,
+        }
+    }
+}
+
+impl Node {
+    fn matches_char(&self, c: char) -> bool {
+        match self {
+            Node::Char(want) => *want == c,
+            Node::Any => c != '\n',
+            Node::Class { items, negated } => items.iter().any(|item| item.matches(c)) != *negated,
+            _ => false,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct Regex {
+    alternatives: Vec<Vec<Node>>,
+}
+
+impl Regex {
+    pub(crate) fn new(pattern: &str) -> Result<Self, String> {
+        let chars: Vec<char> = pattern.chars().collect();
+        let mut parser = Parser {
+            chars: &chars,
+            pos: 0,
+        };
+        let alternatives = parser.alternatives()?;
+        if parser.pos < chars.len() {
+            return Err("unmatched `)`".to_string());
+        }
+        Ok(Self { alternatives })
+    }
+
+    /// The first non-empty match in `text` at or after char index `from`, as
+    /// char indices `(start, end)`.
+    pub(crate) fn find_at(&self, text: &[char], from: usize) -> Option<(usize, usize)> {
+        (from..=text.len()).find_map(|start| {
+            let end = self.alternatives.iter().find_map(|alt| {
+                match_here(alt, text, start, &mut |end| (end > start).then_some(end))
+            })?;
+            Some((start, end))
+        })
+    }
+}
+
+struct Parser<'a> {
+    chars: &'a [char],
+    pos: usize,
+}
+
+impl Parser<'_> {
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn next(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.pos += 1;
+        Some(c)
+    }
+
+    fn alternatives(&mut self) -> Result<Vec<Vec<Node>>, String> {
+        let mut alternatives = vec![self.sequence()?];
+        while self.peek() == Some('|') {
+            self.pos += 1;
+            alternatives.push(self.sequence()?);
+        }
+        Ok(alternatives)
+    }
+
+    fn sequence(&mut self) -> Result<Vec<Node>, String> {
+        let mut nodes = Vec::new();
+        while let Some(c) = self.peek() {
+            if c == '|' || c == ')' {
+                break;
+            }
+            self.pos += 1;
+            let atom = match c {
+                '.' => Node::Any,
+                '^' => Node::Start,
+                '$' => Node::End,
+                '(' => {
+                    if self.peek() == Some('?') {
+                        if self.chars.get(self.pos + 1) != Some(&':') {
+                            return Err("only `(?:` groups are supported".to_string());
+                        }
+                        self.pos += 2;
+                    }
+                    let group = self.alternatives()?;
+                    if self.next() != Some(')') {
+                        return Err("unclosed `(`".to_string());
+                    }
+                    Node::Group(group)
+                }
+                '[' => self.class()?,
+                '\\' => self.escape()?,
+                '*' | '+' | '?' | '{' => return Err(format!("nothing to repeat before `{c}`")),
+                c => Node::Char(c),
+            };
+            let node = self.quantified(atom)?;
+            nodes.push(node);
+        }
+        Ok(nodes)
+    }
+
+    fn quantified(&mut self, atom: Node) -> Result<Node, String> {
+        let (min, max) = match self.peek() {
+            Some('*') => (0, None),
+            Some('+') => (1, None),
+            Some('?') => (0, Some(1)),
+            Some('{') => {
+                let close = self.chars[self.pos..]
+                    .iter()
+                    .position(|&c| c == '}')
+                    .ok_or("unclosed `{`")?;
+                let body: String = self.chars[self.pos + 1..self.pos + close].iter().collect();
+                let bound = |s: &str| {
+                    s.trim()
+                        .parse::<usize>()
+                        .map_err(|_| format!("invalid repetition `{{{body}}}`"))
+                };
+                let range = match body.split_once(',') {
+                    None => {
+                        let n = bound(&body)?;
+                        (n, Some(n))
+                    }
+                    Some((lo, hi)) if hi.trim().is_empty() => (bound(lo)?, None),
+                    Some((lo, hi)) => (bound(lo)?, Some(bound(hi)?)),
+                };
+                self.pos += close;
+                range
+            }
+            _ => return Ok(atom),
+        };
+        self.pos += 1;
+        if matches!(atom, Node::Start | Node::End) {
+            return Err("anchors can't be repeated".to_string());
+        }
+        if max.is_some_and(|max| max < min) {
+            return Err("repetition maximum is below its minimum".to_string());
+        }
+        if matches!(self.peek(), Some('?' | '+')) {
+            return Err("lazy and possessive quantifiers are not supported".to_string());
+        }
+        Ok(Node::Repeat {
+            node: Box::new(atom),
+            min,
+            max,
+        })
+    }
+
+    fn escape(&mut self) -> Result<Node, String> {
+        Ok(match self.escaped_item()? {
+            Escaped::Char(c) => Node::Char(c),
+            Escaped::Class(item) => Node::Class {
+                items: vec![item],
+                negated: false,
+            },
+        })
+    }
+
+    fn escaped_item(&mut self) -> Result<Escaped, String> {
+        let c = self.next().ok_or("trailing `\\`")?;
+        Ok(match c {
+            'd' => Escaped::Class(ClassItem::Digit(false)),
+            'D' => Escaped::Class(ClassItem::Digit(true)),
+            'w' => Escaped::Class(ClassItem::Word(false)),
+            'W' => Escaped::Class(ClassItem::Word(true)),
+            's' => Escaped::Class(ClassItem::Space(false)),
+            'S' => Escaped::Class(ClassItem::Space(true)),
+            't' => Escaped::Char('\t'),
+            'n' => Escaped::Char('\n'),
+            c if c.is_ascii_alphanumeric() => {
+                return Err(format!("unsupported escape `\\{c}`"));
+            }
+            c => Escaped::Char(c),
+        })
+    }
+
+    fn class(&mut self) -> Result<Node, String> {
+        let negated = self.peek() == Some('^');
+        if negated {
+            self.pos += 1;
+        }
+        let mut items = Vec::new();
+        let mut first = true;
+        loop {
+            let c = self.next().ok_or("unclosed `[`")?;
+            if c == ']' && !first {
+                break;
+            }
+            first = false;
+            let lo = if c == '\\' {
+                match self.escaped_item()? {
+                    Escaped::Char(c) => c,
+                    Escaped::Class(item) => {
+                        items.push(item);
+                        continue;
+                    }
+                }
+            } else {
+                c
+            };
+            if self.peek() == Some('-') && self.chars.get(self.pos + 1).is_some_and(|&c| c != ']') {
+                self.pos += 1;
+                let hi = match self.next() {
+                    Some('\\') => match self.escaped_item()? {
+                        Escaped::Char(c) => c,
+                        Escaped::Class(_) => return Err("invalid class range".to_string()),
+                    },
+                    Some(c) => c,
+                    None => return Err("unclosed `[`".to_string()),
+                };
+                if hi < lo {
+                    return Err(format!("invalid class range `{lo}-{hi}`"));
+                }
+                items.push(ClassItem::Range(lo, hi));
+            } else {
+                items.push(ClassItem::Range(lo, lo));
+            }
+        }
+        Ok(Node::Class { items, negated })
+    }
+}
+
+enum Escaped {
+    Char(char),
+    Class(ClassItem),
+}
+
+/// Matches `nodes` at `pos`, then hands the end position to `k`, which
+/// decides whether the overall match succeeds; backtracks while it doesn't.
+fn match_here(
+    nodes: &[Node],
+    text: &[char],
+    pos: usize,
+    k: &mut dyn FnMut(usize) -> Option<usize>,
+) -> Option<usize> {
+    let Some((first, rest)) = nodes.split_first() else {
+        return k(pos);
+    };
+    match first {
+        Node::Start if pos == 0 => match_here(rest, text, pos, k),
+        Node::End if pos == text.len() => match_here(rest, text, pos, k),
+        Node::Start | Node::End => None,
+        Node::Group(alternatives) => alternatives
+            .iter()
+            .find_map(|alt| match_here(alt, text, pos, &mut |p| match_here(rest, text, p, k))),
+        Node::Repeat { node, min, max } => repeat(node, *min, *max, 0, rest, text, pos, k),
+        single => match text.get(pos) {
+            Some(&c) if single.matches_char(c) => match_here(rest, text, pos + 1, k),
+            _ => None,
+        },
+    }
+}
+
+/// Greedy repetition: one more `node` if allowed, otherwise (or if that
+/// fails) the rest of the sequence.
+#[allow(clippy::too_many_arguments)]
+fn repeat(
+    node: &Node,
+    min: usize,
+    max: Option<usize>,
+    count: usize,
+    rest: &[Node],
+    text: &[char],
+    pos: usize,
+    k: &mut dyn FnMut(usize) -> Option<usize>,
+) -> Option<usize> {
+    if max.is_none_or(|max| count < max) {
+        let more = match_here(std::slice::from_ref(node), text, pos, &mut |p| {
+            // An empty iteration only counts towards the minimum.
+            if p == pos && count >= min {
+                return None;
+            }
+            repeat(node, min, max, count + 1, rest, text, p, k)
+        });
+        if more.is_some() {
+            return more;
+        }
+    }
+    if count >= min {
+        match_here(rest, text, pos, k)
+    } else {
+        None
+    }
+}
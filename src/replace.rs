@@ -0,0 +1,127 @@
+//! `*** Replace File: PATH` sections: the body is the complete new content
+//! of an existing file, as `+` lines like an add, with no context to match.
+//! For a heavily drifted file one replacement is more reliable than dozens
+//! of fragile hunks.
+//!
+//! Each section is expanded into an `*** Update File:` section that removes
+//! every current line and adds the new ones, so the applier, policies and
+//! guards treat it as an ordinary modification. `replace_file.allow` turns
+//! the sections off and `replace_file.max_bytes` caps the new content.
+
+use crate::archive::contained_name;
+use crate::patch::EOF_MARKER;
+use crate::patch::UPDATE_FILE_MARKER;
+use serde::Deserialize;
+use serde::Serialize;
+use std::borrow::Cow;
+
+pub(crate) const REPLACE_FILE_MARKER: &str = "*** Replace File: ";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct ReplaceFileConfig {
+    /// Whether `*** Replace File:` sections are accepted (default: true).
+    #[serde(default = "default_allow")]
+    pub(crate) allow: bool,
+    /// Largest new content, in bytes (default: 1 MiB).
+    #[serde(default = "default_max_bytes")]
+    pub(crate) max_bytes: usize,
+}
+
+fn default_allow() -> bool {
+    true
+}
+
+fn default_max_bytes() -> usize {
+    1024 * 1024
+}
+
+impl Default for ReplaceFileConfig {
+    fn default() -> Self {
+        Self {
+            allow: default_allow(),
+            max_bytes: default_max_bytes(),
+        }
+    }
+}
+
+/// Whether `patch_text` has any replace sections.
+pub(crate) fn contains(patch_text: &str) -> bool {
+    patch_text
+        .lines()
+        .any(|line| line.trim_start().starts_with(REPLACE_FILE_MARKER))
+}
+
+/// Rewrites every replace section in `patch_text` as an update of the
+/// file's current contents. Patches without replace sections are returned
+/// unchanged.
+pub(crate) fn expand<'a>(
+    patch_text: &'a str,
+    cfg: &ReplaceFileConfig,
+) -> Result<Cow<'a, str>, String> {
+    if !contains(patch_text) {
+        return Ok(Cow::Borrowed(patch_text));
+    }
+    if !cfg.allow {
+        return Err(
+            "Replace File sections are disabled: set replace_file.allow in the config to use `*** Replace File:`."
+                .to_string(),
+        );
+    }
+    let mut out = String::new();
+    let mut lines = patch_text.lines().peekable();
+    while let Some(line) = lines.next() {
+        let Some(raw) = line.trim().strip_prefix(REPLACE_FILE_MARKER) else {
+            out.push_str(line);
+            out.push('\n');
+            continue;
+        };
+        let raw = raw.trim();
+        let fail = |msg: &str| format!("Invalid Replace File section for {raw}: {msg}");
+        let mut contents: Vec<&str> = Vec::new();
+        while let Some(body) = lines.next_if(|l| !l.trim_start().starts_with("***")) {
+            match body.strip_prefix('+') {
+                Some(content) => contents.push(content),
+                None if body.trim().is_empty() => {}
+                None => {
+                    return Err(fail(&format!(
+                        "every line must start with '+', got `{body}`"
+                    )));
+                }
+            }
+        }
+        let size: usize = contents.iter().map(|line| line.len() + 1).sum();
+        if size > cfg.max_bytes {
+            return Err(fail(&format!(
+                "the new content is {size} bytes, more than replace_file.max_bytes ({})",
+                cfg.max_bytes
+            )));
+        }
+        let Some(path) = contained_name(raw) else {
+            return Err(fail(
+                "the path must be relative and stay in the working directory",
+            ));
+        };
+        let current = match std::fs::read(&path) {
+            Ok(bytes) => {
+                String::from_utf8(bytes).map_err(|_| fail("the file is not UTF-8 text"))?
+            }
+            Err(_) => return Err(fail("no such file; use `*** Add File:` to create it")),
+        };
+        out.push_str(UPDATE_FILE_MARKER);
+        out.push_str(&path);
+        out.push_str("\n@@\n");
+        for old in current.lines() {
+            out.push('-');
+            out.push_str(old);
+            out.push('\n');
+        }
+        for new in contents {
+            out.push('+');
+            out.push_str(new);
+            out.push('\n');
+        }
+        out.push_str(EOF_MARKER);
+        out.push('\n');
+    }
+    Ok(Cow::Owned(out))
+}
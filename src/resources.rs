@@ -0,0 +1,124 @@
+//! Resource usage of one patch: wall time, CPU time, peak memory, bytes
+//! read and written, and the number of files it touches.
+//!
+//! Every audit entry records it under `resources`, and `--timings` prints
+//! it on stderr, for capacity planning of long-running deployments and for
+//! spotting pathological patches. The numbers come from `/proc/self`, so
+//! on other systems only the wall time and the file count are known. CPU
+//! time includes finished child processes (git, formatters); bytes count
+//! every read and write call, pipes included. Peak memory is the process's,
+//! so under `--watch` it covers the patches before too.
+
+use serde::Serialize;
+use std::time::Instant;
+
+/// Kernel clock ticks per second as reported in `/proc/self/stat`.
+const USER_HZ: u64 = 100;
+
+#[derive(Debug, Clone, Copy, Default)]
+struct Counters {
+    cpu_user_ms: Option<u64>,
+    cpu_system_ms: Option<u64>,
+    bytes_read: Option<u64>,
+    bytes_written: Option<u64>,
+}
+
+fn proc_field(file: &str, key: &str) -> Option<u64> {
+    let text = std::fs::read_to_string(format!("/proc/self/{file}")).ok()?;
+    text.lines()
+        .find_map(|line| line.strip_prefix(key)?.strip_prefix(':'))?
+        .split_whitespace()
+        .next()?
+        .parse()
+        .ok()
+}
+
+fn counters() -> Counters {
+    let ticks = std::fs::read_to_string("/proc/self/stat")
+        .ok()
+        .and_then(|stat| {
+            // Fields after the command name, which may contain spaces.
+            let fields: Vec<u64> = stat
+                .rsplit_once(')')?
+                .1
+                .split_whitespace()
+                .skip(11)
+                .take(4)
+                .map(|field| field.parse().ok())
+                .collect::<Option<_>>()?;
+            let [utime, stime, cutime, cstime] = fields[..] else {
+                return None;
+            };
+            Some((utime + cutime, stime + cstime))
+        });
+    Counters {
+        cpu_user_ms: ticks.map(|(user, _)| user * 1000 / USER_HZ),
+        cpu_system_ms: ticks.map(|(_, system)| system * 1000 / USER_HZ),
+        bytes_read: proc_field("io", "rchar"),
+        bytes_written: proc_field("io", "wchar"),
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub(crate) struct Usage {
+    pub(crate) wall_ms: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) cpu_user_ms: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) cpu_system_ms: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) peak_rss_kb: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) bytes_read: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) bytes_written: Option<u64>,
+    pub(crate) files: usize,
+}
+
+impl Usage {
+    /// One line for `--timings`.
+    pub(crate) fn describe(&self) -> String {
+        let mut parts = vec![format!("{} ms wall", self.wall_ms)];
+        if let (Some(user), Some(system)) = (self.cpu_user_ms, self.cpu_system_ms) {
+            parts.push(format!("{user} ms user + {system} ms system CPU"));
+        }
+        if let Some(rss) = self.peak_rss_kb {
+            parts.push(format!("peak RSS {rss} KiB"));
+        }
+        if let (Some(read), Some(written)) = (self.bytes_read, self.bytes_written) {
+            parts.push(format!("{read} bytes read, {written} written"));
+        }
+        parts.push(format!("{} file(s)", self.files));
+        format!("Timings: {}", parts.join(", "))
+    }
+}
+
+/// Measures from [`Meter::start`] to [`Meter::finish`].
+pub(crate) struct Meter {
+    started: Instant,
+    counters: Counters,
+}
+
+impl Meter {
+    pub(crate) fn start() -> Self {
+        Self {
+            started: Instant::now(),
+            counters: counters(),
+        }
+    }
+
+    /// The usage since the meter started, for a patch touching `files`.
+    pub(crate) fn finish(&self, files: usize) -> Usage {
+        let now = counters();
+        let since = |now: Option<u64>, then: Option<u64>| Some(now?.saturating_sub(then?));
+        Usage {
+            wall_ms: u64::try_from(self.started.elapsed().as_millis()).unwrap_or(u64::MAX),
+            cpu_user_ms: since(now.cpu_user_ms, self.counters.cpu_user_ms),
+            cpu_system_ms: since(now.cpu_system_ms, self.counters.cpu_system_ms),
+            peak_rss_kb: proc_field("status", "VmHWM"),
+            bytes_read: since(now.bytes_read, self.counters.bytes_read),
+            bytes_written: since(now.bytes_written, self.counters.bytes_written),
+            files,
+        }
+    }
+}
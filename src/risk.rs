@@ -0,0 +1,227 @@
+//! Heuristic risk scoring for patches and the `assess` subcommand.
+//!
+//! Each risky operation adds a fixed number of points; the score is their
+//! sum. Policy rules see it as `risk`, so automation can route high-scoring
+//! agent patches to `warn` or `refuse` without a human reading every diff.
+
+use crate::diagnostics;
+use crate::glob::glob_match;
+use crate::patch::Patch;
+use crate::patch::SectionKind;
+use serde::Serialize;
+
+const DELETE_POINTS: u32 = 20;
+const CI_POINTS: u32 = 25;
+const MANIFEST_POINTS: u32 = 15;
+const LARGE_DELTA_POINTS: u32 = 10;
+const EXECUTABLE_POINTS: u32 = 15;
+const SECRET_POINTS: u32 = 30;
+
+/// Lines changed in one file before it counts as a large delta.
+const LARGE_DELTA_LINES: usize = 200;
+
+const CI_PATTERNS: &[&str] = &[
+    ".github/workflows/**",
+    ".github/actions/**",
+    ".gitlab-ci.yml",
+    ".circleci/**",
+    ".buildkite/**",
+    ".travis.yml",
+    "Jenkinsfile",
+    "azure-pipelines.yml",
+];
+
+const MANIFEST_PATTERNS: &[&str] = &[
+    "Cargo.toml",
+    "Cargo.lock",
+    "package.json",
+    "package-lock.json",
+    "yarn.lock",
+    "pnpm-lock.yaml",
+    "requirements*.txt",
+    "pyproject.toml",
+    "poetry.lock",
+    "Pipfile",
+    "Pipfile.lock",
+    "go.mod",
+    "go.sum",
+    "Gemfile",
+    "Gemfile.lock",
+    "pom.xml",
+    "build.gradle",
+    "build.gradle.kts",
+];
+
+const EXECUTABLE_PATTERNS: &[&str] = &["*.sh", "*.bash", "*.exe", "*.bat", "*.cmd", "*.ps1"];
+
+/// Substrings that only appear in credentials.
+const SECRET_MARKERS: &[&str] = &[
+    "PRIVATE KEY-----",
+    "ghp_",
+    "github_pat_",
+    "xoxb-",
+    "xoxp-",
+    "sk_live_",
+];
+
+/// Assignment keys that suggest a hard-coded secret when given a literal.
+const SECRET_KEYS: &[&str] = &["api_key", "apikey", "secret", "password", "passwd", "token"];
+
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct Finding {
+    pub(crate) points: u32,
+    pub(crate) path: String,
+    pub(crate) reason: String,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub(crate) struct RiskReport {
+    pub(crate) score: u32,
+    pub(crate) findings: Vec<Finding>,
+}
+
+impl RiskReport {
+    fn add(&mut self, points: u32, path: &str, reason: impl Into<String>) {
+        self.score += points;
+        self.findings.push(Finding {
+            points,
+            path: path.to_string(),
+            reason: reason.into(),
+        });
+    }
+}
+
+pub(crate) fn assess(patch: &Patch) -> RiskReport {
+    let mut report = RiskReport::default();
+    for section in &patch.sections {
+        let path = section.path.as_str();
+        let targets: Vec<&str> = std::iter::once(path).chain(section.move_to()).collect();
+
+        if section.kind == SectionKind::Delete {
+            report.add(DELETE_POINTS, path, "deletes a file");
+        }
+        if targets.iter().any(|p| matches_any(CI_PATTERNS, p)) {
+            report.add(CI_POINTS, path, "touches CI/workflow configuration");
+        }
+        if targets.iter().any(|p| matches_any(MANIFEST_PATTERNS, p)) {
+            report.add(MANIFEST_POINTS, path, "changes a dependency manifest");
+        }
+
+        let (added, removed) = section.line_counts();
+        if added + removed >= LARGE_DELTA_LINES {
+            report.add(
+                LARGE_DELTA_POINTS,
+                path,
+                format!("changes {} lines", added + removed),
+            );
+        }
+
+        if section.kind == SectionKind::Add {
+            if section.added_contents().starts_with("#!") {
+                report.add(EXECUTABLE_POINTS, path, "adds a script with a shebang");
+            } else if matches_any(EXECUTABLE_PATTERNS, path) {
+                report.add(EXECUTABLE_POINTS, path, "adds an executable file type");
+            }
+        }
+
+        if section.kind != SectionKind::Delete
+            && section
+                .body
+                .iter()
+                .filter_map(|l| l.strip_prefix('+'))
+                .any(looks_like_secret)
+        {
+            report.add(SECRET_POINTS, path, "adds secret-looking content");
+        }
+    }
+    report
+}
+
+fn matches_any(patterns: &[&str], path: &str) -> bool {
+    patterns.iter().any(|p| glob_match(p, path))
+}
+
+fn looks_like_secret(line: &str) -> bool {
+    if SECRET_MARKERS.iter().any(|m| line.contains(m)) || has_aws_key_id(line) {
+        return true;
+    }
+    let lower = line.to_ascii_lowercase();
+    SECRET_KEYS.iter().any(|key| {
+        lower.match_indices(key).any(|(idx, _)| {
+            let rest = lower[idx + key.len()..].trim_start_matches(|c: char| {
+                c.is_ascii_alphanumeric() || c == '_' || c == '"' || c == '\''
+            });
+            let Some(value) = rest.trim_start().strip_prefix(['=', ':']) else {
+                return false;
+            };
+            let value = value.trim_start();
+            let Some(quote) = value.chars().next().filter(|c| *c == '"' || *c == '\'') else {
+                return false;
+            };
+            let literal = value[1..].split(quote).next().unwrap_or_default();
+            literal.len() >= 8 && !literal.contains(char::is_whitespace)
+        })
+    })
+}
+
+/// `AKIA` followed by 16 upper-case alphanumerics (an AWS access key id).
+fn has_aws_key_id(line: &str) -> bool {
+    line.match_indices("AKIA").any(|(idx, _)| {
+        let rest = &line.as_bytes()[idx + 4..];
+        rest.len() >= 16
+            && rest[..16]
+                .iter()
+                .all(|b| b.is_ascii_uppercase() || b.is_ascii_digit())
+    })
+}
+
+/// `apply_patch assess [--json] [--] [PATCH]`
+pub(crate) fn run_assess(args: &[String]) -> i32 {
+    let mut json = false;
+    let mut positional: Vec<String> = Vec::new();
+    for (i, arg) in args.iter().enumerate() {
+        match arg.as_str() {
+            "--json" => json = true,
+            "--" => {
+                positional.extend(args[i + 1..].iter().cloned());
+                break;
+            }
+            other if other.starts_with('-') => {
+                diagnostics::error(format!("unknown option: {other}"));
+                return 2;
+            }
+            other => positional.push(other.to_string()),
+        }
+    }
+    let patch_text = match crate::read_patch(&positional) {
+        Ok(s) => s,
+        Err(code) => return code,
+    };
+    let Some(patch) = Patch::parse(&patch_text) else {
+        eprintln!("Invalid patch: The first line of the patch must be '*** Begin Patch'");
+        return 1;
+    };
+
+    let report = assess(&patch);
+    if json {
+        match serde_json::to_string(&report) {
+            Ok(line) => println!("{line}"),
+            Err(err) => {
+                diagnostics::error(format!("failed to serialize risk report: {err}"));
+                return 1;
+            }
+        }
+        return 0;
+    }
+    println!("Risk score: {}", report.score);
+    if report.findings.is_empty() {
+        println!("  No risky operations found.");
+    }
+    for finding in &report.findings {
+        println!(
+            "  +{:<3} {}: {}",
+            finding.points, finding.path, finding.reason
+        );
+    }
+    0
+}
@@ -0,0 +1,107 @@
+//! `apply_patch roundtrip [FILE...]`: checks that patches survive parsing
+//! and rendering, for fuzzers and regression fixtures.
+//!
+//! Each input (a file, or stdin read as lossy UTF-8 when none is given) is
+//! parsed; a recognized patch is rendered, and the rendering must parse and
+//! render back to itself. Input that isn't a patch is fine, since the
+//! applier reports it; a panic anywhere is a bug. External fuzzers that feed
+//! stdin can drive this command directly; the check itself is the library's
+//! `check_roundtrip`, which the `cargo fuzz` target in `fuzz/` runs.
+
+use crate::diagnostics;
+use crate::patch;
+use std::io::Read;
+
+pub(crate) fn run_roundtrip(args: &[String]) -> i32 {
+    if let Some(flag) = args.iter().find(|a| a.starts_with('-') && *a != "-") {
+        diagnostics::error(format!("unknown option: {flag}"));
+        return 2;
+    }
+    let mut inputs: Vec<(String, String)> = Vec::new();
+    for arg in args.iter().filter(|a| *a != "-") {
+        match std::fs::read(arg) {
+            Ok(bytes) => inputs.push((arg.clone(), String::from_utf8_lossy(&bytes).into_owned())),
+            Err(err) => {
+                diagnostics::error(format!("failed to read {arg}: {err}"));
+                return 1;
+            }
+        }
+    }
+    if args.is_empty() || args.iter().any(|a| a == "-") {
+        let mut bytes = Vec::new();
+        if let Err(err) = std::io::stdin().read_to_end(&mut bytes) {
+            diagnostics::error(format!("Failed to read PATCH from stdin.\n{err}"));
+            return 1;
+        }
+        inputs.push((
+            "stdin".to_string(),
+            String::from_utf8_lossy(&bytes).into_owned(),
+        ));
+    }
+
+    for (name, text) in &inputs {
+        if let Err(msg) = check_roundtrip(text) {
+            diagnostics::error(format!("{name} does not round-trip: {msg}"));
+            return 1;
+        }
+    }
+    println!("{} input(s) round-trip.", inputs.len());
+    0
+}
+
+/// A patch as the applier's envelope parser reads it.
+#[derive(Debug, Clone)]
+pub struct ParsedPatch(patch::Patch);
+
+impl ParsedPatch {
+    /// Number of file sections.
+    pub fn section_count(&self) -> usize {
+        self.0.sections.len()
+    }
+}
+
+/// Parses `text` as a patch; `None` if it isn't one.
+pub fn parse_patch(text: &str) -> Option<ParsedPatch> {
+    patch::Patch::parse(text).map(ParsedPatch)
+}
+
+/// Renders `patch` back to patch text.
+pub fn render_patch(patch: &ParsedPatch) -> String {
+    patch.0.render()
+}
+
+/// Why `text` doesn't round-trip, if it doesn't: a patch must render to
+/// text that parses and renders back to itself. Text that isn't a patch
+/// passes.
+pub fn check_roundtrip(text: &str) -> Result<(), String> {
+    let Some(patch) = parse_patch(text) else {
+        return Ok(());
+    };
+    let rendered = render_patch(&patch);
+    let Some(reparsed) = parse_patch(&rendered) else {
+        return Err("its rendering no longer parses as a patch".to_string());
+    };
+    if reparsed.section_count() != patch.section_count() {
+        return Err(format!(
+            "it has {} sections, its rendering {}",
+            patch.section_count(),
+            reparsed.section_count()
+        ));
+    }
+    let again = render_patch(&reparsed);
+    if let Some((n, (want, got))) = rendered
+        .split_inclusive('\n')
+        .zip(again.split_inclusive('\n'))
+        .enumerate()
+        .find(|(_, (want, got))| want != got)
+    {
+        return Err(format!(
+            "rendering is not stable at line {}: {want:?} became {got:?}",
+            n + 1
+        ));
+    }
+    if again != rendered {
+        return Err("rendering is not stable: the line count changed".to_string());
+    }
+    Ok(())
+}
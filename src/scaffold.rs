@@ -0,0 +1,133 @@
+//! `*** Render Template: NAME -> PATH` sections: a file rendered from a
+//! locally configured template instead of being spelled out in the patch.
+//!
+//! Templates live in `templates/` next to the config file (normally
+//! `~/.apply_patch/templates/NAME`) and use `{{var}}` placeholders. The
+//! section body sets them as `+var=value` lines. Like archives, template
+//! sections are expanded into ordinary `*** Add File:` sections before
+//! anything else looks at the patch. A missing template, an unset
+//! placeholder or a variable the template doesn't use fails the patch.
+
+use crate::patch::ADD_FILE_MARKER;
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+
+pub(crate) const RENDER_TEMPLATE_MARKER: &str = "*** Render Template: ";
+
+/// Where templates are read from.
+pub(crate) fn templates_dir() -> Option<PathBuf> {
+    Some(crate::config_path()?.parent()?.join("templates"))
+}
+
+/// Replaces every template section in `patch_text` with an add section for
+/// the rendered file. Patches without template sections are returned
+/// unchanged.
+pub(crate) fn expand(patch_text: &str) -> Result<Cow<'_, str>, String> {
+    if !patch_text
+        .lines()
+        .any(|line| line.trim_start().starts_with(RENDER_TEMPLATE_MARKER))
+    {
+        return Ok(Cow::Borrowed(patch_text));
+    }
+    let mut out = String::new();
+    let mut lines = patch_text.lines().peekable();
+    while let Some(line) = lines.next() {
+        let Some(spec) = line.trim().strip_prefix(RENDER_TEMPLATE_MARKER) else {
+            out.push_str(line);
+            out.push('\n');
+            continue;
+        };
+        let Some((name, path)) = spec.split_once(" -> ") else {
+            return Err(format!(
+                "Invalid template section: expected `{RENDER_TEMPLATE_MARKER}NAME -> PATH`, got `{}`",
+                line.trim()
+            ));
+        };
+        let (name, path) = (name.trim(), path.trim());
+        let fail = |msg: String| format!("Invalid template section for {path}: {msg}");
+        let mut vars: BTreeMap<&str, &str> = BTreeMap::new();
+        while let Some(next) = lines.next_if(|l| !l.trim_start().starts_with("***")) {
+            if next.trim().is_empty() {
+                continue;
+            }
+            let Some((key, value)) = next.strip_prefix('+').and_then(|l| l.split_once('=')) else {
+                return Err(fail(format!("expected `+name=value`, got `{next}`")));
+            };
+            if !is_identifier(key) {
+                return Err(fail(format!("invalid variable name `{key}`")));
+            }
+            if vars.insert(key, value).is_some() {
+                return Err(fail(format!("variable `{key}` is set twice")));
+            }
+        }
+        let template = load(name).map_err(fail)?;
+        let rendered = render(&template, &vars).map_err(fail)?;
+        out.push_str(ADD_FILE_MARKER);
+        out.push_str(path);
+        out.push('\n');
+        for content_line in rendered.lines() {
+            out.push('+');
+            out.push_str(content_line);
+            out.push('\n');
+        }
+    }
+    Ok(Cow::Owned(out))
+}
+
+fn load(name: &str) -> Result<String, String> {
+    let valid = !name.is_empty()
+        && !name.starts_with('.')
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'));
+    if !valid {
+        return Err(format!("invalid template name `{name}`"));
+    }
+    let Some(dir) = templates_dir() else {
+        return Err("could not determine the templates directory".to_string());
+    };
+    let path = dir.join(name);
+    std::fs::read_to_string(&path)
+        .map_err(|err| format!("cannot read template {}: {err}", path.display()))
+}
+
+/// Fills `{{var}}` placeholders from `vars`, which must cover every
+/// placeholder and contain nothing else.
+fn render(template: &str, vars: &BTreeMap<&str, &str>) -> Result<String, String> {
+    let mut out = String::with_capacity(template.len());
+    let mut used: Vec<&str> = Vec::new();
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        out.push_str(&rest[..start]);
+        let after = &rest[start + 2..];
+        let Some(end) = after.find("}}") else {
+            return Err("template has an unclosed `{{`".to_string());
+        };
+        let name = after[..end].trim();
+        if !is_identifier(name) {
+            return Err(format!(
+                "template has an invalid placeholder `{{{{{name}}}}}`"
+            ));
+        }
+        let Some(value) = vars.get(name) else {
+            return Err(format!("variable `{name}` is not set"));
+        };
+        out.push_str(value);
+        used.push(name);
+        rest = &after[end + 2..];
+    }
+    out.push_str(rest);
+    if let Some(unused) = vars.keys().find(|key| !used.contains(key)) {
+        return Err(format!("the template does not use variable `{unused}`"));
+    }
+    Ok(out)
+}
+
+fn is_identifier(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
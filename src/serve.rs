@@ -0,0 +1,517 @@
+//! `apply_patch serve-http [--bind ADDR]`: a small HTTP front end for
+//! orchestrators on the same host.
+//!
+//! `POST /apply` and `POST /validate` take a patch (or, with
+//! `Content-Type: application/json`, a tool-call object as for
+//! `--input tool-json`) and answer with the exit code, stdout and stderr of
+//! the equivalent CLI run, so responses match the CLI exactly. `GET /status`
+//! reports the effective mode, and `POST /reload` reads the config again
+//! without waiting for the file to look changed. Every request needs
+//! `Authorization: Bearer $APPLY_PATCH_HTTP_TOKEN`.
+//!
+//! Patches run in this process, against a config cached between requests,
+//! with their output captured. Each connection is read and answered on its
+//! own thread, so a slow client holds up nobody else; the runs themselves
+//! take turns, so patches never race each other. A client gets 30 seconds
+//! to send its request head and 30 more for its body, which is only read
+//! once the token checks out; request lines, headers and the number of
+//! connections served at once are capped.
+
+use crate::capture::capture;
+use crate::config_cache::ConfigCache;
+use crate::diagnostics;
+use crate::exit;
+use crate::toolcall::ToolCall;
+use serde::Serialize;
+use std::io::BufRead;
+use std::io::BufReader;
+use std::io::Read;
+use std::io::Write;
+use std::net::Shutdown;
+use std::net::TcpListener;
+use std::net::TcpStream;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::PoisonError;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+use std::time::Instant;
+
+pub(crate) const TOKEN_ENV: &str = "APPLY_PATCH_HTTP_TOKEN";
+const DEFAULT_BIND: &str = "127.0.0.1:8787";
+/// Largest request body accepted, in bytes.
+const MAX_BODY: usize = 16 * 1024 * 1024;
+/// How long a client may take to send its request head, then its body, and
+/// to read the answer.
+const IO_TIMEOUT: Duration = Duration::from_secs(30);
+/// Longest request line or header line accepted, in bytes.
+const MAX_LINE: usize = 8 * 1024;
+/// Most header lines accepted.
+const MAX_HEADERS: usize = 64;
+/// Largest request head (request line and headers) accepted, in bytes.
+const MAX_HEAD: usize = 32 * 1024;
+/// Most connections served at once; more are answered 503 and closed.
+const MAX_CONNECTIONS: usize = 32;
+
+#[derive(Serialize)]
+struct RunResult {
+    exit_code: i32,
+    stdout: String,
+    stderr: String,
+}
+
+#[derive(Serialize)]
+struct Reloaded {
+    reloaded: bool,
+    mode: &'static str,
+}
+
+#[derive(Serialize)]
+struct Status {
+    mode: &'static str,
+    requests: u64,
+    uptime_secs: u64,
+}
+
+struct Request {
+    method: String,
+    path: String,
+    authorization: Option<String>,
+    json: bool,
+    body: Vec<u8>,
+}
+
+struct Response {
+    status: &'static str,
+    body: String,
+}
+
+impl Response {
+    fn error(status: &'static str, message: &str) -> Self {
+        Self {
+            status,
+            body: serde_json::json!({ "error": message }).to_string(),
+        }
+    }
+
+    fn write_to(&self, stream: &mut TcpStream) {
+        let _ = write!(
+            stream,
+            "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            self.status,
+            self.body.len(),
+            self.body
+        );
+    }
+
+    fn json(value: &impl Serialize) -> Self {
+        match serde_json::to_string(value) {
+            Ok(body) => Self {
+                status: "200 OK",
+                body,
+            },
+            Err(err) => Self::error("500 Internal Server Error", &err.to_string()),
+        }
+    }
+}
+
+struct Server {
+    token: String,
+    started: Instant,
+    requests: AtomicU64,
+    /// Connections being served.
+    open: AtomicUsize,
+    /// Runs share the process's working directory, stdout and stderr, so
+    /// they take turns.
+    runner: Mutex<Runner>,
+}
+
+struct Runner {
+    config: ConfigCache,
+    /// `$APPLY_PATCH_TENANT`, as the CLI would read it.
+    tenant: Option<String>,
+    /// The server's working directory, which a tool call's `cwd` is
+    /// relative to.
+    home: PathBuf,
+}
+
+pub(crate) fn run_serve_http(args: &[String]) -> i32 {
+    let mut bind = DEFAULT_BIND.to_string();
+    let mut i = 0;
+    while i < args.len() {
+        match args[i].as_str() {
+            "--bind" => {
+                let Some(addr) = args.get(i + 1) else {
+                    diagnostics::error("--bind requires a value.");
+                    return 2;
+                };
+                bind = addr.clone();
+                i += 2;
+            }
+            other => {
+                diagnostics::error(format!("unknown option: {other}"));
+                return 2;
+            }
+        }
+    }
+    let Some(token) = std::env::var(TOKEN_ENV).ok().filter(|t| !t.is_empty()) else {
+        diagnostics::error(format!("serve-http requires ${TOKEN_ENV} to be set."));
+        return 2;
+    };
+    let tenant = match crate::tenant::resolve(None) {
+        Ok(tenant) => tenant,
+        Err(err) => {
+            eprintln!("{err}");
+            return 2;
+        }
+    };
+    let home = match std::env::current_dir() {
+        Ok(dir) => dir,
+        Err(err) => {
+            diagnostics::error(format!("cannot resolve the working directory: {err}"));
+            return 1;
+        }
+    };
+    let listener = match TcpListener::bind(&bind) {
+        Ok(listener) => listener,
+        Err(err) => {
+            diagnostics::error(format!("cannot listen on {bind}: {err}"));
+            return 1;
+        }
+    };
+    match listener.local_addr() {
+        Ok(addr) => println!("Listening on http://{addr}"),
+        Err(_) => println!("Listening on http://{bind}"),
+    }
+    let _ = std::io::stdout().flush();
+
+    let server = Arc::new(Server {
+        token,
+        started: Instant::now(),
+        requests: AtomicU64::new(0),
+        open: AtomicUsize::new(0),
+        runner: Mutex::new(Runner {
+            config: ConfigCache::default(),
+            tenant,
+            home,
+        }),
+    });
+    for stream in listener.incoming() {
+        let Ok(mut stream) = stream else {
+            continue;
+        };
+        if server.open.fetch_add(1, Ordering::AcqRel) >= MAX_CONNECTIONS {
+            server.open.fetch_sub(1, Ordering::AcqRel);
+            // The answer fits in the socket buffer; don't wait on a client
+            // that won't read it.
+            let _ = stream.set_write_timeout(Some(Duration::from_secs(1)));
+            let busy = Response::error("503 Service Unavailable", "too many connections");
+            busy.write_to(&mut stream);
+            continue;
+        }
+        let server = Arc::clone(&server);
+        std::thread::spawn(move || {
+            server.serve(stream);
+            server.open.fetch_sub(1, Ordering::AcqRel);
+        });
+    }
+    0
+}
+
+impl Server {
+    fn serve(&self, mut stream: TcpStream) {
+        let requests = self.requests.fetch_add(1, Ordering::Relaxed) + 1;
+        let timeouts = stream
+            .set_read_timeout(Some(IO_TIMEOUT))
+            .and_then(|()| stream.set_write_timeout(Some(IO_TIMEOUT)));
+        let response = match timeouts
+            .map_err(|err| Response::error("500 Internal Server Error", &err.to_string()))
+            .and_then(|()| self.read_authorized(&stream))
+        {
+            Ok(request) => self.route(&request, requests),
+            Err(response) => response,
+        };
+        response.write_to(&mut stream);
+        // Closing with unread input resets the connection, which can cost
+        // the client the answer; take a little of what's left first.
+        let _ = stream.shutdown(Shutdown::Write);
+        let _ = stream.set_read_timeout(Some(Duration::from_secs(1)));
+        let _ = std::io::copy(&mut (&stream).take(MAX_HEAD as u64), &mut std::io::sink());
+    }
+
+    /// Reads the request head, checks the token, and only then reads the
+    /// body.
+    fn read_authorized(&self, stream: &TcpStream) -> Result<Request, Response> {
+        let mut reader = BufReader::new(Deadline {
+            stream,
+            until: Instant::now() + IO_TIMEOUT,
+        });
+        let (mut request, length) = read_head(&mut reader)?;
+        let expected = format!("Bearer {}", self.token);
+        if !request
+            .authorization
+            .as_deref()
+            .is_some_and(|auth| same(auth, &expected))
+        {
+            return Err(Response::error(
+                "401 Unauthorized",
+                "missing or invalid bearer token",
+            ));
+        }
+        if length > MAX_BODY {
+            return Err(Response::error("413 Payload Too Large", "body too large"));
+        }
+        reader.get_mut().until = Instant::now() + IO_TIMEOUT;
+        request.body = vec![0; length];
+        reader
+            .read_exact(&mut request.body)
+            .map_err(|err| unreadable(err, "truncated body"))?;
+        Ok(request)
+    }
+
+    fn route(&self, request: &Request, requests: u64) -> Response {
+        // A run that panicked still put stdout and stderr back.
+        let runner = || self.runner.lock().unwrap_or_else(PoisonError::into_inner);
+        match (request.method.as_str(), request.path.as_str()) {
+            ("GET", "/status") => {
+                let mut runner = runner();
+                let options = runner.options();
+                let mode = match runner.config.current(&options) {
+                    Ok(cfg) => cfg.mode.as_str(),
+                    Err(err) => return Response::error("500 Internal Server Error", err),
+                };
+                Response::json(&Status {
+                    mode,
+                    requests,
+                    uptime_secs: self.started.elapsed().as_secs(),
+                })
+            }
+            ("POST", "/reload") => {
+                let mut runner = runner();
+                let options = runner.options();
+                match runner.config.reload(&options) {
+                    Ok(cfg) => Response::json(&Reloaded {
+                        reloaded: true,
+                        mode: cfg.mode.as_str(),
+                    }),
+                    Err(err) => Response::error("500 Internal Server Error", err),
+                }
+            }
+            ("POST", "/apply") => {
+                let body = String::from_utf8_lossy(&request.body);
+                let mut runner = runner();
+                run(|| runner.apply(&body, request.json))
+            }
+            ("POST", "/validate") => {
+                let body = String::from_utf8_lossy(&request.body);
+                let (cwd, patch) = if request.json {
+                    match ToolCall::parse(&body) {
+                        Ok(call) => (call.cwd, call.patch),
+                        Err(err) => return Response::error("400 Bad Request", &err),
+                    }
+                } else {
+                    (None, body.into_owned())
+                };
+                let mut runner = runner();
+                run(|| runner.validate(cwd, patch))
+            }
+            (_, "/status" | "/reload" | "/apply" | "/validate") => {
+                Response::error("405 Method Not Allowed", "method not allowed")
+            }
+            _ => Response::error("404 Not Found", "not found"),
+        }
+    }
+}
+
+/// Runs `f` with its output captured, and answers with its exit code and
+/// output. The caller holds the runner, so captures never overlap.
+fn run(f: impl FnOnce() -> i32) -> Response {
+    diagnostics::discard();
+    match capture(f) {
+        Ok((exit_code, stdout, stderr)) => Response::json(&RunResult {
+            exit_code,
+            stdout,
+            stderr,
+        }),
+        Err(err) => Response::error("500 Internal Server Error", &err.to_string()),
+    }
+}
+
+impl Runner {
+    fn options(&self) -> crate::RunOptions {
+        crate::RunOptions {
+            tenant: self.tenant.clone(),
+            ..Default::default()
+        }
+    }
+
+    /// `apply_patch` (with `--input tool-json` if `json`) with `body` on
+    /// stdin. Returns the exit code.
+    fn apply(&mut self, body: &str, json: bool) -> i32 {
+        let mut options = self.options();
+        let cfg = match self.config.current(&options) {
+            Ok(cfg) => cfg,
+            Err(err) => {
+                diagnostics::error(err);
+                return exit::Exit::ConfigError.code();
+            }
+        };
+        options.dry_run = cfg.readonly;
+        let mut patch = body.to_string();
+        let mut cwd = None;
+        if json {
+            let call = match ToolCall::parse(body) {
+                Ok(call) => call,
+                Err(err) => {
+                    diagnostics::error(err);
+                    return exit::Exit::ParseError.code();
+                }
+            };
+            patch = call.patch;
+            cwd = call.cwd;
+            options.metadata = call.metadata;
+        }
+        in_dir(&self.home, cwd, || {
+            crate::process_patch(cfg, &patch, &options).map_or_else(|code| code, |o| o.code)
+        })
+    }
+
+    /// `apply_patch compare --json` with `patch` on stdin, in `cwd`.
+    fn validate(&mut self, cwd: Option<PathBuf>, patch: String) -> i32 {
+        let args = ["--json".to_string(), "--".to_string(), patch];
+        in_dir(&self.home, cwd, || crate::compare::run_compare(&args))
+    }
+}
+
+/// Runs `f` in `dir` (relative to `home`) and then back in `home`.
+pub(crate) fn in_dir(home: &std::path::Path, dir: Option<PathBuf>, f: impl FnOnce() -> i32) -> i32 {
+    if let Some(dir) = &dir
+        && let Err(err) = std::env::set_current_dir(dir)
+    {
+        diagnostics::error(format!(
+            "cannot use {} as the working directory: {err}",
+            dir.display()
+        ));
+        return 2;
+    }
+    let code = f();
+    if let Err(err) = std::env::set_current_dir(home) {
+        diagnostics::error(format!("cannot return to {}: {err}", home.display()));
+    }
+    code
+}
+
+/// A connection read under one deadline for a whole part of the request,
+/// so a client dripping bytes can't keep it open by beating a per-read
+/// timeout.
+struct Deadline<'a> {
+    stream: &'a TcpStream,
+    until: Instant,
+}
+
+impl Read for Deadline<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let left = self.until.saturating_duration_since(Instant::now());
+        if left.is_zero() {
+            return Err(std::io::ErrorKind::TimedOut.into());
+        }
+        self.stream.set_read_timeout(Some(left))?;
+        let mut stream = self.stream;
+        stream.read(buf)
+    }
+}
+
+fn bad(message: &str) -> Response {
+    Response::error("400 Bad Request", message)
+}
+
+fn unreadable(err: std::io::Error, message: &str) -> Response {
+    match err.kind() {
+        std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut => {
+            Response::error("408 Request Timeout", "timed out reading the request")
+        }
+        _ => bad(message),
+    }
+}
+
+/// One line of the request head, at most [`MAX_LINE`] bytes and within
+/// what is left of `budget`.
+fn read_head_line(
+    reader: &mut impl BufRead,
+    budget: &mut usize,
+    message: &str,
+) -> Result<String, Response> {
+    let too_large = || {
+        Response::error(
+            "431 Request Header Fields Too Large",
+            "request head too large",
+        )
+    };
+    let limit = MAX_LINE.min(*budget);
+    let mut line = Vec::new();
+    reader
+        .take(limit as u64 + 1)
+        .read_until(b'\n', &mut line)
+        .map_err(|err| unreadable(err, message))?;
+    if line.len() > limit || (line.len() == limit && !line.ends_with(b"\n")) {
+        return Err(too_large());
+    }
+    *budget -= line.len();
+    String::from_utf8(line).map_err(|_| bad(message))
+}
+
+/// The request line and headers, and the body's length.
+fn read_head(reader: &mut impl BufRead) -> Result<(Request, usize), Response> {
+    let mut budget = MAX_HEAD;
+    let line = read_head_line(reader, &mut budget, "unreadable request")?;
+    let mut parts = line.split_whitespace();
+    let (Some(method), Some(target)) = (parts.next(), parts.next()) else {
+        return Err(bad("malformed request line"));
+    };
+    let mut request = Request {
+        method: method.to_string(),
+        path: target.split('?').next().unwrap_or(target).to_string(),
+        authorization: None,
+        json: false,
+        body: Vec::new(),
+    };
+    let mut length = 0;
+    for count in 0.. {
+        let header = read_head_line(reader, &mut budget, "unreadable headers")?;
+        let header = header.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if count == MAX_HEADERS {
+            return Err(Response::error(
+                "431 Request Header Fields Too Large",
+                "too many headers",
+            ));
+        }
+        let Some((name, value)) = header.split_once(':') else {
+            return Err(bad("malformed header"));
+        };
+        let value = value.trim();
+        match name.trim().to_ascii_lowercase().as_str() {
+            "authorization" => request.authorization = Some(value.to_string()),
+            "content-type" => request.json = value.starts_with("application/json"),
+            "content-length" => {
+                length = value.parse().map_err(|_| bad("invalid Content-Length"))?;
+            }
+            _ => {}
+        }
+    }
+    Ok((request, length))
+}
+
+/// Compares two strings in time independent of where they differ.
+fn same(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a.bytes()
+            .zip(b.bytes())
+            .fold(0u8, |acc, (x, y)| acc | (x ^ y))
+            == 0
+}
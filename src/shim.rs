@@ -0,0 +1,211 @@
+//! `apply_patch wrap-shim install|uninstall|status`: manages the small
+//! shell shims that make every spelling agents try (`apply_patch`,
+//! `applypatch`, `apply-patch`) run this binary.
+//!
+//! Shims go into `--dir` (default: `~/.local/bin`). Each is a two-line
+//! `sh` script marked as managed, so only files this command wrote are ever
+//! replaced or removed; other files of the same name are reported as
+//! conflicts and left alone unless `--force` is given. Afterwards each name
+//! is looked up on `PATH`, and a different tool that would run instead is
+//! reported too.
+
+use crate::diagnostics;
+use std::path::Path;
+use std::path::PathBuf;
+
+const SHIM_NAMES: &[&str] = &["apply_patch", "applypatch", "apply-patch"];
+
+const SHIM_MARKER: &str = "# apply_patch shim, managed by `apply_patch wrap-shim`";
+
+fn shim_script(binary: &Path) -> String {
+    let quoted = binary.to_string_lossy().replace('\'', r"'\''");
+    format!("#!/bin/sh\n{SHIM_MARKER}\nexec '{quoted}' \"$@\"\n")
+}
+
+fn is_shim(path: &Path) -> bool {
+    std::fs::read_to_string(path).is_ok_and(|text| text.lines().nth(1) == Some(SHIM_MARKER))
+}
+
+/// Whether running `path` runs `binary`: it is the binary or a shim.
+fn runs_binary(path: &Path, binary: &Path) -> bool {
+    is_shim(path) || std::fs::canonicalize(path).is_ok_and(|real| real == binary)
+}
+
+/// The first executable called `name` on `PATH`.
+fn on_path(name: &str) -> Option<PathBuf> {
+    std::env::split_paths(&std::env::var_os("PATH")?)
+        .map(|dir| dir.join(name))
+        .find(|candidate| candidate.is_file() && is_executable(candidate))
+}
+
+#[cfg(unix)]
+fn is_executable(path: &Path) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+    std::fs::metadata(path).is_ok_and(|meta| meta.permissions().mode() & 0o111 != 0)
+}
+
+#[cfg(not(unix))]
+fn is_executable(_path: &Path) -> bool {
+    true
+}
+
+#[cfg(unix)]
+fn make_executable(path: &Path) -> std::io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o755))
+}
+
+#[cfg(not(unix))]
+fn make_executable(_path: &Path) -> std::io::Result<()> {
+    Ok(())
+}
+
+/// Reports names on `PATH` that would run something other than `binary`,
+/// and a `dir` that isn't on `PATH`. Returns whether anything was reported.
+fn report_path_conflicts(dir: &Path, binary: &Path) -> bool {
+    let mut reported = false;
+    let dir_on_path = std::env::var_os("PATH")
+        .is_some_and(|path| std::env::split_paths(&path).any(|entry| entry == dir));
+    if !dir_on_path {
+        println!("Note: {} is not on PATH.", dir.display());
+        reported = true;
+    }
+    for name in SHIM_NAMES {
+        if let Some(found) = on_path(name)
+            && !runs_binary(&found, binary)
+        {
+            println!(
+                "Conflict: `{name}` on PATH is {}, another tool, which runs instead of this binary.",
+                found.display()
+            );
+            reported = true;
+        }
+    }
+    reported
+}
+
+pub(crate) fn run_wrap_shim(args: &[String]) -> i32 {
+    let usage = || {
+        eprintln!("Usage: apply_patch wrap-shim <install|uninstall|status> [--dir DIR] [--force]");
+        2
+    };
+    let Some(action) = args.first().map(String::as_str) else {
+        return usage();
+    };
+    if !matches!(action, "install" | "uninstall" | "status") {
+        return usage();
+    }
+    let mut dir: Option<PathBuf> = None;
+    let mut force = false;
+    let mut rest = args[1..].iter();
+    while let Some(arg) = rest.next() {
+        match arg.as_str() {
+            "--dir" => match rest.next() {
+                Some(value) => dir = Some(PathBuf::from(value)),
+                None => {
+                    diagnostics::error("--dir requires a value.");
+                    return 2;
+                }
+            },
+            "--force" => force = true,
+            other => {
+                diagnostics::error(format!("unknown option: {other}"));
+                return 2;
+            }
+        }
+    }
+    let Some(dir) = dir.or_else(|| {
+        std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".local").join("bin"))
+    }) else {
+        diagnostics::error("could not determine the shim directory (HOME not set); pass --dir.");
+        return 1;
+    };
+    let binary = match std::env::current_exe().and_then(std::fs::canonicalize) {
+        Ok(binary) => binary,
+        Err(err) => {
+            diagnostics::error(format!("cannot locate this binary: {err}"));
+            return 1;
+        }
+    };
+
+    match action {
+        "install" => install(&dir, &binary, force),
+        "uninstall" => uninstall(&dir),
+        _ => status(&dir, &binary),
+    }
+}
+
+fn install(dir: &Path, binary: &Path, force: bool) -> i32 {
+    if let Err(err) = std::fs::create_dir_all(dir) {
+        diagnostics::error(format!("failed to create {}: {err}", dir.display()));
+        return 1;
+    }
+    let mut code = 0;
+    for name in SHIM_NAMES {
+        let path = dir.join(name);
+        if std::fs::canonicalize(&path).is_ok_and(|real| real == binary) {
+            println!("Kept {}: it is this binary.", path.display());
+            continue;
+        }
+        if path.symlink_metadata().is_ok() && !is_shim(&path) && !force {
+            println!(
+                "Skipped {}: another file is there (pass --force to replace it).",
+                path.display()
+            );
+            code = 1;
+            continue;
+        }
+        // Remove first so a symlink is replaced rather than written through.
+        let _ = std::fs::remove_file(&path);
+        let written =
+            std::fs::write(&path, shim_script(binary)).and_then(|()| make_executable(&path));
+        if let Err(err) = written {
+            diagnostics::error(format!("failed to write {}: {err}", path.display()));
+            code = 1;
+            continue;
+        }
+        println!("Installed {} -> {}", path.display(), binary.display());
+    }
+    report_path_conflicts(dir, binary);
+    code
+}
+
+fn uninstall(dir: &Path) -> i32 {
+    let mut code = 0;
+    for name in SHIM_NAMES {
+        let path = dir.join(name);
+        if path.symlink_metadata().is_err() {
+            continue;
+        }
+        if !is_shim(&path) {
+            println!("Left {}: it is not an apply_patch shim.", path.display());
+            continue;
+        }
+        match std::fs::remove_file(&path) {
+            Ok(()) => println!("Removed {}", path.display()),
+            Err(err) => {
+                diagnostics::error(format!("failed to remove {}: {err}", path.display()));
+                code = 1;
+            }
+        }
+    }
+    code
+}
+
+fn status(dir: &Path, binary: &Path) -> i32 {
+    for name in SHIM_NAMES {
+        let path = dir.join(name);
+        let state = if path.symlink_metadata().is_err() {
+            "not installed"
+        } else if is_shim(&path) {
+            "shim"
+        } else if runs_binary(&path, binary) {
+            "this binary"
+        } else {
+            "another file"
+        };
+        println!("{}: {state}", path.display());
+    }
+    report_path_conflicts(dir, binary);
+    0
+}
@@ -0,0 +1,273 @@
+//! In-memory application of a patch, mirroring the vendored applier's
+//! matching rules (exact, then trailing-whitespace-, whitespace- and
+//! punctuation-insensitive), so read-only commands can show exactly what an
+//! apply would write without touching the working tree.
+
+use crate::patch::Chunk;
+use crate::patch::Patch;
+use crate::patch::SectionKind;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::path::Path;
+
+/// Before/after content of one path touched by a patch. `None` means the
+/// file does not exist on that side.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct FileChange {
+    pub(crate) path: String,
+    pub(crate) before: Option<String>,
+    pub(crate) after: Option<String>,
+}
+
+impl FileChange {
+    /// Single-letter status as printed in the applier's summary.
+    pub(crate) fn status(&self) -> char {
+        match (&self.before, &self.after) {
+            (None, Some(_)) => 'A',
+            (Some(_), None) => 'D',
+            _ => 'M',
+        }
+    }
+}
+
+/// Simulates `patch` against the files under `root`. Later sections see
+/// the results of earlier ones, as they would on disk. Changes are reported
+/// per path in first-touched order.
+pub(crate) fn simulate(patch: &Patch, root: &Path) -> Result<Vec<FileChange>, String> {
+    simulate_with(patch, |path| std::fs::read_to_string(root.join(path)).ok())
+}
+
+/// Like [`simulate`], reading each file's starting contents with `base`
+/// instead of from disk.
+pub(crate) fn simulate_with(
+    patch: &Patch,
+    base: impl Fn(&str) -> Option<String>,
+) -> Result<Vec<FileChange>, String> {
+    let mut overlay: HashMap<String, Option<String>> = HashMap::new();
+    let mut changes: Vec<FileChange> = Vec::new();
+
+    let read = |path: &str, overlay: &HashMap<String, Option<String>>| -> Option<String> {
+        match overlay.get(path) {
+            Some(current) => current.clone(),
+            None => base(path),
+        }
+    };
+
+    for section in &patch.sections {
+        let path = section.path.as_str();
+        match section.kind {
+            SectionKind::Add => {
+                let before = read(path, &overlay);
+                record(&mut changes, path, before, Some(section.added_contents()));
+                overlay.insert(path.to_string(), Some(section.added_contents()));
+            }
+            SectionKind::Delete => {
+                let Some(before) = read(path, &overlay) else {
+                    return Err(format!("Failed to delete file {path}: file does not exist"));
+                };
+                record(&mut changes, path, Some(before), None);
+                overlay.insert(path.to_string(), None);
+            }
+            SectionKind::Update => {
+                let Some(before) = read(path, &overlay) else {
+                    return Err(format!("Failed to read file to update {path}"));
+                };
+                let after = derive_new_contents(&before, path, &section.chunks()?)?;
+                match section.move_to() {
+                    Some(dest) => {
+                        let dest_before = read(dest, &overlay);
+                        record(&mut changes, path, Some(before), None);
+                        record(&mut changes, dest, dest_before, Some(after.clone()));
+                        overlay.insert(path.to_string(), None);
+                        overlay.insert(dest.to_string(), Some(after));
+                    }
+                    None => {
+                        record(&mut changes, path, Some(before), Some(after.clone()));
+                        overlay.insert(path.to_string(), Some(after));
+                    }
+                }
+            }
+        }
+    }
+    Ok(changes)
+}
+
+fn record(
+    changes: &mut Vec<FileChange>,
+    path: &str,
+    before: Option<String>,
+    after: Option<String>,
+) {
+    match changes.iter_mut().find(|c| c.path == path) {
+        // Keep the original pre-image when a path is touched more than once.
+        Some(existing) => existing.after = after,
+        None => changes.push(FileChange {
+            path: path.to_string(),
+            before,
+            after,
+        }),
+    }
+}
+
+/// Applies `chunks` to `original`, returning the new file contents.
+pub(crate) fn derive_new_contents(
+    original: &str,
+    path: &str,
+    chunks: &[Chunk],
+) -> Result<String, String> {
+    let mut lines: Vec<String> = original.split('\n').map(str::to_string).collect();
+    if lines.last().is_some_and(String::is_empty) {
+        lines.pop();
+    }
+    let replacements = compute_replacements(&lines, path, chunks)?;
+    for (start, old_len, new_segment) in replacements.into_iter().rev() {
+        lines.splice(start..start + old_len, new_segment);
+    }
+    if !lines.last().is_some_and(String::is_empty) {
+        lines.push(String::new());
+    }
+    Ok(lines.join("\n"))
+}
+
+type Replacement = (usize, usize, Vec<String>);
+
+fn compute_replacements(
+    lines: &[String],
+    path: &str,
+    chunks: &[Chunk],
+) -> Result<Vec<Replacement>, String> {
+    let mut replacements: Vec<Replacement> = Vec::new();
+    let mut line_index = 0;
+
+    for chunk in chunks {
+        if let Some(context) = &chunk.change_context {
+            let Some(found) =
+                seek_sequence(lines, std::slice::from_ref(context), line_index, false)
+            else {
+                return Err(format!("Failed to find context '{context}' in {path}"));
+            };
+            line_index = found + 1;
+        }
+
+        if chunk.old_lines.is_empty() {
+            let insertion = if lines.last().is_some_and(String::is_empty) {
+                lines.len() - 1
+            } else {
+                lines.len()
+            };
+            replacements.push((insertion, 0, chunk.new_lines.clone()));
+            continue;
+        }
+
+        let mut pattern: &[String] = &chunk.old_lines;
+        let mut new_slice: &[String] = &chunk.new_lines;
+        let mut found = seek_sequence(lines, pattern, line_index, chunk.is_end_of_file);
+        if found.is_none() && pattern.last().is_some_and(String::is_empty) {
+            pattern = &pattern[..pattern.len() - 1];
+            if new_slice.last().is_some_and(String::is_empty) {
+                new_slice = &new_slice[..new_slice.len() - 1];
+            }
+            found = seek_sequence(lines, pattern, line_index, chunk.is_end_of_file);
+        }
+        let Some(found) = found else {
+            return Err(format!(
+                "Failed to find expected lines in {path}:\n{}",
+                chunk.old_lines.join("\n")
+            ));
+        };
+        replacements.push((found, pattern.len(), new_slice.to_vec()));
+        line_index = found + pattern.len();
+    }
+
+    replacements.sort_by_key(|(start, _, _)| *start);
+    Ok(replacements)
+}
+
+/// Finds `pattern` in `lines` at or after `start`, trying progressively
+/// looser comparisons. With `eof`, matching starts from the end of the file.
+pub(crate) fn seek_sequence(
+    lines: &[String],
+    pattern: &[String],
+    start: usize,
+    eof: bool,
+) -> Option<usize> {
+    seek_sequence_fuzz(lines, pattern, start, eof).map(|(found, _)| found)
+}
+
+/// Like [`seek_sequence`], also returning how loosely the match was made
+/// (`None` for an exact match).
+pub(crate) fn seek_sequence_fuzz(
+    lines: &[String],
+    pattern: &[String],
+    start: usize,
+    eof: bool,
+) -> Option<(usize, Option<Fuzz>)> {
+    if pattern.is_empty() {
+        return Some((start, None));
+    }
+    if pattern.len() > lines.len() {
+        return None;
+    }
+    let search_start = if eof {
+        lines.len() - pattern.len()
+    } else {
+        start
+    };
+    let last_start = lines.len() - pattern.len();
+    if search_start > last_start {
+        return None;
+    }
+
+    COMPARISONS.iter().find_map(|(fuzz, eq)| {
+        (search_start..=last_start)
+            .find(|&i| {
+                pattern
+                    .iter()
+                    .enumerate()
+                    .all(|(j, p)| eq(&lines[i + j], p))
+            })
+            .map(|found| (found, *fuzz))
+    })
+}
+
+pub(crate) type LineEq = fn(&str, &str) -> bool;
+
+/// The applier's line comparisons, strictest first. A hunk goes where the
+/// first comparison that matches anywhere puts it.
+pub(crate) const COMPARISONS: [(Option<Fuzz>, LineEq); 4] = [
+    (None, |a, b| a == b),
+    (Some(Fuzz::TrailingWhitespace), |a, b| {
+        a.trim_end() == b.trim_end()
+    }),
+    (Some(Fuzz::Whitespace), |a, b| a.trim() == b.trim()),
+    (Some(Fuzz::Punctuation), |a, b| normalise(a) == normalise(b)),
+];
+
+/// How loosely a hunk had to be compared to match.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum Fuzz {
+    /// Lines match once trailing whitespace is ignored.
+    TrailingWhitespace,
+    /// Lines match once leading and trailing whitespace is ignored.
+    Whitespace,
+    /// Lines match once typographic punctuation is mapped to ASCII.
+    Punctuation,
+}
+
+/// Maps typographic punctuation and exotic spaces to their ASCII forms.
+fn normalise(s: &str) -> String {
+    s.trim()
+        .chars()
+        .map(|c| match c {
+            '\u{2010}' | '\u{2011}' | '\u{2012}' | '\u{2013}' | '\u{2014}' | '\u{2015}'
+            | '\u{2212}' => '-',
+            '\u{2018}' | '\u{2019}' | '\u{201A}' | '\u{201B}' => '\'',
+            '\u{201C}' | '\u{201D}' | '\u{201E}' | '\u{201F}' => '"',
+            '\u{00A0}' | '\u{2002}' | '\u{2003}' | '\u{2004}' | '\u{2005}' | '\u{2006}'
+            | '\u{2007}' | '\u{2008}' | '\u{2009}' | '\u{200A}' | '\u{202F}' | '\u{205F}'
+            | '\u{3000}' => ' ',
+            other => other,
+        })
+        .collect()
+}
@@ -0,0 +1,149 @@
+//! `apply_patch squash [--out FILE] PATCH...`: composes patch files meant
+//! to be applied one after another into a single equivalent patch.
+//!
+//! The patches are simulated in order against the working tree, each
+//! seeing the results of the ones before it, and every file that ends up
+//! different gets one section: an add, a delete, or an update diffed from
+//! its current contents to its final ones. Moves come out as a delete plus
+//! an add. If a patch doesn't apply on top of the earlier ones, nothing is
+//! written and the conflicting patch is named.
+
+use crate::diagnostics;
+use crate::exit;
+use crate::patch::ADD_FILE_MARKER;
+use crate::patch::DELETE_FILE_MARKER;
+use crate::patch::Patch;
+use crate::patch::Section;
+use crate::patch::SectionKind;
+use crate::patch::UPDATE_FILE_MARKER;
+use crate::rebase::diff_hunks;
+use crate::simulate::FileChange;
+use crate::simulate::simulate;
+use std::path::Path;
+use std::path::PathBuf;
+
+pub(crate) fn run_squash(args: &[String]) -> i32 {
+    let mut out: Option<PathBuf> = None;
+    let mut files: Vec<String> = Vec::new();
+    let mut i = 0;
+    while i < args.len() {
+        match args[i].as_str() {
+            "--out" => {
+                let Some(path) = args.get(i + 1) else {
+                    diagnostics::error("--out requires a value.");
+                    return 2;
+                };
+                out = Some(PathBuf::from(path));
+                i += 2;
+            }
+            "--" => {
+                files.extend(args[i + 1..].iter().cloned());
+                break;
+            }
+            arg if arg.starts_with('-') => {
+                diagnostics::error(format!("unknown option: {arg}"));
+                return 2;
+            }
+            arg => {
+                files.push(arg.to_string());
+                i += 1;
+            }
+        }
+    }
+    if files.is_empty() {
+        diagnostics::error("squash requires at least one PATCH file.");
+        return 2;
+    }
+
+    let mut sections: Vec<Section> = Vec::new();
+    let mut changes = Vec::new();
+    for file in &files {
+        let text = match std::fs::read_to_string(file) {
+            Ok(text) => text,
+            Err(err) => {
+                diagnostics::error(format!("failed to read {file}: {err}"));
+                return 1;
+            }
+        };
+        let Some(patch) = Patch::parse(&text) else {
+            eprintln!(
+                "Invalid patch {file}: The first line of the patch must be '*** Begin Patch'"
+            );
+            return exit::Exit::ParseError.code();
+        };
+        sections.extend(patch.sections);
+        // Simulating each prefix pins a conflict on the patch that causes it.
+        let prefix = Patch {
+            sections: sections.clone(),
+        };
+        changes = match simulate(&prefix, Path::new(".")) {
+            Ok(changes) => changes,
+            Err(err) => {
+                diagnostics::error(format!(
+                    "{file} does not apply on top of the patches before it: {err}"
+                ));
+                return exit::Exit::ContextMismatch.code();
+            }
+        };
+    }
+    let squashed = squash(changes);
+
+    let text = squashed.render();
+    match out {
+        Some(path) => match std::fs::write(&path, &text) {
+            Ok(()) => {
+                println!(
+                    "Squashed {} patches into {} ({} files)",
+                    files.len(),
+                    path.display(),
+                    squashed.sections.len()
+                );
+                0
+            }
+            Err(err) => {
+                diagnostics::error(format!("failed to write {}: {err}", path.display()));
+                1
+            }
+        },
+        None => {
+            print!("{text}");
+            0
+        }
+    }
+}
+
+/// One section per path whose final contents differ from its current ones.
+fn squash(changes: Vec<FileChange>) -> Patch {
+    let sections = changes
+        .into_iter()
+        .filter_map(|change| {
+            let path = change.path;
+            let (kind, header, body) = match (change.before, change.after) {
+                (None, None) => return None,
+                (Some(before), Some(after)) if before.lines().eq(after.lines()) => return None,
+                (None, Some(after)) => (
+                    SectionKind::Add,
+                    format!("{ADD_FILE_MARKER}{path}"),
+                    after.lines().map(|line| format!("+{line}")).collect(),
+                ),
+                (Some(_), None) => (
+                    SectionKind::Delete,
+                    format!("{DELETE_FILE_MARKER}{path}"),
+                    Vec::new(),
+                ),
+                (Some(before), Some(after)) => (
+                    SectionKind::Update,
+                    format!("{UPDATE_FILE_MARKER}{path}"),
+                    diff_hunks(&before, &after),
+                ),
+            };
+            Some(Section {
+                kind,
+                path,
+                header,
+                body,
+            })
+        })
+        .collect();
+    Patch { sections }
+}
@@ -0,0 +1,507 @@
+//! Two-phase applies: `apply_patch stage`, `commit-staged` and
+//! `discard-staged`.
+//!
+//! `stage` simulates the patch and writes the files it would produce under
+//! `staged/<key>/files/` in the state directory (the key is derived from the
+//! working directory), with a manifest of what each file held beforehand.
+//! The tree is not touched. A human can read and edit the staged files, or
+//! delete one to leave that file alone, and `commit-staged` then moves them
+//! into place: every new file is written next to its target (or in
+//! `staging_dir`; see `writes`) first, and only then renamed over it, so a
+//! failure part way restores what was already replaced. Files that changed
+//! since they were staged stop the commit before anything is written. `discard-staged` throws the staged changes
+//! away.
+//!
+//! Both steps go through the same mode, policies and guards as an apply:
+//! `stage` runs the patch through them and stages what the applier would
+//! have written, and `commit-staged` runs them again on a patch rebuilt
+//! from the staged files, since those may have been edited and the config
+//! may have changed in between. A refusal at either step changes nothing.
+//! Neither works with `readonly`.
+
+use crate::concise;
+use crate::diagnostics;
+use crate::digest::sha256_hex;
+use crate::exit;
+use crate::patch::ADD_FILE_MARKER;
+use crate::patch::BEGIN_PATCH_MARKER;
+use crate::patch::DELETE_FILE_MARKER;
+use crate::patch::END_PATCH_MARKER;
+use crate::patch::Patch;
+use crate::patch::UPDATE_FILE_MARKER;
+use crate::preview::contained_path;
+use crate::rebase::diff_hunks;
+use crate::simulate::simulate;
+use crate::writes;
+use serde::Deserialize;
+use serde::Serialize;
+use std::path::Path;
+use std::path::PathBuf;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct StagedFile {
+    path: String,
+    /// `A`, `M` or `D`, as in the applier's summary.
+    status: char,
+    /// Hash of the contents when staged; `None` if the file didn't exist.
+    before_sha256: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Manifest {
+    cwd: String,
+    timestamp: u64,
+    files: Vec<StagedFile>,
+    /// `--expected-files` given to `stage`, checked again on commit.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    expected_files: Option<Vec<String>>,
+}
+
+/// What `stage` and `commit-staged` have the apply step do instead of
+/// writing the patch, once the mode and guards let it through.
+#[derive(Debug)]
+pub(crate) enum Step {
+    /// Write the files the patch produces to this staging area.
+    Stage(PathBuf),
+    /// Move the files staged in this area into place.
+    Commit(PathBuf),
+}
+
+/// The staging area for the current directory.
+fn area(cfg: &crate::Config) -> Result<PathBuf, String> {
+    let cwd = std::env::current_dir()
+        .and_then(|dir| dir.canonicalize())
+        .map_err(|err| format!("cannot resolve the working directory: {err}"))?;
+    let state = crate::state::dir(cfg.state_dir.as_deref()).ok_or(
+        "could not determine the state directory (HOME/XDG_STATE_HOME not set).".to_string(),
+    )?;
+    let key = &sha256_hex(cwd.display().to_string().as_bytes())[..16];
+    Ok(state.join("staged").join(key))
+}
+
+fn load(dir: &Path) -> Result<Option<Manifest>, String> {
+    let path = dir.join("manifest.json");
+    match std::fs::read(&path) {
+        Ok(bytes) => serde_json::from_slice(&bytes)
+            .map(Some)
+            .map_err(|err| format!("failed to parse {}: {err}", path.display())),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(format!("failed to read {}: {err}", path.display())),
+    }
+}
+
+fn digest(path: &str) -> Option<String> {
+    std::fs::read(path).ok().map(|bytes| sha256_hex(&bytes))
+}
+
+fn stage(
+    dir: &Path,
+    patch_text: &str,
+    expected_files: Option<&[String]>,
+) -> Result<Manifest, String> {
+    if load(dir)?.is_some() {
+        return Err(format!(
+            "changes are already staged in {}; run `apply_patch commit-staged` or `apply_patch discard-staged` first.",
+            dir.display()
+        ));
+    }
+    let patch = Patch::parse(patch_text).ok_or(
+        "Invalid patch: The first line of the patch must be '*** Begin Patch'".to_string(),
+    )?;
+    let changes = simulate(&patch, Path::new("."))?;
+    let mut files: Vec<StagedFile> = Vec::new();
+    for change in &changes {
+        let rel = contained_path(&change.path).ok_or(format!(
+            "cannot stage {}: path escapes the working tree.",
+            change.path
+        ))?;
+        if let Some(contents) = &change.after {
+            let dest = dir.join("files").join(rel);
+            if let Some(parent) = dest.parent() {
+                std::fs::create_dir_all(parent)
+                    .map_err(|err| format!("failed to create {}: {err}", parent.display()))?;
+            }
+            std::fs::write(&dest, contents)
+                .map_err(|err| format!("failed to write {}: {err}", dest.display()))?;
+        }
+        files.push(StagedFile {
+            path: change.path.clone(),
+            status: change.status(),
+            before_sha256: change.before.as_ref().and(digest(&change.path)),
+        });
+    }
+    let manifest = Manifest {
+        cwd: std::env::current_dir()
+            .map(|dir| dir.display().to_string())
+            .unwrap_or_default(),
+        timestamp: crate::jsonl::timestamp(),
+        files,
+        expected_files: expected_files.map(<[String]>::to_vec),
+    };
+    let data = serde_json::to_vec_pretty(&manifest).map_err(|err| err.to_string())?;
+    std::fs::create_dir_all(dir)
+        .and_then(|()| std::fs::write(dir.join("manifest.json"), data))
+        .map_err(|err| format!("failed to write the staging manifest: {err}"))?;
+    Ok(manifest)
+}
+
+/// A file moved into place, and what it held before so it can be put back.
+struct Committed<'a> {
+    path: &'a str,
+    original: Option<Vec<u8>>,
+}
+
+fn restore(done: &[Committed<'_>]) {
+    for file in done.iter().rev() {
+        let _ = match &file.original {
+            Some(bytes) => std::fs::write(file.path, bytes),
+            None => std::fs::remove_file(file.path),
+        };
+    }
+}
+
+/// The patch that turns the tree into the staged files: what
+/// `commit-staged` runs past the mode and guards. Files whose staged copy
+/// was removed are left out.
+fn rebuilt_patch(dir: &Path, manifest: &Manifest) -> String {
+    let mut out = format!("{BEGIN_PATCH_MARKER}\n");
+    for file in &manifest.files {
+        if file.status == 'D' {
+            out.push_str(&format!("{DELETE_FILE_MARKER}{}\n", file.path));
+            continue;
+        }
+        let Some(staged) = contained_path(&file.path)
+            .and_then(|rel| std::fs::read(dir.join("files").join(rel)).ok())
+        else {
+            continue;
+        };
+        let staged = String::from_utf8_lossy(&staged);
+        match std::fs::read(&file.path) {
+            Ok(current) if file.status == 'M' => {
+                let hunks = diff_hunks(&String::from_utf8_lossy(&current), &staged);
+                if hunks.is_empty() {
+                    continue;
+                }
+                out.push_str(&format!("{UPDATE_FILE_MARKER}{}\n", file.path));
+                for line in hunks {
+                    out.push_str(&line);
+                    out.push('\n');
+                }
+            }
+            _ => {
+                out.push_str(&format!("{ADD_FILE_MARKER}{}\n", file.path));
+                for line in staged.lines() {
+                    out.push_str(&format!("+{line}\n"));
+                }
+            }
+        }
+    }
+    out.push_str(END_PATCH_MARKER);
+    out.push('\n');
+    out
+}
+
+/// Moves the staged copies of `touched` into place, and deletes the staged
+/// deletions among them. Returns the summary lines and the files left
+/// alone because their staged copy was removed.
+fn commit(
+    dir: &Path,
+    manifest: &Manifest,
+    touched: &[String],
+    staging_dir: Option<&Path>,
+) -> Result<(Vec<String>, Vec<String>), String> {
+    let files: Vec<&StagedFile> = manifest
+        .files
+        .iter()
+        .filter(|file| touched.contains(&file.path))
+        .collect();
+    let changed: Vec<&str> = files
+        .iter()
+        .filter(|file| digest(&file.path) != file.before_sha256)
+        .map(|file| file.path.as_str())
+        .collect();
+    if !changed.is_empty() {
+        return Err(format!(
+            "changed since they were staged, so nothing was committed: {}. Run `apply_patch discard-staged` and stage the patch again.",
+            changed.join(", ")
+        ));
+    }
+
+    // Phase 1: every new file is written to a temporary file.
+    let staging = writes::Staging::new(staging_dir);
+    let mut writes: Vec<(&StagedFile, PathBuf)> = Vec::new();
+    let skipped: Vec<String> = manifest
+        .files
+        .iter()
+        .filter(|file| file.status != 'D' && !touched.contains(&file.path))
+        .filter(|file| {
+            contained_path(&file.path).is_some_and(|rel| !dir.join("files").join(rel).exists())
+        })
+        .map(|file| file.path.clone())
+        .collect();
+    let fail = |writes: &[(&StagedFile, PathBuf)], msg: String| {
+        for (_, tmp) in writes {
+            let _ = std::fs::remove_file(tmp);
+        }
+        Err(msg)
+    };
+    for &file in &files {
+        if file.status == 'D' {
+            continue;
+        }
+        let Some(rel) = contained_path(&file.path) else {
+            return fail(&writes, format!("{} escapes the working tree", file.path));
+        };
+        let contents = match std::fs::read(dir.join("files").join(rel)) {
+            Ok(contents) => contents,
+            Err(err) => {
+                return fail(
+                    &writes,
+                    format!("failed to read the staged {}: {err}", file.path),
+                );
+            }
+        };
+        let target = Path::new(&file.path);
+        if let Some(parent) = target.parent().filter(|p| !p.as_os_str().is_empty())
+            && let Err(err) = std::fs::create_dir_all(parent)
+        {
+            return fail(
+                &writes,
+                format!("failed to create {}: {err}", parent.display()),
+            );
+        }
+        let tmp = staging.temp_path(target, "staged");
+        let written =
+            std::fs::write(&tmp, contents).and_then(|()| match std::fs::metadata(target) {
+                Ok(meta) => std::fs::set_permissions(&tmp, meta.permissions()),
+                Err(_) => Ok(()),
+            });
+        if let Err(err) = written {
+            let _ = std::fs::remove_file(&tmp);
+            return fail(&writes, format!("failed to write {}: {err}", tmp.display()));
+        }
+        writes.push((file, tmp));
+    }
+
+    // Phase 2: renames and deletes, undone if one fails.
+    let mut done: Vec<Committed<'_>> = Vec::new();
+    let mut summary: Vec<String> = Vec::new();
+    let deletes = files.iter().copied().filter(|file| file.status == 'D');
+    let moves = writes.iter().map(|(file, tmp)| (*file, Some(tmp)));
+    for (file, tmp) in moves.chain(deletes.map(|file| (file, None))) {
+        let original = std::fs::read(&file.path).ok();
+        let result = match tmp {
+            Some(tmp) => std::fs::rename(tmp, &file.path),
+            None => std::fs::remove_file(&file.path),
+        };
+        if let Err(err) = result {
+            restore(&done);
+            let _ = fail(&writes, String::new());
+            return Err(format!(
+                "failed to commit {}: {err}; the files already committed were restored",
+                file.path
+            ));
+        }
+        done.push(Committed {
+            path: &file.path,
+            original,
+        });
+        summary.push(format!("{} {}", file.status, file.path));
+    }
+    // Added, then modified, then deleted, like the applier's summary.
+    summary.sort_by_key(|line| "AMD".find(&line[..1]));
+    Ok((summary, skipped))
+}
+
+/// Runs `step` for a patch the mode and guards let through. `Err` carries
+/// the failure's kind and message, as from the applier.
+pub(crate) fn run_step(
+    step: &Step,
+    patch_arg: &str,
+    expected_files: Option<&[String]>,
+    staging_dir: Option<&Path>,
+) -> Result<(), (exit::Exit, String)> {
+    let fail = |err: String| {
+        diagnostics::error(&err);
+        (exit::Exit::Failed, err)
+    };
+    match step {
+        Step::Stage(dir) => {
+            let manifest = stage(dir, patch_arg, expected_files).map_err(fail)?;
+            println!(
+                "Staged {} file(s) in {}; nothing in the working tree was changed.",
+                manifest.files.len(),
+                dir.join("files").display()
+            );
+            for file in &manifest.files {
+                println!("{} {}", file.status, file.path);
+            }
+            println!(
+                "Review or edit them there, then run `apply_patch commit-staged` or `apply_patch discard-staged`."
+            );
+        }
+        Step::Commit(dir) => {
+            let manifest = load(dir)
+                .and_then(|manifest| manifest.ok_or("nothing is staged for this directory.".into()))
+                .map_err(fail)?;
+            let touched = Patch::parse(patch_arg)
+                .map(|patch| patch.touched_paths())
+                .unwrap_or_default();
+            let (summary, skipped) = commit(dir, &manifest, &touched, staging_dir).map_err(fail)?;
+            let _ = std::fs::remove_dir_all(dir);
+            concise::print_success(&summary);
+            for path in skipped {
+                println!("Left {path} alone: its staged copy was removed.");
+            }
+        }
+    }
+    Ok(())
+}
+
+fn usage(name: &str) -> i32 {
+    match name {
+        "stage" => eprintln!("Usage: apply_patch stage [--expected-files LIST] [--] [PATCH]"),
+        _ => eprintln!("Usage: apply_patch {name}"),
+    }
+    2
+}
+
+/// The config and staging area for `stage` and `commit-staged`; `Err` is
+/// the exit code.
+fn setup(name: &str) -> Result<(crate::Config, PathBuf), i32> {
+    let cfg = crate::effective_config(&crate::RunOptions::default()).map_err(|err| {
+        diagnostics::error(err);
+        exit::Exit::ConfigError.code()
+    })?;
+    if cfg.readonly {
+        diagnostics::error(format!(
+            "{name} cannot be used with readonly; nothing was changed."
+        ));
+        return Err(2);
+    }
+    let dir = area(&cfg).map_err(|err| {
+        diagnostics::error(err);
+        1
+    })?;
+    Ok((cfg, dir))
+}
+
+pub(crate) fn run_stage(args: &[String]) -> i32 {
+    let mut expected_files: Option<Vec<String>> = None;
+    let mut positional: Vec<String> = Vec::new();
+    let mut i = 0;
+    while i < args.len() {
+        match args[i].as_str() {
+            "--" => {
+                positional.extend_from_slice(&args[i + 1..]);
+                break;
+            }
+            "--expected-files" => {
+                let Some(value) = args.get(i + 1) else {
+                    diagnostics::error("--expected-files requires a value.");
+                    return 2;
+                };
+                match crate::expected::parse(value) {
+                    Ok(paths) => expected_files = Some(paths),
+                    Err(err) => {
+                        diagnostics::error(err);
+                        return 2;
+                    }
+                }
+                i += 1;
+            }
+            arg if arg.starts_with('-') => return usage("stage"),
+            arg => positional.push(arg.to_string()),
+        }
+        i += 1;
+    }
+    let (cfg, dir) = match setup("stage") {
+        Ok(setup) => setup,
+        Err(code) => return code,
+    };
+    let patch_text = match crate::read_patch(&positional) {
+        Ok(text) => text,
+        Err(code) => return code,
+    };
+    match load(&dir) {
+        Ok(None) => {}
+        Ok(Some(_)) => {
+            diagnostics::error(format!(
+                "changes are already staged in {}; run `apply_patch commit-staged` or `apply_patch discard-staged` first.",
+                dir.display()
+            ));
+            return 1;
+        }
+        Err(err) => {
+            diagnostics::error(err);
+            return 1;
+        }
+    }
+    // Staging writes nothing to the tree, so it runs as a dry run that
+    // stages what the applier would have written.
+    let options = crate::RunOptions {
+        dry_run: true,
+        expected_files,
+        staging: Some(Step::Stage(dir)),
+        ..Default::default()
+    };
+    crate::process_patch(&cfg, &patch_text, &options).map_or_else(|code| code, |o| o.code)
+}
+
+pub(crate) fn run_commit_staged(args: &[String]) -> i32 {
+    if !args.is_empty() {
+        return usage("commit-staged");
+    }
+    let (cfg, dir) = match setup("commit-staged") {
+        Ok(setup) => setup,
+        Err(code) => return code,
+    };
+    let manifest = match load(&dir) {
+        Ok(Some(manifest)) => manifest,
+        Ok(None) => {
+            diagnostics::error("nothing is staged for this directory.");
+            return 1;
+        }
+        Err(err) => {
+            diagnostics::error(err);
+            return 1;
+        }
+    };
+    let patch_text = rebuilt_patch(&dir, &manifest);
+    let options = crate::RunOptions {
+        expected_files: manifest.expected_files,
+        staging: Some(Step::Commit(dir)),
+        ..Default::default()
+    };
+    crate::process_patch(&cfg, &patch_text, &options).map_or_else(|code| code, |o| o.code)
+}
+
+pub(crate) fn run_discard_staged(args: &[String]) -> i32 {
+    if !args.is_empty() {
+        return usage("discard-staged");
+    }
+    let dir =
+        match crate::effective_config(&crate::RunOptions::default()).and_then(|cfg| area(&cfg)) {
+            Ok(dir) => dir,
+            Err(err) => {
+                diagnostics::error(err);
+                return 1;
+            }
+        };
+    match load(&dir) {
+        Ok(None) => {
+            println!("Nothing is staged for this directory.");
+            0
+        }
+        Ok(Some(_)) | Err(_) => match std::fs::remove_dir_all(&dir) {
+            Ok(()) => {
+                println!("Discarded the staged changes in {}.", dir.display());
+                0
+            }
+            Err(err) => {
+                diagnostics::error(format!("failed to remove {}: {err}", dir.display()));
+                1
+            }
+        },
+    }
+}
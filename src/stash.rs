@@ -0,0 +1,82 @@
+//! `--stash-before`: a git stash entry holding the files a patch is about
+//! to touch, so an unwanted apply can be undone with plain git.
+//!
+//! The entry is built without touching the working tree or the index: the
+//! current contents of the targets go into a temporary index on top of
+//! `HEAD`, which is committed in stash form and recorded with `git stash
+//! store`. Untracked targets are included; files the patch creates are
+//! absent from it, as they are now.
+
+use crate::branch::FALLBACK_EMAIL;
+use crate::branch::FALLBACK_NAME;
+use std::path::Path;
+use std::process::Command;
+
+/// Stashes the current state of `paths` (relative to the current
+/// directory) and returns the stash commit's short hash, or `None` outside
+/// a git work tree.
+pub(crate) fn save(paths: &[String]) -> Result<Option<String>, String> {
+    if git(&["rev-parse", "--is-inside-work-tree"], None).is_err() {
+        return Ok(None);
+    }
+    git(&["rev-parse", "--verify", "--quiet", "HEAD"], None)
+        .map_err(|_| "the repository has no commits yet".to_string())?;
+    let index =
+        std::env::temp_dir().join(format!("apply_patch-stash-{}.index", std::process::id()));
+    let tree = (|| {
+        git(&["read-tree", "HEAD"], Some(&index))?;
+        let mut args = vec!["update-index", "--add", "--remove", "--"];
+        args.extend(paths.iter().map(String::as_str));
+        git(&args, Some(&index))?;
+        git(&["write-tree"], Some(&index))
+    })();
+    let _ = std::fs::remove_file(&index);
+    let tree = tree?;
+
+    let branch = git(&["rev-parse", "--abbrev-ref", "HEAD"], None)?;
+    let message = format!(
+        "On {branch}: apply_patch: before patching {} files",
+        paths.len()
+    );
+    let head_tree = git(&["rev-parse", "HEAD^{tree}"], None)?;
+    let index_commit = commit_tree(&head_tree, &["HEAD"], &format!("index on {branch}"))?;
+    let stash = commit_tree(&tree, &["HEAD", &index_commit], &message)?;
+    git(&["stash", "store", "-m", &message, &stash], None)?;
+    git(&["rev-parse", "--short", &stash], None).map(Some)
+}
+
+fn commit_tree(tree: &str, parents: &[&str], message: &str) -> Result<String, String> {
+    let mut args: Vec<String> = Vec::new();
+    if git(&["config", "user.email"], None).is_err() {
+        args.extend(["-c".into(), format!("user.name={FALLBACK_NAME}")]);
+        args.extend(["-c".into(), format!("user.email={FALLBACK_EMAIL}")]);
+    }
+    args.extend([
+        "commit-tree".into(),
+        tree.into(),
+        "-m".into(),
+        message.into(),
+    ]);
+    for parent in parents {
+        args.extend(["-p".into(), (*parent).into()]);
+    }
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    git(&args, None)
+}
+
+fn git(args: &[&str], index: Option<&Path>) -> Result<String, String> {
+    let mut cmd = Command::new("git");
+    cmd.args(args);
+    if let Some(index) = index {
+        cmd.env("GIT_INDEX_FILE", index);
+    }
+    let output = cmd
+        .output()
+        .map_err(|err| format!("failed to run git: {err}"))?;
+    if output.status.success() {
+        Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+    } else {
+        let stderr = String::from_utf8_lossy(&output.stderr).trim().to_string();
+        Err(format!("git {} failed: {stderr}", args.join(" ")))
+    }
+}
@@ -0,0 +1,156 @@
+//! Small persistent state carried between invocations.
+//!
+//! Stored as JSON in the state directory (`state.json`, or `state.NAME.json`
+//! for a tenant), kept apart from the config so dotfile-managed configs stay
+//! clean. Unreadable or missing state is treated as empty, and write errors
+//! are reported as warnings, so the state never blocks a patch.
+
+use crate::diagnostics;
+use crate::failures::FailureStreak;
+use crate::stats::AgentStats;
+use crate::tenant;
+use serde::Deserialize;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::path::PathBuf;
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub(crate) struct State {
+    /// The current run of identical apply failures (see `failures`).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) failures: Option<FailureStreak>,
+    /// Session counts by agent name (see `stats`).
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub(crate) agents: BTreeMap<String, AgentStats>,
+}
+
+/// The state directory: `state_dir` from the config, otherwise
+/// `$XDG_STATE_HOME/apply_patch`, otherwise `~/.local/state/apply_patch`.
+/// None with `--no-config`, which carries nothing between runs.
+pub(crate) fn dir(configured: Option<&Path>) -> Option<PathBuf> {
+    if let Some(dir) = configured {
+        return Some(dir.to_path_buf());
+    }
+    if crate::hermetic::enabled() {
+        return None;
+    }
+    if let Some(xdg) = std::env::var_os("XDG_STATE_HOME").filter(|v| !v.is_empty()) {
+        return Some(PathBuf::from(xdg).join("apply_patch"));
+    }
+    let home = PathBuf::from(std::env::var_os("HOME")?);
+    Some(home.join(".local").join("state").join("apply_patch"))
+}
+
+/// Where the state for `tenant` lives, or `None` without a state directory.
+pub(crate) fn path(configured: Option<&Path>, tenant: Option<&str>) -> Option<PathBuf> {
+    let path = dir(configured)?.join("state.json");
+    Some(match tenant {
+        Some(name) => tenant::scoped_path(&path, name),
+        None => path,
+    })
+}
+
+pub(crate) fn load(path: &Path) -> State {
+    std::fs::read(path)
+        .ok()
+        .and_then(|bytes| serde_json::from_slice(&bytes).ok())
+        .unwrap_or_default()
+}
+
+pub(crate) fn save(path: &Path, state: &State) {
+    let result = (|| {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let tmp = path.with_extension("json.tmp");
+        let data = serde_json::to_vec_pretty(state).map_err(std::io::Error::other)?;
+        std::fs::write(&tmp, data)?;
+        std::fs::rename(tmp, path)
+    })();
+    if let Err(err) = result {
+        diagnostics::warning(format!(
+            "failed to write state file {}: {err}",
+            path.display()
+        ));
+    }
+}
+
+/// State files left next to the config by releases before the state
+/// directory existed.
+fn legacy_files(config_dir: &Path) -> Vec<PathBuf> {
+    let Ok(entries) = std::fs::read_dir(config_dir) else {
+        return Vec::new();
+    };
+    let mut files: Vec<PathBuf> = entries
+        .filter_map(Result::ok)
+        .map(|entry| entry.path())
+        .filter(|path| {
+            path.file_name()
+                .and_then(|name| name.to_str())
+                .is_some_and(|name| {
+                    name == "state.json" || (name.starts_with("state.") && name.ends_with(".json"))
+                })
+        })
+        .collect();
+    files.sort();
+    files
+}
+
+/// `apply_patch migrate-state`: moves state files from the config directory
+/// into the state directory, leaving any that already exist there alone.
+pub(crate) fn run_migrate_state(args: &[String]) -> i32 {
+    if let Some(arg) = args.first() {
+        diagnostics::error(format!("unknown option: {arg}"));
+        return 2;
+    }
+    let cfg = match crate::effective_config(&crate::RunOptions::default()) {
+        Ok(cfg) => cfg,
+        Err(err) => {
+            diagnostics::error(err);
+            return crate::exit::Exit::ConfigError.code();
+        }
+    };
+    let (Some(config_dir), Some(state_dir)) = (
+        crate::config_path().and_then(|p| p.parent().map(Path::to_path_buf)),
+        dir(cfg.state_dir.as_deref()),
+    ) else {
+        diagnostics::error("could not determine config path (HOME/XDG_CONFIG_HOME not set).");
+        return 1;
+    };
+    let files = legacy_files(&config_dir);
+    if files.is_empty() {
+        println!("No state to migrate in {}.", config_dir.display());
+        return 0;
+    }
+    if let Err(err) = std::fs::create_dir_all(&state_dir) {
+        diagnostics::error(format!("failed to create {}: {err}", state_dir.display()));
+        return 1;
+    }
+    let mut code = 0;
+    for from in files {
+        let Some(name) = from.file_name() else {
+            continue;
+        };
+        let to = state_dir.join(name);
+        if to.exists() {
+            eprintln!(
+                "Warning: {} already exists; left {} in place.",
+                to.display(),
+                from.display()
+            );
+            continue;
+        }
+        // Across filesystems rename fails; fall back to copy and remove.
+        let moved = std::fs::rename(&from, &to)
+            .or_else(|_| std::fs::copy(&from, &to).and_then(|_| std::fs::remove_file(&from)));
+        match moved {
+            Ok(()) => println!("Moved {} -> {}", from.display(), to.display()),
+            Err(err) => {
+                diagnostics::error(format!("failed to move {}: {err}", from.display()));
+                code = 1;
+            }
+        }
+    }
+    code
+}
@@ -0,0 +1,132 @@
+//! Per-agent session counts for banner templates (`{warn_count}` and
+//! `{session_apply_count}`) and for rotating through `warn_messages`.
+//!
+//! Counts are kept in the state file, keyed by `$APPLY_PATCH_AGENT`, and
+//! only while a configured banner uses them or there are warn messages to
+//! rotate. A session is the runs sharing
+//! one `$APPLY_PATCH_SESSION`; without it, a session ends after half an hour
+//! without patches.
+
+use crate::Mode;
+use crate::jsonl;
+use crate::state;
+use serde::Deserialize;
+use serde::Serialize;
+use std::path::Path;
+
+pub(crate) const SESSION_ENV: &str = "APPLY_PATCH_SESSION";
+const SESSION_IDLE_SECS: u64 = 30 * 60;
+const VARIABLES: [&str; 2] = ["{warn_count}", "{session_apply_count}"];
+
+/// How `warn_messages` are picked as warnings repeat in a session.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum WarnRotation {
+    /// First, second, ..., last, then the first again.
+    #[default]
+    RoundRobin,
+    /// First, second, ..., then the last (firmest) from there on.
+    Escalate,
+}
+
+impl WarnRotation {
+    pub(crate) fn as_str(self) -> &'static str {
+        match self {
+            WarnRotation::RoundRobin => "round-robin",
+            WarnRotation::Escalate => "escalate",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub(crate) struct AgentStats {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    session: Option<String>,
+    #[serde(default)]
+    last_seen: u64,
+    /// Patches applied this session, in any mode.
+    #[serde(default)]
+    pub(crate) applied: u64,
+    /// Patches applied with the warn banner this session.
+    #[serde(default)]
+    pub(crate) warned: u64,
+}
+
+impl AgentStats {
+    /// Whether a run now, in `session`, starts a new session.
+    fn expired(&self, session: Option<&str>, now: u64) -> bool {
+        match session {
+            Some(session) => self.session.as_deref() != Some(session),
+            None => {
+                self.session.is_some() || now.saturating_sub(self.last_seen) > SESSION_IDLE_SECS
+            }
+        }
+    }
+}
+
+/// Whether any of `templates` uses a session count.
+pub(crate) fn used_by(templates: &[&str]) -> bool {
+    templates
+        .iter()
+        .any(|t| VARIABLES.iter().any(|v| t.contains(v)))
+}
+
+/// The banner for this session's warning as counted in `stats` (the
+/// first one without counts). `templates` must not be empty.
+pub(crate) fn rotate<'a>(
+    templates: &[&'a str],
+    rotation: WarnRotation,
+    stats: Option<&AgentStats>,
+) -> &'a str {
+    let repeat = stats.map_or(0, |stats| stats.warned.saturating_sub(1)) as usize;
+    let index = match rotation {
+        WarnRotation::RoundRobin => repeat % templates.len(),
+        WarnRotation::Escalate => repeat.min(templates.len() - 1),
+    };
+    templates[index]
+}
+
+fn session() -> Option<String> {
+    std::env::var(SESSION_ENV).ok().filter(|v| !v.is_empty())
+}
+
+/// The counts so far in the current session, without recording anything.
+pub(crate) fn current(state_dir: Option<&Path>, tenant: Option<&str>, agent: &str) -> AgentStats {
+    let Some(path) = state::path(state_dir, tenant) else {
+        return AgentStats::default();
+    };
+    match state::load(&path).agents.remove(agent) {
+        Some(stats) if !stats.expired(session().as_deref(), jsonl::timestamp()) => stats,
+        _ => AgentStats::default(),
+    }
+}
+
+/// Counts one applied patch for `agent` and returns the updated counts.
+pub(crate) fn record(
+    state_dir: Option<&Path>,
+    tenant: Option<&str>,
+    agent: &str,
+    mode: Mode,
+) -> AgentStats {
+    let Some(path) = state::path(state_dir, tenant) else {
+        return AgentStats::default();
+    };
+    let mut state = state::load(&path);
+    let session = session();
+    let now = jsonl::timestamp();
+    let stats = state.agents.entry(agent.to_string()).or_default();
+    if stats.expired(session.as_deref(), now) {
+        *stats = AgentStats {
+            session,
+            ..AgentStats::default()
+        };
+    }
+    stats.last_seen = now;
+    stats.applied += 1;
+    if mode == Mode::Warn {
+        stats.warned += 1;
+    }
+    let stats = stats.clone();
+    state::save(&path, &state);
+    stats
+}
@@ -0,0 +1,473 @@
+//! Streaming applies for very large targets
+//! (`limits.stream_above_bytes` in the config).
+//!
+//! The applier reads each target into a `String`, splits it into lines and
+//! splices the hunks in, so a generated file of hundreds of megabytes costs
+//! several times its size in memory. When an update section targets a file
+//! above the threshold, the whole patch is applied here instead, a line at
+//! a time: each hunk is located by one pass over the file that holds only
+//! as many lines as the hunk has, trying the applier's comparisons in the
+//! same order, and once every hunk is placed the result is written to a
+//! temporary file (next to the target, or in `staging_dir`; see `writes`).
+//! Matching, the final newline and the summary are the applier's; nothing is written unless every hunk is
+//! found.
+//!
+//! These applies skip what needs the whole file in memory: `verify_writes`,
+//! `conflict_guard`, the patch cache, `mirror_dir`, `format_on_apply`, the
+//! disk-space check, the write-ahead log and the `git apply` fallback.
+//! `final_newline` is applied as the file is written, and `write_strategy`
+//! decides how the temporary file lands: `replace` renames it over the
+//! target (falling back to in place as whole-patch applies do), `in-place`
+//! copies it into the target so the inode survives, which briefly needs
+//! the disk space for both.
+
+use crate::concise;
+use crate::exit;
+use crate::newline;
+use crate::newline::FinalNewline;
+use crate::patch::Chunk;
+use crate::patch::Patch;
+use crate::patch::SectionKind;
+use crate::simulate::COMPARISONS;
+use crate::writes;
+use crate::writes::Staging;
+use crate::writes::WriteStrategy;
+use std::collections::HashSet;
+use std::collections::VecDeque;
+use std::io::BufRead;
+use std::io::BufReader;
+use std::io::BufWriter;
+use std::io::Read;
+use std::io::Seek;
+use std::io::SeekFrom;
+use std::io::Write;
+use std::path::Path;
+
+/// A hunk placed in the original file: replace `old_len` lines from
+/// `start` with `new_lines`.
+struct Replacement<'a> {
+    start: usize,
+    old_len: usize,
+    new_lines: &'a [String],
+}
+
+/// Whether `patch` should be applied here: an update section targets a file
+/// larger than `threshold` bytes, and no path is touched twice (later
+/// sections would have to see earlier ones' results).
+pub(crate) fn wanted(patch: &Patch, threshold: Option<u64>) -> bool {
+    let Some(threshold) = threshold else {
+        return false;
+    };
+    let mut seen: HashSet<&str> = HashSet::new();
+    for section in &patch.sections {
+        let paths = std::iter::once(section.path.as_str()).chain(section.move_to());
+        for path in paths {
+            if !seen.insert(path) {
+                return false;
+            }
+        }
+    }
+    patch.sections.iter().any(|section| {
+        section.kind == SectionKind::Update
+            && std::fs::metadata(&section.path).is_ok_and(|meta| meta.len() > threshold)
+    })
+}
+
+/// The lines of `path` as the applier splits them: on `\n`, without a
+/// final empty line for a trailing newline.
+fn lines(path: &str) -> Result<impl Iterator<Item = Result<String, String>>, String> {
+    let file = std::fs::File::open(path)
+        .map_err(|err| format!("Failed to read file to update {path}: {err}"))?;
+    let path = path.to_string();
+    Ok(BufReader::new(file).split(b'\n').map(move |line| {
+        let line = line.map_err(|err| format!("Failed to read file to update {path}: {err}"))?;
+        String::from_utf8(line).map_err(|_| {
+            format!("Failed to read file to update {path}: stream did not contain valid UTF-8")
+        })
+    }))
+}
+
+/// The number of lines in `path`, and whether the last one is empty.
+fn count(path: &str) -> Result<(usize, bool), String> {
+    let mut total = 0;
+    let mut last_empty = false;
+    for line in lines(path)? {
+        total += 1;
+        last_empty = line?.is_empty();
+    }
+    Ok((total, last_empty))
+}
+
+/// Streaming [`crate::simulate::seek_sequence`]: where `pattern` starts in
+/// `path` (of `total` lines) at or after `start`.
+fn seek(
+    path: &str,
+    pattern: &[String],
+    start: usize,
+    eof: bool,
+    total: usize,
+) -> Result<Option<usize>, String> {
+    if pattern.is_empty() {
+        return Ok(Some(start));
+    }
+    if pattern.len() > total {
+        return Ok(None);
+    }
+    let last_start = total - pattern.len();
+    let search_start = if eof { last_start } else { start };
+    if search_start > last_start {
+        return Ok(None);
+    }
+    // The first match for each comparison; the strictest that matched wins.
+    let mut found: [Option<usize>; COMPARISONS.len()] = [None; COMPARISONS.len()];
+    let mut window: VecDeque<String> = VecDeque::with_capacity(pattern.len());
+    for (i, line) in lines(path)?.enumerate() {
+        window.push_back(line?);
+        if window.len() > pattern.len() {
+            window.pop_front();
+        }
+        let Some(at) = (i + 1).checked_sub(pattern.len()) else {
+            continue;
+        };
+        if at < search_start {
+            continue;
+        }
+        if at > last_start {
+            break;
+        }
+        let best = found
+            .iter()
+            .position(Option::is_some)
+            .unwrap_or(found.len());
+        for (level, (_, eq)) in COMPARISONS.iter().enumerate().take(best) {
+            if window.iter().zip(pattern).all(|(a, b)| eq(a, b)) {
+                found[level] = Some(at);
+                break;
+            }
+        }
+        if found[0].is_some() {
+            break;
+        }
+    }
+    Ok(found.into_iter().flatten().next())
+}
+
+/// Places `chunks` in `path`, as the applier's `compute_replacements` does.
+fn locate<'a>(
+    path: &str,
+    chunks: &'a [Chunk],
+) -> Result<Vec<Replacement<'a>>, (exit::Exit, String)> {
+    let failed = |err: String| (exit::Exit::Failed, err);
+    let (total, last_empty) = count(path).map_err(failed)?;
+    let mut replacements: Vec<Replacement<'a>> = Vec::new();
+    let mut line_index = 0;
+    for chunk in chunks {
+        if let Some(context) = &chunk.change_context {
+            let found = seek(
+                path,
+                std::slice::from_ref(context),
+                line_index,
+                false,
+                total,
+            )
+            .map_err(failed)?
+            .ok_or_else(|| {
+                (
+                    exit::Exit::ContextMismatch,
+                    format!("Failed to find context '{context}' in {path}"),
+                )
+            })?;
+            line_index = found + 1;
+        }
+        if chunk.old_lines.is_empty() {
+            replacements.push(Replacement {
+                start: if last_empty { total - 1 } else { total },
+                old_len: 0,
+                new_lines: &chunk.new_lines,
+            });
+            continue;
+        }
+        let mut pattern: &[String] = &chunk.old_lines;
+        let mut new_lines: &[String] = &chunk.new_lines;
+        let mut found =
+            seek(path, pattern, line_index, chunk.is_end_of_file, total).map_err(failed)?;
+        if found.is_none() && pattern.last().is_some_and(String::is_empty) {
+            pattern = &pattern[..pattern.len() - 1];
+            if new_lines.last().is_some_and(String::is_empty) {
+                new_lines = &new_lines[..new_lines.len() - 1];
+            }
+            found = seek(path, pattern, line_index, chunk.is_end_of_file, total).map_err(failed)?;
+        }
+        let Some(found) = found else {
+            return Err((
+                exit::Exit::ContextMismatch,
+                format!(
+                    "Failed to find expected lines in {path}:\n{}",
+                    chunk.old_lines.join("\n")
+                ),
+            ));
+        };
+        replacements.push(Replacement {
+            start: found,
+            old_len: pattern.len(),
+            new_lines,
+        });
+        line_index = found + pattern.len();
+    }
+    replacements.sort_by_key(|replacement| replacement.start);
+    Ok(replacements)
+}
+
+/// Whether `path` ends with a newline; `None` if it is missing or empty.
+fn ending(path: &str) -> Option<bool> {
+    let mut file = std::fs::File::open(path).ok()?;
+    file.seek(SeekFrom::End(-1)).ok()?;
+    let mut last = [0];
+    file.read_exact(&mut last).ok()?;
+    Some(last[0] == b'\n')
+}
+
+/// Writes `path` with `replacements` applied to `dest`, ending with a
+/// newline unless `newline` is false. The result goes to a temporary file
+/// next to `dest` first: with `replace` it is renamed over `dest` with
+/// `path`'s permissions, and `in-place` copies it into `dest`, which keeps
+/// its inode. Returns why a `replace` write went in place instead, if it
+/// did.
+fn write(
+    path: &str,
+    dest: &str,
+    replacements: &[Replacement<'_>],
+    newline: bool,
+    strategy: WriteStrategy,
+    staging: &Staging<'_>,
+) -> Result<Option<String>, String> {
+    let target = Path::new(dest);
+    if let Some(parent) = target.parent().filter(|p| !p.as_os_str().is_empty()) {
+        std::fs::create_dir_all(parent)
+            .map_err(|err| format!("Failed to create parent directories for {dest}: {err}"))?;
+    }
+    let tmp = staging.temp_path(target, "tmp");
+    let result = write_lines(path, &tmp, replacements, newline).and_then(|()| {
+        let io = |err: std::io::Error| format!("Failed to write file {dest}: {err}");
+        let links = std::fs::metadata(target).map_or(1, |meta| writes::hard_links(&meta));
+        let why = match strategy {
+            WriteStrategy::InPlace => None,
+            WriteStrategy::Replace if links > 1 => Some(format!("it has {links} hard links")),
+            WriteStrategy::Replace => {
+                let replaced = std::fs::metadata(path)
+                    .and_then(|meta| std::fs::set_permissions(&tmp, meta.permissions()))
+                    .and_then(|()| std::fs::rename(&tmp, target));
+                match replaced {
+                    Ok(()) => return Ok(None),
+                    Err(err) => Some(format!("it couldn't be replaced ({err})")),
+                }
+            }
+        };
+        let mut from = std::fs::File::open(&tmp).map_err(io)?;
+        let mut to = std::fs::File::create(target).map_err(io)?;
+        std::io::copy(&mut from, &mut to).map_err(io)?;
+        std::fs::remove_file(&tmp).map_err(io)?;
+        Ok(why)
+    });
+    if result.is_err() {
+        let _ = std::fs::remove_file(&tmp);
+    }
+    result
+}
+
+fn write_lines(
+    path: &str,
+    tmp: &Path,
+    replacements: &[Replacement<'_>],
+    newline: bool,
+) -> Result<(), String> {
+    let io = |err: std::io::Error| format!("Failed to write file {}: {err}", tmp.display());
+    let file = std::fs::File::options()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(tmp)
+        .map_err(io)?;
+    let mut out = BufWriter::new(file);
+    // Lines are joined with `\n`, plus one at the end unless the last line
+    // is already empty, as the applier does.
+    let mut last: Option<bool> = None;
+    let mut emit = |out: &mut BufWriter<std::fs::File>, line: &str| {
+        if last.is_some() {
+            out.write_all(b"\n")?;
+        }
+        last = Some(line.is_empty());
+        out.write_all(line.as_bytes())
+    };
+    let mut pending = replacements.iter().peekable();
+    let mut skip = 0;
+    for (i, line) in lines(path)?.enumerate() {
+        let line = line?;
+        while let Some(replacement) = pending.next_if(|r| r.start == i) {
+            for new in replacement.new_lines {
+                emit(&mut out, new).map_err(io)?;
+            }
+            skip += replacement.old_len;
+        }
+        if skip > 0 {
+            skip -= 1;
+            continue;
+        }
+        emit(&mut out, &line).map_err(io)?;
+    }
+    for replacement in pending {
+        for new in replacement.new_lines {
+            emit(&mut out, new).map_err(io)?;
+        }
+    }
+    if last == Some(false) {
+        out.write_all(b"\n").map_err(io)?;
+    }
+    let mut file = out.into_inner().map_err(|err| io(err.into_error()))?;
+    if !newline {
+        // What was written ends with `\n` unless it is empty; drop it, with
+        // a `\r` before it.
+        let len = file.seek(SeekFrom::End(0)).map_err(io)?;
+        let tail = len.min(2);
+        let mut end = vec![0; tail as usize];
+        file.seek(SeekFrom::End(-(tail as i64))).map_err(io)?;
+        file.read_exact(&mut end).map_err(io)?;
+        let cut = match end.as_slice() {
+            [b'\r', b'\n'] => 2,
+            [.., b'\n'] => 1,
+            _ => 0,
+        };
+        file.set_len(len - cut).map_err(io)?;
+    }
+    Ok(())
+}
+
+/// Applies `patch` (or, with `dry_run`, only places its hunks) and prints
+/// the applier's summary. `Err` carries the failure's kind and message.
+pub(crate) fn apply(
+    patch: &Patch,
+    dry_run: bool,
+    final_newline: FinalNewline,
+    strategy: WriteStrategy,
+    staging_dir: Option<&Path>,
+) -> Result<(), (exit::Exit, String)> {
+    let result = apply_sections(patch, dry_run, final_newline, strategy, staging_dir);
+    if let Err((_, msg)) = &result {
+        eprintln!("{msg}");
+    }
+    result
+}
+
+fn apply_sections(
+    patch: &Patch,
+    dry_run: bool,
+    final_newline: FinalNewline,
+    strategy: WriteStrategy,
+    staging_dir: Option<&Path>,
+) -> Result<(), (exit::Exit, String)> {
+    let staging = Staging::new(staging_dir);
+    let failed = |err: String| (exit::Exit::Failed, err);
+    let chunks: Vec<Option<Vec<Chunk>>> = patch
+        .sections
+        .iter()
+        .map(|section| match section.kind {
+            SectionKind::Update => section.chunks().map(Some),
+            _ => Ok(None),
+        })
+        .collect::<Result<_, _>>()
+        .map_err(|err| (exit::Exit::ParseError, err))?;
+    // Every hunk is placed before anything is written.
+    let mut placed: Vec<Option<Vec<Replacement<'_>>>> = Vec::new();
+    for (section, chunks) in patch.sections.iter().zip(&chunks) {
+        match (section.kind, chunks) {
+            (SectionKind::Update, Some(chunks)) => {
+                placed.push(Some(locate(&section.path, chunks)?))
+            }
+            (SectionKind::Delete, _) if !Path::new(&section.path).is_file() => {
+                return Err(failed(format!(
+                    "Failed to delete file {}: file does not exist",
+                    section.path
+                )));
+            }
+            _ => placed.push(None),
+        }
+    }
+    if dry_run {
+        println!("Dry run: nothing was written. The patch would update the following files:");
+        for section in &patch.sections {
+            match section.move_to() {
+                Some(dest) => {
+                    println!("D {}", section.path);
+                    let status = if Path::new(dest).exists() { 'M' } else { 'A' };
+                    println!("{status} {dest}");
+                }
+                None => println!("{} {}", section.status(), section.path),
+            }
+        }
+        return Ok(());
+    }
+    // Files that no longer end with a newline, reported after the summary.
+    let mut lost: Vec<&str> = Vec::new();
+    // `replace` writes that went in place, likewise.
+    let mut in_place: Vec<(&str, String)> = Vec::new();
+    for (section, replacements) in patch.sections.iter().zip(&placed) {
+        let path = section.path.as_str();
+        // As `final_newline` judges the applier's writes: against what was
+        // at the written path before.
+        let dest = section.move_to().unwrap_or(path);
+        let before = (section.kind != SectionKind::Delete)
+            .then(|| ending(dest))
+            .flatten();
+        let newline = newline::wanted(final_newline, before).unwrap_or(true);
+        if before == Some(true) && !newline {
+            lost.push(dest);
+        }
+        match (section.kind, replacements) {
+            (SectionKind::Add, _) => {
+                if let Some(parent) = Path::new(path)
+                    .parent()
+                    .filter(|p| !p.as_os_str().is_empty())
+                {
+                    std::fs::create_dir_all(parent).map_err(|err| {
+                        failed(format!(
+                            "Failed to create parent directories for {path}: {err}"
+                        ))
+                    })?;
+                }
+                let mut contents = section.added_contents();
+                if !newline {
+                    contents.pop();
+                    if contents.ends_with('\r') {
+                        contents.pop();
+                    }
+                }
+                std::fs::write(path, contents)
+                    .map_err(|err| failed(format!("Failed to write file {path}: {err}")))?;
+            }
+            (SectionKind::Delete, _) => std::fs::remove_file(path)
+                .map_err(|err| failed(format!("Failed to delete file {path}: {err}")))?,
+            (SectionKind::Update, Some(replacements)) => {
+                if let Some(why) =
+                    write(path, dest, replacements, newline, strategy, &staging).map_err(failed)?
+                {
+                    in_place.push((dest, why));
+                }
+                if dest != path {
+                    std::fs::remove_file(path).map_err(|err| {
+                        failed(format!("Failed to remove original {path}: {err}"))
+                    })?;
+                }
+            }
+            (SectionKind::Update, None) => {}
+        }
+    }
+    concise::print_success(&patch.applied_summary());
+    for path in lost {
+        newline::report_lost(path, final_newline);
+    }
+    for (path, why) in in_place {
+        println!("Wrote {path} in place instead of replacing it: {why}.");
+    }
+    Ok(())
+}
@@ -0,0 +1,200 @@
+//! Symlinked targets (`symlinks` in the config).
+//!
+//! A patch target that is a symlink, or sits under a symlinked directory,
+//! is followed, refused or (for the target itself) replaced by a regular
+//! copy before the patch is applied, depending on `symlinks.action`. A link
+//! that resolves outside the working directory is always refused, so a
+//! patch can't write through it to files elsewhere.
+
+use crate::Mode;
+use serde::Deserialize;
+use serde::Serialize;
+use std::path::Component;
+use std::path::Path;
+use std::path::PathBuf;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum SymlinkAction {
+    #[default]
+    Follow,
+    Refuse,
+    /// Replace a symlinked target with a regular file holding the link
+    /// target's contents, so the patch doesn't change the linked file.
+    ReplaceLink,
+}
+
+impl SymlinkAction {
+    pub(crate) fn as_str(self) -> &'static str {
+        match self {
+            SymlinkAction::Follow => "follow",
+            SymlinkAction::Refuse => "refuse",
+            SymlinkAction::ReplaceLink => "replace-link",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub(crate) struct SymlinksConfig {
+    #[serde(default)]
+    pub(crate) action: SymlinkAction,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct Link {
+    /// The patch target.
+    pub(crate) target: String,
+    /// The symlink: the target itself or one of its parent directories.
+    pub(crate) link: String,
+    /// Where the link points, resolved.
+    pub(crate) resolved: String,
+    /// The link resolves outside the working directory.
+    pub(crate) escapes: bool,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct SymlinkGuard {
+    pub(crate) links: Vec<Link>,
+    pub(crate) action: SymlinkAction,
+    pub(crate) to: Mode,
+}
+
+impl SymlinkGuard {
+    pub(crate) fn describe(&self) -> Vec<String> {
+        self.links
+            .iter()
+            .filter(|link| link.escapes || self.action == SymlinkAction::Refuse)
+            .map(|link| {
+                let via = if link.link == link.target {
+                    String::new()
+                } else {
+                    format!(" (via {})", link.link)
+                };
+                if link.escapes {
+                    format!(
+                        "Symlink (refuse mode): {}{via} resolves to {}, outside the working directory; patches never write through such links.",
+                        link.target, link.resolved
+                    )
+                } else {
+                    format!(
+                        "Symlink (refuse mode): {}{via} is a symlink to {}.",
+                        link.target, link.resolved
+                    )
+                }
+            })
+            .collect()
+    }
+
+    /// The link that made the guard refuse, if any.
+    pub(crate) fn refusing(&self) -> Option<&Link> {
+        self.links
+            .iter()
+            .find(|link| link.escapes || self.action == SymlinkAction::Refuse)
+    }
+
+    /// With `replace-link`, turns every symlinked target into a regular file
+    /// with its current contents. Links in parent directories are followed.
+    pub(crate) fn replace_links(&self) -> Result<(), String> {
+        if self.action != SymlinkAction::ReplaceLink {
+            return Ok(());
+        }
+        for link in self.links.iter().filter(|link| link.link == link.target) {
+            let path = Path::new(&link.target);
+            // A dangling or directory link is removed without a copy; the
+            // applier then creates a regular file or reports the error.
+            let contents = std::fs::metadata(path)
+                .ok()
+                .filter(|meta| meta.is_file())
+                .and_then(|_| std::fs::read(path).ok());
+            let replaced = std::fs::remove_file(path).and_then(|()| match &contents {
+                Some(contents) => std::fs::write(path, contents),
+                None => Ok(()),
+            });
+            replaced.map_err(|err| format!("failed to replace symlink {}: {err}", link.target))?;
+            println!("Replaced symlink {} with a regular file.", link.target);
+        }
+        Ok(())
+    }
+}
+
+/// Finds symlinks among `files` and their parent directories (relative to
+/// the current directory). Returns `None` when there are none, or when
+/// they are all followed without concern.
+pub(crate) fn guard(cfg: &SymlinksConfig, files: &[String], mode: Mode) -> Option<SymlinkGuard> {
+    let root = std::env::current_dir().ok()?.canonicalize().ok()?;
+    let links: Vec<Link> = files
+        .iter()
+        .filter_map(|target| find_link(&root, target))
+        .collect();
+    if links.is_empty() {
+        return None;
+    }
+    let refuse = links
+        .iter()
+        .any(|link| link.escapes || cfg.action == SymlinkAction::Refuse);
+    if !refuse && cfg.action == SymlinkAction::Follow {
+        return None;
+    }
+    Some(SymlinkGuard {
+        links,
+        action: cfg.action,
+        to: if refuse { Mode::Refuse } else { mode },
+    })
+}
+
+/// The first symlink on the way from `root` to `target`.
+fn find_link(root: &Path, target: &str) -> Option<Link> {
+    let relative = Path::new(target);
+    if relative.is_absolute() {
+        return None;
+    }
+    let mut current = root.to_path_buf();
+    let mut shown = PathBuf::new();
+    for component in relative.components() {
+        match component {
+            Component::Normal(part) => {
+                current.push(part);
+                shown.push(part);
+            }
+            Component::ParentDir => {
+                current.pop();
+                shown.push("..");
+                continue;
+            }
+            _ => continue,
+        }
+        let meta = std::fs::symlink_metadata(&current).ok()?;
+        if !meta.file_type().is_symlink() {
+            continue;
+        }
+        let resolved = resolve(&current)?;
+        return Some(Link {
+            target: target.to_string(),
+            link: shown.to_string_lossy().into_owned(),
+            resolved: resolved.display().to_string(),
+            escapes: !resolved.starts_with(root),
+        });
+    }
+    None
+}
+
+/// Where the symlink at `path` points, following any further links; for a
+/// dangling link, its target with `.` and `..` resolved lexically.
+fn resolve(path: &Path) -> Option<PathBuf> {
+    if let Ok(resolved) = path.canonicalize() {
+        return Some(resolved);
+    }
+    let dest = std::fs::read_link(path).ok()?;
+    let parent = path.parent()?.canonicalize().ok()?;
+    let mut resolved = PathBuf::new();
+    for component in parent.join(dest).components() {
+        match component {
+            Component::ParentDir => {
+                resolved.pop();
+            }
+            Component::CurDir => {}
+            other => resolved.push(other),
+        }
+    }
+    Some(resolved)
+}
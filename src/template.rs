@@ -0,0 +1,68 @@
+//! `{variable}` substitution for the refuse, warn and apply banners.
+//!
+//! Unknown placeholders are left as written, so banners that happen to
+//! contain braces don't need escaping.
+
+use crate::Mode;
+use crate::fingerprint;
+use crate::policy::PatchFacts;
+use crate::stats::AgentStats;
+
+/// `stats` fills the session counts; without it they are left as written.
+pub(crate) fn render(
+    template: &str,
+    mode: Mode,
+    facts: &PatchFacts,
+    stats: Option<&AgentStats>,
+) -> String {
+    let mut out = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        out.push_str(&rest[..start]);
+        let after = &rest[start + 1..];
+        let value = after
+            .find('}')
+            .and_then(|end| Some((end, value(&after[..end], mode, facts, stats)?)));
+        match value {
+            Some((end, value)) => {
+                out.push_str(&value);
+                rest = &after[end + 1..];
+            }
+            None => {
+                out.push('{');
+                rest = after;
+            }
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+fn value(name: &str, mode: Mode, facts: &PatchFacts, stats: Option<&AgentStats>) -> Option<String> {
+    Some(match name {
+        "mode" => mode.as_str().to_string(),
+        "files" => facts.files.join(", "),
+        "file_count" => facts.files.len().to_string(),
+        "lines_added" => facts.lines_added.to_string(),
+        "lines_removed" => facts.lines_removed.to_string(),
+        "lines_changed" => facts.lines_changed().to_string(),
+        "risk" => facts.risk.to_string(),
+        "agent" => facts.agent.clone(),
+        "warn_count" => stats?.warned.to_string(),
+        "session_apply_count" => stats?.applied.to_string(),
+        "cwd" => std::env::current_dir()
+            .map(|p| p.display().to_string())
+            .unwrap_or_default(),
+        "hostname" => fingerprint::current().hostname.unwrap_or_default(),
+        "user" => fingerprint::current().user.unwrap_or_default(),
+        "repo" => fingerprint::current().repo.unwrap_or_default(),
+        "branch" => fingerprint::current().branch.unwrap_or_default(),
+        _ => {
+            let value = facts.metadata.get(name.strip_prefix("metadata.")?)?;
+            match value {
+                serde_json::Value::String(s) => s.clone(),
+                other => other.to_string(),
+            }
+        }
+    })
+}
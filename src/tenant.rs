@@ -0,0 +1,102 @@
+//! Per-tenant config namespaces (`--tenant NAME` / `$APPLY_PATCH_TENANT`).
+//!
+//! A tenant's settings live under `tenants.NAME` in the config file and are
+//! layered over the top-level ones. Files inherited from the top level (such
+//! as `audit_log` and the feedback file) get the tenant name spliced into
+//! their file name, so teams sharing a runner never write to each other's
+//! logs or state. A tenant section that isn't a valid config is a config
+//! error, not silently ignored.
+
+use crate::Config;
+use serde_json::Map;
+use serde_json::Value;
+use std::path::Path;
+use std::path::PathBuf;
+
+pub(crate) const TENANT_ENV: &str = "APPLY_PATCH_TENANT";
+
+/// The tenant from `--tenant` or, failing that and without `--no-config`,
+/// `$APPLY_PATCH_TENANT`.
+pub(crate) fn resolve(flag: Option<String>) -> Result<Option<String>, String> {
+    let tenant = flag.or_else(|| {
+        std::env::var(TENANT_ENV)
+            .ok()
+            .filter(|t| !t.is_empty() && !crate::hermetic::enabled())
+    });
+    match tenant {
+        Some(name) if !is_valid_name(&name) => Err(format!(
+            "Error: invalid tenant name: {name} (use letters, digits, '.', '_' or '-')."
+        )),
+        other => Ok(other),
+    }
+}
+
+fn is_valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && !name.starts_with('.')
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-'))
+}
+
+/// `dir/audit.jsonl` -> `dir/audit.NAME.jsonl`.
+pub(crate) fn scoped_path(path: &Path, tenant: &str) -> PathBuf {
+    let stem = path
+        .file_stem()
+        .map(|s| s.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let name = match path.extension() {
+        Some(ext) => format!("{stem}.{tenant}.{}", ext.to_string_lossy()),
+        None => format!("{stem}.{tenant}"),
+    };
+    path.with_file_name(name)
+}
+
+/// The effective config for `tenant`: its `tenants` entry layered over the
+/// top-level settings. Fails when the entry isn't a valid config section.
+pub(crate) fn effective(mut cfg: Config, tenant: &str) -> Result<Config, String> {
+    let overrides = cfg.tenants.remove(tenant);
+    cfg.tenants.clear();
+    if let Some(audit_log) = &cfg.audit_log {
+        cfg.audit_log = Some(scoped_path(audit_log, tenant));
+    }
+    let overrides = match overrides {
+        None => return Ok(cfg),
+        Some(Value::Object(overrides)) => overrides,
+        Some(_) => return Err(format!("tenants.{tenant} must be an object")),
+    };
+    let mut merged = match serde_json::to_value(&cfg) {
+        Ok(Value::Object(merged)) => merged,
+        Ok(_) => return Err("the config is not an object".to_string()),
+        Err(err) => return Err(err.to_string()),
+    };
+    merged.extend(overrides);
+    serde_json::from_value(Value::Object(merged)).map_err(|err| format!("tenants.{tenant}: {err}"))
+}
+
+/// Records config flags in the `tenants.NAME` object, created if missing:
+/// `Some(Some(v))` sets the key, `Some(None)` removes it so the top-level
+/// value applies again.
+pub(crate) fn set_overrides<'a>(
+    cfg: &mut Config,
+    tenant: &str,
+    changes: impl IntoIterator<Item = (&'a str, Option<Option<String>>)>,
+) {
+    let mut overrides = match cfg.tenants.remove(tenant) {
+        Some(Value::Object(overrides)) => overrides,
+        _ => Map::new(),
+    };
+    for (key, change) in changes {
+        match change {
+            Some(Some(value)) => {
+                overrides.insert(key.to_string(), Value::String(value));
+            }
+            Some(None) => {
+                overrides.remove(key);
+            }
+            None => {}
+        }
+    }
+    cfg.tenants
+        .insert(tenant.to_string(), Value::Object(overrides));
+}
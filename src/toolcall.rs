@@ -0,0 +1,44 @@
+//! `--input tool-json`: a patch wrapped in a tool-call payload.
+//!
+//! Function-calling layers hand tools a JSON object rather than raw text,
+//! e.g. `{"patch": "...", "cwd": "...", "metadata": {...}}`. The patch is
+//! taken from `patch` (or `input`), `cwd` is used like `--cwd`, and
+//! `metadata` is recorded in the audit log and available to banners as
+//! `{metadata.KEY}`.
+
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) enum InputFormat {
+    #[default]
+    Patch,
+    ToolJson,
+}
+
+impl InputFormat {
+    pub(crate) fn parse(value: &str) -> Option<Self> {
+        match value {
+            "patch" => Some(Self::Patch),
+            "tool-json" => Some(Self::ToolJson),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct ToolCall {
+    #[serde(alias = "input")]
+    pub(crate) patch: String,
+    #[serde(default)]
+    pub(crate) cwd: Option<PathBuf>,
+    #[serde(default)]
+    pub(crate) metadata: BTreeMap<String, serde_json::Value>,
+}
+
+impl ToolCall {
+    pub(crate) fn parse(text: &str) -> Result<Self, String> {
+        serde_json::from_str(text).map_err(|err| format!("invalid tool-call JSON: {err}"))
+    }
+}
@@ -0,0 +1,72 @@
+//! Patch trailers: `Key: value` lines after `*** End Patch`.
+//!
+//! An agent can sign its patch the way a commit is signed off:
+//!
+//! ```text
+//! *** End Patch
+//! Reason: fix the off-by-one in the pager
+//! Task-Id: PROJ-142
+//! Model: gpt-5
+//! ```
+//!
+//! Keys are letters, digits and `-`, starting with a letter. The lines are
+//! removed before anything else sees the patch (`--strict` included) and
+//! become metadata: banners quote them as `{metadata.KEY}`, the audit log
+//! records them, and `--to-branch` commits end with them as git trailers. Metadata from a
+//! tool-call payload wins over a trailer with the same key. Text after the
+//! envelope that isn't all trailers is left alone for the applier to report.
+
+use crate::patch::END_PATCH_MARKER;
+use std::borrow::Cow;
+
+fn parse_trailer(line: &str) -> Option<(String, String)> {
+    let (key, value) = line.split_once(':')?;
+    let valid = key.starts_with(|c: char| c.is_ascii_alphabetic())
+        && key.chars().all(|c| c.is_ascii_alphanumeric() || c == '-');
+    (valid && !value.trim().is_empty()).then(|| (key.to_string(), value.trim().to_string()))
+}
+
+/// `patch_arg` without its trailers, and the trailers in order.
+pub(crate) fn strip(patch_arg: &str) -> (Cow<'_, str>, Vec<(String, String)>) {
+    let lines: Vec<&str> = patch_arg.split_inclusive('\n').collect();
+    let text = |line: &str| line.trim_end_matches(['\n', '\r']).to_string();
+    let Some(end) = lines
+        .iter()
+        .rposition(|l| text(l).trim() == END_PATCH_MARKER)
+    else {
+        return (Cow::Borrowed(patch_arg), Vec::new());
+    };
+    let mut after = &lines[end + 1..];
+    // The closing line of a heredoc wrapper stays after the trailers.
+    let heredoc_end = after.last().copied().filter(|l| l.trim() == "EOF");
+    if heredoc_end.is_some() {
+        after = &after[..after.len() - 1];
+    }
+    let mut trailers: Vec<(String, String)> = Vec::new();
+    for line in after {
+        let line = text(line);
+        if line.trim().is_empty() {
+            continue;
+        }
+        match parse_trailer(&line) {
+            Some(trailer) => trailers.push(trailer),
+            None => return (Cow::Borrowed(patch_arg), Vec::new()),
+        }
+    }
+    if trailers.is_empty() {
+        return (Cow::Borrowed(patch_arg), Vec::new());
+    }
+    let mut stripped: String = lines[..=end].concat();
+    if let Some(eof) = heredoc_end {
+        stripped.push_str(eof);
+    }
+    (Cow::Owned(stripped), trailers)
+}
+
+/// The trailers as a git trailer block for a commit message.
+pub(crate) fn commit_block(trailers: &[(String, String)]) -> String {
+    trailers
+        .iter()
+        .map(|(key, value)| format!("{key}: {value}\n"))
+        .collect()
+}
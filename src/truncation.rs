@@ -0,0 +1,66 @@
+//! Heuristic detection of truncated patches.
+//!
+//! Model output cut off mid-stream is a common cause of broken files: the
+//! envelope never closes, or a file section stops right after its header
+//! or hunk marker. On the apply and warn paths such patches are rejected
+//! before anything is written (including with `--partial`), with a message
+//! asking for the complete patch; refuse mode still answers with its
+//! banner.
+
+use crate::patch::BEGIN_PATCH_MARKER;
+use crate::patch::END_PATCH_MARKER;
+use crate::patch::MOVE_TO_MARKER;
+use crate::patch::Patch;
+use crate::patch::SectionKind;
+
+/// Why `patch_text` looks truncated, or `None` if it doesn't. Text that
+/// isn't a patch at all is left for the parser to report.
+pub(crate) fn detect(patch_text: &str) -> Option<String> {
+    let mut lines = patch_text.lines().map(str::trim).filter(|l| !l.is_empty());
+    let mut first = lines.next()?;
+    if matches!(first, "<<EOF" | "<<'EOF'" | "<<\"EOF\"") {
+        first = lines.next()?;
+    }
+    if first != BEGIN_PATCH_MARKER {
+        return None;
+    }
+    if !lines.any(|line| line == END_PATCH_MARKER) {
+        let mid_line = if patch_text.ends_with('\n') {
+            ""
+        } else {
+            ", and the last line stops mid-line"
+        };
+        return Some(format!("there is no `{END_PATCH_MARKER}` line{mid_line}"));
+    }
+
+    let patch = Patch::parse(patch_text)?;
+    for section in &patch.sections {
+        if section.kind != SectionKind::Update {
+            continue;
+        }
+        let body: Vec<&str> = section
+            .body
+            .iter()
+            .map(String::as_str)
+            .filter(|line| !line.trim().is_empty() && !line.trim().starts_with(MOVE_TO_MARKER))
+            .collect();
+        match body.last() {
+            // A pure rename.
+            None if section.move_to().is_some() => {}
+            None => {
+                return Some(format!(
+                    "the section for {} has a header but no changes",
+                    section.path
+                ));
+            }
+            Some(last) if last.starts_with("@@") => {
+                return Some(format!(
+                    "the last hunk of {} ends after its `@@` line",
+                    section.path
+                ));
+            }
+            Some(_) => {}
+        }
+    }
+    None
+}
@@ -0,0 +1,50 @@
+//! Post-write verification (`verify_writes` in the config).
+//!
+//! Before a whole patch is applied its result is simulated in memory; once
+//! the applier reports success, every touched file is read back and its
+//! hash compared with the simulated one, so interference from another
+//! process, a filesystem quirk or an applier bug is reported as a failure
+//! instead of a success.
+
+use crate::digest::sha256_hex;
+use crate::patch::Patch;
+use crate::simulate::FileChange;
+use crate::simulate::simulate;
+use std::path::Path;
+
+/// The contents `patch_text` should leave on disk, or `None` if it can't be
+/// simulated (the applier then reports why).
+pub(crate) fn expected(patch_text: &str) -> Option<Vec<FileChange>> {
+    let patch = Patch::parse(patch_text)?;
+    simulate(&patch, Path::new(".")).ok()
+}
+
+/// Reads back each changed file and compares it with `expected`.
+pub(crate) fn check(expected: &[FileChange]) -> Result<(), String> {
+    for change in expected {
+        let path = change.path.as_str();
+        let found = std::fs::read(path).ok();
+        match (&change.after, found) {
+            (None, None) => {}
+            (None, Some(_)) => {
+                return Err(format!(
+                    "Verification failed: {path} still exists after being deleted."
+                ));
+            }
+            (Some(_), None) => {
+                return Err(format!(
+                    "Verification failed: {path} is missing after being written."
+                ));
+            }
+            (Some(after), Some(found)) => {
+                let (want, got) = (sha256_hex(after.as_bytes()), sha256_hex(&found));
+                if want != got {
+                    return Err(format!(
+                        "Verification failed: {path} does not match the patch's result (expected sha256 {want}, found {got})."
+                    ));
+                }
+            }
+        }
+    }
+    Ok(())
+}
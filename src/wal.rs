@@ -0,0 +1,575 @@
+//! Write-ahead intent log (`write_ahead_log` in the config).
+//!
+//! Before a patch touches the tree, an `intent` record listing each file,
+//! what will happen to it and the sha256 of its contents before and after
+//! is appended to `wal.jsonl` in the state directory; once the run is over
+//! an `applied` or `failed` record closes it. An intent that was never
+//! closed is an apply that was interrupted (killed, crashed, out of power),
+//! and `apply_patch wal show` lists those with what each file holds now, so
+//! an unexplained tree state can be traced to one. Nothing is recovered
+//! automatically.
+//!
+//! The contents each file had before are kept too, by digest, next to the
+//! log, so `apply_patch revert-file PATH` can put back a single file the
+//! last apply (or a given one) changed without undoing the whole patch. It
+//! only does so while the file still holds what that apply left.
+
+use crate::Decision;
+use crate::diagnostics;
+use crate::digest::sha256_hex;
+use crate::jsonl;
+use crate::patch::Patch;
+use crate::patch::SectionKind;
+use crate::simulate::FileChange;
+use crate::state;
+use crate::tenant;
+use serde::Deserialize;
+use serde::Serialize;
+use std::collections::BTreeSet;
+use std::path::Path;
+use std::path::PathBuf;
+
+/// Past this size, finished transactions are dropped from the log.
+const COMPACT_BYTES: u64 = 1024 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Status {
+    Intent,
+    Applied,
+    Failed,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct FileIntent {
+    path: String,
+    op: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    to: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    before_sha256: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    after_sha256: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Record {
+    id: String,
+    timestamp: u64,
+    status: Status,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pid: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    cwd: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    files: Vec<FileIntent>,
+}
+
+fn log_path(state_dir: Option<&Path>, tenant: Option<&str>) -> Option<PathBuf> {
+    let path = state::dir(state_dir)?.join("wal.jsonl");
+    Some(match tenant {
+        Some(name) => tenant::scoped_path(&path, name),
+        None => path,
+    })
+}
+
+/// Where the contents files had before a logged apply are kept, named by
+/// their digest.
+fn contents_dir(log: &Path) -> PathBuf {
+    log.with_extension("files")
+}
+
+fn keep_contents(dir: &Path, contents: &str) -> std::io::Result<()> {
+    let file = dir.join(sha256_hex(contents.as_bytes()));
+    if file.exists() {
+        return Ok(());
+    }
+    std::fs::create_dir_all(dir)?;
+    let tmp = file.with_extension("tmp");
+    std::fs::write(&tmp, contents)?;
+    std::fs::rename(&tmp, &file)
+}
+
+/// An open intent, closed by [`Transaction::finish`].
+pub(crate) struct Transaction {
+    path: PathBuf,
+    id: String,
+}
+
+/// Records the intent to apply `patch_arg`, whose simulated result is
+/// `changes` when it could be simulated. `None` (after a warning) if the
+/// log can't be written; the apply goes ahead regardless.
+pub(crate) fn begin(
+    state_dir: Option<&Path>,
+    tenant: Option<&str>,
+    patch_arg: &str,
+    changes: Option<&[FileChange]>,
+) -> Option<Transaction> {
+    let path = log_path(state_dir, tenant)?;
+    let patch = Patch::parse(patch_arg)?;
+    let digest = |path: &str, after: bool| {
+        let change = changes?.iter().find(|c| c.path == path)?;
+        let contents = if after { &change.after } else { &change.before };
+        contents.as_deref().map(|c| sha256_hex(c.as_bytes()))
+    };
+    let files = patch
+        .sections
+        .iter()
+        .map(|section| {
+            let to = section.move_to().map(str::to_string);
+            let op = match (section.kind, &to) {
+                (SectionKind::Add, _) => "add",
+                (SectionKind::Delete, _) => "delete",
+                (SectionKind::Update, Some(_)) => "move",
+                (SectionKind::Update, None) => "update",
+            };
+            let dest = to.as_deref().unwrap_or(&section.path);
+            FileIntent {
+                path: section.path.clone(),
+                op: op.to_string(),
+                before_sha256: digest(&section.path, false),
+                after_sha256: digest(dest, true),
+                to,
+            }
+        })
+        .collect();
+    let dir = contents_dir(&path);
+    for change in changes.unwrap_or_default() {
+        if let Some(before) = &change.before
+            && let Err(err) = keep_contents(&dir, before)
+        {
+            diagnostics::warning(format!(
+                "failed to keep the contents of {} in {}: {err}; revert-file won't be able to restore it",
+                change.path,
+                dir.display()
+            ));
+        }
+    }
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_nanos())
+        .unwrap_or_default();
+    let id = format!("{}-{now:x}", std::process::id());
+    let record = Record {
+        id: id.clone(),
+        timestamp: jsonl::timestamp(),
+        status: Status::Intent,
+        pid: Some(std::process::id()),
+        cwd: std::env::current_dir()
+            .ok()
+            .map(|dir| dir.display().to_string()),
+        files,
+    };
+    if let Err(err) = jsonl::append(&path, &record) {
+        crate::diagnostics::warning(format!(
+            "failed to write the write-ahead log {}: {err}",
+            path.display()
+        ));
+        return None;
+    }
+    Some(Transaction { path, id })
+}
+
+impl Transaction {
+    /// Closes the intent with the run's outcome.
+    pub(crate) fn finish(self, decision: Decision) {
+        let status = match decision {
+            Decision::Applied | Decision::Partial => Status::Applied,
+            _ => Status::Failed,
+        };
+        let record = Record {
+            id: self.id,
+            timestamp: jsonl::timestamp(),
+            status,
+            pid: None,
+            cwd: None,
+            files: Vec::new(),
+        };
+        if let Err(err) = jsonl::append(&self.path, &record) {
+            crate::diagnostics::warning(format!(
+                "failed to write the write-ahead log {}: {err}",
+                self.path.display()
+            ));
+            return;
+        }
+        compact(&self.path);
+    }
+}
+
+fn load(path: &Path) -> Vec<Record> {
+    std::fs::read_to_string(path)
+        .unwrap_or_default()
+        .lines()
+        .filter_map(|line| serde_json::from_str(line).ok())
+        .collect()
+}
+
+/// The intents in `records` that were never closed.
+fn interrupted(records: &[Record]) -> Vec<&Record> {
+    records
+        .iter()
+        .filter(|r| r.status == Status::Intent)
+        .filter(|r| {
+            !records
+                .iter()
+                .any(|other| other.id == r.id && other.status != Status::Intent)
+        })
+        .collect()
+}
+
+/// Keeps the log small by dropping finished transactions once it is over
+/// [`COMPACT_BYTES`], with the contents only they referred to. Interrupted
+/// intents are kept until the log is removed.
+fn compact(path: &Path) {
+    if std::fs::metadata(path).map_or(true, |meta| meta.len() <= COMPACT_BYTES) {
+        return;
+    }
+    let records = load(path);
+    let keep = interrupted(&records);
+    let mut text = String::new();
+    for record in &keep {
+        if let Ok(line) = serde_json::to_string(record) {
+            text.push_str(&line);
+            text.push('\n');
+        }
+    }
+    if std::fs::write(path, text).is_err() {
+        return;
+    }
+    let referred: BTreeSet<&str> = keep
+        .iter()
+        .flat_map(|record| &record.files)
+        .filter_map(|file| file.before_sha256.as_deref())
+        .collect();
+    let Ok(entries) = std::fs::read_dir(contents_dir(path)) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        if !referred.contains(entry.file_name().to_string_lossy().as_ref()) {
+            let _ = std::fs::remove_file(entry.path());
+        }
+    }
+}
+
+/// What `path` holds now: the contents it had `before` the patch or
+/// those it has `after` (digests; `None` when the file doesn't exist on
+/// that side), or something else.
+fn current_state(
+    cwd: Option<&str>,
+    path: &str,
+    before: Option<&str>,
+    after: Option<&str>,
+) -> &'static str {
+    let full = cwd.map_or_else(|| PathBuf::from(path), |cwd| Path::new(cwd).join(path));
+    let current = std::fs::read(&full).ok().map(|bytes| sha256_hex(&bytes));
+    match current.as_deref() {
+        current if current == after => "as the patch leaves it",
+        current if current == before => "as it was before",
+        None => "missing",
+        Some(_) => "changed since",
+    }
+}
+
+/// A path with its digest before and after the patch (`None`: absent).
+type Expected<'a> = (&'a str, Option<&'a str>, Option<&'a str>);
+
+/// `file`'s paths with their digests before and after, or `None` when the
+/// patch couldn't be simulated and there are no digests to compare.
+fn expected_states(file: &FileIntent) -> Option<Vec<Expected<'_>>> {
+    let (before, after) = (file.before_sha256.as_deref(), file.after_sha256.as_deref());
+    Some(match (file.op.as_str(), &file.to) {
+        ("add", _) => vec![(file.path.as_str(), None, Some(after?))],
+        ("delete", _) => vec![(file.path.as_str(), Some(before?), None)],
+        (_, Some(to)) => vec![
+            (file.path.as_str(), Some(before?), None),
+            (to.as_str(), None, Some(after?)),
+        ],
+        _ => vec![(file.path.as_str(), Some(before?), Some(after?))],
+    })
+}
+
+/// The log the config and `$APPLY_PATCH_TENANT` point at, or the exit code
+/// after reporting why there is none.
+fn configured_log() -> Result<PathBuf, i32> {
+    let cfg = match crate::effective_config(&crate::RunOptions::default()) {
+        Ok(cfg) => cfg,
+        Err(err) => {
+            diagnostics::error(err);
+            return Err(crate::exit::Exit::ConfigError.code());
+        }
+    };
+    let tenant = match tenant::resolve(None) {
+        Ok(tenant) => tenant,
+        Err(msg) => {
+            eprintln!("{msg}");
+            return Err(2);
+        }
+    };
+    log_path(cfg.state_dir.as_deref(), tenant.as_deref()).ok_or_else(|| {
+        diagnostics::error(
+            "could not determine the state directory (HOME/XDG_STATE_HOME not set).",
+        );
+        1
+    })
+}
+
+pub(crate) fn run_wal(args: &[String]) -> i32 {
+    let (show, all, json) = match args {
+        [action, rest @ ..] if action == "show" => {
+            let mut all = false;
+            let mut json = false;
+            for arg in rest {
+                match arg.as_str() {
+                    "--all" => all = true,
+                    "--json" => json = true,
+                    other => {
+                        diagnostics::error(format!("unknown option: {other}"));
+                        return 2;
+                    }
+                }
+            }
+            (true, all, json)
+        }
+        [action] if action == "clear" => (false, false, false),
+        _ => {
+            eprintln!("Usage: apply_patch wal <show [--all] [--json]|clear>");
+            return 2;
+        }
+    };
+    let path = match configured_log() {
+        Ok(path) => path,
+        Err(code) => return code,
+    };
+    if !show {
+        let _ = std::fs::remove_dir_all(contents_dir(&path));
+        return match std::fs::remove_file(&path) {
+            Ok(()) => {
+                println!("Removed {}.", path.display());
+                0
+            }
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+                println!("No write-ahead log at {}.", path.display());
+                0
+            }
+            Err(err) => {
+                diagnostics::error(format!("failed to remove {}: {err}", path.display()));
+                1
+            }
+        };
+    }
+
+    let records = load(&path);
+    let intents: Vec<&Record> = if all {
+        records
+            .iter()
+            .filter(|r| r.status == Status::Intent)
+            .collect()
+    } else {
+        interrupted(&records)
+    };
+    let outcome = |record: &Record| {
+        records
+            .iter()
+            .find(|r| r.id == record.id && r.status != Status::Intent)
+            .map_or("interrupted", |r| match r.status {
+                Status::Applied => "applied",
+                _ => "failed",
+            })
+    };
+    if json {
+        let report: Vec<serde_json::Value> = intents
+            .iter()
+            .map(|record| {
+                serde_json::json!({
+                    "id": record.id,
+                    "timestamp": record.timestamp,
+                    "outcome": outcome(record),
+                    "pid": record.pid,
+                    "cwd": record.cwd,
+                    "files": record.files,
+                })
+            })
+            .collect();
+        println!("{}", serde_json::Value::Array(report));
+        return 0;
+    }
+    if intents.is_empty() {
+        println!(
+            "No {}applies in {}.",
+            if all { "" } else { "interrupted " },
+            path.display()
+        );
+        return 0;
+    }
+    for record in intents {
+        println!(
+            "{} at {} (pid {}) in {}: {}",
+            record.id,
+            record.timestamp,
+            record.pid.map_or("?".to_string(), |pid| pid.to_string()),
+            record.cwd.as_deref().unwrap_or("?"),
+            outcome(record)
+        );
+        for file in &record.files {
+            let target = match &file.to {
+                Some(to) => format!("{} -> {to}", file.path),
+                None => file.path.clone(),
+            };
+            let states = match expected_states(file) {
+                Some(states) => states
+                    .into_iter()
+                    .map(|(path, before, after)| {
+                        let state = current_state(record.cwd.as_deref(), path, before, after);
+                        if file.to.is_some() {
+                            format!("{path} {state}")
+                        } else {
+                            state.to_string()
+                        }
+                    })
+                    .collect::<Vec<_>>()
+                    .join(", "),
+                None => "no digests recorded".to_string(),
+            };
+            println!("  {} {target}: {states}", file.op);
+        }
+    }
+    0
+}
+
+/// `revert-file [--run ID] PATH`: puts back what PATH held before the last
+/// applied patch that touched it, or before run ID.
+pub(crate) fn run_revert_file(args: &[String]) -> i32 {
+    const USAGE: &str = "Usage: apply_patch revert-file [--run ID] PATH";
+    let mut run = None;
+    let mut target = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--run" => match args.next() {
+                Some(id) => run = Some(id.as_str()),
+                None => {
+                    eprintln!("{USAGE}");
+                    return 2;
+                }
+            },
+            other if other.starts_with("--") => {
+                diagnostics::error(format!("unknown option: {other}"));
+                return 2;
+            }
+            _ if target.is_none() => target = Some(arg.as_str()),
+            _ => {
+                eprintln!("{USAGE}");
+                return 2;
+            }
+        }
+    }
+    let Some(target) = target else {
+        eprintln!("{USAGE}");
+        return 2;
+    };
+    let path = match configured_log() {
+        Ok(path) => path,
+        Err(code) => return code,
+    };
+    let cwd = match std::env::current_dir() {
+        Ok(cwd) => cwd,
+        Err(err) => {
+            diagnostics::error(format!("cannot resolve the working directory: {err}"));
+            return 1;
+        }
+    };
+    let wanted = crate::ownstate::resolve(&cwd, Path::new(target));
+
+    let records = load(&path);
+    if let Some(id) = run
+        && !records.iter().any(|r| r.id == id)
+    {
+        diagnostics::error(format!("no run {id} in {}.", path.display()));
+        return 1;
+    }
+    let applied = |record: &Record| {
+        records
+            .iter()
+            .any(|r| r.id == record.id && r.status == Status::Applied)
+    };
+    let found = records
+        .iter()
+        .rev()
+        .filter(|r| r.status == Status::Intent)
+        .filter(|r| run.map_or_else(|| applied(r), |id| r.id == id))
+        .find_map(|record| {
+            let base = record.cwd.as_deref().map_or(cwd.as_path(), Path::new);
+            let matches = |path: &str| crate::ownstate::resolve(base, Path::new(path)) == wanted;
+            record
+                .files
+                .iter()
+                .find(|file| matches(&file.path) || file.to.as_deref().is_some_and(matches))
+                .map(|file| (record, base, file))
+        });
+    let Some((record, base, file)) = found else {
+        diagnostics::error(format!(
+            "{} in {} touched {target}.",
+            run.map_or_else(
+                || "no applied patch".to_string(),
+                |id| format!("run {id} never")
+            ),
+            path.display()
+        ));
+        return 1;
+    };
+    let id = &record.id;
+    let Some(states) = expected_states(file) else {
+        diagnostics::error(format!(
+            "{id} couldn't be simulated, so no contents were kept for {target}."
+        ));
+        return 1;
+    };
+
+    // Every side must still be as the patch left it, and every old
+    // contents still kept, before anything is written.
+    let dir = contents_dir(&path);
+    let mut restore: Vec<(PathBuf, Option<String>)> = Vec::new();
+    for (path, before, after) in states {
+        let full = base.join(path);
+        let current = std::fs::read(&full).ok().map(|bytes| sha256_hex(&bytes));
+        if current.as_deref() != after {
+            diagnostics::error(format!(
+                "{path} changed since {id}; revert it by hand. Nothing was changed."
+            ));
+            return 1;
+        }
+        let contents = match before {
+            Some(digest) => match std::fs::read_to_string(dir.join(digest)) {
+                Ok(contents) => Some(contents),
+                Err(err) => {
+                    diagnostics::error(format!(
+                        "the contents of {path} before {id} are not kept in {} ({err}). Nothing was changed.",
+                        dir.display()
+                    ));
+                    return 1;
+                }
+            },
+            None => None,
+        };
+        restore.push((full, contents));
+    }
+    for (full, contents) in restore {
+        let result = match contents {
+            Some(contents) => full
+                .parent()
+                .map_or(Ok(()), std::fs::create_dir_all)
+                .and_then(|()| std::fs::write(&full, contents)),
+            None => std::fs::remove_file(&full),
+        };
+        if let Err(err) = result {
+            diagnostics::error(format!("failed to restore {}: {err}", full.display()));
+            return 1;
+        }
+    }
+    let shown = match &file.to {
+        Some(to) => format!("{} -> {to}", file.path),
+        None => file.path.clone(),
+    };
+    println!("Undid the {} of {shown} from {id}.", file.op);
+    0
+}
@@ -0,0 +1,128 @@
+//! `--watch DIR`: apply `*.patch` files dropped into a directory.
+//!
+//! Each file goes through the same mode/policy pipeline as a patch given on
+//! the command line (relative to the current directory, not DIR), then is
+//! moved to `DIR/applied/` or `DIR/failed/`. The directory is polled, so it
+//! works on any filesystem without extra dependencies. The config is cached
+//! and only re-read when the file changes; while it fails to load, patches
+//! wait in DIR.
+
+use crate::Config;
+use crate::Decision;
+use crate::RunOptions;
+use crate::config_cache::ConfigCache;
+use crate::diagnostics;
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::io::Write as _;
+use std::path::Path;
+use std::path::PathBuf;
+use std::time::Duration;
+
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+const APPLIED_DIR: &str = "applied";
+const FAILED_DIR: &str = "failed";
+
+pub(crate) fn run_watch(dir: &Path, options: &RunOptions) -> i32 {
+    for sub in [APPLIED_DIR, FAILED_DIR] {
+        if let Err(err) = std::fs::create_dir_all(dir.join(sub)) {
+            diagnostics::error(format!(
+                "failed to create {}: {err}",
+                dir.join(sub).display()
+            ));
+            return 1;
+        }
+    }
+    println!("Watching {} for *.patch files.", dir.display());
+
+    // Sizes seen on the previous poll: a file is only picked up once its
+    // size has stopped changing, so half-written patches are left alone.
+    let mut pending: HashMap<PathBuf, u64> = HashMap::new();
+    // Files processed but not moved away; never processed twice.
+    let mut stuck: HashSet<PathBuf> = HashSet::new();
+    let mut config = ConfigCache::default();
+    loop {
+        let mut ready: Vec<PathBuf> = Vec::new();
+        let mut seen: HashMap<PathBuf, u64> = HashMap::new();
+        for path in patch_files(dir) {
+            if stuck.contains(&path) {
+                continue;
+            }
+            let Ok(len) = std::fs::metadata(&path).map(|m| m.len()) else {
+                continue;
+            };
+            if pending.get(&path) == Some(&len) {
+                ready.push(path);
+            } else {
+                seen.insert(path, len);
+            }
+        }
+        pending = seen;
+        ready.sort();
+        // With a broken config, leave patches in place until it is fixed.
+        if let Some(cfg) = config.get(options) {
+            for path in ready {
+                if !process_file(dir, &path, cfg, options) {
+                    stuck.insert(path);
+                }
+            }
+        }
+        std::thread::sleep(POLL_INTERVAL);
+    }
+}
+
+fn patch_files(dir: &Path) -> Vec<PathBuf> {
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return Vec::new();
+    };
+    entries
+        .filter_map(|entry| entry.ok().map(|e| e.path()))
+        .filter(|path| path.is_file() && path.extension().is_some_and(|ext| ext == "patch"))
+        .collect()
+}
+
+/// Applies one patch file and moves it out of the way. Returns false if the
+/// file could not be moved.
+fn process_file(dir: &Path, path: &Path, cfg: &Config, options: &RunOptions) -> bool {
+    let name = path
+        .file_name()
+        .map(|n| n.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    println!("==> {name}");
+    diagnostics::discard();
+    let decision = match std::fs::read_to_string(path) {
+        Ok(patch) => crate::process_patch(cfg, &patch, options)
+            .map_or(Decision::Failed, |outcome| outcome.decision),
+        Err(err) => {
+            diagnostics::error(format!("failed to read {}: {err}", path.display()));
+            Decision::Failed
+        }
+    };
+    let sub = match decision {
+        Decision::Applied | Decision::Forwarded => APPLIED_DIR,
+        Decision::Partial | Decision::Refused | Decision::Failed | Decision::DryRun => FAILED_DIR,
+    };
+    let mut dest = dir.join(sub).join(&name);
+    if dest.exists() {
+        dest = dir
+            .join(sub)
+            .join(format!("{}-{name}", crate::jsonl::timestamp()));
+    }
+    let moved = match std::fs::rename(path, &dest) {
+        Ok(()) => {
+            let moved_name = dest.file_name().unwrap_or_default().to_string_lossy();
+            println!("--> {sub}/{moved_name}");
+            true
+        }
+        Err(err) => {
+            diagnostics::error(format!(
+                "failed to move {} to {}: {err}",
+                path.display(),
+                dest.display()
+            ));
+            false
+        }
+    };
+    let _ = std::io::stdout().flush();
+    moved
+}
@@ -0,0 +1,90 @@
+//! `skip_whitespace_only_hunks` in the config: update hunks that only
+//! re-indent lines or change trailing whitespace are removed from a patch
+//! and reported, for teams whose formatter owns whitespace anyway.
+//!
+//! A hunk is whitespace-only when it removes and adds the same number of
+//! lines and they match pairwise once leading and trailing whitespace is
+//! trimmed. Like `drop_paths`, skipping happens before policies and guards
+//! run. A section left without hunks is removed too, unless it moves the
+//! file.
+
+use crate::patch::Patch;
+use crate::patch::SectionKind;
+use std::borrow::Cow;
+
+/// Whether the lines of one hunk only change whitespace.
+fn whitespace_only(lines: &[String]) -> bool {
+    let removed: Vec<&str> = lines
+        .iter()
+        .filter_map(|line| line.strip_prefix('-'))
+        .collect();
+    let added: Vec<&str> = lines
+        .iter()
+        .filter_map(|line| line.strip_prefix('+'))
+        .collect();
+    !removed.is_empty()
+        && removed.len() == added.len()
+        && removed != added
+        && removed
+            .iter()
+            .zip(&added)
+            .all(|(old, new)| old.trim() == new.trim())
+}
+
+/// Removes every whitespace-only hunk. Returns the remaining patch text and
+/// one note per skipped hunk.
+pub(crate) fn strip(patch_text: &str, enabled: bool) -> (Cow<'_, str>, Vec<String>) {
+    if !enabled {
+        return (Cow::Borrowed(patch_text), Vec::new());
+    }
+    let Some(mut patch) = Patch::parse(patch_text) else {
+        return (Cow::Borrowed(patch_text), Vec::new());
+    };
+    let mut notes: Vec<String> = Vec::new();
+    patch.sections.retain_mut(|section| {
+        if section.kind != SectionKind::Update {
+            return true;
+        }
+        let moves = section.move_to().is_some();
+        let (head, body) = section.body.split_at(usize::from(moves));
+        // Hunks start at their `@@` line; the first may have none.
+        let mut hunks: Vec<&[String]> = Vec::new();
+        let mut start = 0;
+        for (i, line) in body.iter().enumerate() {
+            if line.starts_with("@@") && i > start {
+                hunks.push(&body[start..i]);
+                start = i;
+            }
+        }
+        hunks.push(&body[start..]);
+        let mut kept: Vec<String> = head.to_vec();
+        let mut skipped = 0;
+        for (n, hunk) in hunks.iter().enumerate() {
+            if whitespace_only(hunk) {
+                notes.push(format!(
+                    "Skipped whitespace-only hunk {} of {} (skip_whitespace_only_hunks).",
+                    n + 1,
+                    section.path
+                ));
+                skipped += 1;
+            } else {
+                kept.extend(hunk.iter().cloned());
+            }
+        }
+        let total = hunks.len();
+        if skipped == 0 {
+            return true;
+        }
+        if skipped == total && moves {
+            // A move needs a hunk; keep the section as it was.
+            notes.truncate(notes.len() - skipped);
+            return true;
+        }
+        section.body = kept;
+        skipped < total
+    });
+    if notes.is_empty() {
+        return (Cow::Borrowed(patch_text), notes);
+    }
+    (Cow::Owned(patch.render()), notes)
+}
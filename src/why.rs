@@ -0,0 +1,360 @@
+//! `apply_patch why`: explains the most recent decision.
+//!
+//! Every run that gets as far as a patch leaves a trace in
+//! `last-decision.json` in the state directory (per tenant): the config
+//! layers that made up its settings, the policy rule that matched, the
+//! checks that were configured, the notices from guards and escalation,
+//! the refusal reason and the banner it printed. `why` prints it back, so
+//! "why was my patch refused?" doesn't need the source. Writing the trace is
+//! best effort and never reported.
+
+use crate::Config;
+use crate::Decision;
+use crate::Mode;
+use crate::diagnostics;
+use crate::policy::PatchFacts;
+use crate::reason::RefusalReason;
+use crate::state;
+use crate::tenant;
+use serde::Deserialize;
+use serde::Serialize;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+/// Config sections listed under "checks" when they differ from the
+/// defaults; `limits` and `max_dir_delete_files` are always listed.
+const CHECKS: [&str; 12] = [
+    "escalate",
+    "editor_guard",
+    "git_boundaries",
+    "require_clean",
+    "branch_rules",
+    "codeowners",
+    "symlinks",
+    "new_dirs",
+    "lint",
+    "failure_guard",
+    "drop_paths",
+    "conflict_guard",
+];
+
+/// The banner printed this run, if any.
+static MESSAGE: Mutex<Option<String>> = Mutex::new(None);
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct PolicyMatch {
+    index: usize,
+    when: String,
+    mode: Mode,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct Trace {
+    timestamp: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    cwd: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    tenant: Option<String>,
+    layers: Vec<String>,
+    #[serde(default)]
+    files: Vec<String>,
+    #[serde(default)]
+    lines_added: usize,
+    #[serde(default)]
+    lines_removed: usize,
+    #[serde(default)]
+    base_mode: Mode,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    policy: Option<PolicyMatch>,
+    /// Policy rules that were evaluated.
+    #[serde(default)]
+    policies: usize,
+    #[serde(default)]
+    mode: Mode,
+    #[serde(default)]
+    checks: Vec<(String, serde_json::Value)>,
+    #[serde(default)]
+    notices: Vec<String>,
+    /// `REFUSED: <code> <detail>`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    reason: Option<String>,
+    /// `None` when the run stopped before deciding (parse errors, --strict).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    decision: Option<Decision>,
+    exit_code: i32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    message: Option<String>,
+}
+
+/// Notes the banner printed this run.
+pub(crate) fn emitted(message: &str) {
+    if let Ok(mut slot) = MESSAGE.lock() {
+        *slot = Some(message.to_string());
+    }
+}
+
+fn trace_path(state_dir: Option<&Path>, tenant: Option<&str>) -> Option<PathBuf> {
+    let path = state::dir(state_dir)?.join("last-decision.json");
+    Some(match tenant {
+        Some(name) => tenant::scoped_path(&path, name),
+        None => path,
+    })
+}
+
+/// Where the settings came from, in the order they were layered.
+fn layers(cfg: &Config, tenant: Option<&str>) -> Vec<String> {
+    let mut layers = vec!["built-in defaults".to_string()];
+    let file = crate::config_path();
+    match &file {
+        Some(path) if path.exists() => layers.push(format!("config file {}", path.display())),
+        Some(path) => layers.push(format!("no config file at {}", path.display())),
+        None if crate::hermetic::enabled() => layers.push("--no-config".to_string()),
+        None => layers.push("no config path (HOME/XDG_CONFIG_HOME not set)".to_string()),
+    }
+    let raw = file
+        .filter(|path| path.exists())
+        .and_then(|path| crate::load_config(&path).ok());
+    if let Some(name) = tenant {
+        let keys = raw
+            .as_ref()
+            .and_then(|raw| raw.tenants.get(name))
+            .and_then(|overrides| overrides.as_object())
+            .map(|overrides| overrides.keys().cloned().collect::<Vec<_>>().join(", "))
+            .filter(|keys| !keys.is_empty());
+        layers.push(match keys {
+            Some(keys) => format!("tenant {name}: overrides {keys}"),
+            None => format!("tenant {name}: no overrides (log files scoped to it)"),
+        });
+    }
+    if let Some(locale) = crate::locale::resolve(&cfg.output)
+        && let Some(key) = raw
+            .as_ref()
+            .and_then(|raw| crate::locale::matching_key(raw, &locale))
+    {
+        layers.push(format!("locale {locale}: messages from locales.{key}"));
+    }
+    layers
+}
+
+/// The configured checks: limits always, other sections when they differ
+/// from the defaults.
+fn checks(cfg: &Config) -> Vec<(String, serde_json::Value)> {
+    let (Ok(serde_json::Value::Object(current)), Ok(serde_json::Value::Object(defaults))) = (
+        serde_json::to_value(cfg),
+        serde_json::to_value(Config::default()),
+    ) else {
+        return Vec::new();
+    };
+    ["limits", "max_dir_delete_files"]
+        .into_iter()
+        .chain(CHECKS)
+        .filter_map(|key| {
+            let value = current.get(key)?;
+            let always = matches!(key, "limits" | "max_dir_delete_files");
+            (always || defaults.get(key) != Some(value)).then(|| (key.to_string(), value.clone()))
+        })
+        .collect()
+}
+
+fn new_trace(cfg: &Config, tenant: Option<&str>, exit_code: i32) -> Trace {
+    Trace {
+        timestamp: crate::jsonl::timestamp(),
+        cwd: std::env::current_dir()
+            .ok()
+            .map(|dir| dir.display().to_string()),
+        tenant: tenant.map(str::to_string),
+        layers: layers(cfg, tenant),
+        base_mode: cfg.mode,
+        mode: cfg.mode,
+        policies: cfg.policies.len(),
+        checks: checks(cfg),
+        exit_code,
+        message: MESSAGE.lock().ok().and_then(|mut slot| slot.take()),
+        ..Trace::default()
+    }
+}
+
+fn save(cfg: &Config, tenant: Option<&str>, trace: &Trace) {
+    let Some(path) = trace_path(cfg.state_dir.as_deref(), tenant) else {
+        return;
+    };
+    if let Some(parent) = path.parent() {
+        let _ = std::fs::create_dir_all(parent);
+    }
+    if let Ok(data) = serde_json::to_vec_pretty(trace) {
+        let _ = std::fs::write(path, data);
+    }
+}
+
+/// What a run decided, for [`record`].
+pub(crate) struct Decided<'a> {
+    pub(crate) facts: &'a PatchFacts,
+    pub(crate) policy: Option<usize>,
+    pub(crate) mode: Mode,
+    pub(crate) notices: &'a [String],
+    pub(crate) reason: Option<&'a RefusalReason>,
+    pub(crate) decision: Decision,
+    pub(crate) exit_code: i32,
+}
+
+/// Records the trace of a run that reached a decision.
+pub(crate) fn record(cfg: &Config, tenant: Option<&str>, decided: &Decided<'_>) {
+    let mut trace = new_trace(cfg, tenant, decided.exit_code);
+    trace.files = decided.facts.files.clone();
+    trace.lines_added = decided.facts.lines_added;
+    trace.lines_removed = decided.facts.lines_removed;
+    trace.policy = decided.policy.and_then(|index| {
+        let rule = cfg.policies.get(index)?;
+        Some(PolicyMatch {
+            index,
+            when: rule.when.clone(),
+            mode: rule.mode,
+        })
+    });
+    trace.mode = decided.mode;
+    trace.notices = decided.notices.to_vec();
+    trace.reason = decided.reason.map(RefusalReason::line);
+    trace.decision = Some(decided.decision);
+    save(cfg, tenant, &trace);
+}
+
+/// Records the trace of a run that stopped before deciding.
+pub(crate) fn record_stopped(cfg: &Config, tenant: Option<&str>, exit_code: i32) {
+    save(cfg, tenant, &new_trace(cfg, tenant, exit_code));
+}
+
+fn print(trace: &Trace, path: &Path) {
+    let outcome = match trace.decision {
+        Some(decision) => serde_json::to_value(decision)
+            .ok()
+            .and_then(|v| v.as_str().map(str::to_string))
+            .unwrap_or_default(),
+        None => "stopped before a decision (the error was printed on stderr)".to_string(),
+    };
+    println!(
+        "Last decision: {outcome}, exit code {} (at {} in {}; from {}).",
+        trace.exit_code,
+        trace.timestamp,
+        trace.cwd.as_deref().unwrap_or("?"),
+        path.display()
+    );
+    println!("Config layers:");
+    for layer in &trace.layers {
+        println!("  {layer}");
+    }
+    if trace.decision.is_none() {
+        return;
+    }
+    println!(
+        "Files: {} (+{} -{})",
+        if trace.files.is_empty() {
+            "none".to_string()
+        } else {
+            trace.files.join(", ")
+        },
+        trace.lines_added,
+        trace.lines_removed
+    );
+    println!("Base mode: {}", trace.base_mode.as_str());
+    match &trace.policy {
+        Some(rule) => println!(
+            "Policy: rule {} `{}` matched and selected {}.",
+            rule.index,
+            rule.when,
+            rule.mode.as_str()
+        ),
+        None if trace.policies == 0 => println!("Policy: no rules configured."),
+        None => println!(
+            "Policy: none of the {} rules matched; the base mode applied.",
+            trace.policies
+        ),
+    }
+    println!("Checks:");
+    for (key, value) in &trace.checks {
+        println!("  {key}: {value}");
+    }
+    if !trace.notices.is_empty() {
+        println!("Notices:");
+        for notice in &trace.notices {
+            println!("  {notice}");
+        }
+    }
+    println!("Enforced mode: {}", trace.mode.as_str());
+    if let Some(reason) = &trace.reason {
+        println!("Reason: {reason}");
+    }
+    match &trace.message {
+        Some(message) => {
+            println!("Message:");
+            for line in message.lines() {
+                if line.is_empty() {
+                    println!();
+                } else {
+                    println!("  {line}");
+                }
+            }
+        }
+        None => println!("Message: none"),
+    }
+}
+
+pub(crate) fn run_why(args: &[String]) -> i32 {
+    let json = match args {
+        [] => false,
+        [flag] if flag == "--json" => true,
+        _ => {
+            eprintln!("Usage: apply_patch why [--json]");
+            return 2;
+        }
+    };
+    let cfg = match crate::effective_config(&crate::RunOptions::default()) {
+        Ok(cfg) => cfg,
+        Err(err) => {
+            diagnostics::error(err);
+            return crate::exit::Exit::ConfigError.code();
+        }
+    };
+    let tenant = match tenant::resolve(None) {
+        Ok(tenant) => tenant,
+        Err(msg) => {
+            eprintln!("{msg}");
+            return 2;
+        }
+    };
+    let Some(path) = trace_path(cfg.state_dir.as_deref(), tenant.as_deref()) else {
+        diagnostics::error(
+            "could not determine the state directory (HOME/XDG_STATE_HOME not set).",
+        );
+        return 1;
+    };
+    let trace: Trace = match std::fs::read(&path) {
+        Ok(bytes) => match serde_json::from_slice(&bytes) {
+            Ok(trace) => trace,
+            Err(err) => {
+                diagnostics::error(format!("failed to parse {}: {err}", path.display()));
+                return 1;
+            }
+        },
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+            println!("No decision recorded yet in {}.", path.display());
+            return 0;
+        }
+        Err(err) => {
+            diagnostics::error(format!("failed to read {}: {err}", path.display()));
+            return 1;
+        }
+    };
+    if json {
+        match serde_json::to_string_pretty(&trace) {
+            Ok(text) => println!("{text}"),
+            Err(err) => {
+                diagnostics::error(err);
+                return 1;
+            }
+        }
+    } else {
+        print(&trace, &path);
+    }
+    0
+}
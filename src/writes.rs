@@ -0,0 +1,193 @@
+//! How whole-patch applies write files (`write_strategy` in the config).
+//!
+//! `in-place` (the default) is what the applier does: each file is
+//! truncated and rewritten, so it keeps its inode, and with it hard links,
+//! bind mounts and the watches of build tools. `replace` writes a temporary
+//! file next to the target and renames it over the target, so readers never
+//! see a half-written file, at the cost of a new inode. Files with more than
+//! one hard link, and files that can't be renamed over (such as bind-mount
+//! targets), are still written in place, and a line after the summary says
+//! so.
+//!
+//! Temporary files that are renamed over a target (here, in streaming
+//! applies and in `commit-staged`) go to `staging_dir` when it is set and on
+//! the target's filesystem, else to `$TMPDIR` when that is set and on the
+//! target's filesystem, else next to the target. A rename never crosses
+//! filesystems, so it stays atomic; a configured `staging_dir` on another
+//! filesystem is passed over with a warning.
+
+use crate::concise;
+use crate::diagnostics;
+use crate::patch::Patch;
+use crate::simulate::FileChange;
+use serde::Deserialize;
+use serde::Serialize;
+use std::cell::Cell;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum WriteStrategy {
+    #[default]
+    InPlace,
+    Replace,
+}
+
+impl WriteStrategy {
+    pub(crate) fn as_str(self) -> &'static str {
+        match self {
+            WriteStrategy::InPlace => "in-place",
+            WriteStrategy::Replace => "replace",
+        }
+    }
+}
+
+/// Writes `changes` (the simulated result of `patch`) with the `replace`
+/// strategy and prints the applier's summary, followed by a line for each
+/// file written in place instead.
+pub(crate) fn apply_replacing(
+    patch: &Patch,
+    changes: &[FileChange],
+    staging_dir: Option<&Path>,
+) -> Result<(), String> {
+    let staging = Staging::new(staging_dir);
+    let mut in_place: Vec<(String, String)> = Vec::new();
+    for change in changes {
+        let path = Path::new(&change.path);
+        let Some(contents) = &change.after else {
+            std::fs::remove_file(path)
+                .map_err(|err| format!("Failed to delete file {}: {err}", change.path))?;
+            continue;
+        };
+        if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+            std::fs::create_dir_all(parent).map_err(|err| {
+                format!(
+                    "Failed to create parent directories for {}: {err}",
+                    change.path
+                )
+            })?;
+        }
+        let links = std::fs::metadata(path).map_or(1, |meta| hard_links(&meta));
+        let why = if links > 1 {
+            Some(format!("it has {links} hard links"))
+        } else {
+            replace(path, contents, &staging)
+                .err()
+                .map(|err| format!("it couldn't be replaced ({err})"))
+        };
+        if let Some(why) = why {
+            std::fs::write(path, contents)
+                .map_err(|err| format!("Failed to write file {}: {err}", change.path))?;
+            in_place.push((change.path.clone(), why));
+        }
+    }
+    concise::print_success(&patch.applied_summary());
+    for (path, why) in in_place {
+        println!("Wrote {path} in place instead of replacing it: {why}.");
+    }
+    Ok(())
+}
+
+/// Writes `contents` to a temporary file (see [`Staging`]), with
+/// `path`'s permissions, and renames it over `path`.
+fn replace(path: &Path, contents: &str, staging: &Staging<'_>) -> std::io::Result<()> {
+    let tmp = staging.temp_path(path, "tmp");
+    let result = std::fs::write(&tmp, contents)
+        .and_then(|()| match std::fs::metadata(path) {
+            Ok(meta) => std::fs::set_permissions(&tmp, meta.permissions()),
+            Err(_) => Ok(()),
+        })
+        .and_then(|()| std::fs::rename(&tmp, path));
+    if result.is_err() {
+        let _ = std::fs::remove_file(&tmp);
+    }
+    result
+}
+
+/// Where the temporary files of one run go: `staging_dir` (the config's),
+/// else `$TMPDIR`, when on the target's filesystem, else next to the
+/// target.
+pub(crate) struct Staging<'a> {
+    dir: Option<&'a Path>,
+    /// Set once `dir` has been passed over, so that is reported once.
+    warned: Cell<bool>,
+}
+
+impl<'a> Staging<'a> {
+    pub(crate) fn new(dir: Option<&'a Path>) -> Self {
+        Self {
+            dir,
+            warned: Cell::new(false),
+        }
+    }
+
+    /// A temporary file to rename over `target`; `suffix` ends its name.
+    pub(crate) fn temp_path(&self, target: &Path, suffix: &str) -> PathBuf {
+        // Two targets with the same name share a staging directory.
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+        let name = target.file_name().unwrap_or_default().to_string_lossy();
+        let pid = std::process::id();
+        let parent = target
+            .parent()
+            .filter(|p| !p.as_os_str().is_empty())
+            .unwrap_or(Path::new("."));
+        let staged = |dir: &Path| {
+            let n = COUNTER.fetch_add(1, Ordering::Relaxed);
+            dir.join(format!(".{name}.apply_patch.{pid}.{n}.{suffix}"))
+        };
+        if let Some(dir) = self.dir {
+            let why = match same_device(dir, parent) {
+                Ok(true) => return staged(dir),
+                Ok(false) => format!("is not on the filesystem of {}", target.display()),
+                Err(err) => format!("can't be used ({err})"),
+            };
+            if !self.warned.replace(true) {
+                diagnostics::warning(format!(
+                    "staging_dir {} {why}; staging elsewhere.",
+                    dir.display()
+                ));
+            }
+        }
+        let tmpdir = std::env::var_os("TMPDIR")
+            .filter(|dir| !dir.is_empty())
+            .map(PathBuf::from);
+        if let Some(dir) = tmpdir.filter(|dir| same_device(dir, parent).unwrap_or(false)) {
+            return staged(&dir);
+        }
+        target.with_file_name(format!(".{name}.apply_patch.{pid}.{suffix}"))
+    }
+}
+
+/// Whether directories `a` and `b` are on the same filesystem. Without
+/// Unix device numbers to compare, never.
+#[cfg(unix)]
+fn same_device(a: &Path, b: &Path) -> std::io::Result<bool> {
+    use std::os::unix::fs::MetadataExt;
+    let a = std::fs::metadata(a)?;
+    if !a.is_dir() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::NotADirectory,
+            "not a directory",
+        ));
+    }
+    Ok(a.dev() == std::fs::metadata(b)?.dev())
+}
+
+#[cfg(not(unix))]
+fn same_device(_a: &Path, _b: &Path) -> std::io::Result<bool> {
+    Ok(false)
+}
+
+#[cfg(unix)]
+pub(crate) fn hard_links(meta: &std::fs::Metadata) -> u64 {
+    use std::os::unix::fs::MetadataExt;
+    meta.nlink()
+}
+
+#[cfg(not(unix))]
+pub(crate) fn hard_links(_meta: &std::fs::Metadata) -> u64 {
+    1
+}
@@ -0,0 +1,324 @@
+//! The subset of YAML that hand-written manifests use, read into a
+//! `serde_json::Value` so the same `Deserialize` types serve both formats.
+//!
+//! Supported: block mappings and sequences (`- key: value` items included),
+//! plain, `'single'` and `"double"` quoted scalars, `[a, b]` flow lists,
+//! `|` literal blocks with `-`/`+` chomping, `#` comments and a leading
+//! `---`. Anchors, tags, `>` folded blocks, flow mappings and multiple
+//! documents are rejected rather than misread.
+
+use serde_json::Map;
+use serde_json::Value;
+
+struct Parser {
+    lines: Vec<String>,
+    pos: usize,
+}
+
+fn indent(line: &str) -> usize {
+    line.len() - line.trim_start_matches(' ').len()
+}
+
+fn is_blank(line: &str) -> bool {
+    let trimmed = line.trim();
+    trimmed.is_empty() || trimmed.starts_with('#')
+}
+
+/// Parses `text` as one YAML document.
+pub(crate) fn parse(text: &str) -> Result<Value, String> {
+    let mut lines: Vec<String> = text.lines().map(str::to_string).collect();
+    if let Some(n) = lines
+        .iter()
+        .position(|line| !line.trim().is_empty() && line[indent(line)..].starts_with('\t'))
+    {
+        return Err(format!("line {}: tabs can't indent YAML", n + 1));
+    }
+    if let Some(first) = lines.iter().position(|line| !is_blank(line))
+        && lines[first].trim_end() == "---"
+    {
+        lines[first].clear();
+    }
+    let mut parser = Parser { lines, pos: 0 };
+    let value = parser.node(0)?;
+    parser.skip_blank();
+    if parser.pos < parser.lines.len() {
+        return Err(parser.error("unexpected indentation"));
+    }
+    Ok(value)
+}
+
+impl Parser {
+    fn error(&self, msg: &str) -> String {
+        format!("line {}: {msg}", self.pos + 1)
+    }
+
+    fn skip_blank(&mut self) {
+        while self.pos < self.lines.len() && is_blank(&self.lines[self.pos]) {
+            self.pos += 1;
+        }
+    }
+
+    /// The node starting at the next line indented at least `min`; null if
+    /// there is none.
+    fn node(&mut self, min: usize) -> Result<Value, String> {
+        self.skip_blank();
+        let Some(line) = self.lines.get(self.pos) else {
+            return Ok(Value::Null);
+        };
+        let at = indent(line);
+        if at < min {
+            return Ok(Value::Null);
+        }
+        let content = &line[at..];
+        if content == "-" || content.starts_with("- ") {
+            self.sequence(at)
+        } else if split_key(content).is_some() {
+            self.mapping(at)
+        } else {
+            let value = scalar(content).map_err(|msg| self.error(&msg))?;
+            self.pos += 1;
+            Ok(value)
+        }
+    }
+
+    fn sequence(&mut self, at: usize) -> Result<Value, String> {
+        let mut items = Vec::new();
+        loop {
+            self.skip_blank();
+            let Some(line) = self.lines.get(self.pos) else {
+                break;
+            };
+            let content = &line[indent(line)..];
+            if indent(line) != at || !(content == "-" || content.starts_with("- ")) {
+                break;
+            }
+            let rest = content[1..].trim_start();
+            if rest.is_empty() {
+                self.pos += 1;
+                items.push(self.node(at + 1)?);
+            } else {
+                // Read the item as if it started on its own line, at the
+                // column where it starts.
+                let column = line.len() - rest.len();
+                self.lines[self.pos] = format!("{}{rest}", " ".repeat(column));
+                items.push(self.node(column)?);
+            }
+        }
+        Ok(Value::Array(items))
+    }
+
+    fn mapping(&mut self, at: usize) -> Result<Value, String> {
+        let mut map = Map::new();
+        loop {
+            self.skip_blank();
+            let Some(line) = self.lines.get(self.pos) else {
+                break;
+            };
+            if indent(line) < at {
+                break;
+            }
+            if indent(line) > at {
+                return Err(self.error("unexpected indentation"));
+            }
+            let content = &line[at..];
+            let Some((key, rest)) = split_key(content) else {
+                if content == "-" || content.starts_with("- ") {
+                    break;
+                }
+                return Err(self.error("expected `key: value`"));
+            };
+            let key = key.map_err(|msg| self.error(&msg))?;
+            let rest = rest.to_string();
+            if map.contains_key(&key) {
+                return Err(self.error(&format!("duplicate key `{key}`")));
+            }
+            self.pos += 1;
+            let value = if strip_comment(&rest).is_empty() {
+                // A sequence may sit at the key's own indentation.
+                self.skip_blank();
+                let nested_sequence = self.lines.get(self.pos).is_some_and(|line| {
+                    indent(line) == at && {
+                        let content = &line[at..];
+                        content == "-" || content.starts_with("- ")
+                    }
+                });
+                if nested_sequence {
+                    self.sequence(at)?
+                } else {
+                    self.node(at + 1)?
+                }
+            } else if let Some(chomp) = rest.strip_prefix('|') {
+                self.block(at, strip_comment(chomp))?
+            } else {
+                scalar(&rest).map_err(|msg| format!("line {}: {msg}", self.pos))?
+            };
+            map.insert(key, value);
+        }
+        Ok(Value::Object(map))
+    }
+
+    /// A `|` literal block under a key indented `at`.
+    fn block(&mut self, at: usize, chomp: &str) -> Result<Value, String> {
+        if !matches!(chomp, "" | "-" | "+") {
+            return Err(format!(
+                "line {}: unsupported block scalar header `|{chomp}`",
+                self.pos
+            ));
+        }
+        let mut body: Vec<&str> = Vec::new();
+        let mut block_indent: Option<usize> = None;
+        while let Some(line) = self.lines.get(self.pos) {
+            if line.trim().is_empty() {
+                body.push("");
+                self.pos += 1;
+                continue;
+            }
+            let this = indent(line);
+            let Some(block) = block_indent.or((this > at).then_some(this)) else {
+                break;
+            };
+            if this < block {
+                break;
+            }
+            block_indent = Some(block);
+            body.push(&line[block..]);
+            self.pos += 1;
+        }
+        let mut text = body.join("\n");
+        match chomp {
+            "-" => text.truncate(text.trim_end_matches('\n').len()),
+            "+" => text.push('\n'),
+            _ => {
+                text.truncate(text.trim_end_matches('\n').len());
+                if !text.is_empty() {
+                    text.push('\n');
+                }
+            }
+        }
+        Ok(Value::String(text))
+    }
+}
+
+/// Splits `key: rest` (or `key:`) into the key and the rest. `None` if the
+/// line isn't a mapping entry.
+fn split_key(content: &str) -> Option<(Result<String, String>, &str)> {
+    if content.starts_with('"') || content.starts_with('\'') {
+        let end = quoted_end(content)?;
+        let rest = content[end..].strip_prefix(':')?;
+        if !(rest.is_empty() || rest.starts_with(' ')) {
+            return None;
+        }
+        let key = match scalar(&content[..end]) {
+            Ok(Value::String(key)) => Ok(key),
+            Ok(_) => Err("keys must be strings".to_string()),
+            Err(err) => Err(err),
+        };
+        return Some((key, rest.trim_start()));
+    }
+    if content.starts_with(['[', '{', '#']) {
+        return None;
+    }
+    let colon = content
+        .match_indices(':')
+        .map(|(i, _)| i)
+        .find(|&i| content[i + 1..].is_empty() || content[i + 1..].starts_with(' '))?;
+    if content[..colon].contains(" #") {
+        return None;
+    }
+    Some((
+        Ok(content[..colon].trim_end().to_string()),
+        content[colon + 1..].trim_start(),
+    ))
+}
+
+/// The byte just past the closing quote of the scalar `text` starts with.
+fn quoted_end(text: &str) -> Option<usize> {
+    let quote = text.chars().next()?;
+    let mut chars = text.char_indices().skip(1);
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '\\' if quote == '"' => {
+                chars.next();
+            }
+            '\'' if quote == '\'' && text[i + 1..].starts_with('\'') => {
+                chars.next();
+            }
+            c if c == quote => return Some(i + 1),
+            _ => {}
+        }
+    }
+    None
+}
+
+/// `text` without a trailing ` # comment`, trimmed.
+fn strip_comment(text: &str) -> &str {
+    let text = text.trim();
+    if text.starts_with('#') {
+        return "";
+    }
+    match text.find(" #") {
+        Some(i) => text[..i].trim_end(),
+        None => text,
+    }
+}
+
+fn scalar(text: &str) -> Result<Value, String> {
+    let text = text.trim();
+    if text.starts_with('"') || text.starts_with('\'') {
+        let end = quoted_end(text).ok_or("unterminated quoted string")?;
+        if !strip_comment(&text[end..]).is_empty() {
+            return Err("unexpected text after a quoted string".to_string());
+        }
+        let inner = &text[..end];
+        return if inner.starts_with('"') {
+            serde_json::from_str::<String>(inner)
+                .map(Value::String)
+                .map_err(|err| format!("invalid double-quoted string: {err}"))
+        } else {
+            Ok(Value::String(inner[1..inner.len() - 1].replace("''", "'")))
+        };
+    }
+    let text = strip_comment(text);
+    if let Some(inner) = text.strip_prefix('[') {
+        let inner = inner
+            .strip_suffix(']')
+            .ok_or("flow lists must end on the line they start")?;
+        return flow_items(inner)?
+            .into_iter()
+            .map(scalar)
+            .collect::<Result<Vec<_>, _>>()
+            .map(Value::Array);
+    }
+    if text.starts_with(['{', '&', '*', '!', '>', '|', '%', '@', '`']) {
+        return Err(format!("unsupported YAML: `{text}`"));
+    }
+    Ok(match text {
+        "" | "~" | "null" | "Null" | "NULL" => Value::Null,
+        "true" | "True" | "TRUE" => Value::Bool(true),
+        "false" | "False" | "FALSE" => Value::Bool(false),
+        _ => match text.parse::<i64>() {
+            Ok(n) => Value::from(n),
+            Err(_) => Value::String(text.to_string()),
+        },
+    })
+}
+
+/// The comma-separated items of a flow list, quotes respected.
+fn flow_items(inner: &str) -> Result<Vec<&str>, String> {
+    let mut items = Vec::new();
+    let mut rest = inner.trim();
+    while !rest.is_empty() {
+        let end = if rest.starts_with('"') || rest.starts_with('\'') {
+            let end = quoted_end(rest).ok_or("unterminated quoted string")?;
+            end + rest[end..].find(',').unwrap_or(rest.len() - end)
+        } else {
+            if rest.starts_with('[') {
+                return Err("nested flow lists are not supported".to_string());
+            }
+            rest.find(',').unwrap_or(rest.len())
+        };
+        items.push(rest[..end].trim());
+        rest = rest[end..].strip_prefix(',').unwrap_or("").trim_start();
+    }
+    Ok(items)
+}
@@ -0,0 +1,84 @@
+// Calls the C ABI in this process. It has its own test binary, without the
+// libtest harness, because a run captures descriptors 1 and 2 and enters
+// `cwd`, which would disturb tests running alongside it, and because the
+// harness would take the output of `println!` before the capture saw it.
+
+use std::ffi::{CStr, CString, c_char};
+use std::path::PathBuf;
+
+fn temp_dir() -> PathBuf {
+    let path = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+        .join("target")
+        .join("tmp_tests")
+        .join(format!("apply_patch_ffi_{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&path);
+    std::fs::create_dir_all(&path).expect("create temp dir");
+    path
+}
+
+fn call(patch: Option<&str>, options: Option<&str>) -> (i32, serde_json::Value) {
+    let patch = patch.map(|p| CString::new(p).unwrap());
+    let options = options.map(|o| CString::new(o).unwrap());
+    let mut out: *mut c_char = std::ptr::null_mut();
+    let code = unsafe {
+        patch_22::apply_patch_run(
+            patch.as_ref().map_or(std::ptr::null(), |p| p.as_ptr()),
+            options.as_ref().map_or(std::ptr::null(), |o| o.as_ptr()),
+            &mut out,
+        )
+    };
+    assert!(!out.is_null());
+    let json = unsafe { CStr::from_ptr(out) }.to_str().unwrap().to_string();
+    unsafe { patch_22::apply_patch_free(out) };
+    (code, serde_json::from_str(&json).unwrap())
+}
+
+fn main() {
+    c_abi_runs_patches_in_process();
+    println!("test c_abi_runs_patches_in_process ... ok");
+}
+
+fn c_abi_runs_patches_in_process() {
+    let dir = temp_dir();
+    let work = dir.join("work");
+    std::fs::create_dir_all(&work).unwrap();
+    let cfg = dir.join("config.json");
+    std::fs::write(&cfg, r#"{"mode": "warn"}"#).unwrap();
+    let options = |extra: &str| {
+        format!(r#"{{"cwd": {:?}, "config": {:?}{extra}}}"#, work.display().to_string(), cfg.display().to_string())
+    };
+    let home = std::env::current_dir().unwrap();
+    let patch = "*** Begin Patch\n*** Add File: hi.txt\n+hi\n*** End Patch\n";
+
+    let (code, answer) = call(Some(patch), Some(&options(r#", "dry_run": true"#)));
+    assert_eq!(code, 0, "{answer}");
+    assert!(!work.join("hi.txt").exists());
+
+    let (code, answer) = call(Some(patch), Some(&options("")));
+    assert_eq!(code, 0, "{answer}");
+    assert_eq!(answer["exit_code"], 0);
+    assert!(answer["stdout"].as_str().unwrap().starts_with("Success. Updated the following files:\nA hi.txt\nNOTE TO LLM:"), "{answer}");
+    assert_eq!(std::fs::read_to_string(work.join("hi.txt")).unwrap(), "hi\n");
+    assert_eq!(std::env::current_dir().unwrap(), home);
+
+    std::fs::write(&cfg, r#"{"mode": "refuse"}"#).unwrap();
+    let refused = "*** Begin Patch\n*** Add File: no.txt\n+no\n*** End Patch\n";
+    let (code, answer) = call(Some(refused), Some(&options("")));
+    assert_eq!(code, 0);
+    assert!(answer["stdout"].as_str().unwrap().contains("nothing was changed"), "{answer}");
+    assert!(!work.join("no.txt").exists());
+
+    let (code, answer) = call(Some(patch), Some(r#"{"nope": 1}"#));
+    assert_eq!(code, 2);
+    assert!(answer["stderr"].as_str().unwrap().starts_with("Error: invalid options: unknown field `nope`"), "{answer}");
+    let (code, answer) = call(None, None);
+    assert_eq!(code, 2);
+    assert_eq!(answer["stderr"], "Error: the patch is null.\n");
+
+    // The answer is optional.
+    let again = CString::new(patch).unwrap();
+    let opts = CString::new(options("")).unwrap();
+    let code = unsafe { patch_22::apply_patch_run(again.as_ptr(), opts.as_ptr(), std::ptr::null_mut()) };
+    assert_eq!(code, 0);
+    let _ = std::fs::remove_dir_all(&dir);
+}
@@ -67,9 +67,10 @@ fn run_with_stdin(mut cmd: Command, stdin: &str) -> (i32, String, String) {
     {
         use std::io::Write;
         let mut handle = child.stdin.take().expect("missing stdin handle");
-        handle
-            .write_all(stdin.as_bytes())
-            .expect("failed to write stdin");
+        // A command that fails before reading its input closes the pipe.
+        if let Err(err) = handle.write_all(stdin.as_bytes()) {
+            assert_eq!(err.kind(), std::io::ErrorKind::BrokenPipe, "failed to write stdin: {err}");
+        }
     }
     let output = child.wait_with_output().expect("failed to read output");
     let code = output.status.code().unwrap_or(1);
@@ -468,6 +469,3444 @@ fn assert_config_flags_cannot_mix_with_patch_arg(program: &Path, cfg_path: &Path
     );
 }
 
+fn assert_feedback_file_records_decisions(program: &Path, cfg_path: &Path) {
+    let work = TempDir::new();
+    let feedback = work.path().join("feedback").join("decisions.jsonl");
+
+    let (code, _stdout, stderr) = run({
+        let mut cmd = Command::new(program);
+        cmd.arg("--refuse").env("APPLY_PATCH_CONFIG", cfg_path);
+        cmd
+    });
+    assert_eq!(code, 0, "stderr:\n{stderr}");
+
+    let patch = add_file_patch("fb.txt", &["fb"]);
+    let (code, _stdout, stderr) = run_with_stdin({
+        let mut cmd = Command::new(program);
+        cmd.current_dir(work.path())
+            .env("APPLY_PATCH_CONFIG", cfg_path)
+            .env("APPLY_PATCH_FEEDBACK_FILE", &feedback);
+        cmd
+    }, &patch);
+    assert_eq!(code, 0, "stderr:\n{stderr}");
+    assert!(stderr.is_empty(), "stderr:\n{stderr}");
+
+    let (code, _stdout, stderr) = run({
+        let mut cmd = Command::new(program);
+        cmd.arg("--warn").env("APPLY_PATCH_CONFIG", cfg_path);
+        cmd
+    });
+    assert_eq!(code, 0, "stderr:\n{stderr}");
+
+    let (code, _stdout, stderr) = run_with_stdin({
+        let mut cmd = Command::new(program);
+        cmd.current_dir(work.path())
+            .env("APPLY_PATCH_CONFIG", cfg_path)
+            .env("APPLY_PATCH_FEEDBACK_FILE", &feedback);
+        cmd
+    }, &patch);
+    assert_eq!(code, 0, "stderr:\n{stderr}");
+
+    let contents = std::fs::read_to_string(&feedback).unwrap();
+    let records = read_jsonl(&feedback);
+    assert_eq!(records.len(), 2, "feedback:\n{contents}");
+    assert_eq!(records[0]["mode"], "refuse");
+    assert_eq!(records[0]["decision"], "refused");
+    assert!(
+        records[0]["message"]
+            .as_str()
+            .unwrap()
+            .contains("nothing was changed"),
+        "feedback:\n{contents}"
+    );
+    assert_eq!(records[1]["mode"], "warn");
+    assert_eq!(records[1]["decision"], "applied");
+    assert!(
+        records[1]["message"].as_str().unwrap().contains("NOTE TO LLM:"),
+        "feedback:\n{contents}"
+    );
+}
+
+fn assert_delete_content_verification(program: &Path, cfg_path: &Path) {
+    let work = TempDir::new();
+    apply_mode_config(program, cfg_path);
+    let run_patch = |patch: &str, extra: &[&str]| {
+        run_with_stdin({
+            let mut cmd = Command::new(program);
+            cmd.current_dir(work.path())
+                .env("APPLY_PATCH_CONFIG", cfg_path)
+                .args(extra);
+            cmd
+        }, patch)
+    };
+    let target = work.path().join("victim.txt");
+
+    // Stale expected content: refuse and leave the file alone.
+    std::fs::write(&target, "keep me\n").unwrap();
+    let stale = "*** Begin Patch\n*** Delete File: victim.txt\n-something else\n*** End Patch\n";
+    let (code, _stdout, stderr) = run_patch(stale, &[]);
+    assert_eq!(code, 1, "stderr:\n{stderr}");
+    assert!(
+        stderr.contains("refusing to delete victim.txt"),
+        "stderr:\n{stderr}"
+    );
+    assert!(target.exists());
+
+    // --force-delete bypasses the comparison.
+    let (code, stdout, stderr) = run_patch(stale, &["--force-delete"]);
+    assert_eq!(code, 0, "stderr:\n{stderr}");
+    assert!(stdout.contains("D victim.txt"), "stdout:\n{stdout}");
+    assert!(!target.exists());
+
+    // Matching content lines.
+    std::fs::write(&target, "keep me\n").unwrap();
+    let matching = "*** Begin Patch\n*** Delete File: victim.txt\n-keep me\n*** End Patch\n";
+    let (code, stdout, stderr) = run_patch(matching, &[]);
+    assert_eq!(code, 0, "stderr:\n{stderr}");
+    assert!(stdout.contains("D victim.txt"), "stdout:\n{stdout}");
+    assert!(!target.exists());
+
+    // Matching and mismatching digests.
+    std::fs::write(&target, "keep me\n").unwrap();
+    let wrong_hash = format!(
+        "*** Begin Patch\n*** Delete File: victim.txt\n*** Expected SHA256: {}\n*** End Patch\n",
+        "0".repeat(64)
+    );
+    let (code, _stdout, stderr) = run_patch(&wrong_hash, &[]);
+    assert_eq!(code, 1, "stderr:\n{stderr}");
+    assert!(target.exists());
+
+    let right_hash = "*** Begin Patch\n*** Delete File: victim.txt\n*** Expected SHA256: 2b8425c4d20e743705f4787b4dda39344b4242bc8636228a00b7d65378aa7694\n*** End Patch\n";
+    let (code, stdout, stderr) = run_patch(right_hash, &[]);
+    assert_eq!(code, 0, "stderr:\n{stderr}");
+    assert!(stdout.contains("D victim.txt"), "stdout:\n{stdout}");
+    assert!(!target.exists());
+}
+
+fn write_config(cfg_path: &Path, config: serde_json::Value) {
+    std::fs::write(cfg_path, serde_json::to_vec_pretty(&config).unwrap()).unwrap();
+}
+
+fn assert_policy_rules_select_mode(program: &Path, cfg_path: &Path) {
+    let work = TempDir::new();
+    write_config(
+        cfg_path,
+        serde_json::json!({
+            "mode": "apply",
+            "policies": [
+                {"when": "files ~ 'secret/**' || (agent == 'codex' && lines_added > 2)", "mode": "refuse"},
+                {"when": "files ~ '*.md'", "mode": "warn"},
+            ],
+        }),
+    );
+    let run_patch = |patch: &str, agent: &str| {
+        run_with_stdin({
+            let mut cmd = Command::new(program);
+            cmd.current_dir(work.path())
+                .env("APPLY_PATCH_CONFIG", cfg_path)
+                .env("APPLY_PATCH_AGENT", agent);
+            cmd
+        }, patch)
+    };
+
+    let (code, stdout, stderr) = run_patch(&add_file_patch("secret/key.txt", &["k"]), "");
+    assert_eq!(code, 0, "stderr:\n{stderr}");
+    assert!(stdout.contains("nothing was changed"), "stdout:\n{stdout}");
+    assert!(!work.path().join("secret/key.txt").exists());
+
+    let big = add_file_patch("big.txt", &["1", "2", "3"]);
+    let (code, stdout, _stderr) = run_patch(&big, "codex");
+    assert_eq!(code, 0);
+    assert!(stdout.contains("nothing was changed"), "stdout:\n{stdout}");
+    let (code, stdout, stderr) = run_patch(&big, "claude");
+    assert_eq!(code, 0, "stderr:\n{stderr}");
+    assert!(stdout.contains("A big.txt"), "stdout:\n{stdout}");
+    assert!(!stdout.contains("NOTE TO LLM:"), "stdout:\n{stdout}");
+
+    let (code, stdout, stderr) = run_patch(&add_file_patch("docs/notes.md", &["n"]), "");
+    assert_eq!(code, 0, "stderr:\n{stderr}");
+    assert!(stdout.contains("A docs/notes.md"), "stdout:\n{stdout}");
+    assert!(stdout.contains("NOTE TO LLM:"), "stdout:\n{stdout}");
+
+    write_config(
+        cfg_path,
+        serde_json::json!({"policies": [{"when": "lines_added >", "mode": "refuse"}]}),
+    );
+    let (code, _stdout, stderr) = run_patch(&add_file_patch("x.txt", &["x"]), "");
+    assert_eq!(code, 1);
+    assert!(
+        stderr.contains("invalid policy expression `lines_added >`"),
+        "stderr:\n{stderr}"
+    );
+    assert!(!work.path().join("x.txt").exists());
+}
+
+fn read_jsonl(path: &Path) -> Vec<serde_json::Value> {
+    std::fs::read_to_string(path)
+        .unwrap()
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect()
+}
+
+fn assert_large_patches_escalate(program: &Path, cfg_path: &Path) {
+    let work = TempDir::new();
+    let audit_log = work.path().join("audit.jsonl");
+    write_config(
+        cfg_path,
+        serde_json::json!({
+            "mode": "apply",
+            "escalate": {"large_patch_lines": 3, "to": "refuse"},
+            "audit_log": audit_log,
+        }),
+    );
+    let run_patch = |patch: &str| {
+        run_with_stdin({
+            let mut cmd = Command::new(program);
+            cmd.current_dir(work.path()).env("APPLY_PATCH_CONFIG", cfg_path);
+            cmd
+        }, patch)
+    };
+
+    let (code, stdout, stderr) = run_patch(&add_file_patch("small.txt", &["1", "2"]));
+    assert_eq!(code, 0, "stderr:\n{stderr}");
+    assert!(stdout.contains("A small.txt"), "stdout:\n{stdout}");
+    assert!(!stdout.contains("Escalated"), "stdout:\n{stdout}");
+
+    let (code, stdout, stderr) = run_patch(&add_file_patch("large.txt", &["1", "2", "3"]));
+    assert_eq!(code, 0, "stderr:\n{stderr}");
+    assert!(
+        stdout.contains("Escalated to refuse mode: this patch changes 3 lines (threshold: 3)."),
+        "stdout:\n{stdout}"
+    );
+    assert!(stdout.contains("nothing was changed"), "stdout:\n{stdout}");
+    assert!(!work.path().join("large.txt").exists());
+
+    let entries = read_jsonl(&audit_log);
+    assert_eq!(entries.len(), 2);
+    assert_eq!(entries[0]["decision"], "applied");
+    assert_eq!(entries[0]["files"], serde_json::json!(["small.txt"]));
+    assert!(entries[0].get("escalation").is_none());
+    assert_eq!(entries[1]["decision"], "refused");
+    assert_eq!(entries[1]["base_mode"], "apply");
+    assert_eq!(entries[1]["mode"], "refuse");
+    assert_eq!(entries[1]["escalation"]["lines_changed"], 3);
+    assert_eq!(entries[1]["escalation"]["to"], "refuse");
+}
+
+fn assert_double_dash_separator(program: &Path, cfg_path: &Path) {
+    let work = TempDir::new();
+    apply_mode_config(program, cfg_path);
+
+    let patch = add_file_patch("dashed.txt", &["after-separator"]);
+    let (code, stdout, stderr) = run({
+        let mut cmd = Command::new(program);
+        cmd.current_dir(work.path())
+            .env("APPLY_PATCH_CONFIG", cfg_path)
+            .arg("--")
+            .arg(patch);
+        cmd
+    });
+    assert_eq!(code, 0, "stdout:\n{stdout}\nstderr:\n{stderr}");
+    assert!(stdout.contains("A dashed.txt"), "stdout:\n{stdout}");
+
+    // A flag-looking PATCH after `--` is handed to the applier, not parsed as
+    // an option (and does not change the config).
+    let (code, _stdout, stderr) = run({
+        let mut cmd = Command::new(program);
+        cmd.current_dir(work.path())
+            .env("APPLY_PATCH_CONFIG", cfg_path)
+            .arg("--")
+            .arg("--refuse");
+        cmd
+    });
+    assert_eq!(code, 1, "stderr:\n{stderr}");
+    assert!(!stderr.contains("unknown option"), "stderr:\n{stderr}");
+    let (code, stdout, _stderr) = run({
+        let mut cmd = Command::new(program);
+        cmd.arg("--show-config").env("APPLY_PATCH_CONFIG", cfg_path);
+        cmd
+    });
+    assert_eq!(code, 0);
+    assert!(stdout.contains("mode: apply"), "stdout:\n{stdout}");
+}
+
+fn assert_generate_docs(program: &Path) {
+    let out = TempDir::new();
+    for format in ["man", "markdown"] {
+        let (code, stdout, stderr) = run({
+            let mut cmd = Command::new(program);
+            cmd.arg("generate-docs").arg(format).arg("--out").arg(out.path());
+            cmd
+        });
+        assert_eq!(code, 0, "stderr:\n{stderr}");
+        assert!(stdout.starts_with("Wrote "), "stdout:\n{stdout}");
+    }
+
+    let man = std::fs::read_to_string(out.path().join("apply_patch.1")).unwrap();
+    assert!(man.starts_with(".TH APPLY_PATCH 1"), "man:\n{man}");
+    assert!(man.contains("\\-\\-force\\-delete"), "man:\n{man}");
+    assert!(man.contains(".SH EXIT STATUS"), "man:\n{man}");
+
+    let markdown = std::fs::read_to_string(out.path().join("apply_patch.md")).unwrap();
+    for needle in ["`--show-config`", "`--set-warn-message <text>`", "`escalate.to`", "`APPLY_PATCH_CONFIG`"] {
+        assert!(markdown.contains(needle), "missing {needle} in:\n{markdown}");
+    }
+
+    // Without --out the document goes to stdout.
+    let (code, stdout, _stderr) = run({
+        let mut cmd = Command::new(program);
+        cmd.arg("generate-docs").arg("markdown");
+        cmd
+    });
+    assert_eq!(code, 0);
+    assert_eq!(stdout, markdown);
+
+    let (code, _stdout, stderr) = run({
+        let mut cmd = Command::new(program);
+        cmd.arg("generate-docs");
+        cmd
+    });
+    assert_eq!(code, 2);
+    assert_eq!(
+        stderr,
+        "Error: generate-docs requires a format: man or markdown.\n"
+    );
+}
+
+fn assert_preview_snapshots(program: &Path) {
+    let work = TempDir::new();
+    std::fs::write(work.path().join("edit.txt"), "one\ntwo\n").unwrap();
+    std::fs::write(work.path().join("gone.txt"), "bye\n").unwrap();
+    let out = TempDir::new();
+
+    let patch = "*** Begin Patch\n*** Update File: edit.txt\n@@\n-two\n+TWO\n*** Add File: new/added.txt\n+fresh\n*** Delete File: gone.txt\n*** End Patch\n";
+    let (code, stdout, stderr) = run({
+        let mut cmd = Command::new(program);
+        cmd.current_dir(work.path())
+            .arg("preview")
+            .arg("--out")
+            .arg(out.path())
+            .arg(patch);
+        cmd
+    });
+    assert_eq!(code, 0, "stderr:\n{stderr}");
+    assert_eq!(
+        stdout,
+        format!(
+            "Preview written to {}:\nM edit.txt\nA new/added.txt\nD gone.txt\n",
+            out.path().display()
+        )
+    );
+
+    let read = |rel: &str| std::fs::read_to_string(out.path().join(rel)).ok();
+    assert_eq!(read("before/edit.txt").as_deref(), Some("one\ntwo\n"));
+    assert_eq!(read("after/edit.txt").as_deref(), Some("one\nTWO\n"));
+    assert_eq!(read("before/new/added.txt"), None);
+    assert_eq!(read("after/new/added.txt").as_deref(), Some("fresh\n"));
+    assert_eq!(read("before/gone.txt").as_deref(), Some("bye\n"));
+    assert_eq!(read("after/gone.txt"), None);
+
+    // The working tree is untouched.
+    assert_eq!(
+        std::fs::read_to_string(work.path().join("edit.txt")).unwrap(),
+        "one\ntwo\n"
+    );
+    assert!(work.path().join("gone.txt").exists());
+    assert!(!work.path().join("new").exists());
+
+    let (code, _stdout, stderr) = run({
+        let mut cmd = Command::new(program);
+        cmd.current_dir(work.path()).arg("preview").arg(patch);
+        cmd
+    });
+    assert_eq!(code, 2);
+    assert_eq!(stderr, "Error: preview requires --out DIR.\n");
+}
+
+fn assert_apply_message_and_templates(program: &Path, cfg_path: &Path) {
+    let work = TempDir::new();
+    let configure = |args: &[&str]| {
+        let (code, _stdout, stderr) = run({
+            let mut cmd = Command::new(program);
+            cmd.args(args).env("APPLY_PATCH_CONFIG", cfg_path);
+            cmd
+        });
+        assert_eq!(code, 0, "stderr:\n{stderr}");
+    };
+    let run_patch = |patch: &str| {
+        run_with_stdin({
+            let mut cmd = Command::new(program);
+            cmd.current_dir(work.path()).env("APPLY_PATCH_CONFIG", cfg_path);
+            cmd
+        }, patch)
+    };
+
+    configure(&["--apply", "--set-apply-message", "Changed {file_count} file(s) in {mode} mode: {files}. {unknown}"]);
+    let (code, stdout, stderr) = run_patch(&add_file_patch("a.txt", &["a", "b"]));
+    assert_eq!(code, 0, "stderr:\n{stderr}");
+    assert!(stdout.contains("A a.txt"), "stdout:\n{stdout}");
+    assert!(
+        stdout.ends_with("Changed 1 file(s) in apply mode: a.txt. {unknown}\n"),
+        "stdout:\n{stdout}"
+    );
+
+    let (code, stdout, _stderr) = run({
+        let mut cmd = Command::new(program);
+        cmd.arg("--show-config").env("APPLY_PATCH_CONFIG", cfg_path);
+        cmd
+    });
+    assert_eq!(code, 0);
+    assert!(stdout.contains("apply_message: custom"), "stdout:\n{stdout}");
+
+    // The warn banner replaces the apply message and shares its variables.
+    configure(&["--warn", "--set-warn-message", "WARN +{lines_added}"]);
+    let (code, stdout, _stderr) = run_patch(&add_file_patch("b.txt", &["1", "2", "3"]));
+    assert_eq!(code, 0);
+    assert!(stdout.ends_with("WARN +3\n"), "stdout:\n{stdout}");
+    assert!(!stdout.contains("Changed"), "stdout:\n{stdout}");
+
+    configure(&["--refuse", "--set-refuse-message", "REFUSED {files}"]);
+    let (code, stdout, _stderr) = run_patch(&add_file_patch("c.txt", &["c"]));
+    assert_eq!(code, 0);
+    assert_eq!(stdout, "REFUSED c.txt\n");
+
+    configure(&["--apply", "--clear-apply-message", "--clear-warn-message", "--clear-refuse-message"]);
+    let (code, stdout, _stderr) = run_patch(&add_file_patch("d.txt", &["d"]));
+    assert_eq!(code, 0);
+    assert!(!stdout.contains("Changed"), "stdout:\n{stdout}");
+}
+
+fn assert_partial_applies_independent_sections(program: &Path, cfg_path: &Path) {
+    let work = TempDir::new();
+    apply_mode_config(program, cfg_path);
+    std::fs::write(work.path().join("edit.txt"), "one\n").unwrap();
+    let residual = work.path().join("residual.patch");
+    let run_partial = |patch: &str| {
+        run({
+            let mut cmd = Command::new(program);
+            cmd.current_dir(work.path())
+                .env("APPLY_PATCH_CONFIG", cfg_path)
+                .arg("--partial")
+                .arg("--residual")
+                .arg(&residual)
+                .arg(patch);
+            cmd
+        })
+    };
+
+    let patch = "*** Begin Patch\n*** Add File: new.txt\n+new\n*** Update File: stale.txt\n@@\n-old\n+new\n*** Update File: edit.txt\n@@\n-one\n+ONE\n*** End Patch\n";
+    let (code, stdout, stderr) = run_partial(patch);
+    assert_eq!(code, 3, "stdout:\n{stdout}\nstderr:\n{stderr}");
+    assert!(
+        stdout.starts_with("Applied 2 of 3 file sections.\nUpdated the following files:\nA new.txt\nM edit.txt\nFailed to apply:\nM stale.txt: "),
+        "stdout:\n{stdout}"
+    );
+    assert!(stdout.contains("Residual patch written to"), "stdout:\n{stdout}");
+    assert_eq!(std::fs::read_to_string(work.path().join("new.txt")).unwrap(), "new\n");
+    assert_eq!(std::fs::read_to_string(work.path().join("edit.txt")).unwrap(), "ONE\n");
+    assert_eq!(
+        std::fs::read_to_string(&residual).unwrap(),
+        "*** Begin Patch\n*** Update File: stale.txt\n@@\n-old\n+new\n*** End Patch\n"
+    );
+
+    let (code, stdout, _stderr) = run_partial(&update_file_patch("edit.txt", "ONE", "one"));
+    assert_eq!(code, 0);
+    assert_eq!(stdout, "Success. Updated the following files:\nM edit.txt\n");
+
+    let (code, stdout, _stderr) = run_partial(&update_file_patch("missing.txt", "a", "b"));
+    assert_eq!(code, 1);
+    assert!(stdout.starts_with("Applied 0 of 1 file sections.\nFailed to apply:\n"), "stdout:\n{stdout}");
+}
+
+fn wait_for(path: &Path) -> bool {
+    for _ in 0..100 {
+        if path.exists() {
+            return true;
+        }
+        std::thread::sleep(std::time::Duration::from_millis(100));
+    }
+    false
+}
+
+fn assert_watch_applies_dropped_patches(program: &Path, cfg_path: &Path) {
+    let work = TempDir::new();
+    let inbox = work.path().join("inbox");
+    std::fs::create_dir_all(&inbox).unwrap();
+    apply_mode_config(program, cfg_path);
+
+    let mut child = Command::new(program)
+        .current_dir(work.path())
+        .env("APPLY_PATCH_CONFIG", cfg_path)
+        .arg("--watch")
+        .arg(&inbox)
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .expect("failed to spawn watcher");
+
+    std::fs::write(inbox.join("good.patch"), add_file_patch("watched.txt", &["hi"])).unwrap();
+    std::fs::write(inbox.join("bad.patch"), update_file_patch("missing.txt", "a", "b")).unwrap();
+    std::fs::write(inbox.join("ignored.txt"), "not a patch").unwrap();
+    let applied = wait_for(&inbox.join("applied/good.patch"));
+    let failed = wait_for(&inbox.join("failed/bad.patch"));
+
+    // The cached config is reloaded once the file changes.
+    write_config(cfg_path, serde_json::json!({"mode": "refuse"}));
+    std::fs::write(inbox.join("later.patch"), add_file_patch("later.txt", &["x"])).unwrap();
+    let refused = wait_for(&inbox.join("failed/later.patch"));
+    let _ = child.kill();
+    let _ = child.wait();
+
+    assert!(applied, "good.patch was not moved to applied/");
+    assert!(failed, "bad.patch was not moved to failed/");
+    assert!(refused, "later.patch was not refused after the config changed");
+    assert!(!work.path().join("later.txt").exists());
+    assert_eq!(std::fs::read_to_string(work.path().join("watched.txt")).unwrap(), "hi\n");
+    assert!(!inbox.join("good.patch").exists());
+    assert!(inbox.join("ignored.txt").exists());
+    apply_mode_config(program, cfg_path);
+}
+
+fn git(dir: &Path, args: &[&str]) -> String {
+    let (code, stdout, stderr) = run({
+        let mut cmd = Command::new("git");
+        cmd.current_dir(dir)
+            .args(["-c", "user.name=test", "-c", "user.email=test@example.com"])
+            .args(args);
+        cmd
+    });
+    assert_eq!(code, 0, "git {args:?} failed:\n{stderr}");
+    stdout
+}
+
+fn assert_stash_before_records_targets(program: &Path, cfg_path: &Path) {
+    let work = TempDir::new();
+    let repo = work.path();
+    git(repo, &["init", "--quiet"]);
+    std::fs::write(repo.join("a.txt"), "old\n").unwrap();
+    std::fs::write(repo.join("b.txt"), "old\n").unwrap();
+    git(repo, &["add", "-A"]);
+    git(repo, &["commit", "--quiet", "-m", "init"]);
+    std::fs::write(repo.join("b.txt"), "mine\n").unwrap();
+    std::fs::write(repo.join("u.txt"), "untracked\n").unwrap();
+    write_config(cfg_path, serde_json::json!({}));
+
+    let patch = "*** Begin Patch\n*** Update File: b.txt\n@@\n-mine\n+theirs\n*** Delete File: u.txt\n*** Add File: n.txt\n+new\n*** End Patch\n";
+    let (code, stdout, stderr) = run({
+        let mut cmd = Command::new(program);
+        cmd.current_dir(repo)
+            .env("APPLY_PATCH_CONFIG", cfg_path)
+            .args(["--stash-before", patch]);
+        cmd
+    });
+    assert_eq!(code, 0, "stderr:\n{stderr}");
+    let hash = git(repo, &["rev-parse", "--short", "stash@{0}"]);
+    assert!(
+        stdout.starts_with(&format!(
+            "Stashed the files about to be patched as stash@{{0}} ({}).\nSuccess.",
+            hash.trim()
+        )),
+        "stdout:\n{stdout}"
+    );
+    assert_eq!(git(repo, &["stash", "list"]).lines().count(), 1);
+    assert_eq!(git(repo, &["show", "stash@{0}:b.txt"]), "mine\n");
+    assert_eq!(git(repo, &["show", "stash@{0}:u.txt"]), "untracked\n");
+    assert_eq!(git(repo, &["show", "stash@{0}:a.txt"]), "old\n");
+    assert!(git(repo, &["diff", "--cached", "--name-only"]).is_empty());
+    assert_eq!(std::fs::read_to_string(repo.join("b.txt")).unwrap(), "theirs\n");
+
+    git(repo, &["checkout", "stash@{0}", "--", "b.txt", "u.txt"]);
+    assert_eq!(std::fs::read_to_string(repo.join("b.txt")).unwrap(), "mine\n");
+    assert_eq!(std::fs::read_to_string(repo.join("u.txt")).unwrap(), "untracked\n");
+
+    let outside = TempDir::new();
+    let run_outside = |args: &[&str]| {
+        run({
+            let mut cmd = Command::new(program);
+            cmd.current_dir(outside.path())
+                .env("APPLY_PATCH_CONFIG", cfg_path)
+                .env("GIT_CEILING_DIRECTORIES", outside.path().parent().unwrap())
+                .args(args);
+            cmd
+        })
+    };
+    let (code, _stdout, stderr) = run_outside(&["--stash-before", &add_file_patch("x.txt", &["x"])]);
+    assert_eq!(code, 0);
+    assert_eq!(
+        stderr,
+        "Warning: --stash-before: not in a git work tree; nothing was stashed.\n1 warning, 0 errors\n"
+    );
+
+    // --deny-warnings turns the warning into a failure before anything is written.
+    let (code, stdout, stderr) =
+        run_outside(&["--stash-before", "--deny-warnings", &add_file_patch("y.txt", &["y"])]);
+    assert_eq!(code, 1, "stdout:\n{stdout}");
+    assert_eq!(
+        stderr,
+        "Warning: --stash-before: not in a git work tree; nothing was stashed.\nError: --deny-warnings: the warnings above stop the patch. Nothing was changed.\n1 warning, 1 error; failing because of --deny-warnings\n"
+    );
+    assert!(!outside.path().join("y.txt").exists());
+    let (code, _stdout, stderr) = run_outside(&["--deny-warnings", &add_file_patch("z.txt", &["z"])]);
+    assert_eq!((code, stderr.as_str()), (0, ""));
+}
+
+fn assert_to_branch_commits_in_worktree(program: &Path, cfg_path: &Path) {
+    let work = TempDir::new();
+    let repo = work.path();
+    git(repo, &["init", "--quiet", "-b", "main"]);
+    std::fs::create_dir_all(repo.join("src")).unwrap();
+    std::fs::write(repo.join("src/lib.rs"), "old\n").unwrap();
+    git(repo, &["add", "-A"]);
+    git(repo, &["commit", "--quiet", "-m", "init"]);
+    apply_mode_config(program, cfg_path);
+
+    let run_in = |dir: &Path, branch: &str, patch: &str| {
+        run({
+            let mut cmd = Command::new(program);
+            cmd.current_dir(dir)
+                .env("APPLY_PATCH_CONFIG", cfg_path)
+                .arg("--to-branch")
+                .arg(branch)
+                .arg(patch);
+            cmd
+        })
+    };
+
+    // Paths resolve relative to the caller's subdirectory.
+    let (code, stdout, stderr) = run_in(&repo.join("src"), "agent/fix", &update_file_patch("lib.rs", "old", "new"));
+    assert_eq!(code, 0, "stderr:\n{stderr}");
+    assert!(stdout.contains("M lib.rs"), "stdout:\n{stdout}");
+    assert!(stdout.contains("to branch agent/fix."), "stdout:\n{stdout}");
+
+    assert_eq!(std::fs::read_to_string(repo.join("src/lib.rs")).unwrap(), "old\n");
+    assert_eq!(git(repo, &["status", "--porcelain"]), "");
+    assert_eq!(git(repo, &["show", "agent/fix:src/lib.rs"]), "new\n");
+    assert!(git(repo, &["log", "-1", "--format=%B", "agent/fix"]).starts_with("Apply patch to 1 file(s)\n\nM lib.rs (+1 -1)"));
+    assert_eq!(git(repo, &["worktree", "list"]).lines().count(), 1);
+
+    let (code, _stdout, stderr) = run_in(repo, "agent/fix", &add_file_patch("x.txt", &["x"]));
+    assert_eq!(code, 1);
+    assert_eq!(stderr, "Error: branch agent/fix already exists\n");
+
+    // A failed apply leaves no branch behind.
+    let (code, _stdout, _stderr) = run_in(repo, "agent/broken", &update_file_patch("missing.txt", "a", "b"));
+    assert_eq!(code, 1);
+    assert_eq!(git(repo, &["branch", "--list", "agent/broken"]), "");
+}
+
+fn assert_cwd_reroots_patch_paths(program: &Path, cfg_path: &Path) {
+    let work = TempDir::new();
+    let elsewhere = TempDir::new();
+    apply_mode_config(program, cfg_path);
+    let run_from = |cwd: Option<&Path>, env: Option<&Path>, patch: &str| {
+        run({
+            let mut cmd = Command::new(program);
+            cmd.current_dir(elsewhere.path()).env("APPLY_PATCH_CONFIG", cfg_path);
+            if let Some(dir) = cwd {
+                cmd.arg("--cwd").arg(dir);
+            }
+            if let Some(dir) = env {
+                cmd.env("APPLY_PATCH_CWD", dir);
+            }
+            cmd.arg(patch);
+            cmd
+        })
+    };
+
+    let (code, _stdout, stderr) = run_from(Some(work.path()), None, &add_file_patch("a.txt", &["a"]));
+    assert_eq!(code, 0, "stderr:\n{stderr}");
+    assert!(work.path().join("a.txt").exists());
+    assert!(!elsewhere.path().join("a.txt").exists());
+
+    let (code, _stdout, stderr) = run_from(None, Some(work.path()), &add_file_patch("b.txt", &["b"]));
+    assert_eq!(code, 0, "stderr:\n{stderr}");
+    assert!(work.path().join("b.txt").exists());
+
+    // The flag wins over the environment.
+    let (code, _stdout, _stderr) = run_from(Some(elsewhere.path()), Some(work.path()), &add_file_patch("c.txt", &["c"]));
+    assert_eq!(code, 0);
+    assert!(elsewhere.path().join("c.txt").exists());
+
+    let missing = work.path().join("missing");
+    let (code, _stdout, stderr) = run_from(Some(&missing), None, &add_file_patch("d.txt", &["d"]));
+    assert_eq!(code, 2);
+    assert!(
+        stderr.starts_with(&format!("Error: cannot use {} as the working directory:", missing.display())),
+        "stderr:\n{stderr}"
+    );
+}
+
+fn assert_compare_reports_hunk_status(program: &Path) {
+    let work = TempDir::new();
+    std::fs::write(work.path().join("a.txt"), "one\nTWO\nthree\nfour\nfive\n").unwrap();
+    std::fs::write(work.path().join("done.txt"), "x\n").unwrap();
+    let patch = "*** Begin Patch\n*** Update File: a.txt\n@@\n-one\n+ONE\n@@\n-two\n+TWO\n@@\n-four\n-six\n+4\n*** Add File: done.txt\n+x\n*** Add File: new.txt\n+n\n*** Delete File: gone.txt\n*** End Patch\n";
+    let compare = |json: bool| {
+        run({
+            let mut cmd = Command::new(program);
+            cmd.current_dir(work.path()).arg("compare");
+            if json {
+                cmd.arg("--json");
+            }
+            cmd.arg(patch);
+            cmd
+        })
+    };
+
+    let (code, stdout, stderr) = compare(false);
+    assert_eq!(code, 0, "stderr:\n{stderr}");
+    assert_eq!(
+        stdout,
+        "applies  a.txt @@ 1\napplied  a.txt @@ 2\ndiverged a.txt @@ 3\napplied  done.txt\napplies  new.txt\napplied  gone.txt\n2 applies, 3 applied, 1 diverged\n"
+    );
+    assert_eq!(
+        std::fs::read_to_string(work.path().join("a.txt")).unwrap(),
+        "one\nTWO\nthree\nfour\nfive\n"
+    );
+    assert!(!work.path().join("new.txt").exists());
+
+    let (code, stdout, _stderr) = compare(true);
+    assert_eq!(code, 0);
+    let reports: serde_json::Value = serde_json::from_str(&stdout).unwrap();
+    assert_eq!(reports[2], serde_json::json!({"path": "a.txt", "hunk": 3, "status": "diverged"}));
+    assert_eq!(reports[4]["hunk"], serde_json::Value::Null);
+}
+
+fn archive_base64(dir: &Path, tar_args: &str) -> String {
+    let (code, stdout, stderr) = run({
+        let mut cmd = Command::new("sh");
+        cmd.current_dir(dir)
+            .arg("-c")
+            .arg(format!("tar {tar_args} | base64 -w 76"));
+        cmd
+    });
+    assert_eq!(code, 0, "stderr:\n{stderr}");
+    stdout
+}
+
+fn assert_archive_sections_extract(program: &Path, cfg_path: &Path) {
+    let work = TempDir::new();
+    let src = work.path().join("src");
+    std::fs::create_dir_all(src.join("sub")).unwrap();
+    std::fs::write(src.join("a.txt"), "hello\n").unwrap();
+    std::fs::write(src.join("sub/b.txt"), "x\ny\n").unwrap();
+    let dest = work.path().join("dest");
+    std::fs::create_dir_all(&dest).unwrap();
+    apply_mode_config(program, cfg_path);
+    let run_patch = |patch: String| {
+        run({
+            let mut cmd = Command::new(program);
+            cmd.current_dir(&dest).env("APPLY_PATCH_CONFIG", cfg_path).arg(patch);
+            cmd
+        })
+    };
+    let archive_patch = |dir: &str, encoded: &str| {
+        format!("*** Begin Patch\n*** Add File: README\n+scaffold\n*** Add Files From Archive: {dir}\n{encoded}*** End Patch\n")
+    };
+
+    let gz = archive_base64(work.path(), "czf - -C src .");
+    let (code, stdout, stderr) = run_patch(archive_patch("fixtures", &gz));
+    assert_eq!(code, 0, "stderr:\n{stderr}");
+    assert!(stdout.contains("A fixtures/a.txt"), "stdout:\n{stdout}");
+    assert_eq!(std::fs::read_to_string(dest.join("README")).unwrap(), "scaffold\n");
+    assert_eq!(std::fs::read_to_string(dest.join("fixtures/a.txt")).unwrap(), "hello\n");
+    assert_eq!(std::fs::read_to_string(dest.join("fixtures/sub/b.txt")).unwrap(), "x\ny\n");
+
+    // Plain tar works too, and `.` extracts into the current directory.
+    let tar = archive_base64(work.path(), "cf - -C src sub");
+    let (code, _stdout, stderr) = run_patch(archive_patch(".", &tar));
+    assert_eq!(code, 0, "stderr:\n{stderr}");
+    assert_eq!(std::fs::read_to_string(dest.join("sub/b.txt")).unwrap(), "x\ny\n");
+
+    let escaping = archive_base64(work.path(), "cf - -P --transform 's,^,../,' -C src a.txt");
+    let (code, _stdout, stderr) = run_patch(archive_patch("more", &escaping));
+    assert_eq!(code, 1);
+    assert_eq!(stderr, "Invalid archive for more: ../a.txt escapes the target directory\n");
+    assert!(!work.path().join("a.txt").exists());
+
+    write_config(cfg_path, serde_json::json!({"archives": {"max_files": 1}}));
+    let (code, _stdout, stderr) = run_patch(archive_patch("limited", &gz));
+    assert_eq!(code, 1);
+    assert_eq!(stderr, "Invalid archive for limited: more than 1 files\n");
+    write_config(cfg_path, serde_json::json!({"archives": {"max_bytes": 1000}}));
+    let (code, _stdout, stderr) = run_patch(archive_patch("limited", &gz));
+    assert_eq!(code, 1);
+    assert_eq!(stderr, "Invalid archive for limited: archive is larger than 1000 bytes once decompressed\n");
+    assert!(!dest.join("limited").exists());
+}
+
+fn assert_config_versions_migrate(program: &Path, cfg_path: &Path) {
+    let work = TempDir::new();
+    let backup = cfg_path.with_file_name("config.json.v0.bak");
+    let _ = std::fs::remove_file(&backup);
+    let run_args = |args: &[&str]| {
+        run({
+            let mut cmd = Command::new(program);
+            cmd.current_dir(work.path()).env("APPLY_PATCH_CONFIG", cfg_path).args(args);
+            cmd
+        })
+    };
+
+    // Unversioned files are read as they are; runs never rewrite them.
+    let original = "{\"mode\": \"warn\"}";
+    std::fs::write(cfg_path, original).unwrap();
+    let (code, stdout, stderr) = run_args(&["--show-config"]);
+    assert_eq!(code, 0, "stderr:\n{stderr}");
+    assert!(stdout.contains("mode: warn\n"), "stdout:\n{stdout}");
+    let (code, _stdout, stderr) = run_args(&[&add_file_patch("v0.txt", &["a"])]);
+    assert_eq!(code, 0, "stderr:\n{stderr}");
+    assert_eq!(std::fs::read_to_string(cfg_path).unwrap(), original);
+    assert!(!backup.exists());
+
+    // migrate-config upgrades them, keeping the original and adding no defaults.
+    let (code, stdout, stderr) = run_args(&["migrate-config"]);
+    assert_eq!(code, 0, "stderr:\n{stderr}");
+    assert!(stdout.starts_with("Upgraded "), "stdout:\n{stdout}");
+    assert_eq!(std::fs::read_to_string(&backup).unwrap(), original);
+    let cfg: serde_json::Value = serde_json::from_str(&std::fs::read_to_string(cfg_path).unwrap()).unwrap();
+    assert_eq!(cfg, serde_json::json!({"mode": "warn", "version": 1}));
+    let (code, stdout, _stderr) = run_args(&["migrate-config"]);
+    assert_eq!(code, 0);
+    assert!(stdout.ends_with("is already at version 1.\n"), "stdout:\n{stdout}");
+
+    // Newer versions are rejected, and never overwritten by config flags.
+    let future = "{\"version\": 99, \"mode\": \"refuse\"}";
+    std::fs::write(cfg_path, future).unwrap();
+    let (code, _stdout, stderr) = run_args(&[&add_file_patch("a.txt", &["a"])]);
+    assert_eq!(code, 1);
+    assert_eq!(
+        stderr,
+        format!(
+            "Error: invalid config {}: config version 99 is newer than this apply_patch supports (1); upgrade apply_patch\n",
+            cfg_path.display()
+        )
+    );
+    assert!(!work.path().join("a.txt").exists());
+    let (code, _stdout, _stderr) = run_args(&["--mode", "apply"]);
+    assert_eq!(code, 1);
+    assert_eq!(std::fs::read_to_string(cfg_path).unwrap(), future);
+
+    // Unparseable files are errors rather than silently reset to defaults.
+    std::fs::write(cfg_path, "{\"mode\": ").unwrap();
+    let (code, _stdout, stderr) = run_args(&[&add_file_patch("a.txt", &["a"])]);
+    assert_eq!(code, 1);
+    assert!(stderr.starts_with("Error: invalid config "), "stderr:\n{stderr}");
+    std::fs::remove_file(cfg_path).unwrap();
+    std::fs::remove_file(&backup).unwrap();
+}
+
+fn assert_reanchor_drifted_hunks(program: &Path, cfg_path: &Path) {
+    let work = TempDir::new();
+    let target = work.path().join("lib.rs");
+    let original = "fn a() {}\n\nfn helper(x: u32) -> u32 {\n    let y = x * 2;\n    y + 1\n}\n";
+    std::fs::write(&target, original).unwrap();
+    let patch = "*** Begin Patch\n*** Update File: lib.rs\n@@ fn old_helper(x: u32) -> u32 {\n fn helper(x: u64) -> u64 {\n-    let y = x * 2;\n+    let y = x * 3;\n     y + 1\n*** End Patch\n";
+    let run_patch = || {
+        run({
+            let mut cmd = Command::new(program);
+            cmd.current_dir(work.path()).env("APPLY_PATCH_CONFIG", cfg_path).arg(patch);
+            cmd
+        })
+    };
+
+    write_config(cfg_path, serde_json::json!({}));
+    let (code, _stdout, stderr) = run_patch();
+    assert_eq!(code, 1);
+    assert!(stderr.contains("Failed to find context"), "stderr:\n{stderr}");
+    assert_eq!(std::fs::read_to_string(&target).unwrap(), original);
+
+    write_config(cfg_path, serde_json::json!({"reanchor": {"min_similarity": 0.9}}));
+    let (code, _stdout, _stderr) = run_patch();
+    assert_eq!(code, 1);
+    assert_eq!(std::fs::read_to_string(&target).unwrap(), original);
+
+    write_config(cfg_path, serde_json::json!({"reanchor": {"min_similarity": 0.6}}));
+    let (code, stdout, stderr) = run_patch();
+    assert_eq!(code, 0, "stderr:\n{stderr}");
+    assert!(
+        stdout.starts_with("Re-anchored hunk 1 of lib.rs at line 3 (67% of its context matched).\nSuccess."),
+        "stdout:\n{stdout}"
+    );
+    assert_eq!(
+        std::fs::read_to_string(&target).unwrap(),
+        original.replace("x * 2", "x * 3")
+    );
+}
+
+fn assert_json_stream_progress(program: &Path, cfg_path: &Path) {
+    let work = TempDir::new();
+    std::fs::write(work.path().join("b.txt"), "x\n").unwrap();
+    write_config(cfg_path, serde_json::json!({}));
+    let run_patch = |patch: &str| {
+        run({
+            let mut cmd = Command::new(program);
+            cmd.current_dir(work.path())
+                .env("APPLY_PATCH_CONFIG", cfg_path)
+                .args(["--format", "json-stream", patch]);
+            cmd
+        })
+    };
+
+    let patch = "*** Begin Patch\n*** Add File: a.txt\n+a\n*** Update File: b.txt\n@@\n-x\n+y\n*** End Patch\n";
+    let (code, stdout, stderr) = run_patch(patch);
+    assert_eq!(code, 0, "stderr:\n{stderr}");
+    assert_eq!(stdout, "Success. Updated the following files:\nA a.txt\nM b.txt\n");
+    let events: Vec<serde_json::Value> = stderr
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    assert_eq!(
+        events,
+        vec![
+            serde_json::json!({"event": "progress", "completed": 0, "total": 2, "current": "a.txt"}),
+            serde_json::json!({"event": "progress", "completed": 1, "total": 2, "current": "b.txt"}),
+            serde_json::json!({"event": "finished", "completed": 2, "total": 2}),
+        ]
+    );
+
+    let patch = "*** Begin Patch\n*** Add File: c.txt\n+c\n*** Update File: b.txt\n@@\n-missing\n+z\n*** Add File: d.txt\n+d\n*** End Patch\n";
+    let (code, stdout, stderr) = run_patch(patch);
+    assert_eq!(code, 1);
+    assert!(stdout.is_empty(), "stdout:\n{stdout}");
+    assert!(
+        stderr.contains(r#"{"event":"finished","completed":1,"total":3}"#),
+        "stderr:\n{stderr}"
+    );
+    assert!(work.path().join("c.txt").exists());
+    assert!(!work.path().join("d.txt").exists());
+
+    let (code, _stdout, stderr) = run({
+        let mut cmd = Command::new(program);
+        cmd.env("APPLY_PATCH_CONFIG", cfg_path).args(["--format", "xml", patch]);
+        cmd
+    });
+    assert_eq!(code, 2);
+    assert!(stderr.contains("invalid --format value: xml"), "stderr:\n{stderr}");
+}
+
+fn assert_banner_session_counts(program: &Path, cfg_path: &Path) {
+    let work = TempDir::new();
+    let state_home = TempDir::new();
+    let run_as = |agent: &str, session: &str, patch: &str| {
+        run({
+            let mut cmd = Command::new(program);
+            cmd.current_dir(work.path())
+                .env("APPLY_PATCH_CONFIG", cfg_path)
+                .env("XDG_STATE_HOME", state_home.path())
+                .env("APPLY_PATCH_AGENT", agent)
+                .env("APPLY_PATCH_SESSION", session)
+                .arg(patch);
+            cmd
+        })
+    };
+    write_config(
+        cfg_path,
+        serde_json::json!({
+            "mode": "warn",
+            "warn_message": "{agent}: shell patch {session_apply_count}, warning {warn_count}.",
+        }),
+    );
+
+    let (code, stdout, _stderr) = run_as("alpha", "s1", &add_file_patch("a1.txt", &["x"]));
+    assert_eq!(code, 0);
+    assert!(stdout.ends_with("alpha: shell patch 1, warning 1.\n"), "stdout:\n{stdout}");
+    let (_code, stdout, _stderr) = run_as("alpha", "s1", &add_file_patch("a2.txt", &["x"]));
+    assert!(stdout.ends_with("alpha: shell patch 2, warning 2.\n"), "stdout:\n{stdout}");
+    let (_code, stdout, _stderr) = run_as("beta", "s1", &add_file_patch("b1.txt", &["x"]));
+    assert!(stdout.ends_with("beta: shell patch 1, warning 1.\n"), "stdout:\n{stdout}");
+    let (_code, stdout, _stderr) = run_as("alpha", "s2", &add_file_patch("a3.txt", &["x"]));
+    assert!(stdout.ends_with("alpha: shell patch 1, warning 1.\n"), "stdout:\n{stdout}");
+
+    // Refusals show the counts without adding to them.
+    write_config(
+        cfg_path,
+        serde_json::json!({"mode": "refuse", "refuse_message": "Refused after {session_apply_count}."}),
+    );
+    let (_code, stdout, _stderr) = run_as("alpha", "s2", &add_file_patch("a4.txt", &["x"]));
+    assert_eq!(stdout, "Refused after 1.\n");
+}
+
+fn assert_base_rebases_onto_worktree(program: &Path, cfg_path: &Path) {
+    let work = TempDir::new();
+    let repo = work.path();
+    git(repo, &["init", "--quiet"]);
+    let target = repo.join("f.txt");
+    std::fs::write(&target, "one\ntwo\nthree\nfour\nfive\nsix\nseven\n").unwrap();
+    git(repo, &["add", "-A"]);
+    git(repo, &["commit", "--quiet", "-m", "base"]);
+    // Edited since the patch was written, inside the patch's context.
+    std::fs::write(&target, "one\ntwo\nTHREE\nfour\nfive\nsix\nseven\n").unwrap();
+    write_config(cfg_path, serde_json::json!({}));
+    let run_in_repo = |args: &[&str]| {
+        run({
+            let mut cmd = Command::new(program);
+            cmd.current_dir(repo).env("APPLY_PATCH_CONFIG", cfg_path).args(args);
+            cmd
+        })
+    };
+    let patch = "*** Begin Patch\n*** Update File: f.txt\n@@\n two\n three\n-four\n+FOUR\n five\n*** End Patch\n";
+
+    let (code, _stdout, _stderr) = run_in_repo(&[patch]);
+    assert_eq!(code, 1);
+
+    let (code, stdout, stderr) = run_in_repo(&["--base", "HEAD", "--rebased-out", "out.patch", patch]);
+    assert_eq!(code, 0, "stderr:\n{stderr}");
+    assert_eq!(stdout, "Rebased patch written to out.patch\n");
+    assert_eq!(
+        std::fs::read_to_string(repo.join("out.patch")).unwrap(),
+        "*** Begin Patch\n*** Update File: f.txt\n@@\n one\n two\n THREE\n-four\n+FOUR\n five\n six\n seven\n*** End Patch\n"
+    );
+
+    let (code, stdout, stderr) = run_in_repo(&["--base", "HEAD", patch]);
+    assert_eq!(code, 0, "stderr:\n{stderr}");
+    assert!(stdout.starts_with("Success."), "stdout:\n{stdout}");
+    assert_eq!(
+        std::fs::read_to_string(&target).unwrap(),
+        "one\ntwo\nTHREE\nFOUR\nfive\nsix\nseven\n"
+    );
+
+    // The patch's own line changed since the base: a conflict.
+    std::fs::write(&target, "one\ntwo\nthree\n4\nfive\nsix\nseven\n").unwrap();
+    let (code, _stdout, stderr) = run_in_repo(&["--base", "HEAD", patch]);
+    assert_eq!(code, 1);
+    assert!(stderr.contains("conflict in f.txt: line 4 at HEAD"), "stderr:\n{stderr}");
+
+    let (code, _stdout, stderr) = run_in_repo(&["--rebased-out", "out.patch", patch]);
+    assert_eq!(code, 2);
+    assert!(stderr.contains("--rebased-out requires --base"), "stderr:\n{stderr}");
+}
+
+fn assert_serve_http(program: &Path, cfg_path: &Path) {
+    use std::io::BufRead;
+    use std::io::Read;
+    use std::io::Write;
+
+    let work = TempDir::new();
+    write_config(cfg_path, serde_json::json!({"mode": "warn"}));
+    let (code, _stdout, stderr) = run({
+        let mut cmd = Command::new(program);
+        cmd.arg("serve-http").env_remove("APPLY_PATCH_HTTP_TOKEN");
+        cmd
+    });
+    assert_eq!(code, 2);
+    assert!(stderr.contains("requires $APPLY_PATCH_HTTP_TOKEN"), "stderr:\n{stderr}");
+
+    let mut server = Command::new(program)
+        .args(["serve-http", "--bind", "127.0.0.1:0"])
+        .current_dir(work.path())
+        .env("APPLY_PATCH_CONFIG", cfg_path)
+        .env("APPLY_PATCH_HTTP_TOKEN", "s3cret")
+        .stdout(Stdio::piped())
+        .spawn()
+        .expect("failed to start serve-http");
+    let mut banner = String::new();
+    std::io::BufReader::new(server.stdout.take().unwrap())
+        .read_line(&mut banner)
+        .unwrap();
+    let addr = banner.trim().strip_prefix("Listening on http://").expect(&banner).to_string();
+    let request = |method: &str, path: &str, token: &str, content_type: &str, body: &str| {
+        let mut stream = std::net::TcpStream::connect(&addr).unwrap();
+        write!(
+            stream,
+            "{method} {path} HTTP/1.1\r\nHost: localhost\r\nAuthorization: Bearer {token}\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\n\r\n{body}",
+            body.len()
+        )
+        .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        let status = head.split_whitespace().nth(1).unwrap().to_string();
+        (status, serde_json::from_str::<serde_json::Value>(body).unwrap())
+    };
+
+    let (status, _body) = request("GET", "/status", "wrong", "text/plain", "");
+    assert_eq!(status, "401");
+    let (status, body) = request("GET", "/status", "s3cret", "text/plain", "");
+    assert_eq!(status, "200");
+    assert_eq!(body["mode"], "warn");
+
+    let patch = add_file_patch("served.txt", &["hi"]);
+    let (status, body) = request("POST", "/validate", "s3cret", "text/plain", &patch);
+    assert_eq!(status, "200");
+    assert_eq!(body["exit_code"], 0);
+    assert!(!work.path().join("served.txt").exists());
+
+    let (status, body) = request("POST", "/apply", "s3cret", "text/plain", &patch);
+    assert_eq!(status, "200");
+    assert_eq!(body["exit_code"], 0);
+    assert!(body["stdout"].as_str().unwrap().contains("A served.txt\nNOTE TO LLM:"), "{body}");
+    assert_eq!(std::fs::read_to_string(work.path().join("served.txt")).unwrap(), "hi\n");
+
+    std::fs::create_dir_all(work.path().join("sub")).unwrap();
+    let call = serde_json::json!({"patch": add_file_patch("nested.txt", &["x"]), "cwd": "sub"});
+    let (_status, body) = request("POST", "/apply", "s3cret", "application/json", &call.to_string());
+    assert_eq!(body["exit_code"], 0, "{body}");
+    assert!(work.path().join("sub/nested.txt").exists());
+
+    let (status, _body) = request("DELETE", "/apply", "s3cret", "text/plain", "");
+    assert_eq!(status, "405");
+
+    // A client stalled mid-request holds up nobody else.
+    let mut stalled = std::net::TcpStream::connect(&addr).unwrap();
+    write!(stalled, "POST /apply HTTP/1.1\r\nAuthorization: Bearer s3cret\r\nContent-Length: 100\r\n\r\n*** Begin").unwrap();
+    let (status, body) = request("POST", "/apply", "s3cret", "text/plain", &add_file_patch("meanwhile.txt", &["m"]));
+    assert_eq!(status, "200");
+    assert_eq!(body["exit_code"], 0, "{body}");
+    assert!(work.path().join("meanwhile.txt").exists());
+
+    // The token is checked before the body is read, and the head is capped.
+    let raw = |head: String| {
+        let mut stream = std::net::TcpStream::connect(&addr).unwrap();
+        let _ = stream.write_all(head.as_bytes());
+        let mut response = String::new();
+        let _ = stream.read_to_string(&mut response);
+        response.split_whitespace().nth(1).unwrap_or_default().to_string()
+    };
+    assert_eq!(raw("POST /apply HTTP/1.1\r\nAuthorization: Bearer wrong\r\nContent-Length: 1000000\r\n\r\n".to_string()), "401");
+    assert_eq!(raw(format!("GET /status HTTP/1.1\r\nX-Long: {}\r\n\r\n", "a".repeat(9000))), "431");
+    assert_eq!(raw(format!("GET /status HTTP/1.1\r\n{}\r\n", "X-Many: a\r\n".repeat(65))), "431");
+
+    // The config is cached, but a changed file is read again.
+    write_config(cfg_path, serde_json::json!({"mode": "refuse"}));
+    let (_status, body) = request("GET", "/status", "s3cret", "text/plain", "");
+    assert_eq!(body["mode"], "refuse");
+    let (_status, body) = request("POST", "/apply", "s3cret", "text/plain", &add_file_patch("refused.txt", &["r"]));
+    assert_eq!(body["exit_code"], 0);
+    assert!(body["stdout"].as_str().unwrap().contains("nothing was changed"), "{body}");
+    assert!(!work.path().join("refused.txt").exists());
+    drop(stalled);
+
+    // `POST /reload` reads the file even when it looks unchanged.
+    let (status, _body) = request("POST", "/reload", "wrong", "text/plain", "");
+    assert_eq!(status, "401");
+    std::fs::write(cfg_path, "{\"mode\": \"nope\"}").unwrap();
+    let (status, body) = request("POST", "/reload", "s3cret", "text/plain", "");
+    assert_eq!(status, "500");
+    assert!(body["error"].as_str().unwrap().contains("nope"), "{body}");
+    write_config(cfg_path, serde_json::json!({"mode": "warn"}));
+    let (status, body) = request("POST", "/reload", "s3cret", "text/plain", "");
+    assert_eq!(status, "200");
+    assert_eq!(body, serde_json::json!({"reloaded": true, "mode": "warn"}));
+    let (status, _body) = request("GET", "/reload", "s3cret", "text/plain", "");
+    assert_eq!(status, "405");
+    let _ = server.kill();
+    let _ = server.wait();
+}
+
+fn assert_render_template_sections(program: &Path, cfg_path: &Path) {
+    let work = TempDir::new();
+    let templates = cfg_path.parent().unwrap().join("templates");
+    std::fs::create_dir_all(&templates).unwrap();
+    std::fs::write(
+        templates.join("module.rs"),
+        "//! {{ doc }}\n\npub fn {{name}}() -> &'static str {\n    \"{{name}}\"\n}\n",
+    )
+    .unwrap();
+    write_config(cfg_path, serde_json::json!({}));
+    let run_patch = |patch: &str| {
+        run_with_stdin({
+            let mut cmd = Command::new(program);
+            cmd.current_dir(work.path()).env("APPLY_PATCH_CONFIG", cfg_path);
+            cmd
+        }, patch)
+    };
+
+    let patch = "*** Begin Patch\n*** Render Template: module.rs -> src/greet.rs\n+name=greet\n+doc=Greetings.\n*** Add File: README.md\n+hi\n*** End Patch\n";
+    let (code, stdout, stderr) = run_patch(patch);
+    assert_eq!(code, 0, "stderr:\n{stderr}");
+    assert!(stdout.contains("A src/greet.rs\nA README.md"), "stdout:\n{stdout}");
+    assert_eq!(
+        std::fs::read_to_string(work.path().join("src/greet.rs")).unwrap(),
+        "//! Greetings.\n\npub fn greet() -> &'static str {\n    \"greet\"\n}\n"
+    );
+
+    for (body, error) in [
+        ("*** Render Template: module.rs -> a.rs\n+name=a\n", "Invalid template section for a.rs: variable `doc` is not set"),
+        ("*** Render Template: module.rs -> b.rs\n+name=b\n+doc=x\n+extra=1\n", "the template does not use variable `extra`"),
+        ("*** Render Template: ../secret -> c.rs\n", "invalid template name `../secret`"),
+        ("*** Render Template: missing -> d.rs\n", "cannot read template "),
+    ] {
+        let (code, _stdout, stderr) = run_patch(&format!("*** Begin Patch\n{body}*** End Patch\n"));
+        assert_eq!(code, 1);
+        assert!(stderr.contains(error), "stderr:\n{stderr}");
+    }
+    assert!(!work.path().join("a.rs").exists());
+}
+
+fn assert_squash_composes_patches(program: &Path, cfg_path: &Path) {
+    let work = TempDir::new();
+    let root = work.path();
+    std::fs::write(root.join("lib.txt"), "a\nb\nc\n").unwrap();
+    std::fs::write(root.join("gone.txt"), "old\n").unwrap();
+    let patches = [
+        "*** Begin Patch\n*** Update File: lib.txt\n@@\n a\n-b\n+B\n*** Add File: new.txt\n+one\n*** End Patch\n",
+        "*** Begin Patch\n*** Update File: new.txt\n@@\n-one\n+two\n*** Update File: lib.txt\n@@\n B\n-c\n+C\n*** End Patch\n",
+        "*** Begin Patch\n*** Delete File: gone.txt\n*** End Patch\n",
+    ];
+    for (i, patch) in patches.iter().enumerate() {
+        std::fs::write(root.join(format!("{i}.patch")), patch).unwrap();
+    }
+    let run_in_work = |args: &[&str]| {
+        run({
+            let mut cmd = Command::new(program);
+            cmd.current_dir(root).env("APPLY_PATCH_CONFIG", cfg_path).args(args);
+            cmd
+        })
+    };
+
+    let (code, stdout, stderr) = run_in_work(&["squash", "0.patch", "1.patch", "2.patch"]);
+    assert_eq!(code, 0, "stderr:\n{stderr}");
+    assert_eq!(
+        stdout,
+        "*** Begin Patch\n*** Update File: lib.txt\n@@\n a\n-b\n-c\n+B\n+C\n*** Add File: new.txt\n+two\n*** Delete File: gone.txt\n*** End Patch\n"
+    );
+    let (code, stdout, _stderr) = run_in_work(&["squash", "--out", "all.patch", "0.patch", "1.patch", "2.patch"]);
+    assert_eq!(code, 0);
+    assert_eq!(stdout, "Squashed 3 patches into all.patch (3 files)\n");
+    let (code, _stdout, stderr) = run_in_work(&["--", &std::fs::read_to_string(root.join("all.patch")).unwrap()]);
+    assert_eq!(code, 0, "stderr:\n{stderr}");
+    assert_eq!(std::fs::read_to_string(root.join("lib.txt")).unwrap(), "a\nB\nC\n");
+    assert_eq!(std::fs::read_to_string(root.join("new.txt")).unwrap(), "two\n");
+
+    // The second patch expects the first's result, so reversing them conflicts.
+    std::fs::write(root.join("lib.txt"), "a\nb\nc\n").unwrap();
+    let (code, _stdout, stderr) = run_in_work(&["squash", "1.patch", "0.patch"]);
+    assert_eq!(code, 1);
+    assert!(
+        stderr.contains("Error: 1.patch does not apply on top of the patches before it: "),
+        "stderr:\n{stderr}"
+    );
+}
+
+fn assert_dry_run_and_readonly(program: &Path, cfg_path: &Path) {
+    let work = TempDir::new();
+    let audit_log = work.path().join("audit.jsonl");
+    std::fs::write(work.path().join("notes.txt"), "old\n").unwrap();
+    let run_patch = |args: &[&str], patch: &str| {
+        run_with_stdin({
+            let mut cmd = Command::new(program);
+            cmd.current_dir(work.path()).env("APPLY_PATCH_CONFIG", cfg_path).args(args);
+            cmd
+        }, patch)
+    };
+    let patch = "*** Begin Patch\n*** Update File: notes.txt\n@@\n-old\n+new\n*** Add File: more.txt\n+x\n*** End Patch\n";
+
+    write_config(cfg_path, serde_json::json!({"audit_log": audit_log}));
+    let (code, stdout, stderr) = run_patch(&["--dry-run"], patch);
+    assert_eq!(code, 0, "stderr:\n{stderr}");
+    assert_eq!(
+        stdout,
+        "Dry run: nothing was written. The patch would update the following files:\nM notes.txt\nA more.txt\n"
+    );
+    assert_eq!(std::fs::read_to_string(work.path().join("notes.txt")).unwrap(), "old\n");
+    assert!(!work.path().join("more.txt").exists());
+    assert_eq!(read_jsonl(&audit_log)[0]["decision"], "dry_run");
+
+    // readonly forces it on, still running the matcher and showing banners.
+    write_config(cfg_path, serde_json::json!({"mode": "warn", "readonly": true}));
+    let (code, stdout, _stderr) = run_patch(&[], patch);
+    assert_eq!(code, 0);
+    assert!(stdout.starts_with("Dry run: nothing was written."), "stdout:\n{stdout}");
+    assert!(stdout.contains("NOTE TO LLM:"), "stdout:\n{stdout}");
+    assert!(!work.path().join("more.txt").exists());
+    let (code, _stdout, stderr) = run_patch(&[], &update_file_patch("notes.txt", "missing", "new"));
+    assert_eq!(code, 1);
+    assert!(stderr.contains("Failed to find expected lines"), "stderr:\n{stderr}");
+    let (code, _stdout, stderr) = run_patch(&["--to-branch", "demo"], patch);
+    assert_eq!(code, 2);
+    assert!(stderr.contains("--to-branch cannot be used with --dry-run or readonly"), "stderr:\n{stderr}");
+}
+
+fn assert_archive_flag_patches_entries(program: &Path, cfg_path: &Path) {
+    let work = TempDir::new();
+    let src = work.path().join("src");
+    std::fs::create_dir_all(src.join("lib")).unwrap();
+    std::fs::write(src.join("handler.py"), "def handler():\n    return 1\n").unwrap();
+    std::fs::write(src.join("lib/util.py"), "X = 1\n").unwrap();
+    let sh = |script: &str| {
+        let (code, stdout, stderr) = run({
+            let mut cmd = Command::new("sh");
+            cmd.current_dir(work.path()).arg("-c").arg(script);
+            cmd
+        });
+        assert_eq!(code, 0, "{script}: {stderr}");
+        stdout
+    };
+    sh("cd src && zip -q -r ../bundle.zip handler.py lib && tar czf ../bundle.tgz .");
+    let run_patch = |args: &[&str], patch: &str| {
+        run_with_stdin({
+            let mut cmd = Command::new(program);
+            cmd.current_dir(work.path()).env("APPLY_PATCH_CONFIG", cfg_path).args(args);
+            cmd
+        }, patch)
+    };
+    let patch = "*** Begin Patch\n*** Update File: handler.py\n@@ def handler():\n-    return 1\n+    return 2\n*** Add File: lib/new.py\n+Y = 2\n*** Delete File: lib/util.py\n*** End Patch\n";
+
+    write_config(cfg_path, serde_json::json!({}));
+    let (code, stdout, stderr) = run_patch(&["--archive", "bundle.zip"], patch);
+    assert_eq!(code, 0, "stderr:\n{stderr}");
+    assert_eq!(
+        stdout,
+        "Success. Updated the following files in bundle.zip:\nM handler.py\nA lib/new.py\nD lib/util.py\n"
+    );
+    sh("unzip -tq bundle.zip");
+    assert_eq!(sh("unzip -p bundle.zip handler.py"), "def handler():\n    return 2\n");
+    assert_eq!(sh("unzip -p bundle.zip lib/new.py"), "Y = 2\n");
+    assert!(!sh("unzip -l bundle.zip").contains("util.py"));
+    assert!(!work.path().join("handler.py").exists());
+
+    // tar.gz entries named `./...`, written to a new archive.
+    let (code, _stdout, stderr) = run_patch(&["--archive", "bundle.tgz", "--archive-out", "out.tgz"], patch);
+    assert_eq!(code, 0, "stderr:\n{stderr}");
+    assert_eq!(sh("tar xzf out.tgz -O ./handler.py"), "def handler():\n    return 2\n");
+    assert_eq!(sh("tar xzf out.tgz -O lib/new.py"), "Y = 2\n");
+    assert!(!sh("tar tzf out.tgz").contains("util.py"));
+    assert_eq!(sh("tar xzf bundle.tgz -O ./handler.py"), "def handler():\n    return 1\n");
+
+    // Mismatches and modes work as usual, leaving the archive alone.
+    let before = std::fs::read(work.path().join("bundle.tgz")).unwrap();
+    let (code, _stdout, stderr) =
+        run_patch(&["--archive", "bundle.tgz"], &update_file_patch("handler.py", "missing", "x"));
+    assert_eq!(code, 1);
+    assert!(stderr.contains("Failed to find expected lines in handler.py"), "stderr:\n{stderr}");
+    write_config(cfg_path, serde_json::json!({"mode": "refuse"}));
+    let (code, stdout, _stderr) = run_patch(&["--archive", "bundle.tgz"], patch);
+    assert_eq!(code, 0);
+    assert!(stdout.contains("NOTE TO LLM"), "stdout:\n{stdout}");
+    assert_eq!(std::fs::read(work.path().join("bundle.tgz")).unwrap(), before);
+
+    let (code, _stdout, stderr) = run_patch(&["--archive", "bundle.tgz", "--partial"], patch);
+    assert_eq!(code, 2);
+    assert!(stderr.contains("--archive cannot be combined with --partial"), "stderr:\n{stderr}");
+}
+
+fn assert_event_fd_stream(program: &Path, cfg_path: &Path) {
+    let work = TempDir::new();
+    let events = work.path().join("events.jsonl");
+    std::fs::write(work.path().join("notes.txt"), "old\n").unwrap();
+    let run_patch = |script: &str, patch: &str| {
+        run_with_stdin({
+            let mut cmd = Command::new("sh");
+            cmd.current_dir(work.path())
+                .env("APPLY_PATCH_CONFIG", cfg_path)
+                .arg("-c")
+                .arg(script)
+                .arg(program)
+                .arg(&events);
+            cmd
+        }, patch)
+    };
+
+    write_config(cfg_path, serde_json::json!({}));
+    let patch = "*** Begin Patch\n*** Update File: notes.txt\n@@\n-old\n+new\n*** Add File: more.txt\n+x\n*** End Patch\n";
+    let (code, stdout, stderr) = run_patch(r#"exec "$0" --event-fd 3 3>>"$1""#, patch);
+    assert_eq!(code, 0, "stderr:\n{stderr}");
+    assert_eq!(stdout, "Success. Updated the following files:\nA more.txt\nM notes.txt\n");
+    let lines = read_jsonl(&events);
+    let names: Vec<&str> = lines.iter().map(|l| l["event"].as_str().unwrap()).collect();
+    assert_eq!(names, ["started", "policy-decision", "file-applied", "file-applied", "finished"]);
+    assert_eq!(lines[0]["files"], serde_json::json!(["notes.txt", "more.txt"]));
+    assert_eq!(lines[1]["mode"], "apply");
+    assert_eq!(lines[2]["path"], "notes.txt");
+    assert_eq!(lines[2]["status"], "M");
+    assert_eq!(lines[4]["decision"], "applied");
+    assert_eq!(lines[4]["exit_code"], 0);
+    assert!(lines[4]["timestamp"].as_u64().is_some());
+
+    // The environment variable works too; refusals carry the reason.
+    std::fs::remove_file(&events).unwrap();
+    write_config(cfg_path, serde_json::json!({"mode": "refuse"}));
+    let (code, stdout, _stderr) =
+        run_patch(r#"APPLY_PATCH_EVENT_FD=3 exec "$0" 3>>"$1""#, &add_file_patch("x.txt", &["x"]));
+    assert_eq!(code, 0);
+    assert!(stdout.contains("NOTE TO LLM"), "stdout:\n{stdout}");
+    let lines = read_jsonl(&events);
+    assert_eq!(lines.len(), 3);
+    assert_eq!(lines[1]["reason"]["code"], "mode");
+    assert_eq!(lines[2]["decision"], "refused");
+
+    let (code, _stdout, stderr) = run_patch(r#"exec "$0" --event-fd nine"#, patch);
+    assert_eq!(code, 2);
+    assert_eq!(stderr, "Error: invalid event file descriptor: nine\n");
+}
+
+fn assert_truncated_patches_rejected(program: &Path, cfg_path: &Path) {
+    let work = TempDir::new();
+    std::fs::write(work.path().join("notes.txt"), "old\n").unwrap();
+    let run_patch = |args: &[&str], patch: &str| {
+        run_with_stdin({
+            let mut cmd = Command::new(program);
+            cmd.current_dir(work.path()).env("APPLY_PATCH_CONFIG", cfg_path).args(args);
+            cmd
+        }, patch)
+    };
+    write_config(cfg_path, serde_json::json!({}));
+
+    let (code, stdout, stderr) =
+        run_patch(&["--partial"], "*** Begin Patch\n*** Add File: new.txt\n+x\n*** Update File: notes.txt\n@@\n-old\n+ne");
+    assert_eq!(code, 1);
+    assert_eq!(stdout, "");
+    assert_eq!(
+        stderr,
+        "Refused: the patch appears truncated (there is no `*** End Patch` line, and the last line stops mid-line). Nothing was changed; resend the complete patch.\n"
+    );
+    assert!(!work.path().join("new.txt").exists());
+
+    let (code, _stdout, stderr) =
+        run_patch(&[], "*** Begin Patch\n*** Add File: new.txt\n+x\n*** Update File: notes.txt\n*** End Patch\n");
+    assert_eq!(code, 1);
+    assert!(stderr.contains("(the section for notes.txt has a header but no changes)"), "stderr:\n{stderr}");
+    let (code, _stdout, stderr) =
+        run_patch(&[], "*** Begin Patch\n*** Update File: notes.txt\n@@ fn main\n*** End Patch\n");
+    assert_eq!(code, 1);
+    assert!(stderr.contains("(the last hunk of notes.txt ends after its `@@` line)"), "stderr:\n{stderr}");
+    assert!(!work.path().join("new.txt").exists());
+
+    // A rename without hunks isn't truncated; the applier reports it.
+    let (code, _stdout, stderr) =
+        run_patch(&[], "*** Begin Patch\n*** Update File: notes.txt\n*** Move to: moved.txt\n*** End Patch\n");
+    assert_eq!(code, 1);
+    assert!(!stderr.contains("truncated"), "stderr:\n{stderr}");
+
+    // Refuse mode answers a truncated patch with its banner, like any other.
+    write_config(cfg_path, serde_json::json!({"mode": "refuse"}));
+    let (code, stdout, stderr) =
+        run_patch(&[], "*** Begin Patch\n*** Update File: notes.txt\n@@\n-old\n+ne");
+    assert_eq!(code, 0);
+    assert_eq!(stderr, "");
+    assert!(stdout.starts_with("NOTE TO LLM:\n"), "stdout:\n{stdout}");
+    assert_eq!(std::fs::read_to_string(work.path().join("notes.txt")).unwrap(), "old\n");
+}
+
+fn assert_mirror_dir_replays_applies(program: &Path, cfg_path: &Path) {
+    let work = TempDir::new();
+    let mirror = TempDir::new();
+    for (name, contents) in [("notes.txt", "old\n"), ("other.txt", "orig\n"), ("gone.txt", "x\n")] {
+        std::fs::write(work.path().join(name), contents).unwrap();
+    }
+    std::fs::write(mirror.path().join("notes.txt"), "old\n").unwrap();
+    std::fs::write(mirror.path().join("other.txt"), "edited in the mirror\n").unwrap();
+    std::fs::write(mirror.path().join("gone.txt"), "x\n").unwrap();
+    write_config(cfg_path, serde_json::json!({"mirror_dir": mirror.path()}));
+    let patch = "*** Begin Patch\n*** Update File: notes.txt\n@@\n-old\n+new\n*** Update File: other.txt\n@@\n-orig\n+changed\n*** Add File: sub/x.txt\n+x\n*** Delete File: gone.txt\n*** End Patch\n";
+    let (code, stdout, stderr) = run_with_stdin({
+        let mut cmd = Command::new(program);
+        cmd.current_dir(work.path()).env("APPLY_PATCH_CONFIG", cfg_path);
+        cmd
+    }, patch);
+    assert_eq!(code, 0, "stderr:\n{stderr}");
+    assert!(stdout.starts_with("Success."), "stdout:\n{stdout}");
+    assert_eq!(std::fs::read_to_string(work.path().join("other.txt")).unwrap(), "changed\n");
+    assert_eq!(std::fs::read_to_string(mirror.path().join("notes.txt")).unwrap(), "new\n");
+    assert_eq!(std::fs::read_to_string(mirror.path().join("sub/x.txt")).unwrap(), "x\n");
+    assert!(!mirror.path().join("gone.txt").exists());
+    assert_eq!(
+        std::fs::read_to_string(mirror.path().join("other.txt")).unwrap(),
+        "edited in the mirror\n"
+    );
+    assert_eq!(
+        stderr,
+        format!(
+            "Warning: mirror {} has diverged from the working tree; not updated.\n1 warning, 0 errors\n",
+            mirror.path().join("other.txt").display()
+        )
+    );
+}
+
+fn assert_patch_cache_replays(program: &Path, cfg_path: &Path) {
+    let work = TempDir::new();
+    let state = TempDir::new();
+    write_config(
+        cfg_path,
+        serde_json::json!({"patch_cache": true, "state_dir": state.path()}),
+    );
+    let reset = || {
+        std::fs::write(work.path().join("notes.txt"), "old\n").unwrap();
+        std::fs::write(work.path().join("gone.txt"), "x\n").unwrap();
+        let _ = std::fs::remove_dir_all(work.path().join("sub"));
+    };
+    let patch = "*** Begin Patch\n*** Update File: notes.txt\n@@\n-old\n+new\n*** Add File: sub/x.txt\n+x\n*** Delete File: gone.txt\n*** End Patch\n";
+    let apply = |patch: &str| {
+        run_with_stdin({
+            let mut cmd = Command::new(program);
+            cmd.current_dir(work.path()).env("APPLY_PATCH_CONFIG", cfg_path);
+            cmd
+        }, patch)
+    };
+    let cache = |action: &str| {
+        run({
+            let mut cmd = Command::new(program);
+            cmd.env("APPLY_PATCH_CONFIG", cfg_path).args(["cache", action]);
+            cmd
+        })
+    };
+
+    reset();
+    let (code, first, stderr) = apply(patch);
+    assert_eq!(code, 0, "stderr:\n{stderr}");
+    let (code, stdout, _) = cache("list");
+    assert_eq!(code, 0);
+    assert!(stdout.contains("  3 files  "), "stdout:\n{stdout}");
+    assert!(stdout.contains("0 hits"), "stdout:\n{stdout}");
+
+    // Same patch, same files (CRLF line endings don't change the key).
+    reset();
+    let (code, second, stderr) = apply(&patch.replace('\n', "\r\n"));
+    assert_eq!(code, 0, "stderr:\n{stderr}");
+    assert_eq!(second, first);
+    assert_eq!(std::fs::read_to_string(work.path().join("notes.txt")).unwrap(), "new\n");
+    assert_eq!(std::fs::read_to_string(work.path().join("sub/x.txt")).unwrap(), "x\n");
+    assert!(!work.path().join("gone.txt").exists());
+    let (_, stdout, _) = cache("list");
+    assert!(stdout.starts_with("1 cached patches"), "stdout:\n{stdout}");
+    assert!(stdout.contains("1 hits"), "stdout:\n{stdout}");
+
+    // A file that changed since means a normal apply, which fails here.
+    reset();
+    std::fs::write(work.path().join("notes.txt"), "drifted\n").unwrap();
+    let (code, _, _) = apply(patch);
+    assert_ne!(code, 0);
+    assert!(cache("list").1.contains("1 hits"));
+
+    let (code, stdout, _) = cache("clear");
+    assert_eq!(code, 0);
+    assert!(stdout.starts_with("Removed 1 cached patches"), "stdout:\n{stdout}");
+    assert!(cache("list").1.starts_with("0 cached patches"));
+    let (code, _, stderr) = cache("prune");
+    assert_eq!(code, 2, "stderr:\n{stderr}");
+}
+
+fn assert_replace_file_sections(program: &Path, cfg_path: &Path) {
+    let work = TempDir::new();
+    std::fs::write(work.path().join("conf.txt"), "a\nb\n  drifted  \nc\n").unwrap();
+    let apply = |patch: &str| {
+        run({
+            let mut cmd = Command::new(program);
+            cmd.current_dir(work.path()).env("APPLY_PATCH_CONFIG", cfg_path).arg(patch);
+            cmd
+        })
+    };
+    let patch = "*** Begin Patch\n*** Replace File: conf.txt\n+x = 1\n+y = 2\n*** End Patch\n";
+
+    write_config(cfg_path, serde_json::json!({}));
+    let (code, stdout, stderr) = apply(patch);
+    assert_eq!(code, 0, "stderr:\n{stderr}");
+    assert_eq!(stdout, "Success. Updated the following files:\nM conf.txt\n");
+    assert_eq!(std::fs::read_to_string(work.path().join("conf.txt")).unwrap(), "x = 1\ny = 2\n");
+
+    let (code, _stdout, stderr) =
+        apply("*** Begin Patch\n*** Replace File: missing.txt\n+x\n*** End Patch\n");
+    assert_eq!(code, 1);
+    assert!(stderr.contains("Invalid Replace File section for missing.txt: no such file"), "stderr:\n{stderr}");
+
+    write_config(cfg_path, serde_json::json!({"replace_file": {"max_bytes": 4}}));
+    let (code, _stdout, stderr) = apply(patch);
+    assert_eq!(code, 1);
+    assert!(
+        stderr.contains("the new content is 12 bytes, more than replace_file.max_bytes (4)"),
+        "stderr:\n{stderr}"
+    );
+
+    write_config(cfg_path, serde_json::json!({"replace_file": {"allow": false}}));
+    let (code, _stdout, stderr) = apply(patch);
+    assert_eq!(code, 1);
+    assert!(stderr.starts_with("Replace File sections are disabled"), "stderr:\n{stderr}");
+    assert_eq!(std::fs::read_to_string(work.path().join("conf.txt")).unwrap(), "x = 1\ny = 2\n");
+    write_config(cfg_path, serde_json::json!({}));
+}
+
+fn assert_parse_error_guidance(program: &Path, cfg_path: &Path) {
+    let work = TempDir::new();
+    std::fs::write(work.path().join("a.txt"), "a\n").unwrap();
+    let apply = |patch: &str| {
+        run({
+            let mut cmd = Command::new(program);
+            cmd.current_dir(work.path()).env("APPLY_PATCH_CONFIG", cfg_path).arg(patch);
+            cmd
+        })
+    };
+    let patch = "*** Begin Patch\n*** Update File: a.txt\n@@\n-a\n+b\nUpdate File: x\n*** End Patch\n";
+
+    write_config(cfg_path, serde_json::json!({}));
+    let (code, _stdout, stderr) = apply(patch);
+    assert_eq!(code, 1);
+    assert!(
+        stderr.contains("\nNOTE TO LLM: the patch could not be parsed (Invalid patch hunk on line 6:"),
+        "stderr:\n{stderr}"
+    );
+    assert!(
+        stderr.contains("\nThe problem is at line 6 of the patch:\nUpdate File: x\n\nA patch must have exactly this shape:\n*** Begin Patch\n"),
+        "stderr:\n{stderr}"
+    );
+    assert_eq!(std::fs::read_to_string(work.path().join("a.txt")).unwrap(), "a\n");
+
+    write_config(
+        cfg_path,
+        serde_json::json!({"parse_error_message": "Bad line {line_number}: `{line}`"}),
+    );
+    let (code, _stdout, stderr) = apply(patch);
+    assert_eq!(code, 1);
+    assert!(stderr.ends_with("\nBad line 6: `Update File: x`\n"), "stderr:\n{stderr}");
+    assert!(!stderr.contains("NOTE TO LLM"), "stderr:\n{stderr}");
+    write_config(cfg_path, serde_json::json!({}));
+}
+
+fn assert_patch_fd_input(program: &Path, cfg_path: &Path) {
+    let work = TempDir::new();
+    std::fs::write(work.path().join("notes.txt"), "old\n").unwrap();
+    let patch_file = work.path().join("change.patch");
+    std::fs::write(&patch_file, update_file_patch("notes.txt", "old", "new")).unwrap();
+    write_config(cfg_path, serde_json::json!({}));
+    let run_fd = |script: &str, stdin: &str| {
+        run_with_stdin({
+            let mut cmd = Command::new("sh");
+            cmd.current_dir(work.path())
+                .env("APPLY_PATCH_CONFIG", cfg_path)
+                .arg("-c")
+                .arg(script)
+                .arg(program)
+                .arg(&patch_file);
+            cmd
+        }, stdin)
+    };
+
+    // Stdin is left alone: it holds something that isn't a patch.
+    let (code, stdout, stderr) =
+        run_fd(r#"echo 'not a patch' | "$0" --patch-fd 3 3<"$1""#, "");
+    assert_eq!(code, 0, "stderr:\n{stderr}");
+    assert_eq!(stdout, "Success. Updated the following files:\nM notes.txt\n");
+    assert_eq!(std::fs::read_to_string(work.path().join("notes.txt")).unwrap(), "new\n");
+
+    let (code, _stdout, stderr) = run_fd(r#"exec "$0" --patch-fd 3 3</dev/null"#, "");
+    assert_eq!(code, 2);
+    assert_eq!(stderr, "Error: no patch on file descriptor 3.\n");
+
+    let (code, _stdout, stderr) = run_fd(r#"exec "$0" --patch-fd three"#, "");
+    assert_eq!(code, 2);
+    assert_eq!(stderr, "Error: invalid patch file descriptor: three\n");
+
+    let (code, _stdout, stderr) = run_fd(r#"exec "$0" --patch-fd 3 PATCH 3<"$1""#, "");
+    assert_eq!(code, 2);
+    assert_eq!(stderr, "Error: --patch-fd cannot be combined with a PATCH argument.\n");
+}
+
+fn assert_init_writes_starter_config(program: &Path) {
+    let home = TempDir::new();
+    let cfg_path = home.path().join("conf").join("config.json");
+    let init = |args: &[&str], stdin: &str| {
+        run_with_stdin({
+            let mut cmd = Command::new(program);
+            cmd.env("APPLY_PATCH_CONFIG", &cfg_path).arg("init").args(args);
+            cmd
+        }, stdin)
+    };
+
+    let (code, stdout, stderr) = init(&["--strict"], "");
+    assert_eq!(code, 0, "stderr:\n{stderr}");
+    assert_eq!(stdout, format!("Wrote a starter config to {}.\n", cfg_path.display()));
+    let cfg: serde_json::Value =
+        serde_json::from_str(&std::fs::read_to_string(&cfg_path).unwrap()).unwrap();
+    assert_eq!(cfg["mode"], "warn");
+    assert_eq!(cfg["escalate"]["to"], "refuse");
+    assert_eq!(cfg["branch_rules"][0]["branch"], "main");
+    assert!(cfg["//mode"].as_str().unwrap().contains("apply, warn"));
+    let (code, stdout, stderr) = run({
+        let mut cmd = Command::new(program);
+        cmd.env("APPLY_PATCH_CONFIG", &cfg_path).arg("--show-config");
+        cmd
+    });
+    assert_eq!(code, 0, "stderr:\n{stderr}");
+    assert!(stdout.contains("mode: warn"), "stdout:\n{stdout}");
+
+    // An existing config is only replaced with --force.
+    let (code, _stdout, stderr) = init(&["--relaxed"], "");
+    assert_eq!(code, 1);
+    assert_eq!(
+        stderr,
+        format!("Error: {} already exists; pass --force to overwrite it.\n", cfg_path.display())
+    );
+    let (code, _stdout, stderr) = init(&["--strict", "--relaxed", "--force"], "");
+    assert_eq!(code, 2);
+    assert_eq!(stderr, "Error: --strict and --relaxed cannot be combined.\n");
+
+    // Without a preset the answers come from stdin; blank keeps the default.
+    let (code, _stdout, stderr) = init(&["--force"], "\nrefuse\nsecrets/**, deploy/*\n\n");
+    assert_eq!(code, 0, "stderr:\n{stderr}");
+    assert!(stderr.starts_with("Preset [strict/recommended/relaxed] (recommended): "), "stderr:\n{stderr}");
+    let cfg: serde_json::Value =
+        serde_json::from_str(&std::fs::read_to_string(&cfg_path).unwrap()).unwrap();
+    assert_eq!(cfg["mode"], "refuse");
+    assert_eq!(cfg["policies"][0]["when"], "files ~ 'secrets/**' || files ~ 'deploy/*'");
+    assert_eq!(cfg["policies"][0]["mode"], "refuse");
+    assert_eq!(cfg["escalate"]["large_patch_lines"], 500);
+    assert_eq!(cfg["notify"]["command"], serde_json::Value::Null);
+}
+
+fn assert_disk_space_precheck(program: &Path, cfg_path: &Path) {
+    let work = TempDir::new();
+    std::fs::write(work.path().join("notes.txt"), "old\n").unwrap();
+    let patch = "*** Begin Patch\n*** Update File: notes.txt\n@@\n-old\n+a much longer line\n*** Add File: sub/new.txt\n+x\n*** End Patch\n";
+    let apply = || {
+        run_with_stdin({
+            let mut cmd = Command::new(program);
+            cmd.current_dir(work.path()).env("APPLY_PATCH_CONFIG", cfg_path);
+            cmd
+        }, patch)
+    };
+
+    // A reserve no filesystem can meet fails the run before anything is written.
+    write_config(cfg_path, serde_json::json!({"disk_space": {"reserve_bytes": 1u64 << 62}}));
+    let (code, stdout, stderr) = apply();
+    assert_eq!(code, 1, "stdout:\n{stdout}");
+    assert!(stderr.starts_with("Error: Not enough disk space on "), "stderr:\n{stderr}");
+    assert!(
+        stderr.contains(": the patch needs 17 more bytes and disk_space.reserve_bytes keeps 4611686018427387904 free, but only "),
+        "stderr:\n{stderr}"
+    );
+    assert!(stderr.contains(" are available. Nothing was changed.\n"), "stderr:\n{stderr}");
+    assert_eq!(std::fs::read_to_string(work.path().join("notes.txt")).unwrap(), "old\n");
+    assert!(!work.path().join("sub").exists());
+
+    // With the check off (or a reserve that fits) the patch applies.
+    write_config(cfg_path, serde_json::json!({"disk_space": {"check": false, "reserve_bytes": 1u64 << 62}}));
+    let (code, stdout, stderr) = apply();
+    assert_eq!(code, 0, "stderr:\n{stderr}");
+    assert_eq!(stdout, "Success. Updated the following files:\nA sub/new.txt\nM notes.txt\n");
+}
+
+fn assert_check_reports_per_file(program: &Path, cfg_path: &Path) {
+    let work = TempDir::new();
+    std::fs::write(work.path().join("lib.rs"), "fn a() {}\nfn b() {}  \nfn c() {}\n").unwrap();
+    std::fs::write(work.path().join("other.rs"), "one\n").unwrap();
+    write_config(cfg_path, serde_json::json!({"mode": "refuse"}));
+    let patch = "*** Begin Patch\n*** Update File: lib.rs\n@@\n-fn a() {}\n+fn a() { 1 }\n@@\n-fn b() {}\n+fn b() { 2 }\n*** Update File: other.rs\n@@\n-two\n+three\n*** Delete File: gone.rs\n*** Add File: new.rs\n+x\n*** End Patch\n";
+    let check = |args: &[&str]| {
+        run_with_stdin({
+            let mut cmd = Command::new(program);
+            cmd.current_dir(work.path()).env("APPLY_PATCH_CONFIG", cfg_path).args(args);
+            cmd
+        }, patch)
+    };
+
+    let (code, stdout, stderr) = check(&["--check", "--format", "json"]);
+    assert_eq!(code, 1, "stderr:\n{stderr}");
+    let report: serde_json::Value = serde_json::from_str(&stdout).unwrap();
+    assert_eq!(report["applies"], false);
+    let files = report["files"].as_array().unwrap();
+    let statuses: Vec<&str> = files.iter().map(|f| f["status"].as_str().unwrap()).collect();
+    assert_eq!(statuses, ["fuzzy", "conflict", "missing-file", "clean"]);
+    assert_eq!(
+        files[0]["hunks"],
+        serde_json::json!([
+            {"hunk": 1, "status": "clean", "line": 1, "old_lines": 1, "new_lines": 1},
+            {"hunk": 2, "status": "fuzzy", "line": 2, "old_lines": 1, "new_lines": 1, "fuzz": "trailing-whitespace"},
+        ])
+    );
+    assert_eq!(files[1]["hunks"][0]["status"], "conflict");
+    assert!(files[1]["hunks"][0].get("line").is_none());
+    assert_eq!(files[3]["kind"], "add");
+    // Nothing was written, and the refuse mode never came into it.
+    assert_eq!(std::fs::read_to_string(work.path().join("lib.rs")).unwrap(), "fn a() {}\nfn b() {}  \nfn c() {}\n");
+    assert!(!work.path().join("new.rs").exists());
+
+    let (code, stdout, _stderr) = check(&["--check"]);
+    assert_eq!(code, 1);
+    assert_eq!(
+        stdout,
+        "fuzzy        lib.rs\nfuzzy        lib.rs @@ 2 (line 2)\nconflict     other.rs\nconflict     other.rs @@ 1\nmissing-file gone.rs\nclean        new.rs\n"
+    );
+
+    let (code, _stdout, stderr) = run({
+        let mut cmd = Command::new(program);
+        cmd.current_dir(work.path()).args(["--format", "json"]);
+        cmd
+    });
+    assert_eq!(code, 2);
+    assert_eq!(stderr, "Error: --format json requires --check.\n");
+}
+
+fn assert_write_strategy(program: &Path, cfg_path: &Path) {
+    use std::os::unix::fs::MetadataExt;
+    let work = TempDir::new();
+    let (single, linked, link) = (
+        work.path().join("single.txt"),
+        work.path().join("linked.txt"),
+        work.path().join("link.txt"),
+    );
+    let patch = "*** Begin Patch\n*** Update File: single.txt\n@@\n-old\n+new\n*** Update File: linked.txt\n@@\n-old\n+new\n*** Add File: added.txt\n+x\n*** End Patch\n";
+    let setup = || {
+        for path in [&single, &linked, &link] {
+            let _ = std::fs::remove_file(path);
+        }
+        let _ = std::fs::remove_file(work.path().join("added.txt"));
+        std::fs::write(&single, "old\n").unwrap();
+        std::fs::write(&linked, "old\n").unwrap();
+        std::fs::hard_link(&linked, &link).unwrap();
+        std::fs::metadata(&single).unwrap().ino()
+    };
+    let apply = || {
+        run({
+            let mut cmd = Command::new(program);
+            cmd.current_dir(work.path()).env("APPLY_PATCH_CONFIG", cfg_path).arg(patch);
+            cmd
+        })
+    };
+
+    // In place (the default): inodes and hard links are kept.
+    write_config(cfg_path, serde_json::json!({}));
+    let inode = setup();
+    let (code, stdout, stderr) = apply();
+    assert_eq!(code, 0, "stderr:\n{stderr}");
+    assert_eq!(stdout, "Success. Updated the following files:\nA added.txt\nM single.txt\nM linked.txt\n");
+    assert_eq!(std::fs::metadata(&single).unwrap().ino(), inode);
+    assert_eq!(std::fs::read_to_string(&link).unwrap(), "new\n");
+
+    // Replace: a new inode, except for the hard-linked file, which is reported.
+    write_config(cfg_path, serde_json::json!({"write_strategy": "replace"}));
+    let inode = setup();
+    let (code, stdout, stderr) = apply();
+    assert_eq!(code, 0, "stderr:\n{stderr}");
+    assert_eq!(
+        stdout,
+        "Success. Updated the following files:\nA added.txt\nM single.txt\nM linked.txt\nWrote linked.txt in place instead of replacing it: it has 2 hard links.\n"
+    );
+    assert_ne!(std::fs::metadata(&single).unwrap().ino(), inode);
+    assert_eq!(std::fs::read_to_string(&single).unwrap(), "new\n");
+    assert_eq!(std::fs::read_to_string(&link).unwrap(), "new\n");
+    assert_eq!(std::fs::read_to_string(work.path().join("added.txt")).unwrap(), "x\n");
+    let leftovers: Vec<_> = std::fs::read_dir(work.path())
+        .unwrap()
+        .flatten()
+        .filter(|entry| entry.file_name().to_string_lossy().ends_with(".tmp"))
+        .collect();
+    assert!(leftovers.is_empty());
+
+    // Temporary files go to staging_dir on the target's filesystem; one that
+    // can't be used is passed over with a warning.
+    let staging = work.path().join("staging");
+    std::fs::create_dir_all(&staging).unwrap();
+    write_config(cfg_path, serde_json::json!({"write_strategy": "replace", "staging_dir": staging}));
+    setup();
+    let (code, _, stderr) = apply();
+    assert_eq!(code, 0, "stderr:\n{stderr}");
+    assert_eq!(stderr, "");
+    assert_eq!(std::fs::read_to_string(&single).unwrap(), "new\n");
+    assert_eq!(std::fs::read_dir(&staging).unwrap().count(), 0);
+    let missing = work.path().join("missing");
+    write_config(cfg_path, serde_json::json!({"write_strategy": "replace", "staging_dir": missing}));
+    setup();
+    let (code, _, stderr) = apply();
+    assert_eq!(code, 0, "stderr:\n{stderr}");
+    assert_eq!(
+        stderr,
+        format!("Warning: staging_dir {} can't be used (No such file or directory (os error 2)); staging elsewhere.\n1 warning, 0 errors\n", missing.display())
+    );
+    assert_eq!(std::fs::read_to_string(&single).unwrap(), "new\n");
+
+    // A warning printed while writing fails --deny-warnings, and the audit
+    // log records the failure rather than an apply.
+    let audit = work.path().join("audit.jsonl");
+    write_config(cfg_path, serde_json::json!({"write_strategy": "replace", "staging_dir": missing, "audit_log": audit}));
+    setup();
+    let (code, _, stderr) = run({
+        let mut cmd = Command::new(program);
+        cmd.current_dir(work.path()).env("APPLY_PATCH_CONFIG", cfg_path).args(["--deny-warnings", patch]);
+        cmd
+    });
+    assert_eq!(code, 1, "stderr:\n{stderr}");
+    assert!(stderr.ends_with("\n1 warning, 1 error; failing because of --deny-warnings\n"), "stderr:\n{stderr}");
+    let entry: serde_json::Value = serde_json::from_str(std::fs::read_to_string(&audit).unwrap().trim()).unwrap();
+    assert_eq!(entry["decision"], "failed");
+}
+
+fn assert_wrap_shim(program: &Path, cfg_path: &Path) {
+    use std::os::unix::fs::PermissionsExt;
+    let home = TempDir::new();
+    let bin = home.path().join("bin");
+    let other = home.path().join("other");
+    std::fs::create_dir_all(&other).unwrap();
+    let foreign = other.join("apply-patch");
+    std::fs::write(&foreign, "#!/bin/sh\necho other tool\n").unwrap();
+    std::fs::set_permissions(&foreign, std::fs::Permissions::from_mode(0o755)).unwrap();
+    let path_var = format!("{}:{}:/usr/bin:/bin", other.display(), bin.display());
+    let wrap_shim = |args: &[&str]| {
+        run({
+            let mut cmd = Command::new(program);
+            cmd.env("PATH", &path_var).arg("wrap-shim").args(args).arg("--dir").arg(&bin);
+            cmd
+        })
+    };
+    let binary = std::fs::canonicalize(program).unwrap();
+
+    // A foreign file in the shim directory is skipped, and the other tool
+    // found first on PATH is reported.
+    std::fs::create_dir_all(&bin).unwrap();
+    std::fs::write(bin.join("applypatch"), "mine\n").unwrap();
+    let (code, stdout, _) = wrap_shim(&["install"]);
+    assert_eq!(code, 1, "stdout:\n{stdout}");
+    assert!(stdout.contains(&format!("Installed {} -> {}", bin.join("apply_patch").display(), binary.display())));
+    assert!(stdout.contains(&format!("Skipped {}: another file is there (pass --force to replace it).", bin.join("applypatch").display())));
+    assert!(stdout.contains(&format!("Conflict: `apply-patch` on PATH is {}, another tool, which runs instead of this binary.", foreign.display())));
+    assert!(!stdout.contains("is not on PATH"));
+    assert_eq!(std::fs::read_to_string(bin.join("applypatch")).unwrap(), "mine\n");
+
+    // The shim runs this binary.
+    let work = TempDir::new();
+    write_config(cfg_path, serde_json::json!({}));
+    let (code, stdout, stderr) = run({
+        let mut cmd = Command::new(bin.join("apply_patch"));
+        cmd.current_dir(work.path())
+            .env("APPLY_PATCH_CONFIG", cfg_path)
+            .arg(add_file_patch("shimmed.txt", &["hi"]));
+        cmd
+    });
+    assert_eq!(code, 0, "stderr:\n{stderr}");
+    assert!(stdout.starts_with("Success."));
+    assert_eq!(std::fs::read_to_string(work.path().join("shimmed.txt")).unwrap(), "hi\n");
+
+    let (code, stdout, _) = wrap_shim(&["status"]);
+    assert_eq!(code, 0);
+    assert!(stdout.contains(&format!("{}: shim\n", bin.join("apply_patch").display())));
+    assert!(stdout.contains(&format!("{}: another file\n", bin.join("applypatch").display())));
+
+    // --force replaces it; uninstall removes only shims.
+    let (code, stdout, _) = wrap_shim(&["install", "--force"]);
+    assert_eq!(code, 0, "stdout:\n{stdout}");
+    std::fs::write(bin.join("apply-patch"), "mine\n").unwrap();
+    let (code, stdout, _) = wrap_shim(&["uninstall"]);
+    assert_eq!(code, 0);
+    assert!(stdout.contains(&format!("Removed {}", bin.join("applypatch").display())));
+    assert!(stdout.contains(&format!("Left {}: it is not an apply_patch shim.", bin.join("apply-patch").display())));
+    assert!(!bin.join("apply_patch").exists() && !bin.join("applypatch").exists());
+    assert!(bin.join("apply-patch").exists());
+
+    let (code, _, stderr) = wrap_shim(&["reinstall"]);
+    assert_eq!(code, 2);
+    assert!(stderr.starts_with("Usage: apply_patch wrap-shim"));
+}
+
+fn assert_batch_applies_in_order(program: &Path, cfg_path: &Path) {
+    write_config(cfg_path, serde_json::json!({}));
+    let work = TempDir::new();
+    std::fs::create_dir_all(work.path().join("a")).unwrap();
+    std::fs::create_dir_all(work.path().join("b")).unwrap();
+    std::fs::write(work.path().join("01.patch"), add_file_patch("one.txt", &["1"])).unwrap();
+    let manifest = work.path().join("batch.json");
+    std::fs::write(
+        &manifest,
+        serde_json::json!({
+            "patches": [
+                { "patch": "01.patch", "cwd": "a" },
+                { "name": "broken", "patch_text": update_file_patch("missing.txt", "x", "y"), "cwd": "b" },
+                { "name": "last", "patch_text": add_file_patch("three.txt", &["3"]), "cwd": "b" }
+            ]
+        })
+        .to_string(),
+    )
+    .unwrap();
+    let batch = |args: &[&str]| {
+        run({
+            let mut cmd = Command::new(program);
+            cmd.env("APPLY_PATCH_CONFIG", cfg_path).arg("batch").args(args).arg(&manifest);
+            cmd
+        })
+    };
+
+    // Fail fast (the default): the entry after the failure is skipped.
+    let (code, stdout, stderr) = batch(&[]);
+    assert_eq!(code, 1, "stdout:\n{stdout}\nstderr:\n{stderr}");
+    let a = work.path().join("a");
+    let b = work.path().join("b");
+    assert!(stdout.contains(&format!("== [1/3] 01.patch in {}: applied\n", a.display())));
+    assert!(stdout.contains(&format!("== [2/3] broken in {}: failed (exit 1)\n", b.display())));
+    assert!(stdout.contains(&format!("== [3/3] last in {}: skipped\n", b.display())));
+    assert!(stdout.ends_with("Batch: 1 applied, 0 refused, 1 failed, 1 skipped.\n"));
+    assert_eq!(std::fs::read_to_string(a.join("one.txt")).unwrap(), "1\n");
+    assert!(!b.join("three.txt").exists());
+
+    // Continue on error, with the JSON report.
+    std::fs::remove_file(a.join("one.txt")).unwrap();
+    let (code, stdout, _) = batch(&["--continue-on-error", "--json"]);
+    assert_eq!(code, 1);
+    let report: serde_json::Value = serde_json::from_str(&stdout).unwrap();
+    assert_eq!(report["ok"], false);
+    let statuses: Vec<&str> = report["patches"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|p| p["status"].as_str().unwrap())
+        .collect();
+    assert_eq!(statuses, ["applied", "failed", "applied"]);
+    assert!(report["patches"][1]["stderr"].as_str().unwrap().contains("missing.txt"));
+    assert_eq!(std::fs::read_to_string(b.join("three.txt")).unwrap(), "3\n");
+
+    // A YAML manifest; a refusal is reported as such and stops the batch.
+    std::fs::remove_file(b.join("three.txt")).unwrap();
+    std::fs::write(
+        &manifest,
+        format!(
+            "# replayed session\non_error: stop\npatches:\n  - name: refused\n    args: [--expected-files, other.txt]\n    patch_text: |\n{}  - name: 'last'\n    cwd: b\n    patch: \"01.patch\"\n",
+            add_file_patch("two.txt", &["2"]).lines().map(|line| format!("      {line}\n")).collect::<String>()
+        ),
+    )
+    .unwrap();
+    let (code, stdout, stderr) = batch(&[]);
+    assert_eq!(code, 1, "stdout:\n{stdout}\nstderr:\n{stderr}");
+    assert!(stdout.contains(&format!("== [1/2] refused in {}: refused\n", work.path().display())), "{stdout}");
+    assert!(stdout.ends_with("Batch: 0 applied, 1 refused, 0 failed, 1 skipped.\n"));
+    assert!(!work.path().join("two.txt").exists());
+    let (code, _, _) = run({
+        let mut cmd = Command::new(program);
+        cmd.env("APPLY_PATCH_CONFIG", cfg_path)
+            .env("APPLY_PATCH_EXIT_CODES", "detailed")
+            .args(["batch", "--continue-on-error"])
+            .arg(&manifest);
+        cmd
+    });
+    assert_eq!(code, 6);
+    assert_eq!(std::fs::read_to_string(b.join("one.txt")).unwrap(), "1\n");
+    std::fs::write(&manifest, "patches:\n\t- patch: 01.patch\n").unwrap();
+    let (code, _, stderr) = batch(&[]);
+    assert_eq!(code, 2);
+    assert!(stderr.contains("line 2: tabs can't indent YAML"), "{stderr}");
+
+    std::fs::write(&manifest, r#"{"patches": [{"patch": "01.patch", "patch_text": "x"}]}"#).unwrap();
+    let (code, stdout, _) = batch(&[]);
+    assert_eq!(code, 2);
+    assert!(stdout.contains("Error: each batch entry needs exactly one of `patch` and `patch_text`."));
+    std::fs::write(&manifest, r#"{"patchez": []}"#).unwrap();
+    let (code, _, stderr) = batch(&[]);
+    assert_eq!(code, 2);
+    assert!(stderr.starts_with("Error: invalid batch manifest"));
+}
+
+fn assert_concise_output(program: &Path, cfg_path: &Path) {
+    let work = TempDir::new();
+    std::fs::write(work.path().join("b.txt"), "old\n").unwrap();
+    let apply = |args: &[&str], patch: &str| {
+        run({
+            let mut cmd = Command::new(program);
+            cmd.current_dir(work.path())
+                .env("APPLY_PATCH_CONFIG", cfg_path)
+                .args(args)
+                .arg(patch);
+            cmd
+        })
+    };
+    let patch = "*** Begin Patch\n*** Add File: a.txt\n+x\n*** Update File: b.txt\n@@\n-old\n+new\n*** End Patch\n";
+
+    // --concise: a one-line summary and the warn message cut to the budget.
+    write_config(
+        cfg_path,
+        serde_json::json!({
+            "mode": "warn",
+            "warn_message": "Please use your native editing tool instead of apply_patch next time.",
+            "concise": { "message_bytes": 24 }
+        }),
+    );
+    let (code, stdout, stderr) = apply(&["--concise"], patch);
+    assert_eq!(code, 0, "stderr:\n{stderr}");
+    assert_eq!(stdout, "Success. A a.txt, M b.txt\nPlease use your…\n");
+
+    // Enabled in the config; messages within the budget are left alone.
+    write_config(
+        cfg_path,
+        serde_json::json!({ "apply_message": "Applied.", "concise": { "enabled": true } }),
+    );
+    let (code, stdout, stderr) = apply(&[], &update_file_patch("b.txt", "new", "newer"));
+    assert_eq!(code, 0, "stderr:\n{stderr}");
+    assert_eq!(stdout, "Success. M b.txt\nApplied.\n");
+
+    let (code, stdout, _) = run({
+        let mut cmd = Command::new(program);
+        cmd.env("APPLY_PATCH_CONFIG", cfg_path).arg("--show-config");
+        cmd
+    });
+    assert_eq!(code, 0);
+    assert!(stdout.contains("concise: messages up to 200 bytes\n"));
+}
+
+fn assert_final_newline(program: &Path, cfg_path: &Path) {
+    let work = TempDir::new();
+    let (bare, ended) = (work.path().join("bare.txt"), work.path().join("ended.txt"));
+    let patch = "*** Begin Patch\n*** Update File: bare.txt\n@@\n-old\n+new\n*** Update File: ended.txt\n@@\n-old\n+new\n*** End Patch\n";
+    let apply = |cfg: serde_json::Value| {
+        write_config(cfg_path, cfg);
+        std::fs::write(&bare, "keep\nold").unwrap();
+        std::fs::write(&ended, "keep\nold\n").unwrap();
+        run({
+            let mut cmd = Command::new(program);
+            cmd.current_dir(work.path()).env("APPLY_PATCH_CONFIG", cfg_path).arg(patch);
+            cmd
+        })
+    };
+
+    // Preserve (the default): each file keeps its ending.
+    let (code, stdout, stderr) = apply(serde_json::json!({}));
+    assert_eq!(code, 0, "stderr:\n{stderr}");
+    assert_eq!(stdout, "Success. Updated the following files:\nM bare.txt\nM ended.txt\n");
+    assert_eq!(std::fs::read_to_string(&bare).unwrap(), "keep\nnew");
+    assert_eq!(std::fs::read_to_string(&ended).unwrap(), "keep\nnew\n");
+
+    let (code, _, stderr) = apply(serde_json::json!({"final_newline": "always"}));
+    assert_eq!(code, 0, "stderr:\n{stderr}");
+    assert_eq!(std::fs::read_to_string(&bare).unwrap(), "keep\nnew\n");
+
+    // Never: the file that loses its newline is reported.
+    let (code, stdout, stderr) = apply(serde_json::json!({"final_newline": "never"}));
+    assert_eq!(code, 0, "stderr:\n{stderr}");
+    assert!(stdout.ends_with("ended.txt no longer ends with a newline (final_newline: never).\n"));
+    assert!(!stdout.contains("bare.txt no longer"));
+    assert_eq!(std::fs::read_to_string(&bare).unwrap(), "keep\nnew");
+    assert_eq!(std::fs::read_to_string(&ended).unwrap(), "keep\nnew");
+}
+
+fn assert_git_apply_fallback(program: &Path, cfg_path: &Path) {
+    let work = TempDir::new();
+    let target = work.path().join("f.txt");
+    // The hunk's first context line has drifted ("b" became "B").
+    let patch = "*** Begin Patch\n*** Update File: f.txt\n@@\n b\n c\n-d\n+D\n e\n*** Add File: new.txt\n+n\n*** End Patch\n";
+    let apply = |cfg: serde_json::Value| {
+        write_config(cfg_path, cfg);
+        std::fs::write(&target, "a\nB\nc\nd\ne\nf\n").unwrap();
+        let _ = std::fs::remove_file(work.path().join("new.txt"));
+        run({
+            let mut cmd = Command::new(program);
+            cmd.current_dir(work.path()).env("APPLY_PATCH_CONFIG", cfg_path).arg(patch);
+            cmd
+        })
+    };
+
+    let (code, _, stderr) = apply(serde_json::json!({}));
+    assert_eq!(code, 1);
+    assert!(stderr.contains("Failed to find expected lines"), "stderr:\n{stderr}");
+    assert_eq!(std::fs::read_to_string(&target).unwrap(), "a\nB\nc\nd\ne\nf\n");
+
+    let (code, stdout, stderr) = apply(serde_json::json!({"fallback": "git-apply"}));
+    assert_eq!(code, 0, "stderr:\n{stderr}");
+    assert!(stdout.starts_with("Success. Updated the following files:\nA new.txt\nM f.txt\nApplied with git apply; the built-in applier couldn't: Failed to find expected lines in f.txt:\n"), "stdout:\n{stdout}");
+    assert_eq!(std::fs::read_to_string(&target).unwrap(), "a\nB\nc\nD\ne\nf\n");
+    assert_eq!(std::fs::read_to_string(work.path().join("new.txt")).unwrap(), "n\n");
+
+    // When git can't place it either, both engines' errors are reported and
+    // nothing is written.
+    let unplaceable = "*** Begin Patch\n*** Update File: f.txt\n@@\n-zzz\n+y\n*** End Patch\n";
+    let (code, _, stderr) = run({
+        let mut cmd = Command::new(program);
+        cmd.current_dir(work.path()).env("APPLY_PATCH_CONFIG", cfg_path).arg(unplaceable);
+        cmd
+    });
+    assert_eq!(code, 1);
+    assert!(stderr.contains("Failed to find expected lines"));
+    assert!(stderr.contains("git apply couldn't apply it either:"), "stderr:\n{stderr}");
+    assert_eq!(std::fs::read_to_string(&target).unwrap(), "a\nB\nc\nD\ne\nf\n");
+    assert_three_way(program, cfg_path);
+}
+
+fn assert_three_way(program: &Path, cfg_path: &Path) {
+    let work = TempDir::new();
+    let repo = work.path();
+    git(repo, &["init", "--quiet"]);
+    std::fs::create_dir(repo.join("src")).unwrap();
+    std::fs::write(repo.join("src/f.txt"), "a\nb\nc\nd\ne\nf\ng\n").unwrap();
+    std::fs::write(repo.join("src/g.txt"), "1\n2\n3\n").unwrap();
+    git(repo, &["add", "-A"]);
+    git(repo, &["commit", "--quiet", "-m", "init"]);
+    write_config(cfg_path, serde_json::json!({}));
+    let apply = |args: &[&str]| {
+        run({
+            let mut cmd = Command::new(program);
+            cmd.current_dir(repo.join("src")).env("APPLY_PATCH_CONFIG", cfg_path).args(args);
+            cmd
+        })
+    };
+    // Written against the committed f.txt; "b" has been edited since, and
+    // it is the patch's context, so neither applier can place the hunk.
+    let patch = "*** Begin Patch\n*** Update File: f.txt\n@@\n b\n c\n d\n-e\n+E\n*** End Patch\n";
+    std::fs::write(repo.join("src/f.txt"), "a\nB\nc\nd\ne\nf\ng\n").unwrap();
+    let (code, _, stderr) = apply(&[patch]);
+    assert_eq!(code, 1, "stderr:\n{stderr}");
+
+    let (code, stdout, stderr) = apply(&["--3way", patch]);
+    assert_eq!(code, 0, "stderr:\n{stderr}");
+    assert!(
+        stdout.starts_with("Success. Updated the following files:\nM f.txt\nMerged with the index versions as the base (--3way); the built-in applier couldn't: Failed to find expected lines in f.txt:\n"),
+        "stdout:\n{stdout}"
+    );
+    assert_eq!(std::fs::read_to_string(repo.join("src/f.txt")).unwrap(), "a\nB\nc\nd\nE\nf\ng\n");
+    assert!(git(repo, &["diff", "--cached", "--name-only"]).is_empty());
+
+    // Only lines both sides changed become conflicts.
+    std::fs::write(repo.join("src/g.txt"), "1\nmine\n3\n").unwrap();
+    let conflicting = "*** Begin Patch\n*** Update File: g.txt\n@@\n 1\n-2\n+theirs\n 3\n*** End Patch\n";
+    let (code, stdout, stderr) = apply(&["--3way", conflicting]);
+    assert_eq!(code, 0, "stderr:\n{stderr}");
+    assert!(stdout.contains("Merged with the index versions as the base (--3way)"), "stdout:\n{stdout}");
+    assert!(
+        stderr.contains("Warning: g.txt: 1 conflict(s) with the working tree, left between <<<<<<< and >>>>>>> markers.\n"),
+        "stderr:\n{stderr}"
+    );
+    assert_eq!(
+        std::fs::read_to_string(repo.join("src/g.txt")).unwrap(),
+        "1\n<<<<<<< g.txt\nmine\n=======\ntheirs\n>>>>>>> patch\n3\n"
+    );
+
+    // A file git doesn't track has no base.
+    std::fs::write(repo.join("src/u.txt"), "x\ny\n").unwrap();
+    let (code, _, stderr) = apply(&["--3way", &update_file_patch("u.txt", "nope", "z")]);
+    assert_eq!(code, 1);
+    assert!(stderr.contains("git apply couldn't apply it to the index versions either:"), "stderr:\n{stderr}");
+    assert_eq!(std::fs::read_to_string(repo.join("src/u.txt")).unwrap(), "x\ny\n");
+}
+
+fn assert_target_file_size_limit(program: &Path, cfg_path: &Path) {
+    let work = TempDir::new();
+    std::fs::write(work.path().join("big.txt"), "0123456789\nold\n").unwrap();
+    let apply = |patch: &str| {
+        run({
+            let mut cmd = Command::new(program);
+            cmd.current_dir(work.path()).env("APPLY_PATCH_CONFIG", cfg_path).arg(patch);
+            cmd
+        })
+    };
+    write_config(cfg_path, serde_json::json!({"limits": {"max_target_file_bytes": 10}}));
+    let (code, stdout, stderr) = apply(&update_file_patch("big.txt", "old", "new"));
+    assert_eq!(code, 0, "stderr:\n{stderr}");
+    assert!(
+        stdout.starts_with(
+            "Target file size (refuse mode): big.txt is 15 bytes, more than limits.max_target_file_bytes (10); edit it another way.\nNOTE TO LLM:\n"
+        ),
+        "stdout:\n{stdout}"
+    );
+    assert_eq!(std::fs::read_to_string(work.path().join("big.txt")).unwrap(), "0123456789\nold\n");
+    let audit_log = work.path().join("audit.jsonl");
+    write_config(
+        cfg_path,
+        serde_json::json!({
+            "mode": "warn",
+            "refusal_reason_line": true,
+            "audit_log": audit_log,
+            "limits": {"max_target_file_bytes": 10},
+        }),
+    );
+    let (code, stdout, _) = apply(&update_file_patch("big.txt", "old", "new"));
+    assert_eq!(code, 0);
+    assert!(stdout.starts_with("REFUSED: too_large big.txt\n"), "stdout:\n{stdout}");
+    let entry: serde_json::Value =
+        serde_json::from_str(std::fs::read_to_string(&audit_log).unwrap().lines().last().unwrap()).unwrap();
+    assert_eq!(entry["decision"], "refused");
+    assert_eq!(entry["reason"], serde_json::json!({"code": "too_large", "detail": "big.txt"}));
+    assert_eq!(entry["target_size"]["files"][0]["bytes"], 15);
+    write_config(cfg_path, serde_json::json!({"limits": {"max_target_file_bytes": 10}}));
+
+    // New files aren't targets.
+    let (code, _, stderr) = apply(&add_file_patch("other.txt", &["0123456789", "more"]));
+    assert_eq!(code, 0, "stderr:\n{stderr}");
+
+    write_config(cfg_path, serde_json::json!({"limits": {"max_target_file_bytes": null}}));
+    let (code, _, stderr) = apply(&update_file_patch("big.txt", "old", "new"));
+    assert_eq!(code, 0, "stderr:\n{stderr}");
+    let (_, stdout, _) = run({
+        let mut cmd = Command::new(program);
+        cmd.env("APPLY_PATCH_CONFIG", cfg_path).arg("--show-config");
+        cmd
+    });
+    assert!(stdout.contains("max_target_file_bytes: unlimited\n"));
+
+    // Above stream_above_bytes the patch is applied a line at a time, with
+    // the same matching, output and result as the applier.
+    let files = [
+        ("s.txt", "fn a() {\n    one  \n}\nfn b() {\n    one\n}\n"),
+        ("t.txt", "x\ny\nz"),
+        ("gone.txt", "bye\n"),
+    ];
+    let patch = "*** Begin Patch\n*** Update File: s.txt\n*** Move to: moved/s.txt\n@@\n fn a() {\n-    one\n+    uno\n@@ fn b() {\n-    one\n+    two\n*** Update File: t.txt\n@@\n y\n-z\n+zz\n+\n*** End of File\n*** Delete File: gone.txt\n*** Add File: new.txt\n+fresh\n*** End Patch";
+    let mut results = Vec::new();
+    for stream_above in [serde_json::json!(0), serde_json::json!(null)] {
+        let dir = TempDir::new();
+        for (name, text) in files {
+            std::fs::write(dir.path().join(name), text).unwrap();
+        }
+        write_config(cfg_path, serde_json::json!({"limits": {"stream_above_bytes": stream_above}}));
+        let apply_here = |args: &[&str]| {
+            run({
+                let mut cmd = Command::new(program);
+                cmd.current_dir(dir.path()).env("APPLY_PATCH_CONFIG", cfg_path).args(args);
+                cmd
+            })
+        };
+        let dry = apply_here(&["--dry-run", patch]);
+        let applied = apply_here(&[patch]);
+        let read = |name: &str| std::fs::read_to_string(dir.path().join(name)).ok();
+        let contents = ["s.txt", "moved/s.txt", "t.txt", "gone.txt", "new.txt"].map(read);
+        let missing = apply_here(&[&update_file_patch("t.txt", "nowhere", "new")]);
+        assert_eq!(read("t.txt"), contents[2]);
+        results.push((dry, applied, contents, missing));
+    }
+    assert_eq!(results[0].1.0, 0, "stderr:\n{}", results[0].1.2);
+    assert_eq!(results[0].2[1].as_deref(), Some("fn a() {\n    uno\n}\nfn b() {\n    two\n}\n"));
+    assert_eq!(results[0].2[2].as_deref(), Some("x\ny\nzz"));
+    assert_eq!(results[0].3.0, 1);
+    assert_eq!(results[0], results[1]);
+    let (_, stdout, _) = run({
+        let mut cmd = Command::new(program);
+        cmd.env("APPLY_PATCH_CONFIG", cfg_path).arg("--show-config");
+        cmd
+    });
+    assert!(stdout.contains("stream_above_bytes: never\n"), "stdout:\n{stdout}");
+
+    // Streamed writes follow write_strategy: in place by default, so a hard
+    // link sees the new contents.
+    std::fs::write(work.path().join("linked.txt"), "one\n").unwrap();
+    std::fs::hard_link(work.path().join("linked.txt"), work.path().join("link.txt")).unwrap();
+    write_config(cfg_path, serde_json::json!({"limits": {"stream_above_bytes": 0}}));
+    let (code, _, stderr) = apply(&update_file_patch("linked.txt", "one", "two"));
+    assert_eq!(code, 0, "stderr:\n{stderr}");
+    assert_eq!(std::fs::read_to_string(work.path().join("link.txt")).unwrap(), "two\n");
+    write_config(
+        cfg_path,
+        serde_json::json!({"limits": {"stream_above_bytes": 0}, "write_strategy": "replace"}),
+    );
+    let (code, stdout, _) = apply(&update_file_patch("linked.txt", "two", "three"));
+    assert_eq!(code, 0);
+    assert_eq!(
+        stdout,
+        "Success. Updated the following files:\nM linked.txt\nWrote linked.txt in place instead of replacing it: it has 2 hard links.\n"
+    );
+    assert_eq!(std::fs::read_to_string(work.path().join("link.txt")).unwrap(), "three\n");
+}
+
+fn assert_localized_messages(program: &Path, cfg_path: &Path) {
+    let work = TempDir::new();
+    let apply = |lang: &str, name: &str| {
+        run({
+            let mut cmd = Command::new(program);
+            cmd.current_dir(work.path())
+                .env("APPLY_PATCH_CONFIG", cfg_path)
+                .env_remove("LC_ALL")
+                .env_remove("LC_MESSAGES")
+                .env("LANG", lang)
+                .arg(add_file_patch(name, &["x"]));
+            cmd
+        })
+    };
+    let mut cfg = serde_json::json!({
+        "mode": "warn",
+        "warn_message": "Use your editor ({file_count} file).",
+        "locales": {
+            "de": { "warn_message": "Nutze deinen Editor ({file_count} Datei)." },
+            "pt_BR": { "warn_message": "Use seu editor." }
+        }
+    });
+    write_config(cfg_path, cfg.clone());
+
+    let (code, stdout, stderr) = apply("de_DE.UTF-8", "a.txt");
+    assert_eq!(code, 0, "stderr:\n{stderr}");
+    assert!(stdout.ends_with("Nutze deinen Editor (1 Datei).\n"), "stdout:\n{stdout}");
+    let (_, stdout, _) = apply("pt_BR.UTF-8", "b.txt");
+    assert!(stdout.ends_with("Use seu editor.\n"));
+    let (_, stdout, _) = apply("fr_FR.UTF-8", "c.txt");
+    assert!(stdout.ends_with("Use your editor (1 file).\n"));
+
+    // output.locale wins over the environment.
+    cfg["output"] = serde_json::json!({ "locale": "de" });
+    write_config(cfg_path, cfg);
+    let (_, stdout, _) = apply("en_US.UTF-8", "d.txt");
+    assert!(stdout.ends_with("Nutze deinen Editor (1 Datei).\n"));
+    let (_, stdout, _) = run({
+        let mut cmd = Command::new(program);
+        cmd.env("APPLY_PATCH_CONFIG", cfg_path).arg("--show-config");
+        cmd
+    });
+    assert!(stdout.contains("locale: de (messages from locales.de)\nrefuse_message: default\nwarn_message: custom\n"));
+}
+
+fn assert_open_pr_pushes_and_creates(program: &Path, cfg_path: &Path) {
+    use std::os::unix::fs::PermissionsExt;
+    let work = TempDir::new();
+    let repo = work.path().join("repo");
+    let remote = work.path().join("remote.git");
+    let bin = work.path().join("bin");
+    std::fs::create_dir_all(&repo).unwrap();
+    std::fs::create_dir_all(&bin).unwrap();
+    git(work.path(), &["init", "--quiet", "--bare", remote.to_str().unwrap()]);
+    git(&repo, &["init", "--quiet", "-b", "main"]);
+    std::fs::write(repo.join("lib.rs"), "old\n").unwrap();
+    git(&repo, &["add", "-A"]);
+    git(&repo, &["commit", "--quiet", "-m", "init"]);
+    git(&repo, &["remote", "add", "origin", remote.to_str().unwrap()]);
+
+    // A stand-in for gh that records how it was called.
+    let calls = work.path().join("gh-calls.txt");
+    let gh = bin.join("gh");
+    std::fs::write(
+        &gh,
+        format!(
+            "#!/bin/sh\nprintf '%s\\n' \"token=$GH_TOKEN\" \"$@\" > '{}'\necho https://github.com/acme/app/pull/7\n",
+            calls.display()
+        ),
+    )
+    .unwrap();
+    std::fs::set_permissions(&gh, std::fs::Permissions::from_mode(0o755)).unwrap();
+    let path_var = format!("{}:{}", bin.display(), std::env::var("PATH").unwrap_or_default());
+    write_config(
+        cfg_path,
+        serde_json::json!({"pull_request": {"token_env": "AGENT_GH_TOKEN", "draft": true}}),
+    );
+    let open_pr = |branch: &str, patch: &str| {
+        run({
+            let mut cmd = Command::new(program);
+            cmd.current_dir(&repo)
+                .env("APPLY_PATCH_CONFIG", cfg_path)
+                .env("PATH", &path_var)
+                .env("AGENT_GH_TOKEN", "secret")
+                .args(["--to-branch", branch, "--open-pr"])
+                .arg(patch);
+            cmd
+        })
+    };
+
+    let (code, stdout, stderr) = open_pr("agent/pr", &update_file_patch("lib.rs", "old", "new"));
+    assert_eq!(code, 0, "stderr:\n{stderr}");
+    assert!(stdout.ends_with("to branch agent/pr.\nOpened pull request: https://github.com/acme/app/pull/7\n"), "stdout:\n{stdout}");
+    assert_eq!(git(&remote, &["show", "agent/pr:lib.rs"]), "new\n");
+    assert_eq!(
+        std::fs::read_to_string(&calls).unwrap(),
+        "token=secret\npr\ncreate\n--head\nagent/pr\n--base\nmain\n--title\nApply patch to 1 file(s)\n--body\nM lib.rs (+1 -1)\n--draft\n"
+    );
+
+    // A failed push keeps the branch and its commit, and exits 1.
+    git(&repo, &["remote", "set-url", "origin", work.path().join("gone.git").to_str().unwrap()]);
+    let (code, _, stderr) = open_pr("agent/nopush", &update_file_patch("lib.rs", "old", "newer"));
+    assert_eq!(code, 1);
+    assert!(stderr.starts_with("Error: could not push agent/nopush to origin:"), "stderr:\n{stderr}");
+    assert_eq!(git(&repo, &["show", "agent/nopush:lib.rs"]), "newer\n");
+
+    let (code, _, stderr) = run({
+        let mut cmd = Command::new(program);
+        cmd.current_dir(&repo).env("APPLY_PATCH_CONFIG", cfg_path).arg("--open-pr");
+        cmd
+    });
+    assert_eq!(code, 2);
+    assert_eq!(stderr, "Error: --open-pr requires --to-branch.\n");
+}
+
+fn assert_no_config_is_hermetic(program: &Path, cfg_path: &Path) {
+    let work = TempDir::new();
+    std::fs::write(work.path().join("lib.rs"), "old\n").unwrap();
+    write_config(cfg_path, serde_json::json!({"mode": "refuse", "refuse_message": "Refused here."}));
+    let apply = |extra: &[&str], env: &[(&str, &str)], patch: &str| {
+        run({
+            let mut cmd = Command::new(program);
+            cmd.current_dir(work.path())
+                .env("APPLY_PATCH_CONFIG", cfg_path)
+                .env("APPLY_PATCH_CWD", "/nonexistent")
+                .env("APPLY_PATCH_TENANT", "not a tenant")
+                .envs(env.iter().copied())
+                .args(extra)
+                .arg(patch);
+            cmd
+        })
+    };
+
+    // The config would refuse, and the environment overrides are invalid.
+    let (code, _, _) = apply(&[], &[], &update_file_patch("lib.rs", "old", "new"));
+    assert_ne!(code, 0);
+
+    let (code, stdout, stderr) = apply(&["--no-config"], &[], &update_file_patch("lib.rs", "old", "new"));
+    assert_eq!(code, 0, "stderr:\n{stderr}");
+    assert_eq!(stdout, "Success. Updated the following files:\nM lib.rs\n");
+    let (code, stdout, stderr) = apply(
+        &["--concise"],
+        &[("APPLY_PATCH_NO_CONFIG", "1")],
+        &update_file_patch("lib.rs", "new", "newer"),
+    );
+    assert_eq!(code, 0, "stderr:\n{stderr}");
+    assert_eq!(stdout, "Success. M lib.rs\n");
+    assert_eq!(std::fs::read_to_string(work.path().join("lib.rs")).unwrap(), "newer\n");
+
+    let (code, _, stderr) = run({
+        let mut cmd = Command::new(program);
+        cmd.env("APPLY_PATCH_CONFIG", cfg_path).args(["--no-config", "--show-config"]);
+        cmd
+    });
+    assert_eq!(code, 2);
+    assert_eq!(stderr, "Error: --no-config cannot be combined with configuration flags.\n");
+}
+
+fn assert_redact_replaces_literals(program: &Path, cfg_path: &Path) {
+    let work = TempDir::new();
+    write_config(cfg_path, serde_json::json!({"redact": {"patterns": ["acme_[a-z]+"]}}));
+    let patch = "*** Begin Patch\n*** Update File: src/lib.rs\n@@ fn acme_key<'a>()\n-    let key = \"hunter2\"; // don't\n+    let key = \"hunter3\";\n+    notify(\"ops@acme.io\", 'x');\n     log(\"hunter2\", acme_secret);\n*** End Patch\n";
+    let patch_file = work.path().join("bug.patch");
+    std::fs::write(&patch_file, patch).unwrap();
+    let redact = |args: &[&str]| {
+        run({
+            let mut cmd = Command::new(program);
+            cmd.current_dir(work.path())
+                .env("APPLY_PATCH_CONFIG", cfg_path)
+                .arg("redact")
+                .args(args);
+            cmd
+        })
+    };
+
+    let (code, stdout, stderr) = redact(&["--pattern", "hunter\\d", "bug.patch"]);
+    assert_eq!(code, 0, "stderr:\n{stderr}");
+    assert_eq!(
+        stdout,
+        "*** Begin Patch\n*** Update File: src/lib.rs\n@@ fn REDACTED1<'a>()\n-    let key = \"s1\"; // don't\n+    let key = \"s2\";\n+    notify(\"s3\", 's4');\n     log(\"s1\", REDACTED4);\n*** End Patch\n"
+    );
+    assert_eq!(stderr, "Redacted 4 string literal(s), 1 email address(es) and 4 pattern match(es).\n");
+
+    // Outside string literals an address keeps its shape.
+    std::fs::write(&patch_file, "*** Begin Patch\n*** Add File: AUTHORS\n+Jo <jo@acme.io>\n*** End Patch\n").unwrap();
+    let (code, stdout, _) = redact(&["--out", "safe.patch", "bug.patch"]);
+    assert_eq!(code, 0);
+    assert_eq!(stdout, "Redacted 0 string literal(s), 1 email address(es) and 0 pattern match(es). Wrote safe.patch.\n");
+    assert_eq!(
+        std::fs::read_to_string(work.path().join("safe.patch")).unwrap(),
+        "*** Begin Patch\n*** Add File: AUTHORS\n+Jo <user1@example.com>\n*** End Patch\n"
+    );
+
+    let (code, _, stderr) = redact(&["--pattern", "a{2,1}", "bug.patch"]);
+    assert_eq!(code, 2);
+    assert_eq!(stderr, "Error: invalid redact pattern `a{2,1}`: repetition maximum is below its minimum\n");
+}
+
+fn assert_format_on_apply(program: &Path, cfg_path: &Path) {
+    let work = TempDir::new();
+    std::fs::write(work.path().join("lib.rs"), "old\n").unwrap();
+    write_config(
+        cfg_path,
+        serde_json::json!({"format_on_apply": {
+            "*.rs": "sed -i 's/  */ /g'",
+            "*.md": "false",
+            "*.txt": "printf '%s\\n' >> args.log",
+        }}),
+    );
+    let apply = |patch: &str| {
+        run({
+            let mut cmd = Command::new(program);
+            cmd.current_dir(work.path()).env("APPLY_PATCH_CONFIG", cfg_path).arg(patch);
+            cmd
+        })
+    };
+
+    let (code, stdout, stderr) = apply(&update_file_patch("lib.rs", "old", "fn   main()  {}"));
+    assert_eq!(code, 0, "stderr:\n{stderr}");
+    assert_eq!(stdout, "Success. Updated the following files:\nM lib.rs\nFormatted lib.rs with `sed -i 's/  */ /g'`.\n");
+    assert_eq!(std::fs::read_to_string(work.path().join("lib.rs")).unwrap(), "fn main() {}\n");
+
+    // A failing formatter is a warning; the patch stays applied.
+    let (code, _, stderr) = apply(&add_file_patch("docs/notes.md", &["# Notes"]));
+    assert_eq!(code, 0, "stderr:\n{stderr}");
+    assert!(
+        stderr.starts_with("Warning: format_on_apply `false` failed on docs/notes.md (exit status: 1)\n"),
+        "stderr:\n{stderr}"
+    );
+    assert_eq!(std::fs::read_to_string(work.path().join("docs/notes.md")).unwrap(), "# Notes\n");
+
+    // Paths reach the formatter as files even when they look like options.
+    let (code, stdout, stderr) = apply(&add_file_patch("-e.txt", &["x"]));
+    assert_eq!(code, 0, "stderr:\n{stderr}");
+    assert!(stdout.ends_with("Formatted -e.txt with `printf '%s\\n' >> args.log`.\n"), "stdout:\n{stdout}");
+    assert_eq!(std::fs::read_to_string(work.path().join("args.log")).unwrap(), "./-e.txt\n");
+}
+
+fn assert_conflict_guard_rolls_back(program: &Path, cfg_path: &Path) {
+    let work = TempDir::new();
+    std::fs::write(work.path().join("lib.rs"), "old\n").unwrap();
+    std::fs::write(work.path().join("busy.txt"), "y\n").unwrap();
+    write_config(cfg_path, serde_json::json!({"conflict_guard": true}));
+    // Something else keeps rewriting busy.txt while the patch deletes it.
+    let mut writer = Command::new("sh")
+        .current_dir(work.path())
+        .args(["-c", "while :; do echo y > busy.txt; done"])
+        .spawn()
+        .unwrap();
+    let patch = format!(
+        "*** Begin Patch\n*** Delete File: busy.txt\n{}",
+        update_file_patch("lib.rs", "old", "new").trim_start_matches("*** Begin Patch\n")
+    );
+    let (code, _, stderr) = run({
+        let mut cmd = Command::new(program);
+        cmd.current_dir(work.path()).env("APPLY_PATCH_CONFIG", cfg_path).arg(&patch);
+        cmd
+    });
+    writer.kill().unwrap();
+    writer.wait().unwrap();
+    assert_eq!(code, 1);
+    assert!(
+        stderr.contains("Error: busy.txt was changed by another process while the patch was being applied, so the patch was rolled back."),
+        "stderr:\n{stderr}"
+    );
+    assert_eq!(std::fs::read_to_string(work.path().join("lib.rs")).unwrap(), "old\n");
+
+    // Without interference the patch applies as usual.
+    let (code, _, stderr) = run({
+        let mut cmd = Command::new(program);
+        cmd.current_dir(work.path()).env("APPLY_PATCH_CONFIG", cfg_path).arg(&patch);
+        cmd
+    });
+    assert_eq!(code, 0, "stderr:\n{stderr}");
+    assert_eq!(std::fs::read_to_string(work.path().join("lib.rs")).unwrap(), "new\n");
+    assert!(!work.path().join("busy.txt").exists());
+}
+
+fn assert_roundtrip_fixtures(program: &Path) {
+    let fixtures = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/roundtrip");
+    let mut files: Vec<_> = std::fs::read_dir(&fixtures)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .collect();
+    files.sort();
+    let (code, stdout, stderr) = run({
+        let mut cmd = Command::new(program);
+        cmd.arg("roundtrip").args(&files);
+        cmd
+    });
+    assert_eq!(code, 0, "stderr:\n{stderr}");
+    assert_eq!(stdout, format!("{} input(s) round-trip.\n", files.len()));
+
+    // Input that isn't a patch has nothing to round-trip.
+    let (code, stdout, _) = run_with_stdin(
+        {
+            let mut cmd = Command::new(program);
+            cmd.arg("roundtrip");
+            cmd
+        },
+        "not a patch",
+    );
+    assert_eq!(code, 0);
+    assert_eq!(stdout, "1 input(s) round-trip.\n");
+}
+
+fn assert_strict_rejects_non_canonical(program: &Path, cfg_path: &Path) {
+    let work = TempDir::new();
+    std::fs::write(work.path().join("lib.rs"), "old\n").unwrap();
+    write_config(cfg_path, serde_json::json!({}));
+    let apply = |patch: &str| {
+        run({
+            let mut cmd = Command::new(program);
+            cmd.current_dir(work.path())
+                .env("APPLY_PATCH_CONFIG", cfg_path)
+                .arg("--strict")
+                .arg(patch);
+            cmd
+        })
+    };
+
+    let sloppy = "*** Begin Patch\n*** Update File: lib.rs \n@@\n-old\n+new\n\n*** Add File: lib.rs\n+dup\nbare\n*** Delete File: gone.txt\n*** End Patch\n";
+    let (code, stdout, stderr) = apply(sloppy);
+    assert_eq!(code, 1);
+    assert_eq!(stdout, "");
+    assert_eq!(
+        stderr,
+        "Refused (--strict): the patch is not canonical. Nothing was changed.\n  line 2: whitespace around `*** Update File: lib.rs`\n  line 6: blank line between sections\n  line 7: lib.rs already has a section at line 2\n  line 9: `bare` doesn't start with `+`\n"
+    );
+    assert_eq!(std::fs::read_to_string(work.path().join("lib.rs")).unwrap(), "old\n");
+
+    let (code, _, stderr) = apply("<<'EOF'\n*** Begin Patch\r\n*** Update File: lib.rs\n@@\n-old\n+new\n*** End Patch\nEOF\n");
+    assert_eq!(code, 1);
+    assert!(stderr.contains("  line 1: the patch is wrapped in a heredoc; send the patch itself\n  line 2: CRLF line endings (use \\n)\n"), "stderr:\n{stderr}");
+
+    // A canonical patch applies as usual.
+    let (code, _, stderr) = apply(&update_file_patch("lib.rs", "old", "new"));
+    assert_eq!(code, 0, "stderr:\n{stderr}");
+    assert_eq!(std::fs::read_to_string(work.path().join("lib.rs")).unwrap(), "new\n");
+}
+
+fn assert_write_ahead_log(program: &Path, cfg_path: &Path) {
+    let work = TempDir::new();
+    let state = TempDir::new();
+    std::fs::write(work.path().join("lib.rs"), "old\n").unwrap();
+    write_config(
+        cfg_path,
+        serde_json::json!({"write_ahead_log": true, "state_dir": state.path()}),
+    );
+    let wal = |args: &[&str]| {
+        run({
+            let mut cmd = Command::new(program);
+            cmd.env("APPLY_PATCH_CONFIG", cfg_path).arg("wal").args(args);
+            cmd
+        })
+    };
+    let log = state.path().join("wal.jsonl");
+
+    let (code, _, stderr) = run({
+        let mut cmd = Command::new(program);
+        cmd.current_dir(work.path())
+            .env("APPLY_PATCH_CONFIG", cfg_path)
+            .arg(update_file_patch("lib.rs", "old", "new"));
+        cmd
+    });
+    assert_eq!(code, 0, "stderr:\n{stderr}");
+    let (code, stdout, _) = wal(&["show"]);
+    assert_eq!(code, 0);
+    assert_eq!(stdout, format!("No interrupted applies in {}.\n", log.display()));
+    let (_, stdout, _) = wal(&["show", "--all"]);
+    assert!(stdout.contains(": applied\n  update lib.rs: as the patch leaves it\n"), "stdout:\n{stdout}");
+    assert_revert_file(program, cfg_path, work.path());
+
+    // An intent without its closing record is an apply that was killed.
+    let text = std::fs::read_to_string(&log).unwrap();
+    let intent = text.lines().next().unwrap();
+    assert!(intent.contains("\"status\":\"intent\""), "{intent}");
+    std::fs::write(&log, format!("{intent}\n")).unwrap();
+    std::fs::write(work.path().join("lib.rs"), "old\n").unwrap();
+    let (code, stdout, _) = wal(&["show"]);
+    assert_eq!(code, 0);
+    assert!(stdout.ends_with(": interrupted\n  update lib.rs: as it was before\n"), "stdout:\n{stdout}");
+    std::fs::write(work.path().join("lib.rs"), "other\n").unwrap();
+    let (_, stdout, _) = wal(&["show"]);
+    assert!(stdout.ends_with("  update lib.rs: changed since\n"), "stdout:\n{stdout}");
+    let (_, stdout, _) = wal(&["show", "--json"]);
+    assert!(stdout.contains("\"outcome\":\"interrupted\""), "stdout:\n{stdout}");
+
+    let (code, stdout, _) = wal(&["clear"]);
+    assert_eq!(code, 0);
+    assert_eq!(stdout, format!("Removed {}.\n", log.display()));
+    assert!(!log.exists());
+}
+
+fn assert_revert_file(program: &Path, cfg_path: &Path, work: &Path) {
+    let command = |dir: &Path, args: &[&str]| {
+        run({
+            let mut cmd = Command::new(program);
+            cmd.current_dir(dir).env("APPLY_PATCH_CONFIG", cfg_path).args(args);
+            cmd
+        })
+    };
+    std::fs::write(work.join("keep.rs"), "a\n").unwrap();
+    let patch = "*** Begin Patch\n*** Update File: keep.rs\n@@\n-a\n+b\n*** Add File: sub/new.rs\n+new\n*** End Patch\n";
+    let (code, _, stderr) = command(work, &[patch]);
+    assert_eq!(code, 0, "stderr:\n{stderr}");
+
+    // One file comes back; the rest of the patch stays. Paths are taken
+    // relative to where revert-file runs.
+    let (code, stdout, stderr) = command(&work.join("sub"), &["revert-file", "../keep.rs"]);
+    assert_eq!(code, 0, "stderr:\n{stderr}");
+    assert!(stdout.starts_with("Undid the update of keep.rs from "), "stdout:\n{stdout}");
+    assert_eq!(std::fs::read_to_string(work.join("keep.rs")).unwrap(), "a\n");
+    assert_eq!(std::fs::read_to_string(work.join("sub/new.rs")).unwrap(), "new\n");
+
+    // A file changed since is left alone.
+    std::fs::write(work.join("sub/new.rs"), "mine\n").unwrap();
+    let (code, _, stderr) = command(work, &["revert-file", "sub/new.rs"]);
+    assert_eq!(code, 1);
+    assert!(stderr.contains("Error: sub/new.rs changed since "), "stderr:\n{stderr}");
+    assert!(stderr.contains("; revert it by hand. Nothing was changed.\n"), "stderr:\n{stderr}");
+    std::fs::write(work.join("sub/new.rs"), "new\n").unwrap();
+    let (code, stdout, _) = command(work, &["revert-file", "sub/new.rs"]);
+    assert_eq!(code, 0);
+    assert!(stdout.starts_with("Undid the add of sub/new.rs from "), "stdout:\n{stdout}");
+    assert!(!work.join("sub/new.rs").exists());
+
+    // --run picks an older apply: lib.rs went old -> new before keep.rs.
+    let (_, stdout, _) = command(work, &["wal", "show", "--all"]);
+    let first = stdout.split(' ').next().unwrap().to_string();
+    let (code, _, stderr) = command(work, &["revert-file", "--run", &first, "keep.rs"]);
+    assert_eq!(code, 1);
+    assert!(stderr.contains(&format!("Error: run {first} never in ")), "stderr:\n{stderr}");
+    let (code, stdout, _) = command(work, &["revert-file", "--run", &first, "lib.rs"]);
+    assert_eq!(code, 0);
+    assert_eq!(stdout, format!("Undid the update of lib.rs from {first}.\n"));
+    assert_eq!(std::fs::read_to_string(work.join("lib.rs")).unwrap(), "old\n");
+    std::fs::write(work.join("lib.rs"), "new\n").unwrap();
+    let (code, _, stderr) = command(work, &["revert-file", "--run", "nope", "lib.rs"]);
+    assert_eq!(code, 1);
+    assert!(stderr.starts_with("Error: no run nope in "), "stderr:\n{stderr}");
+    let (code, _, stderr) = command(work, &["revert-file", "other.rs"]);
+    assert_eq!(code, 1);
+    assert!(stderr.starts_with("Error: no applied patch in "), "stderr:\n{stderr}");
+}
+
+fn assert_own_state_is_refused(program: &Path, cfg_path: &Path) {
+    let work = TempDir::new();
+    let state = work.path().join(".apply_patch/state");
+    std::fs::create_dir_all(&state).unwrap();
+    std::fs::write(state.join("wal.jsonl"), "{}\n").unwrap();
+    std::fs::write(work.path().join("audit.jsonl"), "").unwrap();
+    write_config(
+        cfg_path,
+        serde_json::json!({"state_dir": state, "audit_log": "audit.jsonl", "refusal_reason_line": true}),
+    );
+    let apply = |patch: &str| {
+        run({
+            let mut cmd = Command::new(program);
+            cmd.current_dir(work.path())
+                .env("APPLY_PATCH_CONFIG", cfg_path)
+                .arg(patch);
+            cmd
+        })
+    };
+
+    let (code, stdout, stderr) = apply("*** Begin Patch\n*** Delete File: .apply_patch/state/wal.jsonl\n*** End Patch\n");
+    assert_eq!(code, 0, "stderr:\n{stderr}");
+    assert!(
+        stdout.starts_with(&format!(
+            "REFUSED: own_state .apply_patch/state/wal.jsonl\nOwn state (refuse mode): .apply_patch/state/wal.jsonl is in the state directory {}; patches can't change apply_patch's own records.\n",
+            state.display()
+        )),
+        "stdout:\n{stdout}"
+    );
+    assert!(state.join("wal.jsonl").exists());
+
+    // `..` and the audit log are resolved too.
+    let (_, stdout, _) = apply(&add_file_patch("src/../audit.jsonl", &["forged"]));
+    assert!(stdout.contains("Own state (refuse mode): src/../audit.jsonl is the audit log;"), "stdout:\n{stdout}");
+    let audit = std::fs::read_to_string(work.path().join("audit.jsonl")).unwrap();
+    assert!(!audit.contains("forged") && audit.lines().all(|l| l.contains("\"own_state\"")), "{audit}");
+
+    // So is the config file the run read, even through another path to it.
+    let local = work.path().join(".apply_patch/config.json");
+    std::fs::write(&local, serde_json::json!({"state_dir": state, "refusal_reason_line": true}).to_string()).unwrap();
+    let (code, stdout, _) = run({
+        let mut cmd = Command::new(program);
+        cmd.current_dir(work.path())
+            .env("APPLY_PATCH_CONFIG", &local)
+            .arg(update_file_patch("src/../.apply_patch/config.json", "{", "{\"mode\": \"apply\","));
+        cmd
+    });
+    assert_eq!(code, 0);
+    assert!(
+        stdout.starts_with("REFUSED: own_state src/../.apply_patch/config.json\nOwn state (refuse mode): src/../.apply_patch/config.json is the active config file;"),
+        "stdout:\n{stdout}"
+    );
+    assert!(!std::fs::read_to_string(&local).unwrap().contains("mode"));
+    std::fs::remove_file(&local).unwrap();
+
+    // Other files next to the state directory apply as usual.
+    let (code, stdout, _) = apply(&add_file_patch(".apply_patch/notes.txt", &["x"]));
+    assert_eq!(code, 0);
+    assert!(!stdout.contains("REFUSED"), "stdout:\n{stdout}");
+    assert!(work.path().join(".apply_patch/notes.txt").exists());
+}
+
+fn assert_warn_messages_rotate(program: &Path, cfg_path: &Path) {
+    let work = TempDir::new();
+    let state = TempDir::new();
+    let banners = |rotation: &str, session: &str| {
+        write_config(
+            cfg_path,
+            serde_json::json!({
+                "mode": "warn",
+                "state_dir": state.path(),
+                "warn_rotation": rotation,
+                "warn_messages": ["gentle #{warn_count}", "firm #{warn_count}"],
+            }),
+        );
+        (1..=3)
+            .map(|n| {
+                let (code, stdout, stderr) = run({
+                    let mut cmd = Command::new(program);
+                    cmd.current_dir(work.path())
+                        .env("APPLY_PATCH_CONFIG", cfg_path)
+                        .env("APPLY_PATCH_SESSION", session)
+                        .arg(add_file_patch(&format!("{session}-{n}.txt"), &["x"]));
+                    cmd
+                });
+                assert_eq!(code, 0, "stderr:\n{stderr}");
+                stdout.lines().last().unwrap_or_default().to_string()
+            })
+            .collect::<Vec<_>>()
+    };
+
+    assert_eq!(banners("round-robin", "a"), ["gentle #1", "firm #2", "gentle #3"]);
+    assert_eq!(banners("escalate", "b"), ["gentle #1", "firm #2", "firm #3"]);
+
+    let (_, stdout, _) = run({
+        let mut cmd = Command::new(program);
+        cmd.env("APPLY_PATCH_CONFIG", cfg_path).arg("--show-config");
+        cmd
+    });
+    assert!(stdout.contains("warn_messages: 2 (escalate)\n"), "stdout:\n{stdout}");
+}
+
+fn assert_why_explains_last_decision(program: &Path, cfg_path: &Path) {
+    let work = TempDir::new();
+    let state = TempDir::new();
+    std::fs::write(work.path().join("a.txt"), "old\n").unwrap();
+    write_config(
+        cfg_path,
+        serde_json::json!({
+            "mode": "warn",
+            "state_dir": state.path(),
+            "refuse_message": "Refused {files}.",
+            "policies": [{"when": "files ~ 'a.*'", "mode": "refuse"}],
+            "editor_guard": {"action": "warn"},
+        }),
+    );
+    let why = || {
+        run({
+            let mut cmd = Command::new(program);
+            cmd.env("APPLY_PATCH_CONFIG", cfg_path).arg("why");
+            cmd
+        })
+    };
+    let trace = state.path().join("last-decision.json");
+
+    let (code, stdout, _) = why();
+    assert_eq!(code, 0);
+    assert_eq!(stdout, format!("No decision recorded yet in {}.\n", trace.display()));
+
+    let (code, _, stderr) = run({
+        let mut cmd = Command::new(program);
+        cmd.current_dir(work.path())
+            .env("APPLY_PATCH_CONFIG", cfg_path)
+            .arg(update_file_patch("a.txt", "old", "new"));
+        cmd
+    });
+    assert_eq!(code, 0, "stderr:\n{stderr}");
+    let (code, stdout, _) = why();
+    assert_eq!(code, 0);
+    let expected = format!(
+        "Config layers:\n  built-in defaults\n  config file {}\nFiles: a.txt (+1 -1)\nBase mode: warn\nPolicy: rule 0 `files ~ 'a.*'` matched and selected refuse.\nChecks:\n  limits: {{\"max_target_file_bytes\":268435456,\"stream_above_bytes\":52428800}}\n  max_dir_delete_files: 100\n  editor_guard: {{\"action\":\"warn\",\"patterns\":[\".{{name}}.swp\",\".{{name}}.swo\",\".#{{name}}\",\"{{name}}~\"]}}\nEnforced mode: refuse\nReason: REFUSED: policy 0\nMessage:\n  Refused a.txt.\n",
+        cfg_path.display()
+    );
+    assert!(stdout.starts_with("Last decision: refused, exit code 0 (at "), "stdout:\n{stdout}");
+    assert!(stdout.ends_with(&expected), "stdout:\n{stdout}");
+
+    // A run that stops early still replaces the trace.
+    let (code, _, _) = run({
+        let mut cmd = Command::new(program);
+        cmd.current_dir(work.path())
+            .env("APPLY_PATCH_CONFIG", cfg_path)
+            .arg("--strict")
+            .arg("*** Begin Patch \n*** End Patch\n");
+        cmd
+    });
+    assert_eq!(code, 1);
+    let (_, stdout, _) = why();
+    assert!(stdout.starts_with("Last decision: stopped before a decision (the error was printed on stderr), exit code 1 "), "stdout:\n{stdout}");
+    assert!(!stdout.contains("Policy:"), "stdout:\n{stdout}");
+}
+
+fn assert_jail_confines_writes(program: &Path, cfg_path: &Path) {
+    let root = TempDir::new();
+    let work = root.path().join("work");
+    std::fs::create_dir_all(&work).unwrap();
+    write_config(cfg_path, serde_json::json!({"state_dir": root.path().join("state")}));
+    let apply = |patch: &str| {
+        run({
+            let mut cmd = Command::new(program);
+            cmd.current_dir(root.path())
+                .env("APPLY_PATCH_CONFIG", cfg_path)
+                .args(["--jail", "--cwd", "work"])
+                .arg(patch);
+            cmd
+        })
+    };
+
+    // Claiming to be inside the jail already doesn't skip it.
+    let (code, _, stderr) = run({
+        let mut cmd = Command::new(program);
+        cmd.current_dir(&work)
+            .env("APPLY_PATCH_CONFIG", cfg_path)
+            .env("APPLY_PATCH_IN_JAIL", "1")
+            .arg("--jail")
+            .arg(add_file_patch("forged.txt", &["f"]));
+        cmd
+    });
+    assert_eq!(code, 1);
+    assert!(stderr.starts_with("Error: --jail: "), "stderr:\n{stderr}");
+    assert!(stderr.ends_with("; nothing was changed.\n"), "stderr:\n{stderr}");
+    assert!(!work.join("forged.txt").exists());
+
+    let (code, _, stderr) = apply(&add_file_patch("inside.txt", &["x"]));
+    assert_eq!(code, 0, "stderr:\n{stderr}");
+    assert_eq!(std::fs::read_to_string(work.join("inside.txt")).unwrap(), "x\n");
+    if stderr.contains("applying without a jail") {
+        // No user namespaces here; the fallback above is all there is to check.
+        return;
+    }
+    assert_eq!(stderr, "");
+
+    let (code, _, stderr) = apply(&add_file_patch("../escape.txt", &["y"]));
+    assert_eq!(code, 1);
+    assert!(stderr.contains("Read-only file system"), "stderr:\n{stderr}");
+    assert!(!root.path().join("escape.txt").exists());
+    // The state directory outside the working directory stays writable.
+    assert!(root.path().join("state/last-decision.json").exists());
+
+    // A relative $APPLY_PATCH_CWD is resolved once, not again in the jail.
+    let (code, _, stderr) = run({
+        let mut cmd = Command::new(program);
+        cmd.current_dir(root.path())
+            .env("APPLY_PATCH_CONFIG", cfg_path)
+            .env("APPLY_PATCH_CWD", "work")
+            .arg("--jail")
+            .arg(add_file_patch("from_env.txt", &["z"]));
+        cmd
+    });
+    assert_eq!(code, 0, "stderr:\n{stderr}");
+    assert_eq!(std::fs::read_to_string(work.join("from_env.txt")).unwrap(), "z\n");
+
+    let (code, _, stderr) = run({
+        let mut cmd = Command::new(program);
+        cmd.env("APPLY_PATCH_CONFIG", cfg_path)
+            .args(["--jail", "--watch", "."]);
+        cmd
+    });
+    assert_eq!(code, 2);
+    assert_eq!(stderr, "Error: --jail cannot be combined with --watch or --to-branch.\n");
+}
+
+fn assert_trailers_become_metadata(program: &Path, cfg_path: &Path) {
+    let work = TempDir::new();
+    let repo = work.path();
+    git(repo, &["init", "--quiet", "-b", "main"]);
+    std::fs::write(repo.join("lib.rs"), "old\n").unwrap();
+    git(repo, &["add", "-A"]);
+    git(repo, &["commit", "--quiet", "-m", "init"]);
+    let audit_log = TempDir::new();
+    let audit_path = audit_log.path().join("audit.jsonl");
+    write_config(
+        cfg_path,
+        serde_json::json!({"audit_log": audit_path, "apply_message": "Applied for {metadata.Task-Id} ({metadata.Model})."}),
+    );
+    let with_trailers = |patch: String| format!("{patch}Reason: fix the pager\nTask-Id: PROJ-142\n\nModel: gpt-5\n");
+    let apply = |args: &[&str], patch: &str| {
+        run({
+            let mut cmd = Command::new(program);
+            cmd.current_dir(repo)
+                .env("APPLY_PATCH_CONFIG", cfg_path)
+                .args(args)
+                .arg(patch);
+            cmd
+        })
+    };
+
+    let (code, stdout, stderr) = apply(&["--to-branch", "agent/pager"], &with_trailers(update_file_patch("lib.rs", "old", "new")));
+    assert_eq!(code, 0, "stderr:\n{stderr}");
+    assert!(stdout.contains("Applied for PROJ-142 (gpt-5).\n"), "stdout:\n{stdout}");
+    assert_eq!(
+        git(repo, &["log", "-1", "--format=%B", "agent/pager"]),
+        "Apply patch to 1 file(s)\n\nM lib.rs (+1 -1)\n\nReason: fix the pager\nTask-Id: PROJ-142\nModel: gpt-5\n\n"
+    );
+    assert_eq!(git(repo, &["log", "-1", "--format=%(trailers:key=Task-Id,valueonly)", "agent/pager"]), "PROJ-142\n\n");
+
+    // --strict accepts trailers; the audit log records them.
+    let (code, _, stderr) = apply(&["--strict"], &with_trailers(add_file_patch("new.txt", &["x"])));
+    assert_eq!(code, 0, "stderr:\n{stderr}");
+    let audit = std::fs::read_to_string(&audit_path).unwrap();
+    let last: serde_json::Value = serde_json::from_str(audit.lines().last().unwrap()).unwrap();
+    assert_eq!(
+        last["metadata"],
+        serde_json::json!({"Model": "gpt-5", "Reason": "fix the pager", "Task-Id": "PROJ-142"})
+    );
+
+    // Anything else after the envelope is left for the applier to report.
+    let (code, _, stderr) = apply(&["--strict"], &format!("{}Reason: x\nnot a trailer\n", add_file_patch("other.txt", &["x"])));
+    assert_eq!(code, 1);
+    assert!(stderr.contains("text after `*** End Patch`"), "stderr:\n{stderr}");
+}
+
+fn assert_hashline_hunks_follow_moved_lines(program: &Path, cfg_path: &Path) {
+    let work = TempDir::new();
+    let dir = work.path();
+    write_config(cfg_path, serde_json::json!({}));
+    std::fs::write(dir.join("a.rs"), "fn main() {\n    let x = 1;\n    run(x);\n}\n").unwrap();
+    std::fs::write(dir.join("new.rs"), "fn main() {\n    let x = 2;\n    run(x);\n}\n").unwrap();
+    let command = |args: &[&str]| {
+        let mut cmd = Command::new(program);
+        cmd.current_dir(dir).env("APPLY_PATCH_CONFIG", cfg_path).args(args);
+        cmd
+    };
+
+    let (code, patch, stderr) = run(command(&["make-patch", "--hashline", "a.rs", "new.rs"]));
+    assert_eq!(code, 0, "stderr:\n{stderr}");
+    assert!(patch.contains("*** Update File: a.rs\n@@\n#"), "patch:\n{patch}");
+    assert!(patch.contains("|-    let x = 1;\n+    let x = 2;\n#"), "patch:\n{patch}");
+
+    // Lines inserted before and inside the hunk don't stop it from landing.
+    std::fs::write(dir.join("a.rs"), "// header\n\nfn main() {\n    let x = 1;\n    log();\n    run(x);\n}\n").unwrap();
+    let (code, _, stderr) = run(command(&["--strict", &patch]));
+    assert_eq!(code, 0, "stderr:\n{stderr}");
+    assert_eq!(
+        std::fs::read_to_string(dir.join("a.rs")).unwrap(),
+        "// header\n\nfn main() {\n    let x = 2;\n    log();\n    run(x);\n}\n"
+    );
+
+    // A removed line that is gone fails the patch.
+    let (code, _, stderr) = run(command(&[&patch]));
+    assert_eq!(code, 1);
+    assert!(stderr.contains("matches no line where the hunk can go"), "stderr:\n{stderr}");
+
+    let (code, patch, _) = run(command(&["make-patch", "b.rs", "new.rs"]));
+    assert_eq!(code, 0);
+    assert!(patch.contains("*** Add File: b.rs\n+fn main() {\n"), "patch:\n{patch}");
+}
+
+fn assert_forward_mode_relays_the_answer(program: &Path, cfg_path: &Path) {
+    let work = TempDir::new();
+    let dir = work.path();
+    let apply = |patch: &str| {
+        run({
+            let mut cmd = Command::new(program);
+            cmd.current_dir(dir).env("APPLY_PATCH_CONFIG", cfg_path).arg(patch);
+            cmd
+        })
+    };
+    write_config(
+        cfg_path,
+        serde_json::json!({
+            "mode": "forward",
+            "forward": {"command": "cat > forwarded.patch; printf '%s\\n' \"$APPLY_PATCH_FORWARD_FILES\" > files.txt; echo '{\"summary\": \"Edited 1 file with the editor.\"}'"},
+        }),
+    );
+    let patch = add_file_patch("hello.txt", &["hi"]);
+    let (code, stdout, stderr) = apply(&patch);
+    assert_eq!(code, 0, "stderr:\n{stderr}");
+    assert_eq!(
+        stdout,
+        "Forwarded the patch; nothing was applied here. The editor answered:\nEdited 1 file with the editor.\n"
+    );
+    assert!(!dir.join("hello.txt").exists());
+    assert_eq!(std::fs::read_to_string(dir.join("forwarded.patch")).unwrap(), patch);
+    assert_eq!(std::fs::read_to_string(dir.join("files.txt")).unwrap(), "hello.txt\n");
+
+    write_config(cfg_path, serde_json::json!({"mode": "forward", "forward": {"command": "echo busy >&2; exit 4"}}));
+    let (code, _, stderr) = apply(&add_file_patch("other.txt", &["hi"]));
+    assert_eq!(code, 1);
+    assert!(
+        stderr.contains("forwarding the patch failed: forward.command exited with status 4: busy. Nothing was changed."),
+        "stderr:\n{stderr}"
+    );
+
+    write_config(cfg_path, serde_json::json!({"mode": "forward"}));
+    let (code, _, stderr) = apply(&add_file_patch("other.txt", &["hi"]));
+    assert_eq!(code, 1);
+    assert!(stderr.contains("needs forward.url or forward.command"), "stderr:\n{stderr}");
+    assert!(!dir.join("other.txt").exists());
+}
+
+fn assert_whitespace_only_hunks_are_skipped(program: &Path, cfg_path: &Path) {
+    let work = TempDir::new();
+    let dir = work.path();
+    let apply = |patch: &str| {
+        run({
+            let mut cmd = Command::new(program);
+            cmd.current_dir(dir).env("APPLY_PATCH_CONFIG", cfg_path).arg(patch);
+            cmd
+        })
+    };
+    write_config(cfg_path, serde_json::json!({"skip_whitespace_only_hunks": true}));
+    std::fs::write(dir.join("lib.rs"), "fn a() {\n  one();\n}\n\nfn b() {\n    two();\n}\n").unwrap();
+    let patch = "*** Begin Patch\n*** Update File: lib.rs\n@@ fn a() {\n-  one();\n+    one();\n@@ fn b() {\n-    two();\n+    three();\n*** End Patch\n";
+    let (code, stdout, stderr) = apply(patch);
+    assert_eq!(code, 0, "stderr:\n{stderr}");
+    assert!(
+        stdout.starts_with("Skipped whitespace-only hunk 1 of lib.rs (skip_whitespace_only_hunks).\n"),
+        "stdout:\n{stdout}"
+    );
+    assert_eq!(
+        std::fs::read_to_string(dir.join("lib.rs")).unwrap(),
+        "fn a() {\n  one();\n}\n\nfn b() {\n    three();\n}\n"
+    );
+
+    let (code, stdout, _) = apply(&update_file_patch("lib.rs", "    three();", "\tthree();  "));
+    assert_eq!(code, 0);
+    assert!(stdout.ends_with("Nothing left to apply.\n"), "stdout:\n{stdout}");
+    assert!(std::fs::read_to_string(dir.join("lib.rs")).unwrap().contains("\n    three();\n"));
+}
+
+fn assert_resource_usage_is_recorded(program: &Path, cfg_path: &Path) {
+    let work = TempDir::new();
+    let audit_path = work.path().join("audit.jsonl");
+    write_config(cfg_path, serde_json::json!({"audit_log": audit_path}));
+    let (code, _, stderr) = run({
+        let mut cmd = Command::new(program);
+        cmd.current_dir(work.path())
+            .env("APPLY_PATCH_CONFIG", cfg_path)
+            .arg("--timings")
+            .arg(add_file_patch("a.txt", &["a"]));
+        cmd
+    });
+    assert_eq!(code, 0, "stderr:\n{stderr}");
+    let timings = stderr.lines().find(|line| line.starts_with("Timings: ")).expect("a Timings line");
+    assert!(timings.contains(" ms wall, ") && timings.ends_with(", 1 file(s)"), "stderr:\n{stderr}");
+    let audit = std::fs::read_to_string(&audit_path).unwrap();
+    let entry: serde_json::Value = serde_json::from_str(audit.lines().last().unwrap()).unwrap();
+    assert_eq!(entry["resources"]["files"], 1);
+    assert!(entry["resources"]["wall_ms"].is_u64(), "{entry}");
+    if cfg!(target_os = "linux") {
+        assert!(entry["resources"]["peak_rss_kb"].as_u64().is_some_and(|kb| kb > 0), "{entry}");
+        assert!(entry["resources"]["bytes_written"].is_u64(), "{entry}");
+    }
+}
+
+fn assert_staged_changes_commit_or_discard(program: &Path, cfg_path: &Path) {
+    let work = TempDir::new();
+    let dir = work.path();
+    let state = TempDir::new();
+    write_config(cfg_path, serde_json::json!({"state_dir": state.path()}));
+    let command = |args: &[&str]| {
+        let mut cmd = Command::new(program);
+        cmd.current_dir(dir).env("APPLY_PATCH_CONFIG", cfg_path).args(args);
+        cmd
+    };
+    std::fs::write(dir.join("lib.rs"), "old\n").unwrap();
+    std::fs::write(dir.join("gone.txt"), "x\n").unwrap();
+    let patch = "*** Begin Patch\n*** Update File: lib.rs\n@@\n-old\n+new\n*** Add File: src/added.rs\n+added\n*** Delete File: gone.txt\n*** End Patch\n";
+
+    let (code, stdout, stderr) = run(command(&["stage", patch]));
+    assert_eq!(code, 0, "stderr:\n{stderr}");
+    assert!(stdout.contains("M lib.rs\nA src/added.rs\nD gone.txt\n"), "stdout:\n{stdout}");
+    assert_eq!(std::fs::read_to_string(dir.join("lib.rs")).unwrap(), "old\n");
+    assert!(!dir.join("src").exists());
+    let staged = PathBuf::from(stdout.split(" file(s) in ").nth(1).unwrap().split(';').next().unwrap());
+    std::fs::write(staged.join("lib.rs"), "new, edited by hand\n").unwrap();
+    std::fs::remove_file(staged.join("src/added.rs")).unwrap();
+
+    let (code, _, stderr) = run(command(&["stage", patch]));
+    assert_eq!(code, 1);
+    assert!(stderr.contains("changes are already staged"), "stderr:\n{stderr}");
+
+    let (code, stdout, stderr) = run(command(&["commit-staged"]));
+    assert_eq!(code, 0, "stderr:\n{stderr}");
+    assert_eq!(
+        stdout,
+        "Success. Updated the following files:\nM lib.rs\nD gone.txt\nLeft src/added.rs alone: its staged copy was removed.\n"
+    );
+    assert_eq!(std::fs::read_to_string(dir.join("lib.rs")).unwrap(), "new, edited by hand\n");
+    assert!(!dir.join("gone.txt").exists() && !dir.join("src").exists());
+    let (code, _, stderr) = run(command(&["commit-staged"]));
+    assert_eq!(code, 1);
+    assert!(stderr.contains("nothing is staged"), "stderr:\n{stderr}");
+
+    // A target that changed after staging stops the commit.
+    let (code, _, _) = run(command(&["stage", &update_file_patch("lib.rs", "new, edited by hand", "again")]));
+    assert_eq!(code, 0);
+    std::fs::write(dir.join("lib.rs"), "changed meanwhile\n").unwrap();
+    let (code, _, stderr) = run(command(&["commit-staged"]));
+    assert_eq!(code, 1);
+    assert!(stderr.contains("changed since they were staged, so nothing was committed: lib.rs."), "stderr:\n{stderr}");
+    assert_eq!(std::fs::read_to_string(dir.join("lib.rs")).unwrap(), "changed meanwhile\n");
+    let (code, stdout, _) = run(command(&["discard-staged"]));
+    assert_eq!(code, 0);
+    assert!(stdout.starts_with("Discarded the staged changes"), "stdout:\n{stdout}");
+
+    // Staging goes through the mode and guards: a refused patch is not staged.
+    let again = update_file_patch("lib.rs", "changed meanwhile", "again");
+    write_config(cfg_path, serde_json::json!({"state_dir": state.path(), "mode": "refuse"}));
+    let (code, stdout, _) = run(command(&["stage", &again]));
+    assert_eq!(code, 0);
+    assert!(stdout.contains("nothing was changed") && !stdout.contains("Staged"), "stdout:\n{stdout}");
+    let (code, _, stderr) = run(command(&["commit-staged"]));
+    assert_eq!(code, 1);
+    assert!(stderr.contains("nothing is staged"), "stderr:\n{stderr}");
+    write_config(cfg_path, serde_json::json!({"state_dir": state.path(), "mode": "refuse", "readonly": true}));
+    let (code, _, stderr) = run(command(&["stage", &again]));
+    assert_eq!(code, 2);
+    assert!(stderr.contains("stage cannot be used with readonly"), "stderr:\n{stderr}");
+
+    // ... and so is the commit: staged under apply, refused once the config says so.
+    write_config(cfg_path, serde_json::json!({"state_dir": state.path()}));
+    let (code, stdout, _) = run(command(&["stage", "--expected-files", "other.txt", &again]));
+    assert_eq!(code, 0);
+    assert!(stdout.contains("  + lib.rs\n") && !stdout.contains("Staged"), "stdout:\n{stdout}");
+    let (code, _, stderr) = run(command(&["stage", "--expected-files", "lib.rs", &again]));
+    assert_eq!(code, 0, "stderr:\n{stderr}");
+    write_config(cfg_path, serde_json::json!({"state_dir": state.path(), "mode": "refuse"}));
+    let (code, stdout, _) = run(command(&["commit-staged"]));
+    assert_eq!(code, 0);
+    assert!(stdout.contains("nothing was changed") && !stdout.contains("Success."), "stdout:\n{stdout}");
+    assert_eq!(std::fs::read_to_string(dir.join("lib.rs")).unwrap(), "changed meanwhile\n");
+    write_config(cfg_path, serde_json::json!({"state_dir": state.path(), "readonly": true}));
+    let (code, _, stderr) = run(command(&["commit-staged"]));
+    assert_eq!(code, 2);
+    assert!(stderr.contains("commit-staged cannot be used with readonly"), "stderr:\n{stderr}");
+    write_config(cfg_path, serde_json::json!({"state_dir": state.path()}));
+    let (code, stdout, _) = run(command(&["commit-staged"]));
+    assert_eq!(code, 0);
+    assert!(stdout.contains("Success. Updated the following files:\nM lib.rs\n"), "stdout:\n{stdout}");
+    assert_eq!(std::fs::read_to_string(dir.join("lib.rs")).unwrap(), "again\n");
+}
+
+fn assert_banners_name_where_the_patch_landed(program: &Path, cfg_path: &Path) {
+    let work = TempDir::new();
+    let repo = work.path();
+    git(repo, &["init", "--quiet", "-b", "feature/x"]);
+    let audit_path = repo.join(".git").join("audit.jsonl");
+    write_config(
+        cfg_path,
+        serde_json::json!({"mode": "warn", "warn_message": "Landed as {user}@{hostname} in {repo} on {branch}.", "audit_log": audit_path}),
+    );
+    let (code, stdout, stderr) = run({
+        let mut cmd = Command::new(program);
+        cmd.current_dir(repo)
+            .env("APPLY_PATCH_CONFIG", cfg_path)
+            .env("USER", "agent-7")
+            .arg(add_file_patch("a.txt", &["a"]));
+        cmd
+    });
+    assert_eq!(code, 0, "stderr:\n{stderr}");
+    let top = repo.canonicalize().unwrap();
+    let audit = std::fs::read_to_string(&audit_path).unwrap();
+    let entry: serde_json::Value = serde_json::from_str(audit.lines().last().unwrap()).unwrap();
+    let hostname = entry["fingerprint"]["hostname"].as_str().unwrap_or_default();
+    assert!(
+        stdout.contains(&format!("Landed as agent-7@{hostname} in {} on feature/x.", top.display())),
+        "stdout:\n{stdout}"
+    );
+    assert_eq!(entry["fingerprint"]["user"], "agent-7");
+    assert_eq!(entry["fingerprint"]["branch"], "feature/x");
+    assert_eq!(entry["fingerprint"]["repo"], top.display().to_string());
+}
+
+fn assert_expected_files_pin_the_patch(program: &Path, cfg_path: &Path) {
+    let work = TempDir::new();
+    let dir = work.path();
+    std::fs::write(dir.join("a.rs"), "a\n").unwrap();
+    std::fs::write(dir.join("c.rs"), "c\n").unwrap();
+    let audit_path = dir.join("audit.jsonl");
+    write_config(
+        cfg_path,
+        serde_json::json!({"mode": "apply", "audit_log": audit_path}),
+    );
+    let patch = "*** Begin Patch\n*** Update File: a.rs\n@@\n-a\n+a2\n*** Update File: c.rs\n@@\n-c\n+c2\n*** End Patch";
+    let (code, stdout, stderr) = run({
+        let mut cmd = Command::new(program);
+        cmd.current_dir(dir)
+            .env("APPLY_PATCH_CONFIG", cfg_path)
+            .args(["--expected-files", "./a.rs,b.rs", patch]);
+        cmd
+    });
+    assert_eq!(code, 0, "stderr:\n{stderr}");
+    assert!(
+        stdout.contains("touches 1 file(s) not in --expected-files") && stdout.contains("  + c.rs\n  - b.rs"),
+        "stdout:\n{stdout}"
+    );
+    assert_eq!(std::fs::read_to_string(dir.join("a.rs")).unwrap(), "a\n");
+    let audit = std::fs::read_to_string(&audit_path).unwrap();
+    let entry: serde_json::Value = serde_json::from_str(audit.lines().last().unwrap()).unwrap();
+    assert_eq!(entry["reason"], serde_json::json!({"code": "unexpected_file", "detail": "c.rs"}), "audit:\n{audit}");
+    assert_eq!(entry["expected_files"]["unexpected"], serde_json::json!(["c.rs"]));
+
+    std::fs::write(dir.join("expected.txt"), "# this turn\na.rs\n\nc.rs\n").unwrap();
+    let (code, stdout, stderr) = run({
+        let mut cmd = Command::new(program);
+        cmd.current_dir(dir)
+            .env("APPLY_PATCH_CONFIG", cfg_path)
+            .args(["--expected-files", "@expected.txt", patch]);
+        cmd
+    });
+    assert_eq!(code, 0, "stderr:\n{stderr}");
+    assert!(stdout.contains("M a.rs\nM c.rs"), "stdout:\n{stdout}");
+
+    let (code, _, stderr) = run({
+        let mut cmd = Command::new(program);
+        cmd.current_dir(dir)
+            .env("APPLY_PATCH_CONFIG", cfg_path)
+            .args(["--expected-files", "@missing.txt", patch]);
+        cmd
+    });
+    assert_eq!(code, 2);
+    assert!(stderr.contains("--expected-files manifest"), "stderr:\n{stderr}");
+}
+
+fn assert_edit_line_sections(program: &Path, cfg_path: &Path) {
+    let work = TempDir::new();
+    let file = work.path().join("a.rs");
+    std::fs::write(&file, "fn a() {\n    let x = 1;\n}\nfn b() {\n    let x = 1;\n}\n").unwrap();
+    write_config(cfg_path, serde_json::json!({"mode": "apply"}));
+    let apply = |patch: &str| {
+        run({
+            let mut cmd = Command::new(program);
+            cmd.current_dir(work.path()).env("APPLY_PATCH_CONFIG", cfg_path).arg(patch);
+            cmd
+        })
+    };
+
+    // The second `let x = 1;` block, though the first matches its context.
+    let (code, stdout, stderr) = apply(
+        "*** Begin Patch\n*** Edit Line 5 of a.rs: s/= 1/= 2/\n*** Edit Line 1 of ./a.rs: s|a()|alpha()|\n*** End Patch",
+    );
+    assert_eq!(code, 0, "stderr:\n{stderr}");
+    assert_eq!(stdout, "Success. Updated the following files:\nM a.rs\n");
+    assert_eq!(
+        std::fs::read_to_string(&file).unwrap(),
+        "fn alpha() {\n    let x = 1;\n}\nfn b() {\n    let x = 2;\n}\n"
+    );
+
+    let (code, _, stderr) = apply("*** Begin Patch\n*** Edit Line 2 of a.rs: s/= 3/= 4/\n*** End Patch");
+    assert_eq!(code, 1);
+    assert!(
+        stderr.contains("`= 3` is not on line 2 of a.rs, which reads `    let x = 1;`"),
+        "stderr:\n{stderr}"
+    );
+    let (code, _, stderr) = apply("*** Begin Patch\n*** Edit Line 2 of a.rs: s/x/y\n*** End Patch");
+    assert_eq!(code, 1);
+    assert!(stderr.contains("expected `s/old/new/` after the path"), "stderr:\n{stderr}");
+    let (code, _, stderr) = apply("*** Begin Patch\n*** Edit Line 9 of a.rs: s/x/y/\n*** End Patch");
+    assert_eq!(code, 1);
+    assert!(stderr.contains("a.rs has only 6 line(s)"), "stderr:\n{stderr}");
+    assert_eq!(
+        std::fs::read_to_string(&file).unwrap(),
+        "fn alpha() {\n    let x = 1;\n}\nfn b() {\n    let x = 2;\n}\n"
+    );
+}
+
+fn assert_audit_events_reach_the_collector(program: &Path, cfg_path: &Path) {
+    let work = TempDir::new();
+    let dir = work.path();
+    let socket = dir.join("run").join("audit.sock");
+    let events = dir.join("events.jsonl");
+    write_config(cfg_path, serde_json::json!({"mode": "apply", "audit": {"forward_socket": socket}}));
+    let apply = |patch: String| {
+        run({
+            let mut cmd = Command::new(program);
+            cmd.current_dir(dir).env("APPLY_PATCH_CONFIG", cfg_path).arg(patch);
+            cmd
+        })
+    };
+
+    // Nobody listening: the event is dropped and the patch still applies.
+    let (code, _, stderr) = apply(add_file_patch("early.txt", &["e"]));
+    assert_eq!(code, 0, "stderr:\n{stderr}");
+    assert!(stderr.is_empty(), "stderr:\n{stderr}");
+
+    let mut collector = Command::new(program)
+        .current_dir(dir)
+        .env("APPLY_PATCH_CONFIG", cfg_path)
+        .args(["audit-collector", "--out"])
+        .arg(&events)
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .expect("failed to spawn audit-collector");
+    let listening = wait_for(&socket);
+    let (code, _, stderr) = apply(add_file_patch("late.txt", &["l"]));
+    let (second, _, second_err) = run({
+        let mut cmd = Command::new(program);
+        cmd.env("APPLY_PATCH_CONFIG", cfg_path).arg("audit-collector");
+        cmd
+    });
+    let mut collected = String::new();
+    for _ in 0..50 {
+        collected = std::fs::read_to_string(&events).unwrap_or_default();
+        if !collected.is_empty() {
+            break;
+        }
+        std::thread::sleep(std::time::Duration::from_millis(100));
+    }
+    let _ = collector.kill();
+    let _ = collector.wait();
+
+    assert!(listening, "audit-collector did not create {}", socket.display());
+    assert_eq!(code, 0, "stderr:\n{stderr}");
+    assert_eq!(second, 1);
+    assert!(second_err.contains("another collector is already listening"), "stderr:\n{second_err}");
+    let lines: Vec<serde_json::Value> =
+        collected.lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+    assert_eq!(lines.len(), 1, "events:\n{collected}");
+    assert_eq!(lines[0]["files"], serde_json::json!(["late.txt"]));
+    assert_eq!(lines[0]["decision"], "applied");
+}
+
 #[test]
 fn rust_binary_config_path_and_modes() {
     assert_show_config_uses_dot_apply_patch(&bin_path());
@@ -475,35 +3914,1314 @@ fn rust_binary_config_path_and_modes() {
     let cfgdir = TempDir::new();
     let cfg_path = cfgdir.path().join("config.json");
 
-    let program = bin_path();
-    assert_refuse_and_warn_modes(&program, &cfg_path);
-    assert_custom_messages(&program, &cfg_path);
-    assert_patch_argument_mode(&program, &cfg_path);
-    assert_non_patch_argument_errors(&program, &cfg_path);
-    assert_config_flag_error_paths(&program, &cfg_path);
-    assert_empty_stdin_usage(&program);
-    assert_two_patch_args_usage(&program, &cfg_path);
-    assert_help_exits_zero(&program);
-    assert_config_path_error_when_env_missing(&program);
-    assert_config_flags_cannot_mix_with_patch_arg(&program, &cfg_path);
+    let program = bin_path();
+    assert_refuse_and_warn_modes(&program, &cfg_path);
+    assert_custom_messages(&program, &cfg_path);
+    assert_patch_argument_mode(&program, &cfg_path);
+    assert_non_patch_argument_errors(&program, &cfg_path);
+    assert_config_flag_error_paths(&program, &cfg_path);
+    assert_empty_stdin_usage(&program);
+    assert_two_patch_args_usage(&program, &cfg_path);
+    assert_help_exits_zero(&program);
+    assert_config_path_error_when_env_missing(&program);
+    assert_config_flags_cannot_mix_with_patch_arg(&program, &cfg_path);
+    assert_feedback_file_records_decisions(&program, &cfg_path);
+    assert_delete_content_verification(&program, &cfg_path);
+    assert_double_dash_separator(&program, &cfg_path);
+    assert_generate_docs(&program);
+    assert_preview_snapshots(&program);
+    assert_apply_message_and_templates(&program, &cfg_path);
+    assert_partial_applies_independent_sections(&program, &cfg_path);
+    assert_watch_applies_dropped_patches(&program, &cfg_path);
+    assert_to_branch_commits_in_worktree(&program, &cfg_path);
+    assert_stash_before_records_targets(&program, &cfg_path);
+    assert_cwd_reroots_patch_paths(&program, &cfg_path);
+    assert_compare_reports_hunk_status(&program);
+    assert_archive_sections_extract(&program, &cfg_path);
+    assert_config_versions_migrate(&program, &cfg_path);
+    assert_reanchor_drifted_hunks(&program, &cfg_path);
+    assert_json_stream_progress(&program, &cfg_path);
+    assert_banner_session_counts(&program, &cfg_path);
+    assert_base_rebases_onto_worktree(&program, &cfg_path);
+    assert_serve_http(&program, &cfg_path);
+    assert_render_template_sections(&program, &cfg_path);
+    assert_squash_composes_patches(&program, &cfg_path);
+    assert_dry_run_and_readonly(&program, &cfg_path);
+    assert_archive_flag_patches_entries(&program, &cfg_path);
+    assert_event_fd_stream(&program, &cfg_path);
+    assert_truncated_patches_rejected(&program, &cfg_path);
+    assert_mirror_dir_replays_applies(&program, &cfg_path);
+    assert_patch_cache_replays(&program, &cfg_path);
+    assert_replace_file_sections(&program, &cfg_path);
+    assert_parse_error_guidance(&program, &cfg_path);
+    assert_patch_fd_input(&program, &cfg_path);
+    assert_init_writes_starter_config(&program);
+    assert_disk_space_precheck(&program, &cfg_path);
+    assert_check_reports_per_file(&program, &cfg_path);
+    assert_write_strategy(&program, &cfg_path);
+    assert_wrap_shim(&program, &cfg_path);
+    assert_batch_applies_in_order(&program, &cfg_path);
+    assert_concise_output(&program, &cfg_path);
+    assert_final_newline(&program, &cfg_path);
+    assert_git_apply_fallback(&program, &cfg_path);
+    assert_target_file_size_limit(&program, &cfg_path);
+    assert_localized_messages(&program, &cfg_path);
+    assert_open_pr_pushes_and_creates(&program, &cfg_path);
+    assert_no_config_is_hermetic(&program, &cfg_path);
+    assert_redact_replaces_literals(&program, &cfg_path);
+    assert_format_on_apply(&program, &cfg_path);
+    assert_conflict_guard_rolls_back(&program, &cfg_path);
+    assert_roundtrip_fixtures(&program);
+    assert_strict_rejects_non_canonical(&program, &cfg_path);
+    assert_write_ahead_log(&program, &cfg_path);
+    assert_own_state_is_refused(&program, &cfg_path);
+    assert_warn_messages_rotate(&program, &cfg_path);
+    assert_why_explains_last_decision(&program, &cfg_path);
+    assert_jail_confines_writes(&program, &cfg_path);
+    assert_trailers_become_metadata(&program, &cfg_path);
+    assert_hashline_hunks_follow_moved_lines(&program, &cfg_path);
+    assert_forward_mode_relays_the_answer(&program, &cfg_path);
+    assert_whitespace_only_hunks_are_skipped(&program, &cfg_path);
+    assert_resource_usage_is_recorded(&program, &cfg_path);
+    assert_staged_changes_commit_or_discard(&program, &cfg_path);
+    assert_banners_name_where_the_patch_landed(&program, &cfg_path);
+    assert_expected_files_pin_the_patch(&program, &cfg_path);
+    assert_edit_line_sections(&program, &cfg_path);
+    assert_audit_events_reach_the_collector(&program, &cfg_path);
+}
+
+#[test]
+#[cfg(unix)]
+fn script_config_path_and_modes() {
+    let script = script_path();
+    assert_show_config_uses_dot_apply_patch(&script);
+
+    let cfgdir = TempDir::new();
+    let cfg_path = cfgdir.path().join("config.json");
+
+    assert_refuse_and_warn_modes(&script, &cfg_path);
+    assert_custom_messages(&script, &cfg_path);
+    assert_patch_argument_mode(&script, &cfg_path);
+    assert_non_patch_argument_errors(&script, &cfg_path);
+    assert_config_flag_error_paths(&script, &cfg_path);
+    assert_empty_stdin_usage(&script);
+    assert_two_patch_args_usage(&script, &cfg_path);
+    assert_help_exits_zero(&script);
+    assert_config_flags_cannot_mix_with_patch_arg(&script, &cfg_path);
+    assert_double_dash_separator(&script, &cfg_path);
+}
+
+fn assert_assess_scores_risk(program: &Path, cfg_path: &Path) {
+    let work = TempDir::new();
+    let risky = "*** Begin Patch\n*** Delete File: old.txt\n*** Add File: .github/workflows/ci.yml\n+on: push\n*** Add File: deploy.sh\n+#!/bin/sh\n+export API_KEY=\"abcd1234efgh\"\n*** End Patch\n";
+    let assess = |extra: &[&str], patch: &str| {
+        run_with_stdin({
+            let mut cmd = Command::new(program);
+            cmd.current_dir(work.path()).arg("assess").args(extra);
+            cmd
+        }, patch)
+    };
+
+    let (code, stdout, stderr) = assess(&[], risky);
+    assert_eq!(code, 0, "stderr:\n{stderr}");
+    assert_eq!(
+        stdout,
+        "Risk score: 90\n  +20  old.txt: deletes a file\n  +25  .github/workflows/ci.yml: touches CI/workflow configuration\n  +15  deploy.sh: adds a script with a shebang\n  +30  deploy.sh: adds secret-looking content\n"
+    );
+
+    let (code, stdout, _stderr) = assess(&["--json"], &add_file_patch("notes.txt", &["hi"]));
+    assert_eq!(code, 0);
+    let report: serde_json::Value = serde_json::from_str(&stdout).unwrap();
+    assert_eq!(report, serde_json::json!({"score": 0, "findings": []}));
+
+    // Policy rules see the same score as `risk`.
+    write_config(
+        cfg_path,
+        serde_json::json!({"policies": [{"when": "risk >= 15", "mode": "refuse"}]}),
+    );
+    let (code, stdout, stderr) = run_with_stdin({
+        let mut cmd = Command::new(program);
+        cmd.current_dir(work.path()).env("APPLY_PATCH_CONFIG", cfg_path);
+        cmd
+    }, &add_file_patch("Cargo.toml", &["[package]"]));
+    assert_eq!(code, 0, "stderr:\n{stderr}");
+    assert!(stdout.contains("nothing was changed"), "stdout:\n{stdout}");
+    assert!(!work.path().join("Cargo.toml").exists());
+}
+
+fn assert_refusal_reasons(program: &Path, cfg_path: &Path) {
+    let work = TempDir::new();
+    let audit_log = work.path().join("audit.jsonl");
+    let feedback = work.path().join("feedback.jsonl");
+    write_config(
+        cfg_path,
+        serde_json::json!({
+            "refusal_reason_line": true,
+            "audit_log": audit_log,
+            "policies": [{"when": "files ~ '.github/**'", "mode": "refuse"}],
+            "escalate": {"large_patch_lines": 3, "to": "refuse"},
+        }),
+    );
+    let run_patch = |patch: &str| {
+        run_with_stdin({
+            let mut cmd = Command::new(program);
+            cmd.current_dir(work.path())
+                .env("APPLY_PATCH_CONFIG", cfg_path)
+                .env("APPLY_PATCH_FEEDBACK_FILE", &feedback);
+            cmd
+        }, patch)
+    };
+
+    let (code, stdout, _stderr) = run_patch(&add_file_patch(".github/workflows/ci.yml", &["on: push"]));
+    assert_eq!(code, 0);
+    assert!(stdout.starts_with("REFUSED: policy 0\nNOTE TO LLM:"), "stdout:\n{stdout}");
+
+    let (code, stdout, _stderr) = run_patch(&add_file_patch("big.txt", &["1", "2", "3"]));
+    assert_eq!(code, 0);
+    assert!(stdout.starts_with("REFUSED: large_patch 3\nEscalated"), "stdout:\n{stdout}");
+
+    let (code, stdout, _stderr) = run_patch(&add_file_patch("ok.txt", &["fine"]));
+    assert_eq!(code, 0);
+    assert!(!stdout.contains("REFUSED:"), "stdout:\n{stdout}");
+
+    let audit = read_jsonl(&audit_log);
+    assert_eq!(audit[0]["reason"], serde_json::json!({"code": "policy", "detail": 0}));
+    assert_eq!(audit[1]["reason"], serde_json::json!({"code": "large_patch", "detail": 3}));
+    assert!(audit[2].get("reason").is_none(), "{:?}", audit[2]);
+    let records = read_jsonl(&feedback);
+    assert_eq!(records[0]["reason"]["code"], "policy");
+
+    write_config(cfg_path, serde_json::json!({"mode": "refuse", "refusal_reason_line": true}));
+    let (code, stdout, _stderr) = run_patch(&add_file_patch("x.txt", &["x"]));
+    assert_eq!(code, 0);
+    assert!(stdout.starts_with("REFUSED: mode refuse\n"), "stdout:\n{stdout}");
+}
+
+fn assert_refuse_echo(program: &Path, cfg_path: &Path) {
+    let work = TempDir::new();
+    let patch = "*** Begin Patch\n*** Add File: new.txt\n+a\n+b\n*** Update File: lib.rs\n*** Move to: core.rs\n@@\n-old\n+new\n*** Delete File: gone.txt\n*** End Patch\n";
+    let run_patch = || {
+        run_with_stdin({
+            let mut cmd = Command::new(program);
+            cmd.current_dir(work.path()).env("APPLY_PATCH_CONFIG", cfg_path);
+            cmd
+        }, patch)
+    };
+
+    write_config(cfg_path, serde_json::json!({"mode": "refuse", "refuse_message": "NO", "refuse_echo": "summary"}));
+    let (code, stdout, _stderr) = run_patch();
+    assert_eq!(code, 0);
+    assert_eq!(
+        stdout,
+        "NO\n\nRefused patch summary:\nA new.txt (+2 -0)\nM lib.rs -> core.rs (+1 -1)\nD gone.txt\n"
+    );
+
+    write_config(cfg_path, serde_json::json!({"mode": "refuse", "refuse_message": "NO", "refuse_echo": "full"}));
+    let (code, stdout, _stderr) = run_patch();
+    assert_eq!(code, 0);
+    assert_eq!(stdout, format!("NO\n\nRefused patch:\n{patch}"));
+}
+
+fn assert_detailed_exit_codes(program: &Path, cfg_path: &Path) {
+    let work = TempDir::new();
+    std::fs::write(work.path().join("a.txt"), "one\n").unwrap();
+    let run_detailed = |args: &[&str]| {
+        run({
+            let mut cmd = Command::new(program);
+            cmd.current_dir(work.path())
+                .env("APPLY_PATCH_CONFIG", cfg_path)
+                .env("APPLY_PATCH_EXIT_CODES", "detailed")
+                .args(args);
+            cmd
+        })
+    };
+    let add = add_file_patch("b.txt", &["b"]);
+
+    let (code, stdout, _stderr) = run_detailed(&["--explain-exit-codes"]);
+    assert_eq!(code, 0);
+    let explained: serde_json::Value = serde_json::from_str(&stdout).unwrap();
+    assert_eq!(explained["scheme"], "detailed");
+    assert_eq!(explained["codes"][5], serde_json::json!({"code": 5, "name": "refused_by_mode", "description": "Refused because the base mode is refuse."}));
+    let (code, stdout, _stderr) = run({
+        let mut cmd = Command::new(program);
+        cmd.arg("--explain-exit-codes");
+        cmd
+    });
+    assert_eq!(code, 0);
+    let explained: serde_json::Value = serde_json::from_str(&stdout).unwrap();
+    assert_eq!(explained["scheme"], "default");
+    assert_eq!(explained["codes"][5]["code"], 0);
+    assert_eq!(explained["codes"][2]["code"], 2);
+
+    write_config(cfg_path, serde_json::json!({"mode": "refuse"}));
+    assert_eq!(run_detailed(&[&add]).0, 5);
+    write_config(cfg_path, serde_json::json!({"policies": [{"when": "adds > 0", "mode": "refuse"}]}));
+    assert_eq!(run_detailed(&[&add]).0, 6);
+    write_config(cfg_path, serde_json::json!({"mode": "warn"}));
+    assert_eq!(run_detailed(&[&add]).0, 4);
+    write_config(cfg_path, serde_json::json!({}));
+    assert_eq!(run_detailed(&[&add_file_patch("c.txt", &["c"])]).0, 0);
+    assert_eq!(run_detailed(&["*** Begin Patch\n*** Frobnicate File: a.txt\n*** End Patch\n"]).0, 7);
+    assert_eq!(run_detailed(&[&update_file_patch("a.txt", "two", "three")]).0, 8);
+    std::fs::write(cfg_path, "{").unwrap();
+    assert_eq!(run_detailed(&[&add_file_patch("d.txt", &["d"])]).0, 9);
+    std::fs::remove_file(cfg_path).unwrap();
+}
+
+fn assert_drop_paths(program: &Path, cfg_path: &Path) {
+    let work = TempDir::new();
+    let audit_log = work.path().join("audit.jsonl");
+    std::fs::write(work.path().join("Cargo.lock"), "old\n").unwrap();
+    write_config(
+        cfg_path,
+        serde_json::json!({"drop_paths": ["CHANGELOG.md", "*.lock"], "audit_log": audit_log}),
+    );
+    let run_patch = |patch: &str| {
+        run({
+            let mut cmd = Command::new(program);
+            cmd.current_dir(work.path()).env("APPLY_PATCH_CONFIG", cfg_path).arg(patch);
+            cmd
+        })
+    };
+
+    let patch = "*** Begin Patch\n*** Add File: CHANGELOG.md\n+entry\n*** Update File: Cargo.lock\n@@\n-old\n+new\n*** Add File: src/a.rs\n+fn a() {}\n*** End Patch\n";
+    let (code, stdout, stderr) = run_patch(patch);
+    assert_eq!(code, 0, "stderr:\n{stderr}");
+    assert!(
+        stdout.starts_with("Dropped by policy: CHANGELOG.md\nDropped by policy: Cargo.lock\nSuccess."),
+        "stdout:\n{stdout}"
+    );
+    assert!(work.path().join("src/a.rs").exists());
+    assert!(!work.path().join("CHANGELOG.md").exists());
+    assert_eq!(std::fs::read_to_string(work.path().join("Cargo.lock")).unwrap(), "old\n");
+    let entries = read_jsonl(&audit_log);
+    assert_eq!(entries[0]["dropped"], serde_json::json!(["CHANGELOG.md", "Cargo.lock"]));
+    assert_eq!(entries[0]["files"], serde_json::json!(["src/a.rs"]));
+
+    let (code, stdout, _stderr) = run_patch(&add_file_patch("CHANGELOG.md", &["entry"]));
+    assert_eq!(code, 0);
+    assert_eq!(stdout, "Dropped by policy: CHANGELOG.md\nNothing left to apply.\n");
+}
+
+fn assert_lint_rules(program: &Path, cfg_path: &Path) {
+    let work = TempDir::new();
+    std::fs::write(work.path().join("lib.rs"), "fn a() {\n    b();\n}\n").unwrap();
+    let run_in_work = |args: &[&str]| {
+        run({
+            let mut cmd = Command::new(program);
+            cmd.current_dir(work.path()).env("APPLY_PATCH_CONFIG", cfg_path).args(args);
+            cmd
+        })
+    };
+    let patch = "*** Begin Patch\n*** Add File: notes.txt\n+ok\n+<<<<<<< HEAD\n*** Update File: lib.rs\n@@\n fn a() {\n+\tc(); // TODO\n     b();\n*** End Patch\n";
+
+    write_config(cfg_path, serde_json::json!({}));
+    let (code, stdout, _stderr) = run_in_work(&["lint", patch]);
+    assert_eq!(code, 1);
+    assert_eq!(
+        stdout,
+        "error conflict_markers notes.txt:2: adds a merge conflict marker\n\
+         warn mixed_indentation lib.rs @@ 1: indents with tabs but the file uses spaces\n\
+         warn todo_added lib.rs @@ 1: adds a TODO/FIXME\n"
+    );
+
+    write_config(
+        cfg_path,
+        serde_json::json!({"lint": {"rules": {"conflict_markers": "warn", "todo_added": "off"}}}),
+    );
+    let (code, stdout, _stderr) = run_in_work(&["lint", "--json", patch]);
+    assert_eq!(code, 0);
+    let findings: serde_json::Value = serde_json::from_str(&stdout).unwrap();
+    assert_eq!(findings.as_array().unwrap().len(), 2);
+    assert_eq!(findings[0]["severity"], "warn");
+    assert_eq!(findings[1]["hunk"], 1);
+
+    write_config(
+        cfg_path,
+        serde_json::json!({"lint": {"enforce": true}, "refusal_reason_line": true}),
+    );
+    let (code, stdout, _stderr) = run_in_work(&[patch]);
+    assert_eq!(code, 0);
+    assert!(
+        stdout.starts_with("REFUSED: lint conflict_markers\nLint: error conflict_markers notes.txt:2"),
+        "stdout:\n{stdout}"
+    );
+    assert!(!work.path().join("notes.txt").exists());
+
+    let clean = "*** Begin Patch\n*** Update File: lib.rs\n@@\n fn a() {\n+    c(); // TODO\n     b();\n*** End Patch\n";
+    let (code, stdout, stderr) = run_in_work(&[clean]);
+    assert_eq!(code, 0, "stderr:\n{stderr}");
+    assert!(
+        stdout.starts_with("Lint: warn todo_added lib.rs @@ 1: adds a TODO/FIXME\nSuccess."),
+        "stdout:\n{stdout}"
+    );
+
+    write_config(cfg_path, serde_json::json!({"lint": {"rules": {"tabs": "error"}}}));
+    let (code, _stdout, stderr) = run_in_work(&["lint", patch]);
+    assert_eq!(code, 1);
+    assert!(stderr.contains("unknown lint rule: tabs"), "stderr:\n{stderr}");
+
+    // Trojan-source characters: bidi controls and look-alike letters.
+    write_config(cfg_path, serde_json::json!({}));
+    let sneaky = "*** Begin Patch\n*** Add File: auth.rs\n+let is_admin = false; /*\u{202E} } \u{2066}if is_admin\u{2069} \u{2066} begin admins only */\n+fn ch\u{0435}ck() {}\n*** End Patch\n";
+    let (code, stdout, _stderr) = run_in_work(&["lint", sneaky]);
+    assert_eq!(code, 1);
+    assert!(
+        stdout.starts_with("error invisible_chars auth.rs:1: adds invisible U+202E RIGHT-TO-LEFT OVERRIDE at column 25, U+2066 LEFT-TO-RIGHT ISOLATE at column 29"),
+        "stdout:\n{stdout}"
+    );
+    assert!(
+        stdout.contains("warn homoglyphs auth.rs:2: adds look-alike U+0435 (looks like 'e') at column 6\n"),
+        "stdout:\n{stdout}"
+    );
+
+    // Long lines are only checked once a limit is set.
+    let long = format!(
+        "*** Begin Patch\n*** Update File: lib.rs\n@@\n fn a() {{\n+    let s = \"{}\";\n     b();\n*** End Patch\n",
+        "x".repeat(60)
+    );
+    let (code, stdout, _stderr) = run_in_work(&["lint", &long]);
+    assert_eq!((code, stdout.as_str()), (0, "No lint findings.\n"));
+    write_config(
+        cfg_path,
+        serde_json::json!({
+            "lint": {"enforce": true, "max_line_length": 40, "rules": {"long_lines": "error"}},
+            "refusal_reason_line": true
+        }),
+    );
+    let before = std::fs::read_to_string(work.path().join("lib.rs")).unwrap();
+    let (code, stdout, _stderr) = run_in_work(&[&long]);
+    assert_eq!(code, 0);
+    assert!(
+        stdout.starts_with("REFUSED: lint long_lines\nLint: error long_lines lib.rs @@ 1: adds a line of 75 characters, more than max_line_length (40)\n"),
+        "stdout:\n{stdout}"
+    );
+    assert_eq!(std::fs::read_to_string(work.path().join("lib.rs")).unwrap(), before);
+}
+
+fn assert_shadow_policies(program: &Path, cfg_path: &Path) {
+    let work = TempDir::new();
+    let audit_log = work.path().join("audit.jsonl");
+    write_config(
+        cfg_path,
+        serde_json::json!({
+            "mode": "apply",
+            "audit_log": audit_log,
+            "policies": [
+                {"when": "files ~ '*.lock'", "mode": "refuse", "shadow": true},
+                {"when": "adds > 0", "mode": "warn"}
+            ],
+            "escalate": {"large_patch_lines": 3, "to": "refuse", "shadow": true}
+        }),
+    );
+    let apply = |patch: &str| {
+        run({
+            let mut cmd = Command::new(program);
+            cmd.current_dir(work.path()).env("APPLY_PATCH_CONFIG", cfg_path).arg(patch);
+            cmd
+        })
+    };
+
+    // The shadow rule would have refused, but the patch is applied in the
+    // mode the enforced rule selects.
+    let (code, stdout, stderr) = apply(&add_file_patch("Cargo.lock", &["x"]));
+    assert_eq!(code, 0, "stderr:\n{stderr}");
+    assert!(work.path().join("Cargo.lock").exists(), "stdout:\n{stdout}");
+    assert!(!stdout.contains("REFUSED") && !stdout.contains("shadow"), "stdout:\n{stdout}");
+    // Only the shadow escalation triggers here.
+    let (code, _stdout, stderr) = apply(&add_file_patch("big.txt", &["1", "2", "3"]));
+    assert_eq!(code, 0, "stderr:\n{stderr}");
+    // Shadow rules that agree with the enforced mode leave no trace.
+    let (code, _stdout, _stderr) = apply(&add_file_patch("small.txt", &["1"]));
+    assert_eq!(code, 0);
+
+    let entries = read_jsonl(&audit_log);
+    assert_eq!(entries.len(), 3);
+    assert_eq!(entries[0]["mode"], "warn");
+    assert_eq!(entries[0]["decision"], "applied");
+    assert_eq!(entries[0]["shadow"]["mode"], "refuse");
+    assert_eq!(entries[0]["shadow"]["policy"], 0);
+    assert_eq!(entries[1]["shadow"]["mode"], "refuse");
+    assert_eq!(entries[1]["shadow"]["policy"], 1);
+    assert_eq!(entries[1]["shadow"]["escalation"]["lines_changed"], 3);
+    assert!(entries[2].get("shadow").is_none(), "entry: {}", entries[2]);
+
+    let (code, stdout, _stderr) = run({
+        let mut cmd = Command::new(program);
+        cmd.env("APPLY_PATCH_CONFIG", cfg_path).arg("--show-config");
+        cmd
+    });
+    assert_eq!(code, 0);
+    assert!(stdout.contains("policies: 2 (1 shadow)\nescalate: refuse at 3+ changed lines (shadow)\n"), "stdout:\n{stdout}");
+    write_config(cfg_path, serde_json::json!({}));
+}
+
+#[test]
+fn rust_binary_policy_rules() {
+    let cfgdir = TempDir::new();
+    let cfg_path = cfgdir.path().join("config.json");
+    assert_policy_rules_select_mode(&bin_path(), &cfg_path);
+    assert_assess_scores_risk(&bin_path(), &cfg_path);
+    assert_refusal_reasons(&bin_path(), &cfg_path);
+    assert_refuse_echo(&bin_path(), &cfg_path);
+    assert_detailed_exit_codes(&bin_path(), &cfg_path);
+    assert_drop_paths(&bin_path(), &cfg_path);
+    assert_lint_rules(&bin_path(), &cfg_path);
+    assert_shadow_policies(&bin_path(), &cfg_path);
+}
+
+fn assert_tenants_are_isolated(program: &Path, cfg_path: &Path) {
+    let work = TempDir::new();
+    let audit_log = work.path().join("audit.jsonl");
+    write_config(
+        cfg_path,
+        serde_json::json!({
+            "mode": "apply",
+            "audit_log": audit_log,
+            "tenants": {"team-a": {"mode": "refuse", "refuse_message": "TEAM_A_REFUSED"}},
+        }),
+    );
+    let run_patch = |tenant_args: &[&str], env_tenant: Option<&str>, patch: &str| {
+        run_with_stdin({
+            let mut cmd = Command::new(program);
+            cmd.current_dir(work.path())
+                .env("APPLY_PATCH_CONFIG", cfg_path)
+                .env_remove("APPLY_PATCH_TENANT")
+                .args(tenant_args);
+            if let Some(tenant) = env_tenant {
+                cmd.env("APPLY_PATCH_TENANT", tenant);
+            }
+            cmd
+        }, patch)
+    };
+
+    let (code, stdout, stderr) = run_patch(&["--tenant", "team-a"], None, &add_file_patch("a.txt", &["a"]));
+    assert_eq!(code, 0, "stderr:\n{stderr}");
+    assert_eq!(stdout, "TEAM_A_REFUSED\n");
+    assert!(!work.path().join("a.txt").exists());
+
+    let (code, stdout, stderr) = run_patch(&[], Some("team-b"), &add_file_patch("b.txt", &["b"]));
+    assert_eq!(code, 0, "stderr:\n{stderr}");
+    assert!(stdout.contains("A b.txt"), "stdout:\n{stdout}");
+
+    // Inherited log files are split per tenant.
+    assert!(!audit_log.exists());
+    let team_a = read_jsonl(&work.path().join("audit.team-a.jsonl"));
+    assert_eq!(team_a.len(), 1);
+    assert_eq!(team_a[0]["tenant"], "team-a");
+    assert_eq!(team_a[0]["decision"], "refused");
+    let team_b = read_jsonl(&work.path().join("audit.team-b.jsonl"));
+    assert_eq!(team_b[0]["tenant"], "team-b");
+    assert_eq!(team_b[0]["decision"], "applied");
+
+    // Config flags with a tenant edit only that tenant's section.
+    let (code, _stdout, stderr) = run_patch(&["--tenant", "team-b", "--warn"], None, "");
+    assert_eq!(code, 0, "stderr:\n{stderr}");
+    let cfg: serde_json::Value =
+        serde_json::from_str(&std::fs::read_to_string(cfg_path).unwrap()).unwrap();
+    assert_eq!(cfg["mode"], "apply");
+    assert_eq!(cfg["tenants"]["team-b"]["mode"], "warn");
+    assert_eq!(cfg["tenants"]["team-a"]["mode"], "refuse");
+
+    let (code, stdout, _stderr) = run_patch(&["--tenant", "team-b", "--show-config"], None, "");
+    assert_eq!(code, 0);
+    assert!(stdout.contains("tenant: team-b\nmode: warn\n"), "stdout:\n{stdout}");
+
+    let (code, _stdout, stderr) = run({
+        let mut cmd = Command::new(program);
+        cmd.current_dir(work.path())
+            .env("APPLY_PATCH_CONFIG", cfg_path)
+            .args(["--tenant", "../x"])
+            .arg(add_file_patch("c.txt", &["c"]));
+        cmd
+    });
+    assert_eq!(code, 2);
+    assert!(stderr.starts_with("Error: invalid tenant name: ../x"), "stderr:\n{stderr}");
+
+    // So is the feedback file.
+    let feedback = work.path().join("feedback.jsonl");
+    let (code, _stdout, stderr) = run({
+        let mut cmd = Command::new(program);
+        cmd.current_dir(work.path())
+            .env("APPLY_PATCH_CONFIG", cfg_path)
+            .env("APPLY_PATCH_FEEDBACK_FILE", &feedback)
+            .args(["--tenant", "team-a"])
+            .arg(add_file_patch("d.txt", &["d"]));
+        cmd
+    });
+    assert_eq!(code, 0, "stderr:\n{stderr}");
+    assert!(!feedback.exists());
+    let records = read_jsonl(&work.path().join("feedback.team-a.jsonl"));
+    assert_eq!(records[0]["message"], "TEAM_A_REFUSED");
+
+    // An invalid tenant section is a config error, not the top-level config.
+    write_config(
+        cfg_path,
+        serde_json::json!({"mode": "apply", "tenants": {"team-a": {"mode": "refused"}}}),
+    );
+    let (code, _stdout, stderr) = run_patch(&["--tenant", "team-a"], None, &add_file_patch("e.txt", &["e"]));
+    assert_eq!(code, 1);
+    assert!(stderr.contains("tenants.team-a: unknown variant `refused`"), "stderr:\n{stderr}");
+    assert!(!work.path().join("e.txt").exists());
 }
 
-#[test]
-#[cfg(unix)]
-fn script_config_path_and_modes() {
-    let script = script_path();
-    assert_show_config_uses_dot_apply_patch(&script);
+fn assert_editor_guard(program: &Path, cfg_path: &Path) {
+    let work = TempDir::new();
+    let audit_log = work.path().join("audit.jsonl");
+    std::fs::write(work.path().join("notes.txt"), "old\n").unwrap();
+    std::fs::write(work.path().join(".notes.txt.swp"), "").unwrap();
+    write_config(
+        cfg_path,
+        serde_json::json!({"editor_guard": {"action": "refuse"}, "audit_log": audit_log}),
+    );
+    let run_patch = |patch: &str| {
+        run_with_stdin({
+            let mut cmd = Command::new(program);
+            cmd.current_dir(work.path()).env("APPLY_PATCH_CONFIG", cfg_path);
+            cmd
+        }, patch)
+    };
+
+    let (code, stdout, stderr) = run_patch(&update_file_patch("notes.txt", "old", "new"));
+    assert_eq!(code, 0, "stderr:\n{stderr}");
+    assert!(
+        stdout.starts_with("Editor guard (refuse mode): .notes.txt.swp suggests notes.txt has unsaved changes in an editor.\n"),
+        "stdout:\n{stdout}"
+    );
+    assert!(stdout.contains("nothing was changed"), "stdout:\n{stdout}");
+    assert_eq!(std::fs::read_to_string(work.path().join("notes.txt")).unwrap(), "old\n");
+    let entries = read_jsonl(&audit_log);
+    assert_eq!(entries[0]["editor_guard"]["artifacts"][0]["artifact"], ".notes.txt.swp");
+
+    // Files without artifacts are unaffected.
+    let (code, stdout, _stderr) = run_patch(&add_file_patch("other.txt", &["x"]));
+    assert_eq!(code, 0);
+    assert!(stdout.contains("A other.txt"), "stdout:\n{stdout}");
+
+    // Custom patterns, with warn applying the patch and naming the artifact.
+    write_config(
+        cfg_path,
+        serde_json::json!({"editor_guard": {"action": "warn", "patterns": ["{name}.lock"]}}),
+    );
+    std::fs::write(work.path().join("notes.txt.lock"), "").unwrap();
+    let (code, stdout, _stderr) = run_patch(&update_file_patch("notes.txt", "old", "new"));
+    assert_eq!(code, 0);
+    assert!(stdout.contains("M notes.txt"), "stdout:\n{stdout}");
+    assert!(
+        stdout.contains("Editor guard (warn mode): notes.txt.lock suggests notes.txt"),
+        "stdout:\n{stdout}"
+    );
+    assert!(stdout.contains("NOTE TO LLM:"), "stdout:\n{stdout}");
+}
+
+fn assert_symlinks(program: &Path, cfg_path: &Path) {
+    let outside = TempDir::new();
+    let work = TempDir::new();
+    let root = work.path();
+    std::fs::write(outside.path().join("secret.txt"), "old\n").unwrap();
+    std::fs::write(root.join("real.txt"), "old\n").unwrap();
+    std::os::unix::fs::symlink(outside.path().join("secret.txt"), root.join("escape.txt")).unwrap();
+    std::os::unix::fs::symlink("real.txt", root.join("link.txt")).unwrap();
+    let run_patch = |patch: &str| {
+        run_with_stdin({
+            let mut cmd = Command::new(program);
+            cmd.current_dir(root).env("APPLY_PATCH_CONFIG", cfg_path);
+            cmd
+        }, patch)
+    };
+
+    // Links escaping the working directory are refused even when following.
+    write_config(cfg_path, serde_json::json!({"refusal_reason_line": true}));
+    let (code, stdout, stderr) = run_patch(&update_file_patch("escape.txt", "old", "new"));
+    assert_eq!(code, 0, "stderr:\n{stderr}");
+    assert!(stdout.starts_with("REFUSED: symlink escape.txt\n"), "stdout:\n{stdout}");
+    assert!(stdout.contains("outside the working directory"), "stdout:\n{stdout}");
+    assert_eq!(std::fs::read_to_string(outside.path().join("secret.txt")).unwrap(), "old\n");
+
+    // Links inside it are followed by default.
+    let (code, stdout, _stderr) = run_patch(&update_file_patch("link.txt", "old", "new"));
+    assert_eq!(code, 0);
+    assert!(stdout.contains("M link.txt"), "stdout:\n{stdout}");
+    assert_eq!(std::fs::read_to_string(root.join("real.txt")).unwrap(), "new\n");
+
+    write_config(cfg_path, serde_json::json!({"symlinks": {"action": "refuse"}}));
+    let (code, stdout, _stderr) = run_patch(&update_file_patch("link.txt", "new", "newer"));
+    assert_eq!(code, 0);
+    assert!(
+        stdout.starts_with("Symlink (refuse mode): link.txt is a symlink to "),
+        "stdout:\n{stdout}"
+    );
+    assert_eq!(std::fs::read_to_string(root.join("real.txt")).unwrap(), "new\n");
+
+    // replace-link edits a regular copy and leaves the link target alone.
+    write_config(cfg_path, serde_json::json!({"symlinks": {"action": "replace-link"}}));
+    let (code, stdout, _stderr) = run_patch(&update_file_patch("link.txt", "new", "newer"));
+    assert_eq!(code, 0);
+    assert!(stdout.contains("Replaced symlink link.txt with a regular file.\n"), "stdout:\n{stdout}");
+    assert!(!std::fs::symlink_metadata(root.join("link.txt")).unwrap().file_type().is_symlink());
+    assert_eq!(std::fs::read_to_string(root.join("link.txt")).unwrap(), "newer\n");
+    assert_eq!(std::fs::read_to_string(root.join("real.txt")).unwrap(), "new\n");
+}
+
+fn assert_new_dirs(program: &Path, cfg_path: &Path) {
+    let work = TempDir::new();
+    let root = work.path();
+    std::fs::create_dir_all(root.join(".git")).unwrap();
+    let run_patch = |patch: &str, args: &[&str]| {
+        run_with_stdin({
+            let mut cmd = Command::new(program);
+            cmd.current_dir(root).env("APPLY_PATCH_CONFIG", cfg_path).args(args);
+            cmd
+        }, patch)
+    };
+
+    write_config(cfg_path, serde_json::json!({"new_dirs": {"max_depth": 1}, "refusal_reason_line": true}));
+    let (code, stdout, stderr) = run_patch(&add_file_patch("a/b/c.txt", &["x"]), &[]);
+    assert_eq!(code, 0, "stderr:\n{stderr}");
+    assert!(stdout.starts_with("REFUSED: new_dir a\n"), "stdout:\n{stdout}");
+    assert!(
+        stdout.contains("New directory (refuse mode): a for a/b/c.txt: it creates 2 directory levels"),
+        "stdout:\n{stdout}"
+    );
+    assert!(!root.join("a").exists());
+    let (code, stdout, _stderr) = run_patch(&add_file_patch("a/c.txt", &["x"]), &[]);
+    assert_eq!(code, 0);
+    assert!(stdout.contains("A a/c.txt"), "stdout:\n{stdout}");
+
+    // --max-depth overrides the config.
+    let (_code, stdout, _stderr) = run_patch(&add_file_patch("d/x.txt", &["x"]), &["--max-depth", "0"]);
+    assert!(stdout.starts_with("REFUSED: new_dir d\n"), "stdout:\n{stdout}");
+    let (code, _stdout, stderr) = run_patch(&add_file_patch("d/x.txt", &["x"]), &["--max-depth", "deep"]);
+    assert_eq!(code, 2);
+    assert!(stderr.contains("invalid --max-depth value: deep"), "stderr:\n{stderr}");
+
+    // New top-level directories only; existing ones can grow.
+    write_config(cfg_path, serde_json::json!({"new_dirs": {"top_level": false}}));
+    let (_code, stdout, _stderr) = run_patch(&add_file_patch("e/x.txt", &["x"]), &[]);
+    assert!(stdout.contains("e for e/x.txt: new top-level directories are not allowed."), "stdout:\n{stdout}");
+    let (_code, stdout, _stderr) = run_patch(&add_file_patch("a/f/x.txt", &["x"]), &[]);
+    assert!(stdout.contains("A a/f/x.txt"), "stdout:\n{stdout}");
+
+    // ask needs a terminal; without one the patch is refused.
+    write_config(cfg_path, serde_json::json!({"new_dirs": {"action": "ask"}}));
+    let (_code, stdout, _stderr) = run_patch(&add_file_patch("g/x.txt", &["x"]), &[]);
+    assert!(stdout.contains("there is no terminal to confirm new directories on"), "stdout:\n{stdout}");
+    assert!(!root.join("g").exists());
+
+    write_config(cfg_path, serde_json::json!({"new_dirs": {"action": "deny"}}));
+    let (_code, stdout, _stderr) = run_patch(&add_file_patch("a/h/x.txt", &["x"]), &[]);
+    assert!(stdout.contains("a/h for a/h/x.txt: new directories are denied"), "stdout:\n{stdout}");
+    let (_code, stdout, _stderr) = run_patch(&add_file_patch("a/y.txt", &["x"]), &[]);
+    assert!(stdout.contains("A a/y.txt"), "stdout:\n{stdout}");
+}
+
+fn assert_tool_json_input(program: &Path, cfg_path: &Path) {
+    let work = TempDir::new();
+    let audit_log = work.path().join("audit.jsonl");
+    std::fs::create_dir_all(work.path().join("repo")).unwrap();
+    write_config(
+        cfg_path,
+        serde_json::json!({"mode": "warn", "warn_message": "Call {metadata.call_id} ({metadata.attempt}) used the shell.", "audit_log": audit_log}),
+    );
+    let run_input = |input: &str| {
+        run_with_stdin({
+            let mut cmd = Command::new(program);
+            cmd.current_dir(work.path())
+                .env("APPLY_PATCH_CONFIG", cfg_path)
+                .args(["--input", "tool-json"]);
+            cmd
+        }, input)
+    };
+
+    let call = serde_json::json!({
+        "patch": add_file_patch("hello.txt", &["hi"]),
+        "cwd": "repo",
+        "metadata": {"call_id": "call_42", "attempt": 2},
+    });
+    let (code, stdout, stderr) = run_input(&call.to_string());
+    assert_eq!(code, 0, "stderr:\n{stderr}");
+    assert!(stdout.contains("A hello.txt"), "stdout:\n{stdout}");
+    assert!(stdout.contains("Call call_42 (2) used the shell."), "stdout:\n{stdout}");
+    assert_eq!(std::fs::read_to_string(work.path().join("repo/hello.txt")).unwrap(), "hi\n");
+    let entries = read_jsonl(&audit_log);
+    assert_eq!(entries[0]["metadata"]["call_id"], "call_42");
+
+    let (code, _stdout, stderr) = run_input("*** Begin Patch\n*** End Patch\n");
+    assert_eq!(code, 1);
+    assert!(stderr.contains("invalid tool-call JSON"), "stderr:\n{stderr}");
+}
+
+fn assert_verify_writes(program: &Path, cfg_path: &Path) {
+    let work = TempDir::new();
+    let root = work.path();
+    std::fs::write(root.join("real.txt"), "old\n").unwrap();
+    std::os::unix::fs::symlink("real.txt", root.join("alias.txt")).unwrap();
+    let run_patch = |patch: &str| {
+        run_with_stdin({
+            let mut cmd = Command::new(program);
+            cmd.current_dir(root).env("APPLY_PATCH_CONFIG", cfg_path);
+            cmd
+        }, patch)
+    };
+    // The second section writes through the link, so real.txt doesn't end
+    // up as the first section left it.
+    let patch = "*** Begin Patch\n*** Update File: real.txt\n@@\n-old\n+new\n*** Add File: alias.txt\n+other\n*** End Patch\n";
+
+    write_config(cfg_path, serde_json::json!({}));
+    let (code, _stdout, stderr) = run_patch(patch);
+    assert_eq!(code, 1, "stderr:\n{stderr}");
+    assert!(
+        stderr.contains("Verification failed: real.txt does not match the patch's result (expected sha256 "),
+        "stderr:\n{stderr}"
+    );
+
+    std::fs::write(root.join("real.txt"), "old\n").unwrap();
+    write_config(cfg_path, serde_json::json!({"verify_writes": false}));
+    let (code, stdout, stderr) = run_patch(patch);
+    assert_eq!(code, 0, "stderr:\n{stderr}");
+    assert!(stdout.contains("M real.txt"), "stdout:\n{stdout}");
+}
+
+fn assert_notify(program: &Path, cfg_path: &Path) {
+    let work = TempDir::new();
+    let log = work.path().join("notify.log");
+    std::fs::write(work.path().join("notes.txt"), "old\n").unwrap();
+    let command = format!(
+        "printf '%s|%s|%s|%s\\n' \"$APPLY_PATCH_NOTIFY_EVENT\" \"$APPLY_PATCH_NOTIFY_REASON\" \"$APPLY_PATCH_NOTIFY_FILES\" \"$APPLY_PATCH_NOTIFY_MESSAGE\" >> '{}'",
+        log.display()
+    );
+    let run_patch = |patch: &str| {
+        run_with_stdin({
+            let mut cmd = Command::new(program);
+            cmd.current_dir(work.path()).env("APPLY_PATCH_CONFIG", cfg_path);
+            cmd
+        }, patch)
+    };
+
+    write_config(cfg_path, serde_json::json!({"mode": "refuse", "notify": {"command": command}}));
+    let (code, stdout, stderr) = run_patch(&update_file_patch("notes.txt", "old", "new"));
+    assert_eq!(code, 0, "stderr:\n{stderr}");
+    assert!(stdout.contains("NOTE TO LLM"), "stdout:\n{stdout}");
+    let logged = std::fs::read_to_string(&log).unwrap();
+    assert!(
+        logged.starts_with("refused|mode|notes.txt|REFUSED: mode refuse ("),
+        "log:\n{logged}"
+    );
 
+    // Over the escalate threshold in warn: notified, and still applied.
+    std::fs::remove_file(&log).unwrap();
+    write_config(
+        cfg_path,
+        serde_json::json!({
+            "escalate": {"large_patch_lines": 2, "to": "warn"},
+            "notify": {"command": command}
+        }),
+    );
+    let (code, stdout, _stderr) = run_patch(&update_file_patch("notes.txt", "old", "new"));
+    assert_eq!(code, 0);
+    assert!(stdout.contains("M notes.txt"), "stdout:\n{stdout}");
+    let logged = std::fs::read_to_string(&log).unwrap();
+    assert!(
+        logged.starts_with("escalated||notes.txt|Escalated to warn mode: this patch changes 2 lines"),
+        "log:\n{logged}"
+    );
+
+    // Patches within the limits don't notify.
+    std::fs::remove_file(&log).unwrap();
+    let (code, _stdout, _stderr) = run_patch(&add_file_patch("small.txt", &["x"]));
+    assert_eq!(code, 0);
+    assert!(!log.exists());
+
+    // A failing notifier only warns.
+    write_config(cfg_path, serde_json::json!({"mode": "refuse", "notify": {"command": "exit 3"}}));
+    let (code, _stdout, stderr) = run_patch(&add_file_patch("other.txt", &["x"]));
+    assert_eq!(code, 0);
+    assert!(stderr.contains("Warning: notify.command failed"), "stderr:\n{stderr}");
+}
+
+fn assert_dir_ops(program: &Path, cfg_path: &Path) {
+    let work = TempDir::new();
+    let audit_log = work.path().join("audit.jsonl");
+    std::fs::create_dir_all(work.path().join("build/sub")).unwrap();
+    std::fs::write(work.path().join("build/a.txt"), "a\n").unwrap();
+    std::fs::write(work.path().join("build/sub/b.txt"), "b\n").unwrap();
+    let run_patch = |patch: &str| {
+        run_with_stdin({
+            let mut cmd = Command::new(program);
+            cmd.current_dir(work.path()).env("APPLY_PATCH_CONFIG", cfg_path);
+            cmd
+        }, patch)
+    };
+    let patch = "*** Begin Patch\n*** Delete Directory: build\n*** Add Directory: docs/api\n*** Add File: notes.txt\n+moved\n*** End Patch\n";
+
+    write_config(cfg_path, serde_json::json!({}));
+    let (code, _stdout, stderr) = run_patch(patch);
+    assert_eq!(code, 1);
+    assert!(stderr.starts_with("Directory operations are disabled"), "stderr:\n{stderr}");
+    assert!(work.path().join("build/a.txt").exists());
+
+    // Over the limit: refused, with every file still there.
+    write_config(
+        cfg_path,
+        serde_json::json!({"allow_dir_ops": true, "max_dir_delete_files": 1, "audit_log": audit_log}),
+    );
+    let (code, stdout, _stderr) = run_patch(patch);
+    assert_eq!(code, 0);
+    assert!(
+        stdout.starts_with("Directory delete (refuse mode): build/ holds 2 files, more than max_dir_delete_files (1).\n"),
+        "stdout:\n{stdout}"
+    );
+    assert!(work.path().join("build/sub/b.txt").exists());
+    assert!(!work.path().join("docs").exists());
+    let entries = read_jsonl(&audit_log);
+    assert_eq!(entries[0]["reason"], serde_json::json!({"code": "dir_delete", "detail": "build"}));
+    assert_eq!(entries[0]["files"], serde_json::json!(["build/a.txt", "build/sub/b.txt", "notes.txt", "docs/api"]));
+
+    write_config(cfg_path, serde_json::json!({"allow_dir_ops": true}));
+    let (code, stdout, stderr) = run_patch(patch);
+    assert_eq!(code, 0, "stderr:\n{stderr}");
+    assert_eq!(
+        stdout,
+        "Success. Updated the following files:\nA notes.txt\nD build/a.txt\nD build/sub/b.txt\nD build/\nA docs/api/\n"
+    );
+    assert!(!work.path().join("build").exists());
+    assert!(work.path().join("docs/api").is_dir());
+
+    // Directory-only patches skip the applier; bad paths are rejected.
+    let (code, stdout, _stderr) = run_patch("*** Begin Patch\n*** Add Directory: empty\n*** End Patch\n");
+    assert_eq!(code, 0);
+    assert_eq!(stdout, "Success. Updated the following files:\nA empty/\n");
+    let (code, _stdout, stderr) = run_patch("*** Begin Patch\n*** Delete Directory: ../elsewhere\n*** End Patch\n");
+    assert_eq!(code, 1);
+    assert!(stderr.contains("must be relative and stay in the working directory"), "stderr:\n{stderr}");
+
+    // Added directories go through new_dirs and the path guards.
+    write_config(
+        cfg_path,
+        serde_json::json!({"allow_dir_ops": true, "new_dirs": {"action": "deny"}, "refusal_reason_line": true}),
+    );
+    let (code, stdout, _stderr) = run_patch("*** Begin Patch\n*** Add Directory: a/b/c/d\n*** End Patch\n");
+    assert_eq!(code, 0);
+    assert!(
+        stdout.starts_with("REFUSED: new_dir a\nNew directory (refuse mode): a for a/b/c/d: new directories are denied (new_dirs.action).\n"),
+        "stdout:\n{stdout}"
+    );
+    assert!(!work.path().join("a").exists());
+    write_config(cfg_path, serde_json::json!({"allow_dir_ops": true}));
+    let (code, stdout, _stderr) = run_with_stdin(
+        {
+            let mut cmd = Command::new(program);
+            cmd.current_dir(work.path())
+                .env("APPLY_PATCH_CONFIG", cfg_path)
+                .args(["--expected-files", "notes.txt"]);
+            cmd
+        },
+        "*** Begin Patch\n*** Add Directory: extra\n*** End Patch\n",
+    );
+    assert_eq!(code, 0);
+    assert!(stdout.contains("  + extra\n"), "stdout:\n{stdout}");
+    assert!(!work.path().join("extra").exists());
+}
+
+fn assert_branch_rules(program: &Path, cfg_path: &Path) {
+    let work = TempDir::new();
+    let repo = work.path();
+    git(repo, &["init", "--quiet", "-b", "main"]);
+    std::fs::write(repo.join("a.txt"), "old\n").unwrap();
+    git(repo, &["add", "-A"]);
+    git(repo, &["commit", "--quiet", "-m", "init"]);
+
+    let run_patch = || {
+        run({
+            let mut cmd = Command::new(program);
+            cmd.current_dir(repo)
+                .env("APPLY_PATCH_CONFIG", cfg_path)
+                .arg(update_file_patch("a.txt", "old", "new"));
+            cmd
+        })
+    };
+
+    write_config(
+        cfg_path,
+        serde_json::json!({
+            "mode": "apply",
+            "refusal_reason_line": true,
+            "branch_rules": [
+                {"branch": "main", "mode": "refuse"},
+                {"branch": "feature/*", "mode": "apply"},
+                {"branch": "**", "mode": "warn"}
+            ]
+        }),
+    );
+    let (code, stdout, _stderr) = run_patch();
+    assert_eq!(code, 0);
+    assert!(
+        stdout.starts_with("REFUSED: branch main\nBranch rule (refuse mode): the working tree is on main, matched by branch_rules[0] (main).\n"),
+        "stdout: {stdout}"
+    );
+    assert_eq!(std::fs::read_to_string(repo.join("a.txt")).unwrap(), "old\n");
+
+    // `main` must match the whole branch name, not its last component.
+    git(repo, &["checkout", "--quiet", "-b", "feature/main"]);
+    let (code, stdout, _stderr) = run_patch();
+    assert_eq!(code, 0, "stdout: {stdout}");
+    assert!(!stdout.contains("Branch rule"), "stdout: {stdout}");
+    assert_eq!(std::fs::read_to_string(repo.join("a.txt")).unwrap(), "new\n");
+
+    git(repo, &["checkout", "--quiet", "-b", "fix/x"]);
+    std::fs::write(repo.join("a.txt"), "old\n").unwrap();
+    let (code, stdout, _stderr) = run_patch();
+    assert_eq!(code, 0);
+    assert!(
+        stdout.contains("Branch rule (warn mode): the working tree is on fix/x, matched by branch_rules[2] (**)."),
+        "stdout: {stdout}"
+    );
+    assert_eq!(std::fs::read_to_string(repo.join("a.txt")).unwrap(), "new\n");
+
+    write_config(cfg_path, serde_json::json!({}));
+}
+
+fn assert_codeowners_lanes(program: &Path, cfg_path: &Path) {
+    let work = TempDir::new();
+    let repo = work.path();
+    std::fs::create_dir_all(repo.join(".git")).unwrap();
+    std::fs::create_dir_all(repo.join(".github")).unwrap();
+    std::fs::write(
+        repo.join(".github/CODEOWNERS"),
+        "# lanes\n*.md @acme/docs\n/services/ @acme/platform\n/services/billing/ @acme/payments @acme/finance\nagents/ @acme/agents # ours\n",
+    )
+    .unwrap();
+    for dir in ["services/billing", "services/search", "tools/agents"] {
+        std::fs::create_dir_all(repo.join(dir)).unwrap();
+    }
+
+    let run_patch = |cwd: &Path, patch: &str| {
+        run({
+            let mut cmd = Command::new(program);
+            cmd.current_dir(cwd).env("APPLY_PATCH_CONFIG", cfg_path).arg(patch);
+            cmd
+        })
+    };
+
+    write_config(
+        cfg_path,
+        serde_json::json!({
+            "codeowners": {"owners": ["@acme/agents", "@acme/platform"], "action": "refuse"},
+            "refusal_reason_line": true
+        }),
+    );
+    let (code, stdout, _stderr) = run_patch(repo, &add_file_patch("services/billing/api.rs", &["x"]));
+    assert_eq!(code, 0);
+    assert!(
+        stdout.starts_with("REFUSED: not_owner services/billing/api.rs\nCODEOWNERS (refuse mode): services/billing/api.rs is owned by @acme/payments @acme/finance; leave it to them.\n"),
+        "stdout: {stdout}"
+    );
+    assert!(!repo.join("services/billing/api.rs").exists());
+
+    // Relative to a subdirectory; `agents/` matches at any depth.
+    let (code, stdout, stderr) = run_patch(&repo.join("services/search"), &add_file_patch("../../tools/agents/run.sh", &["x"]));
+    assert_eq!(code, 0, "stderr: {stderr}");
+    assert!(stdout.starts_with("Success."), "stdout: {stdout}");
+    let (code, stdout, _stderr) = run_patch(&repo.join("services/search"), &add_file_patch("index.rs", &["x"]));
+    assert_eq!(code, 0);
+    assert!(stdout.starts_with("Success."), "stdout: {stdout}");
+
+    let (code, stdout, _stderr) = run_patch(repo, &add_file_patch("misc.txt", &["x"]));
+    assert_eq!(code, 0);
+    assert!(stdout.starts_with("Success."), "stdout: {stdout}");
+    write_config(
+        cfg_path,
+        serde_json::json!({
+            "codeowners": {"owners": ["@acme/agents"], "action": "warn", "allow_unowned": false}
+        }),
+    );
+    let (code, stdout, _stderr) = run_patch(repo, &add_file_patch("misc2.txt", &["x"]));
+    assert_eq!(code, 0);
+    assert!(
+        stdout.contains("CODEOWNERS (warn mode): misc2.txt has no owner, and unowned paths are outside this agent's lane.\n"),
+        "stdout: {stdout}"
+    );
+    assert!(repo.join("misc2.txt").exists());
+    write_config(cfg_path, serde_json::json!({}));
+}
+
+fn assert_stale_reads_refused(program: &Path, cfg_path: &Path) {
+    let work = TempDir::new();
+    let notes = work.path().join("notes.txt");
+    let reset = || {
+        std::fs::write(&notes, "old\n").unwrap();
+        std::fs::File::options()
+            .write(true)
+            .open(&notes)
+            .unwrap()
+            .set_modified(std::time::UNIX_EPOCH + std::time::Duration::from_secs(1_700_000_000))
+            .unwrap();
+    };
+    let run_patch = |args: &[&str], patch: &str| {
+        run({
+            let mut cmd = Command::new(program);
+            cmd.current_dir(work.path()).env("APPLY_PATCH_CONFIG", cfg_path).args(args).arg(patch);
+            cmd
+        })
+    };
+    let patch_read_at = |time: &str| {
+        format!("*** Begin Patch\n*** Update File: notes.txt\n*** Read At: {time}\n@@\n-old\n+new\n*** End Patch\n")
+    };
+    write_config(cfg_path, serde_json::json!({"refusal_reason_line": true}));
+
+    // Modified after it was read: refused, asking for a re-read.
+    reset();
+    let (code, stdout, stderr) = run_patch(&[], &patch_read_at("1699999999"));
+    assert_eq!(code, 0, "stderr:\n{stderr}");
+    assert!(stdout.starts_with("REFUSED: stale_read notes.txt\nStale read (refuse mode): notes.txt was modified at 2023-11-14T22:13:20Z, after it was read at 1699999999; re-read it and write the patch again.\n"), "stdout:\n{stdout}");
+    assert_eq!(std::fs::read_to_string(&notes).unwrap(), "old\n");
+
+    // Read in the same second, or later (RFC 3339 with an offset works too):
+    // the line is stripped and the patch applies.
+    for time in ["1700000000", "2023-11-15T00:13:20+02:00"] {
+        reset();
+        let (code, stdout, stderr) = run_patch(&[], &patch_read_at(time));
+        assert_eq!(code, 0, "stderr:\n{stderr}");
+        assert_eq!(stdout, "Success. Updated the following files:\nM notes.txt\n");
+    }
+
+    // A manifest gives the times instead; a line in the patch wins.
+    let manifest = work.path().join("reads.json");
+    std::fs::write(&manifest, r#"{"./notes.txt": "2023-11-14T22:13:19Z"}"#).unwrap();
+    reset();
+    let manifest_arg = manifest.to_str().unwrap();
+    let (code, stdout, _stderr) = run_patch(&["--read-manifest", manifest_arg], &update_file_patch("notes.txt", "old", "new"));
+    assert_eq!(code, 0);
+    assert!(stdout.starts_with("REFUSED: stale_read notes.txt\n"), "stdout:\n{stdout}");
+    let (code, stdout, _stderr) = run_patch(&["--read-manifest", manifest_arg], &patch_read_at("1700000001.5"));
+    assert_eq!(code, 0);
+    assert_eq!(stdout, "Success. Updated the following files:\nM notes.txt\n");
+
+    let (code, _stdout, stderr) = run_patch(&[], &patch_read_at("yesterday"));
+    assert_eq!(code, 1);
+    assert_eq!(stderr, "Invalid Read At time for notes.txt: yesterday (use Unix seconds or RFC 3339, e.g. 2025-01-01T00:00:00Z).\n");
+}
+
+fn assert_git_boundaries(program: &Path, cfg_path: &Path) {
+    let work = TempDir::new();
+    let root = work.path();
+    std::fs::create_dir_all(root.join(".git/info")).unwrap();
+    std::fs::write(root.join(".git/config"), "[core]\n\tsparseCheckout = true\n").unwrap();
+    std::fs::write(root.join(".git/info/sparse-checkout"), "/*\n!/*/\n/src/\n/vendor/\n/nested/\n").unwrap();
+    std::fs::write(root.join(".gitmodules"), "[submodule \"lib\"]\n\tpath = vendor/lib\n").unwrap();
+    std::fs::create_dir_all(root.join("nested/.git")).unwrap();
+    write_config(
+        cfg_path,
+        serde_json::json!({"git_boundaries": {"action": "refuse"}, "refusal_reason_line": true}),
+    );
+    let run_patch = |patch: &str| {
+        run_with_stdin({
+            let mut cmd = Command::new(program);
+            cmd.current_dir(root).env("APPLY_PATCH_CONFIG", cfg_path);
+            cmd
+        }, patch)
+    };
+
+    let (code, stdout, _stderr) = run_patch(&add_file_patch("vendor/lib/x.c", &["x"]));
+    assert_eq!(code, 0);
+    assert!(
+        stdout.starts_with("REFUSED: submodule vendor/lib/x.c\nGit boundary (refuse mode): vendor/lib/x.c is inside the submodule or nested repository at vendor/lib;"),
+        "stdout:\n{stdout}"
+    );
+    assert!(!root.join("vendor/lib/x.c").exists());
+
+    let (code, stdout, _stderr) = run_patch(&add_file_patch("nested/deep/y.txt", &["y"]));
+    assert_eq!(code, 0);
+    assert!(stdout.contains("nested repository at nested;"), "stdout:\n{stdout}");
+
+    let (code, stdout, _stderr) = run_patch(&add_file_patch("docs/a.md", &["a"]));
+    assert_eq!(code, 0);
+    assert!(stdout.starts_with("REFUSED: sparse_checkout docs/a.md\n"), "stdout:\n{stdout}");
+    assert!(!root.join("docs/a.md").exists());
+
+    let (code, stdout, _stderr) = run_patch("*** Begin Patch\n*** Add File: src/a.rs\n+a\n*** Add File: top.txt\n+t\n*** End Patch\n");
+    assert_eq!(code, 0);
+    assert!(stdout.contains("A src/a.rs") && stdout.contains("A top.txt"), "stdout:\n{stdout}");
+}
+
+fn assert_failure_guard(program: &Path, cfg_path: &Path) {
+    let work = TempDir::new();
+    let state_home = TempDir::new();
+    let state_path = state_home.path().join("apply_patch/state.json");
+    std::fs::write(work.path().join("notes.txt"), "current\n").unwrap();
+    write_config(
+        cfg_path,
+        serde_json::json!({"failure_guard": {"threshold": 2}, "refusal_reason_line": true}),
+    );
+    let run_patch = |patch: &str| {
+        run({
+            let mut cmd = Command::new(program);
+            cmd.current_dir(work.path())
+                .env("APPLY_PATCH_CONFIG", cfg_path)
+                .env("XDG_STATE_HOME", state_home.path())
+                .arg(patch);
+            cmd
+        })
+    };
+    let stale = update_file_patch("notes.txt", "stale", "new");
+
+    let (code, _stdout, stderr) = run_patch(&stale);
+    assert_eq!(code, 1);
+    assert!(!stderr.contains("times in a row"), "stderr:\n{stderr}");
+    let (code, _stdout, stderr) = run_patch(&stale);
+    assert_eq!(code, 1);
+    assert!(
+        stderr.contains("This patch has now failed 2 times in a row with the same error:\n  Failed to find expected lines in"),
+        "stderr:\n{stderr}"
+    );
+    assert!(stderr.contains("Re-read the affected files"), "stderr:\n{stderr}");
+
+    // A success resets the streak.
+    let (code, _stdout, stderr) = run_patch(&update_file_patch("notes.txt", "current", "next"));
+    assert_eq!(code, 0, "stderr:\n{stderr}");
+    let state: serde_json::Value = serde_json::from_str(&std::fs::read_to_string(&state_path).unwrap()).unwrap();
+    assert!(state.get("failures").is_none(), "state: {state}");
+
+    // With a cooldown, hitting the threshold refuses every patch for a while.
+    write_config(
+        cfg_path,
+        serde_json::json!({"failure_guard": {"threshold": 1, "cooldown_secs": 600}, "refusal_reason_line": true}),
+    );
+    let (code, _stdout, stderr) = run_patch(&stale);
+    assert_eq!(code, 1);
+    assert!(stderr.contains("failed 1 times in a row"), "stderr:\n{stderr}");
+    assert!(stderr.contains("refuse further patches for the next 600s"), "stderr:\n{stderr}");
+    let (code, stdout, _stderr) = run_patch(&add_file_patch("other.txt", &["x"]));
+    assert_eq!(code, 0);
+    assert!(stdout.starts_with("REFUSED: cooldown "), "stdout:\n{stdout}");
+    assert!(stdout.contains("Failure guard (refuse mode): the last 1 patches failed"), "stdout:\n{stdout}");
+    assert!(!work.path().join("other.txt").exists());
+
+    // Turning the guard off lifts the cooldown.
+    write_config(cfg_path, serde_json::json!({}));
+    let (code, stdout, _stderr) = run_patch(&add_file_patch("other.txt", &["x"]));
+    assert_eq!(code, 0);
+    assert!(stdout.contains("A other.txt"), "stdout:\n{stdout}");
+    std::fs::remove_file(&state_path).unwrap();
+}
+
+fn assert_state_dir_migration(program: &Path, cfg_path: &Path) {
+    let work = TempDir::new();
+    let state_home = TempDir::new();
+    let custom = work.path().join("custom-state");
+    let legacy = cfg_path.with_file_name("state.json");
+    let legacy_tenant = cfg_path.with_file_name("state.ci.json");
+    std::fs::write(&legacy, "{}").unwrap();
+    std::fs::write(&legacy_tenant, "{}").unwrap();
+    let run_in_work = |args: &[&str]| {
+        run({
+            let mut cmd = Command::new(program);
+            cmd.current_dir(work.path())
+                .env("APPLY_PATCH_CONFIG", cfg_path)
+                .env("XDG_STATE_HOME", state_home.path())
+                .args(args);
+            cmd
+        })
+    };
+
+    write_config(cfg_path, serde_json::json!({}));
+    let (code, stdout, stderr) = run_in_work(&["migrate-state"]);
+    assert_eq!(code, 0, "stderr:\n{stderr}");
+    assert_eq!(stdout.lines().count(), 2, "stdout:\n{stdout}");
+    assert!(stdout.starts_with("Moved "), "stdout:\n{stdout}");
+    assert!(!legacy.exists() && !legacy_tenant.exists());
+    assert!(state_home.path().join("apply_patch/state.json").exists());
+    assert!(state_home.path().join("apply_patch/state.ci.json").exists());
+    let (code, stdout, _stderr) = run_in_work(&["migrate-state"]);
+    assert_eq!(code, 0);
+    assert!(stdout.starts_with("No state to migrate in "), "stdout:\n{stdout}");
+
+    // `state_dir` overrides the XDG location.
+    write_config(
+        cfg_path,
+        serde_json::json!({"state_dir": custom, "failure_guard": {"threshold": 3}}),
+    );
+    let (code, _stdout, _stderr) = run_in_work(&[&update_file_patch("missing.txt", "a", "b")]);
+    assert_eq!(code, 1);
+    let state: serde_json::Value =
+        serde_json::from_str(&std::fs::read_to_string(custom.join("state.json")).unwrap()).unwrap();
+    assert_eq!(state["failures"]["count"], 1);
+}
+
+fn assert_require_clean(program: &Path, cfg_path: &Path) {
+    let work = TempDir::new();
+    let repo = work.path();
+    let sub = repo.join("sub");
+    std::fs::create_dir_all(&sub).unwrap();
+    git(repo, &["init", "--quiet"]);
+    for name in ["staged.txt", "edited.txt", "clean.txt"] {
+        std::fs::write(sub.join(name), "old\n").unwrap();
+    }
+    git(repo, &["add", "-A"]);
+    git(repo, &["commit", "--quiet", "-m", "init"]);
+    std::fs::write(sub.join("staged.txt"), "old\nstaged\n").unwrap();
+    git(repo, &["add", "sub/staged.txt"]);
+    std::fs::write(sub.join("edited.txt"), "old\nedited\n").unwrap();
+
+    let run_patch = |patch: &str| {
+        run({
+            let mut cmd = Command::new(program);
+            cmd.current_dir(&sub).env("APPLY_PATCH_CONFIG", cfg_path).arg(patch);
+            cmd
+        })
+    };
+
+    write_config(
+        cfg_path,
+        serde_json::json!({"require_clean": {"check": "staged"}, "refusal_reason_line": true}),
+    );
+    let (code, stdout, _stderr) = run_patch(&update_file_patch("staged.txt", "old", "new"));
+    assert_eq!(code, 0);
+    assert!(
+        stdout.starts_with("REFUSED: dirty staged.txt\nRequire clean (refuse mode): staged.txt has staged changes; commit or stash them before patching it.\n"),
+        "stdout:\n{stdout}"
+    );
+    assert_eq!(std::fs::read_to_string(sub.join("staged.txt")).unwrap(), "old\nstaged\n");
+    // Unstaged edits only count with `any`.
+    let (code, stdout, _stderr) = run_patch(&update_file_patch("edited.txt", "old", "new"));
+    assert_eq!(code, 0);
+    assert!(stdout.contains("M edited.txt"), "stdout:\n{stdout}");
+
+    write_config(
+        cfg_path,
+        serde_json::json!({"require_clean": {"check": "any", "action": "warn"}}),
+    );
+    let (code, stdout, _stderr) = run_patch(&update_file_patch("edited.txt", "new", "newer"));
+    assert_eq!(code, 0);
+    assert!(stdout.contains("M edited.txt"), "stdout:\n{stdout}");
+    assert!(
+        stdout.contains("Require clean (warn mode): edited.txt has uncommitted changes;"),
+        "stdout:\n{stdout}"
+    );
+    let (code, stdout, _stderr) = run_patch(&update_file_patch("clean.txt", "old", "new"));
+    assert_eq!(code, 0);
+    assert!(!stdout.contains("Require clean"), "stdout:\n{stdout}");
+    assert!(!stdout.contains("NOTE TO LLM"), "stdout:\n{stdout}");
+}
+
+#[test]
+fn rust_binary_escalation_and_audit_log() {
     let cfgdir = TempDir::new();
     let cfg_path = cfgdir.path().join("config.json");
-
-    assert_refuse_and_warn_modes(&script, &cfg_path);
-    assert_custom_messages(&script, &cfg_path);
-    assert_patch_argument_mode(&script, &cfg_path);
-    assert_non_patch_argument_errors(&script, &cfg_path);
-    assert_config_flag_error_paths(&script, &cfg_path);
-    assert_empty_stdin_usage(&script);
-    assert_two_patch_args_usage(&script, &cfg_path);
-    assert_help_exits_zero(&script);
-    assert_config_flags_cannot_mix_with_patch_arg(&script, &cfg_path);
+    assert_large_patches_escalate(&bin_path(), &cfg_path);
+    assert_tenants_are_isolated(&bin_path(), &cfg_path);
+    assert_editor_guard(&bin_path(), &cfg_path);
+    assert_symlinks(&bin_path(), &cfg_path);
+    assert_new_dirs(&bin_path(), &cfg_path);
+    assert_tool_json_input(&bin_path(), &cfg_path);
+    assert_verify_writes(&bin_path(), &cfg_path);
+    assert_notify(&bin_path(), &cfg_path);
+    assert_dir_ops(&bin_path(), &cfg_path);
+    assert_branch_rules(&bin_path(), &cfg_path);
+    assert_codeowners_lanes(&bin_path(), &cfg_path);
+    assert_stale_reads_refused(&bin_path(), &cfg_path);
+    assert_git_boundaries(&bin_path(), &cfg_path);
+    assert_failure_guard(&bin_path(), &cfg_path);
+    assert_state_dir_migration(&bin_path(), &cfg_path);
+    assert_require_clean(&bin_path(), &cfg_path);
 }